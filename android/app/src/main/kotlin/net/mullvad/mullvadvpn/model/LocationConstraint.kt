@@ -27,4 +27,10 @@ sealed class LocationConstraint : Parcelable {
         override val location: GeoIpLocation
             get() = GeoIpLocation(null, null, countryCode, cityCode, hostname)
     }
+
+    @Parcelize
+    data class CustomList(val listName: String) : LocationConstraint() {
+        override val location: GeoIpLocation
+            get() = GeoIpLocation(null, null, "", null, null)
+    }
 }
@@ -77,6 +77,7 @@ class RelayList {
 
                         return city?.relays?.find { relay -> relay.name == location.hostname }
                     }
+                    is LocationConstraint.CustomList -> return null
                 }
             }
         }
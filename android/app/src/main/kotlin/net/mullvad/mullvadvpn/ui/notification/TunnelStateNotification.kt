@@ -33,7 +33,13 @@ class TunnelStateNotification(
             is TunnelState.Disconnected -> hide()
             is TunnelState.Connecting -> show(null)
             is TunnelState.Connected -> hide()
-            is TunnelState.Error -> show(state.errorState)
+            is TunnelState.Error -> {
+                if (isCancelled(state.errorState.cause)) {
+                    hide()
+                } else {
+                    show(state.errorState)
+                }
+            }
         }
 
         update()
@@ -52,7 +58,13 @@ class TunnelStateNotification(
         shouldShow = true
     }
 
-    private fun blockingErrorMessage(cause: ErrorStateCause): String {
+    // Connecting is only cancelled when disconnecting, so there is no error to show
+    private fun isCancelled(cause: ErrorStateCause): Boolean {
+        return cause is ErrorStateCause.TunnelParameterError &&
+            cause.error == ParameterGenerationError.Cancelled
+    }
+
+    private fun blockingErrorMessage(cause: ErrorStateCause): String? {
         val messageId = when (cause) {
             is ErrorStateCause.InvalidDnsServers -> {
                 val addresses = cause.addresses
@@ -77,6 +89,7 @@ class TunnelStateNotification(
                     ParameterGenerationError.CustomTunnelHostResultionError -> {
                         R.string.custom_tunnel_host_resolution_error
                     }
+                    ParameterGenerationError.Cancelled -> return null
                 }
             }
             is ErrorStateCause.VpnPermissionDenied -> R.string.vpn_permission_denied_error
@@ -1,5 +1,6 @@
 package net.mullvad.talpid.tunnel
 
 enum class ParameterGenerationError {
-    NoMatchingRelay, NoMatchingBridgeRelay, NoWireguardKey, CustomTunnelHostResultionError
+    NoMatchingRelay, NoMatchingBridgeRelay, NoWireguardKey, CustomTunnelHostResultionError,
+    Cancelled
 }
@@ -228,6 +228,7 @@ function build {
             mullvad-problem-report
             libtalpid_openvpn_plugin.dylib
             mullvad-setup
+            mullvad-exclude
         )
     elif [[ ("$(uname -s)" == "Linux") ]]; then
         BINARIES=(
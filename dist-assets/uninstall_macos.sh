@@ -39,7 +39,7 @@ FISH_COMPLETIONS_PATH="/usr/local/share/fish/vendor_completions.d/mullvad.fish"
 sudo rm -f "$FISH_COMPLETIONS_PATH"
 
 echo "Removing CLI symlinks from /usr/local/bin/ ..."
-sudo rm -f /usr/local/bin/mullvad /usr/local/bin/mullvad-problem-report
+sudo rm -f /usr/local/bin/mullvad /usr/local/bin/mullvad-problem-report /usr/local/bin/mullvad-exclude
 
 echo "Removing app from /Applications ..."
 sudo rm -rf /Applications/Mullvad\ VPN.app
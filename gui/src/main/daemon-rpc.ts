@@ -972,6 +972,8 @@ function convertFromParameterError(
       return TunnelParameterError.noWireguardKey;
     case grpcTypes.ErrorState.GenerationError.CUSTOM_TUNNEL_HOST_RESOLUTION_ERROR:
       return TunnelParameterError.customTunnelHostResolutionError;
+    case grpcTypes.ErrorState.GenerationError.CANCELLED:
+      return TunnelParameterError.cancelled;
   }
 }
 
@@ -53,6 +53,7 @@ export enum TunnelParameterError {
   noMatchingBridgeRelay,
   noWireguardKey,
   customTunnelHostResolutionError,
+  cancelled,
 }
 
 export type ErrorState =
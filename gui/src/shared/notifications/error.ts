@@ -194,5 +194,7 @@ function getTunnelParameterMessage(error: TunnelParameterError): string {
         'notifications',
         'Unable to resolve host of custom tunnel. Try changing your settings.',
       );
+    case TunnelParameterError.cancelled:
+      return messages.pgettext('notifications', 'Connecting was cancelled.');
   }
 }
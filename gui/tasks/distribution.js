@@ -93,6 +93,7 @@ const config = {
       { from: distAssets(path.join('${env.TARGET_TRIPLE}', 'mullvad-problem-report')), to: '.' },
       { from: distAssets(path.join('${env.TARGET_TRIPLE}', 'mullvad-daemon')), to: '.' },
       { from: distAssets(path.join('${env.TARGET_TRIPLE}', 'mullvad-setup')), to: '.' },
+      { from: distAssets(path.join('${env.TARGET_TRIPLE}', 'mullvad-exclude')), to: '.' },
       {
         from: distAssets(path.join('${env.TARGET_TRIPLE}', 'libtalpid_openvpn_plugin.dylib')),
         to: '.',
@@ -40,7 +40,7 @@ mod relay_list;
 pub use address_cache::AddressCache;
 pub use device::DevicesProxy;
 pub use hyper::StatusCode;
-pub use relay_list::RelayListProxy;
+pub use relay_list::{parse_relay_list, RelayListProxy, SignedResponse};
 
 /// Error code returned by the Mullvad API if the voucher has alreaby been used.
 pub const VOUCHER_USED: &str = "VOUCHER_USED";
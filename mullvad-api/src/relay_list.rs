@@ -21,6 +21,25 @@ pub struct RelayListProxy {
 
 const RELAY_LIST_TIMEOUT: Duration = Duration::from_secs(15);
 
+/// Parses a relay list in the format served by the API, e.g. one that has been saved to disk.
+pub fn parse_relay_list(body: &[u8]) -> Result<relay_list::RelayList, serde_json::Error> {
+    let relay_list: ServerRelayList = serde_json::from_slice(body)?;
+    Ok(relay_list.into_relay_list(None))
+}
+
+/// Header that contains the detached signature of a relay list response body.
+const SIGNATURE_HEADER: &str = "x-mullvad-signature";
+
+/// A response along with its raw body and the detached signature of the body, so that the body
+/// can be verified before the response is used.
+#[derive(Debug)]
+pub struct SignedResponse<T> {
+    pub value: T,
+    pub body: Vec<u8>,
+    /// Base64 encoded signature of `body`, if the API provided one.
+    pub signature: Option<String>,
+}
+
 impl RelayListProxy {
     /// Construct a new relay list rest client
     pub fn new(handle: rest::MullvadRestHandle) -> Self {
@@ -32,45 +51,137 @@ impl RelayListProxy {
         &self,
         etag: Option<String>,
     ) -> impl Future<Output = Result<Option<relay_list::RelayList>, rest::Error>> {
+        let request = self.signed_relay_list(etag);
+        async move { Ok(request.await?.map(|response| response.value)) }
+    }
+
+    /// Fetch the relay list along with its signature
+    pub fn signed_relay_list(
+        &self,
+        etag: Option<String>,
+    ) -> impl Future<Output = Result<Option<SignedResponse<relay_list::RelayList>>, rest::Error>>
+    {
         let service = self.handle.service.clone();
         let request = self.handle.factory.request("app/v1/relays", Method::GET);
+        async move { Self::send_relay_list_request(service, request?, etag).await }
+    }
+
+    /// Fetch the relay list along with its signature from `url` instead of the API. The URL
+    /// should serve the relay list in the same format as the API.
+    pub fn mirrored_relay_list(
+        &self,
+        url: &str,
+        etag: Option<String>,
+    ) -> impl Future<Output = Result<Option<SignedResponse<relay_list::RelayList>>, rest::Error>>
+    {
+        let service = self.handle.service.clone();
+        let request = rest::RestRequest::get(url);
+        async move { Self::send_relay_list_request(service, request?, etag).await }
+    }
+
+    async fn send_relay_list_request(
+        service: rest::RequestServiceHandle,
+        mut request: rest::RestRequest,
+        etag: Option<String>,
+    ) -> Result<Option<SignedResponse<relay_list::RelayList>>, rest::Error> {
+        request.set_timeout(RELAY_LIST_TIMEOUT);
+
+        if let Some(ref tag) = etag {
+            request.add_header(header::IF_NONE_MATCH, tag)?;
+        }
+
+        let response = service.request(request).await?;
+        if etag.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if response.status() != StatusCode::OK {
+            return rest::handle_error_response(response).await;
+        }
+
+        let etag = response_etag(&response);
+        let signature = response_signature(&response);
+        let body = rest::read_body(response).await?;
+        let relay_list: ServerRelayList =
+            serde_json::from_slice(&body).map_err(rest::Error::DeserializeError)?;
+
+        Ok(Some(SignedResponse {
+            value: relay_list.into_relay_list(etag),
+            body,
+            signature,
+        }))
+    }
+
+    /// Fetch the changes to the relay list since the relay list with the tag `etag`. Returns
+    /// `None` if the relay list has not changed. Fails if the API cannot produce the changes
+    /// for the tag, in which case the full relay list should be fetched instead.
+    pub fn relay_list_delta(
+        &self,
+        etag: String,
+    ) -> impl Future<Output = Result<Option<SignedResponse<relay_list::RelayListDelta>>, rest::Error>>
+    {
+        let service = self.handle.service.clone();
+        let request = self
+            .handle
+            .factory
+            .request("app/v1/relays/delta", Method::GET);
 
         async move {
             let mut request = request?;
             request.set_timeout(RELAY_LIST_TIMEOUT);
-
-            if let Some(ref tag) = etag {
-                request.add_header(header::IF_NONE_MATCH, tag)?;
-            }
+            request.add_header(header::IF_NONE_MATCH, &etag)?;
 
             let response = service.request(request).await?;
-            if etag.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+            if response.status() == StatusCode::NOT_MODIFIED {
                 return Ok(None);
             }
             if response.status() != StatusCode::OK {
                 return rest::handle_error_response(response).await;
             }
 
-            let etag = response
-                .headers()
-                .get(header::ETAG)
-                .and_then(|tag| match tag.to_str() {
-                    Ok(tag) => Some(tag.to_string()),
-                    Err(_) => {
-                        log::error!("Ignoring invalid tag from server: {:?}", tag.as_bytes());
-                        None
-                    }
-                });
-
-            Ok(Some(
-                rest::deserialize_body::<ServerRelayList>(response)
-                    .await?
-                    .into_relay_list(etag),
-            ))
+            let new_etag = response_etag(&response);
+            let signature = response_signature(&response);
+            let body = rest::read_body(response).await?;
+            let delta: ServerRelayListDelta =
+                serde_json::from_slice(&body).map_err(rest::Error::DeserializeError)?;
+
+            Ok(Some(SignedResponse {
+                value: relay_list::RelayListDelta {
+                    base_etag: etag,
+                    changed: delta.changed.into_relay_list(new_etag),
+                    removed: delta
+                        .removed
+                        .into_iter()
+                        .map(|hostname| hostname.to_lowercase())
+                        .collect(),
+                },
+                body,
+                signature,
+            }))
         }
     }
 }
 
+fn response_etag(response: &rest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|tag| match tag.to_str() {
+            Ok(tag) => Some(tag.to_string()),
+            Err(_) => {
+                log::error!("Ignoring invalid tag from server: {:?}", tag.as_bytes());
+                None
+            }
+        })
+}
+
+fn response_signature(response: &rest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|signature| signature.to_str().ok())
+        .map(|signature| signature.to_string())
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct ServerRelayList {
     locations: BTreeMap<String, Location>,
@@ -79,6 +190,15 @@ struct ServerRelayList {
     bridge: Bridges,
 }
 
+/// Relays that were added or changed since a given relay list, and the hostnames of relays that
+/// were removed. The endpoint data is always included in full.
+#[derive(Debug, serde::Deserialize)]
+struct ServerRelayListDelta {
+    #[serde(flatten)]
+    changed: ServerRelayList,
+    removed: Vec<String>,
+}
+
 impl ServerRelayList {
     fn into_relay_list(self, etag: Option<String>) -> relay_list::RelayList {
         let mut countries = BTreeMap::new();
@@ -120,6 +240,7 @@ impl ServerRelayList {
                 .into_iter()
                 .map(|(_key, country)| country)
                 .collect(),
+            source: relay_list::RelayListSource::Api,
         }
     }
 }
@@ -166,6 +287,7 @@ fn into_mullvad_relay(
         owned: relay.owned,
         provider: relay.provider,
         weight: relay.weight,
+        load: relay.load,
         endpoint_data,
         location: Some(location),
     }
@@ -229,6 +351,8 @@ struct Relay {
     ipv4_addr_in: Ipv4Addr,
     ipv6_addr_in: Option<Ipv6Addr>,
     weight: u64,
+    #[serde(default)]
+    load: Option<u8>,
     include_in_country: bool,
 }
 
@@ -311,6 +435,17 @@ struct WireGuardRelay {
     #[serde(flatten)]
     relay: Relay,
     public_key: wireguard::PublicKey,
+    #[serde(default)]
+    pluggable_transports: Vec<String>,
+    /// Relays that do not report this are assumed to support the PQ PSK exchange.
+    #[serde(default = "default_quantum_resistant")]
+    quantum_resistant: bool,
+    #[serde(default)]
+    daita: bool,
+}
+
+fn default_quantum_resistant() -> bool {
+    true
 }
 
 impl WireGuardRelay {
@@ -320,6 +455,11 @@ impl WireGuardRelay {
             location,
             relay_list::RelayEndpointData::Wireguard(relay_list::WireguardRelayEndpointData {
                 public_key: self.public_key,
+                // The API does not report this, so every relay is assumed to be capable
+                multihop_entry: true,
+                pluggable_transports: self.pluggable_transports,
+                quantum_resistant: self.quantum_resistant,
+                daita: self.daita,
             }),
         )
     }
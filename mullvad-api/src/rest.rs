@@ -555,15 +555,25 @@ pub async fn deserialize_body<T: serde::de::DeserializeOwned>(response: Response
 }
 
 async fn deserialize_body_inner<T: serde::de::DeserializeOwned>(
-    mut response: Response,
+    response: Response,
     body_length: usize,
 ) -> Result<T> {
+    let body = read_body_inner(response, body_length).await?;
+    serde_json::from_slice(&body).map_err(Error::DeserializeError)
+}
+
+/// Reads the raw body of a response.
+pub async fn read_body(response: Response) -> Result<Vec<u8>> {
+    let body_length = get_body_length(&response);
+    read_body_inner(response, body_length).await
+}
+
+async fn read_body_inner(mut response: Response, body_length: usize) -> Result<Vec<u8>> {
     let mut body: Vec<u8> = Vec::with_capacity(body_length);
     while let Some(chunk) = response.body_mut().next().await {
         body.extend(&chunk?);
     }
-
-    serde_json::from_slice(&body).map_err(Error::DeserializeError)
+    Ok(body)
 }
 
 fn get_body_length(response: &Response) -> usize {
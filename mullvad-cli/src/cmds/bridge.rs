@@ -4,7 +4,10 @@ use mullvad_management_interface::types;
 use mullvad_types::relay_constraints::{
     BridgeConstraints, BridgeSettings, BridgeState, Constraint, LocationConstraint,
 };
-use talpid_types::net::openvpn::{self, SHADOWSOCKS_CIPHERS};
+use talpid_types::net::{
+    openvpn::{self, SHADOWSOCKS_CIPHERS},
+    IpVersion,
+};
 
 use std::{convert::TryFrom, net::SocketAddr};
 
@@ -57,6 +60,11 @@ fn create_bridge_set_subcommand() -> clap::App<'static> {
                         .help("The hosting provider(s) to use, or 'any' for no preference.")
                         .multiple_values(true)
                         .required(true),
+                )
+                .arg(
+                    clap::Arg::new("exclude")
+                        .help("Use any hosting provider except the given ones.")
+                        .long("exclude"),
                 ),
         )
         .subcommand(
@@ -72,6 +80,16 @@ fn create_bridge_set_subcommand() -> clap::App<'static> {
                         .required(true),
                 ),
         )
+        .subcommand(
+            clap::App::new("ip-version")
+                .about("Set the IP version used to connect to bridges")
+                .arg(
+                    clap::Arg::new("ip version")
+                        .help("IP version, or 'any' for no preference.")
+                        .possible_values(["any", "4", "6"])
+                        .required(true),
+                ),
+        )
         .subcommand(location::get_subcommand().about(
             "Set country or city to select bridge relays from. Use the 'list' \
              command to show available alternatives.",
@@ -202,6 +220,9 @@ impl Bridge {
             Some(("ownership", ownership_matches)) => {
                 Self::handle_set_bridge_ownership(ownership_matches).await
             }
+            Some(("ip-version", ip_version_matches)) => {
+                Self::handle_set_bridge_ip_version(ip_version_matches).await
+            }
             Some(("custom", custom_matches)) => {
                 Self::handle_bridge_set_custom_settings(custom_matches).await
             }
@@ -240,6 +261,7 @@ impl Bridge {
             Some(location::get_constraint_from_args(matches)),
             None,
             None,
+            None,
         )
         .await
     }
@@ -251,20 +273,32 @@ impl Bridge {
         } else {
             providers
         };
+        let exclude = matches.is_present("exclude");
 
-        Self::update_bridge_settings(None, Some(providers), None).await
+        Self::update_bridge_settings(None, Some((providers, exclude)), None, None).await
     }
 
     async fn handle_set_bridge_ownership(matches: &clap::ArgMatches) -> Result<()> {
         let ownership =
             super::relay::parse_ownership_constraint(matches.value_of("ownership").unwrap());
-        Self::update_bridge_settings(None, None, Some(ownership)).await
+        Self::update_bridge_settings(None, None, Some(ownership), None).await
+    }
+
+    async fn handle_set_bridge_ip_version(matches: &clap::ArgMatches) -> Result<()> {
+        let ip_version = match matches.value_of("ip version").unwrap() {
+            "any" => Constraint::Any,
+            "4" => Constraint::Only(IpVersion::V4),
+            "6" => Constraint::Only(IpVersion::V6),
+            _ => unreachable!(),
+        };
+        Self::update_bridge_settings(None, None, None, Some(ip_version)).await
     }
 
     async fn update_bridge_settings(
         location: Option<types::RelayLocation>,
-        providers: Option<Vec<String>>,
+        providers: Option<(Vec<String>, bool)>,
         ownership: Option<types::Ownership>,
+        ip_version: Option<Constraint<IpVersion>>,
     ) -> Result<()> {
         let mut rpc = new_rpc_client().await?;
         let settings = rpc.get_settings(()).await?.into_inner();
@@ -275,10 +309,11 @@ impl Bridge {
                 if let Some(new_location) = location {
                     constraints.location = Constraint::<LocationConstraint>::from(new_location);
                 }
-                if let Some(new_providers) = providers {
+                if let Some((new_providers, exclude)) = providers {
                     constraints.providers =
                         types::relay_constraints::try_providers_constraint_from_proto(
                             &new_providers,
+                            exclude,
                         )
                         .unwrap();
                 }
@@ -286,12 +321,16 @@ impl Bridge {
                     constraints.ownership =
                         types::relay_constraints::ownership_constraint_from_proto(new_ownership);
                 }
+                if let Some(new_ip_version) = ip_version {
+                    constraints.ip_version = new_ip_version;
+                }
                 constraints
             }
             _ => {
                 let location = Constraint::<LocationConstraint>::from(location.unwrap_or_default());
+                let (providers, exclude) = providers.unwrap_or_default();
                 let providers = types::relay_constraints::try_providers_constraint_from_proto(
-                    &providers.unwrap_or_default(),
+                    &providers, exclude,
                 )
                 .unwrap();
                 let ownership = ownership
@@ -302,6 +341,7 @@ impl Bridge {
                     location,
                     providers,
                     ownership,
+                    ip_version: ip_version.unwrap_or(Constraint::Any),
                 }
             }
         };
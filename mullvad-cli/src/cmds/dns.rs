@@ -1,7 +1,14 @@
-use crate::{new_rpc_client, Command, Result};
+use crate::{new_rpc_client, Command, Error, Result};
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
 use mullvad_management_interface::types;
-use mullvad_types::settings::{DnsOptions, DnsState};
-use std::{convert::TryInto, net::IpAddr};
+use mullvad_types::{
+    dns_blocklist::{BlocklistSource, DnsBlocklist},
+    settings::{DnsOptions, DnsState, EncryptedDnsResolver, EncryptedDnsUrl},
+};
+use std::{
+    convert::TryInto,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+};
 
 pub struct Dns;
 
@@ -61,8 +68,70 @@ impl Command for Dns {
                                 clap::Arg::new("servers")
                                     .multiple_occurrences(true)
                                     .help("One or more IP addresses pointing to DNS resolvers.")
+                                    .required_unless_present("encrypted"),
+                            )
+                            .arg(
+                                clap::Arg::new("encrypted")
+                                    .long("encrypted")
+                                    .takes_value(true)
+                                    .multiple_occurrences(true)
+                                    .help(
+                                        "URL of a DNS over HTTPS or DNS over TLS resolver, e.g. \
+                                        https://dns.example.com/dns-query or tls://192.0.2.1. \
+                                        If given, queries are only sent to these resolvers, \
+                                        encrypted, and the IP addresses are ignored.",
+                                    ),
+                            ),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("blocklist")
+                    .about(
+                        "Manage lists of domains that are not resolved. Queries are filtered \
+                            locally while any blocklist is enabled",
+                    )
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("list")
+                            .about("Display the blocklists and when they were last updated"),
+                    )
+                    .subcommand(
+                        clap::App::new("add")
+                            .about("Add a blocklist")
+                            .arg(
+                                clap::Arg::new("source")
+                                    .help(
+                                        "HTTP(S) URL or absolute path of the blocklist. Each line \
+                                            is either in hosts file format, a single domain, or an \
+                                            Adblock Plus rule of the form ||example.com^",
+                                    )
                                     .required(true),
+                            )
+                            .arg(
+                                clap::Arg::new("interval")
+                                    .long("interval")
+                                    .takes_value(true)
+                                    .help("Number of hours between updates of the blocklist")
+                                    .default_value("24"),
                             ),
+                    )
+                    .subcommand(
+                        clap::App::new("remove")
+                            .about("Remove a blocklist")
+                            .arg(blocklist_index_arg()),
+                    )
+                    .subcommand(
+                        clap::App::new("enable")
+                            .about("Enable a blocklist")
+                            .arg(blocklist_index_arg()),
+                    )
+                    .subcommand(
+                        clap::App::new("disable")
+                            .about("Disable a blocklist")
+                            .arg(blocklist_index_arg()),
+                    )
+                    .subcommand(
+                        clap::App::new("update").about("Update all enabled blocklists now"),
                     ),
             )
     }
@@ -88,11 +157,19 @@ impl Command for Dns {
                             _ => e.exit(),
                         },
                     };
-                    self.set_custom(servers).await
+                    let encrypted = match matches.values_of_t::<EncryptedDnsUrl>("encrypted") {
+                        Ok(urls) => urls,
+                        Err(e) => match e.kind {
+                            clap::ErrorKind::ArgumentNotFound => vec![],
+                            _ => e.exit(),
+                        },
+                    };
+                    self.set_custom(servers, encrypted).await
                 }
                 _ => unreachable!("No custom-dns server command given"),
             },
             Some(("get", _)) => self.get().await,
+            Some(("blocklist", matches)) => self.blocklist(matches).await,
             _ => unreachable!("No custom-dns command given"),
         }
     }
@@ -125,7 +202,18 @@ impl Dns {
         Ok(())
     }
 
-    async fn set_custom(&self, servers: Option<Vec<IpAddr>>) -> Result<()> {
+    async fn set_custom(
+        &self,
+        servers: Option<Vec<IpAddr>>,
+        encrypted: Vec<EncryptedDnsUrl>,
+    ) -> Result<()> {
+        let encrypted_resolvers = encrypted
+            .into_iter()
+            .map(|url| {
+                resolve_encrypted_url(url)
+                    .map(|resolver| types::EncryptedDnsResolver::from(&resolver))
+            })
+            .collect::<Result<Vec<_>>>()?;
         let mut rpc = new_rpc_client().await?;
         let settings = rpc.get_settings(()).await?.into_inner();
         rpc.set_dns_options(types::DnsOptions {
@@ -136,6 +224,7 @@ impl Dns {
                     .into_iter()
                     .map(|a| a.to_string())
                     .collect(),
+                encrypted_resolvers,
             }),
             ..settings.tunnel_options.unwrap().dns_options.unwrap()
         })
@@ -169,6 +258,12 @@ impl Dns {
                 );
                 println!("Block gambling: {}", options.default_options.block_gambling);
             }
+            DnsState::Custom if !options.custom_options.encrypted_resolvers.is_empty() => {
+                println!("Custom DNS: yes\nEncrypted servers:");
+                for resolver in &options.custom_options.encrypted_resolvers {
+                    println!("{}", resolver);
+                }
+            }
             DnsState::Custom => {
                 println!("Custom DNS: yes\nServers:");
                 for server in &options.custom_options.addresses {
@@ -179,4 +274,124 @@ impl Dns {
 
         Ok(())
     }
+
+    async fn blocklist(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let blocklists = rpc.get_settings(()).await?.into_inner().dns_blocklists;
+                if blocklists.is_empty() {
+                    println!("No DNS blocklists");
+                    return Ok(());
+                }
+                let statuses = rpc
+                    .get_dns_blocklist_status(())
+                    .await?
+                    .into_inner()
+                    .statuses;
+                for (index, blocklist) in blocklists.into_iter().enumerate() {
+                    let blocklist = DnsBlocklist::try_from(blocklist).unwrap();
+                    println!(
+                        "{}: {} ({}, updated every {} hours)",
+                        index,
+                        blocklist.source,
+                        if blocklist.enabled {
+                            "enabled"
+                        } else {
+                            "disabled"
+                        },
+                        blocklist.update_interval
+                    );
+                    match statuses.get(index) {
+                        Some(status) if status.loaded => {
+                            println!("    Domains: {}", status.domains);
+                            if let Some(updated) = &status.updated {
+                                let ndt = NaiveDateTime::from_timestamp(
+                                    updated.seconds,
+                                    updated.nanos as u32,
+                                );
+                                let updated =
+                                    DateTime::<Utc>::from_utc(ndt, Utc).with_timezone(&Local);
+                                println!("    Updated: {}", updated.format("%Y-%m-%d %H:%M"));
+                            }
+                            if !status.error.is_empty() {
+                                println!("    Last update failed: {}", status.error);
+                            }
+                        }
+                        _ if blocklist.enabled => println!("    Not loaded yet"),
+                        _ => (),
+                    }
+                }
+            }
+            Some(("add", matches)) => {
+                let source: BlocklistSource =
+                    matches.value_of("source").unwrap().parse().map_err(|_| {
+                        Error::InvalidCommand(
+                            "The blocklist must be an HTTP(S) URL or an absolute path",
+                        )
+                    })?;
+                let update_interval = matches.value_of_t_or_exit::<u32>("interval");
+                if update_interval == 0 {
+                    return Err(Error::InvalidCommand(
+                        "The update interval must be at least one hour",
+                    ));
+                }
+                let blocklist = DnsBlocklist {
+                    update_interval,
+                    ..DnsBlocklist::new(source)
+                };
+                rpc.add_dns_blocklist(types::DnsBlocklist::from(&blocklist))
+                    .await?;
+                println!("Added DNS blocklist {}", blocklist.source);
+            }
+            Some(("remove", matches)) => {
+                let index = matches.value_of_t_or_exit::<u32>("index");
+                rpc.remove_dns_blocklist(index).await?;
+                println!("Removed DNS blocklist {}", index);
+            }
+            Some((command @ ("enable" | "disable"), matches)) => {
+                let index = matches.value_of_t_or_exit::<u32>("index");
+                let enabled = command == "enable";
+                rpc.set_dns_blocklist_enabled(types::DnsBlocklistEnabled { index, enabled })
+                    .await?;
+                println!(
+                    "{} DNS blocklist {}",
+                    if enabled { "Enabled" } else { "Disabled" },
+                    index
+                );
+            }
+            Some(("update", _)) => {
+                rpc.update_dns_blocklists(()).await?;
+                println!("Updating DNS blocklists");
+            }
+            _ => unreachable!("unhandled command"),
+        }
+        Ok(())
+    }
+}
+
+fn blocklist_index_arg() -> clap::Arg<'static> {
+    clap::Arg::new("index")
+        .help("Index of the blocklist, as displayed by 'list'")
+        .required(true)
+}
+
+/// Looks up the address of a resolver that is given by name. This is done once, when the resolver
+/// is added, since the resolver cannot be used to look up its own address.
+fn resolve_encrypted_url(url: EncryptedDnsUrl) -> Result<EncryptedDnsResolver> {
+    let address = match url.host.parse::<IpAddr>() {
+        Ok(address) => SocketAddr::new(address, url.port),
+        Err(_) => (url.host.as_str(), url.port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or(Error::CommandFailed(
+                "Failed to look up the address of the encrypted DNS server",
+            ))?,
+    };
+    Ok(EncryptedDnsResolver {
+        protocol: url.protocol,
+        server_name: url.host,
+        address,
+    })
 }
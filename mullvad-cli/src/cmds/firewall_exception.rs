@@ -0,0 +1,117 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use mullvad_management_interface::types;
+use talpid_types::{
+    firewall_exception::{Direction, FirewallException as Exception},
+    net::TransportProtocol,
+};
+
+pub struct FirewallException;
+
+#[mullvad_management_interface::async_trait]
+impl Command for FirewallException {
+    fn name(&self) -> &'static str {
+        "firewall-exception"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about(
+                "Allow traffic through the firewall regardless of the tunnel state. Responses \
+                    to the allowed traffic are allowed as well",
+            )
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(clap::App::new("list").about("Display the firewall exceptions"))
+            .subcommand(
+                clap::App::new("add")
+                    .about("Add a firewall exception")
+                    .arg(
+                        clap::Arg::new("direction")
+                            .help("Whether this device or the remote hosts initiate the traffic")
+                            .required(true)
+                            .possible_values(["outgoing", "incoming"]),
+                    )
+                    .arg(
+                        clap::Arg::new("protocol")
+                            .required(true)
+                            .possible_values(["tcp", "udp"]),
+                    )
+                    .arg(
+                        clap::Arg::new("network")
+                            .help("IP address or network in CIDR notation of the remote hosts")
+                            .required(true),
+                    )
+                    .arg(clap::Arg::new("port").help(
+                        "Destination port of the traffic, i.e. the remote port of outgoing \
+                            traffic and the local port of incoming traffic. Any port is allowed \
+                            if this is omitted",
+                    )),
+            )
+            .subcommand(
+                clap::App::new("remove")
+                    .about("Remove a firewall exception")
+                    .arg(
+                        clap::Arg::new("index")
+                            .help("Index of the exception, as displayed by 'list'")
+                            .required(true),
+                    ),
+            )
+            .subcommand(clap::App::new("clear").about("Remove all firewall exceptions"))
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let exceptions = rpc.get_settings(()).await?.into_inner().firewall_exceptions;
+                if exceptions.is_empty() {
+                    println!("No firewall exceptions");
+                }
+                for (index, exception) in exceptions.into_iter().enumerate() {
+                    let exception = Exception::try_from(exception).unwrap();
+                    println!("{}: {}", index, exception);
+                }
+            }
+            Some(("add", matches)) => {
+                let exception = parse_exception(matches)?;
+                rpc.add_firewall_exception(types::FirewallException::from(&exception))
+                    .await?;
+                println!("Added firewall exception for {}", exception);
+            }
+            Some(("remove", matches)) => {
+                let index = matches.value_of_t_or_exit::<u32>("index");
+                rpc.remove_firewall_exception(index).await?;
+                println!("Removed firewall exception {}", index);
+            }
+            Some(("clear", _)) => {
+                rpc.clear_firewall_exceptions(()).await?;
+                println!("Removed all firewall exceptions");
+            }
+            _ => unreachable!("unhandled command"),
+        }
+        Ok(())
+    }
+}
+
+fn parse_exception(matches: &clap::ArgMatches) -> Result<Exception> {
+    let direction = match matches.value_of("direction").unwrap() {
+        "incoming" => Direction::Incoming,
+        _ => Direction::Outgoing,
+    };
+    let protocol: TransportProtocol = matches.value_of_t_or_exit("protocol");
+    let network = matches.value_of("network").unwrap().parse().map_err(|_| {
+        Error::InvalidCommand("The network must be an IP address or a network in CIDR notation")
+    })?;
+    let port = match matches.value_of("port") {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port != 0 => Some(port),
+            _ => return Err(Error::InvalidCommand("Invalid port. Must be 1-65535.")),
+        },
+        None => None,
+    };
+    Ok(Exception {
+        direction,
+        protocol,
+        network,
+        port,
+    })
+}
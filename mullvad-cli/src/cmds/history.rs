@@ -0,0 +1,132 @@
+use crate::{new_rpc_client, Command, Result};
+use mullvad_management_interface::types::{self, Timestamp};
+
+pub struct History;
+
+#[mullvad_management_interface::async_trait]
+impl Command for History {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Display the connection attempts that the daemon has made, oldest first")
+            .arg(
+                clap::Arg::new("count")
+                    .help("Only display the most recent attempts")
+                    .long("count")
+                    .short('n')
+                    .takes_value(true),
+            )
+            .subcommand(clap::App::new("clear").about("Clear the connection history"))
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        if matches.subcommand_matches("clear").is_some() {
+            rpc.clear_connection_history(()).await?;
+            println!("Cleared the connection history");
+            return Ok(());
+        }
+
+        let attempts = rpc.get_connection_history(()).await?.into_inner().attempts;
+        if attempts.is_empty() {
+            println!("No connection attempts have been recorded");
+            return Ok(());
+        }
+        let skip = if matches.is_present("count") {
+            let count = matches.value_of_t_or_exit::<usize>("count");
+            attempts.len().saturating_sub(count)
+        } else {
+            0
+        };
+        for attempt in attempts.iter().skip(skip) {
+            print_attempt(attempt);
+        }
+        Ok(())
+    }
+}
+
+fn print_attempt(attempt: &types::ConnectionAttempt) {
+    let tunnel_type = match types::TunnelType::from_i32(attempt.tunnel_type) {
+        Some(types::TunnelType::Wireguard) => "WireGuard",
+        Some(types::TunnelType::Openvpn) => "OpenVPN",
+        None => "unknown tunnel type",
+    };
+    let endpoint = attempt
+        .endpoint
+        .as_ref()
+        .map(|endpoint| {
+            let protocol = match types::TransportProtocol::from_i32(endpoint.protocol) {
+                Some(types::TransportProtocol::Udp) => "UDP",
+                Some(types::TransportProtocol::Tcp) => "TCP",
+                None => "unknown protocol",
+            };
+            format!("{}/{}", endpoint.address, protocol)
+        })
+        .unwrap_or_default();
+    let relay = if attempt.exit.is_empty() {
+        "custom server"
+    } else {
+        &attempt.exit
+    };
+    println!(
+        "{}: {} to {} ({})",
+        attempt
+            .started
+            .as_ref()
+            .map(format_timestamp)
+            .unwrap_or_default(),
+        tunnel_type,
+        relay,
+        endpoint
+    );
+
+    for (name, hostname) in [
+        ("Entry", &attempt.entry),
+        ("Bridge", &attempt.bridge),
+        ("Obfuscator", &attempt.obfuscator),
+    ] {
+        if !hostname.is_empty() {
+            println!("\t{}: {}", name, hostname);
+        }
+    }
+
+    match (&attempt.connected, &attempt.ended) {
+        (Some(connected), Some(ended)) => println!(
+            "\tConnected for {}",
+            format_duration(ended.seconds - connected.seconds)
+        ),
+        (Some(connected), None) => println!("\tConnected since {}", format_timestamp(connected)),
+        (None, _) => println!("\tNever connected"),
+    }
+    match &attempt.ended {
+        Some(ended) => println!(
+            "\tEnded at {}: {}",
+            format_timestamp(ended),
+            attempt.end_reason
+        ),
+        None => println!("\tIn progress"),
+    }
+}
+
+fn format_timestamp(timestamp: &Timestamp) -> String {
+    let ndt = chrono::NaiveDateTime::from_timestamp(timestamp.seconds, timestamp.nanos as u32);
+    let utc = chrono::DateTime::<chrono::Utc>::from_utc(ndt, chrono::Utc);
+    utc.with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
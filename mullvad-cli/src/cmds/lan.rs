@@ -1,4 +1,8 @@
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::Error;
 use crate::{new_rpc_client, Command, Result};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use mullvad_management_interface::types;
 
 pub struct Lan;
 
@@ -9,7 +13,7 @@ impl Command for Lan {
     }
 
     fn clap_subcommand(&self) -> clap::App<'static> {
-        clap::App::new(self.name())
+        let subcmd = clap::App::new(self.name())
             .about("Control the allow local network sharing setting")
             .setting(clap::AppSettings::SubcommandRequiredElseHelp)
             .subcommand(
@@ -21,17 +25,27 @@ impl Command for Lan {
             )
             .subcommand(
                 clap::App::new("get").about("Display the current local network sharing setting"),
-            )
+            );
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            subcmd.subcommand(create_rules_subcommand())
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        {
+            subcmd
+        }
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
-        if let Some(set_matches) = matches.subcommand_matches("set") {
-            let allow_lan = set_matches.value_of("policy").expect("missing policy");
-            self.set(allow_lan == "allow").await
-        } else if let Some(_matches) = matches.subcommand_matches("get") {
-            self.get().await
-        } else {
-            unreachable!("No lan command given");
+        match matches.subcommand() {
+            Some(("set", set_matches)) => {
+                let allow_lan = set_matches.value_of("policy").expect("missing policy");
+                self.set(allow_lan == "allow").await
+            }
+            Some(("get", _)) => self.get().await,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(("rules", rules_matches)) => self.rules(rules_matches).await,
+            _ => unreachable!("No lan command given"),
         }
     }
 }
@@ -46,11 +60,120 @@ impl Lan {
 
     async fn get(&self) -> Result<()> {
         let mut rpc = new_rpc_client().await?;
-        let allow_lan = rpc.get_settings(()).await?.into_inner().allow_lan;
+        let settings = rpc.get_settings(()).await?.into_inner();
         println!(
             "Local network sharing setting: {}",
-            if allow_lan { "allow" } else { "block" }
+            if settings.allow_lan { "allow" } else { "block" }
         );
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        match settings.lan_rules {
+            Some(rules) => {
+                println!("Allowed local network traffic:");
+                for subnet in &rules.subnets {
+                    println!("    subnet {}", subnet);
+                }
+                for interface in &rules.interfaces {
+                    println!("    interface {}", interface);
+                }
+                for service in rules.services {
+                    let service = match types::lan_rules::Service::from_i32(service) {
+                        Some(types::lan_rules::Service::Mdns) => "mdns",
+                        Some(types::lan_rules::Service::Dhcp) => "dhcp",
+                        Some(types::lan_rules::Service::Printing) => "printing",
+                        None => "unknown",
+                    };
+                    println!("    service {}", service);
+                }
+            }
+            None => println!("Allowed local network traffic: all"),
+        }
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn rules(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let values = |name| -> Vec<String> {
+                    matches
+                        .values_of(name)
+                        .map(|values| values.map(String::from).collect())
+                        .unwrap_or_default()
+                };
+                let rules = types::LanRules {
+                    subnets: values("subnet"),
+                    interfaces: values("interface"),
+                    services: values("service")
+                        .iter()
+                        .map(|service| {
+                            i32::from(match service.as_str() {
+                                "mdns" => types::lan_rules::Service::Mdns,
+                                "dhcp" => types::lan_rules::Service::Dhcp,
+                                _ => types::lan_rules::Service::Printing,
+                            })
+                        })
+                        .collect(),
+                };
+                if rules.subnets.is_empty()
+                    && rules.interfaces.is_empty()
+                    && rules.services.is_empty()
+                {
+                    return Err(Error::InvalidCommand(
+                        "At least one subnet, interface or service must be given",
+                    ));
+                }
+                rpc.set_lan_rules(rules).await?;
+                println!("Changed the allowed local network traffic");
+            }
+            Some(("clear", _)) => {
+                rpc.clear_lan_rules(()).await?;
+                println!("Allowing all local network traffic");
+            }
+            _ => unreachable!("No lan rules command given"),
+        }
         Ok(())
     }
 }
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn create_rules_subcommand() -> clap::App<'static> {
+    clap::App::new("rules")
+        .about(
+            "Restrict the local network traffic that is allowed when local network \
+                sharing is allowed",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::App::new("set")
+                .about(
+                    "Only allow traffic that matches any of the given subnets, \
+                        interfaces or services",
+                )
+                .arg(
+                    clap::Arg::new("subnet")
+                        .long("subnet")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .help("Local network in CIDR notation, e.g. 192.168.1.0/24"),
+                )
+                .arg(
+                    clap::Arg::new("interface")
+                        .long("interface")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .help("Interface over which any local network is reachable"),
+                )
+                .arg(
+                    clap::Arg::new("service")
+                        .long("service")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .possible_values(["mdns", "dhcp", "printing"])
+                        .help("Service that is allowed on any local network"),
+                ),
+        )
+        .subcommand(
+            clap::App::new("clear").about("Allow all local network traffic when it is allowed"),
+        )
+}
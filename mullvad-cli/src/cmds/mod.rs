@@ -25,9 +25,20 @@ pub use self::disconnect::Disconnect;
 mod dns;
 pub use self::dns::Dns;
 
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod firewall_exception;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub use self::firewall_exception::FirewallException;
+
+mod history;
+pub use self::history::History;
+
 mod lan;
 pub use self::lan::Lan;
 
+mod network_rule;
+pub use self::network_rule::NetworkRule;
+
 mod obfuscation;
 pub use self::obfuscation::Obfuscation;
 
@@ -40,11 +51,14 @@ pub use self::relay::Relay;
 mod reset;
 pub use self::reset::Reset;
 
-#[cfg(any(target_os = "linux", windows))]
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
 mod split_tunnel;
-#[cfg(any(target_os = "linux", windows))]
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
 pub use self::split_tunnel::SplitTunnel;
 
+mod schedule;
+pub use self::schedule::Schedule;
+
 mod status;
 pub use self::status::Status;
 
@@ -65,12 +79,17 @@ pub fn get_commands() -> HashMap<&'static str, Box<dyn Command>> {
         Box::new(Connect),
         Box::new(Disconnect),
         Box::new(Dns),
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        Box::new(FirewallException),
+        Box::new(History),
         Box::new(Reconnect),
         Box::new(Lan),
+        Box::new(NetworkRule),
         Box::new(Obfuscation),
         Box::new(Relay),
         Box::new(Reset),
-        #[cfg(any(target_os = "linux", windows))]
+        Box::new(Schedule),
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
         Box::new(SplitTunnel),
         Box::new(Status),
         Box::new(Tunnel),
@@ -0,0 +1,176 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use mullvad_management_interface::{types, ManagementServiceClient};
+use mullvad_types::network_rules::{NetworkRule as NetworkRuleType, NetworkTrust};
+use talpid_types::net::NetworkIdentity;
+
+pub struct NetworkRule;
+
+#[mullvad_management_interface::async_trait]
+impl Command for NetworkRule {
+    fn name(&self) -> &'static str {
+        "network-rule"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about(
+                "Connect or disconnect automatically depending on the network that the host \
+                    is connected to",
+            )
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("current")
+                    .about("Display the network that the host is connected to"),
+            )
+            .subcommand(clap::App::new("list").about("Display all network rules"))
+            .subcommand(
+                clap::App::new("set")
+                    .about(
+                        "Disconnect when joining a trusted network, and connect when joining an \
+                            untrusted one. The current network is used unless another one is given",
+                    )
+                    .args(network_args())
+                    .arg(
+                        clap::Arg::new("trust")
+                            .required(true)
+                            .possible_values(["trusted", "untrusted"]),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("delete")
+                    .about(
+                        "Remove the rule for a network. The current network is used unless \
+                            another one is given",
+                    )
+                    .args(network_args()),
+            )
+            .subcommand(
+                clap::App::new("lockdown")
+                    .about("Block traffic outside the tunnel while on an untrusted network")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change the lockdown setting")
+                            .arg(
+                                clap::Arg::new("policy")
+                                    .required(true)
+                                    .possible_values(["on", "off"]),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get").about("Display the current lockdown setting"),
+                    ),
+            )
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("current", _)) => match Self::get_current_network(&mut rpc).await? {
+                Some(network) => println!("Current network: {}", network),
+                None => println!("Current network: unknown"),
+            },
+            Some(("list", _)) => Self::list(&mut rpc).await?,
+            Some(("set", matches)) => {
+                let network = Self::get_network(&mut rpc, matches).await?;
+                let trust = match matches.value_of("trust").unwrap() {
+                    "trusted" => NetworkTrust::Trusted,
+                    "untrusted" => NetworkTrust::Untrusted,
+                    _ => unreachable!("unhandled network trust"),
+                };
+                rpc.set_network_rule(types::NetworkRule::from(NetworkRuleType {
+                    network: network.clone(),
+                    trust,
+                }))
+                .await?;
+                println!("Marked {} as {}", network, trust);
+            }
+            Some(("delete", matches)) => {
+                let network = Self::get_network(&mut rpc, matches).await?;
+                rpc.remove_network_rule(types::NetworkIdentity::from(network.clone()))
+                    .await?;
+                println!("Removed the rule for {}", network);
+            }
+            Some(("lockdown", matches)) => match matches.subcommand() {
+                Some(("set", matches)) => {
+                    let lockdown = matches.value_of("policy").unwrap() == "on";
+                    rpc.set_network_lockdown(lockdown).await?;
+                    println!("Changed lockdown setting");
+                }
+                Some(("get", _)) => {
+                    let lockdown = rpc
+                        .get_settings(())
+                        .await?
+                        .into_inner()
+                        .network_rules
+                        .map(|rules| rules.lockdown_untrusted)
+                        .unwrap_or(false);
+                    println!(
+                        "Lock down untrusted networks: {}",
+                        if lockdown { "on" } else { "off" }
+                    );
+                }
+                _ => unreachable!("unhandled command"),
+            },
+            _ => unreachable!("unhandled command"),
+        }
+        Ok(())
+    }
+}
+
+impl NetworkRule {
+    async fn list(rpc: &mut ManagementServiceClient) -> Result<()> {
+        let rules = rpc
+            .get_settings(())
+            .await?
+            .into_inner()
+            .network_rules
+            .map(|rules| rules.rules)
+            .unwrap_or_default();
+        if rules.is_empty() {
+            println!("No network rules");
+        }
+        for rule in rules {
+            let rule = NetworkRuleType::try_from(rule).unwrap();
+            println!("{}: {}", rule.network, rule.trust);
+        }
+        Ok(())
+    }
+
+    async fn get_current_network(
+        rpc: &mut ManagementServiceClient,
+    ) -> Result<Option<NetworkIdentity>> {
+        let network = rpc.get_current_network(()).await?.into_inner();
+        Ok(NetworkIdentity::try_from(network).ok())
+    }
+
+    /// Returns the network given by `--wifi` or `--wired`, or the current network.
+    async fn get_network(
+        rpc: &mut ManagementServiceClient,
+        matches: &clap::ArgMatches,
+    ) -> Result<NetworkIdentity> {
+        if let Some(ssid) = matches.value_of("wifi") {
+            return Ok(NetworkIdentity::Wifi(ssid.to_owned()));
+        }
+        if let Some(fingerprint) = matches.value_of("wired") {
+            return Ok(NetworkIdentity::Wired(fingerprint.to_owned()));
+        }
+        Self::get_current_network(rpc)
+            .await?
+            .ok_or(Error::CommandFailed("The current network is unknown"))
+    }
+}
+
+fn network_args() -> [clap::Arg<'static>; 2] {
+    [
+        clap::Arg::new("wifi")
+            .help("SSID of a Wi-Fi network")
+            .long("wifi")
+            .takes_value(true)
+            .conflicts_with("wired"),
+        clap::Arg::new("wired")
+            .help("Gateway and interface of a wired network, as displayed by 'current'")
+            .long("wired")
+            .takes_value(true),
+    ]
+}
@@ -2,7 +2,9 @@ use crate::{new_rpc_client, Command, Result};
 
 use mullvad_management_interface::{types as grpc_types, ManagementServiceClient};
 
-use mullvad_types::relay_constraints::{ObfuscationSettings, SelectedObfuscation};
+use mullvad_types::relay_constraints::{
+    ObfuscationMethod, ObfuscationSettings, SelectedObfuscation,
+};
 
 use std::convert::TryFrom;
 
@@ -63,6 +65,22 @@ impl Obfuscation {
                 };
                 Self::set_obfuscation_settings(&mut rpc, &settings).await?;
             }
+            Some(("priority", priority_matches)) => {
+                let priority = priority_matches
+                    .values_of("methods")
+                    .unwrap()
+                    .map(|method| method.parse::<ObfuscationMethod>().unwrap())
+                    .collect();
+                let mut rpc = new_rpc_client().await?;
+                let mut settings = Self::get_obfuscation_settings(&mut rpc).await?;
+                settings.priority = priority;
+                Self::set_obfuscation_settings(&mut rpc, &settings).await?;
+            }
+            Some(("reuse", reuse_matches)) => {
+                let enabled = reuse_matches.value_of("policy").unwrap() == "on";
+                let mut rpc = new_rpc_client().await?;
+                rpc.set_reuse_obfuscator(enabled).await?;
+            }
             _ => unreachable!("unhandled command"),
         }
         Ok(())
@@ -76,6 +94,20 @@ impl Obfuscation {
             obfuscation_settings.selected_obfuscation
         );
         println!("udp2tcp settings: {}", obfuscation_settings.udp2tcp);
+        println!(
+            "Automatic mode priority: {}",
+            obfuscation_settings
+                .priority
+                .iter()
+                .map(ObfuscationMethod::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let reuse_obfuscator = rpc.get_settings(()).await?.into_inner().reuse_obfuscator;
+        println!(
+            "Reuse obfuscator: {}",
+            if reuse_obfuscator { "on" } else { "off" }
+        );
         Ok(())
     }
 
@@ -130,6 +162,34 @@ fn create_obfuscation_set_subcommand() -> clap::App<'static> {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            clap::App::new("priority")
+                .about(
+                    "Set the order in which the automatic obfuscation mode tries obfuscation \
+                    methods. Each method is used for two connection attempts before moving on \
+                    to the next one",
+                )
+                .arg(
+                    clap::Arg::new("methods")
+                        .required(true)
+                        .index(1)
+                        .multiple_values(true)
+                        .possible_values(["off", "udp2tcp"]),
+                ),
+        )
+        .subcommand(
+            clap::App::new("reuse")
+                .about(
+                    "Specifies whether the obfuscator of the last connection is reused when \
+                    reconnecting, as long as it is still available",
+                )
+                .arg(
+                    clap::Arg::new("policy")
+                        .required(true)
+                        .index(1)
+                        .possible_values(["on", "off"]),
+                ),
+        )
 }
 
 fn create_obfuscation_get_subcommand() -> clap::App<'static> {
@@ -8,7 +8,12 @@ use std::{
 };
 
 use mullvad_management_interface::{types, ManagementServiceClient};
-use mullvad_types::relay_constraints::{Constraint, RelaySettings};
+use mullvad_types::{
+    relay_constraints::{Constraint, PortRanges, ProtocolPreference, RelaySettings},
+    relay_list::RelayListSource,
+    relay_rotation::RelayRotationPolicy,
+    wireguard::QuantumResistantState,
+};
 use talpid_types::net::all_of_the_internet;
 
 pub struct Relay;
@@ -122,6 +127,11 @@ impl Command for Relay {
                                 .multiple_values(true)
                                 .required(true)
                             )
+                            .arg(
+                                clap::Arg::new("exclude")
+                                .help("Use any hosting provider except the given ones.")
+                                .long("exclude")
+                            )
                     )
                     .subcommand(
                         clap::App::new("ownership")
@@ -134,6 +144,19 @@ impl Command for Relay {
                                 .required(true)
                             )
                     )
+                    .subcommand(
+                        clap::App::new("avoid")
+                            .about("Set locations that relays are never selected from. This \
+                                   applies to entry and exit relays, and to bridges.")
+                            .arg(
+                                clap::Arg::new("location")
+                                .help("The locations to avoid, or 'none' to avoid no location. \
+                                       A location is a country code, a country and city code \
+                                       such as 'se-got', or a hostname.")
+                                .multiple_values(true)
+                                .required(true)
+                            )
+                    )
                     .subcommand(
                         clap::App::new("tunnel")
                             .about("Set tunnel protocol-specific constraints.")
@@ -155,6 +178,23 @@ impl Command for Relay {
                                             .possible_values(["any", "udp", "tcp"])
                                             .takes_value(true),
                                     )
+                                    .arg(
+                                        clap::Arg::new("ip version")
+                                            .long("ipv")
+                                            .possible_values(["any", "4", "6"])
+                                            .takes_value(true),
+                                    )
+                                    .arg(
+                                        clap::Arg::new("entry location")
+                                            .help("Entry relay to connect to the exit relay \
+                                                   through, instead of a bridge. This can be \
+                                                   'any', 'none', or any location that is valid \
+                                                   with 'set location', such as 'se got'. \
+                                                   Requires TCP.")
+                                            .long("entry-location")
+                                            .min_values(1)
+                                            .max_values(3),
+                                    )
                             )
                             .subcommand(
                                 clap::App::new("wireguard")
@@ -162,7 +202,10 @@ impl Command for Relay {
                                     .setting(clap::AppSettings::ArgRequiredElseHelp)
                                     .arg(
                                         clap::Arg::new("port")
-                                            .help("Port to use. Either 'any' or a specific port")
+                                            .help("Ports to use. Either 'any' or a comma-separated \
+                                                   list of ports and port ranges, such as \
+                                                   '53,51820-51830'. A random port in the list \
+                                                   is used on each attempt")
                                             .long("port")
                                             .takes_value(true),
                                     )
@@ -181,7 +224,41 @@ impl Command for Relay {
                                             .min_values(1)
                                             .max_values(3),
                                     )
+                                    .arg(
+                                        clap::Arg::new("entry policy")
+                                            .help("How to pick the entry relay when the entry \
+                                                   location is 'any'. 'shortest-path' picks the \
+                                                   relay closest to the path between you and the \
+                                                   exit relay.")
+                                            .long("entry-policy")
+                                            .possible_values(["random", "shortest-path"])
+                                            .takes_value(true),
+                                    )
+                            )
+                    )
+                    .subcommand(
+                        clap::App::new("custom-hop")
+                            .about("Use a custom WireGuard server as one of the hops of a \
+                                    multihop tunnel. The other hop is the relay that matches \
+                                    the location constraints. The server must accept the \
+                                    public key and tunnel addresses of this device")
+                            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(
+                                create_custom_hop_subcommand("entry")
+                                    .about("Enter the tunnel through the custom server"),
                             )
+                            .subcommand(
+                                create_custom_hop_subcommand("exit")
+                                    .about("Leave the tunnel through the custom server")
+                                    .arg(
+                                        clap::Arg::new("v4-gateway")
+                                            .help("IPv4 gateway address of the custom server")
+                                            .required(true),
+                                    ),
+                            )
+                            .subcommand(
+                                clap::App::new("none").about("Stop using a custom server"),
+                            ),
                     )
                     .subcommand(clap::App::new("tunnel-protocol")
                                 .about("Set tunnel protocol")
@@ -194,6 +271,276 @@ impl Command for Relay {
                                 ),
             )
             .subcommand(clap::App::new("get"))
+            .subcommand(
+                clap::App::new("exclude")
+                    .about("Manage relays that are never selected")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("add")
+                            .about("Exclude a relay from being selected")
+                            .arg(
+                                clap::Arg::new("hostname")
+                                    .help("The hostname")
+                                    .required(true),
+                            )
+                            .arg(
+                                clap::Arg::new("session")
+                                    .long("session")
+                                    .help("Only exclude the relay until the daemon restarts"),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("remove")
+                            .about("Allow an excluded relay to be selected again")
+                            .arg(
+                                clap::Arg::new("hostname")
+                                    .help("The hostname")
+                                    .required(true),
+                            )
+                            .arg(
+                                clap::Arg::new("session")
+                                    .long("session")
+                                    .help("Remove a relay excluded until the daemon restarts"),
+                            ),
+                    )
+                    .subcommand(clap::App::new("list").about("List excluded relays")),
+            )
+            .subcommand(
+                clap::App::new("load-aware")
+                    .about("Control whether relays with a high load are less likely to be selected")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change the load-aware selection setting")
+                            .arg(
+                                clap::Arg::new("policy")
+                                    .required(true)
+                                    .possible_values(["on", "off"]),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get")
+                            .about("Display the current load-aware selection setting"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("latency-aware")
+                    .about("Control whether relays with a lower latency are preferred")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change the latency-aware selection setting")
+                            .arg(
+                                clap::Arg::new("policy")
+                                    .required(true)
+                                    .possible_values(["on", "off"]),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get")
+                            .about("Display the current latency-aware selection setting"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("avoid-recent")
+                    .about("Control whether the relays of the most recent connections are avoided")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change how many recently used relays are avoided")
+                            .arg(
+                                clap::Arg::new("count")
+                                    .help("Number of recently used relays to avoid, or 0 to disable")
+                                    .required(true),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get")
+                            .about("Display how many recently used relays are avoided"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("sticky")
+                    .about(
+                        "Control whether the relay of the last successful connection is reused \
+                        when reconnecting",
+                    )
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change after how many failures the relay is no longer reused")
+                            .arg(
+                                clap::Arg::new("max-failures")
+                                    .help("Number of failed connection attempts, or 0 to disable")
+                                    .required(true),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get")
+                            .about("Display after how many failures the relay is no longer reused"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("prefer-ipv6")
+                    .about("Control whether relays are connected to over IPv6 when possible")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change the IPv6 preference setting")
+                            .arg(
+                                clap::Arg::new("policy")
+                                    .required(true)
+                                    .possible_values(["on", "off"]),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get").about("Display the current IPv6 preference setting"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("source")
+                    .about("Control where the relay list is obtained from")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change where the relay list is obtained from")
+                            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(
+                                clap::App::new("api")
+                                    .about("Download the relay list from the Mullvad API"),
+                            )
+                            .subcommand(
+                                clap::App::new("url")
+                                    .about(
+                                        "Download the relay list from a mirror of the Mullvad API",
+                                    )
+                                    .arg(
+                                        clap::Arg::new("url")
+                                            .help("HTTPS URL that serves the relay list")
+                                            .required(true)
+                                            .validator(|url: &str| {
+                                                if url.starts_with("https://") {
+                                                    Ok(())
+                                                } else {
+                                                    Err("The URL must use HTTPS")
+                                                }
+                                            }),
+                                    ),
+                            )
+                            .subcommand(
+                                clap::App::new("file")
+                                    .about(
+                                        "Read the relay list from a file in the format served by \
+                                        the Mullvad API",
+                                    )
+                                    .arg(clap::Arg::new("path").required(true)),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get")
+                            .about("Display where the relay list is obtained from"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("protocol-preference")
+                    .about(
+                        "Control how the tunnel protocol is chosen when any tunnel protocol is \
+                        allowed",
+                    )
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change how the tunnel protocol is chosen")
+                            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(
+                                clap::App::new("auto")
+                                    .about("Choose the tunnel protocol based on the retry attempt"),
+                            )
+                            .subcommand(
+                                clap::App::new("weighted")
+                                    .about(
+                                        "Choose WireGuard or OpenVPN at random, in proportion to \
+                                        the given weights",
+                                    )
+                                    .arg(clap::Arg::new("wireguard").required(true))
+                                    .arg(clap::Arg::new("openvpn").required(true)),
+                            )
+                            .subcommand(
+                                clap::App::new("wireguard-unless-failed").about(
+                                    "Choose WireGuard unless a WireGuard tunnel has failed \
+                                    since the daemon started",
+                                ),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get")
+                            .about("Display how the tunnel protocol is chosen"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("rotation")
+                    .about("Reconnect to a newly selected relay on a schedule")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        clap::App::new("set")
+                            .about("Change when relays are rotated")
+                            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(
+                                clap::App::new("off")
+                                    .about("Do not rotate relays"),
+                            )
+                            .subcommand(
+                                clap::App::new("interval")
+                                    .about("Rotate after being connected to a relay for some time")
+                                    .arg(
+                                        clap::Arg::new("hours")
+                                            .help("Number of hours to stay connected to a relay")
+                                            .required(true),
+                                    ),
+                            )
+                            .subcommand(
+                                clap::App::new("daily")
+                                    .about("Rotate every day at a given local time")
+                                    .arg(
+                                        clap::Arg::new("time")
+                                            .help("Time of day, given as HH:MM")
+                                            .required(true),
+                                    ),
+                            ),
+                    )
+                    .subcommand(
+                        clap::App::new("get").about("Display when relays are rotated"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("preview")
+                    .about("Show the relays that will likely be used by the next connection attempts")
+                    .arg(
+                        clap::Arg::new("count")
+                            .help("Number of connection attempts to show relays for")
+                            .default_value("5"),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("explain")
+                    .about("Explain why the relays of the last connection attempt were selected"),
+            )
+            .subcommand(
+                clap::App::new("check")
+                    .about("Check whether a tunnel can be set up with the current settings"),
+            )
+            .subcommand(
+                clap::App::new("self-test")
+                    .about("Report the countries and cities that no relays can be selected for"),
+            )
+            .subcommand(
+                clap::App::new("stats")
+                    .about("Show connection statistics for the most recently used relays"),
+            )
+            .subcommand(location::get_subcommand().name("bench").about(
+                "Measure the round-trip time to the relays in a location that tunnels may use \
+                given the other relay constraints",
+            ))
             .subcommand(
                 clap::App::new("list").about("List available countries and cities"),
             )
@@ -208,6 +555,37 @@ impl Command for Relay {
             self.set(set_matches).await
         } else if matches.subcommand_matches("get").is_some() {
             self.get().await
+        } else if let Some(exclude_matches) = matches.subcommand_matches("exclude") {
+            self.exclude(exclude_matches).await
+        } else if let Some(load_aware_matches) = matches.subcommand_matches("load-aware") {
+            self.load_aware(load_aware_matches).await
+        } else if let Some(latency_aware_matches) = matches.subcommand_matches("latency-aware") {
+            self.latency_aware(latency_aware_matches).await
+        } else if let Some(avoid_recent_matches) = matches.subcommand_matches("avoid-recent") {
+            self.avoid_recent(avoid_recent_matches).await
+        } else if let Some(sticky_matches) = matches.subcommand_matches("sticky") {
+            self.sticky(sticky_matches).await
+        } else if let Some(prefer_ipv6_matches) = matches.subcommand_matches("prefer-ipv6") {
+            self.prefer_ipv6(prefer_ipv6_matches).await
+        } else if let Some(source_matches) = matches.subcommand_matches("source") {
+            self.source(source_matches).await
+        } else if let Some(preference_matches) = matches.subcommand_matches("protocol-preference") {
+            self.protocol_preference(preference_matches).await
+        } else if let Some(rotation_matches) = matches.subcommand_matches("rotation") {
+            self.rotation(rotation_matches).await
+        } else if let Some(preview_matches) = matches.subcommand_matches("preview") {
+            let count = preview_matches.value_of_t_or_exit("count");
+            self.preview(count).await
+        } else if matches.subcommand_matches("explain").is_some() {
+            self.explain().await
+        } else if matches.subcommand_matches("check").is_some() {
+            self.check().await
+        } else if matches.subcommand_matches("self-test").is_some() {
+            self.self_test().await
+        } else if matches.subcommand_matches("stats").is_some() {
+            self.stats().await
+        } else if let Some(bench_matches) = matches.subcommand_matches("bench") {
+            self.bench(bench_matches).await
         } else if matches.subcommand_matches("list").is_some() {
             self.list().await
         } else if matches.subcommand_matches("update").is_some() {
@@ -239,6 +617,8 @@ impl Relay {
             self.set_providers(providers_matches).await
         } else if let Some(ownership_matches) = matches.subcommand_matches("ownership") {
             self.set_ownership(ownership_matches).await
+        } else if let Some(avoid_matches) = matches.subcommand_matches("avoid") {
+            self.set_avoided_locations(avoid_matches).await
         } else if let Some(matches) = matches.subcommand_matches("tunnel") {
             if let Some(tunnel_matches) = matches.subcommand_matches("openvpn") {
                 self.set_openvpn_constraints(tunnel_matches).await
@@ -247,6 +627,8 @@ impl Relay {
             } else {
                 unreachable!("Invalid tunnel protocol");
             }
+        } else if let Some(custom_hop_matches) = matches.subcommand_matches("custom-hop") {
+            self.set_custom_hop(custom_hop_matches).await
         } else if let Some(tunnel_matches) = matches.subcommand_matches("tunnel-protocol") {
             self.set_tunnel_protocol(tunnel_matches).await
         } else {
@@ -392,6 +774,7 @@ impl Relay {
                                 country: country.code,
                                 city: city.code,
                                 hostname: relay.hostname,
+                                ..Default::default()
                             });
                         }
                     }
@@ -484,11 +867,12 @@ impl Relay {
         } else {
             providers
         };
+        let exclude = matches.is_present("exclude");
 
         self.update_constraints(types::RelaySettingsUpdate {
             r#type: Some(types::relay_settings_update::Type::Normal(
                 types::NormalRelaySettingsUpdate {
-                    providers: Some(types::ProviderUpdate { providers }),
+                    providers: Some(types::ProviderUpdate { providers, exclude }),
                     ..Default::default()
                 },
             )),
@@ -511,12 +895,41 @@ impl Relay {
         .await
     }
 
+    async fn set_avoided_locations(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let locations: Vec<&str> = matches.values_of("location").unwrap().collect();
+        let locations = if locations == ["none"] {
+            vec![]
+        } else {
+            locations
+                .into_iter()
+                .map(parse_avoided_location)
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        self.update_constraints(types::RelaySettingsUpdate {
+            r#type: Some(types::relay_settings_update::Type::Normal(
+                types::NormalRelaySettingsUpdate {
+                    avoided_locations: Some(types::AvoidedLocationsUpdate { locations }),
+                    ..Default::default()
+                },
+            )),
+        })
+        .await
+    }
+
     async fn set_openvpn_constraints(&self, matches: &clap::ArgMatches) -> Result<()> {
         let mut openvpn_constraints = {
             let mut rpc = new_rpc_client().await?;
             self.get_openvpn_constraints(&mut rpc).await?
         };
         openvpn_constraints.port = parse_transport_port(matches, &mut openvpn_constraints.port)?;
+        if let Some(ipv) = matches.value_of("ip version") {
+            openvpn_constraints.ip_version = ip_version_constraint_to_proto(ipv);
+        }
+        if let Some(entry) = matches.values_of("entry location") {
+            openvpn_constraints.entry_location = parse_entry_location_constraint(entry);
+            openvpn_constraints.use_multihop = openvpn_constraints.entry_location.is_some();
+        }
 
         self.update_constraints(types::RelaySettingsUpdate {
             r#type: Some(types::relay_settings_update::Type::Normal(
@@ -563,52 +976,96 @@ impl Relay {
         let mut wireguard_constraints = self.get_wireguard_constraints(&mut rpc).await?;
 
         if let Some(port) = matches.value_of("port") {
-            wireguard_constraints.port = match parse_port_constraint(port)? {
-                Constraint::Any => 0,
-                Constraint::Only(specific_port) => {
-                    let specific_port = u32::from(specific_port);
-
-                    let is_valid_port = relay_list
-                        .port_ranges
-                        .iter()
-                        .any(|range| range.first <= specific_port && specific_port <= range.last);
-                    if !is_valid_port {
-                        return Err(Error::CommandFailed("The specified port is invalid"));
+            wireguard_constraints.port = 0;
+            wireguard_constraints.port_ranges = vec![];
+            if let Constraint::Only(ports) = parse_port_ranges_constraint(port)? {
+                let relay_port_ranges: Vec<(u16, u16)> = relay_list
+                    .port_ranges
+                    .iter()
+                    .map(|range| (range.first as u16, range.last as u16))
+                    .collect();
+                if ports.intersect(&relay_port_ranges).is_empty() {
+                    return Err(Error::CommandFailed("The specified port is invalid"));
+                }
+                match ports.single() {
+                    Some(port) => wireguard_constraints.port = u32::from(port),
+                    None => {
+                        wireguard_constraints.port_ranges = ports
+                            .ranges()
+                            .iter()
+                            .map(|&(first, last)| types::PortRange {
+                                first: u32::from(first),
+                                last: u32::from(last),
+                            })
+                            .collect()
                     }
-
-                    specific_port
                 }
             }
         }
 
         if let Some(ipv) = matches.value_of("ip version") {
-            wireguard_constraints.ip_version =
-                parse_ip_version_constraint(ipv).option().map(|protocol| {
-                    types::IpVersionConstraint {
-                        protocol: protocol as i32,
-                    }
-                });
+            wireguard_constraints.ip_version = ip_version_constraint_to_proto(ipv);
         }
         if let Some(entry) = matches.values_of("entry location") {
             wireguard_constraints.entry_location = parse_entry_location_constraint(entry);
             let use_multihop = wireguard_constraints.entry_location.is_some();
             if use_multihop {
-                let use_pq_safe_psk = rpc
-                    .get_settings(())
-                    .await?
-                    .into_inner()
-                    .tunnel_options
-                    .unwrap()
-                    .wireguard
-                    .unwrap()
-                    .use_pq_safe_psk;
-                if use_pq_safe_psk {
-                    return Err(Error::CommandFailed(
-                        "Quantum resistant tunnels do not work when multihop is enabled",
-                    ));
-                }
+                Self::check_multihop_options(&mut rpc).await?;
             }
             wireguard_constraints.use_multihop = use_multihop;
+            wireguard_constraints.custom_hop = None;
+        }
+        if let Some(entry_policy) = matches.value_of("entry policy") {
+            wireguard_constraints.entry_policy = match entry_policy {
+                "random" => types::EntryRelayPolicy::Random,
+                "shortest-path" => types::EntryRelayPolicy::ShortestPath,
+                _ => unreachable!("Invalid entry policy"),
+            } as i32;
+        }
+
+        self.update_constraints(types::RelaySettingsUpdate {
+            r#type: Some(types::relay_settings_update::Type::Normal(
+                types::NormalRelaySettingsUpdate {
+                    wireguard_constraints: Some(wireguard_constraints),
+                    ..Default::default()
+                },
+            )),
+        })
+        .await
+    }
+
+    async fn set_custom_hop(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let mut wireguard_constraints = self.get_wireguard_constraints(&mut rpc).await?;
+
+        match matches.subcommand() {
+            Some(("none", _)) => {
+                wireguard_constraints.custom_hop = None;
+                wireguard_constraints.use_multihop = wireguard_constraints.entry_location.is_some();
+            }
+            Some((hop, hop_matches)) => {
+                Self::check_multihop_options(&mut rpc).await?;
+                let (hop, ipv4_gateway) = match hop {
+                    "entry" => (types::custom_multihop_relay::Hop::Entry, String::new()),
+                    "exit" => (
+                        types::custom_multihop_relay::Hop::Exit,
+                        hop_matches
+                            .value_of_t_or_exit::<Ipv4Addr>("v4-gateway")
+                            .to_string(),
+                    ),
+                    _ => unreachable!("Invalid custom hop"),
+                };
+                let peer_key_str: String = hop_matches.value_of_t_or_exit("peer-pubkey");
+                wireguard_constraints.custom_hop = Some(types::CustomMultihopRelay {
+                    hop: hop as i32,
+                    host: hop_matches.value_of_t_or_exit("host"),
+                    port: u32::from(hop_matches.value_of_t_or_exit::<u16>("port")),
+                    public_key: Self::validate_wireguard_key(&peer_key_str).to_vec(),
+                    ipv4_gateway,
+                });
+                wireguard_constraints.use_multihop = true;
+            }
+            None => unreachable!("No custom hop given"),
         }
 
         self.update_constraints(types::RelaySettingsUpdate {
@@ -622,6 +1079,33 @@ impl Relay {
         .await
     }
 
+    /// Fails if a setting that does not work with multihop is enabled.
+    async fn check_multihop_options(rpc: &mut ManagementServiceClient) -> Result<()> {
+        let wireguard_options = rpc
+            .get_settings(())
+            .await?
+            .into_inner()
+            .tunnel_options
+            .unwrap()
+            .wireguard
+            .unwrap();
+        let quantum_resistant = wireguard_options
+            .quantum_resistant
+            .map(|state| QuantumResistantState::try_from(state).unwrap())
+            .unwrap_or_default();
+        if quantum_resistant.is_enabled() {
+            return Err(Error::CommandFailed(
+                "Quantum resistant tunnels do not work when multihop is enabled",
+            ));
+        }
+        if wireguard_options.ephemeral_keys {
+            return Err(Error::CommandFailed(
+                "Ephemeral keys do not work when multihop is enabled",
+            ));
+        }
+        Ok(())
+    }
+
     async fn get_wireguard_constraints(
         &self,
         rpc: &mut ManagementServiceClient,
@@ -667,6 +1151,357 @@ impl Relay {
         .await
     }
 
+    async fn load_aware(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let enabled = matches.value_of("policy").unwrap() == "on";
+                rpc.set_load_aware_selection(enabled).await?;
+                println!("Changed load-aware selection setting");
+            }
+            Some(("get", _)) => {
+                let enabled = rpc
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .load_aware_selection;
+                println!(
+                    "Load-aware selection: {}",
+                    if enabled { "on" } else { "off" }
+                );
+            }
+            _ => unreachable!("No load-aware command given"),
+        }
+        Ok(())
+    }
+
+    async fn latency_aware(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let enabled = matches.value_of("policy").unwrap() == "on";
+                rpc.set_latency_aware_selection(enabled).await?;
+                println!("Changed latency-aware selection setting");
+            }
+            Some(("get", _)) => {
+                let enabled = rpc
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .latency_aware_selection;
+                println!(
+                    "Latency-aware selection: {}",
+                    if enabled { "on" } else { "off" }
+                );
+            }
+            _ => unreachable!("No latency-aware command given"),
+        }
+        Ok(())
+    }
+
+    async fn avoid_recent(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let count: u32 = matches.value_of_t_or_exit("count");
+                rpc.set_avoid_recent_relays(count).await?;
+                println!("Changed the number of recently used relays to avoid");
+            }
+            Some(("get", _)) => {
+                let count = rpc.get_settings(()).await?.into_inner().avoid_recent_relays;
+                if count == 0 {
+                    println!("Recently used relays: not avoided");
+                } else {
+                    println!("Recently used relays: avoiding the last {}", count);
+                }
+            }
+            _ => unreachable!("No avoid-recent command given"),
+        }
+        Ok(())
+    }
+
+    async fn sticky(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let max_failures: u32 = matches.value_of_t_or_exit("max-failures");
+                rpc.set_sticky_relay(max_failures).await?;
+                println!("Changed sticky relay setting");
+            }
+            Some(("get", _)) => {
+                let max_failures = rpc
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .sticky_relay_max_failures;
+                if max_failures == 0 {
+                    println!("Sticky relay: off");
+                } else {
+                    println!("Sticky relay: reused until it fails {} times", max_failures);
+                }
+            }
+            _ => unreachable!("No sticky command given"),
+        }
+        Ok(())
+    }
+
+    async fn prefer_ipv6(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let enabled = matches.value_of("policy").unwrap() == "on";
+                rpc.set_prefer_ipv6(enabled).await?;
+                println!("Changed IPv6 preference setting");
+            }
+            Some(("get", _)) => {
+                let enabled = rpc.get_settings(()).await?.into_inner().prefer_ipv6;
+                println!("Prefer IPv6: {}", if enabled { "on" } else { "off" });
+            }
+            _ => unreachable!("No prefer-ipv6 command given"),
+        }
+        Ok(())
+    }
+
+    async fn source(&self, matches: &clap::ArgMatches) -> Result<()> {
+        use types::relay_list_source::Source;
+
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let source = match matches.subcommand() {
+                    Some(("api", _)) => Source::Api(()),
+                    Some(("url", matches)) => Source::Url(matches.value_of("url").unwrap().into()),
+                    Some(("file", matches)) => {
+                        // The daemon does not share the working directory of the CLI
+                        let path = matches.value_of("path").unwrap();
+                        let path = match std::fs::canonicalize(path) {
+                            Ok(path) => path,
+                            Err(error) => clap::Error::raw(
+                                clap::ErrorKind::InvalidValue,
+                                format!("Cannot use {}: {}", path, error),
+                            )
+                            .exit(),
+                        };
+                        Source::File(path.to_string_lossy().into_owned())
+                    }
+                    _ => unreachable!("No relay list source given"),
+                };
+                rpc.set_relay_list_source(types::RelayListSource {
+                    source: Some(source),
+                })
+                .await
+                .map_err(|error| Error::RpcFailedExt("Failed to set relay list source", error))?;
+                println!("Changed relay list source");
+            }
+            Some(("get", _)) => {
+                let source = rpc
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .relay_list_source
+                    .unwrap();
+                println!(
+                    "Relay list source: {}",
+                    RelayListSource::try_from(source).unwrap()
+                );
+            }
+            _ => unreachable!("No relay list source command given"),
+        }
+        Ok(())
+    }
+
+    async fn protocol_preference(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let preference = match matches.subcommand() {
+                    Some(("auto", _)) => None,
+                    Some(("weighted", matches)) => Some(ProtocolPreference::Weighted {
+                        wireguard: matches.value_of_t_or_exit("wireguard"),
+                        openvpn: matches.value_of_t_or_exit("openvpn"),
+                    }),
+                    Some(("wireguard-unless-failed", _)) => {
+                        Some(ProtocolPreference::WireguardUnlessFailed)
+                    }
+                    _ => unreachable!("No protocol preference given"),
+                };
+                rpc.set_protocol_preference(types::ProtocolPreference::from(preference))
+                    .await
+                    .map_err(|error| {
+                        Error::RpcFailedExt("Failed to set protocol preference", error)
+                    })?;
+                println!("Changed protocol preference");
+            }
+            Some(("get", _)) => {
+                let preference = rpc
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .protocol_preference
+                    .unwrap();
+                match Option::<ProtocolPreference>::try_from(preference).unwrap() {
+                    Some(preference) => println!("Protocol preference: {}", preference),
+                    None => println!("Protocol preference: auto"),
+                }
+            }
+            _ => unreachable!("No protocol preference command given"),
+        }
+        Ok(())
+    }
+
+    async fn rotation(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("set", matches)) => {
+                let policy = match matches.subcommand() {
+                    Some(("off", _)) => RelayRotationPolicy::Off,
+                    Some(("interval", matches)) => RelayRotationPolicy::Interval {
+                        hours: matches.value_of_t_or_exit("hours"),
+                    },
+                    Some(("daily", matches)) => {
+                        let (hour, minute) = parse_time_of_day(matches.value_of("time").unwrap());
+                        RelayRotationPolicy::Daily { hour, minute }
+                    }
+                    _ => unreachable!("No relay rotation policy given"),
+                };
+                rpc.set_relay_rotation_policy(types::RelayRotationPolicy::from(policy))
+                    .await
+                    .map_err(|error| {
+                        Error::RpcFailedExt("Failed to set relay rotation policy", error)
+                    })?;
+                println!("Changed relay rotation policy");
+            }
+            Some(("get", _)) => {
+                let policy = rpc
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .tunnel_options
+                    .unwrap()
+                    .relay_rotation
+                    .unwrap();
+                println!(
+                    "Relay rotation: {}",
+                    RelayRotationPolicy::try_from(policy).unwrap()
+                );
+            }
+            _ => unreachable!("No relay rotation command given"),
+        }
+        Ok(())
+    }
+
+    async fn exclude(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let session_hostnames = rpc
+            .get_session_excluded_relays(())
+            .await?
+            .into_inner()
+            .hostnames;
+        match matches.subcommand() {
+            Some(("add", matches)) if matches.is_present("session") => {
+                let hostname = matches.value_of("hostname").unwrap().to_lowercase();
+                if session_hostnames.contains(&hostname) {
+                    println!("{} is already excluded", hostname);
+                    return Ok(());
+                }
+                if !Self::relay_exists(&hostname).await? {
+                    eprintln!("Warning: No matching relay was found.");
+                }
+                let mut hostnames = session_hostnames;
+                hostnames.push(hostname);
+                return Self::set_session_excluded_relays(&mut rpc, hostnames).await;
+            }
+            Some(("remove", matches)) if matches.is_present("session") => {
+                let hostname = matches.value_of("hostname").unwrap().to_lowercase();
+                let mut hostnames = session_hostnames;
+                let num_excluded = hostnames.len();
+                hostnames.retain(|excluded| excluded != &hostname);
+                if hostnames.len() == num_excluded {
+                    return Err(Error::CommandFailed("The relay is not excluded"));
+                }
+                return Self::set_session_excluded_relays(&mut rpc, hostnames).await;
+            }
+            _ => (),
+        }
+
+        let mut hostnames = match rpc
+            .get_settings(())
+            .await?
+            .into_inner()
+            .relay_settings
+            .unwrap()
+            .endpoint
+            .unwrap()
+        {
+            types::relay_settings::Endpoint::Normal(settings) => settings.excluded_relays,
+            types::relay_settings::Endpoint::Custom(_settings) => vec![],
+        };
+
+        match matches.subcommand() {
+            Some(("add", matches)) => {
+                let hostname = matches.value_of("hostname").unwrap().to_lowercase();
+                if hostnames.contains(&hostname) {
+                    println!("{} is already excluded", hostname);
+                    return Ok(());
+                }
+                if !Self::relay_exists(&hostname).await? {
+                    eprintln!("Warning: No matching relay was found.");
+                }
+                hostnames.push(hostname);
+            }
+            Some(("remove", matches)) => {
+                let hostname = matches.value_of("hostname").unwrap().to_lowercase();
+                let num_excluded = hostnames.len();
+                hostnames.retain(|excluded| excluded != &hostname);
+                if hostnames.len() == num_excluded {
+                    return Err(Error::CommandFailed("The relay is not excluded"));
+                }
+            }
+            Some(("list", _)) => {
+                if hostnames.is_empty() && session_hostnames.is_empty() {
+                    println!("No relays are excluded");
+                }
+                for hostname in hostnames {
+                    println!("{}", hostname);
+                }
+                for hostname in session_hostnames {
+                    println!("{} (until the daemon restarts)", hostname);
+                }
+                return Ok(());
+            }
+            _ => unreachable!("No exclude command given"),
+        }
+
+        self.update_constraints(types::RelaySettingsUpdate {
+            r#type: Some(types::relay_settings_update::Type::Normal(
+                types::NormalRelaySettingsUpdate {
+                    excluded_relays: Some(types::ExcludedRelaysUpdate { hostnames }),
+                    ..Default::default()
+                },
+            )),
+        })
+        .await
+    }
+
+    async fn set_session_excluded_relays(
+        rpc: &mut ManagementServiceClient,
+        hostnames: Vec<String>,
+    ) -> Result<()> {
+        rpc.set_session_excluded_relays(types::ExcludedRelaysUpdate { hostnames })
+            .await?;
+        println!("Excluded relays updated until the daemon restarts");
+        Ok(())
+    }
+
+    async fn relay_exists(hostname: &str) -> Result<bool> {
+        let countries = Self::get_filtered_relays().await?;
+        Ok(countries
+            .iter()
+            .flat_map(|country| &country.cities)
+            .flat_map(|city| &city.relays)
+            .any(|relay| relay.hostname.to_lowercase() == hostname))
+    }
+
     async fn get(&self) -> Result<()> {
         let mut rpc = new_rpc_client().await?;
         let relay_settings = rpc
@@ -684,6 +1519,157 @@ impl Relay {
         Ok(())
     }
 
+    async fn explain(&self) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match rpc.explain_relay_selection(()).await {
+            Ok(explanation) => println!("{}", explanation.into_inner()),
+            Err(status) if status.code() == mullvad_management_interface::Code::NotFound => {
+                println!("No relays have been selected yet")
+            }
+            Err(status) => {
+                return Err(Error::RpcFailedExt(
+                    "Failed to explain the relay selection",
+                    status,
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match rpc.check_can_connect(()).await {
+            Ok(_) => println!("A tunnel can be set up with the current settings"),
+            Err(status)
+                if status.code() == mullvad_management_interface::Code::FailedPrecondition =>
+            {
+                println!("No tunnel can be set up: {}", status.message())
+            }
+            Err(status) => {
+                return Err(Error::RpcFailedExt(
+                    "Failed to check the current settings",
+                    status,
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    async fn self_test(&self) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let report = rpc
+            .run_self_test(())
+            .await
+            .map_err(|error| Error::RpcFailedExt("Failed to run relay self-test", error))?
+            .into_inner();
+        for failure in &report.failures {
+            let location = failure
+                .location
+                .as_ref()
+                .map(|location| {
+                    [&location.country, &location.city, &location.hostname]
+                        .iter()
+                        .filter(|part| !part.is_empty())
+                        .map(|part| part.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_default();
+            println!("{}: {}", location, failure.reason);
+        }
+        println!(
+            "{} locations passed, {} failed",
+            report.passed,
+            report.failures.len()
+        );
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let mut stats = rpc
+            .get_relay_stats(())
+            .await
+            .map_err(|error| Error::RpcFailedExt("Failed to get relay statistics", error))?
+            .into_inner()
+            .relays;
+        if stats.is_empty() {
+            println!("No relays have been used yet");
+        }
+        stats.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        for stat in stats {
+            println!(
+                "{}: selected {} times, {} failed, {} succeeded",
+                stat.hostname, stat.selected, stat.failures, stat.successes
+            );
+        }
+        Ok(())
+    }
+
+    async fn preview(&self, count: u32) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let previews = rpc
+            .preview_relays(count)
+            .await
+            .map_err(|error| Error::RpcFailedExt("Failed to preview relays", error))?
+            .into_inner()
+            .relays;
+        if previews.is_empty() {
+            println!("No relays can be selected");
+        }
+        for (attempt, preview) in previews.into_iter().enumerate() {
+            let tunnel_type = match types::TunnelType::from_i32(preview.tunnel_type) {
+                Some(types::TunnelType::Openvpn) => "OpenVPN",
+                Some(types::TunnelType::Wireguard) => "WireGuard",
+                None => "unknown tunnel type",
+            };
+            let mut line = format!("Attempt {}: {} via {}", attempt, preview.exit, tunnel_type);
+            if !preview.entry.is_empty() {
+                line.push_str(&format!(", entering through {}", preview.entry));
+            }
+            if !preview.bridge.is_empty() {
+                line.push_str(&format!(", bridge {}", preview.bridge));
+            }
+            if !preview.obfuscator.is_empty() {
+                line.push_str(&format!(", obfuscated through {}", preview.obfuscator));
+            }
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    async fn bench(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let location = location::get_constraint_from_args(matches);
+        let mut rpc = new_rpc_client().await?;
+        let benchmarks = rpc
+            .benchmark_relays(location)
+            .await
+            .map_err(|error| Error::RpcFailedExt("Failed to benchmark relays", error))?
+            .into_inner()
+            .relays;
+        if benchmarks.is_empty() {
+            println!("No relays match the location and relay constraints");
+        }
+        let format_rtt = |rtt: Option<types::Duration>| match rtt {
+            Some(rtt) => format!(
+                "{} ms",
+                rtt.seconds * 1000 + i64::from(rtt.nanos / 1_000_000)
+            ),
+            None => "-".to_string(),
+        };
+        for benchmark in benchmarks {
+            println!(
+                "{}: median {}, min {}, {}/{} responses",
+                benchmark.hostname,
+                format_rtt(benchmark.median_rtt),
+                format_rtt(benchmark.min_rtt),
+                benchmark.responses,
+                benchmark.probes,
+            );
+        }
+        Ok(())
+    }
+
     async fn list(&self) -> Result<()> {
         let mut countries = Self::get_filtered_relays().await?;
         countries.sort_by(|c1, c2| natord::compare_ignore_case(&c1.name, &c2.name));
@@ -769,6 +1755,21 @@ impl Relay {
     }
 }
 
+fn parse_time_of_day(raw_time: &str) -> (u32, u32) {
+    let time = raw_time
+        .split_once(':')
+        .and_then(|(hour, minute)| Some((hour.parse().ok()?, minute.parse().ok()?)))
+        .filter(|&(hour, minute)| hour < 24 && minute < 60);
+    match time {
+        Some(time) => time,
+        None => clap::Error::raw(
+            clap::ErrorKind::InvalidValue,
+            format!("Invalid time of day: {}", raw_time),
+        )
+        .exit(),
+    }
+}
+
 fn parse_port_constraint(raw_port: &str) -> Result<Constraint<u16>> {
     match raw_port.to_lowercase().as_str() {
         "any" => Ok(Constraint::Any),
@@ -778,6 +1779,20 @@ fn parse_port_constraint(raw_port: &str) -> Result<Constraint<u16>> {
     }
 }
 
+fn parse_port_ranges_constraint(raw_ports: &str) -> Result<Constraint<PortRanges>> {
+    match raw_ports.to_lowercase().as_str() {
+        "any" => Ok(Constraint::Any),
+        ports => Ok(Constraint::Only(PortRanges::from_str(ports).map_err(
+            |_| {
+                Error::InvalidCommand(
+                "Invalid port. Must be \"any\", a port, or a comma-separated list of ports and \
+                 port ranges, such as \"53,51820-51830\".",
+            )
+            },
+        )?)),
+    }
+}
+
 fn parse_protocol(raw_protocol: &str) -> Constraint<types::TransportProtocol> {
     match raw_protocol {
         "any" => Constraint::Any,
@@ -796,6 +1811,29 @@ fn parse_ip_version_constraint(raw_protocol: &str) -> Constraint<types::IpVersio
     }
 }
 
+pub fn ip_version_constraint_to_proto(raw_protocol: &str) -> Option<types::IpVersionConstraint> {
+    parse_ip_version_constraint(raw_protocol)
+        .option()
+        .map(|protocol| types::IpVersionConstraint {
+            protocol: protocol as i32,
+        })
+}
+
+fn create_custom_hop_subcommand(name: &'static str) -> clap::App<'static> {
+    clap::App::new(name)
+        .arg(clap::Arg::new("host").help("Hostname or IP").required(true))
+        .arg(
+            clap::Arg::new("port")
+                .help("Remote network port")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::new("peer-pubkey")
+                .help("Base64 encoded peer public key")
+                .required(true),
+        )
+}
+
 fn parse_entry_location_constraint<'a, T: Iterator<Item = &'a str>>(
     mut location: T,
 ) -> Option<types::RelayLocation> {
@@ -812,6 +1850,32 @@ fn parse_entry_location_constraint<'a, T: Iterator<Item = &'a str>>(
     ))
 }
 
+/// Parses a location given as `<country>`, `<country>-<city>`, or a hostname such as
+/// `se-got-wg-001`, whose country and city are the first two parts of the hostname.
+fn parse_avoided_location(raw_location: &str) -> Result<types::RelayLocation> {
+    let location = raw_location.to_lowercase();
+    let parts: Vec<&str> = location.split('-').collect();
+    let valid = location::country_code_validator(parts[0]).is_ok()
+        && parts
+            .get(1)
+            .map_or(true, |city| location::city_code_validator(city).is_ok());
+    if !valid || parts[0] == "any" || parts[0] == "nearest" {
+        return Err(Error::InvalidCommand(
+            "Invalid location. Must be a country code, a country and city code such as \
+             \"se-got\", or a hostname.",
+        ));
+    }
+
+    Ok(match parts.as_slice() {
+        [country] => location::get_constraint(*country, None, None),
+        [country, city] => location::get_constraint(*country, Some(*city), None),
+        [country, city, ..] => {
+            location::get_constraint(*country, Some(*city), Some(location.as_str()))
+        }
+        [] => unreachable!("split always returns at least one part"),
+    })
+}
+
 fn parse_transport_port(
     matches: &clap::ArgMatches,
     current_constraint: &mut Option<types::TransportPort>,
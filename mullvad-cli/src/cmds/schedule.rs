@@ -0,0 +1,144 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, Utc, Weekday};
+use mullvad_management_interface::types;
+use mullvad_types::schedule::ConnectionSchedule;
+
+const WEEKDAYS: [Weekday; 5] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+
+pub struct Schedule;
+
+#[mullvad_management_interface::async_trait]
+impl Command for Schedule {
+    fn name(&self) -> &'static str {
+        "schedule"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about(
+                "Connect and disconnect automatically at set times. The tunnel is connected when \
+                    a scheduled period starts, and disconnected when it ends",
+            )
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("list")
+                    .about("Display the schedules and whether a scheduled period is ongoing"),
+            )
+            .subcommand(
+                clap::App::new("add")
+                    .about("Add a period during which the tunnel should be connected")
+                    .arg(
+                        clap::Arg::new("days")
+                            .help(
+                                "Days on which the period starts. Either 'daily', 'weekdays', \
+                                    'weekends', or a comma-separated list of days, e.g. 'mon,wed'",
+                            )
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::new("start")
+                            .help("Start of the period in local time, e.g. 09:00")
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::new("end")
+                            .help(
+                                "End of the period in local time, e.g. 17:00. The period ends on \
+                                    the following day if this is not after the start",
+                            )
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("remove").about("Remove a schedule").arg(
+                    clap::Arg::new("index")
+                        .help("Index of the schedule, as displayed by 'list'")
+                        .required(true),
+                ),
+            )
+            .subcommand(clap::App::new("clear").about("Remove all schedules"))
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let schedules = rpc.get_settings(()).await?.into_inner().schedules;
+                if schedules.is_empty() {
+                    println!("No schedules");
+                    return Ok(());
+                }
+                for (index, schedule) in schedules.into_iter().enumerate() {
+                    let schedule = ConnectionSchedule::try_from(schedule).unwrap();
+                    println!("{}: {}", index, schedule);
+                }
+
+                let state = rpc.get_schedule_state(()).await?.into_inner();
+                println!(
+                    "Scheduled period: {}",
+                    if state.active { "ongoing" } else { "none" }
+                );
+                if let Some(next_change) = state.next_change {
+                    let ndt = NaiveDateTime::from_timestamp(
+                        next_change.seconds,
+                        next_change.nanos as u32,
+                    );
+                    let next_change = DateTime::<Utc>::from_utc(ndt, Utc).with_timezone(&Local);
+                    println!("Next change: {}", next_change.format("%a %Y-%m-%d %H:%M"));
+                }
+            }
+            Some(("add", matches)) => {
+                let schedule = ConnectionSchedule {
+                    days: parse_days(matches.value_of("days").unwrap())?,
+                    start: parse_time(matches.value_of("start").unwrap())?,
+                    end: parse_time(matches.value_of("end").unwrap())?,
+                };
+                rpc.add_schedule(types::ConnectionSchedule::from(schedule.clone()))
+                    .await?;
+                println!("Added schedule {}", schedule);
+            }
+            Some(("remove", matches)) => {
+                let index = matches.value_of_t_or_exit::<u32>("index");
+                rpc.remove_schedule(index).await?;
+                println!("Removed schedule {}", index);
+            }
+            Some(("clear", _)) => {
+                rpc.clear_schedules(()).await?;
+                println!("Removed all schedules");
+            }
+            _ => unreachable!("unhandled command"),
+        }
+        Ok(())
+    }
+}
+
+fn parse_days(days: &str) -> Result<Vec<Weekday>> {
+    match days {
+        "daily" => Ok(WEEKDAYS
+            .iter()
+            .copied()
+            .chain([Weekday::Sat, Weekday::Sun])
+            .collect()),
+        "weekdays" => Ok(WEEKDAYS.to_vec()),
+        "weekends" => Ok(vec![Weekday::Sat, Weekday::Sun]),
+        days => days
+            .split(',')
+            .map(|day| {
+                day.trim()
+                    .parse()
+                    .map_err(|_| Error::InvalidCommand("Invalid day of the week"))
+            })
+            .collect(),
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| Error::InvalidCommand("Times must be given as HH:MM"))
+}
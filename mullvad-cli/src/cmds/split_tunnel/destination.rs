@@ -0,0 +1,66 @@
+use crate::{new_rpc_client, Result};
+
+pub fn create_destination_subcommand() -> clap::App<'static> {
+    clap::App::new("destination")
+        .about("Manage networks and domains to exclude from the tunnel")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::App::new("add").arg(
+                clap::Arg::new("destination")
+                    .help("IP address, network in CIDR notation, or domain name")
+                    .required(true),
+            ),
+        )
+        .subcommand(clap::App::new("delete").arg(clap::Arg::new("destination").required(true)))
+        .subcommand(clap::App::new("clear"))
+        .subcommand(clap::App::new("list"))
+}
+
+pub async fn handle_destination_cmd(matches: &clap::ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("add", matches)) => {
+            let destination: String = matches.value_of_t_or_exit("destination");
+            new_rpc_client()
+                .await?
+                .add_excluded_destination(destination)
+                .await?;
+            Ok(())
+        }
+        Some(("delete", matches)) => {
+            let destination: String = matches.value_of_t_or_exit("destination");
+            new_rpc_client()
+                .await?
+                .remove_excluded_destination(destination)
+                .await?;
+            Ok(())
+        }
+        Some(("clear", _)) => {
+            new_rpc_client()
+                .await?
+                .clear_excluded_destinations(())
+                .await?;
+            Ok(())
+        }
+        Some(("list", _)) => {
+            let destinations = new_rpc_client()
+                .await?
+                .get_settings(())
+                .await?
+                .into_inner()
+                .excluded_destinations
+                .unwrap_or_default();
+
+            println!("Excluded networks:");
+            for network in &destinations.networks {
+                println!("    {}", network);
+            }
+            println!("Excluded domains:");
+            for domain in &destinations.domains {
+                println!("    {}", domain);
+            }
+
+            Ok(())
+        }
+        _ => unreachable!("unhandled command"),
+    }
+}
@@ -1,3 +1,4 @@
+use super::{destination, mode};
 use crate::{new_rpc_client, Command, Result};
 
 pub struct SplitTunnel;
@@ -16,11 +17,17 @@ impl Command for SplitTunnel {
             )
             .setting(clap::AppSettings::SubcommandRequiredElseHelp)
             .subcommand(create_pid_subcommand())
+            .subcommand(destination::create_destination_subcommand())
+            .subcommand(mode::create_mode_subcommand())
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
         match matches.subcommand() {
             Some(("pid", pid_matches)) => Self::handle_pid_cmd(pid_matches).await,
+            Some(("destination", destination_matches)) => {
+                destination::handle_destination_cmd(destination_matches).await
+            }
+            Some(("mode", mode_matches)) => mode::handle_mode_cmd(mode_matches).await,
             _ => unreachable!("unhandled comand"),
         }
     }
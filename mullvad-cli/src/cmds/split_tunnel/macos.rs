@@ -0,0 +1,63 @@
+use super::{destination, mode};
+use crate::{new_rpc_client, Command, Result};
+
+pub struct SplitTunnel;
+
+#[mullvad_management_interface::async_trait]
+impl Command for SplitTunnel {
+    fn name(&self) -> &'static str {
+        "split-tunnel"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about(
+                "Manage split tunneling. To launch applications outside \
+                    the tunnel, use the program 'mullvad-exclude' instead of this command.",
+            )
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(create_pid_subcommand())
+            .subcommand(destination::create_destination_subcommand())
+            .subcommand(mode::create_mode_subcommand())
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("pid", pid_matches)) => Self::handle_pid_cmd(pid_matches).await,
+            Some(("destination", destination_matches)) => {
+                destination::handle_destination_cmd(destination_matches).await
+            }
+            Some(("mode", mode_matches)) => mode::handle_mode_cmd(mode_matches).await,
+            _ => unreachable!("unhandled comand"),
+        }
+    }
+}
+
+fn create_pid_subcommand() -> clap::App<'static> {
+    clap::App::new("pid")
+        .about("Display processes that are excluded from the tunnel")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list"))
+}
+
+impl SplitTunnel {
+    async fn handle_pid_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let mut pids_stream = new_rpc_client()
+                    .await?
+                    .get_split_tunnel_processes(())
+                    .await?
+                    .into_inner();
+                println!("Excluded PIDs:");
+
+                while let Some(pid) = pids_stream.message().await? {
+                    println!("    {}", pid);
+                }
+
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
@@ -2,9 +2,18 @@
 #[path = "linux.rs"]
 mod imp;
 
+#[cfg(target_os = "macos")]
+#[path = "macos.rs"]
+mod imp;
+
 #[cfg(windows)]
 #[path = "windows.rs"]
 mod imp;
 
-#[cfg(any(target_os = "linux", windows))]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod destination;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod mode;
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
 pub use imp::*;
@@ -0,0 +1,48 @@
+use crate::{new_rpc_client, Result};
+use mullvad_management_interface::types;
+use talpid_types::split_tunnel::SplitTunnelMode;
+
+pub fn create_mode_subcommand() -> clap::App<'static> {
+    clap::App::new("mode")
+        .about("Control whether split tunneled applications are excluded from the tunnel")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::App::new("set")
+                .about(
+                    "Change the split tunnel mode. In the include mode, applications \
+                        launched with 'mullvad-exclude' are the only ones that use the tunnel",
+                )
+                .arg(
+                    clap::Arg::new("mode")
+                        .required(true)
+                        .possible_values(["exclude", "include"]),
+                ),
+        )
+        .subcommand(clap::App::new("get").about("Display the current split tunnel mode"))
+}
+
+pub async fn handle_mode_cmd(matches: &clap::ArgMatches) -> Result<()> {
+    let mut rpc = new_rpc_client().await?;
+    match matches.subcommand() {
+        Some(("set", matches)) => {
+            let mode = match matches.value_of("mode").unwrap() {
+                "exclude" => SplitTunnelMode::Exclude,
+                "include" => SplitTunnelMode::Include,
+                _ => unreachable!("unhandled split tunnel mode"),
+            };
+            rpc.set_split_tunnel_mode(types::SplitTunnelMode::from(mode))
+                .await?;
+            println!("Changed split tunnel mode");
+        }
+        Some(("get", _)) => {
+            let settings = rpc.get_settings(()).await?.into_inner();
+            let mode = settings
+                .split_tunnel_mode
+                .map(|mode| SplitTunnelMode::try_from(mode).unwrap())
+                .unwrap_or_default();
+            println!("Split tunnel mode: {}", mode);
+        }
+        _ => unreachable!("unhandled command"),
+    }
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use crate::{format, new_rpc_client, Command, Error, Result};
 use mullvad_management_interface::{
-    types::daemon_event::Event as EventType, ManagementServiceClient,
+    types::{self, daemon_event::Event as EventType},
+    ManagementServiceClient,
 };
 use mullvad_types::{location::GeoIpLocation, states::TunnelState};
 
@@ -33,6 +34,10 @@ impl Command for Status {
                     .help("Enables debug output"),
             )
             .subcommand(clap::App::new("listen").about("Listen for VPN tunnel state changes"))
+            .subcommand(
+                clap::App::new("traffic")
+                    .about("Display the traffic through the VPN tunnel as it is sampled"),
+            )
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
@@ -48,6 +53,20 @@ impl Command for Status {
         } else {
             let state = TunnelState::try_from(state).expect("invalid tunnel state");
             format::print_state(&state, verbose);
+            if verbose
+                && matches!(
+                    state,
+                    TunnelState::Connected { .. } | TunnelState::Connecting { .. }
+                )
+            {
+                print_relay_chain(&mut rpc).await?;
+            }
+            if verbose && matches!(state, TunnelState::Connected { .. }) {
+                let stats = rpc.get_traffic_stats(()).await?.into_inner();
+                if let Some(sample) = stats.samples.last() {
+                    print_traffic_sample(sample);
+                }
+            }
         }
 
         if show_full_location {
@@ -103,14 +122,41 @@ impl Command for Status {
                             println!("Remove device event: {:#?}", device);
                         }
                     }
+                    EventType::RelayRotation(rotation) => {
+                        if debug {
+                            println!("Relay rotation event: {:#?}", rotation);
+                        }
+                    }
+                    EventType::RelaySelection(selection) => {
+                        if debug {
+                            println!("Relay selection event: {:#?}", selection);
+                        }
+                    }
                 }
             }
         }
 
+        if matches.subcommand_matches("traffic").is_some() {
+            let mut samples = rpc.traffic_stats_listen(()).await?.into_inner();
+            while let Some(sample) = samples.message().await? {
+                print_traffic_sample(&sample);
+            }
+        }
+
         Ok(())
     }
 }
 
+fn print_traffic_sample(sample: &types::TrafficSample) {
+    println!(
+        "Traffic: {}/s down, {}/s up ({} received, {} sent)",
+        format::format_bytes(sample.rx_rate),
+        format::format_bytes(sample.tx_rate),
+        format::format_bytes(sample.rx_bytes),
+        format::format_bytes(sample.tx_bytes),
+    );
+}
+
 async fn print_location(rpc: &mut ManagementServiceClient) -> Result<()> {
     let location = match rpc.get_current_location(()).await {
         Ok(response) => GeoIpLocation::try_from(response.into_inner()).expect("invalid geoip data"),
@@ -136,3 +182,73 @@ async fn print_location(rpc: &mut ManagementServiceClient) -> Result<()> {
     );
     Ok(())
 }
+
+async fn print_relay_chain(rpc: &mut ManagementServiceClient) -> Result<()> {
+    let chain = match rpc.get_last_relay_chain(()).await {
+        Ok(response) => response.into_inner(),
+        Err(status) if status.code() == mullvad_management_interface::Code::NotFound => {
+            return Ok(())
+        }
+        Err(status) => return Err(Error::RpcFailed(status)),
+    };
+
+    let format_hop = |hop: &types::RelayHop| {
+        let endpoint = hop
+            .endpoint
+            .as_ref()
+            .map(|endpoint| {
+                format!(
+                    "{}/{}",
+                    endpoint.address,
+                    format_protocol(endpoint.protocol)
+                )
+            })
+            .unwrap_or_default();
+        if hop.hostname.is_empty() {
+            format!("custom server at {}", endpoint)
+        } else {
+            format!("{} ({})", hop.hostname, endpoint)
+        }
+    };
+
+    println!("Relay chain:");
+    if let Some(obfuscation) = &chain.obfuscation {
+        let obfuscation_type = match types::ObfuscationType::from_i32(obfuscation.obfuscation_type)
+        {
+            Some(types::ObfuscationType::Udp2tcp) => "UDP-over-TCP",
+            Some(types::ObfuscationType::PluggableTransport) => "pluggable transport",
+            None => "unknown obfuscation",
+        };
+        let hostname = if chain.obfuscator.is_empty() {
+            "obfuscator"
+        } else {
+            &chain.obfuscator
+        };
+        println!(
+            "\tObfuscator: {} ({}:{}/{}, {})",
+            hostname,
+            obfuscation.address,
+            obfuscation.port,
+            format_protocol(obfuscation.protocol),
+            obfuscation_type
+        );
+    }
+    if let Some(bridge) = &chain.bridge {
+        println!("\tBridge: {}", format_hop(bridge));
+    }
+    if let Some(entry) = &chain.entry {
+        println!("\tEntry: {}", format_hop(entry));
+    }
+    if let Some(exit) = &chain.exit {
+        println!("\tExit: {}", format_hop(exit));
+    }
+    Ok(())
+}
+
+fn format_protocol(protocol: i32) -> &'static str {
+    match types::TransportProtocol::from_i32(protocol) {
+        Some(types::TransportProtocol::Udp) => "UDP",
+        Some(types::TransportProtocol::Tcp) => "TCP",
+        None => "unknown protocol",
+    }
+}
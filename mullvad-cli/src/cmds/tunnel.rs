@@ -1,6 +1,9 @@
 use crate::{new_rpc_client, Command, Error, Result};
-use mullvad_management_interface::types::{self, Timestamp, TunnelOptions};
-use mullvad_types::wireguard::DEFAULT_ROTATION_INTERVAL;
+use mullvad_management_interface::{
+    types::{self, Timestamp, TunnelOptions},
+    ManagementServiceClient,
+};
+use mullvad_types::wireguard::{PortFallback, QuantumResistantState, DEFAULT_ROTATION_INTERVAL};
 use std::{convert::TryFrom, time::Duration};
 
 pub struct Tunnel;
@@ -38,7 +41,11 @@ fn create_wireguard_subcommand() -> clap::App<'static> {
         .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .subcommand(create_wireguard_mtu_subcommand())
         .subcommand(create_wireguard_quantum_resistant_tunnel_subcommand())
-        .subcommand(create_wireguard_keys_subcommand());
+        .subcommand(create_wireguard_ephemeral_keys_subcommand())
+        .subcommand(create_wireguard_daita_subcommand())
+        .subcommand(create_wireguard_port_fallback_subcommand())
+        .subcommand(create_wireguard_keys_subcommand())
+        .subcommand(create_wireguard_export_subcommand());
     #[cfg(windows)]
     {
         subcmd.subcommand(create_wireguard_use_wg_nt_subcommand())
@@ -55,7 +62,13 @@ fn create_wireguard_mtu_subcommand() -> clap::App<'static> {
         .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .subcommand(clap::App::new("get"))
         .subcommand(clap::App::new("unset"))
-        .subcommand(clap::App::new("set").arg(clap::Arg::new("mtu").required(true)))
+        .subcommand(
+            clap::App::new("set").arg(
+                clap::Arg::new("mtu")
+                    .help("MTU in bytes, or 'auto' to lower the default MTU if it turns out to be too large after connecting")
+                    .required(true),
+            ),
+        )
 }
 
 fn create_wireguard_quantum_resistant_tunnel_subcommand() -> clap::App<'static> {
@@ -63,7 +76,66 @@ fn create_wireguard_quantum_resistant_tunnel_subcommand() -> clap::App<'static>
         .about("EXPERIMENTAL: Enables quantum-resistant PSK exchange in the tunnel")
         .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .subcommand(clap::App::new("get"))
-        .subcommand(clap::App::new("set").arg(clap::Arg::new("policy").required(true)))
+        .subcommand(
+            clap::App::new("set").arg(
+                clap::Arg::new("policy")
+                    .required(true)
+                    .possible_values(["auto", "on", "off"]),
+            ),
+        )
+}
+
+fn create_wireguard_ephemeral_keys_subcommand() -> clap::App<'static> {
+    clap::App::new("ephemeral-keys")
+        .about("Use a new key for every connection, registered with the relay using the device key")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("get"))
+        .subcommand(
+            clap::App::new("set").arg(
+                clap::Arg::new("policy")
+                    .required(true)
+                    .possible_values(["on", "off"]),
+            ),
+        )
+}
+
+fn create_wireguard_daita_subcommand() -> clap::App<'static> {
+    clap::App::new("daita")
+        .about("Pad packets and send dummy traffic to defend against traffic analysis. Only relays that support DAITA are used")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("get"))
+        .subcommand(
+            clap::App::new("set").arg(
+                clap::Arg::new("policy")
+                    .required(true)
+                    .possible_values(["on", "off"]),
+            ),
+        )
+}
+
+fn create_wireguard_port_fallback_subcommand() -> clap::App<'static> {
+    clap::App::new("port-fallback")
+        .about(
+            "Configure the ports that later connection attempts fall back to when no port is set",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("get"))
+        .subcommand(
+            clap::App::new("set")
+                .setting(clap::AppSettings::ArgRequiredElseHelp)
+                .arg(
+                    clap::Arg::new("port 53")
+                        .long("port-53")
+                        .takes_value(true)
+                        .possible_values(["on", "off"]),
+                )
+                .arg(
+                    clap::Arg::new("port 443")
+                        .long("port-443")
+                        .takes_value(true)
+                        .possible_values(["on", "off"]),
+                ),
+        )
 }
 
 fn create_wireguard_keys_subcommand() -> clap::App<'static> {
@@ -75,6 +147,13 @@ fn create_wireguard_keys_subcommand() -> clap::App<'static> {
         .subcommand(create_wireguard_keys_rotation_interval_subcommand())
 }
 
+fn create_wireguard_export_subcommand() -> clap::App<'static> {
+    clap::App::new("export").about(
+        "Print the last WireGuard tunnel as a wg-quick config. \
+            The config contains the private key of this device",
+    )
+}
+
 #[cfg(windows)]
 fn create_wireguard_use_wg_nt_subcommand() -> clap::App<'static> {
     clap::App::new("use-wireguard-nt")
@@ -180,6 +259,26 @@ impl Tunnel {
                 _ => unreachable!("unhandled command"),
             },
 
+            Some(("ephemeral-keys", matches)) => match matches.subcommand() {
+                Some(("get", _)) => Self::process_wireguard_ephemeral_keys_get().await,
+                Some(("set", matches)) => Self::process_wireguard_ephemeral_keys_set(matches).await,
+                _ => unreachable!("unhandled command"),
+            },
+
+            Some(("daita", matches)) => match matches.subcommand() {
+                Some(("get", _)) => Self::process_wireguard_daita_get().await,
+                Some(("set", matches)) => Self::process_wireguard_daita_set(matches).await,
+                _ => unreachable!("unhandled command"),
+            },
+
+            Some(("port-fallback", matches)) => match matches.subcommand() {
+                Some(("get", _)) => Self::process_wireguard_port_fallback_get().await,
+                Some(("set", matches)) => Self::process_wireguard_port_fallback_set(matches).await,
+                _ => unreachable!("unhandled command"),
+            },
+
+            Some(("export", _)) => Self::process_wireguard_export().await,
+
             #[cfg(windows)]
             Some(("use-wireguard-nt", matches)) => match matches.subcommand() {
                 Some(("get", _)) => Self::process_wireguard_use_wg_nt_get().await,
@@ -193,11 +292,13 @@ impl Tunnel {
 
     async fn process_wireguard_mtu_get() -> Result<()> {
         let tunnel_options = Self::get_tunnel_options().await?;
-        let mtu = tunnel_options.wireguard.unwrap().mtu;
+        let wireguard_options = tunnel_options.wireguard.unwrap();
         println!(
             "mtu: {}",
-            if mtu != 0 {
-                mtu.to_string()
+            if wireguard_options.mtu != 0 {
+                wireguard_options.mtu.to_string()
+            } else if wireguard_options.mtu_detection {
+                "auto".to_string()
             } else {
                 "unset".to_string()
             },
@@ -206,9 +307,16 @@ impl Tunnel {
     }
 
     async fn process_wireguard_mtu_set(matches: &clap::ArgMatches) -> Result<()> {
-        let mtu = matches.value_of_t_or_exit::<u16>("mtu");
         let mut rpc = new_rpc_client().await?;
+        if matches.value_of("mtu") == Some("auto") {
+            rpc.set_wireguard_mtu(0).await?;
+            rpc.set_wireguard_mtu_detection(true).await?;
+            println!("Wireguard MTU will be detected after connecting");
+            return Ok(());
+        }
+        let mtu = matches.value_of_t_or_exit::<u16>("mtu");
         rpc.set_wireguard_mtu(mtu as u32).await?;
+        rpc.set_wireguard_mtu_detection(false).await?;
         println!("Wireguard MTU has been updated");
         Ok(())
     }
@@ -216,51 +324,137 @@ impl Tunnel {
     async fn process_wireguard_mtu_unset() -> Result<()> {
         let mut rpc = new_rpc_client().await?;
         rpc.set_wireguard_mtu(0).await?;
+        rpc.set_wireguard_mtu_detection(false).await?;
         println!("Wireguard MTU has been unset");
         Ok(())
     }
 
     async fn process_wireguard_quantum_resistant_tunnel_get() -> Result<()> {
         let tunnel_options = Self::get_tunnel_options().await?;
-        if tunnel_options.wireguard.unwrap().use_pq_safe_psk {
-            println!("enabled");
-        } else {
-            println!("disabled");
-        }
+        let state = tunnel_options
+            .wireguard
+            .unwrap()
+            .quantum_resistant
+            .map(|state| QuantumResistantState::try_from(state).unwrap())
+            .unwrap_or_default();
+        println!("{}", state);
         Ok(())
     }
 
     async fn process_wireguard_quantum_resistant_tunnel_set(
         matches: &clap::ArgMatches,
     ) -> Result<()> {
-        let use_pq_safe_psk = matches.value_of("policy").unwrap() == "on";
+        let state = match matches.value_of("policy").unwrap() {
+            "auto" => QuantumResistantState::Auto,
+            "on" => QuantumResistantState::On,
+            "off" => QuantumResistantState::Off,
+            _ => unreachable!(),
+        };
         let mut rpc = new_rpc_client().await?;
-        let settings = rpc.get_settings(()).await?;
-        if use_pq_safe_psk {
-            let multihop_is_enabled = settings
-                .into_inner()
-                .relay_settings
-                .unwrap()
-                .endpoint
-                .and_then(|endpoint| {
-                    if let types::relay_settings::Endpoint::Normal(settings) = endpoint {
-                        Some(settings.wireguard_constraints.unwrap().use_multihop)
-                    } else {
-                        None
-                    }
-                })
-                .unwrap_or(false);
-            if multihop_is_enabled {
-                return Err(Error::CommandFailed(
-                    "Quantum resistant tunnels do not work when multihop is enabled",
-                ));
-            }
+        if state.is_enabled() && Self::multihop_is_enabled(&mut rpc).await? {
+            return Err(Error::CommandFailed(
+                "Quantum resistant tunnels do not work when multihop is enabled",
+            ));
         }
-        rpc.set_quantum_resistant_tunnel(use_pq_safe_psk).await?;
+        rpc.set_quantum_resistant_tunnel(types::QuantumResistantState::from(state))
+            .await?;
         println!("Updated quantum resistant tunnel setting");
         Ok(())
     }
 
+    async fn process_wireguard_ephemeral_keys_get() -> Result<()> {
+        let tunnel_options = Self::get_tunnel_options().await?;
+        if tunnel_options.wireguard.unwrap().ephemeral_keys {
+            println!("enabled");
+        } else {
+            println!("disabled");
+        }
+        Ok(())
+    }
+
+    async fn process_wireguard_ephemeral_keys_set(matches: &clap::ArgMatches) -> Result<()> {
+        let enabled = matches.value_of("policy").unwrap() == "on";
+        let mut rpc = new_rpc_client().await?;
+        if enabled && Self::multihop_is_enabled(&mut rpc).await? {
+            return Err(Error::CommandFailed(
+                "Ephemeral keys do not work when multihop is enabled",
+            ));
+        }
+        rpc.set_wireguard_ephemeral_keys(enabled).await?;
+        println!("Updated ephemeral key setting");
+        Ok(())
+    }
+
+    async fn process_wireguard_daita_get() -> Result<()> {
+        let tunnel_options = Self::get_tunnel_options().await?;
+        if tunnel_options.wireguard.unwrap().daita {
+            println!("enabled");
+        } else {
+            println!("disabled");
+        }
+        Ok(())
+    }
+
+    async fn process_wireguard_daita_set(matches: &clap::ArgMatches) -> Result<()> {
+        let enabled = matches.value_of("policy").unwrap() == "on";
+        let mut rpc = new_rpc_client().await?;
+        rpc.set_wireguard_daita(enabled).await?;
+        println!("Updated DAITA setting");
+        Ok(())
+    }
+
+    async fn process_wireguard_port_fallback_get() -> Result<()> {
+        let tunnel_options = Self::get_tunnel_options().await?;
+        let port_fallback = tunnel_options
+            .wireguard
+            .unwrap()
+            .port_fallback
+            .map(PortFallback::from)
+            .unwrap_or_default();
+        println!("Fallback ports: {}", port_fallback);
+        Ok(())
+    }
+
+    async fn process_wireguard_port_fallback_set(matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let mut port_fallback = rpc
+            .get_settings(())
+            .await?
+            .into_inner()
+            .tunnel_options
+            .unwrap()
+            .wireguard
+            .unwrap()
+            .port_fallback
+            .unwrap_or_default();
+        if let Some(policy) = matches.value_of("port 53") {
+            port_fallback.port_53 = policy == "on";
+        }
+        if let Some(policy) = matches.value_of("port 443") {
+            port_fallback.port_443 = policy == "on";
+        }
+        rpc.set_wireguard_port_fallback(port_fallback).await?;
+        println!("Updated fallback ports");
+        Ok(())
+    }
+
+    async fn multihop_is_enabled(rpc: &mut ManagementServiceClient) -> Result<bool> {
+        let settings = rpc.get_settings(()).await?;
+        Ok(settings
+            .into_inner()
+            .relay_settings
+            .unwrap()
+            .endpoint
+            .and_then(|endpoint| {
+                if let types::relay_settings::Endpoint::Normal(settings) = endpoint {
+                    Some(settings.wireguard_constraints.unwrap().use_multihop)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(false))
+    }
+
     #[cfg(windows)]
     async fn process_wireguard_use_wg_nt_get() -> Result<()> {
         let tunnel_options = Self::get_tunnel_options().await?;
@@ -314,6 +508,23 @@ impl Tunnel {
         Ok(())
     }
 
+    async fn process_wireguard_export() -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        match rpc.export_wireguard_config(()).await {
+            Ok(config) => print!("{}", config.into_inner()),
+            Err(status) if status.code() == mullvad_management_interface::Code::NotFound => {
+                println!("No single-hop WireGuard tunnel has been set up yet")
+            }
+            Err(status) => {
+                return Err(Error::RpcFailedExt(
+                    "Failed to export WireGuard config",
+                    status,
+                ))
+            }
+        }
+        Ok(())
+    }
+
     async fn process_wireguard_rotation_interval_get() -> Result<()> {
         let tunnel_options = Self::get_tunnel_options().await?;
         match tunnel_options.wireguard.unwrap().rotation_interval {
@@ -154,6 +154,24 @@ fn format_endpoint(hostname: Option<&str>, endpoint: &Endpoint, verbose: bool) -
     }
 }
 
+/// Formats a number of bytes using the largest unit that keeps the value above one.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["kB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = UNITS[0];
+    for next_unit in UNITS {
+        value /= 1000.0;
+        unit = next_unit;
+        if value < 1000.0 {
+            break;
+        }
+    }
+    format!("{value:.1} {unit}")
+}
+
 fn print_error_state(error_state: &ErrorState) {
     if error_state.block_failure().is_some() {
         eprintln!("Mullvad daemon failed to setup firewall rules!");
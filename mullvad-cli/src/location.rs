@@ -4,7 +4,10 @@ pub fn get_subcommand() -> clap::App<'static> {
     clap::App::new("location")
         .arg(
             clap::Arg::new("country")
-                .help("The two letter country code, or 'any' for no preference.")
+                .help(
+                    "The two letter country code, 'any' for no preference, or 'nearest' for the \
+                     city closest to you.",
+                )
                 .required(true)
                 .index(1)
                 .validator(country_code_validator),
@@ -42,6 +45,15 @@ pub fn get_constraint<T: AsRef<str>>(
             "City can't be given when selecting 'any' country",
         )
         .exit(),
+        ("nearest", None, None) => RelayLocation {
+            nearest: true,
+            ..Default::default()
+        },
+        ("nearest", ..) => clap::Error::raw(
+            clap::ErrorKind::InvalidValue,
+            "City can't be given when selecting the 'nearest' location",
+        )
+        .exit(),
         (_, None, None) => RelayLocation {
             country,
             ..Default::default()
@@ -55,6 +67,7 @@ pub fn get_constraint<T: AsRef<str>>(
             country,
             city,
             hostname,
+            ..Default::default()
         },
         (..) => clap::Error::raw(
             clap::ErrorKind::InvalidValue,
@@ -65,10 +78,12 @@ pub fn get_constraint<T: AsRef<str>>(
 }
 
 pub fn country_code_validator(code: &str) -> std::result::Result<(), String> {
-    if code.len() == 2 || code == "any" {
+    if code.len() == 2 || code == "any" || code == "nearest" {
         Ok(())
     } else {
-        Err(String::from("Country codes must be two letters, or 'any'."))
+        Err(String::from(
+            "Country codes must be two letters, or 'any' or 'nearest'.",
+        ))
     }
 }
 
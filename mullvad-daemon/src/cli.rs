@@ -59,6 +59,12 @@ lazy_static::lazy_static! {
     MULLVAD_RPC_SOCKET_PATH    Location of the management interface device.
                                It refers to Unix domain socket on Unix based platforms, and named pipe on Windows.
                                [Default: {}]
+    MULLVAD_RELAY_CONSTRAINTS_OVERRIDE
+                               Relay constraints, as JSON, that take precedence over the settings
+                               when selecting relays. Intended for testing.
+    MULLVAD_WIREGUARD_ENDPOINT_OVERRIDE
+                               Socket address that WireGuard tunnels connect to instead of the
+                               selected relay, e.g. a local forwarder.
 
 ",
         mullvad_paths::get_default_resource_dir().display(),
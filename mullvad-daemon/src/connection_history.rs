@@ -0,0 +1,153 @@
+//! Keeps a bounded log of connection attempts on disk, for debugging intermittent disconnects.
+
+use crate::tunnel::SelectionSnapshot;
+use chrono::Utc;
+use mullvad_types::connection_history::ConnectionAttempt;
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+use talpid_types::{net::TunnelEndpoint, ErrorExt};
+use tokio::{fs, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Failed to serialize connection history")]
+    Serialize(#[error(source)] serde_json::Error),
+
+    #[error(display = "Unable to write connection history file")]
+    Write(#[error(source)] io::Error),
+}
+
+const CONNECTION_HISTORY_FILE: &str = "connection-history.json";
+
+/// Maximum number of connection attempts that are kept. The oldest attempts are removed first.
+const MAX_ATTEMPTS: usize = 500;
+
+pub struct ConnectionHistory {
+    path: PathBuf,
+    attempts: VecDeque<ConnectionAttempt>,
+}
+
+impl ConnectionHistory {
+    /// Loads the connection history from `cache_dir`. The history starts out empty if it cannot
+    /// be read.
+    pub async fn load(cache_dir: &Path) -> Self {
+        let path = cache_dir.join(CONNECTION_HISTORY_FILE);
+        let attempts = match fs::read_to_string(&path).await {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|error| {
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to parse connection history")
+                );
+                VecDeque::new()
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(error) => {
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to read connection history")
+                );
+                VecDeque::new()
+            }
+        };
+        ConnectionHistory { path, attempts }
+    }
+
+    /// Returns the recorded connection attempts, oldest first.
+    pub fn attempts(&self) -> Vec<ConnectionAttempt> {
+        self.attempts.iter().cloned().collect()
+    }
+
+    /// Records a new connection attempt to `endpoint`, using the relays in `selection`. An attempt
+    /// that is still in progress is ended first.
+    pub async fn start_attempt(
+        &mut self,
+        endpoint: &TunnelEndpoint,
+        selection: Option<SelectionSnapshot>,
+    ) {
+        self.end_current(|attempt| {
+            if attempt.connected.is_some() {
+                "Reconnected"
+            } else {
+                "Failed to connect"
+            }
+            .to_owned()
+        });
+        if self.attempts.len() >= MAX_ATTEMPTS {
+            self.attempts.pop_front();
+        }
+        let selection = selection.filter(|selection| selection.tunnel_type == endpoint.tunnel_type);
+        self.attempts.push_back(ConnectionAttempt {
+            started: Utc::now(),
+            tunnel_type: endpoint.tunnel_type,
+            endpoint: endpoint.endpoint,
+            exit: selection
+                .as_ref()
+                .and_then(|selection| selection.exit.clone()),
+            entry: selection
+                .as_ref()
+                .and_then(|selection| selection.entry.clone()),
+            bridge: selection
+                .as_ref()
+                .and_then(|selection| selection.bridge.clone()),
+            obfuscator: selection.and_then(|selection| selection.obfuscator),
+            connected: None,
+            ended: None,
+            end_reason: None,
+        });
+        self.save_or_log().await;
+    }
+
+    /// Marks the attempt in progress as connected.
+    pub async fn set_connected(&mut self) {
+        match self.attempts.back_mut() {
+            Some(attempt) if attempt.ended.is_none() => attempt.connected = Some(Utc::now()),
+            _ => return,
+        }
+        self.save_or_log().await;
+    }
+
+    /// Ends the attempt in progress, if there is one, because of `reason`.
+    pub async fn end_attempt(&mut self, reason: String) {
+        if self.end_current(|_| reason) {
+            self.save_or_log().await;
+        }
+    }
+
+    /// Removes all recorded connection attempts.
+    pub async fn clear(&mut self) -> Result<()> {
+        self.attempts.clear();
+        self.save().await
+    }
+
+    /// Ends the attempt in progress with the reason returned by `reason`. Returns whether there
+    /// was such an attempt.
+    fn end_current(&mut self, reason: impl FnOnce(&ConnectionAttempt) -> String) -> bool {
+        match self.attempts.back_mut() {
+            Some(attempt) if attempt.ended.is_none() => {
+                attempt.end_reason = Some(reason(attempt));
+                attempt.ended = Some(Utc::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn save(&self) -> Result<()> {
+        let data = serde_json::to_string(&self.attempts).map_err(Error::Serialize)?;
+        fs::write(&self.path, data).await.map_err(Error::Write)
+    }
+
+    async fn save_or_log(&self) {
+        if let Err(error) = self.save().await {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to save connection history")
+            );
+        }
+    }
+}
@@ -781,7 +781,11 @@ impl AccountManager {
         match api_result {
             Ok(wg_data) => {
                 log::debug!("Replacing WireGuard key");
-                config.device.wg_data = wg_data;
+                let allowed_relays = config.device.wg_data.allowed_relays.take();
+                config.device.wg_data = WireguardData {
+                    allowed_relays,
+                    ..wg_data
+                };
                 match self.set(PrivateDeviceEvent::RotatedKey(config)).await {
                     Ok(_) => {
                         Self::drain_requests(&mut self.rotation_requests, || Ok(()));
@@ -68,6 +68,8 @@ impl DeviceService {
                         private_key,
                         addresses,
                         created: Utc::now(),
+                        allowed_relays: None,
+                        expiry: None,
                     },
                 )?,
             })
@@ -100,6 +102,8 @@ impl DeviceService {
                     private_key,
                     addresses,
                     created: Utc::now(),
+                    allowed_relays: None,
+                    expiry: None,
                 },
             )?,
         })
@@ -183,6 +187,8 @@ impl DeviceService {
             private_key,
             addresses,
             created: Utc::now(),
+            allowed_relays: None,
+            expiry: None,
         })
     }
 
@@ -215,6 +221,8 @@ impl DeviceService {
             private_key,
             addresses,
             created: Utc::now(),
+            allowed_relays: None,
+            expiry: None,
         })
     }
 
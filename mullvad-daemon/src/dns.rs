@@ -1,5 +1,9 @@
-use mullvad_types::settings::{DnsOptions, DnsState};
+#[cfg(not(target_os = "android"))]
+use crate::dns_forwarder::{self, Upstream};
+use mullvad_types::settings::{DnsOptions, DnsState, Settings};
 use std::net::{IpAddr, Ipv4Addr};
+#[cfg(not(target_os = "android"))]
+use talpid_core::tunnel_state_machine::DnsUpstream;
 
 /// When we want to block certain contents with the help of DNS server side,
 /// we compute the resolver IP to use based on these constants. The last
@@ -53,3 +57,47 @@ pub fn addresses_from_options(options: &DnsOptions) -> Option<Vec<IpAddr>> {
         }
     }
 }
+
+/// Return the DNS servers that the system should use. These are the resolvers from the DNS
+/// options, unless queries go through the local forwarder.
+pub fn dns_servers(settings: &Settings) -> Option<Vec<IpAddr>> {
+    #[cfg(not(target_os = "android"))]
+    if forwarder_upstream(settings).is_some() {
+        return Some(vec![dns_forwarder::FORWARDER_ADDRESS]);
+    }
+    addresses_from_options(&settings.tunnel_options.dns_options)
+}
+
+/// Return where the local forwarder should send queries, or `None` if it is not needed. It is
+/// needed to encrypt queries, and to filter them using the DNS blocklists.
+#[cfg(not(target_os = "android"))]
+pub fn forwarder_upstream(settings: &Settings) -> Option<Upstream> {
+    let options = &settings.tunnel_options.dns_options;
+    if options.state == DnsState::Custom && !options.custom_options.encrypted_resolvers.is_empty() {
+        return Some(Upstream::Encrypted(
+            options.custom_options.encrypted_resolvers.clone(),
+        ));
+    }
+    if !settings
+        .dns_blocklists
+        .iter()
+        .any(|blocklist| blocklist.enabled)
+    {
+        return None;
+    }
+    Some(match addresses_from_options(options) {
+        Some(addresses) => Upstream::Plain(addresses),
+        None => Upstream::TunnelGateway,
+    })
+}
+
+/// Return where the local forwarder sends queries, for the tunnel state machine to allow them.
+#[cfg(not(target_os = "android"))]
+pub fn tunnel_upstream(settings: &Settings) -> Option<DnsUpstream> {
+    forwarder_upstream(settings).map(|upstream| match upstream {
+        // Encrypted queries are not sent to port 53, so no resolvers need to be allowed
+        Upstream::Encrypted(_) => DnsUpstream::Resolvers(vec![]),
+        Upstream::Plain(addresses) => DnsUpstream::Resolvers(addresses),
+        Upstream::TunnelGateway => DnsUpstream::TunnelGateway,
+    })
+}
@@ -0,0 +1,391 @@
+//! Loads the domains of the DNS blocklists and keeps them up to date. Downloaded blocklists are
+//! cached, so that they are not downloaded again every time the daemon starts, and so that they
+//! can still be used when they cannot be downloaded.
+
+use crate::DaemonEventSender;
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use hyper::{body::HttpBody, header, Body, Client, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use mullvad_types::dns_blocklist::{self, BlocklistSource, BlocklistStatus, DnsBlocklist};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use talpid_core::mpsc::Sender;
+use talpid_types::ErrorExt;
+use tokio::{
+    fs,
+    sync::{watch, Notify},
+};
+
+const CACHE_DIR: &str = "dns-blocklists";
+
+/// How long to wait before trying to update a blocklist again after failing to.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Longest time to wait before checking whether a blocklist should be updated. Timers do not
+/// account for changes to the system clock or time spent suspended, so they are not trusted to
+/// fire on time beyond this.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 5;
+
+/// Largest number of bytes to download for a blocklist.
+const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Invalid URL")]
+    InvalidUrl(#[error(source)] hyper::http::uri::InvalidUri),
+
+    #[error(display = "Request failed")]
+    Request(#[error(source)] hyper::Error),
+
+    #[error(display = "Timed out while downloading the blocklist")]
+    Timeout,
+
+    #[error(display = "Unexpected HTTP status: {}", _0)]
+    HttpStatus(StatusCode),
+
+    #[error(display = "Too many redirects")]
+    TooManyRedirects,
+
+    #[error(display = "The blocklist is larger than {} bytes", MAX_SIZE)]
+    TooLarge,
+
+    #[error(display = "Failed to read the blocklist")]
+    Read(#[error(source)] io::Error),
+}
+
+/// Sent whenever the domains of the blocklists have been loaded or updated.
+#[derive(Debug, Clone)]
+pub struct BlocklistsLoaded {
+    /// Domains in any of the enabled blocklists.
+    pub domains: Arc<HashSet<String>>,
+    pub statuses: HashMap<BlocklistSource, BlocklistStatus>,
+}
+
+struct LoadedBlocklist {
+    domains: HashSet<String>,
+    status: BlocklistStatus,
+    next_update: DateTime<Utc>,
+}
+
+/// Loads the enabled blocklists, and loads them again whenever their update interval has passed.
+/// The daemon is notified whenever the domains change.
+pub struct BlocklistUpdater {
+    blocklists_tx: watch::Sender<Vec<DnsBlocklist>>,
+    update_now: Arc<Notify>,
+}
+
+impl BlocklistUpdater {
+    /// Starts loading `blocklists` in the background. This stops when the updater is dropped.
+    pub fn spawn(
+        blocklists: Vec<DnsBlocklist>,
+        cache_dir: &Path,
+        event_tx: DaemonEventSender<BlocklistsLoaded>,
+    ) -> Self {
+        let (blocklists_tx, blocklists_rx) = watch::channel(blocklists);
+        let update_now = Arc::new(Notify::new());
+        tokio::spawn(Self::run(
+            blocklists_rx,
+            update_now.clone(),
+            cache_dir.join(CACHE_DIR),
+            event_tx,
+        ));
+        BlocklistUpdater {
+            blocklists_tx,
+            update_now,
+        }
+    }
+
+    /// Replaces the blocklists to load. Blocklists that have not been loaded yet are loaded
+    /// immediately.
+    pub fn set_blocklists(&self, blocklists: Vec<DnsBlocklist>) {
+        let _ = self.blocklists_tx.send(blocklists);
+    }
+
+    /// Loads all enabled blocklists again, regardless of when they were last updated.
+    pub fn update_now(&self) {
+        self.update_now.notify_one();
+    }
+
+    async fn run(
+        mut blocklists_rx: watch::Receiver<Vec<DnsBlocklist>>,
+        update_now: Arc<Notify>,
+        cache_dir: PathBuf,
+        event_tx: DaemonEventSender<BlocklistsLoaded>,
+    ) {
+        let client = Client::builder().build::<_, Body>(
+            hyper_rustls::HttpsConnectorBuilder::new()
+                .with_webpki_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        );
+        let mut loaded: HashMap<BlocklistSource, LoadedBlocklist> = HashMap::new();
+        let mut changed = true;
+        let mut force_update = false;
+
+        loop {
+            let blocklists: Vec<DnsBlocklist> = blocklists_rx
+                .borrow()
+                .iter()
+                .filter(|blocklist| blocklist.enabled)
+                .cloned()
+                .collect();
+
+            let num_loaded = loaded.len();
+            loaded.retain(|source, _| {
+                blocklists
+                    .iter()
+                    .any(|blocklist| blocklist.source == *source)
+            });
+            changed |= loaded.len() != num_loaded;
+
+            for blocklist in &blocklists {
+                let loaded_blocklist = match loaded.remove(&blocklist.source) {
+                    Some(previous) if !force_update && previous.next_update > Utc::now() => {
+                        previous
+                    }
+                    previous => {
+                        changed = true;
+                        load(&client, blocklist, previous, &cache_dir, force_update).await
+                    }
+                };
+                loaded.insert(blocklist.source.clone(), loaded_blocklist);
+            }
+            force_update = false;
+
+            if changed {
+                let domains = loaded
+                    .values()
+                    .flat_map(|blocklist| blocklist.domains.iter().cloned())
+                    .collect();
+                let statuses = loaded
+                    .iter()
+                    .map(|(source, blocklist)| (source.clone(), blocklist.status.clone()))
+                    .collect();
+                let event = BlocklistsLoaded {
+                    domains: Arc::new(domains),
+                    statuses,
+                };
+                if event_tx.send(event).is_err() {
+                    break;
+                }
+                changed = false;
+            }
+
+            let now = Utc::now();
+            let wait = loaded
+                .values()
+                .map(|blocklist| (blocklist.next_update - now).to_std().unwrap_or_default())
+                .min()
+                .unwrap_or(MAX_WAIT)
+                .min(MAX_WAIT);
+            futures::select! {
+                result = Box::pin(blocklists_rx.changed()).fuse() => {
+                    if result.is_err() {
+                        // The updater was dropped
+                        break;
+                    }
+                }
+                _ = Box::pin(update_now.notified()).fuse() => force_update = true,
+                _ = Box::pin(tokio::time::sleep(wait)).fuse() => (),
+            }
+        }
+    }
+}
+
+type HttpClient = Client<HttpsConnector<hyper::client::HttpConnector>>;
+
+/// Loads the domains of `blocklist`. Downloaded blocklists are read from the cache instead if
+/// they were cached less than an update interval ago, unless `force_update` is set. If the domains
+/// cannot be loaded, the ones that were loaded before are kept.
+async fn load(
+    client: &HttpClient,
+    blocklist: &DnsBlocklist,
+    previous: Option<LoadedBlocklist>,
+    cache_dir: &Path,
+    force_update: bool,
+) -> LoadedBlocklist {
+    let update_interval = chrono::Duration::hours(blocklist.update_interval.into());
+
+    let (cached, result) = match &blocklist.source {
+        BlocklistSource::Url(url) => {
+            let cache_path = cache_dir.join(cache_file_name(url));
+            // The cache is only needed before the blocklist has been loaded
+            let cached = match previous {
+                None => read_cache(&cache_path).await,
+                Some(_) => None,
+            };
+            let result = match &cached {
+                Some((contents, updated))
+                    if !force_update && *updated + update_interval > Utc::now() =>
+                {
+                    Ok((contents.clone(), *updated))
+                }
+                _ => download(client, url).await.map(|contents| {
+                    write_cache(cache_dir, &cache_path, &contents);
+                    (contents, Utc::now())
+                }),
+            };
+            (cached, result)
+        }
+        BlocklistSource::File(path) => {
+            let result = fs::read_to_string(path)
+                .await
+                .map(|contents| (contents, Utc::now()))
+                .map_err(Error::Read);
+            (None, result)
+        }
+    };
+
+    match result {
+        Ok((contents, updated)) => {
+            let domains = dns_blocklist::parse_domains(&contents);
+            log::debug!(
+                "Loaded {} domains from DNS blocklist {}",
+                domains.len(),
+                blocklist.source
+            );
+            LoadedBlocklist {
+                status: BlocklistStatus {
+                    domains: domains.len(),
+                    updated: Some(updated),
+                    error: None,
+                },
+                domains,
+                next_update: updated + update_interval,
+            }
+        }
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg(&format!(
+                    "Failed to update DNS blocklist {}",
+                    blocklist.source
+                ))
+            );
+            let (domains, updated) = match (previous, cached) {
+                (Some(previous), _) => (previous.domains, previous.status.updated),
+                (None, Some((contents, updated))) => {
+                    (dns_blocklist::parse_domains(&contents), Some(updated))
+                }
+                (None, None) => (HashSet::new(), None),
+            };
+            LoadedBlocklist {
+                status: BlocklistStatus {
+                    domains: domains.len(),
+                    updated,
+                    error: Some(describe_error(&error)),
+                },
+                domains,
+                next_update: Utc::now()
+                    + chrono::Duration::from_std(RETRY_INTERVAL).unwrap_or(update_interval),
+            }
+        }
+    }
+}
+
+/// Downloads the contents of `url`, following redirects.
+async fn download(client: &HttpClient, url: &str) -> Result<String, Error> {
+    tokio::time::timeout(DOWNLOAD_TIMEOUT, async {
+        let mut uri: Uri = url.parse().map_err(Error::InvalidUrl)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let mut response = client.get(uri.clone()).await.map_err(Error::Request)?;
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(Error::HttpStatus(status))?;
+                uri = redirect_uri(&uri, location).map_err(Error::InvalidUrl)?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(Error::HttpStatus(status));
+            }
+
+            let mut body = Vec::new();
+            while let Some(chunk) = response.body_mut().data().await {
+                let chunk = chunk.map_err(Error::Request)?;
+                if body.len() + chunk.len() > MAX_SIZE {
+                    return Err(Error::TooLarge);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            return Ok(String::from_utf8_lossy(&body).into_owned());
+        }
+        Err(Error::TooManyRedirects)
+    })
+    .await
+    .map_err(|_| Error::Timeout)?
+}
+
+/// Returns the URI that `location` refers to, which may be relative to `uri`.
+fn redirect_uri(uri: &Uri, location: &str) -> Result<Uri, hyper::http::uri::InvalidUri> {
+    let location: Uri = location.parse()?;
+    if location.scheme().is_some() {
+        return Ok(location);
+    }
+    let path = location
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    format!(
+        "{}://{}{}",
+        uri.scheme_str().unwrap_or("https"),
+        uri.authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or_default(),
+        path
+    )
+    .parse()
+}
+
+/// Returns the name of the file that the contents of `url` are cached in. The hash is not
+/// guaranteed to be stable across Rust versions, in which case the blocklist is simply downloaded
+/// again.
+fn cache_file_name(url: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the cached contents of a blocklist and when they were cached.
+async fn read_cache(path: &Path) -> Option<(String, DateTime<Utc>)> {
+    let modified = fs::metadata(path).await.ok()?.modified().ok()?;
+    let contents = fs::read_to_string(path).await.ok()?;
+    Some((contents, DateTime::<Utc>::from(modified)))
+}
+
+fn write_cache(cache_dir: &Path, path: &Path, contents: &str) {
+    let result = std::fs::create_dir_all(cache_dir).and_then(|_| std::fs::write(path, contents));
+    if let Err(error) = result {
+        log::error!(
+            "{}",
+            error.display_chain_with_msg("Failed to cache DNS blocklist")
+        );
+    }
+}
+
+/// Describes `error` and its sources on a single line.
+fn describe_error(error: &Error) -> String {
+    let mut description = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        description.push_str(": ");
+        description.push_str(&error.to_string());
+        source = error.source();
+    }
+    description
+}
@@ -0,0 +1,329 @@
+//! Forwards DNS queries when the daemon has to handle them itself. The system is told to use the
+//! forwarder as its resolver, and the forwarder sends queries on to resolvers that are reached
+//! over DNS over HTTPS or DNS over TLS, so that queries only leave the device encrypted, or to
+//! plaintext resolvers through the tunnel. Domains in the DNS blocklists are not resolved.
+
+use mullvad_types::{
+    dns_blocklist,
+    settings::{EncryptedDnsProtocol, EncryptedDnsResolver},
+};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use talpid_types::ErrorExt;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::watch,
+    task::JoinHandle,
+};
+use trust_dns_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    lookup::Lookup,
+    TokioAsyncResolver,
+};
+use trust_dns_server::{
+    authority::MessageResponseBuilder,
+    proto::op::{header::MessageType, op_code::OpCode, Header, Query, ResponseCode},
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    ServerFuture,
+};
+
+/// Address that the forwarder listens on. This is what the system is told to use as its resolver.
+pub const FORWARDER_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const FORWARDER_PORT: u16 = 53;
+
+/// How long an idle TCP connection to the forwarder is kept open.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Failed to create the upstream resolver")]
+    CreateResolver(#[error(source)] ResolveError),
+
+    #[error(display = "Failed to bind the UDP socket of the forwarder")]
+    BindUdp(#[error(source)] io::Error),
+
+    #[error(display = "Failed to bind the TCP socket of the forwarder")]
+    BindTcp(#[error(source)] io::Error),
+}
+
+/// Where the forwarder sends queries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    /// Resolvers that are reached over DNS over HTTPS or DNS over TLS.
+    Encrypted(Vec<EncryptedDnsResolver>),
+    /// Resolvers that are sent plaintext queries through the tunnel.
+    Plain(Vec<IpAddr>),
+    /// The gateway of the current tunnel.
+    TunnelGateway,
+}
+
+/// Returns the gateways of the current tunnel, or nothing if there is no tunnel.
+pub type TunnelGateways = Arc<dyn Fn() -> Vec<IpAddr> + Send + Sync>;
+
+type BlockedDomains = Arc<RwLock<Arc<HashSet<String>>>>;
+
+/// Runs the forwarder whenever queries must be handled by the daemon.
+pub struct DnsForwarder {
+    upstream_tx: watch::Sender<Option<Upstream>>,
+    blocked_domains: BlockedDomains,
+}
+
+impl DnsForwarder {
+    /// Starts forwarding queries to `upstream` in the background. The forwarder does not listen
+    /// while `upstream` is `None`. Forwarding stops when the forwarder is dropped.
+    pub fn spawn(upstream: Option<Upstream>, tunnel_gateways: TunnelGateways) -> Self {
+        let (upstream_tx, upstream_rx) = watch::channel(upstream);
+        let blocked_domains = BlockedDomains::default();
+        tokio::spawn(Self::run(
+            upstream_rx,
+            blocked_domains.clone(),
+            tunnel_gateways,
+        ));
+        DnsForwarder {
+            upstream_tx,
+            blocked_domains,
+        }
+    }
+
+    /// Replaces the resolvers that queries are forwarded to.
+    pub fn set_upstream(&self, upstream: Option<Upstream>) {
+        let _ = self.upstream_tx.send(upstream);
+    }
+
+    /// Replaces the domains that are not resolved. Subdomains of them are not resolved either.
+    pub fn set_blocked_domains(&self, domains: Arc<HashSet<String>>) {
+        *self.blocked_domains.write().unwrap() = domains;
+    }
+
+    async fn run(
+        mut upstream_rx: watch::Receiver<Option<Upstream>>,
+        blocked_domains: BlockedDomains,
+        tunnel_gateways: TunnelGateways,
+    ) {
+        let mut server: Option<JoinHandle<()>> = None;
+        loop {
+            if let Some(server) = server.take() {
+                server.abort();
+                let _ = server.await;
+            }
+
+            let upstream = upstream_rx.borrow().clone();
+            if let Some(upstream) = upstream {
+                log::debug!("Forwarding DNS queries to {:?}", upstream);
+                // Queries are not sent elsewhere if this fails. They fail instead
+                match start_server(upstream, blocked_domains.clone(), tunnel_gateways.clone()).await
+                {
+                    Ok(handle) => server = Some(handle),
+                    Err(error) => log::error!(
+                        "{}",
+                        error.display_chain_with_msg("Failed to start the DNS forwarder")
+                    ),
+                }
+            }
+
+            if upstream_rx.changed().await.is_err() {
+                // The forwarder was dropped
+                if let Some(server) = server.take() {
+                    server.abort();
+                }
+                break;
+            }
+        }
+    }
+}
+
+async fn start_server(
+    upstream: Upstream,
+    blocked_domains: BlockedDomains,
+    tunnel_gateways: TunnelGateways,
+) -> Result<JoinHandle<()>, Error> {
+    let upstream = match upstream {
+        Upstream::Encrypted(resolvers) => {
+            UpstreamResolver::Fixed(Arc::new(encrypted_resolver(&resolvers)?))
+        }
+        Upstream::Plain(addresses) => {
+            UpstreamResolver::Fixed(Arc::new(plain_resolver(&addresses)?))
+        }
+        Upstream::TunnelGateway => UpstreamResolver::TunnelGateway {
+            gateways: tunnel_gateways,
+            resolver: Mutex::new(None),
+        },
+    };
+
+    let address = SocketAddr::new(FORWARDER_ADDRESS, FORWARDER_PORT);
+    let mut server = ServerFuture::new(Forwarder {
+        upstream,
+        blocked_domains,
+    });
+    server.register_socket(UdpSocket::bind(address).await.map_err(Error::BindUdp)?);
+    server.register_listener(
+        TcpListener::bind(address).await.map_err(Error::BindTcp)?,
+        TCP_TIMEOUT,
+    );
+
+    Ok(tokio::spawn(async move {
+        if let Err(error) = server.block_until_done().await {
+            log::error!("DNS forwarder stopped: {}", error);
+        }
+    }))
+}
+
+fn encrypted_resolver(resolvers: &[EncryptedDnsResolver]) -> Result<TokioAsyncResolver, Error> {
+    let name_servers: Vec<NameServerConfig> = resolvers
+        .iter()
+        .map(|resolver| NameServerConfig {
+            socket_addr: resolver.address,
+            protocol: match resolver.protocol {
+                EncryptedDnsProtocol::Https => Protocol::Https,
+                EncryptedDnsProtocol::Tls => Protocol::Tls,
+            },
+            tls_dns_name: Some(resolver.server_name.clone()),
+            trust_nx_responses: true,
+            tls_config: None,
+            bind_addr: None,
+        })
+        .collect();
+    let config =
+        ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(name_servers));
+    TokioAsyncResolver::tokio(config, ResolverOpts::default()).map_err(Error::CreateResolver)
+}
+
+fn plain_resolver(addresses: &[IpAddr]) -> Result<TokioAsyncResolver, Error> {
+    let config = ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(addresses, 53, true),
+    );
+    TokioAsyncResolver::tokio(config, ResolverOpts::default()).map_err(Error::CreateResolver)
+}
+
+enum UpstreamResolver {
+    Fixed(Arc<TokioAsyncResolver>),
+    /// The resolver is created again whenever the tunnel gateways change.
+    TunnelGateway {
+        gateways: TunnelGateways,
+        resolver: Mutex<Option<(Vec<IpAddr>, Arc<TokioAsyncResolver>)>>,
+    },
+}
+
+impl UpstreamResolver {
+    /// Returns the resolver to forward queries to, or `None` if there is no tunnel to send them
+    /// through.
+    fn resolver(&self) -> Option<Arc<TokioAsyncResolver>> {
+        match self {
+            UpstreamResolver::Fixed(resolver) => Some(resolver.clone()),
+            UpstreamResolver::TunnelGateway { gateways, resolver } => {
+                let gateways = gateways();
+                if gateways.is_empty() {
+                    return None;
+                }
+                let mut resolver = resolver.lock().unwrap();
+                if let Some((current_gateways, current)) = &*resolver {
+                    if *current_gateways == gateways {
+                        return Some(current.clone());
+                    }
+                }
+                match plain_resolver(&gateways) {
+                    Ok(new_resolver) => {
+                        let new_resolver = Arc::new(new_resolver);
+                        *resolver = Some((gateways, new_resolver.clone()));
+                        Some(new_resolver)
+                    }
+                    Err(error) => {
+                        log::error!("{}", error.display_chain());
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An implementation of [trust_dns_server::server::RequestHandler] that resolves queries using
+/// the upstream resolvers, unless the domain is blocked.
+struct Forwarder {
+    upstream: UpstreamResolver,
+    blocked_domains: BlockedDomains,
+}
+
+impl Forwarder {
+    async fn forward<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) {
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let result = match self.lookup(request.query().original()).await {
+            Ok(lookup) => {
+                let mut header = Header::new();
+                header.set_id(request.id());
+                header.set_op_code(OpCode::Query);
+                header.set_message_type(MessageType::Response);
+                header.set_authoritative(false);
+                header.set_recursion_desired(request.header().recursion_desired());
+                header.set_recursion_available(true);
+                response_handle
+                    .send_response(builder.build(
+                        header,
+                        lookup.record_iter(),
+                        // Responses only contain query answers, no ns, soa or additionals
+                        std::iter::empty(),
+                        std::iter::empty(),
+                        std::iter::empty(),
+                    ))
+                    .await
+            }
+            Err(response_code) => {
+                response_handle
+                    .send_response(builder.error_msg(request.header(), response_code))
+                    .await
+            }
+        };
+        if let Err(error) = result {
+            log::error!("Failed to send DNS response: {}", error);
+        }
+    }
+
+    async fn lookup(&self, query: &Query) -> Result<Lookup, ResponseCode> {
+        let blocked_domains = self.blocked_domains.read().unwrap().clone();
+        if dns_blocklist::is_blocked(&blocked_domains, &query.name().to_ascii()) {
+            log::trace!("Not resolving blocked domain {}", query.name());
+            return Err(ResponseCode::NXDomain);
+        }
+
+        let resolver = self.upstream.resolver().ok_or(ResponseCode::ServFail)?;
+        resolver
+            .lookup(query.name().clone(), query.query_type(), Default::default())
+            .await
+            .map_err(|error| match error.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, .. } => *response_code,
+                _ => {
+                    log::debug!("Failed to forward DNS query: {}", error);
+                    ResponseCode::ServFail
+                }
+            })
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for Forwarder {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        if !request.src().ip().is_loopback() {
+            log::error!("Dropping a stray request from outside: {}", request.src());
+            return Header::new().into();
+        }
+        if request.message_type() == MessageType::Query && request.op_code() == OpCode::Query {
+            self.forward(request, response_handle).await;
+        } else {
+            log::trace!("Dropping non-query request: {:?}", request);
+        }
+        Header::new().into()
+    }
+}
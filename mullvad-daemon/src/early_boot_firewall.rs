@@ -1,4 +1,5 @@
 use mullvad_daemon::settings::{self, SettingsPersister};
+use mullvad_types::settings::Settings;
 use talpid_core::firewall::{self, Firewall, FirewallPolicy};
 
 #[derive(err_derive::Error, Debug)]
@@ -15,24 +16,27 @@ pub enum Error {
 
 pub async fn initialize_firewall() -> Result<(), Error> {
     let mut firewall = Firewall::new(mullvad_types::TUNNEL_FWMARK)?;
-    let allow_lan = get_allow_lan().await.unwrap_or_else(|err| {
+    let settings = get_settings().await.unwrap_or_else(|err| {
         log::info!(
             "Not allowing LAN traffic due to failing to read settings: {}",
             err
         );
-        false
+        Settings::default()
     });
     let policy = FirewallPolicy::Blocked {
-        allow_lan,
+        allow_lan: settings.allow_lan,
+        lan_rules: settings.lan_rules,
+        firewall_exceptions: settings.firewall_exceptions,
         allowed_endpoint: None,
+        split_tunnel_mode: settings.split_tunnel_mode,
     };
     log::info!("Applying firewall policy {policy}");
     firewall.apply_policy(policy)?;
     Ok(())
 }
 
-async fn get_allow_lan() -> Result<bool, Error> {
+async fn get_settings() -> Result<Settings, Error> {
     let path = mullvad_paths::settings_dir()?;
     let settings = SettingsPersister::load(&path).await;
-    Ok(settings.allow_lan)
+    Ok(settings.to_settings())
 }
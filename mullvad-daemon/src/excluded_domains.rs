@@ -0,0 +1,136 @@
+//! Resolves the domains that are excluded from the tunnel, so that traffic to the addresses that
+//! they resolve to can be sent outside the tunnel as well.
+
+use crate::DaemonEventSender;
+use ipnetwork::IpNetwork;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    time::Duration,
+};
+use talpid_core::mpsc::Sender;
+use tokio::sync::watch;
+
+/// How often excluded domains are resolved again, to pick up addresses that have changed.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait for a single domain to resolve.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Addresses that the excluded domains resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedDomains(pub Vec<IpAddr>);
+
+/// Resolves excluded domains every [`RESOLVE_INTERVAL`], and whenever the domains change. The
+/// daemon is notified whenever the set of resolved addresses changes. If a domain cannot be
+/// resolved, the addresses it last resolved to are kept.
+pub struct DomainResolver {
+    domains_tx: watch::Sender<Vec<String>>,
+}
+
+impl DomainResolver {
+    /// Starts resolving `domains` in the background. Resolving stops when the resolver is
+    /// dropped.
+    pub fn spawn(domains: Vec<String>, event_tx: DaemonEventSender<ResolvedDomains>) -> Self {
+        let (domains_tx, domains_rx) = watch::channel(domains);
+        tokio::spawn(Self::run(domains_rx, event_tx));
+        DomainResolver { domains_tx }
+    }
+
+    /// Replaces the domains to resolve. They are resolved immediately.
+    pub fn set_domains(&self, domains: Vec<String>) {
+        let _ = self.domains_tx.send(domains);
+    }
+
+    async fn run(
+        mut domains_rx: watch::Receiver<Vec<String>>,
+        event_tx: DaemonEventSender<ResolvedDomains>,
+    ) {
+        let mut resolved: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        let mut last_addresses = vec![];
+        loop {
+            let domains = domains_rx.borrow().clone();
+            resolved.retain(|domain, _| domains.contains(domain));
+            for domain in domains {
+                match lookup(&domain).await {
+                    Ok(addresses) => {
+                        resolved.insert(domain, addresses);
+                    }
+                    Err(error) => {
+                        log::warn!("Failed to resolve excluded domain {}: {}", domain, error)
+                    }
+                }
+            }
+
+            let addresses: Vec<IpAddr> = resolved
+                .values()
+                .flatten()
+                .copied()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            if addresses != last_addresses {
+                log::debug!("Excluded domains resolved to {} addresses", addresses.len());
+                if event_tx.send(ResolvedDomains(addresses.clone())).is_err() {
+                    break;
+                }
+                last_addresses = addresses;
+            }
+
+            if let Ok(Err(_)) = tokio::time::timeout(RESOLVE_INTERVAL, domains_rx.changed()).await {
+                // The resolver was dropped
+                break;
+            }
+        }
+    }
+}
+
+async fn lookup(domain: &str) -> std::io::Result<Vec<IpAddr>> {
+    let addresses = tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((domain, 0)))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    Ok(addresses.map(|address| address.ip()).collect())
+}
+
+/// Returns the networks whose traffic should be sent outside the tunnel: the excluded `networks`,
+/// followed by the `resolved` addresses of excluded domains that no such network contains.
+pub fn excluded_networks(networks: &[IpNetwork], resolved: &[IpAddr]) -> Vec<IpNetwork> {
+    let mut excluded = networks.to_vec();
+    excluded.extend(
+        resolved
+            .iter()
+            .filter(|address| !networks.iter().any(|network| network.contains(**address)))
+            .map(|address| IpNetwork::from(*address)),
+    );
+    excluded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_excluded_networks() {
+        let networks: Vec<IpNetwork> = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ];
+        let resolved: Vec<IpAddr> = vec![
+            "10.1.2.3".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+            "2001:db9::1".parse().unwrap(),
+        ];
+
+        assert_eq!(
+            excluded_networks(&networks, &resolved),
+            vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+                "192.0.2.1/32".parse().unwrap(),
+                "2001:db9::1/128".parse().unwrap(),
+            ]
+        );
+        assert_eq!(excluded_networks(&[], &[]), vec![]);
+    }
+}
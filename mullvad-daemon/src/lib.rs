@@ -8,9 +8,16 @@ pub mod account_history;
 mod api;
 #[cfg(not(target_os = "android"))]
 mod cleanup;
+pub mod connection_history;
 pub mod device;
 mod dns;
+#[cfg(not(target_os = "android"))]
+mod dns_blocklist;
+#[cfg(not(target_os = "android"))]
+mod dns_forwarder;
 pub mod exception_logging;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod excluded_domains;
 mod geoip;
 pub mod logging;
 #[cfg(target_os = "macos")]
@@ -18,12 +25,16 @@ mod macos;
 #[cfg(not(target_os = "android"))]
 pub mod management_interface;
 mod migrations;
+mod relay_latency;
 #[cfg(not(target_os = "android"))]
 pub mod rpc_uniqueness_check;
 pub mod runtime;
+#[cfg(not(target_os = "android"))]
+mod schedule;
 pub mod settings;
 pub mod shutdown;
 mod target_state;
+mod traffic_stats;
 mod tunnel;
 pub mod version;
 mod version_check;
@@ -36,22 +47,39 @@ use futures::{
     StreamExt,
 };
 use mullvad_relay_selector::{
-    updater::{RelayListUpdater, RelayListUpdaterHandle},
+    updater::{self, RelayListUpdater, RelayListUpdaterHandle},
     RelaySelector, SelectorConfig,
 };
 use mullvad_types::{
     account::{AccountData, AccountToken, VoucherSubmission},
     auth_failed::AuthFailed,
+    connection_history::ConnectionAttempt,
+    custom_list::{CustomList, CustomListsSettings},
     device::{Device, DeviceEvent, DeviceEventCause, DeviceId, DeviceState, RemoveDeviceEvent},
+    escalation::{self, EscalationLadder},
+    excluded_destinations::{self, ExcludedDestination},
     location::GeoIpLocation,
-    relay_constraints::{BridgeSettings, BridgeState, ObfuscationSettings, RelaySettingsUpdate},
-    relay_list::RelayList,
+    relay_constraints::{
+        BridgeSettings, BridgeState, Constraint, LocationConstraint, ObfuscationSettings,
+        ProtocolPreference, RelaySettings, RelaySettingsUpdate,
+    },
+    relay_list::{RelayList, RelayListSource},
+    relay_rotation::{self, RelayRotationEvent, RelayRotationPolicy},
+    relay_selection::RelaySelectionEvent,
     settings::{DnsOptions, Settings},
     states::{TargetState, TunnelState},
+    traffic_stats::TrafficSample,
     version::{AppVersion, AppVersionInfo},
-    wireguard::{PublicKey, RotationInterval},
+    wireguard::{PortFallback, PublicKey, QuantumResistantState, RotationInterval},
+};
+#[cfg(not(target_os = "android"))]
+use mullvad_types::{
+    dns_blocklist::{BlocklistSource, BlocklistStatus, DnsBlocklist},
+    network_rules::{NetworkRule, NetworkTrust},
+    schedule::{ConnectionSchedule, ScheduleState},
 };
 use settings::SettingsPersister;
+use std::collections::HashMap;
 #[cfg(target_os = "android")]
 use std::os::unix::io::RawFd;
 #[cfg(target_os = "windows")]
@@ -59,12 +87,13 @@ use std::{collections::HashSet, ffi::OsString};
 use std::{
     marker::PhantomData,
     mem,
+    net::IpAddr,
     path::PathBuf,
     pin::Pin,
     sync::{Arc, Weak},
     time::Duration,
 };
-#[cfg(any(target_os = "linux", windows))]
+#[cfg(not(target_os = "android"))]
 use talpid_core::split_tunnel;
 use talpid_core::{
     mpsc::Sender,
@@ -72,9 +101,15 @@ use talpid_core::{
 };
 #[cfg(target_os = "android")]
 use talpid_types::android::AndroidContext;
+#[cfg(not(target_os = "android"))]
+use talpid_types::net::NetworkIdentity;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use talpid_types::{
+    firewall_exception::FirewallException, lan::LanRules, split_tunnel::SplitTunnelMode,
+};
 use talpid_types::{
     net::{TunnelEndpoint, TunnelType},
-    tunnel::{ErrorStateCause, TunnelStateTransition},
+    tunnel::{ActionAfterDisconnect, ErrorStateCause, TunnelStateTransition},
     ErrorExt,
 };
 #[cfg(any(target_os = "macos", target_os = "linux"))]
@@ -154,6 +189,54 @@ pub enum Error {
     #[error(display = "Account history error")]
     AccountHistory(#[error(source)] account_history::Error),
 
+    #[error(display = "Connection history error")]
+    ConnectionHistory(#[error(source)] connection_history::Error),
+
+    #[error(display = "A custom list named {} already exists", _0)]
+    CustomListExists(String),
+
+    #[error(display = "There is no custom list named {}", _0)]
+    CustomListNotFound(String),
+
+    #[error(display = "Custom lists cannot contain other custom lists")]
+    NestedCustomList,
+
+    #[error(display = "Invalid escalation ladder")]
+    InvalidEscalationLadder(#[error(source)] escalation::Error),
+
+    #[error(display = "Invalid relay list file")]
+    InvalidRelayListFile(#[error(source)] mullvad_relay_selector::Error),
+
+    #[error(display = "Invalid relay rotation policy")]
+    InvalidRelayRotationPolicy(#[error(source)] relay_rotation::Error),
+
+    #[error(display = "Invalid excluded destination")]
+    InvalidExcludedDestination(#[error(source)] excluded_destinations::Error),
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[error(display = "{} is not a local network", _0)]
+    NotLanNetwork(ipnetwork::IpNetwork),
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[error(display = "There is no firewall exception at index {}", _0)]
+    FirewallExceptionNotFound(usize),
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[error(display = "There already is a firewall exception for {}", _0)]
+    FirewallExceptionExists(FirewallException),
+
+    #[cfg(not(target_os = "android"))]
+    #[error(display = "There is no schedule at index {}", _0)]
+    ScheduleNotFound(usize),
+
+    #[cfg(not(target_os = "android"))]
+    #[error(display = "There is no DNS blocklist at index {}", _0)]
+    DnsBlocklistNotFound(usize),
+
+    #[cfg(not(target_os = "android"))]
+    #[error(display = "There already is a DNS blocklist from {}", _0)]
+    DnsBlocklistExists(BlocklistSource),
+
     #[cfg(not(target_os = "android"))]
     #[error(display = "Factory reset partially failed: {}", _0)]
     FactoryResetError(&'static str),
@@ -190,8 +273,40 @@ pub enum DaemonCommand {
     GetAccountHistory(oneshot::Sender<Option<AccountToken>>),
     /// Remove the last used account, if there is one
     ClearAccountHistory(ResponseTx<(), Error>),
+    /// Get the recorded connection attempts, oldest first
+    GetConnectionHistory(oneshot::Sender<Vec<ConnectionAttempt>>),
+    /// Remove all recorded connection attempts
+    ClearConnectionHistory(ResponseTx<(), Error>),
     /// Get the list of countries and cities where there are relays.
     GetRelayLocations(oneshot::Sender<RelayList>),
+    /// Get a summary of the relays selected for the last generated tunnel parameters.
+    GetRelaySelection(oneshot::Sender<Option<tunnel::SelectionSnapshot>>),
+    /// Get a human-readable explanation of why the relays of the last relay selection were
+    /// selected.
+    ExplainRelaySelection(oneshot::Sender<Option<String>>),
+    /// Get every hop of the path that traffic takes through the last generated tunnel parameters.
+    GetLastRelayChain(oneshot::Sender<Option<tunnel::RelayChain>>),
+    /// Get the traffic samples of the current or last tunnel, oldest first.
+    GetTrafficStats(oneshot::Sender<Vec<TrafficSample>>),
+    /// Get a receiver of every new traffic sample.
+    ListenTrafficStats(oneshot::Sender<tokio::sync::mpsc::UnboundedReceiver<TrafficSample>>),
+    /// Get the relays that would likely be selected for the given number of connection attempts.
+    PreviewRelays(oneshot::Sender<Vec<tunnel::RelayPreview>>, u32),
+    /// Get connection statistics for recently used exit relays, by hostname.
+    GetRelayStats(oneshot::Sender<HashMap<String, tunnel::RelayStat>>),
+    /// Attempt to select relays for every country and city in the relay list.
+    RunSelfTest(oneshot::Sender<tunnel::SelfTestReport>),
+    /// Check whether tunnel parameters can be generated for the current settings.
+    CheckCanConnect(oneshot::Sender<Result<(), tunnel::UnsatisfiableReason>>),
+    /// Exclude the relays with the given hostnames until the daemon restarts.
+    SetSessionExcludedRelays(oneshot::Sender<()>, Vec<String>),
+    /// Get the relays that are excluded until the daemon restarts.
+    GetSessionExcludedRelays(oneshot::Sender<Vec<String>>),
+    /// Benchmark the relays in a location that tunnels may use.
+    BenchmarkRelays(
+        oneshot::Sender<Vec<relay_latency::RelayBenchmark>>,
+        Constraint<LocationConstraint>,
+    ),
     /// Trigger an asynchronous relay list update. This returns before the relay list is actually
     /// updated.
     UpdateRelayLocations,
@@ -211,6 +326,9 @@ pub enum DaemonCommand {
     UpdateRelaySettings(ResponseTx<(), settings::Error>, RelaySettingsUpdate),
     /// Set the allow LAN setting.
     SetAllowLan(ResponseTx<(), settings::Error>, bool),
+    /// Set the rules that restrict LAN access, or allow all LAN traffic if `None`
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    SetLanRules(ResponseTx<(), Error>, Option<LanRules>),
     /// Set the beta program setting.
     SetShowBetaReleases(ResponseTx<(), settings::Error>, bool),
     /// Set the block_when_disconnected setting.
@@ -226,12 +344,20 @@ pub enum DaemonCommand {
     /// Set if IPv6 should be enabled in the tunnel
     SetEnableIpv6(ResponseTx<(), settings::Error>, bool),
     /// Set whether to enable PQ PSK exchange in the tunnel
-    SetQuantumResistantTunnel(ResponseTx<(), settings::Error>, bool),
+    SetQuantumResistantTunnel(ResponseTx<(), settings::Error>, QuantumResistantState),
+    /// Set whether to use a new WireGuard key for every connection
+    SetWireguardEphemeralKeys(ResponseTx<(), settings::Error>, bool),
     /// Set DNS options or servers to use
     SetDnsOptions(ResponseTx<(), settings::Error>, DnsOptions),
     /// Toggle macOS network check leak
     /// Set MTU for wireguard tunnels
     SetWireguardMtu(ResponseTx<(), settings::Error>, Option<u16>),
+    /// Set whether to detect the MTU of wireguard tunnels when no MTU is set
+    SetWireguardMtuDetection(ResponseTx<(), settings::Error>, bool),
+    /// Set whether to use DAITA for wireguard tunnels
+    SetWireguardDaita(ResponseTx<(), settings::Error>, bool),
+    /// Set the well-known ports that wireguard tunnels fall back to on later retry attempts
+    SetWireguardPortFallback(ResponseTx<(), settings::Error>, PortFallback),
     /// Set automatic key rotation interval for wireguard tunnels
     SetWireguardRotationInterval(ResponseTx<(), settings::Error>, Option<RotationInterval>),
     /// Get the daemon settings
@@ -240,6 +366,8 @@ pub enum DaemonCommand {
     RotateWireguardKey(ResponseTx<(), Error>),
     /// Return a public key of the currently set wireguard private key, if there is one
     GetWireguardKey(ResponseTx<Option<PublicKey>, Error>),
+    /// Get the last generated WireGuard tunnel parameters as a `wg-quick` config
+    ExportWireguardConfig(oneshot::Sender<Option<String>>),
     /// Get information about the currently running and latest app versions
     GetVersionInfo(oneshot::Sender<Option<AppVersionInfo>>),
     /// Return whether the daemon is performing post-upgrade tasks
@@ -250,7 +378,7 @@ pub enum DaemonCommand {
     #[cfg(not(target_os = "android"))]
     FactoryReset(ResponseTx<(), Error>),
     /// Request list of processes excluded from the tunnel
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     GetSplitTunnelProcesses(ResponseTx<Vec<i32>, split_tunnel::Error>),
     /// Exclude traffic of a process (PID) from the tunnel
     #[cfg(target_os = "linux")]
@@ -276,6 +404,66 @@ pub enum DaemonCommand {
     /// Returns all processes currently being excluded from the tunnel
     #[cfg(windows)]
     GetSplitTunnelProcesses(ResponseTx<Vec<split_tunnel::ExcludedProcess>, split_tunnel::Error>),
+    /// Exclude traffic to a network or domain from the tunnel
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    AddExcludedDestination(ResponseTx<(), Error>, String),
+    /// Remove a network or domain from the destinations excluded from the tunnel
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    RemoveExcludedDestination(ResponseTx<(), Error>, String),
+    /// Clear the destinations excluded from the tunnel
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    ClearExcludedDestinations(ResponseTx<(), Error>),
+    /// Set whether split tunneled apps are excluded from the tunnel or are the only ones using it
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    SetSplitTunnelMode(ResponseTx<(), settings::Error>, SplitTunnelMode),
+    /// Set how a network is treated, replacing any existing rule for the network
+    #[cfg(not(target_os = "android"))]
+    SetNetworkRule(ResponseTx<(), settings::Error>, NetworkRule),
+    /// Remove the rule for a network
+    #[cfg(not(target_os = "android"))]
+    RemoveNetworkRule(ResponseTx<(), settings::Error>, NetworkIdentity),
+    /// Set whether traffic outside the tunnel is blocked while on an untrusted network
+    #[cfg(not(target_os = "android"))]
+    SetNetworkLockdown(ResponseTx<(), settings::Error>, bool),
+    /// Get the network that the host is connected to
+    #[cfg(not(target_os = "android"))]
+    GetCurrentNetwork(oneshot::Sender<Option<NetworkIdentity>>),
+    /// Add a period during which the tunnel should be connected
+    #[cfg(not(target_os = "android"))]
+    AddSchedule(ResponseTx<(), Error>, ConnectionSchedule),
+    /// Remove the schedule at the given index
+    #[cfg(not(target_os = "android"))]
+    RemoveSchedule(ResponseTx<(), Error>, usize),
+    /// Remove all schedules
+    #[cfg(not(target_os = "android"))]
+    ClearSchedules(ResponseTx<(), Error>),
+    /// Get whether the schedules currently call for the tunnel to be connected
+    #[cfg(not(target_os = "android"))]
+    GetScheduleState(oneshot::Sender<ScheduleState>),
+    /// Add a DNS blocklist
+    #[cfg(not(target_os = "android"))]
+    AddDnsBlocklist(ResponseTx<(), Error>, DnsBlocklist),
+    /// Remove the DNS blocklist at the given index
+    #[cfg(not(target_os = "android"))]
+    RemoveDnsBlocklist(ResponseTx<(), Error>, usize),
+    /// Enable or disable the DNS blocklist at the given index
+    #[cfg(not(target_os = "android"))]
+    SetDnsBlocklistEnabled(ResponseTx<(), Error>, usize, bool),
+    /// Load all enabled DNS blocklists again, regardless of when they were last updated
+    #[cfg(not(target_os = "android"))]
+    UpdateDnsBlocklists(oneshot::Sender<()>),
+    /// Get the status of each DNS blocklist, or `None` for blocklists that have not been loaded
+    #[cfg(not(target_os = "android"))]
+    GetDnsBlocklistStatus(oneshot::Sender<Vec<Option<BlocklistStatus>>>),
+    /// Add traffic that the firewall allows in every tunnel state
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    AddFirewallException(ResponseTx<(), Error>, FirewallException),
+    /// Remove the firewall exception at the given index
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    RemoveFirewallException(ResponseTx<(), Error>, usize),
+    /// Remove all firewall exceptions
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    ClearFirewallExceptions(ResponseTx<(), Error>),
     /// Toggle wireguard-nt on or off
     #[cfg(target_os = "windows")]
     UseWireGuardNt(ResponseTx<(), Error>, bool),
@@ -284,6 +472,33 @@ pub enum DaemonCommand {
     CheckVolumes(ResponseTx<(), Error>),
     /// Register settings for WireGuard obfuscator
     SetObfuscationSettings(ResponseTx<(), settings::Error>, ObfuscationSettings),
+    /// Create an empty custom list with the given name
+    CreateCustomList(ResponseTx<(), Error>, String),
+    /// Delete the custom list with the given name
+    DeleteCustomList(ResponseTx<(), Error>, String),
+    /// Replace the locations of an existing custom list
+    UpdateCustomList(ResponseTx<(), Error>, CustomList),
+    /// Set the retry strategy to use instead of the built-in one
+    SetEscalationLadder(ResponseTx<(), Error>, EscalationLadder),
+    /// Set whether relays with a high load should be less likely to be selected
+    SetLoadAwareSelection(ResponseTx<(), settings::Error>, bool),
+    /// Set how many exit relays of the most recent connections to avoid
+    SetAvoidRecentRelays(ResponseTx<(), settings::Error>, u32),
+    /// Set whether relay endpoints should be reached over IPv6 when possible
+    SetPreferIpv6(ResponseTx<(), settings::Error>, bool),
+    /// Set whether the obfuscator of the last connection should be reused when reconnecting
+    SetReuseObfuscator(ResponseTx<(), settings::Error>, bool),
+    /// Set whether relays with a lower latency should be preferred
+    SetLatencyAwareSelection(ResponseTx<(), settings::Error>, bool),
+    /// Set after how many failures the exit relay of the last successful connection is no longer
+    /// reused
+    SetStickyRelay(ResponseTx<(), settings::Error>, u32),
+    /// Set how the tunnel protocol is chosen when any tunnel protocol is allowed
+    SetProtocolPreference(ResponseTx<(), settings::Error>, Option<ProtocolPreference>),
+    /// Set where relay lists are obtained from
+    SetRelayListSource(ResponseTx<(), Error>, RelayListSource),
+    /// Set when to reconnect to a newly selected relay while connected
+    SetRelayRotationPolicy(ResponseTx<(), Error>, RelayRotationPolicy),
     /// Saves the target tunnel state and enters a blocking state. The state is restored
     /// upon restart.
     PrepareRestart,
@@ -311,6 +526,22 @@ pub(crate) enum InternalDaemonEvent {
     /// The split tunnel paths or state were updated.
     #[cfg(target_os = "windows")]
     ExcludedPathsEvent(ExcludedPathsUpdate, oneshot::Sender<Result<(), Error>>),
+    /// The relay rotation policy says that it is time to reconnect to a new relay.
+    RotateRelay,
+    /// Relays were selected for a connection attempt, or no relays could be selected.
+    RelaySelection(RelaySelectionEvent),
+    /// The excluded domains resolved to a new set of addresses.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    ExcludedDomainsResolved(excluded_domains::ResolvedDomains),
+    /// The host joined another network, or lost its connection to the network.
+    #[cfg(not(target_os = "android"))]
+    NetworkChanged(Option<NetworkIdentity>),
+    /// A period of the connection schedules started or ended.
+    #[cfg(not(target_os = "android"))]
+    ScheduleChanged(schedule::ScheduleChanged),
+    /// The DNS blocklists were loaded or updated.
+    #[cfg(not(target_os = "android"))]
+    DnsBlocklistsLoaded(dns_blocklist::BlocklistsLoaded),
 }
 
 #[cfg(target_os = "windows")]
@@ -343,6 +574,40 @@ impl From<AccountEvent> for InternalDaemonEvent {
     }
 }
 
+impl From<RelaySelectionEvent> for InternalDaemonEvent {
+    fn from(event: RelaySelectionEvent) -> Self {
+        InternalDaemonEvent::RelaySelection(event)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl From<excluded_domains::ResolvedDomains> for InternalDaemonEvent {
+    fn from(addresses: excluded_domains::ResolvedDomains) -> Self {
+        InternalDaemonEvent::ExcludedDomainsResolved(addresses)
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl From<Option<NetworkIdentity>> for InternalDaemonEvent {
+    fn from(network: Option<NetworkIdentity>) -> Self {
+        InternalDaemonEvent::NetworkChanged(network)
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl From<schedule::ScheduleChanged> for InternalDaemonEvent {
+    fn from(event: schedule::ScheduleChanged) -> Self {
+        InternalDaemonEvent::ScheduleChanged(event)
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl From<dns_blocklist::BlocklistsLoaded> for InternalDaemonEvent {
+    fn from(event: dns_blocklist::BlocklistsLoaded) -> Self {
+        InternalDaemonEvent::DnsBlocklistsLoaded(event)
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum DaemonExecutionState {
     Running,
@@ -525,21 +790,46 @@ pub trait EventListener {
 
     /// Notify that a device was revoked using `RemoveDevice`.
     fn notify_remove_device_event(&self, event: RemoveDeviceEvent);
+
+    /// Notify that the daemon is reconnecting to rotate relays.
+    fn notify_relay_rotation(&self, event: RelayRotationEvent);
+
+    /// Notify that relays were selected for a connection attempt, or that none could be selected.
+    fn notify_relay_selection(&self, event: RelaySelectionEvent);
 }
 
 pub struct Daemon<L: EventListener> {
     tunnel_state: TunnelState,
     target_state: PersistentTargetState,
     state: DaemonExecutionState,
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     exclude_pids: split_tunnel::PidManager,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    domain_resolver: excluded_domains::DomainResolver,
+    /// Addresses that the excluded domains last resolved to.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    resolved_excluded_addresses: Vec<IpAddr>,
+    /// The network that the host is connected to, if it is known.
+    #[cfg(not(target_os = "android"))]
+    current_network: Option<NetworkIdentity>,
+    #[cfg(not(target_os = "android"))]
+    schedule_timer: schedule::ScheduleTimer,
+    #[cfg(not(target_os = "android"))]
+    dns_forwarder: dns_forwarder::DnsForwarder,
+    #[cfg(not(target_os = "android"))]
+    dns_blocklist_updater: dns_blocklist::BlocklistUpdater,
+    /// Status of the DNS blocklists that have been loaded.
+    #[cfg(not(target_os = "android"))]
+    dns_blocklist_statuses: HashMap<BlocklistSource, BlocklistStatus>,
     rx: mpsc::UnboundedReceiver<InternalDaemonEvent>,
     tx: DaemonEventSender,
     reconnection_job: Option<AbortHandle>,
+    relay_rotation_job: Option<AbortHandle>,
     event_listener: L,
     migration_complete: migrations::MigrationComplete,
     settings: SettingsPersister,
     account_history: account_history::AccountHistory,
+    connection_history: connection_history::ConnectionHistory,
     device_checker: device::TunnelStateChangeHandler,
     account_manager: device::AccountManagerHandle,
     api_runtime: mullvad_api::Runtime,
@@ -548,6 +838,7 @@ pub struct Daemon<L: EventListener> {
     relay_selector: RelaySelector,
     relay_list_updater: RelayListUpdaterHandle,
     parameters_generator: tunnel::ParametersGenerator,
+    traffic_monitor: traffic_stats::TrafficMonitor,
     app_version_info: Option<AppVersionInfo>,
     shutdown_tasks: Vec<Pin<Box<dyn Future<Output = ()>>>>,
     tunnel_state_machine_handle: TunnelStateMachineHandle,
@@ -606,6 +897,12 @@ where
 
         let initial_selector_config = new_selector_config(&settings, &app_version_info);
         let relay_selector = RelaySelector::new(initial_selector_config, &resource_dir, &cache_dir);
+        relay_selector.set_load_aware(settings.load_aware_selection);
+        relay_selector.set_prefer_ipv6(settings.prefer_ipv6);
+        relay_selector
+            .set_quantum_resistant(settings.tunnel_options.wireguard.negotiates_ephemeral_key());
+        relay_selector.set_daita(settings.tunnel_options.wireguard.options.daita);
+        relay_selector.set_port_fallback(settings.tunnel_options.wireguard.port_fallback);
 
         let proxy_provider =
             api::ApiConnectionModeProvider::new(cache_dir.clone(), relay_selector.clone());
@@ -642,6 +939,7 @@ where
         )
         .await
         .map_err(Error::LoadAccountHistory)?;
+        let connection_history = connection_history::ConnectionHistory::load(&cache_dir).await;
 
         let target_state = if settings.auto_connect {
             log::info!("Automatically connecting since auto-connect is turned on");
@@ -664,23 +962,46 @@ where
 
         let initial_api_endpoint =
             api::get_allowed_endpoint(api_runtime.address_cache.get_address().await);
-        let parameters_generator = tunnel::ParametersGenerator::new(
-            account_manager.clone(),
-            relay_selector.clone(),
-            settings.tunnel_options.clone(),
-        );
+        let mut parameters_generator_builder =
+            tunnel::ParametersGenerator::builder(account_manager.clone(), relay_selector.clone())
+                .tunnel_options(settings.tunnel_options.clone())
+                .constraints_override(tunnel::constraints_override_from_env())
+                .reuse_obfuscator(settings.reuse_obfuscator)
+                .latency_aware(settings.latency_aware_selection)
+                .sticky_relay(settings.sticky_relay_max_failures)
+                .protocol_preference(settings.protocol_preference)
+                .cache_dir(&cache_dir)
+                .selection_events(internal_event_tx.to_specialized_sender());
+        if let Some(endpoint) = tunnel::endpoint_override_from_env() {
+            parameters_generator_builder =
+                parameters_generator_builder.endpoint_rewrite(move |_| endpoint);
+        }
+        let parameters_generator = parameters_generator_builder.build();
+        parameters_generator
+            .set_avoid_recent_relays(settings.avoid_recent_relays)
+            .await;
         let (offline_state_tx, offline_state_rx) = mpsc::unbounded();
         #[cfg(target_os = "windows")]
         let (volume_update_tx, volume_update_rx) = mpsc::unbounded();
         let tunnel_state_machine_handle = tunnel_state_machine::spawn(
             tunnel_state_machine::InitialTunnelState {
                 allow_lan: settings.allow_lan,
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                lan_rules: settings.lan_rules.clone(),
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                firewall_exceptions: settings.firewall_exceptions.clone(),
                 block_when_disconnected: settings.block_when_disconnected,
-                dns_servers: dns::addresses_from_options(&settings.tunnel_options.dns_options),
+                dns_servers: dns::dns_servers(&settings),
+                #[cfg(not(target_os = "android"))]
+                dns_upstream: dns::tunnel_upstream(&settings),
                 allowed_endpoint: initial_api_endpoint,
                 reset_firewall: *target_state != TargetState::Secured,
                 #[cfg(windows)]
                 exclude_paths,
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                excluded_networks: settings.excluded_destinations.networks.clone(),
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                split_tunnel_mode: settings.split_tunnel_mode,
             },
             parameters_generator.clone(),
             log_dir,
@@ -716,6 +1037,7 @@ where
             relay_selector.clone(),
             api_handle.clone(),
             &cache_dir,
+            settings.relay_list_source.clone(),
             on_relay_list_update,
         );
 
@@ -732,19 +1054,56 @@ where
         // Attempt to download a fresh relay list
         relay_list_updater.update().await;
 
+        #[cfg(not(target_os = "android"))]
+        talpid_core::network_identity::spawn_watcher(internal_event_tx.to_specialized_sender());
+
         let daemon = Daemon {
             tunnel_state: TunnelState::Disconnected,
             target_state,
             state: DaemonExecutionState::Running,
             #[cfg(target_os = "linux")]
             exclude_pids: split_tunnel::PidManager::new().map_err(Error::InitSplitTunneling)?,
+            #[cfg(target_os = "macos")]
+            exclude_pids: split_tunnel::PidManager::new(exclusion_gid),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            domain_resolver: excluded_domains::DomainResolver::spawn(
+                settings.excluded_destinations.domains.clone(),
+                internal_event_tx.to_specialized_sender(),
+            ),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            resolved_excluded_addresses: vec![],
+            #[cfg(not(target_os = "android"))]
+            current_network: None,
+            #[cfg(not(target_os = "android"))]
+            schedule_timer: schedule::ScheduleTimer::spawn(
+                settings.schedules.clone(),
+                internal_event_tx.to_specialized_sender(),
+            ),
+            #[cfg(not(target_os = "android"))]
+            dns_forwarder: dns_forwarder::DnsForwarder::spawn(
+                dns::forwarder_upstream(&settings),
+                {
+                    let parameters_generator = parameters_generator.clone();
+                    Arc::new(move || parameters_generator.tunnel_gateways())
+                },
+            ),
+            #[cfg(not(target_os = "android"))]
+            dns_blocklist_updater: dns_blocklist::BlocklistUpdater::spawn(
+                settings.dns_blocklists.clone(),
+                &cache_dir,
+                internal_event_tx.to_specialized_sender(),
+            ),
+            #[cfg(not(target_os = "android"))]
+            dns_blocklist_statuses: HashMap::new(),
             rx: internal_event_rx,
             tx: internal_event_tx,
             reconnection_job: None,
+            relay_rotation_job: None,
             event_listener,
             migration_complete,
             settings,
             account_history,
+            connection_history,
             device_checker: device::TunnelStateChangeHandler::new(account_manager.clone()),
             account_manager,
             api_runtime,
@@ -753,6 +1112,7 @@ where
             relay_selector,
             relay_list_updater,
             parameters_generator,
+            traffic_monitor: traffic_stats::TrafficMonitor::spawn(),
             app_version_info,
             shutdown_tasks: vec![],
             tunnel_state_machine_handle,
@@ -769,6 +1129,13 @@ where
     /// shutdown event is received.
     pub async fn run(mut self) -> Result<(), Error> {
         if *self.target_state == TargetState::Secured {
+            // Without a relay list, the first connection attempt would fail immediately
+            if let Err(error) = self.parameters_generator.ensure_relays_ready().await {
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg("Connecting without a relay list")
+                );
+            }
             self.connect_tunnel();
         }
 
@@ -849,6 +1216,24 @@ where
             DeviceMigrationEvent(event) => self.handle_device_migration_event(event).await,
             #[cfg(windows)]
             ExcludedPathsEvent(update, tx) => self.handle_new_excluded_paths(update, tx).await,
+            RotateRelay => self.handle_relay_rotation(),
+            RelaySelection(event) => self.event_listener.notify_relay_selection(event),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ExcludedDomainsResolved(excluded_domains::ResolvedDomains(addresses)) => {
+                self.resolved_excluded_addresses = addresses;
+                self.update_excluded_networks();
+            }
+            #[cfg(not(target_os = "android"))]
+            NetworkChanged(network) => self.handle_network_change(network).await,
+            #[cfg(not(target_os = "android"))]
+            ScheduleChanged(schedule::ScheduleChanged(active)) => {
+                self.handle_schedule_change(active).await
+            }
+            #[cfg(not(target_os = "android"))]
+            DnsBlocklistsLoaded(dns_blocklist::BlocklistsLoaded { domains, statuses }) => {
+                self.dns_forwarder.set_blocked_domains(domains);
+                self.dns_blocklist_statuses = statuses;
+            }
         }
     }
 
@@ -860,6 +1245,8 @@ where
             .await;
         self.device_checker
             .handle_state_transition(&tunnel_state_transition);
+        self.record_connection_attempt(&tunnel_state_transition)
+            .await;
 
         let tunnel_state = match tunnel_state_transition {
             TunnelStateTransition::Disconnected => TunnelState::Disconnected,
@@ -867,10 +1254,13 @@ where
                 endpoint,
                 location: self.parameters_generator.get_last_location().await,
             },
-            TunnelStateTransition::Connected(endpoint) => TunnelState::Connected {
-                endpoint,
-                location: self.parameters_generator.get_last_location().await,
-            },
+            TunnelStateTransition::Connected(endpoint) => {
+                let location = self.parameters_generator.get_last_location().await;
+                if let Some(hostname) = location.as_ref().and_then(|l| l.hostname.as_deref()) {
+                    self.parameters_generator.report_success(hostname).await;
+                }
+                TunnelState::Connected { endpoint, location }
+            }
             TunnelStateTransition::Disconnecting(after_disconnect) => {
                 TunnelState::Disconnecting(after_disconnect)
             }
@@ -883,12 +1273,31 @@ where
             // aborted.
             self.unschedule_reconnect();
         }
+        if tunnel_state.is_connected() {
+            self.schedule_relay_rotation();
+            self.traffic_monitor
+                .set_interface(self.parameters_generator.tunnel_interface());
+        } else {
+            self.unschedule_relay_rotation();
+            self.traffic_monitor.set_interface(None);
+        }
 
         log::debug!("New tunnel state: {:?}", tunnel_state);
 
         match tunnel_state {
             TunnelState::Disconnected => {
                 self.api_handle.availability.reset_inactivity_timer();
+                // Look up the location of the client again, since the network may have changed
+                let location = self.get_geo_location().await;
+                let parameters_generator = self.parameters_generator.clone();
+                tokio::spawn(async move {
+                    if let Ok(location) = location.await {
+                        parameters_generator
+                            .set_client_location(Some(&location))
+                            .await;
+                    }
+                    parameters_generator.refresh_relay_latencies().await
+                });
             }
             _ => {
                 self.api_handle.availability.stop_inactivity_timer();
@@ -936,6 +1345,40 @@ where
         };
     }
 
+    async fn record_connection_attempt(&mut self, tunnel_state_transition: &TunnelStateTransition) {
+        match tunnel_state_transition {
+            TunnelStateTransition::Connecting(endpoint) => {
+                let selection = self.parameters_generator.selection_snapshot().await;
+                self.connection_history
+                    .start_attempt(endpoint, selection)
+                    .await;
+            }
+            TunnelStateTransition::Connected(_) => self.connection_history.set_connected().await,
+            TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Nothing) => {
+                self.connection_history
+                    .end_attempt("Disconnected".to_owned())
+                    .await
+            }
+            TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Reconnect) => {
+                self.connection_history
+                    .end_attempt("Reconnected".to_owned())
+                    .await
+            }
+            // The cause is recorded once the error state is entered
+            TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Block) => (),
+            TunnelStateTransition::Error(error_state) => {
+                self.connection_history
+                    .end_attempt(error_state.cause().to_string())
+                    .await
+            }
+            TunnelStateTransition::Disconnected => {
+                self.connection_history
+                    .end_attempt("Disconnected".to_owned())
+                    .await
+            }
+        }
+    }
+
     fn schedule_reconnect(&mut self, delay: Duration) {
         self.unschedule_reconnect();
 
@@ -959,6 +1402,52 @@ where
         }
     }
 
+    /// Schedules a reconnect to a new relay according to the relay rotation policy, counting from
+    /// now. Any previously scheduled rotation is cancelled.
+    fn schedule_relay_rotation(&mut self) {
+        self.unschedule_relay_rotation();
+
+        let policy = self.settings.tunnel_options.relay_rotation;
+        let delay = match policy.time_until_rotation(chrono::Local::now().naive_local()) {
+            Some(delay) => delay,
+            None => return,
+        };
+        log::debug!(
+            "Rotating relays in {} minutes ({})",
+            delay.as_secs() / 60,
+            policy
+        );
+
+        let daemon_event_tx = self.tx.clone();
+        let (future, abort_handle) = abortable(Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            let _ = daemon_event_tx.send(InternalDaemonEvent::RotateRelay);
+        }));
+
+        tokio::spawn(future);
+        self.relay_rotation_job = Some(abort_handle);
+    }
+
+    fn unschedule_relay_rotation(&mut self) {
+        if let Some(job) = self.relay_rotation_job.take() {
+            job.abort();
+        }
+    }
+
+    fn handle_relay_rotation(&mut self) {
+        self.relay_rotation_job = None;
+        let previous_relay = match &self.tunnel_state {
+            TunnelState::Connected { location, .. } => location
+                .as_ref()
+                .and_then(|location| location.hostname.clone()),
+            _ => return,
+        };
+        log::info!("Reconnecting to rotate relays");
+        self.event_listener
+            .notify_relay_rotation(RelayRotationEvent { previous_relay });
+        self.reconnect_tunnel();
+    }
+
     async fn handle_command(&mut self, command: DaemonCommand) {
         use self::DaemonCommand::*;
         if !self.state.is_running() {
@@ -980,6 +1469,20 @@ where
             GetWwwAuthToken(tx) => self.on_get_www_auth_token(tx).await,
             SubmitVoucher(tx, voucher) => self.on_submit_voucher(tx, voucher).await,
             GetRelayLocations(tx) => self.on_get_relay_locations(tx),
+            GetRelaySelection(tx) => self.on_get_relay_selection(tx).await,
+            ExplainRelaySelection(tx) => self.on_explain_relay_selection(tx).await,
+            GetLastRelayChain(tx) => self.on_get_last_relay_chain(tx).await,
+            GetTrafficStats(tx) => self.on_get_traffic_stats(tx),
+            ListenTrafficStats(tx) => self.on_listen_traffic_stats(tx),
+            PreviewRelays(tx, count) => self.on_preview_relays(tx, count).await,
+            GetRelayStats(tx) => self.on_get_relay_stats(tx).await,
+            RunSelfTest(tx) => self.on_run_self_test(tx).await,
+            CheckCanConnect(tx) => self.on_check_can_connect(tx).await,
+            SetSessionExcludedRelays(tx, hostnames) => {
+                self.on_set_session_excluded_relays(tx, hostnames).await
+            }
+            GetSessionExcludedRelays(tx) => self.on_get_session_excluded_relays(tx).await,
+            BenchmarkRelays(tx, location) => self.on_benchmark_relays(tx, location),
             UpdateRelayLocations => self.on_update_relay_locations().await,
             LoginAccount(tx, account_token) => self.on_login_account(tx, account_token),
             LogoutAccount(tx) => self.on_logout_account(tx),
@@ -991,8 +1494,12 @@ where
             }
             GetAccountHistory(tx) => self.on_get_account_history(tx),
             ClearAccountHistory(tx) => self.on_clear_account_history(tx).await,
+            GetConnectionHistory(tx) => self.on_get_connection_history(tx),
+            ClearConnectionHistory(tx) => self.on_clear_connection_history(tx).await,
             UpdateRelaySettings(tx, update) => self.on_update_relay_settings(tx, update).await,
             SetAllowLan(tx, allow_lan) => self.on_set_allow_lan(tx, allow_lan).await,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            SetLanRules(tx, lan_rules) => self.on_set_lan_rules(tx, lan_rules).await,
             SetShowBetaReleases(tx, enabled) => self.on_set_show_beta_releases(tx, enabled).await,
             SetBlockWhenDisconnected(tx, block_when_disconnected) => {
                 self.on_set_block_when_disconnected(tx, block_when_disconnected)
@@ -1005,23 +1512,35 @@ where
             }
             SetBridgeState(tx, bridge_state) => self.on_set_bridge_state(tx, bridge_state).await,
             SetEnableIpv6(tx, enable_ipv6) => self.on_set_enable_ipv6(tx, enable_ipv6).await,
-            SetQuantumResistantTunnel(tx, enable_pq) => {
-                self.on_set_quantum_resistant_tunnel(tx, enable_pq).await
+            SetQuantumResistantTunnel(tx, quantum_resistant) => {
+                self.on_set_quantum_resistant_tunnel(tx, quantum_resistant)
+                    .await
+            }
+            SetWireguardEphemeralKeys(tx, enabled) => {
+                self.on_set_wireguard_ephemeral_keys(tx, enabled).await
             }
             SetDnsOptions(tx, dns_servers) => self.on_set_dns_options(tx, dns_servers).await,
             SetWireguardMtu(tx, mtu) => self.on_set_wireguard_mtu(tx, mtu).await,
+            SetWireguardMtuDetection(tx, enabled) => {
+                self.on_set_wireguard_mtu_detection(tx, enabled).await
+            }
+            SetWireguardDaita(tx, enabled) => self.on_set_wireguard_daita(tx, enabled).await,
+            SetWireguardPortFallback(tx, port_fallback) => {
+                self.on_set_wireguard_port_fallback(tx, port_fallback).await
+            }
             SetWireguardRotationInterval(tx, interval) => {
                 self.on_set_wireguard_rotation_interval(tx, interval).await
             }
             GetSettings(tx) => self.on_get_settings(tx),
             RotateWireguardKey(tx) => self.on_rotate_wireguard_key(tx).await,
             GetWireguardKey(tx) => self.on_get_wireguard_key(tx).await,
+            ExportWireguardConfig(tx) => self.on_export_wireguard_config(tx).await,
             GetVersionInfo(tx) => self.on_get_version_info(tx).await,
             IsPerformingPostUpgrade(tx) => self.on_is_performing_post_upgrade(tx).await,
             GetCurrentVersion(tx) => self.on_get_current_version(tx),
             #[cfg(not(target_os = "android"))]
             FactoryReset(tx) => self.on_factory_reset(tx).await,
-            #[cfg(target_os = "linux")]
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
             GetSplitTunnelProcesses(tx) => self.on_get_split_tunnel_processes(tx),
             #[cfg(target_os = "linux")]
             AddSplitTunnelProcess(tx, pid) => self.on_add_split_tunnel_process(tx, pid),
@@ -1039,6 +1558,56 @@ where
             SetSplitTunnelState(tx, enabled) => self.on_set_split_tunnel_state(tx, enabled).await,
             #[cfg(windows)]
             GetSplitTunnelProcesses(tx) => self.on_get_split_tunnel_processes(tx),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            AddExcludedDestination(tx, destination) => {
+                self.on_add_excluded_destination(tx, destination).await
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            RemoveExcludedDestination(tx, destination) => {
+                self.on_remove_excluded_destination(tx, destination).await
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ClearExcludedDestinations(tx) => self.on_clear_excluded_destinations(tx).await,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            SetSplitTunnelMode(tx, mode) => self.on_set_split_tunnel_mode(tx, mode).await,
+            #[cfg(not(target_os = "android"))]
+            SetNetworkRule(tx, rule) => self.on_set_network_rule(tx, rule).await,
+            #[cfg(not(target_os = "android"))]
+            RemoveNetworkRule(tx, network) => self.on_remove_network_rule(tx, network).await,
+            #[cfg(not(target_os = "android"))]
+            SetNetworkLockdown(tx, lockdown) => self.on_set_network_lockdown(tx, lockdown).await,
+            #[cfg(not(target_os = "android"))]
+            GetCurrentNetwork(tx) => self.on_get_current_network(tx),
+            #[cfg(not(target_os = "android"))]
+            AddSchedule(tx, schedule) => self.on_add_schedule(tx, schedule).await,
+            #[cfg(not(target_os = "android"))]
+            RemoveSchedule(tx, index) => self.on_remove_schedule(tx, index).await,
+            #[cfg(not(target_os = "android"))]
+            ClearSchedules(tx) => self.on_clear_schedules(tx).await,
+            #[cfg(not(target_os = "android"))]
+            GetScheduleState(tx) => self.on_get_schedule_state(tx),
+            #[cfg(not(target_os = "android"))]
+            AddDnsBlocklist(tx, blocklist) => self.on_add_dns_blocklist(tx, blocklist).await,
+            #[cfg(not(target_os = "android"))]
+            RemoveDnsBlocklist(tx, index) => self.on_remove_dns_blocklist(tx, index).await,
+            #[cfg(not(target_os = "android"))]
+            SetDnsBlocklistEnabled(tx, index, enabled) => {
+                self.on_set_dns_blocklist_enabled(tx, index, enabled).await
+            }
+            #[cfg(not(target_os = "android"))]
+            UpdateDnsBlocklists(tx) => self.on_update_dns_blocklists(tx),
+            #[cfg(not(target_os = "android"))]
+            GetDnsBlocklistStatus(tx) => self.on_get_dns_blocklist_status(tx),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            AddFirewallException(tx, exception) => {
+                self.on_add_firewall_exception(tx, exception).await
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            RemoveFirewallException(tx, index) => {
+                self.on_remove_firewall_exception(tx, index).await
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ClearFirewallExceptions(tx) => self.on_clear_firewall_exceptions(tx).await,
             #[cfg(target_os = "windows")]
             UseWireGuardNt(tx, state) => self.on_use_wireguard_nt(tx, state).await,
             #[cfg(target_os = "windows")]
@@ -1046,6 +1615,27 @@ where
             SetObfuscationSettings(tx, settings) => {
                 self.on_set_obfuscation_settings(tx, settings).await
             }
+            CreateCustomList(tx, name) => self.on_create_custom_list(tx, name).await,
+            DeleteCustomList(tx, name) => self.on_delete_custom_list(tx, name).await,
+            UpdateCustomList(tx, list) => self.on_update_custom_list(tx, list).await,
+            SetEscalationLadder(tx, ladder) => self.on_set_escalation_ladder(tx, ladder).await,
+            SetLoadAwareSelection(tx, enabled) => {
+                self.on_set_load_aware_selection(tx, enabled).await
+            }
+            SetAvoidRecentRelays(tx, count) => self.on_set_avoid_recent_relays(tx, count).await,
+            SetPreferIpv6(tx, enabled) => self.on_set_prefer_ipv6(tx, enabled).await,
+            SetReuseObfuscator(tx, enabled) => self.on_set_reuse_obfuscator(tx, enabled).await,
+            SetLatencyAwareSelection(tx, enabled) => {
+                self.on_set_latency_aware_selection(tx, enabled).await
+            }
+            SetStickyRelay(tx, max_failures) => self.on_set_sticky_relay(tx, max_failures).await,
+            SetProtocolPreference(tx, preference) => {
+                self.on_set_protocol_preference(tx, preference).await
+            }
+            SetRelayListSource(tx, source) => self.on_set_relay_list_source(tx, source).await,
+            SetRelayRotationPolicy(tx, policy) => {
+                self.on_set_relay_rotation_policy(tx, policy).await
+            }
             PrepareRestart => self.on_prepare_restart(),
             #[cfg(target_os = "android")]
             BypassSocket(fd, tx) => self.on_bypass_socket(fd, tx),
@@ -1060,6 +1650,24 @@ where
     }
 
     async fn handle_device_event(&mut self, event: AccountEvent) {
+        if let AccountEvent::Device(
+            PrivateDeviceEvent::Login(device)
+            | PrivateDeviceEvent::Updated(device)
+            | PrivateDeviceEvent::RotatedKey(device),
+        ) = &event
+        {
+            let stale_credentials = self
+                .parameters_generator
+                .update_credentials(&device.account_token)
+                .await;
+            if stale_credentials
+                && !matches!(&event, AccountEvent::Device(PrivateDeviceEvent::Login(_)))
+                && *self.target_state == TargetState::Secured
+            {
+                log::debug!("Initiating tunnel restart because the OpenVPN credentials changed");
+                self.reconnect_tunnel();
+            }
+        }
         match &event {
             AccountEvent::Device(PrivateDeviceEvent::Login(device)) => {
                 if let Err(error) = self.account_history.set(device.account_token.clone()).await {
@@ -1215,8 +1823,13 @@ where
         match &self.tunnel_state {
             Disconnected => {
                 let location = self.get_geo_location().await;
-                tokio::spawn(async {
-                    Self::oneshot_send(tx, location.await.ok(), "current location");
+                let parameters_generator = self.parameters_generator.clone();
+                tokio::spawn(async move {
+                    let location = location.await.ok();
+                    parameters_generator
+                        .set_client_location(location.as_ref())
+                        .await;
+                    Self::oneshot_send(tx, location, "current location");
                 });
             }
             Connecting { location, .. } => {
@@ -1352,6 +1965,125 @@ where
         Self::oneshot_send(tx, self.relay_selector.get_locations(), "relay locations");
     }
 
+    async fn on_get_relay_selection(
+        &mut self,
+        tx: oneshot::Sender<Option<tunnel::SelectionSnapshot>>,
+    ) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.selection_snapshot().await,
+            "relay selection",
+        );
+    }
+
+    async fn on_run_self_test(&mut self, tx: oneshot::Sender<tunnel::SelfTestReport>) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.self_test().await,
+            "self-test report",
+        );
+    }
+
+    async fn on_explain_relay_selection(&mut self, tx: oneshot::Sender<Option<String>>) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.explain_last_selection().await,
+            "relay selection explanation",
+        );
+    }
+
+    async fn on_get_last_relay_chain(&mut self, tx: oneshot::Sender<Option<tunnel::RelayChain>>) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.last_relay_chain().await,
+            "relay chain",
+        );
+    }
+
+    fn on_get_traffic_stats(&mut self, tx: oneshot::Sender<Vec<TrafficSample>>) {
+        Self::oneshot_send(tx, self.traffic_monitor.history(), "traffic stats");
+    }
+
+    fn on_listen_traffic_stats(
+        &mut self,
+        tx: oneshot::Sender<tokio::sync::mpsc::UnboundedReceiver<TrafficSample>>,
+    ) {
+        Self::oneshot_send(
+            tx,
+            self.traffic_monitor.subscribe(),
+            "traffic stats receiver",
+        );
+    }
+
+    async fn on_preview_relays(
+        &mut self,
+        tx: oneshot::Sender<Vec<tunnel::RelayPreview>>,
+        count: u32,
+    ) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.preview_relays(count).await,
+            "relay preview",
+        );
+    }
+
+    async fn on_get_relay_stats(
+        &mut self,
+        tx: oneshot::Sender<HashMap<String, tunnel::RelayStat>>,
+    ) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.relay_stats().await,
+            "relay stats",
+        );
+    }
+
+    async fn on_check_can_connect(
+        &mut self,
+        tx: oneshot::Sender<Result<(), tunnel::UnsatisfiableReason>>,
+    ) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.can_generate().await,
+            "can connect response",
+        );
+    }
+
+    async fn on_set_session_excluded_relays(
+        &mut self,
+        tx: oneshot::Sender<()>,
+        hostnames: Vec<String>,
+    ) {
+        self.parameters_generator
+            .set_session_excluded_relays(hostnames)
+            .await;
+        Self::oneshot_send(tx, (), "set_session_excluded_relays response");
+    }
+
+    async fn on_get_session_excluded_relays(&mut self, tx: oneshot::Sender<Vec<String>>) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator.session_excluded_relays().await,
+            "session excluded relays",
+        );
+    }
+
+    fn on_benchmark_relays(
+        &mut self,
+        tx: oneshot::Sender<Vec<relay_latency::RelayBenchmark>>,
+        location: Constraint<LocationConstraint>,
+    ) {
+        // Benchmarking takes a few seconds, so it must not block the daemon
+        let parameters_generator = self.parameters_generator.clone();
+        tokio::spawn(async move {
+            Self::oneshot_send(
+                tx,
+                parameters_generator.benchmark_relays(location).await,
+                "relay benchmark",
+            );
+        });
+    }
+
     async fn on_update_relay_locations(&mut self) {
         self.relay_list_updater.update().await;
     }
@@ -1472,6 +2204,23 @@ where
         Self::oneshot_send(tx, result, "clear_account_history response");
     }
 
+    fn on_get_connection_history(&mut self, tx: oneshot::Sender<Vec<ConnectionAttempt>>) {
+        Self::oneshot_send(
+            tx,
+            self.connection_history.attempts(),
+            "get_connection_history response",
+        );
+    }
+
+    async fn on_clear_connection_history(&mut self, tx: ResponseTx<(), Error>) {
+        let result = self
+            .connection_history
+            .clear()
+            .await
+            .map_err(Error::ConnectionHistory);
+        Self::oneshot_send(tx, result, "clear_connection_history response");
+    }
+
     async fn on_get_version_info(&mut self, tx: oneshot::Sender<Option<AppVersionInfo>>) {
         if self.app_version_info.is_none() {
             log::debug!("No version cache found. Fetching new info");
@@ -1550,7 +2299,7 @@ where
         }));
     }
 
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     fn on_get_split_tunnel_processes(&mut self, tx: ResponseTx<Vec<i32>, split_tunnel::Error>) {
         let result = self.exclude_pids.list().map_err(|error| {
             log::error!("{}", error.display_chain_with_msg("Unable to obtain PIDs"));
@@ -1728,243 +2477,1047 @@ where
         );
     }
 
-    #[cfg(windows)]
-    async fn on_use_wireguard_nt(&mut self, tx: ResponseTx<(), Error>, state: bool) {
-        let save_result = self
-            .settings
-            .set_use_wireguard_nt(state)
-            .await
-            .map_err(Error::SettingsError);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_add_excluded_destination(
+        &mut self,
+        tx: ResponseTx<(), Error>,
+        destination: String,
+    ) {
+        let result = match destination.parse::<ExcludedDestination>() {
+            Ok(destination) => {
+                let mut destinations = self.settings.excluded_destinations.clone();
+                destinations.add(destination);
+                self.set_excluded_destinations(destinations).await
+            }
+            Err(error) => Err(Error::InvalidExcludedDestination(error)),
+        };
+        Self::oneshot_send(tx, result, "add_excluded_destination response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_remove_excluded_destination(
+        &mut self,
+        tx: ResponseTx<(), Error>,
+        destination: String,
+    ) {
+        let result = match destination.parse::<ExcludedDestination>() {
+            Ok(destination) => {
+                let mut destinations = self.settings.excluded_destinations.clone();
+                destinations.remove(&destination);
+                self.set_excluded_destinations(destinations).await
+            }
+            Err(error) => Err(Error::InvalidExcludedDestination(error)),
+        };
+        Self::oneshot_send(tx, result, "remove_excluded_destination response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_clear_excluded_destinations(&mut self, tx: ResponseTx<(), Error>) {
+        let result = self.set_excluded_destinations(Default::default()).await;
+        Self::oneshot_send(tx, result, "clear_excluded_destinations response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn set_excluded_destinations(
+        &mut self,
+        destinations: excluded_destinations::ExcludedDestinations,
+    ) -> Result<(), Error> {
+        match self.settings.set_excluded_destinations(destinations).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.domain_resolver
+                        .set_domains(self.settings.excluded_destinations.domains.clone());
+                    self.update_excluded_networks();
+                }
+                Ok(())
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to set excluded destinations")
+                );
+                Err(Error::SettingsError(error))
+            }
+        }
+    }
+
+    /// Sends the excluded networks, and the addresses that the excluded domains resolved to, to
+    /// the tunnel state machine.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn update_excluded_networks(&self) {
+        self.send_tunnel_command(TunnelCommand::SetExcludedNetworks(
+            excluded_domains::excluded_networks(
+                &self.settings.excluded_destinations.networks,
+                &self.resolved_excluded_addresses,
+            ),
+        ));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_set_split_tunnel_mode(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        mode: SplitTunnelMode,
+    ) {
+        let save_result = self.settings.set_split_tunnel_mode(mode).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_split_tunnel_mode response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetSplitTunnelMode(mode));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_split_tunnel_mode response");
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_set_network_rule(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        rule: NetworkRule,
+    ) {
+        let save_result = self.settings.set_network_rule(rule).await;
+        self.on_network_rules_saved(tx, save_result, "set_network_rule response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_remove_network_rule(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        network: NetworkIdentity,
+    ) {
+        let save_result = self.settings.remove_network_rule(&network).await;
+        self.on_network_rules_saved(tx, save_result, "remove_network_rule response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_set_network_lockdown(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        lockdown: bool,
+    ) {
+        let save_result = self.settings.set_network_lockdown(lockdown).await;
+        self.on_network_rules_saved(tx, save_result, "set_network_lockdown response");
+    }
+
+    /// Changed rules only affect whether the current network is locked down. The tunnel is
+    /// connected or disconnected the next time the host joins a network.
+    #[cfg(not(target_os = "android"))]
+    fn on_network_rules_saved(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        save_result: Result<bool, settings::Error>,
+        msg: &'static str,
+    ) {
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), msg);
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::BlockWhenDisconnected(
+                        self.block_when_disconnected(),
+                    ));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), msg);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn on_get_current_network(&self, tx: oneshot::Sender<Option<NetworkIdentity>>) {
+        Self::oneshot_send(tx, self.current_network.clone(), "current network");
+    }
+
+    /// Connects or disconnects according to the rule for the network that the host joined.
+    #[cfg(not(target_os = "android"))]
+    async fn handle_network_change(&mut self, network: Option<NetworkIdentity>) {
+        if network == self.current_network {
+            return;
+        }
+        match &network {
+            Some(network) => log::info!("Joined {}", network),
+            None => log::info!("Not connected to any network"),
+        }
+        self.current_network = network;
+
+        if !self.state.is_running() {
+            return;
+        }
+        self.send_tunnel_command(TunnelCommand::BlockWhenDisconnected(
+            self.block_when_disconnected(),
+        ));
+
+        let trust = self
+            .current_network
+            .as_ref()
+            .and_then(|network| self.settings.network_rules.trust(network));
+        let (trust, target_state) = match trust {
+            Some(trust @ NetworkTrust::Trusted) => (trust, TargetState::Unsecured),
+            Some(trust @ NetworkTrust::Untrusted) => (trust, TargetState::Secured),
+            None => return,
+        };
+        if self.set_target_state(target_state).await {
+            log::info!("Applied rule for {} network", trust);
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_add_schedule(&mut self, tx: ResponseTx<(), Error>, schedule: ConnectionSchedule) {
+        let mut schedules = self.settings.schedules.clone();
+        schedules.push(schedule);
+        let result = self.set_schedules(schedules).await;
+        Self::oneshot_send(tx, result, "add_schedule response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_remove_schedule(&mut self, tx: ResponseTx<(), Error>, index: usize) {
+        let mut schedules = self.settings.schedules.clone();
+        let result = if index < schedules.len() {
+            schedules.remove(index);
+            self.set_schedules(schedules).await
+        } else {
+            Err(Error::ScheduleNotFound(index))
+        };
+        Self::oneshot_send(tx, result, "remove_schedule response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_clear_schedules(&mut self, tx: ResponseTx<(), Error>) {
+        let result = self.set_schedules(vec![]).await;
+        Self::oneshot_send(tx, result, "clear_schedules response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn set_schedules(&mut self, schedules: Vec<ConnectionSchedule>) -> Result<(), Error> {
+        match self.settings.set_schedules(schedules).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.schedule_timer
+                        .set_schedules(self.settings.schedules.clone());
+                }
+                Ok(())
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to set schedules")
+                );
+                Err(Error::SettingsError(error))
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn on_get_schedule_state(&self, tx: oneshot::Sender<ScheduleState>) {
+        let state = schedule::current_state(&self.settings.schedules);
+        Self::oneshot_send(tx, state, "schedule state");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_add_dns_blocklist(&mut self, tx: ResponseTx<(), Error>, blocklist: DnsBlocklist) {
+        let mut blocklists = self.settings.dns_blocklists.clone();
+        let result = if blocklists
+            .iter()
+            .any(|existing| existing.source == blocklist.source)
+        {
+            Err(Error::DnsBlocklistExists(blocklist.source))
+        } else {
+            blocklists.push(blocklist);
+            self.set_dns_blocklists(blocklists).await
+        };
+        Self::oneshot_send(tx, result, "add_dns_blocklist response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_remove_dns_blocklist(&mut self, tx: ResponseTx<(), Error>, index: usize) {
+        let mut blocklists = self.settings.dns_blocklists.clone();
+        let result = if index < blocklists.len() {
+            blocklists.remove(index);
+            self.set_dns_blocklists(blocklists).await
+        } else {
+            Err(Error::DnsBlocklistNotFound(index))
+        };
+        Self::oneshot_send(tx, result, "remove_dns_blocklist response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn on_set_dns_blocklist_enabled(
+        &mut self,
+        tx: ResponseTx<(), Error>,
+        index: usize,
+        enabled: bool,
+    ) {
+        let mut blocklists = self.settings.dns_blocklists.clone();
+        let result = match blocklists.get_mut(index) {
+            Some(blocklist) => {
+                blocklist.enabled = enabled;
+                self.set_dns_blocklists(blocklists).await
+            }
+            None => Err(Error::DnsBlocklistNotFound(index)),
+        };
+        Self::oneshot_send(tx, result, "set_dns_blocklist_enabled response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn set_dns_blocklists(&mut self, blocklists: Vec<DnsBlocklist>) -> Result<(), Error> {
+        match self.settings.set_dns_blocklists(blocklists).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.dns_blocklist_updater
+                        .set_blocklists(self.settings.dns_blocklists.clone());
+                    self.update_dns();
+                }
+                Ok(())
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to set DNS blocklists")
+                );
+                Err(Error::SettingsError(error))
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn on_update_dns_blocklists(&self, tx: oneshot::Sender<()>) {
+        self.dns_blocklist_updater.update_now();
+        Self::oneshot_send(tx, (), "update_dns_blocklists response");
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn on_get_dns_blocklist_status(&self, tx: oneshot::Sender<Vec<Option<BlocklistStatus>>>) {
+        let statuses = self
+            .settings
+            .dns_blocklists
+            .iter()
+            .map(|blocklist| self.dns_blocklist_statuses.get(&blocklist.source).cloned())
+            .collect();
+        Self::oneshot_send(tx, statuses, "dns_blocklist_status response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_add_firewall_exception(
+        &mut self,
+        tx: ResponseTx<(), Error>,
+        exception: FirewallException,
+    ) {
+        let mut exceptions = self.settings.firewall_exceptions.clone();
+        let result = if exceptions.contains(&exception) {
+            Err(Error::FirewallExceptionExists(exception))
+        } else {
+            exceptions.push(exception);
+            self.set_firewall_exceptions(exceptions).await
+        };
+        Self::oneshot_send(tx, result, "add_firewall_exception response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_remove_firewall_exception(&mut self, tx: ResponseTx<(), Error>, index: usize) {
+        let mut exceptions = self.settings.firewall_exceptions.clone();
+        let result = if index < exceptions.len() {
+            exceptions.remove(index);
+            self.set_firewall_exceptions(exceptions).await
+        } else {
+            Err(Error::FirewallExceptionNotFound(index))
+        };
+        Self::oneshot_send(tx, result, "remove_firewall_exception response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_clear_firewall_exceptions(&mut self, tx: ResponseTx<(), Error>) {
+        let result = self.set_firewall_exceptions(vec![]).await;
+        Self::oneshot_send(tx, result, "clear_firewall_exceptions response");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn set_firewall_exceptions(
+        &mut self,
+        exceptions: Vec<FirewallException>,
+    ) -> Result<(), Error> {
+        match self.settings.set_firewall_exceptions(exceptions).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetFirewallExceptions(
+                        self.settings.firewall_exceptions.clone(),
+                    ));
+                }
+                Ok(())
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to set firewall exceptions")
+                );
+                Err(Error::SettingsError(error))
+            }
+        }
+    }
+
+    /// Connects when a period of the schedules starts, and disconnects when it ends.
+    #[cfg(not(target_os = "android"))]
+    async fn handle_schedule_change(&mut self, active: bool) {
+        if !self.state.is_running() {
+            return;
+        }
+        let target_state = if active {
+            TargetState::Secured
+        } else {
+            TargetState::Unsecured
+        };
+        if self.set_target_state(target_state).await {
+            log::info!(
+                "{} since a scheduled period {}",
+                if active {
+                    "Connecting"
+                } else {
+                    "Disconnecting"
+                },
+                if active { "started" } else { "ended" }
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_use_wireguard_nt(&mut self, tx: ResponseTx<(), Error>, state: bool) {
+        let save_result = self
+            .settings
+            .set_use_wireguard_nt(state)
+            .await
+            .map_err(Error::SettingsError);
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "use_wireguard_nt response");
+                Self::oneshot_send(tx, Ok(()), "use_wireguard_nt response");
+                if settings_changed {
+                    self.parameters_generator
+                        .set_tunnel_options(&self.settings.tunnel_options)
+                        .await;
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    if let Some(TunnelType::Wireguard) = self.get_target_tunnel_type() {
+                        log::info!("Initiating tunnel restart");
+                        self.reconnect_tunnel();
+                    }
+                }
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Unable to save settings")
+                );
+                Self::oneshot_send(tx, Err(error), "use_wireguard_nt response");
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_check_volumes(&mut self, tx: ResponseTx<(), Error>) {
+        if self.volume_update_tx.unbounded_send(()).is_ok() {
+            let _ = tx.send(Ok(()));
+        }
+    }
+
+    async fn on_update_relay_settings(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        update: RelaySettingsUpdate,
+    ) {
+        let save_result = self.settings.update_relay_settings(update).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "update_relay_settings response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.relay_selector
+                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
+                    self.parameters_generator.reset_escalation().await;
+                    log::info!("Initiating tunnel restart because the relay settings changed");
+                    self.reconnect_tunnel();
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "update_relay_settings response");
+            }
+        }
+    }
+
+    async fn on_set_allow_lan(&mut self, tx: ResponseTx<(), settings::Error>, allow_lan: bool) {
+        let save_result = self.settings.set_allow_lan(allow_lan).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_allow_lan response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::AllowLan(allow_lan));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_allow_lan response");
+            }
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn on_set_lan_rules(&mut self, tx: ResponseTx<(), Error>, lan_rules: Option<LanRules>) {
+        let non_lan_subnet = lan_rules.iter().find_map(|rules| {
+            rules
+                .subnets
+                .iter()
+                .find(|subnet| !talpid_core::firewall::is_lan_network(subnet))
+        });
+        if let Some(subnet) = non_lan_subnet {
+            Self::oneshot_send(
+                tx,
+                Err(Error::NotLanNetwork(*subnet)),
+                "set_lan_rules response",
+            );
+            return;
+        }
+
+        match self.settings.set_lan_rules(lan_rules.clone()).await {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_lan_rules response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetLanRules(lan_rules));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(Error::SettingsError(e)), "set_lan_rules response");
+            }
+        }
+    }
+
+    async fn on_set_show_beta_releases(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        enabled: bool,
+    ) {
+        let save_result = self.settings.set_show_beta_releases(enabled).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_show_beta_releases response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    let mut handle = self.version_updater_handle.clone();
+                    handle.set_show_beta_releases(enabled).await;
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_show_beta_releases response");
+            }
+        }
+    }
+
+    async fn on_set_block_when_disconnected(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        block_when_disconnected: bool,
+    ) {
+        let save_result = self
+            .settings
+            .set_block_when_disconnected(block_when_disconnected)
+            .await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_block_when_disconnected response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::BlockWhenDisconnected(
+                        self.block_when_disconnected(),
+                    ));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_block_when_disconnected response");
+            }
+        }
+    }
+
+    async fn on_set_auto_connect(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        auto_connect: bool,
+    ) {
+        let save_result = self.settings.set_auto_connect(auto_connect).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set auto-connect response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set auto-connect response");
+            }
+        }
+    }
+
+    async fn on_set_openvpn_mssfix(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        mssfix_arg: Option<u16>,
+    ) {
+        let save_result = self.settings.set_openvpn_mssfix(mssfix_arg).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_openvpn_mssfix response");
+                if settings_changed {
+                    self.parameters_generator
+                        .set_tunnel_options(&self.settings.tunnel_options)
+                        .await;
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    if self.get_target_tunnel_type() == Some(TunnelType::OpenVpn) {
+                        log::info!(
+                            "Initiating tunnel restart because the OpenVPN mssfix setting changed"
+                        );
+                        self.reconnect_tunnel();
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_openvpn_mssfix response");
+            }
+        }
+    }
+
+    async fn on_set_bridge_settings(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        new_settings: BridgeSettings,
+    ) {
+        match self.settings.set_bridge_settings(new_settings).await {
+            Ok(settings_changes) => {
+                if settings_changes {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.relay_selector
+                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
+                    if let Err(error) = self.api_handle.service().next_api_endpoint().await {
+                        log::error!("Failed to rotate API endpoint: {}", error);
+                    }
+                    self.reconnect_tunnel();
+                };
+                Self::oneshot_send(tx, Ok(()), "set_bridge_settings");
+            }
+
+            Err(e) => {
+                log::error!(
+                    "{}",
+                    e.display_chain_with_msg("Failed to set new bridge settings")
+                );
+                Self::oneshot_send(tx, Err(e), "set_bridge_settings");
+            }
+        }
+    }
+
+    async fn on_set_obfuscation_settings(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        new_settings: ObfuscationSettings,
+    ) {
+        match self.settings.set_obfuscation_settings(new_settings).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.relay_selector
+                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
+                    self.reconnect_tunnel();
+                }
+                Self::oneshot_send(tx, Ok(()), "set_obfuscation_settings");
+            }
+            Err(err) => {
+                log::error!(
+                    "{}",
+                    err.display_chain_with_msg("Failed to set obfuscation settings")
+                );
+                Self::oneshot_send(tx, Err(err), "set_obfuscation_settings");
+            }
+        }
+    }
+
+    async fn on_create_custom_list(&mut self, tx: ResponseTx<(), Error>, name: String) {
+        let mut custom_lists = self.settings.custom_lists.clone();
+        let result = if custom_lists.get(&name).is_some() {
+            Err(Error::CustomListExists(name))
+        } else {
+            custom_lists.custom_lists.push(CustomList::new(name));
+            self.set_custom_lists(custom_lists).await
+        };
+        Self::oneshot_send(tx, result, "create_custom_list response");
+    }
+
+    async fn on_delete_custom_list(&mut self, tx: ResponseTx<(), Error>, name: String) {
+        let mut custom_lists = self.settings.custom_lists.clone();
+        let num_lists = custom_lists.custom_lists.len();
+        custom_lists.custom_lists.retain(|list| list.name != name);
+        let result = if custom_lists.custom_lists.len() == num_lists {
+            Err(Error::CustomListNotFound(name))
+        } else {
+            self.set_custom_lists(custom_lists).await
+        };
+        Self::oneshot_send(tx, result, "delete_custom_list response");
+    }
+
+    async fn on_update_custom_list(&mut self, tx: ResponseTx<(), Error>, new_list: CustomList) {
+        let mut custom_lists = self.settings.custom_lists.clone();
+        let result = if new_list
+            .locations
+            .iter()
+            .any(|location| matches!(location, LocationConstraint::CustomList(_)))
+        {
+            Err(Error::NestedCustomList)
+        } else {
+            match custom_lists
+                .custom_lists
+                .iter_mut()
+                .find(|list| list.name == new_list.name)
+            {
+                Some(list) => {
+                    *list = new_list;
+                    self.set_custom_lists(custom_lists).await
+                }
+                None => Err(Error::CustomListNotFound(new_list.name)),
+            }
+        };
+        Self::oneshot_send(tx, result, "update_custom_list response");
+    }
+
+    /// Saves `custom_lists` and reconnects if the relay constraints may select relays from a
+    /// custom list.
+    async fn set_custom_lists(&mut self, custom_lists: CustomListsSettings) -> Result<(), Error> {
+        let settings_changed =
+            self.settings
+                .set_custom_lists(custom_lists)
+                .await
+                .map_err(|error| {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg("Failed to set custom lists")
+                    );
+                    Error::SettingsError(error)
+                })?;
+        if settings_changed {
+            self.event_listener
+                .notify_settings(self.settings.to_settings());
+            self.relay_selector
+                .set_config(new_selector_config(&self.settings, &self.app_version_info));
+            if let RelaySettings::Normal(constraints) = self.settings.get_relay_settings() {
+                let is_custom_list = |location: &Constraint<LocationConstraint>| {
+                    matches!(
+                        location,
+                        Constraint::Only(LocationConstraint::CustomList(_))
+                    )
+                };
+                if is_custom_list(&constraints.location)
+                    || is_custom_list(&constraints.wireguard_constraints.entry_location)
+                {
+                    log::info!("Initiating tunnel restart because a custom list changed");
+                    self.reconnect_tunnel();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_set_escalation_ladder(
+        &mut self,
+        tx: ResponseTx<(), Error>,
+        escalation_ladder: EscalationLadder,
+    ) {
+        if let Err(error) = escalation_ladder.validate() {
+            Self::oneshot_send(
+                tx,
+                Err(Error::InvalidEscalationLadder(error)),
+                "set_escalation_ladder response",
+            );
+            return;
+        }
+        let result = match self.settings.set_escalation_ladder(escalation_ladder).await {
+            Ok(settings_changed) => {
+                // The ladder only affects later retry attempts, so there is no need to reconnect
                 if settings_changed {
-                    self.parameters_generator
-                        .set_tunnel_options(&self.settings.tunnel_options)
-                        .await;
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    if let Some(TunnelType::Wireguard) = self.get_target_tunnel_type() {
-                        log::info!("Initiating tunnel restart");
-                        self.reconnect_tunnel();
-                    }
+                    self.relay_selector
+                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
                 }
+                Ok(())
             }
             Err(error) => {
                 log::error!(
                     "{}",
-                    error.display_chain_with_msg("Unable to save settings")
+                    error.display_chain_with_msg("Failed to set escalation ladder")
                 );
-                Self::oneshot_send(tx, Err(error), "use_wireguard_nt response");
+                Err(Error::SettingsError(error))
             }
-        }
+        };
+        Self::oneshot_send(tx, result, "set_escalation_ladder response");
     }
 
-    #[cfg(windows)]
-    async fn on_check_volumes(&mut self, tx: ResponseTx<(), Error>) {
-        if self.volume_update_tx.unbounded_send(()).is_ok() {
-            let _ = tx.send(Ok(()));
+    async fn on_set_load_aware_selection(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        enabled: bool,
+    ) {
+        let save_result = self.settings.set_load_aware_selection(enabled).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_load_aware_selection response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.relay_selector.set_load_aware(enabled);
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_load_aware_selection response");
+            }
         }
     }
 
-    async fn on_update_relay_settings(
+    async fn on_set_avoid_recent_relays(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
-        update: RelaySettingsUpdate,
+        count: u32,
     ) {
-        let save_result = self.settings.update_relay_settings(update).await;
+        let save_result = self.settings.set_avoid_recent_relays(count).await;
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "update_relay_settings response");
+                Self::oneshot_send(tx, Ok(()), "set_avoid_recent_relays response");
                 if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    self.relay_selector
-                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
-                    log::info!("Initiating tunnel restart because the relay settings changed");
-                    self.reconnect_tunnel();
+                    self.parameters_generator
+                        .set_avoid_recent_relays(count)
+                        .await;
                 }
             }
             Err(e) => {
                 log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
-                Self::oneshot_send(tx, Err(e), "update_relay_settings response");
+                Self::oneshot_send(tx, Err(e), "set_avoid_recent_relays response");
             }
         }
     }
 
-    async fn on_set_allow_lan(&mut self, tx: ResponseTx<(), settings::Error>, allow_lan: bool) {
-        let save_result = self.settings.set_allow_lan(allow_lan).await;
+    async fn on_set_prefer_ipv6(&mut self, tx: ResponseTx<(), settings::Error>, enabled: bool) {
+        let save_result = self.settings.set_prefer_ipv6(enabled).await;
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "set_allow_lan response");
+                Self::oneshot_send(tx, Ok(()), "set_prefer_ipv6 response");
                 if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    self.send_tunnel_command(TunnelCommand::AllowLan(allow_lan));
+                    self.relay_selector.set_prefer_ipv6(enabled);
                 }
             }
             Err(e) => {
                 log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
-                Self::oneshot_send(tx, Err(e), "set_allow_lan response");
+                Self::oneshot_send(tx, Err(e), "set_prefer_ipv6 response");
             }
         }
     }
 
-    async fn on_set_show_beta_releases(
+    async fn on_set_reuse_obfuscator(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
         enabled: bool,
     ) {
-        let save_result = self.settings.set_show_beta_releases(enabled).await;
+        let save_result = self.settings.set_reuse_obfuscator(enabled).await;
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "set_show_beta_releases response");
+                Self::oneshot_send(tx, Ok(()), "set_reuse_obfuscator response");
                 if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    let mut handle = self.version_updater_handle.clone();
-                    handle.set_show_beta_releases(enabled).await;
+                    self.parameters_generator
+                        .set_reuse_obfuscator(enabled)
+                        .await;
                 }
             }
             Err(e) => {
                 log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
-                Self::oneshot_send(tx, Err(e), "set_show_beta_releases response");
+                Self::oneshot_send(tx, Err(e), "set_reuse_obfuscator response");
             }
         }
     }
 
-    async fn on_set_block_when_disconnected(
+    async fn on_set_latency_aware_selection(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
-        block_when_disconnected: bool,
+        enabled: bool,
     ) {
-        let save_result = self
-            .settings
-            .set_block_when_disconnected(block_when_disconnected)
-            .await;
+        let save_result = self.settings.set_latency_aware_selection(enabled).await;
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "set_block_when_disconnected response");
+                Self::oneshot_send(tx, Ok(()), "set_latency_aware_selection response");
                 if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    self.send_tunnel_command(TunnelCommand::BlockWhenDisconnected(
-                        block_when_disconnected,
-                    ));
+                    self.parameters_generator.set_latency_aware(enabled).await;
                 }
             }
             Err(e) => {
                 log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
-                Self::oneshot_send(tx, Err(e), "set_block_when_disconnected response");
+                Self::oneshot_send(tx, Err(e), "set_latency_aware_selection response");
             }
         }
     }
 
-    async fn on_set_auto_connect(
+    async fn on_set_sticky_relay(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
-        auto_connect: bool,
+        max_failures: u32,
     ) {
-        let save_result = self.settings.set_auto_connect(auto_connect).await;
+        let save_result = self
+            .settings
+            .set_sticky_relay_max_failures(max_failures)
+            .await;
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "set auto-connect response");
+                Self::oneshot_send(tx, Ok(()), "set_sticky_relay response");
                 if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
+                    self.parameters_generator
+                        .set_sticky_relay(max_failures)
+                        .await;
                 }
             }
             Err(e) => {
                 log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
-                Self::oneshot_send(tx, Err(e), "set auto-connect response");
+                Self::oneshot_send(tx, Err(e), "set_sticky_relay response");
             }
         }
     }
 
-    async fn on_set_openvpn_mssfix(
+    async fn on_set_protocol_preference(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
-        mssfix_arg: Option<u16>,
+        preference: Option<ProtocolPreference>,
     ) {
-        let save_result = self.settings.set_openvpn_mssfix(mssfix_arg).await;
+        let save_result = self.settings.set_protocol_preference(preference).await;
         match save_result {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "set_openvpn_mssfix response");
+                Self::oneshot_send(tx, Ok(()), "set_protocol_preference response");
                 if settings_changed {
-                    self.parameters_generator
-                        .set_tunnel_options(&self.settings.tunnel_options)
-                        .await;
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    if self.get_target_tunnel_type() == Some(TunnelType::OpenVpn) {
-                        log::info!(
-                            "Initiating tunnel restart because the OpenVPN mssfix setting changed"
-                        );
-                        self.reconnect_tunnel();
-                    }
+                    self.parameters_generator
+                        .set_protocol_preference(preference)
+                        .await;
                 }
             }
             Err(e) => {
                 log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
-                Self::oneshot_send(tx, Err(e), "set_openvpn_mssfix response");
+                Self::oneshot_send(tx, Err(e), "set_protocol_preference response");
             }
         }
     }
 
-    async fn on_set_bridge_settings(
+    async fn on_set_relay_list_source(
         &mut self,
-        tx: ResponseTx<(), settings::Error>,
-        new_settings: BridgeSettings,
+        tx: ResponseTx<(), Error>,
+        source: RelayListSource,
     ) {
-        match self.settings.set_bridge_settings(new_settings).await {
-            Ok(settings_changes) => {
-                if settings_changes {
+        // Reject unusable files up front rather than only logging the failure to read them
+        if let RelayListSource::File(path) = &source {
+            if let Err(error) = updater::read_relay_list_file(path).await {
+                Self::oneshot_send(
+                    tx,
+                    Err(Error::InvalidRelayListFile(error)),
+                    "set_relay_list_source response",
+                );
+                return;
+            }
+        }
+        let result = match self.settings.set_relay_list_source(source.clone()).await {
+            Ok(settings_changed) => {
+                if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    self.relay_selector
-                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
-                    if let Err(error) = self.api_handle.service().next_api_endpoint().await {
-                        log::error!("Failed to rotate API endpoint: {}", error);
-                    }
-                    self.reconnect_tunnel();
-                };
-                Self::oneshot_send(tx, Ok(()), "set_bridge_settings");
+                    self.relay_list_updater.set_source(source).await;
+                }
+                Ok(())
             }
-
-            Err(e) => {
+            Err(error) => {
                 log::error!(
                     "{}",
-                    e.display_chain_with_msg("Failed to set new bridge settings")
+                    error.display_chain_with_msg("Failed to set relay list source")
                 );
-                Self::oneshot_send(tx, Err(e), "set_bridge_settings");
+                Err(Error::SettingsError(error))
             }
-        }
+        };
+        Self::oneshot_send(tx, result, "set_relay_list_source response");
     }
 
-    async fn on_set_obfuscation_settings(
+    async fn on_set_relay_rotation_policy(
         &mut self,
-        tx: ResponseTx<(), settings::Error>,
-        new_settings: ObfuscationSettings,
+        tx: ResponseTx<(), Error>,
+        policy: RelayRotationPolicy,
     ) {
-        match self.settings.set_obfuscation_settings(new_settings).await {
+        if let Err(error) = policy.validate() {
+            Self::oneshot_send(
+                tx,
+                Err(Error::InvalidRelayRotationPolicy(error)),
+                "set_relay_rotation_policy response",
+            );
+            return;
+        }
+        let result = match self.settings.set_relay_rotation_policy(policy).await {
             Ok(settings_changed) => {
                 if settings_changed {
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
-                    self.relay_selector
-                        .set_config(new_selector_config(&self.settings, &self.app_version_info));
-                    self.reconnect_tunnel();
+                    if self.tunnel_state.is_connected() {
+                        self.schedule_relay_rotation();
+                    }
                 }
-                Self::oneshot_send(tx, Ok(()), "set_obfuscation_settings");
+                Ok(())
             }
-            Err(err) => {
+            Err(error) => {
                 log::error!(
                     "{}",
-                    err.display_chain_with_msg("Failed to set obfuscation settings")
+                    error.display_chain_with_msg("Failed to set relay rotation policy")
                 );
-                Self::oneshot_send(tx, Err(err), "set_obfuscation_settings");
+                Err(Error::SettingsError(error))
             }
-        }
+        };
+        Self::oneshot_send(tx, result, "set_relay_rotation_policy response");
     }
 
     async fn on_set_bridge_state(
@@ -2017,22 +3570,38 @@ where
         }
     }
 
+    /// Applies tunnel options that restrict which relays can be used, and warns if no relay can be
+    /// used with them.
+    async fn set_relay_restricting_tunnel_options(&mut self) {
+        if !self
+            .parameters_generator
+            .set_tunnel_options_checked(&self.settings.tunnel_options)
+            .await
+        {
+            log::warn!("No relay matching the relay constraints can be used with the new settings");
+        }
+    }
+
     async fn on_set_quantum_resistant_tunnel(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
-        use_pq_safe_psk: bool,
+        quantum_resistant: QuantumResistantState,
     ) {
         let save_result = self
             .settings
-            .set_quantum_resistant_tunnel(use_pq_safe_psk)
+            .set_quantum_resistant_tunnel(quantum_resistant)
             .await;
         match save_result {
             Ok(settings_changed) => {
                 Self::oneshot_send(tx, Ok(()), "set_quantum_resistant_tunnel response");
                 if settings_changed {
-                    self.parameters_generator
-                        .set_tunnel_options(&self.settings.tunnel_options)
-                        .await;
+                    self.relay_selector.set_quantum_resistant(
+                        self.settings
+                            .tunnel_options
+                            .wireguard
+                            .negotiates_ephemeral_key(),
+                    );
+                    self.set_relay_restricting_tunnel_options().await;
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
                     if self.get_target_tunnel_type() == Some(TunnelType::Wireguard) {
@@ -2048,6 +3617,38 @@ where
         }
     }
 
+    async fn on_set_wireguard_ephemeral_keys(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        enabled: bool,
+    ) {
+        let save_result = self.settings.set_wireguard_ephemeral_keys(enabled).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_wireguard_ephemeral_keys response");
+                if settings_changed {
+                    self.relay_selector.set_quantum_resistant(
+                        self.settings
+                            .tunnel_options
+                            .wireguard
+                            .negotiates_ephemeral_key(),
+                    );
+                    self.set_relay_restricting_tunnel_options().await;
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    if self.get_target_tunnel_type() == Some(TunnelType::Wireguard) {
+                        log::info!("Reconnecting because the ephemeral key setting changed");
+                        self.reconnect_tunnel();
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_wireguard_ephemeral_keys response");
+            }
+        }
+    }
+
     async fn on_set_dns_options(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -2059,13 +3660,11 @@ where
                 Self::oneshot_send(tx, Ok(()), "set_dns_options response");
                 if settings_changed {
                     let settings = self.settings.to_settings();
-                    let resolvers =
-                        dns::addresses_from_options(&settings.tunnel_options.dns_options);
                     self.parameters_generator
                         .set_tunnel_options(&settings.tunnel_options)
                         .await;
                     self.event_listener.notify_settings(settings);
-                    self.send_tunnel_command(TunnelCommand::Dns(resolvers));
+                    self.update_dns();
                 }
             }
             Err(e) => {
@@ -2075,6 +3674,19 @@ where
         }
     }
 
+    /// Points the system and the local DNS forwarder at the resolvers given by the settings.
+    fn update_dns(&self) {
+        #[cfg(not(target_os = "android"))]
+        {
+            self.dns_forwarder
+                .set_upstream(dns::forwarder_upstream(&self.settings));
+            self.send_tunnel_command(TunnelCommand::DnsUpstream(dns::tunnel_upstream(
+                &self.settings,
+            )));
+        }
+        self.send_tunnel_command(TunnelCommand::Dns(dns::dns_servers(&self.settings)));
+    }
+
     async fn on_set_wireguard_mtu(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -2105,6 +3717,84 @@ where
         }
     }
 
+    async fn on_set_wireguard_mtu_detection(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        enabled: bool,
+    ) {
+        let save_result = self.settings.set_wireguard_mtu_detection(enabled).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_wireguard_mtu_detection response");
+                if settings_changed {
+                    self.parameters_generator
+                        .set_tunnel_options(&self.settings.tunnel_options)
+                        .await;
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    if let Some(TunnelType::Wireguard) = self.get_connected_tunnel_type() {
+                        log::info!(
+                            "Initiating tunnel restart because the WireGuard MTU detection setting changed"
+                        );
+                        self.reconnect_tunnel();
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_wireguard_mtu_detection response");
+            }
+        }
+    }
+
+    async fn on_set_wireguard_daita(&mut self, tx: ResponseTx<(), settings::Error>, enabled: bool) {
+        let save_result = self.settings.set_wireguard_daita(enabled).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_wireguard_daita response");
+                if settings_changed {
+                    self.relay_selector.set_daita(enabled);
+                    self.set_relay_restricting_tunnel_options().await;
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    if self.get_target_tunnel_type() == Some(TunnelType::Wireguard) {
+                        log::info!("Reconnecting because the DAITA setting changed");
+                        self.reconnect_tunnel();
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_wireguard_daita response");
+            }
+        }
+    }
+
+    async fn on_set_wireguard_port_fallback(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        port_fallback: PortFallback,
+    ) {
+        let save_result = self
+            .settings
+            .set_wireguard_port_fallback(port_fallback)
+            .await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_wireguard_port_fallback response");
+                if settings_changed {
+                    self.relay_selector.set_port_fallback(port_fallback);
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_wireguard_port_fallback response");
+            }
+        }
+    }
+
     async fn on_set_wireguard_rotation_interval(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -2164,10 +3854,32 @@ where
         Self::oneshot_send(tx, result, "get_wireguard_key response");
     }
 
+    async fn on_export_wireguard_config(&self, tx: oneshot::Sender<Option<String>>) {
+        Self::oneshot_send(
+            tx,
+            self.parameters_generator
+                .export_last_wireguard_config()
+                .await,
+            "export_wireguard_config response",
+        );
+    }
+
     fn on_get_settings(&self, tx: oneshot::Sender<Settings>) {
         Self::oneshot_send(tx, self.settings.to_settings(), "get_settings response");
     }
 
+    /// Returns whether traffic outside the tunnel should be blocked while disconnected. This is
+    /// also the case on untrusted networks if they are locked down.
+    fn block_when_disconnected(&self) -> bool {
+        #[cfg(not(target_os = "android"))]
+        if let Some(network) = &self.current_network {
+            if self.settings.network_rules.is_locked_down(network) {
+                return true;
+            }
+        }
+        self.settings.block_when_disconnected
+    }
+
     fn oneshot_send<T>(tx: oneshot::Sender<T>, t: T, msg: &'static str) {
         if tx.send(t).is_err() {
             log::warn!("Unable to send {} to the daemon command sender", msg);
@@ -2237,6 +3949,7 @@ where
     }
 
     fn disconnect_tunnel(&mut self) {
+        self.parameters_generator.cancel_pending();
         self.send_tunnel_command(TunnelCommand::Disconnect);
     }
 
@@ -2327,6 +4040,8 @@ fn new_selector_config(
         bridge_state: settings.get_bridge_state(),
         bridge_settings: settings.bridge_settings.clone(),
         obfuscation_settings: settings.obfuscation_settings.clone(),
+        custom_lists: settings.custom_lists.clone(),
+        escalation_ladder: settings.escalation_ladder.clone(),
         default_tunnel_type,
     }
 }
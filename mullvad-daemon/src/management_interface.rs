@@ -1,4 +1,6 @@
-use crate::{account_history, device, settings, DaemonCommand, DaemonCommandSender, EventListener};
+use crate::{
+    account_history, device, settings, tunnel, DaemonCommand, DaemonCommandSender, EventListener,
+};
 use futures::{
     channel::{mpsc, oneshot},
     StreamExt,
@@ -13,12 +15,19 @@ use mullvad_paths;
 use mullvad_types::settings::DnsOptions;
 use mullvad_types::{
     account::AccountToken,
-    relay_constraints::{BridgeSettings, BridgeState, ObfuscationSettings, RelaySettingsUpdate},
-    relay_list::RelayList,
+    custom_list::CustomList,
+    escalation::EscalationLadder,
+    relay_constraints::{
+        BridgeSettings, BridgeState, Constraint, LocationConstraint, ObfuscationSettings,
+        ProtocolPreference, RelaySettingsUpdate,
+    },
+    relay_list::{RelayList, RelayListSource},
+    relay_rotation::{RelayRotationEvent, RelayRotationPolicy},
+    relay_selection::RelaySelectionEvent,
     settings::Settings,
     states::{TargetState, TunnelState},
     version,
-    wireguard::{RotationInterval, RotationIntervalError},
+    wireguard::{PortFallback, QuantumResistantState, RotationInterval, RotationIntervalError},
 };
 use parking_lot::RwLock;
 #[cfg(windows)]
@@ -28,7 +37,7 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use talpid_types::ErrorExt;
+use talpid_types::{net::TunnelType, ErrorExt};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 #[derive(err_derive::Error, Debug)]
@@ -51,10 +60,14 @@ type EventsListenerSender = tokio::sync::mpsc::UnboundedSender<Result<types::Dae
 const INVALID_VOUCHER_MESSAGE: &str = "This voucher code is invalid";
 const USED_VOUCHER_MESSAGE: &str = "This voucher code has already been used";
 
+/// Maximum number of connection attempts that relays can be previewed for at once.
+const MAX_PREVIEWED_ATTEMPTS: u32 = 100;
+
 #[mullvad_management_interface::async_trait]
 impl ManagementService for ManagementServiceImpl {
     type GetSplitTunnelProcessesStream = UnboundedReceiverStream<Result<i32, Status>>;
     type EventsListenStream = EventsListenerReceiver;
+    type TrafficStatsListenStream = UnboundedReceiverStream<Result<types::TrafficSample, Status>>;
 
     // Control and get the tunnel state
     //
@@ -93,6 +106,34 @@ impl ManagementService for ManagementServiceImpl {
         Ok(Response::new(types::TunnelState::from(state)))
     }
 
+    async fn get_traffic_stats(&self, _: Request<()>) -> ServiceResult<types::TrafficStats> {
+        log::debug!("get_traffic_stats");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetTrafficStats(tx))?;
+        let samples = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::TrafficStats::from(samples)))
+    }
+
+    async fn traffic_stats_listen(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<Self::TrafficStatsListenStream> {
+        log::debug!("traffic_stats_listen");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ListenTrafficStats(tx))?;
+        let mut samples = self.wait_for_result(rx).await?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(sample) = samples.recv().await {
+                if tx.send(Ok(types::TrafficSample::from(sample))).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+
     // Control the daemon and receive events
     //
 
@@ -158,6 +199,181 @@ impl ManagementService for ManagementServiceImpl {
     // Relays and tunnel constraints
     //
 
+    async fn get_relay_selection(&self, _: Request<()>) -> ServiceResult<String> {
+        log::debug!("get_relay_selection");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetRelaySelection(tx))?;
+        let snapshot = self.wait_for_result(rx).await?;
+        serde_json::to_string(&snapshot)
+            .map(Response::new)
+            .map_err(|error| Status::internal(error.to_string()))
+    }
+
+    async fn explain_relay_selection(&self, _: Request<()>) -> ServiceResult<String> {
+        log::debug!("explain_relay_selection");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ExplainRelaySelection(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found("no relays have been selected"))
+    }
+
+    async fn get_last_relay_chain(&self, _: Request<()>) -> ServiceResult<types::RelayChain> {
+        log::debug!("get_last_relay_chain");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetLastRelayChain(tx))?;
+        let chain = self
+            .wait_for_result(rx)
+            .await?
+            .ok_or_else(|| Status::not_found("no tunnel parameters have been generated"))?;
+        let hop = |hop: tunnel::RelayHop| types::RelayHop {
+            hostname: hop.hostname.unwrap_or_default(),
+            endpoint: Some(types::Endpoint::from(hop.endpoint)),
+        };
+        Ok(Response::new(types::RelayChain {
+            tunnel_type: match chain.tunnel_type {
+                TunnelType::OpenVpn => i32::from(types::TunnelType::Openvpn),
+                TunnelType::Wireguard => i32::from(types::TunnelType::Wireguard),
+            },
+            exit: Some(hop(chain.exit)),
+            entry: chain.entry.map(hop),
+            bridge: chain.bridge.map(hop),
+            obfuscator: chain
+                .obfuscator
+                .as_ref()
+                .and_then(|obfuscator| obfuscator.hostname.clone())
+                .unwrap_or_default(),
+            obfuscation: chain
+                .obfuscator
+                .map(|obfuscator| types::ObfuscationEndpoint::from(obfuscator.endpoint)),
+        }))
+    }
+
+    async fn preview_relays(&self, request: Request<u32>) -> ServiceResult<types::RelayPreviews> {
+        let count = request.into_inner();
+        log::debug!("preview_relays({})", count);
+        if count > MAX_PREVIEWED_ATTEMPTS {
+            return Err(Status::invalid_argument(format!(
+                "Relays can be previewed for at most {} attempts",
+                MAX_PREVIEWED_ATTEMPTS
+            )));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::PreviewRelays(tx, count))?;
+        let previews = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::RelayPreviews {
+            relays: previews
+                .into_iter()
+                .map(|preview| types::RelayPreview {
+                    tunnel_type: match preview.tunnel_type {
+                        TunnelType::OpenVpn => i32::from(types::TunnelType::Openvpn),
+                        TunnelType::Wireguard => i32::from(types::TunnelType::Wireguard),
+                    },
+                    exit: preview.exit,
+                    entry: preview.entry.unwrap_or_default(),
+                    bridge: preview.bridge.unwrap_or_default(),
+                    obfuscator: preview.obfuscator.unwrap_or_default(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn check_can_connect(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("check_can_connect");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::CheckCanConnect(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(|reason| Status::failed_precondition(reason.to_string()))
+    }
+
+    async fn set_session_excluded_relays(
+        &self,
+        request: Request<types::ExcludedRelaysUpdate>,
+    ) -> ServiceResult<()> {
+        let hostnames = request.into_inner().hostnames;
+        log::debug!("set_session_excluded_relays({:?})", hostnames);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetSessionExcludedRelays(tx, hostnames))?;
+        self.wait_for_result(rx).await.map(Response::new)
+    }
+
+    async fn get_session_excluded_relays(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::ExcludedRelaysUpdate> {
+        log::debug!("get_session_excluded_relays");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetSessionExcludedRelays(tx))?;
+        let hostnames = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::ExcludedRelaysUpdate { hostnames }))
+    }
+
+    async fn benchmark_relays(
+        &self,
+        request: Request<types::RelayLocation>,
+    ) -> ServiceResult<types::RelayBenchmarks> {
+        log::debug!("benchmark_relays");
+        let location = Constraint::<LocationConstraint>::from(request.into_inner());
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::BenchmarkRelays(tx, location))?;
+        let benchmarks = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::RelayBenchmarks {
+            relays: benchmarks
+                .into_iter()
+                .map(|benchmark| types::RelayBenchmark {
+                    hostname: benchmark.hostname,
+                    probes: benchmark.probes,
+                    responses: benchmark.responses,
+                    min_rtt: benchmark
+                        .min_rtt
+                        .and_then(|rtt| types::Duration::try_from(rtt).ok()),
+                    median_rtt: benchmark
+                        .median_rtt
+                        .and_then(|rtt| types::Duration::try_from(rtt).ok()),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_relay_stats(&self, _: Request<()>) -> ServiceResult<types::RelayStats> {
+        log::debug!("get_relay_stats");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetRelayStats(tx))?;
+        let stats = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::RelayStats {
+            relays: stats
+                .into_iter()
+                .map(|(hostname, stat)| types::RelayStat {
+                    hostname,
+                    selected: stat.selected,
+                    failures: stat.failures,
+                    successes: stat.successes,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn run_self_test(&self, _: Request<()>) -> ServiceResult<types::SelfTestReport> {
+        log::debug!("run_self_test");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RunSelfTest(tx))?;
+        let report = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::SelfTestReport {
+            passed: u32::try_from(report.passed).unwrap_or(u32::MAX),
+            failures: report
+                .failures
+                .into_iter()
+                .map(|failure| types::SelfTestFailure {
+                    location: Some(types::RelayLocation::from(failure.location)),
+                    reason: failure.reason,
+                })
+                .collect(),
+        }))
+    }
+
     async fn update_relay_locations(&self, _: Request<()>) -> ServiceResult<()> {
         log::debug!("update_relay_locations");
         self.send_command_to_daemon(DaemonCommand::UpdateRelayLocations)?;
@@ -234,6 +450,154 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_settings_error)
     }
 
+    async fn create_custom_list(&self, request: Request<String>) -> ServiceResult<()> {
+        let name = request.into_inner();
+        log::debug!("create_custom_list({})", name);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::CreateCustomList(tx, name))?;
+        self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(()))
+    }
+
+    async fn delete_custom_list(&self, request: Request<String>) -> ServiceResult<()> {
+        let name = request.into_inner();
+        log::debug!("delete_custom_list({})", name);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::DeleteCustomList(tx, name))?;
+        self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(()))
+    }
+
+    async fn update_custom_list(&self, request: Request<types::CustomList>) -> ServiceResult<()> {
+        let custom_list =
+            CustomList::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("update_custom_list({:?})", custom_list);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::UpdateCustomList(tx, custom_list))?;
+        self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(()))
+    }
+
+    async fn set_escalation_ladder(
+        &self,
+        request: Request<types::EscalationLadder>,
+    ) -> ServiceResult<()> {
+        let escalation_ladder =
+            EscalationLadder::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_escalation_ladder({:?})", escalation_ladder);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetEscalationLadder(tx, escalation_ladder))?;
+        self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(()))
+    }
+
+    async fn set_load_aware_selection(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_load_aware_selection({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetLoadAwareSelection(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_avoid_recent_relays(&self, request: Request<u32>) -> ServiceResult<()> {
+        let count = request.into_inner();
+        log::debug!("set_avoid_recent_relays({})", count);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetAvoidRecentRelays(tx, count))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_reuse_obfuscator(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_reuse_obfuscator({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetReuseObfuscator(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_latency_aware_selection(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_latency_aware_selection({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetLatencyAwareSelection(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_sticky_relay(&self, request: Request<u32>) -> ServiceResult<()> {
+        let max_failures = request.into_inner();
+        log::debug!("set_sticky_relay({})", max_failures);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetStickyRelay(tx, max_failures))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_protocol_preference(
+        &self,
+        request: Request<types::ProtocolPreference>,
+    ) -> ServiceResult<()> {
+        let preference = Option::<ProtocolPreference>::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("set_protocol_preference({:?})", preference);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetProtocolPreference(tx, preference))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_prefer_ipv6(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_prefer_ipv6({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetPreferIpv6(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_relay_list_source(
+        &self,
+        request: Request<types::RelayListSource>,
+    ) -> ServiceResult<()> {
+        let source =
+            RelayListSource::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_relay_list_source({})", source);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetRelayListSource(tx, source))?;
+        self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(()))
+    }
+
+    async fn set_relay_rotation_policy(
+        &self,
+        request: Request<types::RelayRotationPolicy>,
+    ) -> ServiceResult<()> {
+        let policy =
+            RelayRotationPolicy::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_relay_rotation_policy({})", policy);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetRelayRotationPolicy(tx, policy))?;
+        self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(()))
+    }
+
     async fn set_bridge_state(&self, request: Request<types::BridgeState>) -> ServiceResult<()> {
         let bridge_state =
             BridgeState::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
@@ -270,6 +634,38 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_settings_error)
     }
 
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn set_lan_rules(&self, request: Request<types::LanRules>) -> ServiceResult<()> {
+        let lan_rules = talpid_types::lan::LanRules::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("set_lan_rules({:?})", lan_rules);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetLanRules(tx, Some(lan_rules)))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn set_lan_rules(&self, _: Request<types::LanRules>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn clear_lan_rules(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_lan_rules");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetLanRules(tx, None))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn clear_lan_rules(&self, _: Request<()>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     async fn set_show_beta_releases(&self, request: Request<bool>) -> ServiceResult<()> {
         let enabled = request.into_inner();
         log::debug!("set_show_beta_releases({})", enabled);
@@ -334,6 +730,42 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_settings_error)
     }
 
+    async fn set_wireguard_mtu_detection(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_wireguard_mtu_detection({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetWireguardMtuDetection(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_wireguard_daita(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_wireguard_daita({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetWireguardDaita(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_wireguard_port_fallback(
+        &self,
+        request: Request<types::WireguardPortFallback>,
+    ) -> ServiceResult<()> {
+        let port_fallback = PortFallback::from(request.into_inner());
+        log::debug!("set_wireguard_port_fallback({})", port_fallback);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetWireguardPortFallback(tx, port_fallback))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
     async fn set_enable_ipv6(&self, request: Request<bool>) -> ServiceResult<()> {
         let enable_ipv6 = request.into_inner();
         log::debug!("set_enable_ipv6({})", enable_ipv6);
@@ -345,11 +777,26 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_settings_error)
     }
 
-    async fn set_quantum_resistant_tunnel(&self, request: Request<bool>) -> ServiceResult<()> {
-        let enable = request.into_inner();
-        log::debug!("set_quantum_resistant_tunnel({})", enable);
+    async fn set_quantum_resistant_tunnel(
+        &self,
+        request: Request<types::QuantumResistantState>,
+    ) -> ServiceResult<()> {
+        let state =
+            QuantumResistantState::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_quantum_resistant_tunnel({})", state);
         let (tx, rx) = oneshot::channel();
-        self.send_command_to_daemon(DaemonCommand::SetQuantumResistantTunnel(tx, enable))?;
+        self.send_command_to_daemon(DaemonCommand::SetQuantumResistantTunnel(tx, state))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+
+    async fn set_wireguard_ephemeral_keys(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_wireguard_ephemeral_keys({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetWireguardEphemeralKeys(tx, enabled))?;
         self.wait_for_result(rx)
             .await?
             .map(Response::new)
@@ -454,6 +901,30 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_daemon_error)
     }
 
+    // Connection history
+    //
+
+    async fn get_connection_history(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::ConnectionHistory> {
+        log::debug!("get_connection_history");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetConnectionHistory(tx))?;
+        let attempts = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::ConnectionHistory::from(attempts)))
+    }
+
+    async fn clear_connection_history(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_connection_history");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ClearConnectionHistory(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+
     async fn get_www_auth_token(&self, _: Request<()>) -> ServiceResult<String> {
         log::debug!("get_www_auth_token");
         let (tx, rx) = oneshot::channel();
@@ -591,14 +1062,46 @@ impl ManagementService for ManagementServiceImpl {
         }
     }
 
+    async fn export_wireguard_config(&self, _: Request<()>) -> ServiceResult<String> {
+        log::debug!("export_wireguard_config");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ExportWireguardConfig(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .ok_or_else(|| {
+                Status::not_found("no single-hop WireGuard tunnel parameters have been generated")
+            })
+    }
+
     // Split tunneling
     //
 
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn set_split_tunnel_mode(
+        &self,
+        request: Request<types::SplitTunnelMode>,
+    ) -> ServiceResult<()> {
+        let mode = talpid_types::split_tunnel::SplitTunnelMode::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("set_split_tunnel_mode({})", mode);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetSplitTunnelMode(tx, mode))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn set_split_tunnel_mode(&self, _: Request<types::SplitTunnelMode>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     async fn get_split_tunnel_processes(
         &self,
         _: Request<()>,
     ) -> ServiceResult<Self::GetSplitTunnelProcessesStream> {
-        #[cfg(target_os = "linux")]
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
         {
             log::debug!("get_split_tunnel_processes");
             let (tx, rx) = oneshot::channel();
@@ -617,7 +1120,7 @@ impl ManagementService for ManagementServiceImpl {
 
             Ok(Response::new(UnboundedReceiverStream::new(rx)))
         }
-        #[cfg(not(target_os = "linux"))]
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
         {
             let (_, rx) = tokio::sync::mpsc::unbounded_channel();
             Ok(Response::new(UnboundedReceiverStream::new(rx)))
@@ -772,6 +1275,338 @@ impl ManagementService for ManagementServiceImpl {
         }))
     }
 
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn add_excluded_destination(&self, request: Request<String>) -> ServiceResult<()> {
+        log::debug!("add_excluded_destination");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddExcludedDestination(
+            tx,
+            request.into_inner(),
+        ))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn add_excluded_destination(&self, _: Request<String>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn remove_excluded_destination(&self, request: Request<String>) -> ServiceResult<()> {
+        log::debug!("remove_excluded_destination");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveExcludedDestination(
+            tx,
+            request.into_inner(),
+        ))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn remove_excluded_destination(&self, _: Request<String>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn clear_excluded_destinations(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_excluded_destinations");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ClearExcludedDestinations(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn clear_excluded_destinations(&self, _: Request<()>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn set_network_rule(&self, request: Request<types::NetworkRule>) -> ServiceResult<()> {
+        let rule = mullvad_types::network_rules::NetworkRule::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("set_network_rule({}, {})", rule.network, rule.trust);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetNetworkRule(tx, rule))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(target_os = "android")]
+    async fn set_network_rule(&self, _: Request<types::NetworkRule>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn remove_network_rule(
+        &self,
+        request: Request<types::NetworkIdentity>,
+    ) -> ServiceResult<()> {
+        let network = talpid_types::net::NetworkIdentity::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("remove_network_rule({})", network);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveNetworkRule(tx, network))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(target_os = "android")]
+    async fn remove_network_rule(&self, _: Request<types::NetworkIdentity>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn set_network_lockdown(&self, request: Request<bool>) -> ServiceResult<()> {
+        let lockdown = request.into_inner();
+        log::debug!("set_network_lockdown({})", lockdown);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetNetworkLockdown(tx, lockdown))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(target_os = "android")]
+    async fn set_network_lockdown(&self, _: Request<bool>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn get_current_network(&self, _: Request<()>) -> ServiceResult<types::NetworkIdentity> {
+        log::debug!("get_current_network");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetCurrentNetwork(tx))?;
+        let network = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::NetworkIdentity::from(network)))
+    }
+    #[cfg(target_os = "android")]
+    async fn get_current_network(&self, _: Request<()>) -> ServiceResult<types::NetworkIdentity> {
+        Ok(Response::new(types::NetworkIdentity::default()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn add_schedule(&self, request: Request<types::ConnectionSchedule>) -> ServiceResult<()> {
+        let schedule = mullvad_types::schedule::ConnectionSchedule::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("add_schedule({})", schedule);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddSchedule(tx, schedule))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(target_os = "android")]
+    async fn add_schedule(&self, _: Request<types::ConnectionSchedule>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn remove_schedule(&self, request: Request<u32>) -> ServiceResult<()> {
+        let index = request.into_inner();
+        log::debug!("remove_schedule({})", index);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveSchedule(tx, index as usize))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(target_os = "android")]
+    async fn remove_schedule(&self, _: Request<u32>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn clear_schedules(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_schedules");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ClearSchedules(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(target_os = "android")]
+    async fn clear_schedules(&self, _: Request<()>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn get_schedule_state(&self, _: Request<()>) -> ServiceResult<types::ScheduleState> {
+        log::debug!("get_schedule_state");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetScheduleState(tx))?;
+        let state = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::ScheduleState::from(state)))
+    }
+    #[cfg(target_os = "android")]
+    async fn get_schedule_state(&self, _: Request<()>) -> ServiceResult<types::ScheduleState> {
+        Ok(Response::new(types::ScheduleState::default()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn add_dns_blocklist(&self, request: Request<types::DnsBlocklist>) -> ServiceResult<()> {
+        let blocklist = mullvad_types::dns_blocklist::DnsBlocklist::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("add_dns_blocklist({})", blocklist.source);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddDnsBlocklist(tx, blocklist))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(target_os = "android")]
+    async fn add_dns_blocklist(&self, _: Request<types::DnsBlocklist>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn remove_dns_blocklist(&self, request: Request<u32>) -> ServiceResult<()> {
+        let index = request.into_inner();
+        log::debug!("remove_dns_blocklist({})", index);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveDnsBlocklist(tx, index as usize))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(target_os = "android")]
+    async fn remove_dns_blocklist(&self, _: Request<u32>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn set_dns_blocklist_enabled(
+        &self,
+        request: Request<types::DnsBlocklistEnabled>,
+    ) -> ServiceResult<()> {
+        let types::DnsBlocklistEnabled { index, enabled } = request.into_inner();
+        log::debug!("set_dns_blocklist_enabled({}, {})", index, enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetDnsBlocklistEnabled(
+            tx,
+            index as usize,
+            enabled,
+        ))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(target_os = "android")]
+    async fn set_dns_blocklist_enabled(
+        &self,
+        _: Request<types::DnsBlocklistEnabled>,
+    ) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn update_dns_blocklists(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("update_dns_blocklists");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::UpdateDnsBlocklists(tx))?;
+        self.wait_for_result(rx).await?;
+        Ok(Response::new(()))
+    }
+    #[cfg(target_os = "android")]
+    async fn update_dns_blocklists(&self, _: Request<()>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(not(target_os = "android"))]
+    async fn get_dns_blocklist_status(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::DnsBlocklistStatuses> {
+        log::debug!("get_dns_blocklist_status");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetDnsBlocklistStatus(tx))?;
+        let statuses = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::DnsBlocklistStatuses {
+            statuses: statuses
+                .into_iter()
+                .map(types::DnsBlocklistStatus::from)
+                .collect(),
+        }))
+    }
+    #[cfg(target_os = "android")]
+    async fn get_dns_blocklist_status(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::DnsBlocklistStatuses> {
+        Ok(Response::new(types::DnsBlocklistStatuses::default()))
+    }
+
+    // Firewall exceptions
+    //
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn add_firewall_exception(
+        &self,
+        request: Request<types::FirewallException>,
+    ) -> ServiceResult<()> {
+        let exception =
+            talpid_types::firewall_exception::FirewallException::try_from(request.into_inner())
+                .map_err(map_protobuf_type_err)?;
+        log::debug!("add_firewall_exception({})", exception);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddFirewallException(tx, exception))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn add_firewall_exception(
+        &self,
+        _: Request<types::FirewallException>,
+    ) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn remove_firewall_exception(&self, request: Request<u32>) -> ServiceResult<()> {
+        let index = request.into_inner();
+        log::debug!("remove_firewall_exception({})", index);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveFirewallException(tx, index as usize))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn remove_firewall_exception(&self, _: Request<u32>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn clear_firewall_exceptions(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_firewall_exceptions");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ClearFirewallExceptions(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    async fn clear_firewall_exceptions(&self, _: Request<()>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     #[cfg(windows)]
     async fn set_use_wireguard_nt(&self, request: Request<bool>) -> ServiceResult<()> {
         log::debug!("set_use_wireguard_nt");
@@ -921,6 +1756,43 @@ impl EventListener for ManagementInterfaceEventBroadcaster {
             )),
         })
     }
+
+    fn notify_relay_rotation(&self, event: RelayRotationEvent) {
+        log::debug!("Broadcasting relay rotation event");
+        self.notify(types::DaemonEvent {
+            event: Some(daemon_event::Event::RelayRotation(
+                types::RelayRotationEvent {
+                    previous_relay: event.previous_relay.unwrap_or_default(),
+                },
+            )),
+        })
+    }
+
+    fn notify_relay_selection(&self, event: RelaySelectionEvent) {
+        log::debug!("Broadcasting relay selection event");
+        let result = match event.result {
+            Ok(relays) => types::relay_selection_event::Result::Selected(types::SelectedRelays {
+                tunnel_type: match relays.tunnel_type {
+                    TunnelType::OpenVpn => i32::from(types::TunnelType::Openvpn),
+                    TunnelType::Wireguard => i32::from(types::TunnelType::Wireguard),
+                },
+                exit: relays.exit.unwrap_or_default(),
+                entry: relays.entry.unwrap_or_default(),
+                bridge: relays.bridge.unwrap_or_default(),
+                obfuscator: relays.obfuscator.unwrap_or_default(),
+                explanation: relays.explanation,
+            }),
+            Err(error) => types::relay_selection_event::Result::Error(error),
+        };
+        self.notify(types::DaemonEvent {
+            event: Some(daemon_event::Event::RelaySelection(
+                types::RelaySelectionEvent {
+                    retry_attempt: event.retry_attempt,
+                    result: Some(result),
+                },
+            )),
+        })
+    }
 }
 
 impl ManagementInterfaceEventBroadcaster {
@@ -949,6 +1821,29 @@ fn map_daemon_error(error: crate::Error) -> Status {
         #[cfg(windows)]
         DaemonError::SplitTunnelError(error) => map_split_tunnel_error(error),
         DaemonError::AccountHistory(error) => map_account_history_error(error),
+        DaemonError::CustomListExists(_) => Status::already_exists(error.to_string()),
+        DaemonError::CustomListNotFound(_) => Status::not_found(error.to_string()),
+        #[cfg(not(target_os = "android"))]
+        DaemonError::ScheduleNotFound(_) => Status::not_found(error.to_string()),
+        #[cfg(not(target_os = "android"))]
+        DaemonError::DnsBlocklistNotFound(_) => Status::not_found(error.to_string()),
+        #[cfg(not(target_os = "android"))]
+        DaemonError::DnsBlocklistExists(_) => Status::already_exists(error.to_string()),
+        DaemonError::NestedCustomList => Status::invalid_argument(error.to_string()),
+        DaemonError::InvalidEscalationLadder(_) => Status::invalid_argument(error.display_chain()),
+        DaemonError::InvalidRelayListFile(_) => Status::invalid_argument(error.display_chain()),
+        DaemonError::InvalidRelayRotationPolicy(_) => {
+            Status::invalid_argument(error.display_chain())
+        }
+        DaemonError::InvalidExcludedDestination(_) => {
+            Status::invalid_argument(error.display_chain())
+        }
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        DaemonError::NotLanNetwork(_) => Status::invalid_argument(error.to_string()),
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        DaemonError::FirewallExceptionNotFound(_) => Status::not_found(error.to_string()),
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        DaemonError::FirewallExceptionExists(_) => Status::already_exists(error.to_string()),
         DaemonError::NoAccountToken | DaemonError::NoAccountTokenHistory => {
             Status::unauthenticated(error.to_string())
         }
@@ -0,0 +1,130 @@
+use super::{Error, Result};
+use mullvad_types::settings::SettingsVersion;
+
+// ======================================================
+// Section for vendoring types and values that
+// this settings version depend on. See `mod.rs`.
+
+/// Whether to negotiate a post-quantum safe PSK with WireGuard relays.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantumResistantState {
+    Auto,
+    On,
+    Off,
+}
+
+// ======================================================
+
+/// The quantum resistance setting was a boolean, `use_pq_safe_psk`, in the WireGuard tunnel
+/// options. It is replaced by `quantum_resistant`, which can also be `auto`. Enabled tunnels stay
+/// enabled, and disabled tunnels use `auto`, which currently does not negotiate a PSK either.
+pub fn migrate(settings: &mut serde_json::Value) -> Result<()> {
+    if !version_matches(settings) {
+        return Ok(());
+    }
+
+    log::info!("Migrating settings format to V7");
+
+    let wireguard_options = settings
+        .get_mut("tunnel_options")
+        .and_then(|options| options.get_mut("wireguard"));
+
+    if let Some(options) = wireguard_options {
+        let options = options.as_object_mut().ok_or(Error::NoMatchingVersion)?;
+        let use_pq_safe_psk = match options.remove("use_pq_safe_psk") {
+            Some(enabled) => serde_json::from_value(enabled).map_err(Error::Parse)?,
+            None => false,
+        };
+        let state = if use_pq_safe_psk {
+            QuantumResistantState::On
+        } else {
+            QuantumResistantState::Auto
+        };
+        options.insert("quantum_resistant".to_string(), serde_json::json!(state));
+    }
+
+    settings["settings_version"] = serde_json::json!(SettingsVersion::V7);
+
+    Ok(())
+}
+
+fn version_matches(settings: &mut serde_json::Value) -> bool {
+    settings
+        .get("settings_version")
+        .map(|version| version == SettingsVersion::V6 as u64)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{migrate, version_matches};
+
+    pub const V6_SETTINGS: &str = r#"
+{
+  "account_token": "1234",
+  "tunnel_options": {
+    "openvpn": {
+      "mssfix": null
+    },
+    "wireguard": {
+      "mtu": null,
+      "use_pq_safe_psk": true,
+      "rotation_interval": null
+    },
+    "generic": {
+      "enable_ipv6": false
+    }
+  },
+  "settings_version": 6
+}
+"#;
+
+    pub const V7_SETTINGS: &str = r#"
+{
+  "account_token": "1234",
+  "tunnel_options": {
+    "openvpn": {
+      "mssfix": null
+    },
+    "wireguard": {
+      "mtu": null,
+      "quantum_resistant": "on",
+      "rotation_interval": null
+    },
+    "generic": {
+      "enable_ipv6": false
+    }
+  },
+  "settings_version": 7
+}
+"#;
+
+    #[test]
+    fn test_v6_migration() {
+        let mut old_settings = serde_json::from_str(V6_SETTINGS).unwrap();
+
+        assert!(version_matches(&mut old_settings));
+
+        migrate(&mut old_settings).unwrap();
+        let new_settings: serde_json::Value = serde_json::from_str(V7_SETTINGS).unwrap();
+
+        assert_eq!(&old_settings, &new_settings);
+    }
+
+    #[test]
+    fn test_v6_migration_disabled() {
+        let mut old_settings: serde_json::Value = serde_json::from_str(V6_SETTINGS).unwrap();
+        old_settings["tunnel_options"]["wireguard"]["use_pq_safe_psk"] = serde_json::json!(false);
+
+        migrate(&mut old_settings).unwrap();
+
+        assert_eq!(
+            old_settings["tunnel_options"]["wireguard"]["quantum_resistant"],
+            serde_json::json!("auto")
+        );
+        assert!(old_settings["tunnel_options"]["wireguard"]
+            .get("use_pq_safe_psk")
+            .is_none());
+    }
+}
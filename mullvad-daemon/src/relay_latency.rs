@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use mullvad_types::relay_list::Relay;
+use tokio::net::TcpStream;
+
+/// TCP port that relays are probed on. OpenVPN relays accept OpenVPN over TCP on it, and WireGuard
+/// relays accept udp2tcp obfuscation on it.
+const PROBE_PORT: u16 = 443;
+
+/// Time to wait for a relay to accept a probe before giving up on it.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of relays to probe at a time.
+const MAX_PROBED_RELAYS: usize = 50;
+
+/// Number of times each relay is probed when benchmarking relays.
+const BENCHMARK_PROBES: u32 = 5;
+
+/// The result of benchmarking a single relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayBenchmark {
+    pub hostname: String,
+    /// Number of probes sent to the relay.
+    pub probes: u32,
+    /// Number of probes that the relay accepted in time.
+    pub responses: u32,
+    /// Shortest round-trip time of any accepted probe.
+    pub min_rtt: Option<Duration>,
+    /// Median round-trip time of the accepted probes.
+    pub median_rtt: Option<Duration>,
+}
+
+/// Measures the round-trip time to `relays` as the time it takes to open a TCP connection to them.
+/// Relays that cannot be reached within [`PROBE_TIMEOUT`] are left out. If there are more than
+/// [`MAX_PROBED_RELAYS`] relays, only that many are probed, chosen at random.
+pub async fn measure_relay_latencies(mut relays: Vec<Relay>) -> HashMap<String, Duration> {
+    if relays.len() > MAX_PROBED_RELAYS {
+        use rand::seq::SliceRandom;
+        relays.shuffle(&mut rand::thread_rng());
+        relays.truncate(MAX_PROBED_RELAYS);
+    }
+    let targets = relays
+        .into_iter()
+        .map(|relay| {
+            let addr = SocketAddr::new(relay.ipv4_addr_in.into(), PROBE_PORT);
+            (relay.hostname, addr)
+        })
+        .collect();
+    measure_latencies(targets, PROBE_TIMEOUT).await
+}
+
+/// Benchmarks `relays` by probing each of them [`BENCHMARK_PROBES`] times, one round at a time, so
+/// that probes to the same relay do not compete with each other. The results are sorted by median
+/// round-trip time, with relays that never responded last. If there are more than
+/// [`MAX_PROBED_RELAYS`] relays, only that many are benchmarked, chosen at random.
+pub async fn benchmark_relays(mut relays: Vec<Relay>) -> Vec<RelayBenchmark> {
+    if relays.len() > MAX_PROBED_RELAYS {
+        use rand::seq::SliceRandom;
+        relays.shuffle(&mut rand::thread_rng());
+        relays.truncate(MAX_PROBED_RELAYS);
+    }
+    let targets = relays
+        .into_iter()
+        .map(|relay| {
+            let addr = SocketAddr::new(relay.ipv4_addr_in.into(), PROBE_PORT);
+            (relay.hostname, addr)
+        })
+        .collect();
+    benchmark_targets(targets, BENCHMARK_PROBES, PROBE_TIMEOUT).await
+}
+
+async fn benchmark_targets(
+    targets: Vec<(String, SocketAddr)>,
+    probes: u32,
+    timeout: Duration,
+) -> Vec<RelayBenchmark> {
+    let mut samples: HashMap<String, Vec<Duration>> = HashMap::new();
+    for _ in 0..probes {
+        for (hostname, rtt) in measure_latencies(targets.clone(), timeout).await {
+            samples.entry(hostname).or_default().push(rtt);
+        }
+    }
+
+    let mut benchmarks: Vec<_> = targets
+        .into_iter()
+        .map(|(hostname, _)| {
+            let mut rtts = samples.remove(&hostname).unwrap_or_default();
+            rtts.sort();
+            RelayBenchmark {
+                hostname,
+                probes,
+                responses: rtts.len() as u32,
+                min_rtt: rtts.first().copied(),
+                median_rtt: rtts.get(rtts.len() / 2).copied(),
+            }
+        })
+        .collect();
+    benchmarks.sort_by_key(|benchmark| (benchmark.median_rtt.is_none(), benchmark.median_rtt));
+    benchmarks
+}
+
+/// Measures the time it takes to connect to each address in `targets`, concurrently.
+async fn measure_latencies(
+    targets: Vec<(String, SocketAddr)>,
+    timeout: Duration,
+) -> HashMap<String, Duration> {
+    let probes = targets.into_iter().map(|(hostname, addr)| async move {
+        let start = Instant::now();
+        match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(_stream)) => Some((hostname, start.elapsed())),
+            Ok(Err(error)) => {
+                log::trace!("Failed to probe {} at {}: {}", hostname, addr, error);
+                None
+            }
+            Err(_) => {
+                log::trace!("Timed out probing {} at {}", hostname, addr);
+                None
+            }
+        }
+    });
+    join_all(probes).await.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_measure_latencies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        // Nothing listens on a port that was just released
+        let closed_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let latencies = measure_latencies(
+            vec![
+                ("open".to_string(), open_addr),
+                ("closed".to_string(), closed_addr),
+            ],
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(latencies.contains_key("open"));
+        assert!(!latencies.contains_key("closed"));
+        assert!(latencies["open"] < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_benchmark_targets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        let closed_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let benchmarks = benchmark_targets(
+            vec![
+                ("closed".to_string(), closed_addr),
+                ("open".to_string(), open_addr),
+            ],
+            3,
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // Unreachable relays are sorted last
+        assert_eq!(benchmarks[0].hostname, "open");
+        assert_eq!(benchmarks[0].probes, 3);
+        assert_eq!(benchmarks[0].responses, 3);
+        assert!(benchmarks[0].min_rtt <= benchmarks[0].median_rtt);
+        assert_eq!(
+            benchmarks[1],
+            RelayBenchmark {
+                hostname: "closed".to_string(),
+                probes: 3,
+                responses: 0,
+                min_rtt: None,
+                median_rtt: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Connects and disconnects the tunnel when the periods of the connection schedules start and
+//! end.
+
+use crate::DaemonEventSender;
+use chrono::{Local, LocalResult, TimeZone, Utc};
+use mullvad_types::schedule::{self, ConnectionSchedule, ScheduleState};
+use std::time::Duration;
+use talpid_core::mpsc::Sender;
+use tokio::sync::watch;
+
+/// Longest time to wait before checking the schedules again. Timers do not account for changes to
+/// the system clock or time spent suspended, so they are not trusted to fire on time beyond this.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Sent when the schedules start or stop calling for the tunnel to be connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleChanged(pub bool);
+
+/// Checks the schedules whenever a period starts or ends, and whenever the schedules change. The
+/// daemon is notified whenever the schedules start or stop calling for the tunnel to be
+/// connected, including when this is first known.
+pub struct ScheduleTimer {
+    schedules_tx: watch::Sender<Vec<ConnectionSchedule>>,
+}
+
+impl ScheduleTimer {
+    /// Starts enforcing `schedules` in the background. This stops when the timer is dropped.
+    pub fn spawn(
+        schedules: Vec<ConnectionSchedule>,
+        event_tx: DaemonEventSender<ScheduleChanged>,
+    ) -> Self {
+        let (schedules_tx, schedules_rx) = watch::channel(schedules);
+        tokio::spawn(Self::run(schedules_rx, event_tx));
+        ScheduleTimer { schedules_tx }
+    }
+
+    /// Replaces the schedules to enforce. They are checked immediately.
+    pub fn set_schedules(&self, schedules: Vec<ConnectionSchedule>) {
+        let _ = self.schedules_tx.send(schedules);
+    }
+
+    async fn run(
+        mut schedules_rx: watch::Receiver<Vec<ConnectionSchedule>>,
+        event_tx: DaemonEventSender<ScheduleChanged>,
+    ) {
+        let mut last_active = None;
+        loop {
+            let schedules = schedules_rx.borrow().clone();
+            let now = Local::now().naive_local();
+
+            // Nothing is enforced without schedules
+            let active = if schedules.is_empty() {
+                None
+            } else {
+                Some(schedule::is_active(&schedules, now))
+            };
+            if let Some(active) = active {
+                if last_active != Some(active) && event_tx.send(ScheduleChanged(active)).is_err() {
+                    break;
+                }
+            }
+            last_active = active;
+
+            let wait = schedule::next_change(&schedules, now)
+                .and_then(|next_change| (next_change - now).to_std().ok())
+                .map(|wait| wait.min(MAX_WAIT))
+                .unwrap_or(MAX_WAIT);
+            if let Ok(Err(_)) = tokio::time::timeout(wait, schedules_rx.changed()).await {
+                // The timer was dropped
+                break;
+            }
+        }
+    }
+}
+
+/// Returns whether `schedules` currently call for the tunnel to be connected.
+pub fn current_state(schedules: &[ConnectionSchedule]) -> ScheduleState {
+    let now = Local::now().naive_local();
+    let next_change = schedule::next_change(schedules, now).and_then(|next_change| {
+        // Times that are skipped when the clock is moved forward are assumed to never occur
+        match Local.from_local_datetime(&next_change) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => {
+                Some(time.with_timezone(&Utc))
+            }
+            LocalResult::None => None,
+        }
+    });
+    ScheduleState {
+        active: schedule::is_active(schedules, now),
+        next_change,
+    }
+}
@@ -1,9 +1,21 @@
 #[cfg(not(target_os = "android"))]
 use futures::TryFutureExt;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use mullvad_types::excluded_destinations::ExcludedDestinations;
 use mullvad_types::{
-    relay_constraints::{BridgeSettings, BridgeState, ObfuscationSettings, RelaySettingsUpdate},
+    custom_list::CustomListsSettings,
+    escalation::EscalationLadder,
+    relay_constraints::{
+        BridgeSettings, BridgeState, ObfuscationSettings, ProtocolPreference, RelaySettingsUpdate,
+    },
+    relay_list::RelayListSource,
+    relay_rotation::RelayRotationPolicy,
     settings::{DnsOptions, Settings},
-    wireguard::RotationInterval,
+    wireguard::{PortFallback, QuantumResistantState, RotationInterval},
+};
+#[cfg(not(target_os = "android"))]
+use mullvad_types::{
+    dns_blocklist::DnsBlocklist, network_rules::NetworkRule, schedule::ConnectionSchedule,
 };
 use rand::Rng;
 #[cfg(target_os = "windows")]
@@ -12,7 +24,13 @@ use std::{
     ops::Deref,
     path::{Path, PathBuf},
 };
+#[cfg(not(target_os = "android"))]
+use talpid_types::net::NetworkIdentity;
 use talpid_types::ErrorExt;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use talpid_types::{
+    firewall_exception::FirewallException, lan::LanRules, split_tunnel::SplitTunnelMode,
+};
 use tokio::{
     fs,
     io::{self, AsyncWriteExt},
@@ -215,6 +233,12 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn set_lan_rules(&mut self, lan_rules: Option<LanRules>) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.lan_rules, lan_rules);
+        self.update(should_save).await
+    }
+
     pub async fn set_block_when_disconnected(
         &mut self,
         block_when_disconnected: bool,
@@ -249,16 +273,19 @@ impl SettingsPersister {
 
     pub async fn set_quantum_resistant_tunnel(
         &mut self,
-        use_pq_safe_psk: bool,
+        quantum_resistant: QuantumResistantState,
     ) -> Result<bool, Error> {
         let should_save = Self::update_field(
-            &mut self
-                .settings
-                .tunnel_options
-                .wireguard
-                .options
-                .use_pq_safe_psk,
-            use_pq_safe_psk,
+            &mut self.settings.tunnel_options.wireguard.quantum_resistant,
+            quantum_resistant,
+        );
+        self.update(should_save).await
+    }
+
+    pub async fn set_wireguard_ephemeral_keys(&mut self, enabled: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.wireguard.ephemeral_keys,
+            enabled,
         );
         self.update(should_save).await
     }
@@ -275,6 +302,33 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    pub async fn set_wireguard_mtu_detection(&mut self, enabled: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.wireguard.options.mtu_detection,
+            enabled,
+        );
+        self.update(should_save).await
+    }
+
+    pub async fn set_wireguard_daita(&mut self, enabled: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.wireguard.options.daita,
+            enabled,
+        );
+        self.update(should_save).await
+    }
+
+    pub async fn set_wireguard_port_fallback(
+        &mut self,
+        port_fallback: PortFallback,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.wireguard.port_fallback,
+            port_fallback,
+        );
+        self.update(should_save).await
+    }
+
     pub async fn set_wireguard_rotation_interval(
         &mut self,
         interval: Option<RotationInterval>,
@@ -324,6 +378,72 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn set_excluded_destinations(
+        &mut self,
+        excluded_destinations: ExcludedDestinations,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.excluded_destinations,
+            excluded_destinations,
+        );
+        self.update(should_save).await
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn set_split_tunnel_mode(&mut self, mode: SplitTunnelMode) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.split_tunnel_mode, mode);
+        self.update(should_save).await
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn set_firewall_exceptions(
+        &mut self,
+        exceptions: Vec<FirewallException>,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.firewall_exceptions, exceptions);
+        self.update(should_save).await
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub async fn set_network_rule(&mut self, rule: NetworkRule) -> Result<bool, Error> {
+        let should_save = self.settings.network_rules.set_rule(rule);
+        self.update(should_save).await
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub async fn remove_network_rule(&mut self, network: &NetworkIdentity) -> Result<bool, Error> {
+        let should_save = self.settings.network_rules.remove_rule(network);
+        self.update(should_save).await
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub async fn set_network_lockdown(&mut self, lockdown: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.network_rules.lockdown_untrusted,
+            lockdown,
+        );
+        self.update(should_save).await
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub async fn set_schedules(
+        &mut self,
+        schedules: Vec<ConnectionSchedule>,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.schedules, schedules);
+        self.update(should_save).await
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub async fn set_dns_blocklists(
+        &mut self,
+        blocklists: Vec<DnsBlocklist>,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.dns_blocklists, blocklists);
+        self.update(should_save).await
+    }
+
     #[cfg(windows)]
     pub async fn set_use_wireguard_nt(&mut self, state: bool) -> Result<bool, Error> {
         let should_save = Self::update_field(
@@ -359,6 +479,104 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    pub async fn set_custom_lists(
+        &mut self,
+        custom_lists: CustomListsSettings,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.custom_lists, custom_lists);
+        self.update(should_save).await
+    }
+
+    pub async fn set_escalation_ladder(
+        &mut self,
+        escalation_ladder: EscalationLadder,
+    ) -> Result<bool, Error> {
+        let should_save =
+            Self::update_field(&mut self.settings.escalation_ladder, escalation_ladder);
+        self.update(should_save).await
+    }
+
+    pub async fn set_load_aware_selection(
+        &mut self,
+        load_aware_selection: bool,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.load_aware_selection,
+            load_aware_selection,
+        );
+        self.update(should_save).await
+    }
+
+    pub async fn set_avoid_recent_relays(
+        &mut self,
+        avoid_recent_relays: u32,
+    ) -> Result<bool, Error> {
+        let should_save =
+            Self::update_field(&mut self.settings.avoid_recent_relays, avoid_recent_relays);
+        self.update(should_save).await
+    }
+
+    pub async fn set_prefer_ipv6(&mut self, prefer_ipv6: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.prefer_ipv6, prefer_ipv6);
+        self.update(should_save).await
+    }
+
+    pub async fn set_reuse_obfuscator(&mut self, reuse_obfuscator: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.reuse_obfuscator, reuse_obfuscator);
+        self.update(should_save).await
+    }
+
+    pub async fn set_latency_aware_selection(
+        &mut self,
+        latency_aware_selection: bool,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.latency_aware_selection,
+            latency_aware_selection,
+        );
+        self.update(should_save).await
+    }
+
+    pub async fn set_sticky_relay_max_failures(
+        &mut self,
+        sticky_relay_max_failures: u32,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.sticky_relay_max_failures,
+            sticky_relay_max_failures,
+        );
+        self.update(should_save).await
+    }
+
+    pub async fn set_protocol_preference(
+        &mut self,
+        protocol_preference: Option<ProtocolPreference>,
+    ) -> Result<bool, Error> {
+        let should_save =
+            Self::update_field(&mut self.settings.protocol_preference, protocol_preference);
+        self.update(should_save).await
+    }
+
+    pub async fn set_relay_list_source(
+        &mut self,
+        relay_list_source: RelayListSource,
+    ) -> Result<bool, Error> {
+        let should_save =
+            Self::update_field(&mut self.settings.relay_list_source, relay_list_source);
+        self.update(should_save).await
+    }
+
+    pub async fn set_relay_rotation_policy(
+        &mut self,
+        relay_rotation: RelayRotationPolicy,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.relay_rotation,
+            relay_rotation,
+        );
+        self.update(should_save).await
+    }
+
     async fn update(&mut self, should_save: bool) -> Result<bool, Error> {
         if should_save {
             self.save().await.map(|_| true)
@@ -0,0 +1,321 @@
+//! Samples the traffic counters of the tunnel interface while connected, and keeps a history of
+//! the samples for displaying the throughput of the tunnel.
+
+use chrono::Utc;
+use mullvad_types::traffic_stats::TrafficSample;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+use talpid_types::ErrorExt;
+use tokio::sync::mpsc;
+
+/// How often the counters of the tunnel interface are sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of samples that are kept. This covers the last five minutes.
+const MAX_HISTORY: usize = 300;
+
+/// Traffic counters of a network interface.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct InterfaceCounters {
+    rx_bytes: u64,
+    tx_bytes: u64,
+    rx_packets: u64,
+    tx_packets: u64,
+}
+
+impl InterfaceCounters {
+    /// Returns the change since `previous`. Counters that decreased are assumed to have been
+    /// reset, e.g. because the interface was recreated.
+    fn since(&self, previous: &InterfaceCounters) -> InterfaceCounters {
+        let delta = |current: u64, previous: u64| current.checked_sub(previous).unwrap_or(current);
+        InterfaceCounters {
+            rx_bytes: delta(self.rx_bytes, previous.rx_bytes),
+            tx_bytes: delta(self.tx_bytes, previous.tx_bytes),
+            rx_packets: delta(self.rx_packets, previous.rx_packets),
+            tx_packets: delta(self.tx_packets, previous.tx_packets),
+        }
+    }
+}
+
+/// Samples the counters of the current tunnel interface every [`SAMPLE_INTERVAL`]. Nothing is
+/// sampled while no interface is set. The last [`MAX_HISTORY`] samples are kept.
+#[derive(Clone)]
+pub struct TrafficMonitor {
+    state: Arc<Mutex<MonitorState>>,
+}
+
+#[derive(Default)]
+struct MonitorState {
+    interface: Option<String>,
+    /// Counters read from the interface by the last sample, and when they were read.
+    last_counters: Option<(Instant, InterfaceCounters)>,
+    /// Traffic since the interface was set.
+    total: InterfaceCounters,
+    history: VecDeque<TrafficSample>,
+    listeners: Vec<mpsc::UnboundedSender<TrafficSample>>,
+    /// Set if reading the counters of the current interface failed, so that it is only logged
+    /// once.
+    read_failed: bool,
+}
+
+impl TrafficMonitor {
+    /// Creates a monitor and starts sampling in the background. Sampling stops once every handle
+    /// to the monitor has been dropped.
+    pub fn spawn() -> Self {
+        let state = Arc::new(Mutex::new(MonitorState::default()));
+        tokio::spawn(Self::run(Arc::downgrade(&state)));
+        TrafficMonitor { state }
+    }
+
+    async fn run(state: Weak<Mutex<MonitorState>>) {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let state = match state.upgrade() {
+                Some(state) => state,
+                None => break,
+            };
+            let interface = state.lock().interface.clone();
+            if let Some(interface) = interface {
+                let counters = read_counters(&interface);
+                state.lock().add_sample(&interface, counters);
+            }
+        }
+    }
+
+    /// Sets the tunnel interface to sample. The history is cleared when a new interface is set,
+    /// but is kept when sampling stops.
+    pub fn set_interface(&self, interface: Option<String>) {
+        let mut state = self.state.lock();
+        if interface.is_some() {
+            state.last_counters = None;
+            state.total = InterfaceCounters::default();
+            state.history.clear();
+            state.read_failed = false;
+        }
+        state.interface = interface;
+    }
+
+    /// Returns the kept samples, oldest first.
+    pub fn history(&self) -> Vec<TrafficSample> {
+        self.state.lock().history.iter().copied().collect()
+    }
+
+    /// Returns a receiver of every new sample.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TrafficSample> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().listeners.push(tx);
+        rx
+    }
+}
+
+impl MonitorState {
+    fn add_sample(&mut self, interface: &str, counters: io::Result<InterfaceCounters>) {
+        // The interface may have changed while the counters were read
+        if self.interface.as_deref() != Some(interface) {
+            return;
+        }
+        let counters = match counters {
+            Ok(counters) => counters,
+            Err(error) => {
+                if !self.read_failed {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg(&format!(
+                            "Failed to read traffic counters of {}",
+                            interface
+                        ))
+                    );
+                    self.read_failed = true;
+                }
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let (delta, elapsed) = match self.last_counters {
+            Some((last_time, last_counters)) => (
+                counters.since(&last_counters),
+                now.duration_since(last_time),
+            ),
+            // The first sample is the baseline that traffic is counted from
+            None => (InterfaceCounters::default(), Duration::ZERO),
+        };
+        self.last_counters = Some((now, counters));
+
+        self.total.rx_bytes += delta.rx_bytes;
+        self.total.tx_bytes += delta.tx_bytes;
+        self.total.rx_packets += delta.rx_packets;
+        self.total.tx_packets += delta.tx_packets;
+
+        let rate = |bytes: u64| {
+            if elapsed.is_zero() {
+                0
+            } else {
+                (bytes as f64 / elapsed.as_secs_f64()) as u64
+            }
+        };
+        let sample = TrafficSample {
+            timestamp: Utc::now(),
+            rx_bytes: self.total.rx_bytes,
+            tx_bytes: self.total.tx_bytes,
+            rx_packets: self.total.rx_packets,
+            tx_packets: self.total.tx_packets,
+            rx_rate: rate(delta.rx_bytes),
+            tx_rate: rate(delta.tx_bytes),
+        };
+
+        if self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(sample);
+        self.listeners.retain(|tx| tx.send(sample).is_ok());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_counters(interface: &str) -> io::Result<InterfaceCounters> {
+    let read = |counter: &str| -> io::Result<u64> {
+        let path = format!("/sys/class/net/{}/statistics/{}", interface, counter);
+        std::fs::read_to_string(path)?
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid counter value"))
+    };
+    Ok(InterfaceCounters {
+        rx_bytes: read("rx_bytes")?,
+        tx_bytes: read("tx_bytes")?,
+        rx_packets: read("rx_packets")?,
+        tx_packets: read("tx_packets")?,
+    })
+}
+
+/// The counters are only 32 bits wide on macOS, so traffic is undercounted when they wrap around
+/// between two samples.
+#[cfg(target_os = "macos")]
+fn read_counters(interface: &str) -> io::Result<InterfaceCounters> {
+    use std::ffi::CStr;
+
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: `addrs` is a valid pointer to write the list to
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut counters = None;
+    let mut current = addrs;
+    while !current.is_null() {
+        // SAFETY: `current` is an element of the list returned by `getifaddrs`
+        let addr = unsafe { &*current };
+        current = addr.ifa_next;
+        if addr.ifa_addr.is_null() || addr.ifa_data.is_null() {
+            continue;
+        }
+        // SAFETY: `ifa_addr` is non-null, and `ifa_name` is a null-terminated string
+        let (family, name) = unsafe {
+            (
+                i32::from((*addr.ifa_addr).sa_family),
+                CStr::from_ptr(addr.ifa_name),
+            )
+        };
+        if family != libc::AF_LINK || name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+        // SAFETY: `ifa_data` points to an `if_data` for link-level addresses
+        let data = unsafe { &*(addr.ifa_data as *const libc::if_data) };
+        counters = Some(InterfaceCounters {
+            rx_bytes: u64::from(data.ifi_ibytes),
+            tx_bytes: u64::from(data.ifi_obytes),
+            rx_packets: u64::from(data.ifi_ipackets),
+            tx_packets: u64::from(data.ifi_opackets),
+        });
+        break;
+    }
+
+    // SAFETY: `addrs` was returned by `getifaddrs` and is not used after this
+    unsafe { libc::freeifaddrs(addrs) };
+    counters.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "interface not found"))
+}
+
+#[cfg(windows)]
+fn read_counters(interface: &str) -> io::Result<InterfaceCounters> {
+    use windows_sys::Win32::NetworkManagement::IpHelper::{GetIfEntry2, MIB_IF_ROW2};
+
+    // SAFETY: Every bit pattern is a valid `MIB_IF_ROW2`
+    let mut row: MIB_IF_ROW2 = unsafe { std::mem::zeroed() };
+    row.InterfaceLuid = talpid_windows_net::luid_from_alias(interface)?;
+    // SAFETY: `row` is a valid `MIB_IF_ROW2` with the LUID set
+    let status = unsafe { GetIfEntry2(&mut row) };
+    if status != 0 {
+        return Err(io::Error::from_raw_os_error(status));
+    }
+    Ok(InterfaceCounters {
+        rx_bytes: row.InOctets,
+        tx_bytes: row.OutOctets,
+        rx_packets: row.InUcastPkts + row.InNUcastPkts,
+        tx_packets: row.OutUcastPkts + row.OutNUcastPkts,
+    })
+}
+
+#[cfg(target_os = "android")]
+fn read_counters(_interface: &str) -> io::Result<InterfaceCounters> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "traffic counters are not available on Android",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counters(rx_bytes: u64, tx_bytes: u64) -> InterfaceCounters {
+        InterfaceCounters {
+            rx_bytes,
+            tx_bytes,
+            rx_packets: rx_bytes / 100,
+            tx_packets: tx_bytes / 100,
+        }
+    }
+
+    #[test]
+    fn test_traffic_samples() {
+        let mut state = MonitorState {
+            interface: Some("wg0".to_owned()),
+            ..MonitorState::default()
+        };
+
+        // The first sample is the baseline
+        state.add_sample("wg0", Ok(counters(1000, 500)));
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(state.history[0].rx_bytes, 0);
+        assert_eq!(state.history[0].rx_rate, 0);
+
+        state.add_sample("wg0", Ok(counters(3000, 700)));
+        let sample = state.history[1];
+        assert_eq!((sample.rx_bytes, sample.tx_bytes), (2000, 200));
+        assert_eq!((sample.rx_packets, sample.tx_packets), (20, 2));
+
+        // Counters that were reset are counted from zero
+        state.add_sample("wg0", Ok(counters(100, 50)));
+        let sample = state.history[2];
+        assert_eq!((sample.rx_bytes, sample.tx_bytes), (2100, 250));
+
+        // Failures and samples of a previous interface are ignored
+        state.add_sample("wg0", Err(io::Error::from(io::ErrorKind::NotFound)));
+        state.add_sample("wg1", Ok(counters(10000, 10000)));
+        assert_eq!(state.history.len(), 3);
+
+        for _ in 0..MAX_HISTORY {
+            state.add_sample("wg0", Ok(counters(100, 50)));
+        }
+        assert_eq!(state.history.len(), MAX_HISTORY);
+        assert_eq!(state.history.back().unwrap().rx_bytes, 2100);
+    }
+}
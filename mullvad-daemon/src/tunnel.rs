@@ -1,14 +1,48 @@
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
+    iter,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use tokio::sync::Mutex;
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, Either};
+use ipnetwork::IpNetwork;
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
 
-use mullvad_relay_selector::{RelaySelector, SelectedBridge, SelectedObfuscator, SelectedRelay};
+use mullvad_relay_selector::{
+    RelaySelector, SelectedBridge, SelectedObfuscator, SelectedRelay, SelectorConfig,
+};
 use mullvad_types::{
-    endpoint::MullvadEndpoint, location::GeoIpLocation, relay_list::Relay, settings::TunnelOptions,
+    custom_tunnel,
+    device::DeviceId,
+    endpoint::{MullvadEndpoint, MullvadWireguardEndpoint},
+    location::{relay_to_geoip, Coordinates, GeoIpLocation},
+    relay_constraints::{
+        BridgeState, Constraint, CustomHop, CustomMultihopRelay, LocationConstraint, Match,
+        OpenVpnConstraints, PortRanges, ProtocolPreference, RelayConstraints,
+        RelayConstraintsUpdate, TransportPort, Udp2TcpObfuscationSettings, WireguardConstraints,
+    },
+    relay_list::{Relay, RelayEndpointData, RelayList},
+    relay_selection::{RelaySelectionEvent, SelectedRelays as SelectedRelayHostnames},
+    settings::{ProtocolPorts, TunnelOptions},
+    wireguard::{WireguardData, WireguardImplementation},
 };
-use talpid_core::tunnel_state_machine::TunnelParametersGenerator;
+use talpid_core::{mpsc::Sender, tunnel_state_machine::TunnelParametersGenerator};
 use talpid_types::{
-    net::{wireguard, TunnelParameters},
+    net::{
+        all_of_the_internet, obfuscation::ObfuscatorConfig, wireguard, Endpoint, IpVersion,
+        ObfuscationEndpoint, SplitDnsRule, TransportProtocol, TunnelParameters, TunnelType,
+    },
     tunnel::ParameterGenerationError,
     ErrorExt,
 };
@@ -16,262 +50,3156 @@ use talpid_types::{
 #[cfg(not(target_os = "android"))]
 use talpid_types::net::openvpn;
 
-use crate::device::{AccountManagerHandle, PrivateAccountAndDevice};
+use crate::{
+    device::{self, AccountManagerHandle, PrivateAccountAndDevice},
+    relay_latency,
+};
+
+/// Environment variable containing relay constraints, as JSON, that take precedence over the
+/// relay constraints in the settings during relay selection.
+const CONSTRAINTS_OVERRIDE_VAR: &str = "MULLVAD_RELAY_CONSTRAINTS_OVERRIDE";
+
+/// Environment variable containing a socket address that the peer endpoint of generated WireGuard
+/// tunnel parameters is replaced with, e.g. the address of a local forwarder.
+const ENDPOINT_OVERRIDE_VAR: &str = "MULLVAD_WIREGUARD_ENDPOINT_OVERRIDE";
+
+/// File that the relays selected by the last generated tunnel parameters are cached in.
+const LAST_RELAYS_FILE: &str = "last-relays.json";
+
+/// File that the exit relays of the most recent successful connections are cached in.
+const RECENT_RELAYS_FILE: &str = "recent-relays.json";
+
+/// File that the fingerprints of networks that require obfuscation are cached in.
+const OBFUSCATED_NETWORKS_FILE: &str = "obfuscated-networks.json";
+
+/// Maximum number of exit relays to keep connection statistics for. The least recently used
+/// entry is evicted when the limit is exceeded.
+const MAX_RELAY_STATS: usize = 100;
+
+/// Maximum number of exit relays of recent connections that are remembered, and thus the maximum
+/// number of recently used relays that can be avoided.
+const MAX_RECENT_RELAYS: usize = 20;
+
+/// Number of consecutive WireGuard timeouts without obfuscation after which a network is assumed
+/// to require obfuscation.
+const MAX_UNOBFUSCATED_TIMEOUTS: u32 = 2;
+
+/// Maximum number of networks that are remembered as requiring obfuscation. The least recently
+/// added network is forgotten when the limit is exceeded.
+const MAX_OBFUSCATED_NETWORKS: usize = 50;
+
+/// Minimum amount of time between measurements of the latency to relays, when latency-aware
+/// relay selection is enabled.
+const RELAY_LATENCY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum amount of time to wait for a relay list in `ensure_relays_ready`.
+const RELAY_LIST_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which the relay selector is checked for a relay list while waiting for one.
+const RELAY_LIST_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Persistent keepalive interval, in seconds, of WireGuard configs exported by
+/// `ParametersGenerator::export_last_wireguard_config`.
+const EXPORT_PERSISTENT_KEEPALIVE: u16 = 25;
+
+/// Maximum length of a domain name in a split DNS rule.
+const MAX_DOMAIN_LENGTH: usize = 253;
+
+/// Maximum length of a single label of a domain name in a split DNS rule.
+const MAX_DOMAIN_LABEL_LENGTH: usize = 63;
+
+/// OpenVPN data channel ciphers supported by the relays.
+#[cfg(not(target_os = "android"))]
+const RELAY_OPENVPN_CIPHERS: &[&str] = &["AES-256-GCM", "AES-256-CBC"];
 
 #[derive(err_derive::Error, Debug)]
 pub enum Error {
     #[error(display = "Not logged in on a valid device")]
     NoAuthDetails,
 
+    #[error(display = "The WireGuard key has expired")]
+    KeyExpired,
+
+    #[error(display = "No device with ID {} has been added", _0)]
+    UnknownDevice(DeviceId),
+
     #[error(display = "No relay available")]
     NoRelayAvailable,
 
-    #[error(display = "No bridge available")]
-    NoBridgeAvailable,
+    /// `forced` is set if the bridge state requires a bridge, as opposed to a bridge being used
+    /// automatically after failed connection attempts.
+    #[error(display = "No bridge available (bridge required: {})", forced)]
+    NoBridgeAvailable { forced: bool },
+
+    #[error(display = "No obfuscator matches the obfuscation settings")]
+    NoObfuscatorAvailable,
+
+    #[error(display = "All matching relays are undergoing maintenance")]
+    RelaysInMaintenance,
+
+    #[error(display = "No relay can be used as the multihop entry relay")]
+    NoMultihopEntry,
+
+    #[error(display = "No relay matching the relay constraints is allowed for this account")]
+    NoAllowedRelay,
+
+    #[error(display = "Every relay matching the relay constraints is excluded")]
+    AllRelaysExcluded,
+
+    #[error(
+        display = "No relay matching the relay constraints supports quantum resistant tunnels"
+    )]
+    NoQuantumResistantRelay,
+
+    #[error(display = "No relay matching the relay constraints supports DAITA")]
+    NoDaitaRelay,
+
+    #[error(
+        display = "No relay matching the relay constraints accepts the configured protocol ports"
+    )]
+    NoRelayOnProtocolPorts,
 
     #[error(display = "Failed to resolve hostname for custom relay")]
     ResolveCustomHostname,
+
+    #[error(
+        display = "Relay {} does not support the pluggable transport {}",
+        relay,
+        transport
+    )]
+    UnsupportedPluggableTransport { transport: String, relay: String },
+
+    #[error(display = "Entry relay {} cannot carry the selected obfuscation", _0)]
+    ObfuscationMismatch(String),
+
+    #[error(display = "Tunnel parameter generation was cancelled")]
+    Cancelled,
+
+    #[error(display = "Invalid split DNS rule: {}", _0)]
+    InvalidSplitDnsRule(String),
+
+    #[error(display = "No relay list was loaded in time")]
+    RelayListUnavailable,
+
+    #[error(display = "Every candidate relay was rejected")]
+    ExhaustedCandidates,
+
+    #[error(display = "Obfuscation is required but the tunnel would not be obfuscated")]
+    ObfuscationRequired,
+
+    #[error(display = "The tunnel would not satisfy the tunnel options: {}", _0)]
+    ProtocolOptionsViolated(String),
+
+    #[cfg(not(target_os = "android"))]
+    #[error(display = "OpenVPN cipher {} is not supported by the relays", _0)]
+    UnsupportedCipher(String),
+}
+
+pub(crate) type SelectedRelays = (
+    SelectedRelay,
+    Option<SelectedBridge>,
+    Option<SelectedObfuscator>,
+);
+
+/// Selects the relays that tunnel parameters are generated for. Implemented by [`RelaySelector`],
+/// which is used unless another implementation is given to [`ParametersGeneratorBuilder`].
+pub(crate) trait RelayProvider: Send {
+    /// Selects relays for `retry_attempt`, deciding whether to use bridges or obfuscation based
+    /// on `escalation_attempt`. `constraints_override` is layered on top of the configured relay
+    /// constraints, and `udp2tcp_override` replaces the configured udp2tcp settings if set.
+    fn get_relay(
+        &self,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+        constraints_override: Option<RelayConstraintsUpdate>,
+        udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+    ) -> Result<SelectedRelays, mullvad_relay_selector::Error>;
+}
+
+impl RelayProvider for RelaySelector {
+    fn get_relay(
+        &self,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+        constraints_override: Option<RelayConstraintsUpdate>,
+        udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+    ) -> Result<SelectedRelays, mullvad_relay_selector::Error> {
+        self.get_relay_with_obfuscation_override(
+            retry_attempt,
+            escalation_attempt,
+            constraints_override,
+            udp2tcp_override,
+        )
+    }
+}
+
+/// Provides the device that tunnel parameters are generated for. Implemented by
+/// [`AccountManagerHandle`].
+pub(crate) trait DeviceProvider: Send + Sync {
+    /// Returns the logged in account and device, or `None` if not logged in.
+    fn device(&self) -> BoxFuture<'_, Option<PrivateAccountAndDevice>>;
+
+    /// Replaces the WireGuard key of the device, e.g. after it has expired.
+    fn rotate_key(&self) -> BoxFuture<'_, Result<(), device::Error>>;
+}
+
+impl DeviceProvider for AccountManagerHandle {
+    fn device(&self) -> BoxFuture<'_, Option<PrivateAccountAndDevice>> {
+        Box::pin(async move { self.data().await.ok().and_then(|state| state.into_device()) })
+    }
+
+    fn rotate_key(&self) -> BoxFuture<'_, Result<(), device::Error>> {
+        Box::pin(AccountManagerHandle::rotate_key(self))
+    }
+}
+
+/// Pluggable transport that WireGuard traffic is obfuscated through. Only relays that advertise
+/// support for the transport named `name` can be used with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PluggableTransport {
+    pub name: String,
+    /// Port that the relay accepts the transport on.
+    pub port: u16,
+    pub protocol: TransportProtocol,
+    /// Passed on to the transport without being interpreted.
+    pub parameters: BTreeMap<String, String>,
+}
+
+/// Reason why no tunnel parameters can be generated for the current settings.
+#[derive(err_derive::Error, Debug)]
+pub enum UnsatisfiableReason {
+    #[error(display = "Not logged in on a valid device")]
+    NotLoggedIn,
+
+    #[error(display = "No relay matches the relay constraints")]
+    NoRelayForConstraints,
+
+    #[error(display = "No bridge matches the bridge constraints")]
+    NoBridgeForConstraints,
 }
 
 #[derive(Clone)]
-pub(crate) struct ParametersGenerator(Arc<Mutex<InnerParametersGenerator>>);
+pub(crate) struct ParametersGenerator {
+    /// Guarded by an asynchronous mutex, since it is held across relay selection and hostname
+    /// resolution. Unlike `std::sync::Mutex`, it is not poisoned if a task panics while holding
+    /// it, so a panic during `generate` does not prevent tunnel parameters from being generated
+    /// later.
+    inner: Arc<Mutex<InnerParametersGenerator>>,
+    /// Notified to abort custom relay hostname resolution.
+    cancel: Arc<Notify>,
+    /// Set synchronously by the tunnel state machine, so it is kept outside of the inner state.
+    tunnel_interface: Arc<parking_lot::Mutex<TunnelInterface>>,
+    /// Set synchronously by the tunnel state machine, so it is kept outside of the inner state.
+    network_report: Arc<parking_lot::Mutex<NetworkReport>>,
+}
+
+/// The interface of the last tunnel that came up.
+
+#[derive(Default)]
+struct TunnelInterface {
+    name: Option<String>,
+    /// IP addresses assigned to the interface.
+    addresses: Vec<IpAddr>,
+    /// Gateways of the tunnel. These are the default DNS servers.
+    gateways: Vec<IpAddr>,
+}
+
+/// Network changes and timeouts reported by the tunnel state machine since the last call to
+/// `generate`.
+#[derive(Default)]
+struct NetworkReport {
+    /// Fingerprint of the network that the next tunnel is established over.
+    network: Option<String>,
+    /// Set if the last tunnel timed out while connecting.
+    timed_out: bool,
+    /// Whether the host has a route to the IPv6 internet outside of the tunnel.
+    ipv6_available: bool,
+}
+
+/// Rewrites the peer endpoint of generated WireGuard tunnel parameters. It receives the endpoint of
+/// the selected relay and returns the endpoint to connect to instead.
+pub(crate) type EndpointRewrite = Arc<dyn Fn(SocketAddr) -> SocketAddr + Send + Sync>;
+
+/// Receives a [`RelaySelectionEvent`] for every call to `generate`.
+type SelectionEventSender = Box<dyn Sender<RelaySelectionEvent> + Send>;
 
 struct InnerParametersGenerator {
     relay_selector: RelaySelector,
+    relay_provider: Box<dyn RelayProvider>,
     tunnel_options: TunnelOptions,
-    account_manager: AccountManagerHandle,
+    device_provider: Arc<dyn DeviceProvider>,
+    /// Set while an expired WireGuard key is being rotated.
+    rotating_key: Arc<AtomicBool>,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    path_mtu: DiscoveredMtu,
+    recently_failed: RecentlyFailedRelays,
+    recently_used: RecentlyUsedRelays,
+    relay_stats: RelayStats,
+    escalation: Escalation,
+    endpoint_rewrite: Option<EndpointRewrite>,
+    protocol_preference: Option<ProtocolPreference>,
+    /// Set once a WireGuard tunnel has failed.
+    wireguard_failed: bool,
+    devices: Devices,
+    reuse_obfuscator: bool,
+    /// Set if the exit relay of the last successful connection should be reused.
+    sticky_relay: Option<StickyRelay>,
+    latency_aware: bool,
+    /// Time at which the latency to relays was last measured.
+    latencies_measured_at: Option<Instant>,
 
     last_generated_relays: Option<LastSelectedRelays>,
+    /// Set if `last_generated_relays` was restored from the cache and no tunnel parameters have
+    /// been generated since.
+    last_relays_cached: bool,
+    cache_path: Option<PathBuf>,
+    recent_relays_path: Option<PathBuf>,
+    obfuscated_networks: ObfuscatedNetworks,
+    obfuscated_networks_path: Option<PathBuf>,
+    /// Hostnames of relays that must not be selected until the daemon restarts.
+    session_excluded_relays: Vec<String>,
+    last_selection: Option<SelectionSnapshot>,
+    selection_events: Option<SelectionEventSender>,
+    last_parameters: CachedParameters,
+    /// Unlike `last_parameters`, this is kept when the settings change.
+    last_generated_parameters: Option<TunnelParameters>,
 }
 
 impl ParametersGenerator {
-    /// Constructs a new tunnel parameters generator.
+    /// Constructs a new tunnel parameters generator. If `constraints_override` is set, it is
+    /// layered on top of the relay constraints in the settings whenever a relay is selected. The
+    /// last selected relays are cached in `cache_dir`.
     pub fn new(
         account_manager: AccountManagerHandle,
         relay_selector: RelaySelector,
         tunnel_options: TunnelOptions,
+        constraints_override: Option<RelayConstraintsUpdate>,
+        cache_dir: &Path,
     ) -> Self {
-        Self(Arc::new(Mutex::new(InnerParametersGenerator {
-            tunnel_options,
+        Self::builder(account_manager, relay_selector)
+            .tunnel_options(tunnel_options)
+            .constraints_override(constraints_override)
+            .cache_dir(cache_dir)
+            .build()
+    }
+
+    /// Returns a builder for a tunnel parameters generator that generates tunnel parameters for the
+    /// device of `device_provider`. Options that are not set use their default values.
+    pub fn builder(
+        device_provider: impl DeviceProvider + 'static,
+        relay_selector: RelaySelector,
+    ) -> ParametersGeneratorBuilder {
+        ParametersGeneratorBuilder {
+            device_provider: Arc::new(device_provider),
             relay_selector,
+            tunnel_options: TunnelOptions::default(),
+            constraints_override: None,
+            cache_dir: None,
+            endpoint_rewrite: None,
+            relay_provider: None,
+            protocol_preference: None,
+            reuse_obfuscator: false,
+            sticky_relay: None,
+            latency_aware: false,
+            selection_events: None,
+        }
+    }
+
+    /// Aborts any custom relay hostname resolution that is currently in progress. The pending
+    /// call to `generate` returns [`Error::Cancelled`].
+    pub fn cancel_pending(&self) {
+        self.cancel.notify_waiters();
+    }
+
+    /// Generates tunnel parameters for `retry_attempt` with `overrides` applied in place of the
+    /// corresponding settings. The overrides only apply to the returned parameters. The tunnel
+    /// state machine generates tunnel parameters through this without any overrides.
+    pub async fn generate_with_overrides(
+        &self,
+        retry_attempt: u32,
+        overrides: &GenerateOverrides,
+    ) -> Result<TunnelParameters, Error> {
+        let network_report = std::mem::take(&mut *self.network_report.lock());
+        let mut inner = self.inner.lock().await;
+        inner
+            .apply_network_report(network_report, retry_attempt)
+            .await;
+        inner.generate(retry_attempt, overrides, &self.cancel).await
+    }
+
+    /// Adds a device of the account that tunnel parameters can be generated for using
+    /// `generate_for_device`, in addition to the active device. A previously added device with the
+    /// same ID is replaced.
+    #[allow(dead_code)] // Not yet exposed to the frontends
+    pub async fn add_device(&self, data: PrivateAccountAndDevice) {
+        self.inner.lock().await.devices.insert(data);
+    }
+
+    /// Removes a device added with `add_device`. Returns whether the device had been added.
+    #[allow(dead_code)] // Not yet exposed to the frontends
+    pub async fn remove_device(&self, device_id: &DeviceId) -> bool {
+        self.inner.lock().await.devices.remove(device_id)
+    }
+
+    /// Generates tunnel parameters using the keys of the device `device_id`, which must have been
+    /// added with `add_device`, instead of the active device.
+    #[allow(dead_code)] // Not yet exposed to the frontends
+    pub async fn generate_for_device(
+        &self,
+        device_id: &DeviceId,
+        retry_attempt: u32,
+    ) -> Result<TunnelParameters, Error> {
+        let overrides = GenerateOverrides {
+            device_id: Some(device_id.clone()),
+            ..GenerateOverrides::default()
+        };
+        self.generate_with_overrides(retry_attempt, &overrides)
+            .await
+    }
 
-            account_manager,
+    /// Records the path MTU discovered by the tunnel layer for the last generated WireGuard
+    /// tunnel. It is used instead of the configured MTU until a different exit relay is selected.
+    pub async fn report_path_mtu(&self, mtu: u16) {
+        let mut inner = self.inner.lock().await;
+        let hostname = match &inner.last_generated_relays {
+            Some(LastSelectedRelays::WireGuard { wg_exit, .. }) => wg_exit.hostname.clone(),
+            _ => {
+                log::debug!("Ignoring path MTU since the last tunnel was not a WireGuard tunnel");
+                return;
+            }
+        };
+        log::debug!("Using discovered path MTU {} for {}", mtu, hostname);
+        inner.path_mtu.report(hostname, mtu);
+        inner.last_parameters.clear();
+    }
 
-            last_generated_relays: None,
-        })))
+    /// Discards cached tunnel parameters after the account or device changed, so that the next
+    /// tunnel is set up with the new credentials. Returns whether the last generated tunnel
+    /// parameters authenticate with a different account token than `account_token`, in which case
+    /// an existing OpenVPN tunnel has to be reconnected.
+    pub async fn update_credentials(&self, account_token: &str) -> bool {
+        let mut inner = self.inner.lock().await;
+        inner.last_parameters.clear();
+        inner
+            .last_generated_parameters
+            .as_ref()
+            .map(|parameters| has_stale_credentials(parameters, account_token))
+            .unwrap_or(false)
     }
 
     /// Sets the tunnel options to use when generating new tunnel parameters.
     pub async fn set_tunnel_options(&self, tunnel_options: &TunnelOptions) {
-        self.0.lock().await.tunnel_options = tunnel_options.clone();
+        self.set_tunnel_options_checked(tunnel_options).await;
     }
 
-    /// Gets the location associated with the last generated tunnel parameters.
-    pub async fn get_last_location(&self) -> Option<GeoIpLocation> {
-        let inner = self.0.lock().await;
+    /// Sets the tunnel options to use when generating new tunnel parameters, and returns whether
+    /// any relay in the current relay list matches the relay constraints combined with the new
+    /// options. The options are applied even if no relay matches them, so that the caller can warn
+    /// about it.
+    pub async fn set_tunnel_options_checked(&self, tunnel_options: &TunnelOptions) -> bool {
+        let mut inner = self.inner.lock().await;
+        inner.tunnel_options = tunnel_options.clone();
+        inner.last_parameters.clear();
+        inner
+            .relay_selector
+            .has_matching_relay(inner.constraints_override())
+    }
 
-        let relays = inner.last_generated_relays.as_ref()?;
+    /// Checks whether tunnel parameters can be generated for the current settings, without
+    /// selecting any relays.
+    pub async fn can_generate(&self) -> Result<(), UnsatisfiableReason> {
+        let inner = self.inner.lock().await;
+        inner
+            .device()
+            .await
+            .map_err(|_| UnsatisfiableReason::NotLoggedIn)?;
+        inner
+            .relay_selector
+            .check_constraints()
+            .map_err(|error| match error {
+                mullvad_relay_selector::Error::NoBridge { .. } => {
+                    UnsatisfiableReason::NoBridgeForConstraints
+                }
+                _ => UnsatisfiableReason::NoRelayForConstraints,
+            })
+    }
 
-        let hostname;
-        let bridge_hostname;
-        let entry_hostname;
-        let obfuscator_hostname;
-        let location;
-        let take_hostname =
-            |relay: &Option<Relay>| relay.as_ref().map(|relay| relay.hostname.clone());
+    /// Waits until the relay selector has a relay list to select relays from. Returns
+    /// immediately if one is already loaded, and [`Error::RelayListUnavailable`] if none is
+    /// loaded within [`RELAY_LIST_READY_TIMEOUT`].
+    pub async fn ensure_relays_ready(&self) -> Result<(), Error> {
+        let relay_selector = self.inner.lock().await.relay_selector.clone();
+        wait_for_relays(&relay_selector, RELAY_LIST_READY_TIMEOUT).await
+    }
 
-        match relays {
-            LastSelectedRelays::WireGuard {
-                wg_entry: entry,
-                wg_exit: exit,
-                obfuscator,
-            } => {
-                entry_hostname = take_hostname(entry);
-                hostname = exit.hostname.clone();
-                obfuscator_hostname = take_hostname(obfuscator);
-                bridge_hostname = None;
-                location = exit.location.as_ref().cloned().unwrap();
-            }
-            #[cfg(not(target_os = "android"))]
-            LastSelectedRelays::OpenVpn { relay, bridge } => {
-                hostname = relay.hostname.clone();
-                bridge_hostname = take_hostname(bridge);
-                entry_hostname = None;
-                obfuscator_hostname = None;
-                location = relay.location.as_ref().cloned().unwrap();
-            }
-        };
+    /// Makes the next call to `generate` start over from the first retry attempt when deciding
+    /// whether to use bridges or obfuscation. Should be called when the relay constraints change,
+    /// so that the new constraints are not first tried with the fallbacks of earlier attempts.
+    pub async fn reset_escalation(&self) {
+        self.inner.lock().await.escalation.reset();
+    }
 
-        Some(GeoIpLocation {
-            ipv4: None,
-            ipv6: None,
-            country: location.country,
-            city: Some(location.city),
+    /// Sets the approximate location of the client, which is used to prefer nearby relays when
+    /// no location is specified in the relay constraints, and to find the nearest location.
+    pub async fn set_client_location(&self, location: Option<&GeoIpLocation>) {
+        let coordinates = location.map(|location| Coordinates {
             latitude: location.latitude,
             longitude: location.longitude,
-            mullvad_exit_ip: true,
-            hostname: Some(hostname),
-            bridge_hostname,
-            entry_hostname,
-            obfuscator_hostname,
-        })
+        });
+        self.inner
+            .lock()
+            .await
+            .relay_selector
+            .set_client_location(coordinates);
     }
-}
 
-impl InnerParametersGenerator {
-    async fn generate(&mut self, retry_attempt: u32) -> Result<TunnelParameters, Error> {
-        let _data = self.device().await?;
-        match self.relay_selector.get_relay(retry_attempt) {
-            Ok((SelectedRelay::Custom(custom_relay), _bridge, _obfsucator)) => {
-                self.last_generated_relays = None;
-                custom_relay
-                    // TODO: generate proxy settings for custom tunnels
-                    .to_tunnel_parameters(self.tunnel_options.clone(), None)
-                    .map_err(|e| {
-                        log::error!("Failed to resolve hostname for custom tunnel config: {}", e);
-                        Error::ResolveCustomHostname
-                    })
-            }
-            Ok((SelectedRelay::Normal(constraints), bridge, obfuscator)) => {
-                self.create_tunnel_parameters(
-                    &constraints.exit_relay,
-                    &constraints.entry_relay,
-                    constraints.endpoint,
-                    bridge,
-                    obfuscator,
-                )
-                .await
+    /// Marks the exit relay `hostname` as working, since a connection through it succeeded. It
+    /// is no longer avoided if it had previously failed, but is recorded as recently used.
+    pub async fn report_success(&self, hostname: &str) {
+        let mut inner = self.inner.lock().await;
+        let inner = &mut *inner;
+        inner.recently_failed.report_success(hostname);
+        inner.relay_stats.record_success(hostname);
+        if let (Some(sticky_relay), Some(last_relays)) =
+            (&mut inner.sticky_relay, &inner.last_generated_relays)
+        {
+            if last_relays.exit().hostname == hostname {
+                sticky_relay.report_success(last_relays.exit());
             }
-            Err(mullvad_relay_selector::Error::NoBridge) => Err(Error::NoBridgeAvailable),
-            Err(_error) => Err(Error::NoRelayAvailable),
+        }
+        if inner.recently_used.is_avoiding() {
+            // Otherwise the next connection would reuse the parameters for this relay
+            inner.last_parameters.clear();
+        }
+        if inner.recently_used.record(hostname) {
+            inner.save_recent_relays().await;
         }
     }
 
-    #[cfg_attr(target_os = "android", allow(unused_variables))]
-    async fn create_tunnel_parameters(
-        &mut self,
-        relay: &Relay,
-        entry_relay: &Option<Relay>,
-        endpoint: MullvadEndpoint,
-        bridge: Option<SelectedBridge>,
-        obfuscator: Option<SelectedObfuscator>,
-    ) -> Result<TunnelParameters, Error> {
-        let data = self.device().await?;
-        match endpoint {
-            #[cfg(not(target_os = "android"))]
-            MullvadEndpoint::OpenVpn(endpoint) => {
-                let (bridge_settings, bridge_relay) = match bridge {
-                    Some(SelectedBridge::Normal(bridge)) => {
-                        (Some(bridge.settings), Some(bridge.relay))
-                    }
-                    Some(SelectedBridge::Custom(settings)) => (Some(settings), None),
-                    None => (None, None),
-                };
+    /// Prevents the relays with the given hostnames from being selected until the daemon
+    /// restarts, in addition to the excluded relays in the settings.
+    pub async fn set_session_excluded_relays(&self, hostnames: Vec<String>) {
+        self.inner.lock().await.session_excluded_relays = hostnames;
+    }
 
-                self.last_generated_relays = Some(LastSelectedRelays::OpenVpn {
-                    relay: relay.clone(),
-                    bridge: bridge_relay,
-                });
+    /// Returns the hostnames set with `set_session_excluded_relays`.
+    pub async fn session_excluded_relays(&self) -> Vec<String> {
+        self.inner.lock().await.session_excluded_relays.clone()
+    }
 
-                Ok(openvpn::TunnelParameters {
-                    config: openvpn::ConnectionConfig::new(
-                        endpoint,
-                        data.account_token,
-                        "-".to_string(),
-                    ),
-                    options: self.tunnel_options.openvpn.clone(),
-                    generic_options: self.tunnel_options.generic.clone(),
-                    proxy: bridge_settings,
-                    #[cfg(target_os = "linux")]
-                    fwmark: mullvad_types::TUNNEL_FWMARK,
-                }
-                .into())
-            }
-            #[cfg(target_os = "android")]
-            MullvadEndpoint::OpenVpn(endpoint) => {
-                unreachable!("OpenVPN is not supported on Android");
-            }
-            MullvadEndpoint::Wireguard(endpoint) => {
-                let tunnel = wireguard::TunnelConfig {
-                    private_key: data.device.wg_data.private_key,
-                    addresses: vec![
-                        data.device.wg_data.addresses.ipv4_address.ip().into(),
-                        data.device.wg_data.addresses.ipv6_address.ip().into(),
-                    ],
-                };
+    /// Sets how many of the exit relays of the most recent successful connections are avoided
+    /// when selecting relays. Zero disables this, but the relays are still remembered.
+    pub async fn set_avoid_recent_relays(&self, count: u32) {
+        let mut inner = self.inner.lock().await;
+        inner.recently_used.set_avoided(count as usize);
+        inner.last_parameters.clear();
+    }
 
-                let (obfuscator_relay, obfuscator_config) = match obfuscator {
-                    Some(obfuscator) => (Some(obfuscator.relay), Some(obfuscator.config)),
-                    None => (None, None),
-                };
+    /// Sets whether the obfuscator relay of the last generated tunnel parameters is reused when a
+    /// new connection is made. See [`ParametersGeneratorBuilder::reuse_obfuscator`].
+    pub async fn set_reuse_obfuscator(&self, reuse_obfuscator: bool) {
+        let mut inner = self.inner.lock().await;
+        inner.reuse_obfuscator = reuse_obfuscator;
+        inner.last_parameters.clear();
+    }
 
-                self.last_generated_relays = Some(LastSelectedRelays::WireGuard {
-                    wg_entry: entry_relay.clone(),
-                    wg_exit: relay.clone(),
-                    obfuscator: obfuscator_relay,
-                });
+    /// Sets whether the latency to relays is measured while disconnected. See
+    /// [`ParametersGeneratorBuilder::latency_aware`]. Latencies that have already been measured
+    /// are forgotten when this is disabled.
+    pub async fn set_latency_aware(&self, latency_aware: bool) {
+        let mut inner = self.inner.lock().await;
+        inner.latency_aware = latency_aware;
+        if !latency_aware {
+            inner.latencies_measured_at = None;
+            inner.relay_selector.set_relay_latencies(HashMap::new());
+        }
+    }
 
-                Ok(wireguard::TunnelParameters {
-                    connection: wireguard::ConnectionConfig {
-                        tunnel,
-                        peer: endpoint.peer,
-                        exit_peer: endpoint.exit_peer,
-                        ipv4_gateway: endpoint.ipv4_gateway,
-                        ipv6_gateway: Some(endpoint.ipv6_gateway),
-                        #[cfg(target_os = "linux")]
-                        fwmark: Some(mullvad_types::TUNNEL_FWMARK),
-                    },
-                    options: self.tunnel_options.wireguard.options.clone(),
-                    generic_options: self.tunnel_options.generic.clone(),
-                    obfuscation: obfuscator_config,
-                }
-                .into())
-            }
+    /// Sets after how many failures in a row the exit relay of the last successful connection is
+    /// no longer reused. See [`ParametersGeneratorBuilder::sticky_relay`]. The reused relay is
+    /// kept if this is changed to a non-zero value.
+    pub async fn set_sticky_relay(&self, max_failures: u32) {
+        let mut inner = self.inner.lock().await;
+        if max_failures == 0 {
+            inner.sticky_relay = None;
+        } else if let Some(sticky_relay) = &mut inner.sticky_relay {
+            sticky_relay.max_failures = max_failures;
+        } else {
+            inner.sticky_relay = Some(StickyRelay::new(max_failures));
         }
+        inner.last_parameters.clear();
     }
 
-    async fn device(&self) -> Result<PrivateAccountAndDevice, Error> {
-        self.account_manager
-            .data()
-            .await
-            .map(|s| s.into_device())
-            .ok()
-            .flatten()
-            .ok_or(Error::NoAuthDetails)
+    /// Sets how the tunnel protocol is chosen when the relay constraints allow any tunnel
+    /// protocol. See [`ParametersGeneratorBuilder::protocol_preference`].
+    pub async fn set_protocol_preference(&self, protocol_preference: Option<ProtocolPreference>) {
+        let mut inner = self.inner.lock().await;
+        inner.protocol_preference = protocol_preference;
+        inner.last_parameters.clear();
     }
-}
 
-impl TunnelParametersGenerator for ParametersGenerator {
-    fn generate(
-        &mut self,
-        retry_attempt: u32,
-    ) -> Pin<Box<dyn Future<Output = Result<TunnelParameters, ParameterGenerationError>>>> {
-        let generator = self.0.clone();
-        Box::pin(async move {
-            let mut inner = generator.lock().await;
-            inner
-                .generate(retry_attempt)
-                .await
-                .map_err(|error| match error {
-                    Error::NoBridgeAvailable => ParameterGenerationError::NoMatchingBridgeRelay,
-                    Error::ResolveCustomHostname => {
-                        ParameterGenerationError::CustomTunnelHostResultionError
-                    }
-                    error => {
-                        log::error!(
-                            "{}",
-                            error.display_chain_with_msg("Failed to generate tunnel parameters")
-                        );
-                        ParameterGenerationError::NoMatchingRelay
-                    }
-                })
+    /// Returns connection statistics for recently used exit relays, by hostname.
+    pub async fn relay_stats(&self) -> HashMap<String, RelayStat> {
+        self.inner.lock().await.relay_stats.stats.clone()
+    }
+
+    /// Returns a summary of the last call to `generate`, or `None` if no tunnel parameters have
+    /// been generated yet.
+    pub async fn selection_snapshot(&self) -> Option<SelectionSnapshot> {
+        self.inner.lock().await.last_selection.clone()
+    }
+
+    /// Returns every hop of the path that traffic takes through the last generated tunnel
+    /// parameters, or `None` if no tunnel parameters have been generated yet.
+    pub async fn last_relay_chain(&self) -> Option<RelayChain> {
+        let inner = self.inner.lock().await;
+        let relays = inner
+            .last_generated_relays
+            .as_ref()
+            .map(LastSelectedRelays::relay_set);
+        Some(RelayChain::new(
+            inner.last_generated_parameters.as_ref()?,
+            relays,
+        ))
+    }
+
+    /// Renders the last generated WireGuard tunnel parameters as a `wg-quick` config, so that the
+    /// tunnel can be set up elsewhere, e.g. on a router. Returns `None` if no tunnel parameters
+    /// have been generated, or if they are for OpenVPN or a multihop tunnel, which `wg-quick`
+    /// cannot express. Obfuscation is not included.
+    ///
+    /// The config contains the private key of the device. It must be treated as a secret, and
+    /// should only be handed to the user on request.
+    pub async fn export_last_wireguard_config(&self) -> Option<String> {
+        let inner = self.inner.lock().await;
+        match inner.last_generated_parameters.as_ref()? {
+            TunnelParameters::Wireguard(parameters) => wg_quick_config(parameters),
+            TunnelParameters::OpenVpn(_) => None,
+        }
+    }
+
+    /// Returns a human-readable explanation of why the relays of the last call to `generate`
+    /// were selected, or `None` if no tunnel parameters have been generated yet.
+    pub async fn explain_last_selection(&self) -> Option<String> {
+        let inner = self.inner.lock().await;
+        inner
+            .last_selection
+            .as_ref()
+            .map(SelectionSnapshot::explain)
+    }
+
+    /// Attempts to select relays for every country and city in the relay list, using the current
+    /// constraints and tunnel options except for the location, and reports the locations that no
+    /// relays can be selected for. Nothing is modified, so the next call to `generate` is not
+    /// affected. The report is empty if a custom relay is used.
+    pub async fn self_test(&self) -> SelfTestReport {
+        let inner = self.inner.lock().await;
+        if inner
+            .relay_selector
+            .effective_constraints(inner.constraints_override.clone())
+            .is_none()
+        {
+            return SelfTestReport::default();
+        }
+        let locations = inner.relay_selector.clone().get_locations();
+        self_test(
+            &*inner.relay_provider,
+            &locations,
+            inner.constraints_override(),
+            &inner.tunnel_options,
+        )
+    }
+
+    /// Selects relays for the first `count` attempts of a new connection under the current
+    /// constraints, without connecting or affecting the next call to `generate`. Relays are
+    /// selected at random, so this only shows which relays will likely be used. The preview ends
+    /// at the first attempt that no relays can be selected for, and is empty if a custom relay is
+    /// used.
+    pub async fn preview_relays(&self, count: u32) -> Vec<RelayPreview> {
+        let inner = self.inner.lock().await;
+        let protocol = inner.preferred_protocol();
+        preview_relays(count, |attempt| {
+            inner.select_relay(attempt, attempt, None, protocol)
         })
     }
+
+    /// Measures the latency to the relays that match the relay constraints, so that relays with a
+    /// lower latency are preferred when selecting relays. Does nothing unless latency-aware relay
+    /// selection is enabled, or if the latency was measured less than
+    /// [`RELAY_LATENCY_INTERVAL`] ago.
+    pub async fn refresh_relay_latencies(&self) {
+        let (relay_selector, candidates) = {
+            let mut inner = self.inner.lock().await;
+            if !inner.latency_aware
+                || inner
+                    .latencies_measured_at
+                    .map(|measured_at| measured_at.elapsed() < RELAY_LATENCY_INTERVAL)
+                    .unwrap_or(false)
+            {
+                return;
+            }
+            inner.latencies_measured_at = Some(Instant::now());
+            let candidates = inner
+                .relay_selector
+                .candidate_relays(inner.constraints_override());
+            (inner.relay_selector.clone(), candidates)
+        };
+        // The lock is not held while probing, so that tunnel parameters can be generated meanwhile
+        let latencies = relay_latency::measure_relay_latencies(candidates).await;
+        log::debug!("Measured the latency to {} relays", latencies.len());
+        relay_selector.set_relay_latencies(latencies);
+    }
+
+    /// Benchmarks the relays in `location` that tunnels may use given the other relay
+    /// constraints. The lock is not held while benchmarking.
+    pub async fn benchmark_relays(
+        &self,
+        location: Constraint<LocationConstraint>,
+    ) -> Vec<relay_latency::RelayBenchmark> {
+        let candidates = {
+            let inner = self.inner.lock().await;
+            let mut constraints_override = inner.constraints_override().unwrap_or_default();
+            constraints_override.location = Some(location);
+            inner
+                .relay_selector
+                .candidate_relays(Some(constraints_override))
+        };
+        relay_latency::benchmark_relays(candidates).await
+    }
+
+    /// Returns the name of the interface of the last tunnel that came up, if it is still in use.
+    pub fn tunnel_interface(&self) -> Option<String> {
+        self.tunnel_interface.lock().name.clone()
+    }
+
+    /// Returns the gateways of the last tunnel that came up, if it is still in use.
+    pub fn tunnel_gateways(&self) -> Vec<IpAddr> {
+        self.tunnel_interface.lock().gateways.clone()
+    }
+
+    /// Returns whether the last location was restored from the cache, i.e. no tunnel parameters
+    /// have been generated since the daemon started.
+    #[allow(dead_code)] // Not yet exposed to the frontends
+    pub async fn is_last_location_cached(&self) -> bool {
+        self.inner.lock().await.last_relays_cached
+    }
+
+    /// Returns the relays selected for the last generated tunnel parameters.
+    #[allow(dead_code)] // Not yet exposed to the frontends
+    pub async fn last_relays(&self) -> Option<SelectedRelaySet> {
+        let inner = self.inner.lock().await;
+        inner
+            .last_generated_relays
+            .as_ref()
+            .map(LastSelectedRelays::relay_set)
+    }
+
+    /// Gets the location associated with the last generated tunnel parameters.
+    pub async fn get_last_location(&self) -> Option<GeoIpLocation> {
+        let inner = self.inner.lock().await;
+        let mut location = inner.last_generated_relays.as_ref()?.location()?;
+
+        // Before the tunnel is up, only the addresses of WireGuard tunnels are known
+        let mut addresses = self.tunnel_interface.lock().addresses.clone();
+        if addresses.is_empty() {
+            if let Some(TunnelParameters::Wireguard(parameters)) = &inner.last_generated_parameters
+            {
+                addresses = parameters.connection.tunnel.addresses.clone();
+            }
+        }
+        location.ipv4 = addresses.iter().find_map(|address| match address {
+            IpAddr::V4(address) => Some(*address),
+            IpAddr::V6(_) => None,
+        });
+        location.ipv6 = addresses.iter().find_map(|address| match address {
+            IpAddr::V6(address) => Some(*address),
+            IpAddr::V4(_) => None,
+        });
+        Some(location)
+    }
 }
 
-/// Contains all relays that were selected last time when tunnel parameters were generated.
-enum LastSelectedRelays {
-    /// Represents all relays generated for a WireGuard tunnel.
+/// Builder for [`ParametersGenerator`].
+pub(crate) struct ParametersGeneratorBuilder {
+    device_provider: Arc<dyn DeviceProvider>,
+    relay_selector: RelaySelector,
+    tunnel_options: TunnelOptions,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    cache_dir: Option<PathBuf>,
+    endpoint_rewrite: Option<EndpointRewrite>,
+    relay_provider: Option<Box<dyn RelayProvider>>,
+    protocol_preference: Option<ProtocolPreference>,
+    reuse_obfuscator: bool,
+    sticky_relay: Option<u32>,
+    latency_aware: bool,
+    selection_events: Option<SelectionEventSender>,
+}
+
+impl ParametersGeneratorBuilder {
+    /// Sets the tunnel options to use when generating tunnel parameters.
+    pub fn tunnel_options(mut self, tunnel_options: TunnelOptions) -> Self {
+        self.tunnel_options = tunnel_options;
+        self
+    }
+
+    /// Sets relay constraints that are layered on top of the relay constraints in the settings.
+    pub fn constraints_override(
+        mut self,
+        constraints_override: Option<RelayConstraintsUpdate>,
+    ) -> Self {
+        self.constraints_override = constraints_override;
+        self
+    }
+
+    /// Sets the directory that the last selected relays are cached in. The cached relays are
+    /// restored when the generator is built.
+    pub fn cache_dir(mut self, cache_dir: &Path) -> Self {
+        self.cache_dir = Some(cache_dir.to_owned());
+        self
+    }
+
+    /// Sets a function that rewrites the peer endpoint of generated WireGuard tunnel parameters,
+    /// e.g. to connect through a local forwarder. The selected relays are still reported as the
+    /// last selected relays.
+    pub fn endpoint_rewrite(
+        mut self,
+        endpoint_rewrite: impl Fn(SocketAddr) -> SocketAddr + Send + Sync + 'static,
+    ) -> Self {
+        self.endpoint_rewrite = Some(Arc::new(endpoint_rewrite));
+        self
+    }
+
+    /// Sets the relay provider that relays are selected with, in place of the relay selector.
+    /// The relay selector is still used for everything other than selecting relays.
+    #[cfg(test)]
+    pub fn relay_provider(mut self, relay_provider: impl RelayProvider + 'static) -> Self {
+        self.relay_provider = Some(Box::new(relay_provider));
+        self
+    }
+
+    /// Sets how the tunnel protocol is chosen when the relay constraints allow any tunnel
+    /// protocol. If `None`, the relay selector decides based on the retry attempt.
+    pub fn protocol_preference(mut self, protocol_preference: Option<ProtocolPreference>) -> Self {
+        self.protocol_preference = protocol_preference;
+        self
+    }
+
+    /// Sets whether the obfuscator relay of the last generated tunnel parameters is reused when a
+    /// new connection is made, as long as it is still online and satisfies the constraints. This
+    /// avoids the latency of switching obfuscators between reconnects. Off by default.
+    pub fn reuse_obfuscator(mut self, reuse_obfuscator: bool) -> Self {
+        self.reuse_obfuscator = reuse_obfuscator;
+        self
+    }
+
+    /// Sets whether the exit relay of the last successful connection is reused for new
+    /// connections and retries, as long as it is still online and satisfies the constraints. This
+    /// keeps long-lived sessions alive when reconnecting, e.g. after roaming between networks. A
+    /// new relay is selected once the reused one has failed `max_failures` times in a row. Zero
+    /// disables this, which is the default.
+    pub fn sticky_relay(mut self, max_failures: u32) -> Self {
+        self.sticky_relay = Some(max_failures).filter(|&max_failures| max_failures > 0);
+        self
+    }
+
+    /// Sets whether the latency to relays is measured while disconnected, so that relays with a
+    /// lower latency are preferred among those that match the constraints. Off by default.
+    pub fn latency_aware(mut self, latency_aware: bool) -> Self {
+        self.latency_aware = latency_aware;
+        self
+    }
+
+    /// Sets where to send the selected relays, or the reason why none could be selected, every
+    /// time tunnel parameters are generated.
+    pub fn selection_events(
+        mut self,
+        selection_events: impl Sender<RelaySelectionEvent> + Send + 'static,
+    ) -> Self {
+        self.selection_events = Some(Box::new(selection_events));
+        self
+    }
+
+    /// Builds the tunnel parameters generator, restoring the relays cached in the cache directory,
+    /// if one is set.
+    pub fn build(self) -> ParametersGenerator {
+        let cache_path = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| dir.join(LAST_RELAYS_FILE));
+        let last_generated_relays = cache_path.as_deref().and_then(load_last_relays);
+        let recent_relays_path = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| dir.join(RECENT_RELAYS_FILE));
+        let recently_used = recent_relays_path
+            .as_deref()
+            .map(RecentlyUsedRelays::load)
+            .unwrap_or_default();
+        let obfuscated_networks_path = self.cache_dir.map(|dir| dir.join(OBFUSCATED_NETWORKS_FILE));
+        let obfuscated_networks = obfuscated_networks_path
+            .as_deref()
+            .map(ObfuscatedNetworks::load)
+            .unwrap_or_default();
+        let relay_provider = match self.relay_provider {
+            Some(relay_provider) => relay_provider,
+            None => Box::new(self.relay_selector.clone()),
+        };
+        ParametersGenerator {
+            inner: Arc::new(Mutex::new(InnerParametersGenerator {
+                tunnel_options: self.tunnel_options,
+                relay_selector: self.relay_selector,
+                relay_provider,
+
+                device_provider: self.device_provider,
+                rotating_key: Arc::default(),
+                constraints_override: self.constraints_override,
+                path_mtu: DiscoveredMtu::default(),
+                recently_failed: RecentlyFailedRelays::default(),
+                recently_used,
+                relay_stats: RelayStats::default(),
+                escalation: Escalation::default(),
+                endpoint_rewrite: self.endpoint_rewrite,
+                protocol_preference: self.protocol_preference,
+                wireguard_failed: false,
+                devices: Devices::default(),
+                reuse_obfuscator: self.reuse_obfuscator,
+                sticky_relay: self.sticky_relay.map(StickyRelay::new),
+                latency_aware: self.latency_aware,
+                latencies_measured_at: None,
+
+                last_relays_cached: last_generated_relays.is_some(),
+                last_generated_relays,
+                cache_path,
+                recent_relays_path,
+                obfuscated_networks,
+                obfuscated_networks_path,
+                session_excluded_relays: Vec::new(),
+                last_selection: None,
+                selection_events: self.selection_events,
+                last_parameters: CachedParameters::default(),
+                last_generated_parameters: None,
+            })),
+            cancel: Arc::new(Notify::new()),
+            tunnel_interface: Arc::default(),
+            network_report: Arc::default(),
+        }
+    }
+}
+
+impl InnerParametersGenerator {
+    /// Generates tunnel parameters for `retry_attempt`. The outcome is sent to the selection event
+    /// sender, if one is set.
+    async fn generate(
+        &mut self,
+        retry_attempt: u32,
+        overrides: &GenerateOverrides,
+        cancel: &Notify,
+    ) -> Result<TunnelParameters, Error> {
+        let result = self
+            .generate_parameters(retry_attempt, overrides, cancel)
+            .await;
+        if result.is_ok() {
+            self.last_relays_cached = false;
+        }
+        if let Some(selection_events) = &self.selection_events {
+            let result = match &result {
+                Ok(_) => match &self.last_selection {
+                    Some(snapshot) => Ok(snapshot.selected_relays()),
+                    None => return result,
+                },
+                Err(error) => Err(error.display_chain()),
+            };
+            let event = RelaySelectionEvent {
+                retry_attempt,
+                result,
+            };
+            if selection_events.send(event).is_err() {
+                log::trace!("Dropping relay selection event since the receiver is gone");
+            }
+        }
+        result
+    }
+
+    async fn generate_parameters(
+        &mut self,
+        retry_attempt: u32,
+        overrides: &GenerateOverrides,
+        cancel: &Notify,
+    ) -> Result<TunnelParameters, Error> {
+        let udp2tcp_override = overrides.udp2tcp.clone();
+        let device_id = overrides.device_id.as_ref();
+        let data = match device_id {
+            Some(device_id) => self.devices.get(device_id)?,
+            None => self.device().await?,
+        };
+        if let Err(error) = check_key_expiry(&data.device.wg_data, Utc::now()) {
+            if device_id.is_some() {
+                return Err(error);
+            }
+            // Retries must not start another rotation while one is in progress
+            if self.rotating_key.swap(true, Ordering::SeqCst) {
+                log::debug!("Waiting for the expired WireGuard key to be rotated");
+                return Err(error);
+            }
+            log::warn!("The WireGuard key has expired. Rotating it before retrying");
+            let device_provider = self.device_provider.clone();
+            let rotating_key = self.rotating_key.clone();
+            tokio::spawn(async move {
+                if let Err(error) = device_provider.rotate_key().await {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg("Failed to rotate expired WireGuard key")
+                    );
+                }
+                rotating_key.store(false, Ordering::SeqCst);
+            });
+            return Err(error);
+        }
+        let allowed_relays = data.device.wg_data.allowed_relays.clone();
+        let _restrictions = RelayRestrictions::apply(
+            &self.relay_selector,
+            allowed_relays.clone(),
+            self.session_excluded_relays.iter().cloned().collect(),
+        );
+        let inputs = GenerationInputs {
+            retry_attempt,
+            selector_config: self.relay_selector.config(),
+            relays_last_updated: self.relay_selector.relays_last_updated(),
+            account_token: data.account_token.clone(),
+            device_key: data.device.wg_data.private_key.public_key(),
+            allowed_relays,
+            excluded_hostnames: self.session_excluded_relays.clone(),
+            overrides: overrides.clone(),
+        };
+        if let Some(parameters) = self.last_parameters.get(&inputs) {
+            log::debug!(
+                "Reusing tunnel parameters for retry attempt {}",
+                retry_attempt
+            );
+            return Ok(parameters);
+        }
+
+        if retry_attempt > 0 {
+            if let Some(last_relays) = &self.last_generated_relays {
+                let hostname = &last_relays.exit().hostname;
+                self.recently_failed.report_failure(hostname.clone());
+                self.relay_stats.record_failure(hostname);
+                if let Some(sticky_relay) = &mut self.sticky_relay {
+                    sticky_relay.report_failure(hostname);
+                }
+                if last_relays.tunnel_type() == TunnelType::Wireguard {
+                    self.wireguard_failed = true;
+                }
+            }
+        }
+
+        let mut escalation_attempt = self.escalation.attempt(retry_attempt);
+        if self.obfuscated_networks.is_current_remembered() {
+            if let Some(first_obfuscated) = self.relay_selector.first_auto_obfuscation_attempt() {
+                log::debug!(
+                    "Escalating to obfuscation immediately since WireGuard timed out on this \
+                    network without it"
+                );
+                escalation_attempt = escalation_attempt.saturating_add(first_obfuscated);
+            }
+        }
+        let protocol = match overrides.pluggable_transport {
+            // Pluggable transports only carry WireGuard traffic
+            Some(_) => Some(TunnelType::Wireguard),
+            None => self.preferred_protocol(),
+        };
+        // Attempts to reuse relays count as picks too, so that falling back to a new relay when a
+        // reused one is offline does not exceed the limit
+        let mut picks_left = self.tunnel_options.max_repicks;
+        let reused = self
+            .reusable_obfuscator(retry_attempt)
+            .filter(|_| overrides.pluggable_transport.is_none())
+            .filter(|_| take_pick(&mut picks_left))
+            .and_then(|obfuscator| {
+                reuse_obfuscator(
+                    &*self.relay_provider,
+                    self.relay_selector
+                        .effective_constraints(self.constraints_override()),
+                    self.constraints_override(),
+                    &obfuscator,
+                    (retry_attempt, escalation_attempt),
+                    udp2tcp_override.clone(),
+                )
+            });
+        let reused = reused.or_else(|| {
+            let relay = self.sticky_relay.as_ref()?.relay.as_ref()?;
+            if overrides.pluggable_transport.is_some() || !take_pick(&mut picks_left) {
+                return None;
+            }
+            reuse_exit_relay(
+                &*self.relay_provider,
+                self.relay_selector
+                    .effective_constraints(self.constraints_override()),
+                self.constraints_override(),
+                relay,
+                (retry_attempt, escalation_attempt),
+                udp2tcp_override.clone(),
+            )
+        });
+        let selected = match reused {
+            Some(selected) => selected,
+            None => self.pick_relay(
+                retry_attempt,
+                escalation_attempt,
+                udp2tcp_override.clone(),
+                protocol,
+                picks_left,
+            )?,
+        };
+
+        let is_custom_relay = matches!(selected.0, SelectedRelay::Custom(_));
+        let parameters = match selected {
+            (SelectedRelay::Custom(custom_relay), bridge, _obfsucator) => {
+                self.last_generated_relays = None;
+                self.save_last_relays().await;
+                let tunnel_options = self.tunnel_options.clone();
+                let proxy = match &custom_relay.config {
+                    custom_tunnel::ConnectionConfig::OpenVpn(_) => {
+                        bridge.map(|bridge| match bridge {
+                            SelectedBridge::Normal(bridge) => bridge.settings,
+                            SelectedBridge::Custom(settings) => settings,
+                        })
+                    }
+                    custom_tunnel::ConnectionConfig::Wireguard(_) => {
+                        warn_ignored_bridge(bridge.as_ref());
+                        None
+                    }
+                };
+                resolve_custom_relay(
+                    move || custom_relay.to_tunnel_parameters(tunnel_options, proxy),
+                    cancel,
+                )
+                .await?
+            }
+            (SelectedRelay::Normal(constraints), bridge, obfuscator) => {
+                let obfuscator = match (&overrides.pluggable_transport, obfuscator) {
+                    (Some(transport), _) => Some(pluggable_transport_obfuscator(
+                        transport,
+                        constraints
+                            .entry_relay
+                            .as_ref()
+                            .unwrap_or(&constraints.exit_relay),
+                    )?),
+                    (None, Some(obfuscator)) => self
+                        .relay_selector
+                        .check_obfuscator_online(obfuscator)
+                        .map_err(|_| Error::NoObfuscatorAvailable)?,
+                    (None, None) => None,
+                };
+                let mut parameters = self
+                    .create_tunnel_parameters(
+                        data,
+                        &constraints.exit_relay,
+                        &constraints.entry_relay,
+                        constraints.endpoint,
+                        bridge,
+                        obfuscator,
+                        overrides,
+                    )
+                    .await?;
+                if let Some(custom_relay) = self.custom_multihop_relay() {
+                    add_custom_hop(&mut parameters, &custom_relay, cancel).await?;
+                    if matches!(custom_relay.hop, CustomHop::Exit { .. }) {
+                        // Traffic does not leave the tunnel at the selected relay
+                        self.last_generated_relays = None;
+                    }
+                }
+                self.relay_stats
+                    .record_selected(&constraints.exit_relay.hostname);
+                self.save_last_relays().await;
+                parameters
+            }
+        };
+
+        let custom_entry = matches!(
+            self.custom_multihop_relay(),
+            Some(CustomMultihopRelay {
+                hop: CustomHop::Entry,
+                ..
+            })
+        );
+        let restrictions = if is_custom_relay || custom_entry || self.endpoint_rewrite.is_some() {
+            None
+        } else {
+            Some(EndpointRestrictions {
+                bridge_state: self.relay_selector.config().bridge_state,
+                ip_version: self
+                    .relay_selector
+                    .effective_constraints(self.constraints_override())
+                    .map(|constraints| constraints.wireguard_constraints.ip_version)
+                    .unwrap_or(Constraint::Any),
+            })
+        };
+        check_protocol_options(&parameters, &self.tunnel_options, restrictions.as_ref())?;
+        self.last_selection = Some(SelectionSnapshot::new(
+            &parameters,
+            self.last_generated_relays
+                .as_ref()
+                .map(LastSelectedRelays::relay_set),
+            self.relay_selector
+                .effective_constraints(self.constraints_override()),
+            (retry_attempt, escalation_attempt),
+            self.relay_selector.has_client_location(),
+        ));
+        self.last_parameters.store(inputs, parameters.clone());
+        self.last_generated_parameters = Some(parameters.clone());
+        Ok(parameters)
+    }
+
+    /// Selects relays at most `max_picks` times, avoiding exit relays that recently failed. Exit
+    /// relays of the most recent connections are also avoided if enabled, unless every other
+    /// candidate recently failed. Fails with [`Error::ExhaustedCandidates`] if every candidate
+    /// recently failed.
+    fn pick_relay(
+        &self,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+        udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+        protocol: Option<TunnelType>,
+        max_picks: u32,
+    ) -> Result<SelectedRelays, Error> {
+        let select = || {
+            self.select_relay(
+                retry_attempt,
+                escalation_attempt,
+                udp2tcp_override.clone(),
+                protocol,
+            )
+        };
+        let is_recently_failed = |(relay, ..): &SelectedRelays| match relay {
+            SelectedRelay::Normal(relay)
+                if self.recently_failed.contains(&relay.exit_relay.hostname) =>
+            {
+                log::debug!(
+                    "Selecting a new relay since {} recently failed",
+                    relay.exit_relay.hostname
+                );
+                true
+            }
+            _ => false,
+        };
+        let is_recently_used = |(relay, ..): &SelectedRelays| match relay {
+            SelectedRelay::Normal(relay)
+                if self.recently_used.avoids(&relay.exit_relay.hostname) =>
+            {
+                log::debug!(
+                    "Selecting a new relay since {} was recently used",
+                    relay.exit_relay.hostname
+                );
+                true
+            }
+            _ => false,
+        };
+
+        let selected = pick_unvetoed(max_picks, select, is_recently_failed, is_recently_used);
+        if let Err(Error::ExhaustedCandidates) = &selected {
+            log::debug!("All {} candidate relays recently failed", max_picks);
+        }
+        selected
+    }
+
+    /// Returns the obfuscator relay of the last generated tunnel parameters if it should be
+    /// reused, i.e. if reusing obfuscators is enabled and this is the first attempt of a new
+    /// connection.
+    fn reusable_obfuscator(&self, retry_attempt: u32) -> Option<Relay> {
+        if !self.reuse_obfuscator || retry_attempt > 0 {
+            return None;
+        }
+        match &self.last_generated_relays {
+            Some(LastSelectedRelays::WireGuard { obfuscator, .. }) => obfuscator.clone(),
+            _ => None,
+        }
+    }
+
+    /// Selects relays, restricted to `protocol` if set. If no relay uses `protocol`, relays of
+    /// any tunnel protocol are selected instead.
+    fn select_relay(
+        &self,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+        udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+        protocol: Option<TunnelType>,
+    ) -> Result<SelectedRelays, Error> {
+        let constraints_override = self.constraints_override();
+        if let Some(protocol) = protocol {
+            let constraints_override = RelayConstraintsUpdate {
+                tunnel_protocol: Some(Constraint::Only(protocol)),
+                ..constraints_override.clone().unwrap_or_default()
+            };
+            match select_relay(
+                &*self.relay_provider,
+                retry_attempt,
+                escalation_attempt,
+                Some(constraints_override),
+                udp2tcp_override.clone(),
+            ) {
+                Err(Error::NoRelayAvailable) => {
+                    log::debug!(
+                        "No relay matches the preferred tunnel protocol {}",
+                        protocol
+                    );
+                }
+                result => return result,
+            }
+        }
+        select_relay(
+            &*self.relay_provider,
+            retry_attempt,
+            escalation_attempt,
+            constraints_override,
+            udp2tcp_override,
+        )
+        .map_err(|error| match error {
+            Error::NoRelayAvailable if !self.tunnel_options.protocol_ports.is_any() => {
+                Error::NoRelayOnProtocolPorts
+            }
+            error => error,
+        })
+    }
+
+    /// Returns the relay constraints override, extended with the protocol ports in the tunnel
+    /// options.
+    fn constraints_override(&self) -> Option<RelayConstraintsUpdate> {
+        apply_protocol_ports(
+            self.relay_selector
+                .effective_constraints(self.constraints_override.clone()),
+            self.constraints_override.clone(),
+            &self.tunnel_options.protocol_ports,
+        )
+    }
+
+    /// Returns the custom server to combine with the selected relay in a multihop tunnel, if any.
+    fn custom_multihop_relay(&self) -> Option<CustomMultihopRelay> {
+        self.relay_selector
+            .effective_constraints(self.constraints_override())?
+            .wireguard_constraints
+            .custom_multihop_relay()
+            .cloned()
+    }
+
+    /// Chooses a tunnel protocol according to the protocol preference, or returns `None` if there
+    /// is no preference or the relay constraints specify a tunnel protocol.
+    fn preferred_protocol(&self) -> Option<TunnelType> {
+        let preference = self.protocol_preference?;
+        let constraints = self
+            .relay_selector
+            .effective_constraints(self.constraints_override.clone())?;
+        if !constraints.tunnel_protocol.is_any() {
+            return None;
+        }
+        let protocol = choose_protocol(preference, self.wireguard_failed, &mut rand::thread_rng());
+        log::debug!(
+            "Preferring {} since any tunnel protocol is allowed",
+            protocol
+        );
+        Some(protocol)
+    }
+
+    #[cfg_attr(target_os = "android", allow(unused_variables))]
+    async fn create_tunnel_parameters(
+        &mut self,
+        data: PrivateAccountAndDevice,
+        relay: &Relay,
+        entry_relay: &Option<Relay>,
+        endpoint: MullvadEndpoint,
+        bridge: Option<SelectedBridge>,
+        obfuscator: Option<SelectedObfuscator>,
+        overrides: &GenerateOverrides,
+    ) -> Result<TunnelParameters, Error> {
+        validate_split_dns(&self.tunnel_options.generic.split_dns)?;
+        match endpoint {
+            #[cfg(not(target_os = "android"))]
+            MullvadEndpoint::OpenVpn(endpoint) => {
+                let (bridge_settings, bridge_relay) = match bridge {
+                    Some(SelectedBridge::Normal(bridge)) => {
+                        (Some(bridge.settings), Some(bridge.relay))
+                    }
+                    Some(SelectedBridge::Custom(settings)) => (Some(settings), None),
+                    None => (None, None),
+                };
+
+                let mut options = self.tunnel_options.openvpn.clone();
+                if let Some(cipher) = &overrides.openvpn_cipher {
+                    options.cipher = Some(cipher.clone());
+                }
+                validate_openvpn_cipher(&options)?;
+
+                // With multihop, the proxy runs on the entry relay rather than on a bridge
+                let bridge_relay = bridge_relay.filter(|_| entry_relay.is_none());
+                self.last_generated_relays = Some(LastSelectedRelays::OpenVpn {
+                    relay: relay.clone(),
+                    entry: entry_relay.clone(),
+                    bridge: bridge_relay,
+                });
+
+                Ok(openvpn::TunnelParameters {
+                    config: openvpn::ConnectionConfig::new(
+                        endpoint,
+                        data.account_token,
+                        "-".to_string(),
+                    ),
+                    options,
+                    generic_options: self.tunnel_options.generic.clone(),
+                    proxy: bridge_settings,
+                    #[cfg(target_os = "linux")]
+                    fwmark: overrides.fwmark.unwrap_or(mullvad_types::TUNNEL_FWMARK),
+                }
+                .into())
+            }
+            #[cfg(target_os = "android")]
+            MullvadEndpoint::OpenVpn(endpoint) => {
+                unreachable!("OpenVPN is not supported on Android");
+            }
+            MullvadEndpoint::Wireguard(endpoint) => {
+                let ignored_bridge = warn_ignored_bridge(bridge.as_ref());
+                debug_assert!(
+                    !ignored_bridge,
+                    "The relay selector returned a bridge for a WireGuard tunnel"
+                );
+
+                let tunnel = tunnel_config(&data.device.wg_data);
+
+                if let Some(entry) = entry_relay {
+                    if !RelaySelector::is_multihop_entry(entry) {
+                        log::error!(
+                            "{} cannot be used as a multihop entry relay",
+                            entry.hostname
+                        );
+                        return Err(Error::NoMultihopEntry);
+                    }
+                }
+
+                let mut endpoint = endpoint;
+                let entry = entry_relay.as_ref().unwrap_or(relay);
+                let (obfuscator_relay, obfuscator_config) = match obfuscator {
+                    Some(obfuscator) => {
+                        validate_entry_obfuscation(entry, &endpoint, &obfuscator)?;
+                        (Some(obfuscator.relay), Some(obfuscator.config))
+                    }
+                    None => {
+                        // The IP version constraint takes precedence over the preference
+                        let ip_version = self
+                            .relay_selector
+                            .effective_constraints(self.constraints_override())
+                            .map(|constraints| constraints.wireguard_constraints.ip_version)
+                            .unwrap_or(Constraint::Any);
+                        apply_ip_version_preference(
+                            &mut endpoint.peer,
+                            entry,
+                            ip_version.or(self.tunnel_options.wireguard.preferred_ip_version),
+                        );
+                        (None, None)
+                    }
+                };
+
+                self.last_generated_relays = Some(LastSelectedRelays::WireGuard {
+                    wg_entry: entry_relay.clone(),
+                    wg_exit: relay.clone(),
+                    obfuscator: obfuscator_relay,
+                    obfuscation: obfuscator_config.clone(),
+                });
+                rewrite_peer_endpoint(&mut endpoint.peer, self.endpoint_rewrite.as_ref());
+
+                let mut options = self.tunnel_options.wireguard.to_talpid_tunnel_options();
+                options.mtu = self.path_mtu.mtu_for(&relay.hostname, options.mtu);
+                // There is nothing left to detect if the MTU is already known
+                options.mtu_detection &= options.mtu.is_none();
+
+                let mut connection = wireguard::ConnectionConfig {
+                    tunnel,
+                    peer: endpoint.peer,
+                    exit_peer: endpoint.exit_peer,
+                    ipv4_gateway: endpoint.ipv4_gateway,
+                    ipv6_gateway: Some(endpoint.ipv6_gateway),
+                    #[cfg(target_os = "linux")]
+                    fwmark: Some(overrides.fwmark.unwrap_or(mullvad_types::TUNNEL_FWMARK)),
+                };
+                apply_wireguard_implementation(
+                    &mut connection,
+                    self.tunnel_options.wireguard.implementation,
+                );
+
+                Ok(wireguard::TunnelParameters {
+                    connection,
+                    options,
+                    generic_options: self.tunnel_options.generic.clone(),
+                    obfuscation: obfuscator_config,
+                }
+                .into())
+            }
+        }
+    }
+
+    async fn save_last_relays(&self) {
+        if let Some(path) = &self.cache_path {
+            save_json(path, &self.last_generated_relays, "last selected relays").await;
+        }
+    }
+
+    /// Applies the network changes and timeouts reported by the tunnel state machine before
+    /// tunnel parameters are generated for `retry_attempt`.
+    async fn apply_network_report(&mut self, report: NetworkReport, retry_attempt: u32) {
+        let unobfuscated_wireguard = matches!(
+            self.last_generated_relays,
+            Some(LastSelectedRelays::WireGuard {
+                obfuscator: None,
+                ..
+            })
+        );
+        if report.timed_out && unobfuscated_wireguard && self.obfuscated_networks.report_timeout() {
+            log::info!(
+                "WireGuard repeatedly timed out on this network. Using obfuscation from the first \
+                attempt on it if obfuscation is automatic"
+            );
+            self.save_obfuscated_networks().await;
+        }
+        if retry_attempt == 0 {
+            self.obfuscated_networks.reset_timeouts();
+        }
+        self.obfuscated_networks.set_current(report.network);
+        self.relay_selector
+            .set_ipv6_available(report.ipv6_available);
+    }
+
+    async fn save_obfuscated_networks(&self) {
+        if let Some(path) = &self.obfuscated_networks_path {
+            let fingerprints = &self.obfuscated_networks.fingerprints;
+            save_json(path, fingerprints, "obfuscated networks").await;
+        }
+    }
+
+    async fn save_recent_relays(&self) {
+        if let Some(path) = &self.recent_relays_path {
+            save_json(path, &self.recently_used.hostnames, "recently used relays").await;
+        }
+    }
+
+    async fn device(&self) -> Result<PrivateAccountAndDevice, Error> {
+        self.device_provider
+            .device()
+            .await
+            .ok_or(Error::NoAuthDetails)
+    }
+}
+
+fn select_relay(
+    relay_provider: &dyn RelayProvider,
+    retry_attempt: u32,
+    escalation_attempt: u32,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+) -> Result<SelectedRelays, Error> {
+    relay_provider
+        .get_relay(
+            retry_attempt,
+            escalation_attempt,
+            constraints_override,
+            udp2tcp_override,
+        )
+        .map_err(|error| match error {
+            mullvad_relay_selector::Error::NoBridge { forced } => {
+                Error::NoBridgeAvailable { forced }
+            }
+            mullvad_relay_selector::Error::NoObfuscator => Error::NoObfuscatorAvailable,
+            mullvad_relay_selector::Error::RelaysInMaintenance => Error::RelaysInMaintenance,
+            mullvad_relay_selector::Error::NoMultihopEntry => Error::NoMultihopEntry,
+            mullvad_relay_selector::Error::NoAllowedRelay => Error::NoAllowedRelay,
+            mullvad_relay_selector::Error::AllRelaysExcluded => Error::AllRelaysExcluded,
+            mullvad_relay_selector::Error::NoQuantumResistantRelay => {
+                Error::NoQuantumResistantRelay
+            }
+            mullvad_relay_selector::Error::NoDaitaRelay => Error::NoDaitaRelay,
+            _ => Error::NoRelayAvailable,
+        })
+}
+
+/// Adds the port of each tunnel protocol in `ports` to `constraints_override`. The relay selector
+/// only applies the port constraints of the tunnel protocol that it selects, so both ports can be
+/// set at once. `constraints` are the effective relay constraints, or `None` if a custom relay is
+/// used, in which case `constraints_override` is returned unchanged.
+fn apply_protocol_ports(
+    constraints: Option<RelayConstraints>,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    ports: &ProtocolPorts,
+) -> Option<RelayConstraintsUpdate> {
+    let constraints = match constraints {
+        Some(constraints) if !ports.is_any() => constraints,
+        _ => return constraints_override,
+    };
+    let mut constraints_override = constraints_override.unwrap_or_default();
+    if let Constraint::Only(port) = ports.wireguard {
+        constraints_override.wireguard_constraints = Some(WireguardConstraints {
+            port: Constraint::Only(PortRanges::from(port)),
+            ..constraints.wireguard_constraints
+        });
+    }
+    if let Constraint::Only(port) = ports.openvpn {
+        constraints_override.openvpn_constraints = Some(OpenVpnConstraints {
+            port: Constraint::Only(port),
+            ..constraints.openvpn_constraints
+        });
+    }
+    Some(constraints_override)
+}
+
+/// Selects relays whose obfuscator is `obfuscator`, provided that it is still online and satisfies
+/// `constraints`. Since obfuscated traffic is sent to the relay that WireGuard traffic enters
+/// through, this narrows the entry relay when multihop is used and the exit relay otherwise.
+/// Returns `None` if `obfuscator` cannot be reused, in which case relays should be selected as
+/// usual.
+fn reuse_obfuscator(
+    relay_provider: &dyn RelayProvider,
+    constraints: Option<RelayConstraints>,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    obfuscator: &Relay,
+    (retry_attempt, escalation_attempt): (u32, u32),
+    udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+) -> Option<SelectedRelays> {
+    let constraints = constraints?;
+    let location = obfuscator.location.as_ref()?;
+    let hostname = Constraint::Only(LocationConstraint::Hostname(
+        location.country_code.clone(),
+        location.city_code.clone(),
+        obfuscator.hostname.clone(),
+    ));
+    let wireguard_constraints = &constraints.wireguard_constraints;
+    let hop_location = if wireguard_constraints.is_relay_multihop() {
+        &wireguard_constraints.entry_location
+    } else {
+        &constraints.location
+    };
+    if !hop_location.matches_with_opts(obfuscator, true)
+        || !constraints.providers.matches(obfuscator)
+        || !constraints.ownership.matches(obfuscator)
+    {
+        return None;
+    }
+
+    let mut update = RelayConstraintsUpdate {
+        tunnel_protocol: Some(Constraint::Only(TunnelType::Wireguard)),
+        ..constraints_override.unwrap_or_default()
+    };
+    if wireguard_constraints.is_relay_multihop() {
+        update.wireguard_constraints = Some(WireguardConstraints {
+            entry_location: hostname,
+            ..wireguard_constraints.clone()
+        });
+    } else {
+        update.location = Some(hostname);
+    }
+    let selected = select_relay(
+        relay_provider,
+        retry_attempt,
+        escalation_attempt,
+        Some(update),
+        udp2tcp_override,
+    );
+    let reused = matches!(
+        &selected,
+        Ok((_, _, Some(selected_obfuscator)))
+            if selected_obfuscator.relay.hostname == obfuscator.hostname
+    );
+    if !reused {
+        log::debug!("Obfuscator {} cannot be reused", obfuscator.hostname);
+        return None;
+    }
+    log::debug!("Reusing obfuscator {}", obfuscator.hostname);
+    selected.ok()
+}
+
+/// Selects relays with `relay` as the exit relay, if it is still online and satisfies the
+/// constraints. Returns `None` if another exit relay would have to be selected.
+fn reuse_exit_relay(
+    relay_provider: &dyn RelayProvider,
+    constraints: Option<RelayConstraints>,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    relay: &Relay,
+    (retry_attempt, escalation_attempt): (u32, u32),
+    udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+) -> Option<SelectedRelays> {
+    let constraints = constraints?;
+    let location = relay.location.as_ref()?;
+    if !constraints.location.matches_with_opts(relay, true)
+        || !constraints.providers.matches(relay)
+        || !constraints.ownership.matches(relay)
+    {
+        return None;
+    }
+
+    let update = RelayConstraintsUpdate {
+        location: Some(Constraint::Only(LocationConstraint::Hostname(
+            location.country_code.clone(),
+            location.city_code.clone(),
+            relay.hostname.clone(),
+        ))),
+        ..constraints_override.unwrap_or_default()
+    };
+    let selected = select_relay(
+        relay_provider,
+        retry_attempt,
+        escalation_attempt,
+        Some(update),
+        udp2tcp_override,
+    );
+    let reused = matches!(
+        &selected,
+        Ok((SelectedRelay::Normal(selected), ..))
+            if selected.exit_relay.hostname == relay.hostname
+    );
+    if !reused {
+        log::debug!("Exit relay {} cannot be reused", relay.hostname);
+        return None;
+    }
+    log::debug!("Reusing exit relay {}", relay.hostname);
+    selected.ok()
+}
+
+/// Selects relays for every country and city in `locations` in turn, with the location of
+/// `constraints_override` replaced by that location.
+fn self_test(
+    relay_provider: &dyn RelayProvider,
+    locations: &RelayList,
+    constraints_override: Option<RelayConstraintsUpdate>,
+    tunnel_options: &TunnelOptions,
+) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    for country in &locations.countries {
+        let cities = country
+            .cities
+            .iter()
+            .map(|city| LocationConstraint::City(country.code.clone(), city.code.clone()));
+        for location in iter::once(LocationConstraint::Country(country.code.clone())).chain(cities)
+        {
+            let constraints_override = RelayConstraintsUpdate {
+                location: Some(Constraint::Only(location.clone())),
+                ..constraints_override.clone().unwrap_or_default()
+            };
+            let result = select_relay(relay_provider, 0, 0, Some(constraints_override), None)
+                .and_then(|(relay, _bridge, obfuscator)| match relay {
+                    SelectedRelay::Normal(relay)
+                        if matches!(relay.endpoint, MullvadEndpoint::Wireguard(_))
+                            && obfuscator.is_none()
+                            && tunnel_options.wireguard.require_obfuscation =>
+                    {
+                        Err(Error::ObfuscationRequired)
+                    }
+                    _ => Ok(()),
+                });
+            match result {
+                Ok(()) => report.passed += 1,
+                Err(error) => report.failures.push(SelfTestFailure {
+                    location,
+                    reason: error.to_string(),
+                }),
+            }
+        }
+    }
+    report
+}
+
+/// Returns the relays selected by `select` for each attempt up to `count`, stopping at the first
+/// attempt that fails or selects a custom relay.
+fn preview_relays(
+    count: u32,
+    select: impl Fn(u32) -> Result<SelectedRelays, Error>,
+) -> Vec<RelayPreview> {
+    let mut previews = vec![];
+    for attempt in 0..count {
+        match select(attempt) {
+            Ok((SelectedRelay::Normal(relay), bridge, obfuscator)) => previews.push(RelayPreview {
+                tunnel_type: match relay.endpoint {
+                    MullvadEndpoint::OpenVpn(_) => TunnelType::OpenVpn,
+                    MullvadEndpoint::Wireguard(_) => TunnelType::Wireguard,
+                },
+                exit: relay.exit_relay.hostname,
+                entry: relay.entry_relay.map(|relay| relay.hostname),
+                bridge: match bridge {
+                    Some(SelectedBridge::Normal(bridge)) => Some(bridge.relay.hostname),
+                    Some(SelectedBridge::Custom(_)) | None => None,
+                },
+                obfuscator: obfuscator.map(|obfuscator| obfuscator.relay.hostname),
+            }),
+            Ok((SelectedRelay::Custom(_), ..)) => break,
+            Err(error) => {
+                log::debug!(
+                    "{}",
+                    error.display_chain_with_msg(&format!(
+                        "No relays to preview for attempt {}",
+                        attempt
+                    ))
+                );
+                break;
+            }
+        }
+    }
+    previews
+}
+
+/// Allowed and excluded relays that apply to a single call to `generate`. The relay selector is
+/// shared with the rest of the daemon, so the previous restrictions are restored when this is
+/// dropped, even if generating the tunnel parameters was cancelled.
+struct RelayRestrictions {
+    relay_selector: RelaySelector,
+    allowed_relays: Option<HashSet<String>>,
+    excluded_relays: HashSet<String>,
+}
+
+impl RelayRestrictions {
+    fn apply(
+        relay_selector: &RelaySelector,
+        allowed_relays: Option<HashSet<String>>,
+        excluded_relays: HashSet<String>,
+    ) -> Self {
+        let restrictions = Self {
+            relay_selector: relay_selector.clone(),
+            allowed_relays: relay_selector.allowed_relays(),
+            excluded_relays: relay_selector.excluded_relays(),
+        };
+        relay_selector.set_allowed_relays(allowed_relays);
+        relay_selector.set_excluded_relays(excluded_relays);
+        restrictions
+    }
+}
+
+impl Drop for RelayRestrictions {
+    fn drop(&mut self) {
+        self.relay_selector
+            .set_allowed_relays(self.allowed_relays.take());
+        self.relay_selector
+            .set_excluded_relays(std::mem::take(&mut self.excluded_relays));
+    }
+}
+
+/// Devices of the account other than the active one, by device ID.
+#[derive(Default)]
+struct Devices(HashMap<DeviceId, PrivateAccountAndDevice>);
+
+impl Devices {
+    fn insert(&mut self, data: PrivateAccountAndDevice) {
+        self.0.insert(data.device.id.clone(), data);
+    }
+
+    fn remove(&mut self, device_id: &DeviceId) -> bool {
+        self.0.remove(device_id).is_some()
+    }
+
+    fn get(&self, device_id: &DeviceId) -> Result<PrivateAccountAndDevice, Error> {
+        self.0
+            .get(device_id)
+            .cloned()
+            .ok_or_else(|| Error::UnknownDevice(device_id.clone()))
+    }
+}
+
+/// Returns the WireGuard tunnel configuration for the keys and addresses in `wg_data`.
+fn tunnel_config(wg_data: &WireguardData) -> wireguard::TunnelConfig {
+    wireguard::TunnelConfig {
+        private_key: wg_data.private_key.clone(),
+        addresses: vec![
+            wg_data.addresses.ipv4_address.ip().into(),
+            wg_data.addresses.ipv6_address.ip().into(),
+        ],
+    }
+}
+
+/// Fails with [`Error::KeyExpired`] if the WireGuard key is past its expiry at `now`. Keys without
+/// an expiry are assumed to be valid.
+fn check_key_expiry(wg_data: &WireguardData, now: DateTime<Utc>) -> Result<(), Error> {
+    if wg_data.is_expired(now) {
+        return Err(Error::KeyExpired);
+    }
+    Ok(())
+}
+
+/// Chooses a tunnel protocol according to `preference`. `wireguard_failed` is whether a WireGuard
+/// tunnel has failed since the daemon started.
+fn choose_protocol(
+    preference: ProtocolPreference,
+    wireguard_failed: bool,
+    rng: &mut impl Rng,
+) -> TunnelType {
+    let (wireguard, openvpn) = match preference {
+        ProtocolPreference::Weighted { wireguard, openvpn } => (wireguard, openvpn),
+        ProtocolPreference::WireguardUnlessFailed if wireguard_failed => (0, 1),
+        ProtocolPreference::WireguardUnlessFailed => (1, 0),
+    };
+    let openvpn = if cfg!(target_os = "android") {
+        0
+    } else {
+        openvpn
+    };
+    let total = u64::from(wireguard) + u64::from(openvpn);
+    if total == 0 || rng.gen_range(0..total) < u64::from(wireguard) {
+        TunnelType::Wireguard
+    } else {
+        TunnelType::OpenVpn
+    }
+}
+
+/// Renders `parameters` in the INI format read by `wg-quick`. Returns `None` for multihop
+/// tunnels.
+fn wg_quick_config(parameters: &wireguard::TunnelParameters) -> Option<String> {
+    let connection = &parameters.connection;
+    if connection.exit_peer.is_some() {
+        return None;
+    }
+    let addresses = connection
+        .tunnel
+        .addresses
+        .iter()
+        .map(|address| match address {
+            IpAddr::V4(address) => format!("{}/32", address),
+            IpAddr::V6(address) => format!("{}/128", address),
+        })
+        .collect::<Vec<_>>();
+    let dns_servers = iter::once(IpAddr::from(connection.ipv4_gateway))
+        .chain(connection.ipv6_gateway.map(IpAddr::from))
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>();
+    let allowed_ips = connection
+        .peer
+        .allowed_ips
+        .iter()
+        .map(|network| network.to_string())
+        .collect::<Vec<_>>();
+
+    let mut config = String::from("[Interface]\n");
+    config += &format!(
+        "PrivateKey = {}\n",
+        connection.tunnel.private_key.to_base64()
+    );
+    config += &format!("Address = {}\n", addresses.join(", "));
+    config += &format!("DNS = {}\n", dns_servers.join(", "));
+    if let Some(mtu) = parameters.options.mtu {
+        config += &format!("MTU = {}\n", mtu);
+    }
+    config += "\n[Peer]\n";
+    config += &format!("PublicKey = {}\n", connection.peer.public_key.to_base64());
+    config += &format!("AllowedIPs = {}\n", allowed_ips.join(", "));
+    config += &format!("Endpoint = {}\n", connection.peer.endpoint);
+    config += &format!("PersistentKeepalive = {}\n", EXPORT_PERSISTENT_KEEPALIVE);
+    Some(config)
+}
+
+/// Returns whether `parameters` authenticate with an account token other than `account_token`.
+/// WireGuard tunnels are authenticated by the device key and never use stale credentials.
+fn has_stale_credentials(parameters: &TunnelParameters, account_token: &str) -> bool {
+    match parameters {
+        TunnelParameters::OpenVpn(parameters) => parameters.config.username != account_token,
+        TunnelParameters::Wireguard(_) => false,
+    }
+}
+
+/// Calls `pick` at most `max_picks` times until it returns a candidate that is neither vetoed nor
+/// discouraged. If there is none, the first candidate that was only discouraged is returned.
+/// Returns [`Error::ExhaustedCandidates`] if every candidate is vetoed.
+fn pick_unvetoed<T>(
+    max_picks: u32,
+    mut pick: impl FnMut() -> Result<T, Error>,
+    mut vetoed: impl FnMut(&T) -> bool,
+    mut discouraged: impl FnMut(&T) -> bool,
+) -> Result<T, Error> {
+    let mut fallback = None;
+    for _ in 0..max_picks {
+        let candidate = pick()?;
+        if vetoed(&candidate) {
+            continue;
+        }
+        if !discouraged(&candidate) {
+            return Ok(candidate);
+        }
+        fallback.get_or_insert(candidate);
+    }
+    fallback.ok_or(Error::ExhaustedCandidates)
+}
+
+/// Takes one of the `picks_left`. Returns `false` if none is left.
+fn take_pick(picks_left: &mut u32) -> bool {
+    match picks_left.checked_sub(1) {
+        Some(left) => {
+            *picks_left = left;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Reads `what` as JSON from `path`. Returns `None` if the file does not exist or cannot be
+/// parsed.
+fn load_json<T: DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(error) => {
+            if error.kind() != std::io::ErrorKind::NotFound {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg(&format!("Failed to read {}", what))
+                );
+            }
+            return None;
+        }
+    };
+    match serde_json::from_str(&data) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg(&format!("Failed to parse {}", what))
+            );
+            None
+        }
+    }
+}
+
+/// Writes `value`, which is described by `what`, as JSON to `path`. Failures are logged.
+async fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T, what: &str) {
+    let result = match serde_json::to_string(value) {
+        Ok(data) => tokio::fs::write(path, data)
+            .await
+            .map_err(|error| error.display_chain_with_msg(&format!("Failed to write {}", what))),
+        Err(error) => Err(error.display_chain_with_msg(&format!("Failed to serialize {}", what))),
+    };
+    if let Err(message) = result {
+        log::error!("{}", message);
+    }
+}
+
+/// Reads the relays selected by the last generated tunnel parameters from `cache_path`.
+fn load_last_relays(cache_path: &Path) -> Option<LastSelectedRelays> {
+    load_json(cache_path, "last selected relays").flatten()
+}
+
+/// Path MTU most recently discovered by the tunnel layer, scoped to the exit relay it was
+/// discovered for.
+#[derive(Default)]
+struct DiscoveredMtu(Option<(String, u16)>);
+
+impl DiscoveredMtu {
+    fn report(&mut self, hostname: String, mtu: u16) {
+        self.0 = Some((hostname, mtu));
+    }
+
+    /// Returns the MTU to use for a tunnel to the exit relay `hostname`. The discovered value is
+    /// forgotten if it belongs to a different relay.
+    fn mtu_for(&mut self, hostname: &str, configured_mtu: Option<u16>) -> Option<u16> {
+        match &self.0 {
+            Some((relay, mtu)) if relay == hostname => Some(*mtu),
+            _ => {
+                self.0 = None;
+                configured_mtu
+            }
+        }
+    }
+}
+
+/// Tracks the retry attempt that escalation to bridges and obfuscation is counted from.
+#[derive(Default)]
+struct Escalation {
+    start: u32,
+    reset_pending: bool,
+}
+
+impl Escalation {
+    fn reset(&mut self) {
+        self.reset_pending = true;
+    }
+
+    /// Returns the attempt to use for escalation purposes given the caller's `retry_attempt`.
+    fn attempt(&mut self, retry_attempt: u32) -> u32 {
+        // A lower retry attempt means that a new connection attempt has started
+        if self.reset_pending || retry_attempt < self.start {
+            self.start = retry_attempt;
+            self.reset_pending = false;
+        }
+        retry_attempt - self.start
+    }
+}
+
+/// Options that only apply to a single call to [`ParametersGenerator::generate_with_overrides`].
+/// Options that are not set are taken from the settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct GenerateOverrides {
+    /// Firewall mark of the tunnel sockets, e.g. to route a tunnel through a different routing
+    /// table.
+    #[cfg(target_os = "linux")]
+    pub fwmark: Option<u32>,
+    /// Device added with `add_device` whose keys are used instead of those of the active device.
+    pub device_id: Option<DeviceId>,
+    /// Used instead of the configured udp2tcp obfuscation settings, e.g. to try a different port.
+    pub udp2tcp: Option<Udp2TcpObfuscationSettings>,
+    /// Used instead of the configured obfuscation. Only WireGuard relays that advertise the
+    /// transport are selected.
+    pub pluggable_transport: Option<PluggableTransport>,
+    /// Used instead of the configured OpenVPN data channel cipher. Must be supported by the
+    /// relays.
+    #[cfg(not(target_os = "android"))]
+    pub openvpn_cipher: Option<String>,
+}
+
+/// Inputs that tunnel parameters were generated from. Tunnel parameters are generated again if any
+/// of them change.
+#[derive(PartialEq)]
+struct GenerationInputs {
+    retry_attempt: u32,
+    selector_config: SelectorConfig,
+    relays_last_updated: SystemTime,
+    account_token: String,
+    device_key: wireguard::PublicKey,
+    allowed_relays: Option<HashSet<String>>,
+    excluded_hostnames: Vec<String>,
+    overrides: GenerateOverrides,
+}
+
+/// The tunnel parameters returned by the last call to `generate`, so that redundant calls with
+/// identical inputs return the same relays without selecting them again.
+#[derive(Default)]
+struct CachedParameters(Option<(GenerationInputs, TunnelParameters)>);
+
+impl CachedParameters {
+    fn get(&self, inputs: &GenerationInputs) -> Option<TunnelParameters> {
+        match &self.0 {
+            Some((cached_inputs, parameters)) if cached_inputs == inputs => {
+                Some(parameters.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn store(&mut self, inputs: GenerationInputs, parameters: TunnelParameters) {
+        self.0 = Some((inputs, parameters));
+    }
+
+    fn clear(&mut self) {
+        self.0 = None;
+    }
+}
+
+/// Hostnames of exit relays that recently failed to connect. These are avoided during relay
+/// selection until a connection through them succeeds.
+#[derive(Default)]
+struct RecentlyFailedRelays(HashSet<String>);
+
+impl RecentlyFailedRelays {
+    fn report_failure(&mut self, hostname: String) {
+        self.0.insert(hostname);
+    }
+
+    fn report_success(&mut self, hostname: &str) {
+        self.0.remove(hostname);
+    }
+
+    fn contains(&self, hostname: &str) -> bool {
+        self.0.contains(hostname)
+    }
+}
+
+/// Hostnames of the exit relays of the [`MAX_RECENT_RELAYS`] most recent successful connections,
+/// of which the `avoided` most recent ones are avoided during relay selection.
+#[derive(Default)]
+struct RecentlyUsedRelays {
+    /// Hostnames ordered from most to least recently used.
+    hostnames: VecDeque<String>,
+    avoided: usize,
+}
+
+impl RecentlyUsedRelays {
+    /// Reads the recently used relays from `path`. Nothing is avoided until [`Self::set_avoided`]
+    /// is called.
+    fn load(path: &Path) -> Self {
+        let hostnames = load_json(path, "recently used relays").unwrap_or_default();
+        RecentlyUsedRelays {
+            hostnames,
+            avoided: 0,
+        }
+    }
+
+    /// Records a successful connection through `hostname`. Returns whether the list changed.
+    fn record(&mut self, hostname: &str) -> bool {
+        if self.hostnames.front().map(String::as_str) == Some(hostname) {
+            return false;
+        }
+        self.hostnames.retain(|used| used != hostname);
+        self.hostnames.push_front(hostname.to_owned());
+        self.hostnames.truncate(MAX_RECENT_RELAYS);
+        true
+    }
+
+    fn set_avoided(&mut self, avoided: usize) {
+        self.avoided = avoided.min(MAX_RECENT_RELAYS);
+    }
+
+    fn is_avoiding(&self) -> bool {
+        self.avoided > 0
+    }
+
+    fn avoids(&self, hostname: &str) -> bool {
+        self.hostnames
+            .iter()
+            .take(self.avoided)
+            .any(|used| used == hostname)
+    }
+}
+
+/// Fingerprints of networks on which WireGuard repeatedly timed out without obfuscation. When
+/// obfuscation is automatic, tunnels over these networks are obfuscated from the first attempt.
+#[derive(Default)]
+struct ObfuscatedNetworks {
+    /// Fingerprints ordered from most to least recently added.
+    fingerprints: VecDeque<String>,
+    /// Fingerprint of the network that tunnels are currently established over, if known.
+    current: Option<String>,
+    /// Consecutive timeouts without obfuscation on the current network.
+    timeouts: u32,
+}
+
+impl ObfuscatedNetworks {
+    fn load(path: &Path) -> Self {
+        let fingerprints = load_json(path, "obfuscated networks").unwrap_or_default();
+        ObfuscatedNetworks {
+            fingerprints,
+            ..Default::default()
+        }
+    }
+
+    fn set_current(&mut self, network: Option<String>) {
+        if self.current != network {
+            self.current = network;
+            self.timeouts = 0;
+        }
+    }
+
+    fn reset_timeouts(&mut self) {
+        self.timeouts = 0;
+    }
+
+    /// Records that WireGuard timed out without obfuscation on the current network. Returns
+    /// whether the network was remembered as a result.
+    fn report_timeout(&mut self) -> bool {
+        let network = match &self.current {
+            Some(network) => network,
+            None => return false,
+        };
+        self.timeouts += 1;
+        if self.timeouts < MAX_UNOBFUSCATED_TIMEOUTS || self.fingerprints.contains(network) {
+            return false;
+        }
+        self.fingerprints.push_front(network.clone());
+        self.fingerprints.truncate(MAX_OBFUSCATED_NETWORKS);
+        true
+    }
+
+    fn is_current_remembered(&self) -> bool {
+        match &self.current {
+            Some(network) => self.fingerprints.contains(network),
+            None => false,
+        }
+    }
+}
+
+/// The exit relay of the last successful connection, which is reused until it has failed
+/// `max_failures` times in a row.
+struct StickyRelay {
+    max_failures: u32,
+    relay: Option<Relay>,
+    failures: u32,
+}
+
+impl StickyRelay {
+    fn new(max_failures: u32) -> Self {
+        StickyRelay {
+            max_failures,
+            relay: None,
+            failures: 0,
+        }
+    }
+
+    fn report_success(&mut self, relay: &Relay) {
+        self.relay = Some(relay.clone());
+        self.failures = 0;
+    }
+
+    fn report_failure(&mut self, hostname: &str) {
+        match &self.relay {
+            Some(relay) if relay.hostname == hostname => (),
+            _ => return,
+        }
+        self.failures += 1;
+        if self.failures >= self.max_failures {
+            log::debug!(
+                "No longer reusing exit relay {} after {} failures",
+                hostname,
+                self.failures
+            );
+            self.relay = None;
+            self.failures = 0;
+        }
+    }
+}
+
+/// Connection statistics for a single exit relay.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelayStat {
+    /// Number of times tunnel parameters were generated for the relay.
+    pub selected: u32,
+    /// Number of times a connection through the relay failed.
+    pub failures: u32,
+    /// Number of times a connection through the relay succeeded.
+    pub successes: u32,
+}
+
+/// Connection statistics for the [`MAX_RELAY_STATS`] most recently used exit relays.
+#[derive(Default)]
+struct RelayStats {
+    stats: HashMap<String, RelayStat>,
+    /// Hostnames ordered from least to most recently used.
+    recently_used: VecDeque<String>,
+}
+
+impl RelayStats {
+    fn record_selected(&mut self, hostname: &str) {
+        self.entry(hostname).selected += 1;
+    }
+
+    fn record_failure(&mut self, hostname: &str) {
+        self.entry(hostname).failures += 1;
+    }
+
+    fn record_success(&mut self, hostname: &str) {
+        self.entry(hostname).successes += 1;
+    }
+
+    fn entry(&mut self, hostname: &str) -> &mut RelayStat {
+        if let Some(index) = self.recently_used.iter().position(|used| used == hostname) {
+            self.recently_used.remove(index);
+        }
+        self.recently_used.push_back(hostname.to_owned());
+        if self.recently_used.len() > MAX_RELAY_STATS {
+            if let Some(evicted) = self.recently_used.pop_front() {
+                self.stats.remove(&evicted);
+            }
+        }
+        self.stats.entry(hostname.to_owned()).or_default()
+    }
+}
+
+/// Reads relay constraints that should override the settings from the environment, if set.
+pub(crate) fn constraints_override_from_env() -> Option<RelayConstraintsUpdate> {
+    let value = std::env::var(CONSTRAINTS_OVERRIDE_VAR).ok()?;
+    match serde_json::from_str(&value) {
+        Ok(constraints_override) => {
+            log::warn!(
+                "Overriding relay constraints using {}",
+                CONSTRAINTS_OVERRIDE_VAR
+            );
+            Some(constraints_override)
+        }
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg(&format!(
+                    "Ignoring invalid relay constraints in {}",
+                    CONSTRAINTS_OVERRIDE_VAR
+                ))
+            );
+            None
+        }
+    }
+}
+
+/// Reads the address that WireGuard peer endpoints should be replaced with from the environment,
+/// if set.
+pub(crate) fn endpoint_override_from_env() -> Option<SocketAddr> {
+    let value = std::env::var(ENDPOINT_OVERRIDE_VAR).ok()?;
+    match value.parse() {
+        Ok(endpoint) => {
+            log::warn!(
+                "Overriding WireGuard endpoints with {} using {}",
+                endpoint,
+                ENDPOINT_OVERRIDE_VAR
+            );
+            Some(endpoint)
+        }
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg(&format!(
+                    "Ignoring invalid endpoint in {}",
+                    ENDPOINT_OVERRIDE_VAR
+                ))
+            );
+            None
+        }
+    }
+}
+
+/// Polls `relay_selector` until it has a relay list, or until `timeout` has elapsed.
+async fn wait_for_relays(relay_selector: &RelaySelector, timeout: Duration) -> Result<(), Error> {
+    let wait = async {
+        while !relay_selector.has_relays() {
+            tokio::time::sleep(RELAY_LIST_POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| Error::RelayListUnavailable)
+}
+
+/// Runs the blocking hostname resolution of a custom relay on a separate thread. Aborts the
+/// resolution and returns [`Error::Cancelled`] if `cancel` is notified first. A resolution that
+/// has already started cannot be interrupted, but its result is discarded.
+async fn resolve_custom_relay<T: Send + 'static>(
+    resolve: impl FnOnce() -> Result<T, custom_tunnel::Error> + Send + 'static,
+    cancel: &Notify,
+) -> Result<T, Error> {
+    let resolve = tokio::task::spawn_blocking(resolve);
+    let cancelled = cancel.notified();
+    futures::pin_mut!(cancelled);
+
+    match futures::future::select(resolve, cancelled).await {
+        Either::Left((Ok(result), _)) => result.map_err(|e| {
+            log::error!("Failed to resolve hostname for custom tunnel config: {}", e);
+            Error::ResolveCustomHostname
+        }),
+        Either::Left((Err(e), _)) => {
+            log::error!("Custom tunnel hostname resolution panicked: {}", e);
+            Err(Error::ResolveCustomHostname)
+        }
+        Either::Right((_, resolve)) => {
+            log::debug!("Custom tunnel hostname resolution was cancelled");
+            resolve.abort();
+            Err(Error::Cancelled)
+        }
+    }
+}
+
+/// Turns the WireGuard tunnel in `parameters` into a multihop tunnel that uses `custom_relay` as
+/// its entry or exit hop. OpenVPN tunnels are left unchanged.
+async fn add_custom_hop(
+    parameters: &mut TunnelParameters,
+    custom_relay: &CustomMultihopRelay,
+    cancel: &Notify,
+) -> Result<(), Error> {
+    let parameters = match parameters {
+        TunnelParameters::Wireguard(parameters) => parameters,
+        _ => return Ok(()),
+    };
+    let host = custom_relay.host.clone();
+    let ip = resolve_custom_relay(move || custom_tunnel::resolve_to_ip(&host), cancel).await?;
+    set_custom_hop(&mut parameters.connection, custom_relay, ip);
+    if custom_relay.hop == CustomHop::Entry && parameters.obfuscation.take().is_some() {
+        log::warn!("Ignoring obfuscation since the entry hop is a custom server");
+    }
+    log::info!("Using {} at {}", custom_relay, ip);
+    Ok(())
+}
+
+/// Adds `custom_relay`, resolved to `ip`, as a hop in front of or behind the relay that
+/// `connection` connects to.
+fn set_custom_hop(
+    connection: &mut wireguard::ConnectionConfig,
+    custom_relay: &CustomMultihopRelay,
+    ip: IpAddr,
+) {
+    let custom_peer = wireguard::PeerConfig {
+        public_key: custom_relay.public_key.clone(),
+        allowed_ips: all_of_the_internet(),
+        endpoint: SocketAddr::new(ip, custom_relay.port),
+        psk: None,
+    };
+    let (mut entry_peer, exit_peer) = match custom_relay.hop {
+        CustomHop::Entry => (custom_peer, connection.peer.clone()),
+        CustomHop::Exit { ipv4_gateway } => {
+            connection.ipv4_gateway = ipv4_gateway;
+            connection.ipv6_gateway = None;
+            (connection.peer.clone(), custom_peer)
+        }
+    };
+    // Only traffic to the exit hop is sent directly to the entry hop
+    entry_peer.allowed_ips = vec![IpNetwork::from(exit_peer.endpoint.ip())];
+    connection.peer = entry_peer;
+    connection.exit_peer = Some(exit_peer);
+}
+
+/// Returns an obfuscator that sends WireGuard traffic to `relay` through `transport`, or
+/// [`Error::UnsupportedPluggableTransport`] if `relay` does not advertise support for it.
+fn pluggable_transport_obfuscator(
+    transport: &PluggableTransport,
+    relay: &Relay,
+) -> Result<SelectedObfuscator, Error> {
+    let supported = match &relay.endpoint_data {
+        RelayEndpointData::Wireguard(data) => data.pluggable_transports.contains(&transport.name),
+        _ => false,
+    };
+    if !supported {
+        return Err(Error::UnsupportedPluggableTransport {
+            transport: transport.name.clone(),
+            relay: relay.hostname.clone(),
+        });
+    }
+    Ok(SelectedObfuscator {
+        config: ObfuscatorConfig::PluggableTransport {
+            name: transport.name.clone(),
+            endpoint: Endpoint {
+                address: SocketAddr::new(relay.ipv4_addr_in.into(), transport.port),
+                protocol: transport.protocol,
+            },
+            parameters: transport.parameters.clone(),
+        },
+        relay: relay.clone(),
+    })
+}
+
+/// Verifies that the relay that traffic first enters through is able to carry the selected
+/// obfuscation, i.e. that it is a WireGuard relay and that the obfuscator targets it.
+fn validate_entry_obfuscation(
+    entry: &Relay,
+    endpoint: &MullvadWireguardEndpoint,
+    obfuscator: &SelectedObfuscator,
+) -> Result<(), Error> {
+    let supports_wireguard = matches!(entry.endpoint_data, RelayEndpointData::Wireguard(_));
+    let targets_entry = match obfuscator.config {
+        ObfuscatorConfig::Udp2Tcp {
+            endpoint: obfs_endpoint,
+        } => {
+            obfuscator.relay.hostname == entry.hostname
+                && obfs_endpoint.ip() == endpoint.peer.endpoint.ip()
+        }
+        ObfuscatorConfig::PluggableTransport {
+            endpoint: obfs_endpoint,
+            ..
+        } => {
+            obfuscator.relay.hostname == entry.hostname
+                && obfs_endpoint.address.ip() == endpoint.peer.endpoint.ip()
+        }
+    };
+    if supports_wireguard && targets_entry {
+        Ok(())
+    } else {
+        log::error!(
+            "Obfuscator on {} does not match entry relay {}",
+            obfuscator.relay.hostname,
+            entry.hostname
+        );
+        Err(Error::ObfuscationMismatch(entry.hostname.clone()))
+    }
+}
+
+/// Settings that the endpoints of tunnel parameters must satisfy when they are connected to
+/// relays from the relay list, as opposed to custom servers or rewritten endpoints.
+struct EndpointRestrictions {
+    bridge_state: BridgeState,
+    /// IP version constraint of WireGuard tunnels.
+    ip_version: Constraint<IpVersion>,
+}
+
+/// Verifies that `parameters` satisfy the tunnel options of the protocol that they were generated
+/// for. Options of other protocols are ignored, so that options left over from before switching
+/// protocols cannot prevent tunnel parameters from being generated. The endpoints are only
+/// checked if `restrictions` is set.
+fn check_protocol_options(
+    parameters: &TunnelParameters,
+    tunnel_options: &TunnelOptions,
+    restrictions: Option<&EndpointRestrictions>,
+) -> Result<(), Error> {
+    match parameters {
+        TunnelParameters::Wireguard(wireguard) => {
+            if tunnel_options.wireguard.require_obfuscation {
+                check_obfuscated(parameters)?;
+            }
+            match restrictions {
+                Some(restrictions) => check_wireguard_endpoint(
+                    wireguard,
+                    tunnel_options.protocol_ports.wireguard,
+                    restrictions.ip_version,
+                ),
+                None => Ok(()),
+            }
+        }
+        TunnelParameters::OpenVpn(openvpn) => match restrictions {
+            Some(restrictions) => check_openvpn_endpoint(
+                &openvpn.config.endpoint,
+                openvpn.proxy.is_some(),
+                tunnel_options.protocol_ports.openvpn,
+                restrictions.bridge_state,
+            ),
+            None => Ok(()),
+        },
+    }
+}
+
+/// Verifies that a WireGuard tunnel uses the protocol port and IP version. The protocol port only
+/// applies to unobfuscated tunnels, since obfuscated traffic is sent to the port of the
+/// obfuscator. The IP version applies to the address that traffic is sent to, which is that of
+/// the obfuscator if there is one.
+fn check_wireguard_endpoint(
+    parameters: &wireguard::TunnelParameters,
+    port: Constraint<u16>,
+    ip_version: Constraint<IpVersion>,
+) -> Result<(), Error> {
+    let peer_endpoint = parameters.connection.peer.endpoint;
+    let address = match &parameters.obfuscation {
+        Some(ObfuscatorConfig::Udp2Tcp { endpoint }) => endpoint.ip(),
+        Some(ObfuscatorConfig::PluggableTransport { endpoint, .. }) => endpoint.address.ip(),
+        None => match port {
+            Constraint::Only(port) if port != peer_endpoint.port() => {
+                return Err(Error::ProtocolOptionsViolated(format!(
+                    "WireGuard would use port {} instead of {}",
+                    peer_endpoint.port(),
+                    port
+                )));
+            }
+            _ => peer_endpoint.ip(),
+        },
+    };
+    let version = match address {
+        IpAddr::V4(_) => IpVersion::V4,
+        IpAddr::V6(_) => IpVersion::V6,
+    };
+    match ip_version {
+        Constraint::Only(ip_version) if ip_version != version => {
+            Err(Error::ProtocolOptionsViolated(format!(
+                "WireGuard would connect over {} instead of {}",
+                version, ip_version
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Verifies that an OpenVPN tunnel uses the protocol port and transport protocol, and that it
+/// uses a bridge if and only if the bridge state requires it. Bridges only support TCP.
+fn check_openvpn_endpoint(
+    endpoint: &Endpoint,
+    bridged: bool,
+    port: Constraint<TransportPort>,
+    bridge_state: BridgeState,
+) -> Result<(), Error> {
+    if let Constraint::Only(port) = port {
+        if endpoint.protocol != port.protocol || !port.port.matches_eq(&endpoint.address.port()) {
+            return Err(Error::ProtocolOptionsViolated(format!(
+                "OpenVPN would use {} port {}, which is not the OpenVPN protocol port",
+                endpoint.protocol,
+                endpoint.address.port()
+            )));
+        }
+    }
+    match bridge_state {
+        BridgeState::On if !bridged => Err(Error::ProtocolOptionsViolated(
+            "OpenVPN would not use a bridge although one is required".to_owned(),
+        )),
+        BridgeState::Off if bridged => Err(Error::ProtocolOptionsViolated(
+            "OpenVPN would use a bridge although bridges are turned off".to_owned(),
+        )),
+        _ if bridged && endpoint.protocol != TransportProtocol::Tcp => {
+            Err(Error::ProtocolOptionsViolated(format!(
+                "OpenVPN would use a bridge over {}",
+                endpoint.protocol
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Verifies that `parameters` describe an obfuscated tunnel.
+fn check_obfuscated(parameters: &TunnelParameters) -> Result<(), Error> {
+    match parameters {
+        TunnelParameters::Wireguard(parameters) if parameters.obfuscation.is_some() => Ok(()),
+        _ => {
+            log::error!("Refusing to connect without obfuscation");
+            Err(Error::ObfuscationRequired)
+        }
+    }
+}
+
+/// Verifies that every split DNS rule has at least one valid domain and at least one usable
+/// resolver.
+fn validate_split_dns(rules: &[SplitDnsRule]) -> Result<(), Error> {
+    for rule in rules {
+        if rule.domains.is_empty() {
+            return Err(Error::InvalidSplitDnsRule("no domains".to_owned()));
+        }
+        if let Some(domain) = rule.domains.iter().find(|domain| !is_valid_domain(domain)) {
+            return Err(Error::InvalidSplitDnsRule(format!(
+                "invalid domain \"{}\"",
+                domain
+            )));
+        }
+        if rule.resolvers.is_empty() {
+            return Err(Error::InvalidSplitDnsRule("no resolvers".to_owned()));
+        }
+        if let Some(resolver) = rule
+            .resolvers
+            .iter()
+            .find(|resolver| resolver.is_unspecified() || resolver.is_multicast())
+        {
+            return Err(Error::InvalidSplitDnsRule(format!(
+                "invalid resolver {}",
+                resolver
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    !domain.is_empty()
+        && domain.len() <= MAX_DOMAIN_LENGTH
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_DOMAIN_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Verifies that the relays support the configured OpenVPN data channel cipher, if any.
+#[cfg(not(target_os = "android"))]
+fn validate_openvpn_cipher(options: &openvpn::TunnelOptions) -> Result<(), Error> {
+    match &options.cipher {
+        Some(cipher) if !RELAY_OPENVPN_CIPHERS.contains(&cipher.as_str()) => {
+            Err(Error::UnsupportedCipher(cipher.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Uses the address of `relay` with the preferred IP version as the peer address, if the relay
+/// has one. Obfuscated tunnels should be left alone, since the obfuscator targets the peer
+/// address chosen by the relay selector.
+fn apply_ip_version_preference(
+    peer: &mut wireguard::PeerConfig,
+    relay: &Relay,
+    preference: Constraint<IpVersion>,
+) {
+    let address = match preference {
+        Constraint::Any => return,
+        Constraint::Only(IpVersion::V4) => IpAddr::from(relay.ipv4_addr_in),
+        Constraint::Only(IpVersion::V6) => match relay.ipv6_addr_in {
+            Some(address) => IpAddr::from(address),
+            None => {
+                log::info!(
+                    "Relay {} has no IPv6 address, using IPv4 instead",
+                    relay.hostname
+                );
+                IpAddr::from(relay.ipv4_addr_in)
+            }
+        },
+    };
+    peer.endpoint.set_ip(address);
+}
+
+/// Logs a warning if a bridge was selected for a WireGuard tunnel, since bridges can only be used
+/// with OpenVPN. Returns whether there was a bridge to ignore.
+fn warn_ignored_bridge(bridge: Option<&SelectedBridge>) -> bool {
+    let name = match bridge {
+        Some(SelectedBridge::Normal(bridge)) => bridge.relay.hostname.as_str(),
+        Some(SelectedBridge::Custom(_)) => "custom bridge",
+        None => return false,
+    };
+    log::warn!(
+        "Ignoring {} since bridges cannot be used with WireGuard",
+        name
+    );
+    true
+}
+
+/// Replaces the endpoint of `peer` with the one returned by `endpoint_rewrite`, if set.
+fn rewrite_peer_endpoint(
+    peer: &mut wireguard::PeerConfig,
+    endpoint_rewrite: Option<&EndpointRewrite>,
+) {
+    if let Some(endpoint_rewrite) = endpoint_rewrite {
+        let endpoint = endpoint_rewrite(peer.endpoint);
+        log::debug!("Rewriting peer endpoint {} to {}", peer.endpoint, endpoint);
+        peer.endpoint = endpoint;
+    }
+}
+
+/// Adjusts the connection config for the WireGuard implementation that it is generated for. A
+/// userspace implementation routes the allowed IPs of its peers as given, so default prefixes are
+/// replaced with two more specific halves that take precedence over the default route. The kernel
+/// implementation is given the connection config unchanged.
+fn apply_wireguard_implementation(
+    connection: &mut wireguard::ConnectionConfig,
+    implementation: WireguardImplementation,
+) {
+    if implementation == WireguardImplementation::Kernel {
+        return;
+    }
+    let peers = std::iter::once(&mut connection.peer).chain(connection.exit_peer.as_mut());
+    for peer in peers {
+        peer.allowed_ips = peer
+            .allowed_ips
+            .iter()
+            .flat_map(|network| split_default_prefix(*network))
+            .collect();
+    }
+}
+
+fn split_default_prefix(network: IpNetwork) -> Vec<IpNetwork> {
+    match network {
+        network if network.prefix() != 0 => vec![network],
+        IpNetwork::V4(_) => vec!["0.0.0.0/1".parse().unwrap(), "128.0.0.0/1".parse().unwrap()],
+        IpNetwork::V6(_) => vec!["::/1".parse().unwrap(), "8000::/1".parse().unwrap()],
+    }
+}
+
+impl TunnelParametersGenerator for ParametersGenerator {
+    fn generate(
+        &mut self,
+        retry_attempt: u32,
+    ) -> Pin<Box<dyn Future<Output = Result<TunnelParameters, ParameterGenerationError>>>> {
+        let generator = self.clone();
+        // The interface belongs to the previous tunnel
+        *self.tunnel_interface.lock() = TunnelInterface::default();
+        Box::pin(async move {
+            generator
+                .generate_with_overrides(retry_attempt, &GenerateOverrides::default())
+                .await
+                .map_err(|error| match error {
+                    Error::NoBridgeAvailable { forced } => {
+                        if forced {
+                            log::error!("Bridge mode is on but no bridge matches the constraints");
+                        } else {
+                            log::error!("No bridge matches the constraints for the automatic bridge fallback");
+                        }
+                        ParameterGenerationError::NoMatchingBridgeRelay
+                    }
+                    Error::ResolveCustomHostname => {
+                        ParameterGenerationError::CustomTunnelHostResultionError
+                    }
+                    Error::Cancelled => {
+                        log::debug!("Tunnel parameter generation was cancelled");
+                        ParameterGenerationError::Cancelled
+                    }
+                    Error::KeyExpired => ParameterGenerationError::NoWireguardKey,
+                    error => {
+                        log::error!(
+                            "{}",
+                            error.display_chain_with_msg("Failed to generate tunnel parameters")
+                        );
+                        ParameterGenerationError::NoMatchingRelay
+                    }
+                })
+        })
+    }
+
+    fn set_tunnel_addresses(&mut self, addresses: &[IpAddr]) {
+        self.tunnel_interface.lock().addresses = addresses.to_vec();
+    }
+
+    fn set_tunnel_interface(&mut self, interface: &str) {
+        self.tunnel_interface.lock().name = Some(interface.to_owned());
+    }
+
+    fn set_tunnel_gateways(&mut self, gateways: &[IpAddr]) {
+        self.tunnel_interface.lock().gateways = gateways.to_vec();
+    }
+
+    fn report_path_mtu(&mut self, mtu: u16) {
+        let generator = self.clone();
+        tokio::spawn(async move { generator.report_path_mtu(mtu).await });
+    }
+
+    fn set_network(&mut self, fingerprint: Option<String>) {
+        self.network_report.lock().network = fingerprint;
+    }
+
+    fn set_ipv6_available(&mut self, available: bool) {
+        self.network_report.lock().ipv6_available = available;
+    }
+
+    fn report_handshake_timeout(&mut self) {
+        self.network_report.lock().timed_out = true;
+    }
+}
+
+/// Result of [`ParametersGenerator::self_test`].
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SelfTestReport {
+    /// Number of locations that relays could be selected for.
+    pub passed: usize,
+    /// Locations that no relays could be selected for.
+    pub failures: Vec<SelfTestFailure>,
+}
+
+/// A location that no relays could be selected for during [`ParametersGenerator::self_test`].
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTestFailure {
+    pub location: LocationConstraint,
+    /// Why no relays could be selected.
+    pub reason: String,
+}
+
+/// Relays selected for the last generated tunnel parameters, regardless of tunnel type.
+#[derive(Clone, Debug)]
+pub struct SelectedRelaySet {
+    pub exit: Relay,
+    pub entry: Option<Relay>,
+    pub obfuscator: Option<Relay>,
+    pub bridge: Option<Relay>,
+}
+
+/// A relay or bridge that traffic passes through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayHop {
+    /// This is `None` for custom relays and bridges.
+    pub hostname: Option<String>,
+    pub endpoint: Endpoint,
+}
+
+/// The obfuscator that traffic passes through, if obfuscation is used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObfuscatorHop {
+    /// Hostname of the relay that runs the obfuscator.
+    pub hostname: Option<String>,
+    pub endpoint: ObfuscationEndpoint,
+}
+
+/// Complete path of the last generated tunnel parameters. The traffic flow can look like this:
+///     client -> obfuscator -> entry -> exit -> internet
+///     client -> bridge -> exit -> internet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayChain {
+    pub tunnel_type: TunnelType,
+    pub exit: RelayHop,
+    pub entry: Option<RelayHop>,
+    pub bridge: Option<RelayHop>,
+    pub obfuscator: Option<ObfuscatorHop>,
+}
+
+impl RelayChain {
+    /// Combines the endpoints in `parameters` with the hostnames of the relays they were
+    /// generated from. `relays` is `None` if a custom relay is used.
+    fn new(parameters: &TunnelParameters, relays: Option<SelectedRelaySet>) -> Self {
+        let (exit_hostname, entry_hostname, bridge_hostname, obfuscator_hostname) = match relays {
+            Some(relays) => (
+                Some(relays.exit.hostname),
+                relays.entry.map(|relay| relay.hostname),
+                relays.bridge.map(|relay| relay.hostname),
+                relays.obfuscator.map(|relay| relay.hostname),
+            ),
+            None => (None, None, None, None),
+        };
+
+        match parameters {
+            TunnelParameters::OpenVpn(parameters) => {
+                // With multihop, the proxy runs on the entry relay
+                let proxy = parameters
+                    .proxy
+                    .as_ref()
+                    .map(|proxy| proxy.get_endpoint().endpoint);
+                let (entry, bridge) = match entry_hostname {
+                    Some(hostname) => (
+                        proxy.map(|endpoint| RelayHop {
+                            hostname: Some(hostname),
+                            endpoint,
+                        }),
+                        None,
+                    ),
+                    None => (
+                        None,
+                        proxy.map(|endpoint| RelayHop {
+                            hostname: bridge_hostname,
+                            endpoint,
+                        }),
+                    ),
+                };
+                RelayChain {
+                    tunnel_type: TunnelType::OpenVpn,
+                    exit: RelayHop {
+                        hostname: exit_hostname,
+                        endpoint: parameters.config.endpoint,
+                    },
+                    entry,
+                    bridge,
+                    obfuscator: None,
+                }
+            }
+            TunnelParameters::Wireguard(parameters) => {
+                let connection = &parameters.connection;
+                let hop = |hostname, peer: &wireguard::PeerConfig| RelayHop {
+                    hostname,
+                    endpoint: Endpoint::from_socket_address(peer.endpoint, TransportProtocol::Udp),
+                };
+                let (exit, entry) = match &connection.exit_peer {
+                    Some(exit_peer) => (
+                        hop(exit_hostname, exit_peer),
+                        Some(hop(entry_hostname, &connection.peer)),
+                    ),
+                    None => (hop(exit_hostname, &connection.peer), None),
+                };
+                RelayChain {
+                    tunnel_type: TunnelType::Wireguard,
+                    exit,
+                    entry,
+                    bridge: None,
+                    obfuscator: parameters.obfuscation.as_ref().map(|config| ObfuscatorHop {
+                        hostname: obfuscator_hostname,
+                        endpoint: ObfuscationEndpoint::from(config),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// Hostnames of the relays that would be selected for a connection attempt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayPreview {
+    pub tunnel_type: TunnelType,
+    pub exit: String,
+    pub entry: Option<String>,
+    pub bridge: Option<String>,
+    pub obfuscator: Option<String>,
+}
+
+/// Summary of the relay selection made for the last generated tunnel parameters. This contains
+/// no keys or account details, so it can be returned to frontends.
+#[derive(Clone, Debug, Serialize)]
+pub struct SelectionSnapshot {
+    pub generated_at: DateTime<Utc>,
+    pub tunnel_type: TunnelType,
+    /// Hostname of the exit relay. This is `None` for custom relays.
+    pub exit: Option<String>,
+    pub entry: Option<String>,
+    pub bridge: Option<String>,
+    pub obfuscator: Option<String>,
+    pub obfuscation: Option<ObfuscatorConfig>,
+    /// Relay constraints, including any override, that the relays were selected with. This is
+    /// `None` for custom relays.
+    pub constraints: Option<RelayConstraints>,
+    pub retry_attempt: u32,
+    /// Attempt number that bridge and obfuscation use was decided by. This is lower than
+    /// `retry_attempt` if escalation restarted since the relay constraints changed.
+    pub escalation_attempt: u32,
+    /// Whether relays close to the client were preferred, which is the case if the location of
+    /// the client is known and no location constraint is set.
+    pub preferred_nearby: bool,
+}
+
+impl SelectionSnapshot {
+    fn new(
+        parameters: &TunnelParameters,
+        relays: Option<SelectedRelaySet>,
+        constraints: Option<RelayConstraints>,
+        (retry_attempt, escalation_attempt): (u32, u32),
+        client_location_known: bool,
+    ) -> Self {
+        let preferred_nearby = client_location_known
+            && constraints
+                .as_ref()
+                .map(|constraints| constraints.location.is_any())
+                .unwrap_or(false);
+        let (tunnel_type, obfuscation) = match parameters {
+            TunnelParameters::OpenVpn(_) => (TunnelType::OpenVpn, None),
+            TunnelParameters::Wireguard(parameters) => {
+                (TunnelType::Wireguard, parameters.obfuscation.clone())
+            }
+        };
+        let hostname = |relay: Option<Relay>| relay.map(|relay| relay.hostname);
+        let (exit, entry, bridge, obfuscator) = match relays {
+            Some(relays) => (
+                Some(relays.exit.hostname),
+                hostname(relays.entry),
+                hostname(relays.bridge),
+                hostname(relays.obfuscator),
+            ),
+            None => (None, None, None, None),
+        };
+        SelectionSnapshot {
+            generated_at: Utc::now(),
+            tunnel_type,
+            exit,
+            entry,
+            bridge,
+            obfuscator,
+            obfuscation,
+            constraints,
+            retry_attempt,
+            escalation_attempt,
+            preferred_nearby,
+        }
+    }
+
+    /// Returns the hostnames of the selected relays, along with an explanation of why they were
+    /// selected.
+    fn selected_relays(&self) -> SelectedRelayHostnames {
+        SelectedRelayHostnames {
+            tunnel_type: self.tunnel_type,
+            exit: self.exit.clone(),
+            entry: self.entry.clone(),
+            bridge: self.bridge.clone(),
+            obfuscator: self.obfuscator.clone(),
+            explanation: self.explain(),
+        }
+    }
+
+    /// Returns a one-paragraph explanation of why these relays were selected.
+    pub fn explain(&self) -> String {
+        let (exit, constraints) = match (&self.exit, &self.constraints) {
+            (Some(exit), Some(constraints)) => (exit, constraints),
+            _ => return format!("A custom {} relay was used.", self.tunnel_type),
+        };
+
+        let mut sentences = vec![format!(
+            "{} was selected as the {} exit relay from relays matching: {}.",
+            exit, self.tunnel_type, constraints
+        )];
+        if self.preferred_nearby {
+            sentences.push("Relays close to your location were preferred.".to_string());
+        }
+        if let Some(entry) = &self.entry {
+            sentences.push(format!("Traffic enters through {} using multihop.", entry));
+        }
+        if self.escalation_attempt < self.retry_attempt {
+            sentences.push(format!(
+                "This was connection attempt {}, but since the relay constraints changed, \
+                 bridges and obfuscation were chosen as for attempt {}.",
+                self.retry_attempt + 1,
+                self.escalation_attempt + 1
+            ));
+        } else if self.retry_attempt > 0 {
+            sentences.push(format!(
+                "This was connection attempt {}.",
+                self.retry_attempt + 1
+            ));
+        }
+        match (&self.obfuscation, &self.obfuscator) {
+            (Some(ObfuscatorConfig::Udp2Tcp { .. }), Some(obfuscator)) => sentences.push(format!(
+                "Traffic is obfuscated using UDP-over-TCP through {}.",
+                obfuscator
+            )),
+            (Some(ObfuscatorConfig::Udp2Tcp { .. }), None) => {
+                sentences.push("Traffic is obfuscated using UDP-over-TCP.".to_string())
+            }
+            (Some(ObfuscatorConfig::PluggableTransport { name, .. }), _) => {
+                sentences.push(format!(
+                    "Traffic is obfuscated using the pluggable transport {}.",
+                    name
+                ))
+            }
+            (None, _) => (),
+        }
+        if let Some(bridge) = &self.bridge {
+            sentences.push(format!("Traffic is sent through the bridge {}.", bridge));
+        }
+        sentences.join(" ")
+    }
+}
+
+/// Contains all relays that were selected last time when tunnel parameters were generated.
+/// This contains no secrets, so it can be cached on disk.
+#[derive(Serialize, Deserialize)]
+enum LastSelectedRelays {
+    /// Represents all relays generated for a WireGuard tunnel.
     /// The traffic flow can look like this:
     ///     client -> obfuscator -> entry -> exit -> internet
     /// But for most users, it will look like this:
@@ -280,10 +3208,2462 @@ enum LastSelectedRelays {
         wg_entry: Option<Relay>,
         wg_exit: Relay,
         obfuscator: Option<Relay>,
+        /// Obfuscation that the tunnel was generated with, including any per-connection override.
+        #[serde(default)]
+        obfuscation: Option<ObfuscatorConfig>,
     },
     /// Represents all relays generated for an OpenVPN tunnel.
     /// The traffic flows like this:
     ///     client -> bridge -> relay -> internet
+    /// Or, when multihop is used:
+    ///     client -> entry -> relay -> internet
+    #[cfg(not(target_os = "android"))]
+    OpenVpn {
+        relay: Relay,
+        #[serde(default)]
+        entry: Option<Relay>,
+        bridge: Option<Relay>,
+    },
+}
+
+impl LastSelectedRelays {
+    fn relay_set(&self) -> SelectedRelaySet {
+        match self {
+            LastSelectedRelays::WireGuard {
+                wg_entry,
+                wg_exit,
+                obfuscator,
+                ..
+            } => SelectedRelaySet {
+                exit: wg_exit.clone(),
+                entry: wg_entry.clone(),
+                obfuscator: obfuscator.clone(),
+                bridge: None,
+            },
+            #[cfg(not(target_os = "android"))]
+            LastSelectedRelays::OpenVpn {
+                relay,
+                entry,
+                bridge,
+            } => SelectedRelaySet {
+                exit: relay.clone(),
+                entry: entry.clone(),
+                obfuscator: None,
+                bridge: bridge.clone(),
+            },
+        }
+    }
+
+    /// Returns the tunnel protocol that the relays were selected for.
+    fn tunnel_type(&self) -> TunnelType {
+        match self {
+            LastSelectedRelays::WireGuard { .. } => TunnelType::Wireguard,
+            #[cfg(not(target_os = "android"))]
+            LastSelectedRelays::OpenVpn { .. } => TunnelType::OpenVpn,
+        }
+    }
+
+    fn exit(&self) -> &Relay {
+        match self {
+            LastSelectedRelays::WireGuard { wg_exit, .. } => wg_exit,
+            #[cfg(not(target_os = "android"))]
+            LastSelectedRelays::OpenVpn { relay, .. } => relay,
+        }
+    }
+
+    /// Returns the location of the exit relay, along with the hostnames of all relays. Returns
+    /// `None` if the exit relay has no location data.
+    fn location(&self) -> Option<GeoIpLocation> {
+        let take_hostname =
+            |relay: &Option<Relay>| relay.as_ref().map(|relay| relay.hostname.clone());
+
+        let (exit, bridge, entry, obfuscator) = match self {
+            LastSelectedRelays::WireGuard {
+                wg_entry: entry,
+                wg_exit: exit,
+                obfuscator,
+                ..
+            } => (exit, &None, entry, obfuscator),
+            #[cfg(not(target_os = "android"))]
+            LastSelectedRelays::OpenVpn {
+                relay,
+                entry,
+                bridge,
+            } => (relay, bridge, entry, &None),
+        };
+
+        let location = relay_to_geoip(exit);
+        if location.is_none() {
+            log::warn!("Relay {} has no location data", exit.hostname);
+        }
+
+        Some(GeoIpLocation {
+            bridge_hostname: take_hostname(bridge),
+            entry_hostname: take_hostname(entry),
+            obfuscator_hostname: take_hostname(obfuscator),
+            ..location?
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::PrivateDevice;
+    use mullvad_relay_selector::NormalSelectedRelay;
+    use mullvad_types::{
+        custom_list::CustomListsSettings,
+        escalation::EscalationLadder,
+        relay_constraints::{
+            BridgeConstraints, BridgeSettings, BridgeState, ObfuscationSettings, RelaySettings,
+            SelectedObfuscation, TransportPort,
+        },
+        relay_list::{
+            OpenVpnEndpoint, RelayListCity, RelayListCountry, WireguardRelayEndpointData,
+        },
+        wireguard::AssociatedAddresses,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::sync::atomic::AtomicUsize;
+
+    fn wireguard_relay(hostname: &str, addr: &str) -> Relay {
+        Relay {
+            hostname: hostname.to_string(),
+            ipv4_addr_in: addr.parse().unwrap(),
+            ipv6_addr_in: None,
+            include_in_country: true,
+            active: true,
+            owned: true,
+            provider: "31173".to_string(),
+            weight: 1,
+            load: None,
+            endpoint_data: RelayEndpointData::Wireguard(WireguardRelayEndpointData {
+                public_key: wireguard::PublicKey::from_base64(
+                    "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+                )
+                .unwrap(),
+                multihop_entry: true,
+                pluggable_transports: vec![],
+                quantum_resistant: true,
+                daita: false,
+            }),
+            location: None,
+        }
+    }
+
+    fn wireguard_endpoint(relay: &Relay) -> MullvadWireguardEndpoint {
+        MullvadWireguardEndpoint {
+            peer: wireguard::PeerConfig {
+                public_key: relay
+                    .endpoint_data
+                    .unwrap_wireguard_ref()
+                    .public_key
+                    .clone(),
+                endpoint: SocketAddr::new(relay.ipv4_addr_in.into(), 51820),
+                allowed_ips: talpid_types::net::all_of_the_internet(),
+                psk: None,
+            },
+            exit_peer: None,
+            ipv4_gateway: "10.64.0.1".parse().unwrap(),
+            ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+        }
+    }
+
+    fn udp2tcp_obfuscator(relay: &Relay) -> SelectedObfuscator {
+        SelectedObfuscator {
+            config: ObfuscatorConfig::Udp2Tcp {
+                endpoint: SocketAddr::new(relay.ipv4_addr_in.into(), 443),
+            },
+            relay: relay.clone(),
+        }
+    }
+
+    #[test]
+    fn test_last_location_without_location_data() {
+        let exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        assert!(exit.location.is_none());
+
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: Some(wireguard_relay("se10-wireguard", "185.213.154.69")),
+            wg_exit: exit.clone(),
+            obfuscator: None,
+            obfuscation: None,
+        };
+        assert_eq!(relays.location(), None);
+
+        #[cfg(not(target_os = "android"))]
+        {
+            let relays = LastSelectedRelays::OpenVpn {
+                relay: exit,
+                entry: None,
+                bridge: None,
+            };
+            assert_eq!(relays.location(), None);
+        }
+    }
+
+    #[test]
+    fn test_last_relay_set() {
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: Some(wireguard_relay("se10-wireguard", "185.213.154.69")),
+            wg_exit: wireguard_relay("se9-wireguard", "185.213.154.68"),
+            obfuscator: Some(wireguard_relay("se10-wireguard", "185.213.154.69")),
+            obfuscation: None,
+        };
+        let relay_set = relays.relay_set();
+        assert_eq!(relay_set.exit.hostname, "se9-wireguard");
+        assert_eq!(relay_set.entry.unwrap().hostname, "se10-wireguard");
+        assert_eq!(relay_set.obfuscator.unwrap().hostname, "se10-wireguard");
+        assert!(relay_set.bridge.is_none());
+
+        #[cfg(not(target_os = "android"))]
+        {
+            let relays = LastSelectedRelays::OpenVpn {
+                relay: wireguard_relay("se-got-001", "185.213.154.131"),
+                entry: None,
+                bridge: Some(wireguard_relay("se-got-br-001", "185.213.154.132")),
+            };
+            let relay_set = relays.relay_set();
+            assert_eq!(relay_set.exit.hostname, "se-got-001");
+            assert_eq!(relay_set.bridge.unwrap().hostname, "se-got-br-001");
+            assert!(relay_set.entry.is_none());
+            assert!(relay_set.obfuscator.is_none());
+
+            let relays = LastSelectedRelays::OpenVpn {
+                relay: wireguard_relay("se-got-001", "185.213.154.131"),
+                entry: Some(wireguard_relay("de-fra-001", "185.213.154.133")),
+                bridge: None,
+            };
+            let relay_set = relays.relay_set();
+            assert_eq!(relay_set.entry.unwrap().hostname, "de-fra-001");
+            assert!(relay_set.bridge.is_none());
+        }
+    }
+
+    #[test]
+    fn test_selection_snapshot() {
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: None,
+            wg_exit: wireguard_relay("se9-wireguard", "185.213.154.68"),
+            obfuscator: Some(wireguard_relay("se9-wireguard", "185.213.154.68")),
+            obfuscation: None,
+        };
+        let obfuscation = udp2tcp_obfuscator(relays.exit()).config;
+        let parameters = wireguard_parameters(relays.exit(), Some(obfuscation.clone()));
+        let private_key = match &parameters {
+            TunnelParameters::Wireguard(parameters) => {
+                parameters.connection.tunnel.private_key.to_base64()
+            }
+            _ => unreachable!(),
+        };
+
+        let snapshot = SelectionSnapshot::new(
+            &parameters,
+            Some(relays.relay_set()),
+            Some(RelayConstraints::default()),
+            (0, 0),
+            false,
+        );
+        assert_eq!(snapshot.tunnel_type, TunnelType::Wireguard);
+        assert_eq!(snapshot.exit.as_deref(), Some("se9-wireguard"));
+        assert_eq!(snapshot.obfuscator.as_deref(), Some("se9-wireguard"));
+        assert!(snapshot.entry.is_none());
+        assert!(snapshot.bridge.is_none());
+        assert_eq!(snapshot.obfuscation, Some(obfuscation));
+
+        let json = serde_json::to_string(&snapshot).expect("Failed to serialize snapshot");
+        assert!(json.contains("\"exit\":\"se9-wireguard\""));
+        assert!(!json.contains(&private_key));
+    }
+
+    #[test]
+    fn test_explain_selection() {
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: None,
+            wg_exit: wireguard_relay("se9-wireguard", "185.213.154.68"),
+            obfuscator: None,
+            obfuscation: None,
+        };
+        let parameters = wireguard_parameters(relays.exit(), None);
+        let constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::City(
+                "se".to_string(),
+                "got".to_string(),
+            )),
+            ..RelayConstraints::default()
+        };
+
+        let snapshot = SelectionSnapshot::new(
+            &parameters,
+            Some(relays.relay_set()),
+            Some(constraints),
+            (3, 1),
+            true,
+        );
+        assert!(!snapshot.preferred_nearby);
+        let explanation = snapshot.explain();
+        assert!(explanation.contains("se9-wireguard"));
+        assert!(explanation.contains("city got, se"));
+        assert!(explanation.contains("as for attempt 2"));
+        assert!(!explanation.contains("obfuscated"));
+
+        let snapshot = SelectionSnapshot::new(
+            &parameters,
+            Some(relays.relay_set()),
+            Some(RelayConstraints::default()),
+            (0, 0),
+            true,
+        );
+        assert!(snapshot.explain().contains("any location"));
+        assert!(snapshot.explain().contains("close to your location"));
+
+        let selected = snapshot.selected_relays();
+        assert_eq!(selected.tunnel_type, TunnelType::Wireguard);
+        assert_eq!(selected.exit.as_deref(), Some("se9-wireguard"));
+        assert_eq!(selected.explanation, snapshot.explain());
+    }
+
+    #[test]
+    fn test_restore_last_relays() {
+        let mut exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        exit.location = Some(mullvad_types::location::Location {
+            country: "Sweden".to_string(),
+            country_code: "se".to_string(),
+            city: "Gothenburg".to_string(),
+            city_code: "got".to_string(),
+            latitude: 57.70887,
+            longitude: 11.97456,
+        });
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: None,
+            wg_exit: exit,
+            obfuscator: None,
+            obfuscation: None,
+        };
+        let location = relays.location();
+        assert!(location.is_some());
+
+        let cache_path =
+            std::env::temp_dir().join(format!("{}-{}", std::process::id(), LAST_RELAYS_FILE));
+        std::fs::write(&cache_path, serde_json::to_string(&Some(relays)).unwrap()).unwrap();
+        let restored = load_last_relays(&cache_path);
+        std::fs::remove_file(&cache_path).unwrap();
+
+        assert_eq!(restored.and_then(|relays| relays.location()), location);
+    }
+
+    #[tokio::test]
+    async fn test_last_location_cached() {
+        let mut exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        exit.location = Some(mullvad_types::location::Location {
+            country: "Sweden".to_string(),
+            country_code: "se".to_string(),
+            city: "Gothenburg".to_string(),
+            city_code: "got".to_string(),
+            latitude: 57.70887,
+            longitude: 11.97456,
+        });
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: None,
+            wg_exit: exit.clone(),
+            obfuscator: None,
+            obfuscation: None,
+        };
+        let cache_dir =
+            std::env::temp_dir().join(format!("{}-last-location-cached", std::process::id()));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(
+            cache_dir.join(LAST_RELAYS_FILE),
+            serde_json::to_string(&Some(relays)).unwrap(),
+        )
+        .unwrap();
+
+        let generator = ParametersGenerator::builder(
+            logged_in_device(),
+            new_relay_selector(relay_list(vec![exit])),
+        )
+        .cache_dir(&cache_dir)
+        .build();
+        assert!(generator.is_last_location_cached().await);
+        assert!(generator.get_last_location().await.is_some());
+
+        // The flag is kept until tunnel parameters are generated successfully
+        assert!(generator
+            .generate_for_device(&"unknown".to_string(), 0)
+            .await
+            .is_err());
+        assert!(generator.is_last_location_cached().await);
+
+        generator
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+        assert!(!generator.is_last_location_cached().await);
+        assert!(generator.get_last_location().await.is_some());
+    }
+
+    #[test]
+    fn test_last_relays_obfuscation() {
+        let exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let obfuscation = ObfuscatorConfig::Udp2Tcp {
+            endpoint: SocketAddr::new(exit.ipv4_addr_in.into(), 5001),
+        };
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: None,
+            wg_exit: exit.clone(),
+            obfuscator: Some(exit),
+            obfuscation: Some(obfuscation.clone()),
+        };
+
+        let mut json = serde_json::to_value(&relays).unwrap();
+        match serde_json::from_value(json.clone()).unwrap() {
+            LastSelectedRelays::WireGuard {
+                obfuscation: restored,
+                ..
+            } => assert_eq!(restored, Some(obfuscation)),
+            #[cfg(not(target_os = "android"))]
+            _ => panic!("expected WireGuard relays"),
+        }
+
+        // Relays cached before the obfuscation was recorded can still be restored
+        json["WireGuard"]
+            .as_object_mut()
+            .unwrap()
+            .remove("obfuscation");
+        match serde_json::from_value(json).unwrap() {
+            LastSelectedRelays::WireGuard { obfuscation, .. } => assert_eq!(obfuscation, None),
+            #[cfg(not(target_os = "android"))]
+            _ => panic!("expected WireGuard relays"),
+        }
+    }
+
+    #[test]
+    fn test_ignored_bridge() {
+        assert!(!warn_ignored_bridge(None));
+
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let bridge = SelectedBridge::Custom(talpid_types::net::openvpn::ProxySettings::Local(
+            talpid_types::net::openvpn::LocalProxySettings {
+                port: 1080,
+                peer: SocketAddr::new(relay.ipv4_addr_in.into(), 443),
+            },
+        ));
+        assert!(warn_ignored_bridge(Some(&bridge)));
+    }
+
+    #[test]
+    fn test_endpoint_rewrite() {
+        let mut exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        exit.location = Some(mullvad_types::location::Location {
+            country: "Sweden".to_string(),
+            country_code: "se".to_string(),
+            city: "Gothenburg".to_string(),
+            city_code: "got".to_string(),
+            latitude: 57.70887,
+            longitude: 11.97456,
+        });
+        let endpoint_rewrite: EndpointRewrite =
+            Arc::new(|endpoint| SocketAddr::new("127.0.0.1".parse().unwrap(), endpoint.port()));
+
+        let mut peer = wireguard_endpoint(&exit).peer;
+        rewrite_peer_endpoint(&mut peer, None);
+        assert_eq!(peer.endpoint, "185.213.154.68:51820".parse().unwrap());
+
+        rewrite_peer_endpoint(&mut peer, Some(&endpoint_rewrite));
+        assert_eq!(peer.endpoint, "127.0.0.1:51820".parse().unwrap());
+
+        // The real relay is still reported as the last selected relay
+        let relays = LastSelectedRelays::WireGuard {
+            wg_entry: None,
+            wg_exit: exit,
+            obfuscator: None,
+            obfuscation: None,
+        };
+        let location = relays.location().unwrap();
+        assert_eq!(location.hostname.as_deref(), Some("se9-wireguard"));
+        assert_eq!(location.city.as_deref(), Some("Gothenburg"));
+    }
+
+    fn wireguard_parameters(
+        relay: &Relay,
+        obfuscation: Option<ObfuscatorConfig>,
+    ) -> TunnelParameters {
+        let tunnel_options = TunnelOptions::default();
+        let endpoint = wireguard_endpoint(relay);
+        wireguard::TunnelParameters {
+            connection: wireguard::ConnectionConfig {
+                tunnel: wireguard::TunnelConfig {
+                    private_key: wireguard::PrivateKey::new_from_random(),
+                    addresses: vec![],
+                },
+                peer: endpoint.peer,
+                exit_peer: None,
+                ipv4_gateway: endpoint.ipv4_gateway,
+                ipv6_gateway: Some(endpoint.ipv6_gateway),
+                #[cfg(target_os = "linux")]
+                fwmark: Some(mullvad_types::TUNNEL_FWMARK),
+            },
+            options: tunnel_options.wireguard.to_talpid_tunnel_options(),
+            generic_options: tunnel_options.generic.clone(),
+            obfuscation,
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_wg_quick_config() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let mut parameters = match wireguard_parameters(&relay, None) {
+            TunnelParameters::Wireguard(parameters) => parameters,
+            TunnelParameters::OpenVpn(_) => unreachable!(),
+        };
+        parameters.connection.tunnel.addresses = vec![
+            "10.64.10.20".parse().unwrap(),
+            "fc00:bbbb:bbbb:bb01::a:1413".parse().unwrap(),
+        ];
+        parameters.options.mtu = Some(1380);
+
+        let config = wg_quick_config(&parameters).expect("expected a config");
+        let mut sections: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut section = String::new();
+        for line in config.lines().filter(|line| !line.is_empty()) {
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                section = name.to_string();
+                continue;
+            }
+            let (key, value) = line.split_once(" = ").expect("expected a key and value");
+            sections
+                .entry(section.clone())
+                .or_default()
+                .insert(key.to_string(), value.to_string());
+        }
+        let split = |value: &str| value.split(", ").map(str::to_string).collect::<Vec<_>>();
+
+        let interface = &sections["Interface"];
+        let private_key: wireguard::PrivateKey =
+            serde_json::from_value(serde_json::Value::String(interface["PrivateKey"].clone()))
+                .unwrap();
+        assert_eq!(private_key, parameters.connection.tunnel.private_key);
+        let addresses = split(&interface["Address"])
+            .iter()
+            .map(|address| address.parse::<IpNetwork>().unwrap().ip())
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, parameters.connection.tunnel.addresses);
+        assert_eq!(
+            split(&interface["DNS"]),
+            vec!["10.64.0.1", "fc00:bbbb:bbbb:bb01::1"]
+        );
+        assert_eq!(interface["MTU"], "1380");
+
+        let peer = &sections["Peer"];
+        assert_eq!(
+            wireguard::PublicKey::from_base64(&peer["PublicKey"]).unwrap(),
+            parameters.connection.peer.public_key
+        );
+        let allowed_ips = split(&peer["AllowedIPs"])
+            .iter()
+            .map(|network| network.parse::<IpNetwork>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(allowed_ips, parameters.connection.peer.allowed_ips);
+        assert_eq!(
+            peer["Endpoint"].parse::<SocketAddr>().unwrap(),
+            parameters.connection.peer.endpoint
+        );
+        assert_eq!(peer["PersistentKeepalive"], "25");
+
+        // Multihop tunnels cannot be exported
+        parameters.connection.exit_peer = Some(parameters.connection.peer.clone());
+        assert!(wg_quick_config(&parameters).is_none());
+    }
+
+    #[test]
+    fn test_custom_hop() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_peer = wireguard_endpoint(&relay).peer;
+        let custom_ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let custom_key = wireguard::PrivateKey::new_from_random().public_key();
+        let custom_relay = |hop| CustomMultihopRelay {
+            hop,
+            host: custom_ip.to_string(),
+            port: 51820,
+            public_key: custom_key.clone(),
+        };
+        let connection = |parameters: TunnelParameters| match parameters {
+            TunnelParameters::Wireguard(parameters) => parameters.connection,
+            TunnelParameters::OpenVpn(_) => unreachable!(),
+        };
+
+        // The relay is the entry
+        let gateway = "10.0.0.1".parse().unwrap();
+        let mut exit_connection = connection(wireguard_parameters(&relay, None));
+        set_custom_hop(
+            &mut exit_connection,
+            &custom_relay(CustomHop::Exit {
+                ipv4_gateway: gateway,
+            }),
+            custom_ip,
+        );
+        assert_eq!(exit_connection.peer.public_key, relay_peer.public_key);
+        assert_eq!(exit_connection.peer.endpoint, relay_peer.endpoint);
+        assert_eq!(
+            exit_connection.peer.allowed_ips,
+            vec![IpNetwork::from(custom_ip)]
+        );
+        let exit_peer = exit_connection.exit_peer.expect("expected an exit peer");
+        assert_eq!(exit_peer.public_key, custom_key);
+        assert_eq!(exit_peer.endpoint, SocketAddr::new(custom_ip, 51820));
+        assert_eq!(exit_peer.allowed_ips, all_of_the_internet());
+        assert_eq!(exit_connection.ipv4_gateway, gateway);
+        assert_eq!(exit_connection.ipv6_gateway, None);
+
+        // The relay is the exit
+        let mut entry_connection = connection(wireguard_parameters(&relay, None));
+        set_custom_hop(
+            &mut entry_connection,
+            &custom_relay(CustomHop::Entry),
+            custom_ip,
+        );
+        assert_eq!(entry_connection.peer.public_key, custom_key);
+        assert_eq!(
+            entry_connection.peer.allowed_ips,
+            vec![IpNetwork::from(relay_peer.endpoint.ip())]
+        );
+        let exit_peer = entry_connection.exit_peer.expect("expected an exit peer");
+        assert_eq!(exit_peer.public_key, relay_peer.public_key);
+        assert_eq!(exit_peer.allowed_ips, relay_peer.allowed_ips);
+        assert_eq!(
+            entry_connection.ipv4_gateway,
+            wireguard_endpoint(&relay).ipv4_gateway
+        );
+    }
+
+    #[test]
+    fn test_relay_chain() {
+        let entry = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let exit = wireguard_relay("de1-wireguard", "185.213.154.69");
+        let obfuscator = udp2tcp_obfuscator(&entry);
+        let mut parameters = wireguard_parameters(&entry, Some(obfuscator.config.clone()));
+        if let TunnelParameters::Wireguard(parameters) = &mut parameters {
+            parameters.connection.exit_peer = Some(wireguard_endpoint(&exit).peer);
+        }
+        let relays = SelectedRelaySet {
+            exit: exit.clone(),
+            entry: Some(entry.clone()),
+            obfuscator: Some(obfuscator.relay),
+            bridge: None,
+        };
+
+        let chain = RelayChain::new(&parameters, Some(relays));
+        assert_eq!(chain.tunnel_type, TunnelType::Wireguard);
+        assert_eq!(
+            chain.exit,
+            RelayHop {
+                hostname: Some("de1-wireguard".to_string()),
+                endpoint: Endpoint::new(exit.ipv4_addr_in, 51820, TransportProtocol::Udp),
+            }
+        );
+        assert_eq!(
+            chain.entry,
+            Some(RelayHop {
+                hostname: Some("se9-wireguard".to_string()),
+                endpoint: Endpoint::new(entry.ipv4_addr_in, 51820, TransportProtocol::Udp),
+            })
+        );
+        assert_eq!(chain.bridge, None);
+        assert_eq!(
+            chain.obfuscator,
+            Some(ObfuscatorHop {
+                hostname: Some("se9-wireguard".to_string()),
+                endpoint: ObfuscationEndpoint::from(&obfuscator.config),
+            })
+        );
+
+        // Hostnames are unknown for custom relays
+        let chain = RelayChain::new(&wireguard_parameters(&exit, None), None);
+        assert_eq!(chain.exit.hostname, None);
+        assert_eq!(chain.entry, None);
+        assert_eq!(chain.obfuscator, None);
+    }
+
+    #[test]
+    fn test_obfuscation_required() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        assert!(matches!(
+            check_obfuscated(&wireguard_parameters(&relay, None)),
+            Err(Error::ObfuscationRequired)
+        ));
+
+        let obfuscation = udp2tcp_obfuscator(&relay).config;
+        assert!(check_obfuscated(&wireguard_parameters(&relay, Some(obfuscation))).is_ok());
+    }
+
+    #[test]
+    fn test_protocol_options() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let mut tunnel_options = TunnelOptions::default();
+        tunnel_options.wireguard.require_obfuscation = true;
+
+        assert!(matches!(
+            check_protocol_options(&wireguard_parameters(&relay, None), &tunnel_options, None),
+            Err(Error::ObfuscationRequired)
+        ));
+
+        // The WireGuard options are ignored after switching to OpenVPN
+        #[cfg(not(target_os = "android"))]
+        {
+            let parameters = TunnelParameters::from(openvpn::TunnelParameters {
+                config: openvpn::ConnectionConfig::new(
+                    talpid_types::net::Endpoint::new(
+                        relay.ipv4_addr_in,
+                        1194,
+                        talpid_types::net::TransportProtocol::Udp,
+                    ),
+                    "1234".to_string(),
+                    "-".to_string(),
+                ),
+                options: tunnel_options.openvpn.clone(),
+                generic_options: tunnel_options.generic.clone(),
+                proxy: None,
+                #[cfg(target_os = "linux")]
+                fwmark: mullvad_types::TUNNEL_FWMARK,
+            });
+            assert!(check_protocol_options(&parameters, &tunnel_options, None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_wireguard_protocol_options() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let obfuscation = udp2tcp_obfuscator(&relay).config;
+        let mut tunnel_options = TunnelOptions::default();
+        let restrictions = EndpointRestrictions {
+            bridge_state: BridgeState::Auto,
+            ip_version: Constraint::Any,
+        };
+        let check = |parameters: &TunnelParameters,
+                     tunnel_options: &TunnelOptions,
+                     restrictions: &EndpointRestrictions| {
+            check_protocol_options(parameters, tunnel_options, Some(restrictions))
+        };
+
+        // WireGuard must use the protocol port, unless the traffic is obfuscated
+        tunnel_options.protocol_ports.wireguard = Constraint::Only(53);
+        assert!(matches!(
+            check(
+                &wireguard_parameters(&relay, None),
+                &tunnel_options,
+                &restrictions
+            ),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(check(
+            &wireguard_parameters(&relay, Some(obfuscation.clone())),
+            &tunnel_options,
+            &restrictions
+        )
+        .is_ok());
+        // Custom servers and rewritten endpoints are not checked
+        assert!(
+            check_protocol_options(&wireguard_parameters(&relay, None), &tunnel_options, None)
+                .is_ok()
+        );
+        tunnel_options.protocol_ports.wireguard = Constraint::Only(51820);
+        assert!(check(
+            &wireguard_parameters(&relay, None),
+            &tunnel_options,
+            &restrictions
+        )
+        .is_ok());
+
+        // The IP version applies to the obfuscator if there is one
+        let ipv6_only = EndpointRestrictions {
+            bridge_state: BridgeState::Auto,
+            ip_version: Constraint::Only(IpVersion::V6),
+        };
+        assert!(matches!(
+            check(
+                &wireguard_parameters(&relay, None),
+                &tunnel_options,
+                &ipv6_only
+            ),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(matches!(
+            check(
+                &wireguard_parameters(&relay, Some(obfuscation)),
+                &tunnel_options,
+                &ipv6_only
+            ),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        let ipv6_obfuscation = ObfuscatorConfig::Udp2Tcp {
+            endpoint: "[2a03:1b20:1:f011::a09f]:443".parse().unwrap(),
+        };
+        assert!(check(
+            &wireguard_parameters(&relay, Some(ipv6_obfuscation)),
+            &tunnel_options,
+            &ipv6_only
+        )
+        .is_ok());
+    }
+
+    #[cfg(not(target_os = "android"))]
+    #[test]
+    fn test_openvpn_protocol_options() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let mut tunnel_options = TunnelOptions::default();
+        let parameters = |protocol, port, bridged: bool| {
+            let bridge = talpid_types::net::openvpn::ProxySettings::Local(
+                talpid_types::net::openvpn::LocalProxySettings {
+                    port: 1080,
+                    peer: SocketAddr::new(relay.ipv4_addr_in.into(), 443),
+                },
+            );
+            TunnelParameters::from(openvpn::TunnelParameters {
+                config: openvpn::ConnectionConfig::new(
+                    talpid_types::net::Endpoint::new(relay.ipv4_addr_in, port, protocol),
+                    "1234".to_string(),
+                    "-".to_string(),
+                ),
+                options: TunnelOptions::default().openvpn,
+                generic_options: TunnelOptions::default().generic,
+                proxy: Some(bridge).filter(|_| bridged),
+                #[cfg(target_os = "linux")]
+                fwmark: mullvad_types::TUNNEL_FWMARK,
+            })
+        };
+        let check = |parameters: &TunnelParameters,
+                     tunnel_options: &TunnelOptions,
+                     bridge_state: BridgeState| {
+            let restrictions = EndpointRestrictions {
+                bridge_state,
+                ip_version: Constraint::Any,
+            };
+            check_protocol_options(parameters, tunnel_options, Some(&restrictions))
+        };
+        let udp = parameters(TransportProtocol::Udp, 1194, false);
+        let tcp = parameters(TransportProtocol::Tcp, 443, false);
+        let bridged_tcp = parameters(TransportProtocol::Tcp, 443, true);
+        let bridged_udp = parameters(TransportProtocol::Udp, 1194, true);
+
+        // OpenVPN must use the transport protocol and port of the protocol port
+        tunnel_options.protocol_ports.openvpn = Constraint::Only(TransportPort {
+            protocol: TransportProtocol::Tcp,
+            port: Constraint::Only(443),
+        });
+        assert!(matches!(
+            check(&udp, &tunnel_options, BridgeState::Auto),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(matches!(
+            check(
+                &parameters(TransportProtocol::Tcp, 80, false),
+                &tunnel_options,
+                BridgeState::Auto
+            ),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(check(&tcp, &tunnel_options, BridgeState::Auto).is_ok());
+        tunnel_options.protocol_ports.openvpn = Constraint::Any;
+
+        // A bridge must be used if and only if the bridge state requires it
+        assert!(matches!(
+            check(&tcp, &tunnel_options, BridgeState::On),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(check(&bridged_tcp, &tunnel_options, BridgeState::On).is_ok());
+        assert!(matches!(
+            check(&bridged_tcp, &tunnel_options, BridgeState::Off),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(check(&udp, &tunnel_options, BridgeState::Off).is_ok());
+        assert!(check(&udp, &tunnel_options, BridgeState::Auto).is_ok());
+
+        // Bridges only carry TCP
+        assert!(matches!(
+            check(&bridged_udp, &tunnel_options, BridgeState::On),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+        assert!(matches!(
+            check(&bridged_udp, &tunnel_options, BridgeState::Auto),
+            Err(Error::ProtocolOptionsViolated(_))
+        ));
+    }
+
+    #[test]
+    fn test_stale_credentials() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        assert!(!has_stale_credentials(
+            &wireguard_parameters(&relay, None),
+            "5678"
+        ));
+
+        #[cfg(not(target_os = "android"))]
+        {
+            let tunnel_options = TunnelOptions::default();
+            let parameters = TunnelParameters::from(openvpn::TunnelParameters {
+                config: openvpn::ConnectionConfig::new(
+                    talpid_types::net::Endpoint::new(
+                        relay.ipv4_addr_in,
+                        1194,
+                        talpid_types::net::TransportProtocol::Udp,
+                    ),
+                    "1234".to_string(),
+                    "-".to_string(),
+                ),
+                options: tunnel_options.openvpn.clone(),
+                generic_options: tunnel_options.generic.clone(),
+                proxy: None,
+                #[cfg(target_os = "linux")]
+                fwmark: mullvad_types::TUNNEL_FWMARK,
+            });
+            assert!(!has_stale_credentials(&parameters, "1234"));
+            assert!(has_stale_credentials(&parameters, "5678"));
+        }
+    }
+
+    #[test]
+    fn test_recently_failed_relays() {
+        let mut failed = RecentlyFailedRelays::default();
+        failed.report_failure("se9-wireguard".to_string());
+        assert!(failed.contains("se9-wireguard"));
+        assert!(!failed.contains("se10-wireguard"));
+
+        failed.report_success("se9-wireguard");
+        assert!(!failed.contains("se9-wireguard"));
+    }
+
+    #[test]
+    fn test_recently_used_relays() {
+        let mut used = RecentlyUsedRelays::default();
+        assert!(used.record("se9-wireguard"));
+        assert!(!used.record("se9-wireguard"));
+        assert!(used.record("se10-wireguard"));
+        assert!(!used.is_avoiding());
+        assert!(!used.avoids("se9-wireguard"));
+
+        used.set_avoided(1);
+        assert!(used.avoids("se10-wireguard"));
+        assert!(!used.avoids("se9-wireguard"));
+
+        used.set_avoided(2);
+        assert!(used.avoids("se9-wireguard"));
+
+        // Using a relay again moves it to the front
+        assert!(used.record("se9-wireguard"));
+        used.set_avoided(1);
+        assert!(used.avoids("se9-wireguard"));
+        assert!(!used.avoids("se10-wireguard"));
+
+        for i in 0..MAX_RECENT_RELAYS {
+            used.record(&format!("relay{}", i));
+        }
+        used.set_avoided(usize::MAX);
+        assert_eq!(used.hostnames.len(), MAX_RECENT_RELAYS);
+        assert!(!used.avoids("se9-wireguard"));
+        assert!(used.avoids("relay0"));
+    }
+
+    #[test]
+    fn test_obfuscated_networks() {
+        let mut networks = ObfuscatedNetworks::default();
+        // Timeouts are not attributed to unknown networks
+        assert!(!networks.report_timeout());
+        assert!(!networks.report_timeout());
+
+        networks.set_current(Some("192.168.1.1%wlan0".to_string()));
+        assert!(!networks.report_timeout());
+        networks.reset_timeouts();
+        assert!(!networks.report_timeout());
+        assert!(!networks.is_current_remembered());
+        assert!(networks.report_timeout());
+        assert!(networks.is_current_remembered());
+
+        // Timeouts on other networks do not count towards this one
+        networks.set_current(Some("10.0.0.1%eth0".to_string()));
+        assert!(!networks.is_current_remembered());
+        assert!(!networks.report_timeout());
+        networks.set_current(None);
+        networks.set_current(Some("10.0.0.1%eth0".to_string()));
+        assert!(!networks.report_timeout());
+
+        networks.set_current(Some("192.168.1.1%wlan0".to_string()));
+        assert!(networks.is_current_remembered());
+        for i in 0..MAX_OBFUSCATED_NETWORKS {
+            networks.set_current(Some(format!("network{}", i)));
+            networks.report_timeout();
+            networks.report_timeout();
+        }
+        assert_eq!(networks.fingerprints.len(), MAX_OBFUSCATED_NETWORKS);
+        networks.set_current(Some("192.168.1.1%wlan0".to_string()));
+        assert!(!networks.is_current_remembered());
+    }
+
+    #[test]
+    fn test_relay_stats() {
+        let mut stats = RelayStats::default();
+        stats.record_selected("se9-wireguard");
+        stats.record_failure("se9-wireguard");
+        stats.record_selected("se9-wireguard");
+        stats.record_success("se9-wireguard");
+        stats.record_selected("se10-wireguard");
+
+        assert_eq!(
+            stats.stats["se9-wireguard"],
+            RelayStat {
+                selected: 2,
+                failures: 1,
+                successes: 1,
+            }
+        );
+        assert_eq!(stats.stats["se10-wireguard"].selected, 1);
+
+        for i in 0..MAX_RELAY_STATS - 1 {
+            stats.record_selected(&format!("relay{}", i));
+        }
+        assert_eq!(stats.stats.len(), MAX_RELAY_STATS);
+        assert!(!stats.stats.contains_key("se9-wireguard"));
+        assert!(stats.stats.contains_key("se10-wireguard"));
+    }
+
+    #[test]
+    fn test_escalation_reset() {
+        let mut escalation = Escalation::default();
+        assert_eq!(escalation.attempt(0), 0);
+        assert_eq!(escalation.attempt(5), 5);
+
+        // Changing the constraints restarts escalation on the next attempt
+        escalation.reset();
+        assert_eq!(escalation.attempt(6), 0);
+        assert_eq!(escalation.attempt(7), 1);
+
+        // A new connection attempt counts from zero again
+        assert_eq!(escalation.attempt(0), 0);
+        assert_eq!(escalation.attempt(3), 3);
+    }
+
+    /// Selects the given relay, or fails to select any relay if there is none.
+    struct FixedRelayProvider(Option<Relay>);
+
+    impl RelayProvider for FixedRelayProvider {
+        fn get_relay(
+            &self,
+            _retry_attempt: u32,
+            _escalation_attempt: u32,
+            _constraints_override: Option<RelayConstraintsUpdate>,
+            _udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+        ) -> Result<SelectedRelays, mullvad_relay_selector::Error> {
+            let relay = self
+                .0
+                .clone()
+                .ok_or(mullvad_relay_selector::Error::NoMultihopEntry)?;
+            let endpoint = MullvadEndpoint::Wireguard(wireguard_endpoint(&relay));
+            Ok((
+                SelectedRelay::Normal(NormalSelectedRelay {
+                    exit_relay: relay,
+                    endpoint,
+                    entry_relay: None,
+                }),
+                None,
+                None,
+            ))
+        }
+    }
+
+    #[test]
+    fn test_relay_provider() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_provider = FixedRelayProvider(Some(relay));
+        for retry_attempt in 0..3 {
+            match select_relay(&relay_provider, retry_attempt, retry_attempt, None, None) {
+                Ok((SelectedRelay::Normal(selected), None, None)) => {
+                    assert_eq!(selected.exit_relay.hostname, "se9-wireguard")
+                }
+                _ => panic!("expected the relay of the relay provider"),
+            }
+        }
+
+        assert!(matches!(
+            select_relay(&FixedRelayProvider(None), 0, 0, None, None),
+            Err(Error::NoMultihopEntry)
+        ));
+    }
+
+    fn wireguard_data(ipv4_address: &str) -> WireguardData {
+        WireguardData {
+            private_key: wireguard::PrivateKey::new_from_random(),
+            addresses: AssociatedAddresses {
+                ipv4_address: ipv4_address.parse().unwrap(),
+                ipv6_address: "fc00:bbbb:bbbb:bb01::2/128".parse().unwrap(),
+            },
+            created: Utc::now() - chrono::Duration::days(30),
+            allowed_relays: None,
+            expiry: None,
+        }
+    }
+
+    #[test]
+    fn test_key_expiry() {
+        let now = Utc::now();
+        let mut wg_data = wireguard_data("10.64.0.2/32");
+        // Keys without an expiry are assumed to be valid
+        assert!(check_key_expiry(&wg_data, now).is_ok());
+
+        wg_data.expiry = Some(now + chrono::Duration::days(1));
+        assert!(check_key_expiry(&wg_data, now).is_ok());
+
+        wg_data.expiry = Some(now - chrono::Duration::days(1));
+        assert!(matches!(
+            check_key_expiry(&wg_data, now),
+            Err(Error::KeyExpired)
+        ));
+    }
+
+    /// Provides the given device, or no device if there is none.
+    struct FixedDeviceProvider(Option<PrivateAccountAndDevice>);
+
+    impl DeviceProvider for FixedDeviceProvider {
+        fn device(&self) -> BoxFuture<'_, Option<PrivateAccountAndDevice>> {
+            Box::pin(futures::future::ready(self.0.clone()))
+        }
+
+        fn rotate_key(&self) -> BoxFuture<'_, Result<(), device::Error>> {
+            Box::pin(futures::future::ready(Ok(())))
+        }
+    }
+
+    fn account_and_device(device_id: &str, wg_data: WireguardData) -> PrivateAccountAndDevice {
+        PrivateAccountAndDevice {
+            account_token: "1234123412341234".to_string(),
+            device: PrivateDevice {
+                id: device_id.to_string(),
+                name: format!("device {}", device_id),
+                wg_data,
+                ports: vec![],
+                hijack_dns: false,
+                created: Utc::now(),
+            },
+        }
+    }
+
+    fn logged_in_device() -> FixedDeviceProvider {
+        FixedDeviceProvider(Some(account_and_device(
+            "test-device",
+            wireguard_data("10.64.0.2/32"),
+        )))
+    }
+
+    /// Provides a device whose WireGuard key has expired. Counts how many times the key is rotated.
+    /// A rotation only completes once `finish_rotation` is notified.
+    struct ExpiredKeyProvider {
+        device: PrivateAccountAndDevice,
+        rotations: Arc<AtomicUsize>,
+        finish_rotation: Arc<Notify>,
+    }
+
+    impl DeviceProvider for ExpiredKeyProvider {
+        fn device(&self) -> BoxFuture<'_, Option<PrivateAccountAndDevice>> {
+            Box::pin(futures::future::ready(Some(self.device.clone())))
+        }
+
+        fn rotate_key(&self) -> BoxFuture<'_, Result<(), device::Error>> {
+            self.rotations.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                self.finish_rotation.notified().await;
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_expired_key_once() {
+        let relay_selector = new_relay_selector(relay_list(vec![wireguard_relay(
+            "se9-wireguard",
+            "185.213.154.68",
+        )]));
+        let mut wg_data = wireguard_data("10.64.0.2/32");
+        wg_data.expiry = Some(Utc::now() - chrono::Duration::days(1));
+        let rotations = Arc::new(AtomicUsize::new(0));
+        let finish_rotation = Arc::new(Notify::new());
+        let device_provider = ExpiredKeyProvider {
+            device: account_and_device("test-device", wg_data),
+            rotations: rotations.clone(),
+            finish_rotation: finish_rotation.clone(),
+        };
+        let generator = ParametersGenerator::builder(device_provider, relay_selector).build();
+
+        for retry_attempt in 0..3 {
+            let result = generator
+                .generate_with_overrides(retry_attempt, &GenerateOverrides::default())
+                .await;
+            assert!(matches!(result, Err(Error::KeyExpired)));
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(rotations.load(Ordering::SeqCst), 1);
+
+        // Once the rotation is done, the key is rotated again if it is still expired
+        finish_rotation.notify_one();
+        tokio::task::yield_now().await;
+        let result = generator
+            .generate_with_overrides(3, &GenerateOverrides::default())
+            .await;
+        assert!(matches!(result, Err(Error::KeyExpired)));
+        tokio::task::yield_now().await;
+        assert_eq!(rotations.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_generate_for_device() {
+        let relay_selector = new_relay_selector(relay_list(vec![wireguard_relay(
+            "se9-wireguard",
+            "185.213.154.68",
+        )]));
+        let active = logged_in_device();
+        let active_key = active
+            .0
+            .as_ref()
+            .unwrap()
+            .device
+            .wg_data
+            .private_key
+            .clone();
+        let generator = ParametersGenerator::builder(active, relay_selector).build();
+        let private_key = |parameters: TunnelParameters| match parameters {
+            TunnelParameters::Wireguard(parameters) => parameters.connection.tunnel.private_key,
+            _ => panic!("expected WireGuard tunnel parameters"),
+        };
+
+        let first = account_and_device("first", wireguard_data("10.64.0.3/32"));
+        let second = account_and_device("second", wireguard_data("10.64.0.4/32"));
+        generator.add_device(first.clone()).await;
+        generator.add_device(second.clone()).await;
+
+        for expected in [first, second] {
+            let parameters = generator
+                .generate_for_device(&expected.device.id, 0)
+                .await
+                .unwrap();
+            assert_eq!(private_key(parameters), expected.device.wg_data.private_key);
+        }
+        // Without a device ID, the active device is used
+        let parameters = generator
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await
+            .unwrap();
+        assert_eq!(private_key(parameters), active_key);
+
+        assert!(generator.remove_device(&"first".to_string()).await);
+        assert!(matches!(
+            generator.generate_for_device(&"first".to_string(), 0).await,
+            Err(Error::UnknownDevice(device_id)) if device_id == "first"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_builder() {
+        let relay_selector = new_relay_selector(relay_list(vec![wireguard_relay(
+            "se9-wireguard",
+            "185.213.154.68",
+        )]));
+
+        let mut generator =
+            ParametersGenerator::builder(logged_in_device(), relay_selector.clone()).build();
+        match generator.generate(0).await {
+            Ok(TunnelParameters::Wireguard(parameters)) => assert_eq!(
+                parameters.connection.peer.endpoint.ip(),
+                "185.213.154.68".parse::<IpAddr>().unwrap()
+            ),
+            _ => panic!("expected WireGuard tunnel parameters"),
+        }
+
+        // Nothing can be generated without a device
+        let generator =
+            ParametersGenerator::builder(FixedDeviceProvider(None), relay_selector).build();
+        let result = generator
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await;
+        assert!(matches!(result, Err(Error::NoAuthDetails)));
+    }
+
+    #[tokio::test]
+    async fn test_generate_with_relay_provider() {
+        let relay_selector = new_relay_selector(relay_list(vec![wireguard_relay(
+            "se9-wireguard",
+            "185.213.154.68",
+        )]));
+        let relay = wireguard_relay("se10-wireguard", "185.213.154.69");
+
+        let mut generator = ParametersGenerator::builder(logged_in_device(), relay_selector)
+            .relay_provider(FixedRelayProvider(Some(relay)))
+            .build();
+        match generator.generate(0).await {
+            Ok(TunnelParameters::Wireguard(parameters)) => assert_eq!(
+                parameters.connection.peer.endpoint,
+                "185.213.154.69:51820".parse().unwrap()
+            ),
+            _ => panic!("expected the relay of the relay provider"),
+        }
+        let inner = generator.inner.lock().await;
+        let last_relays = inner.last_generated_relays.as_ref().unwrap();
+        assert_eq!(last_relays.exit().hostname, "se10-wireguard");
+    }
+
+    #[tokio::test]
+    async fn test_generate_with_endpoint_rewrite() {
+        let relay_selector = new_relay_selector(relay_list(vec![wireguard_relay(
+            "se9-wireguard",
+            "185.213.154.68",
+        )]));
+
+        let mut generator = ParametersGenerator::builder(logged_in_device(), relay_selector)
+            .endpoint_rewrite(|endpoint| {
+                SocketAddr::new("127.0.0.1".parse().unwrap(), endpoint.port())
+            })
+            .build();
+        match generator.generate(0).await {
+            Ok(TunnelParameters::Wireguard(parameters)) => assert_eq!(
+                parameters.connection.peer.endpoint.ip(),
+                "127.0.0.1".parse::<IpAddr>().unwrap()
+            ),
+            _ => panic!("expected WireGuard tunnel parameters"),
+        }
+
+        // The real relay is still reported as the last selected relay
+        let inner = generator.inner.lock().await;
+        let last_relays = inner.last_generated_relays.as_ref().unwrap();
+        assert_eq!(last_relays.exit().hostname, "se9-wireguard");
+    }
+
+    /// Counts how many times relays are selected with the wrapped relay provider.
+    struct CountingRelayProvider(FixedRelayProvider, Arc<AtomicUsize>);
+
+    impl RelayProvider for CountingRelayProvider {
+        fn get_relay(
+            &self,
+            retry_attempt: u32,
+            escalation_attempt: u32,
+            constraints_override: Option<RelayConstraintsUpdate>,
+            udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+        ) -> Result<SelectedRelays, mullvad_relay_selector::Error> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.get_relay(
+                retry_attempt,
+                escalation_attempt,
+                constraints_override,
+                udp2tcp_override,
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_reuses_parameters() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_selector = new_relay_selector(relay_list(vec![relay.clone()]));
+        let selections = Arc::new(AtomicUsize::new(0));
+
+        let mut generator = ParametersGenerator::builder(logged_in_device(), relay_selector)
+            .relay_provider(CountingRelayProvider(
+                FixedRelayProvider(Some(relay)),
+                selections.clone(),
+            ))
+            .build();
+        let first = generator.generate(0).await.unwrap();
+        let second = generator.generate(0).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(selections.load(Ordering::SeqCst), 1);
+
+        // Changing the settings selects relays again
+        generator.set_reuse_obfuscator(true).await;
+        generator.generate(0).await.unwrap();
+        assert_eq!(selections.load(Ordering::SeqCst), 2);
+    }
+
+    /// Selects a multihop tunnel through `entry` and `exit`, obfuscated by a udp2tcp obfuscator
+    /// on `obfuscator`.
+    struct ObfuscatedMultihopProvider {
+        entry: Relay,
+        exit: Relay,
+        obfuscator: Relay,
+    }
+
+    impl RelayProvider for ObfuscatedMultihopProvider {
+        fn get_relay(
+            &self,
+            _retry_attempt: u32,
+            _escalation_attempt: u32,
+            _constraints_override: Option<RelayConstraintsUpdate>,
+            _udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+        ) -> Result<SelectedRelays, mullvad_relay_selector::Error> {
+            let mut endpoint = wireguard_endpoint(&self.entry);
+            endpoint.exit_peer = Some(wireguard_endpoint(&self.exit).peer);
+            Ok((
+                SelectedRelay::Normal(NormalSelectedRelay {
+                    exit_relay: self.exit.clone(),
+                    endpoint: MullvadEndpoint::Wireguard(endpoint),
+                    entry_relay: Some(self.entry.clone()),
+                }),
+                None,
+                Some(udp2tcp_obfuscator(&self.obfuscator)),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_multihop_obfuscation_mismatch() {
+        let entry = wireguard_relay("se10-wireguard", "185.213.154.69");
+        let exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_selector = new_relay_selector(relay_list(vec![entry.clone(), exit.clone()]));
+        let generator = |obfuscator: &Relay| {
+            ParametersGenerator::builder(logged_in_device(), relay_selector.clone())
+                .relay_provider(ObfuscatedMultihopProvider {
+                    entry: entry.clone(),
+                    exit: exit.clone(),
+                    obfuscator: obfuscator.clone(),
+                })
+                .build()
+        };
+
+        // Traffic enters through the entry relay, so the obfuscator must run on it
+        let result = generator(&exit)
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::ObfuscationMismatch(hostname)) if hostname == "se10-wireguard"
+        ));
+
+        match generator(&entry).generate(0).await {
+            Ok(TunnelParameters::Wireguard(parameters)) => {
+                assert_eq!(
+                    parameters.obfuscation,
+                    Some(ObfuscatorConfig::Udp2Tcp {
+                        endpoint: "185.213.154.69:443".parse().unwrap()
+                    })
+                );
+                assert!(parameters.connection.exit_peer.is_some());
+            }
+            _ => panic!("expected obfuscated multihop tunnel parameters"),
+        }
+    }
+
+    /// Provides an OpenVPN relay that is reached through a bridge.
+    #[cfg(not(target_os = "android"))]
+    struct OpenVpnBridgeProvider {
+        relay: Relay,
+        bridge: Relay,
+    }
+
+    #[cfg(not(target_os = "android"))]
+    impl RelayProvider for OpenVpnBridgeProvider {
+        fn get_relay(
+            &self,
+            _retry_attempt: u32,
+            _escalation_attempt: u32,
+            _constraints_override: Option<RelayConstraintsUpdate>,
+            _udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+        ) -> Result<SelectedRelays, mullvad_relay_selector::Error> {
+            let settings = talpid_types::net::openvpn::ProxySettings::Local(
+                talpid_types::net::openvpn::LocalProxySettings {
+                    port: 1080,
+                    peer: SocketAddr::new(self.bridge.ipv4_addr_in.into(), 443),
+                },
+            );
+            Ok((
+                SelectedRelay::Normal(NormalSelectedRelay {
+                    exit_relay: self.relay.clone(),
+                    endpoint: MullvadEndpoint::OpenVpn(Endpoint::new(
+                        self.relay.ipv4_addr_in,
+                        1194,
+                        TransportProtocol::Udp,
+                    )),
+                    entry_relay: None,
+                }),
+                Some(SelectedBridge::Normal(
+                    mullvad_relay_selector::NormalSelectedBridge {
+                        settings,
+                        relay: self.bridge.clone(),
+                    },
+                )),
+                None,
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_last_relays() {
+        let entry = wireguard_relay("se10-wireguard", "185.213.154.69");
+        let exit = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_selector = new_relay_selector(relay_list(vec![entry.clone(), exit.clone()]));
+        let mut generator =
+            ParametersGenerator::builder(logged_in_device(), relay_selector.clone())
+                .relay_provider(ObfuscatedMultihopProvider {
+                    entry: entry.clone(),
+                    exit: exit.clone(),
+                    obfuscator: entry.clone(),
+                })
+                .build();
+        assert!(generator.last_relays().await.is_none());
+
+        generator.generate(0).await.unwrap();
+        let relays = generator.last_relays().await.unwrap();
+        assert_eq!(relays.exit.hostname, "se9-wireguard");
+        assert_eq!(relays.entry.unwrap().hostname, "se10-wireguard");
+        assert_eq!(relays.obfuscator.unwrap().hostname, "se10-wireguard");
+        assert!(relays.bridge.is_none());
+
+        #[cfg(not(target_os = "android"))]
+        {
+            let mut relay = wireguard_relay("se-got-001", "185.213.154.131");
+            relay.endpoint_data = RelayEndpointData::Openvpn;
+            let mut bridge = wireguard_relay("se-got-br-001", "185.213.154.132");
+            bridge.endpoint_data = RelayEndpointData::Bridge;
+            let mut generator = ParametersGenerator::builder(logged_in_device(), relay_selector)
+                .relay_provider(OpenVpnBridgeProvider { relay, bridge })
+                .build();
+
+            generator.generate(0).await.unwrap();
+            let relays = generator.last_relays().await.unwrap();
+            assert_eq!(relays.exit.hostname, "se-got-001");
+            assert_eq!(relays.bridge.unwrap().hostname, "se-got-br-001");
+            assert!(relays.entry.is_none());
+            assert!(relays.obfuscator.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_with_pluggable_transport() {
+        let mut relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        if let RelayEndpointData::Wireguard(data) = &mut relay.endpoint_data {
+            data.pluggable_transports = vec!["example".to_string()];
+        }
+        let relay_selector = new_relay_selector(relay_list(vec![relay]));
+        let generator = ParametersGenerator::builder(logged_in_device(), relay_selector).build();
+        let transport = PluggableTransport {
+            name: "example".to_string(),
+            port: 8443,
+            protocol: TransportProtocol::Tcp,
+            parameters: BTreeMap::from([
+                ("cert".to_string(), "AbC+dEf/123=".to_string()),
+                ("iat-mode".to_string(), "0".to_string()),
+            ]),
+        };
+        let with_transport = |transport: &PluggableTransport| GenerateOverrides {
+            pluggable_transport: Some(transport.clone()),
+            ..GenerateOverrides::default()
+        };
+
+        let parameters = generator
+            .generate_with_overrides(0, &with_transport(&transport))
+            .await
+            .unwrap();
+        match parameters {
+            TunnelParameters::Wireguard(wireguard::TunnelParameters {
+                obfuscation:
+                    Some(ObfuscatorConfig::PluggableTransport {
+                        name,
+                        endpoint,
+                        parameters,
+                    }),
+                ..
+            }) => {
+                assert_eq!(name, transport.name);
+                assert_eq!(
+                    endpoint,
+                    Endpoint {
+                        address: "185.213.154.68:8443".parse().unwrap(),
+                        protocol: TransportProtocol::Tcp,
+                    }
+                );
+                assert_eq!(parameters, transport.parameters);
+            }
+            parameters => panic!("unexpected tunnel parameters {:?}", parameters),
+        }
+
+        let other_transport = PluggableTransport {
+            name: "other".to_string(),
+            ..transport
+        };
+        assert!(matches!(
+            generator
+                .generate_with_overrides(0, &with_transport(&other_transport))
+                .await,
+            Err(Error::UnsupportedPluggableTransport { transport, relay })
+                if transport == "other" && relay == "se9-wireguard"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_generate_with_udp2tcp_override() {
+        let mut relay_list = relay_list(vec![wireguard_relay("se9-wireguard", "185.213.154.68")]);
+        relay_list.wireguard.port_ranges = vec![(53, 53), (4000, 33433)];
+        let mut relay_selector = new_relay_selector(relay_list);
+        let mut config = relay_selector.config();
+        config.obfuscation_settings.selected_obfuscation = SelectedObfuscation::Udp2Tcp;
+        config.obfuscation_settings.udp2tcp.port = Constraint::Only(80);
+        relay_selector.set_config(config);
+        let generator = ParametersGenerator::builder(logged_in_device(), relay_selector).build();
+        let udp2tcp_port = |parameters: TunnelParameters| match parameters {
+            TunnelParameters::Wireguard(wireguard::TunnelParameters {
+                obfuscation: Some(ObfuscatorConfig::Udp2Tcp { endpoint }),
+                ..
+            }) => endpoint.port(),
+            _ => panic!("expected udp2tcp obfuscated tunnel parameters"),
+        };
+
+        let overrides = GenerateOverrides {
+            udp2tcp: Some(Udp2TcpObfuscationSettings {
+                port: Constraint::Only(5001),
+            }),
+            ..GenerateOverrides::default()
+        };
+        let parameters = generator
+            .generate_with_overrides(0, &overrides)
+            .await
+            .unwrap();
+        assert_eq!(udp2tcp_port(parameters), 5001);
+        // The obfuscation that was actually used is recorded
+        match &generator.inner.lock().await.last_generated_relays {
+            Some(LastSelectedRelays::WireGuard {
+                obfuscation: Some(ObfuscatorConfig::Udp2Tcp { endpoint }),
+                ..
+            }) => assert_eq!(endpoint.port(), 5001),
+            _ => panic!("expected the udp2tcp obfuscation to be recorded"),
+        }
+
+        // The override only applies to a single call
+        let parameters = generator
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await
+            .unwrap();
+        assert_eq!(udp2tcp_port(parameters), 80);
+    }
+
+    /// Returns a relay selector that can only select OpenVPN relays.
+    #[cfg(not(target_os = "android"))]
+    fn openvpn_relay_selector() -> RelaySelector {
+        let mut relay = wireguard_relay("se-got-001", "185.213.154.131");
+        relay.endpoint_data = RelayEndpointData::Openvpn;
+        let mut relay_list = relay_list(vec![relay]);
+        relay_list.openvpn.ports = vec![OpenVpnEndpoint {
+            port: 1194,
+            protocol: TransportProtocol::Udp,
+        }];
+        let mut relay_selector = new_relay_selector(relay_list);
+        let mut config = relay_selector.config();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::OpenVpn),
+            ..RelayConstraints::default()
+        });
+        relay_selector.set_config(config);
+        relay_selector
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_generate_with_fwmark() {
+        let fwmark = |parameters: TunnelParameters| match parameters {
+            TunnelParameters::Wireguard(parameters) => parameters.connection.fwmark,
+            TunnelParameters::OpenVpn(parameters) => Some(parameters.fwmark),
+        };
+        let overrides = GenerateOverrides {
+            fwmark: Some(0x1234),
+            ..GenerateOverrides::default()
+        };
+        let wireguard_relay_selector = new_relay_selector(relay_list(vec![wireguard_relay(
+            "se9-wireguard",
+            "185.213.154.68",
+        )]));
+
+        for relay_selector in [wireguard_relay_selector, openvpn_relay_selector()] {
+            let generator =
+                ParametersGenerator::builder(logged_in_device(), relay_selector).build();
+            let parameters = generator
+                .generate_with_overrides(0, &overrides)
+                .await
+                .unwrap();
+            assert_eq!(fwmark(parameters), Some(0x1234));
+
+            // The override only applies to a single call
+            let parameters = generator
+                .generate_with_overrides(0, &GenerateOverrides::default())
+                .await
+                .unwrap();
+            assert_eq!(fwmark(parameters), Some(mullvad_types::TUNNEL_FWMARK));
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    #[tokio::test]
+    async fn test_generate_with_openvpn_cipher() {
+        let generator =
+            ParametersGenerator::builder(logged_in_device(), openvpn_relay_selector()).build();
+        let cipher = |parameters: TunnelParameters| match parameters {
+            TunnelParameters::OpenVpn(parameters) => parameters.options.cipher,
+            _ => panic!("expected OpenVPN tunnel parameters"),
+        };
+        let with_cipher = |cipher: &str| GenerateOverrides {
+            openvpn_cipher: Some(cipher.to_string()),
+            ..GenerateOverrides::default()
+        };
+
+        let parameters = generator
+            .generate_with_overrides(0, &with_cipher("AES-256-CBC"))
+            .await
+            .unwrap();
+        assert_eq!(cipher(parameters), Some("AES-256-CBC".to_string()));
+
+        assert!(matches!(
+            generator
+                .generate_with_overrides(0, &with_cipher("BF-CBC"))
+                .await,
+            Err(Error::UnsupportedCipher(cipher)) if cipher == "BF-CBC"
+        ));
+
+        // The override only applies to a single call
+        let parameters = generator
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await
+            .unwrap();
+        assert_eq!(cipher(parameters), None);
+    }
+
+    #[test]
+    fn test_protocol_weights() {
+        let mut rng = StdRng::seed_from_u64(358);
+        let preference = ProtocolPreference::Weighted {
+            wireguard: 90,
+            openvpn: 10,
+        };
+        let wireguard_count = (0..10_000)
+            .filter(|_| choose_protocol(preference, false, &mut rng) == TunnelType::Wireguard)
+            .count();
+        if cfg!(target_os = "android") {
+            assert_eq!(wireguard_count, 10_000);
+        } else {
+            assert!(
+                (8_800..=9_200).contains(&wireguard_count),
+                "WireGuard was chosen {} times out of 10000",
+                wireguard_count
+            );
+        }
+
+        let only_wireguard = ProtocolPreference::Weighted {
+            wireguard: 1,
+            openvpn: 0,
+        };
+        let no_weights = ProtocolPreference::Weighted {
+            wireguard: 0,
+            openvpn: 0,
+        };
+        for _ in 0..100 {
+            assert_eq!(
+                choose_protocol(only_wireguard, true, &mut rng),
+                TunnelType::Wireguard
+            );
+            assert_eq!(
+                choose_protocol(no_weights, false, &mut rng),
+                TunnelType::Wireguard
+            );
+        }
+    }
+
+    #[test]
+    fn test_wireguard_unless_failed() {
+        let mut rng = StdRng::seed_from_u64(358);
+        let preference = ProtocolPreference::WireguardUnlessFailed;
+        assert_eq!(
+            choose_protocol(preference, false, &mut rng),
+            TunnelType::Wireguard
+        );
+        let expected = if cfg!(target_os = "android") {
+            TunnelType::Wireguard
+        } else {
+            TunnelType::OpenVpn
+        };
+        assert_eq!(choose_protocol(preference, true, &mut rng), expected);
+    }
+
+    #[test]
+    fn test_exhausted_candidates() {
+        let mut picks = 0;
+        let result = pick_unvetoed(
+            10,
+            || {
+                picks += 1;
+                Ok(picks)
+            },
+            |_| true,
+            |_| false,
+        );
+        assert!(matches!(result, Err(Error::ExhaustedCandidates)));
+        assert_eq!(picks, 10);
+
+        let result = pick_unvetoed(10, || Ok(3), |candidate| *candidate != 3, |_| false);
+        assert!(matches!(result, Ok(3)));
+
+        // The first discouraged candidate is used if no other candidate is left
+        let mut picks = 0;
+        let result = pick_unvetoed(
+            10,
+            || {
+                picks += 1;
+                Ok(picks)
+            },
+            |candidate| *candidate < 4,
+            |_| true,
+        );
+        assert!(matches!(result, Ok(4)));
+        assert_eq!(picks, 10);
+
+        let mut picks = 0;
+        assert!(!take_pick(&mut picks));
+        picks = 1;
+        assert!(take_pick(&mut picks));
+        assert_eq!(picks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_generate_requires_obfuscation() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_selector = new_relay_selector(relay_list(vec![relay.clone()]));
+        let mut tunnel_options = TunnelOptions::default();
+        tunnel_options.wireguard.require_obfuscation = true;
+        // The relay provider never selects an obfuscator
+        let generator = ParametersGenerator::builder(logged_in_device(), relay_selector)
+            .tunnel_options(tunnel_options)
+            .relay_provider(FixedRelayProvider(Some(relay)))
+            .build();
+
+        let result = generator
+            .generate_with_overrides(0, &GenerateOverrides::default())
+            .await;
+        assert!(matches!(result, Err(Error::ObfuscationRequired)));
+    }
+
+    #[tokio::test]
+    async fn test_generate_exhausts_candidates() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let relay_selector = new_relay_selector(relay_list(vec![relay.clone()]));
+        let selections = Arc::new(AtomicUsize::new(0));
+        let tunnel_options = TunnelOptions {
+            max_repicks: 3,
+            ..TunnelOptions::default()
+        };
+        let mut generator = ParametersGenerator::builder(logged_in_device(), relay_selector)
+            .tunnel_options(tunnel_options)
+            .relay_provider(CountingRelayProvider(
+                FixedRelayProvider(Some(relay)),
+                selections.clone(),
+            ))
+            .build();
+
+        generator.generate(0).await.unwrap();
+        assert_eq!(selections.swap(0, Ordering::SeqCst), 1);
+
+        // The only relay failed, so every candidate of the retry is vetoed
+        let result = generator
+            .generate_with_overrides(1, &GenerateOverrides::default())
+            .await;
+        assert!(matches!(result, Err(Error::ExhaustedCandidates)));
+        assert_eq!(selections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_cached_parameters() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let parameters = wireguard_parameters(&relay, None);
+        let inputs = |retry_attempt| GenerationInputs {
+            retry_attempt,
+            selector_config: SelectorConfig {
+                relay_settings: RelaySettings::Normal(RelayConstraints::default()),
+                bridge_state: BridgeState::Auto,
+                bridge_settings: BridgeSettings::Normal(BridgeConstraints::default()),
+                obfuscation_settings: ObfuscationSettings::default(),
+                custom_lists: CustomListsSettings::default(),
+                escalation_ladder: EscalationLadder::default(),
+                default_tunnel_type: TunnelType::Wireguard,
+            },
+            relays_last_updated: SystemTime::UNIX_EPOCH,
+            account_token: "1234".to_string(),
+            device_key: relay
+                .endpoint_data
+                .unwrap_wireguard_ref()
+                .public_key
+                .clone(),
+            allowed_relays: None,
+            excluded_hostnames: vec![],
+        };
+
+        let mut cache = CachedParameters::default();
+        assert_eq!(cache.get(&inputs(1)), None);
+        cache.store(inputs(1), parameters.clone());
+
+        // Identical calls return the same parameters without selecting a relay again
+        assert_eq!(cache.get(&inputs(1)), Some(parameters.clone()));
+        assert_eq!(cache.get(&inputs(1)), Some(parameters));
+
+        let mut changed_inputs = inputs(1);
+        changed_inputs.relays_last_updated = SystemTime::now();
+        assert_eq!(cache.get(&changed_inputs), None);
+        assert_eq!(cache.get(&inputs(2)), None);
+
+        cache.clear();
+        assert_eq!(cache.get(&inputs(1)), None);
+    }
+
+    #[test]
+    fn test_discovered_mtu() {
+        let mut path_mtu = DiscoveredMtu::default();
+        assert_eq!(path_mtu.mtu_for("se9-wireguard", None), None);
+        assert_eq!(path_mtu.mtu_for("se9-wireguard", Some(1380)), Some(1380));
+
+        path_mtu.report("se9-wireguard".to_string(), 1280);
+        assert_eq!(path_mtu.mtu_for("se9-wireguard", Some(1380)), Some(1280));
+        assert_eq!(path_mtu.mtu_for("se9-wireguard", None), Some(1280));
+
+        // Switching exit relays resets the MTU to the configured one
+        assert_eq!(path_mtu.mtu_for("se10-wireguard", None), None);
+        assert_eq!(path_mtu.mtu_for("se9-wireguard", Some(1380)), Some(1380));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_custom_relay_resolution() {
+        let cancel = Arc::new(Notify::new());
+
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            canceller.notify_waiters();
+        });
+
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            resolve_custom_relay(
+                || {
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                    Err(custom_tunnel::Error::HostHasNoIpv4(
+                        "slow.example".to_owned(),
+                    ))
+                },
+                &cancel,
+            ),
+        )
+        .await
+        .expect("cancellation did not abort the resolution in time");
+
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+
+    fn relay_list(relays: Vec<Relay>) -> RelayList {
+        RelayList {
+            countries: vec![RelayListCountry {
+                name: "Sweden".to_string(),
+                code: "se".to_string(),
+                cities: vec![RelayListCity {
+                    name: "Gothenburg".to_string(),
+                    code: "got".to_string(),
+                    latitude: 57.70887,
+                    longitude: 11.97456,
+                    relays,
+                }],
+            }],
+            ..RelayList::empty()
+        }
+    }
+
+    fn new_relay_selector(relay_list: RelayList) -> RelaySelector {
+        let config = SelectorConfig {
+            relay_settings: RelaySettings::Normal(RelayConstraints::default()),
+            bridge_state: BridgeState::Auto,
+            bridge_settings: BridgeSettings::Normal(BridgeConstraints::default()),
+            obfuscation_settings: ObfuscationSettings::default(),
+            custom_lists: CustomListsSettings::default(),
+            escalation_ladder: EscalationLadder::default(),
+            default_tunnel_type: TunnelType::Wireguard,
+        };
+        RelaySelector::from_relay_list(config, relay_list)
+    }
+
+    #[test]
+    fn test_reuse_obfuscator() {
+        let relays = vec![
+            wireguard_relay("se9-wireguard", "185.213.154.68"),
+            wireguard_relay("se10-wireguard", "185.213.154.69"),
+        ];
+        let udp2tcp_selector = |relays: Vec<Relay>| {
+            let mut relay_list = relay_list(relays);
+            relay_list.wireguard.port_ranges = vec![(53, 53), (4000, 33433)];
+            let mut relay_selector = new_relay_selector(relay_list);
+            let mut config = relay_selector.config();
+            config.obfuscation_settings.selected_obfuscation = SelectedObfuscation::Udp2Tcp;
+            relay_selector.set_config(config);
+            relay_selector
+        };
+
+        let relay_selector = udp2tcp_selector(relays.clone());
+        let (_, _, obfuscator) = select_relay(&relay_selector, 0, 0, None, None).unwrap();
+        let obfuscator = obfuscator.expect("expected an obfuscator").relay;
+        let constraints = relay_selector.effective_constraints(None);
+        for _ in 0..10 {
+            let (_, _, reused) = reuse_obfuscator(
+                &relay_selector,
+                constraints.clone(),
+                None,
+                &obfuscator,
+                (0, 0),
+                None,
+            )
+            .expect("expected the obfuscator to be reused");
+            assert_eq!(reused.unwrap().relay.hostname, obfuscator.hostname);
+        }
+
+        // Fall back to normal selection once the obfuscator is offline
+        let relays = relays
+            .into_iter()
+            .map(|mut relay| {
+                relay.active = relay.hostname != obfuscator.hostname;
+                relay
+            })
+            .collect();
+        let relay_selector = udp2tcp_selector(relays);
+        assert!(reuse_obfuscator(
+            &relay_selector,
+            relay_selector.effective_constraints(None),
+            None,
+            &obfuscator,
+            (0, 0),
+            None,
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_sticky_relay() {
+        let relays = vec![
+            wireguard_relay("se9-wireguard", "185.213.154.68"),
+            wireguard_relay("se10-wireguard", "185.213.154.69"),
+        ];
+        let mut relay_list = relay_list(relays.clone());
+        relay_list.wireguard.port_ranges = vec![(53, 53), (4000, 33433)];
+        let relay_selector = new_relay_selector(relay_list);
+        let constraints = relay_selector.effective_constraints(None);
+        let sticky = match select_relay(&relay_selector, 0, 0, None, None).unwrap() {
+            (SelectedRelay::Normal(relay), ..) => relay.exit_relay,
+            (SelectedRelay::Custom(_), ..) => unreachable!("Expected a normal relay"),
+        };
+        let other = relays
+            .iter()
+            .find(|relay| relay.hostname != sticky.hostname)
+            .unwrap();
+        for attempt in 0..10 {
+            let (relay, ..) = reuse_exit_relay(
+                &relay_selector,
+                constraints.clone(),
+                None,
+                &sticky,
+                (attempt, attempt),
+                None,
+            )
+            .expect("expected the exit relay to be reused");
+            match relay {
+                SelectedRelay::Normal(relay) => {
+                    assert_eq!(relay.exit_relay.hostname, sticky.hostname)
+                }
+                SelectedRelay::Custom(_) => unreachable!("Expected a normal relay"),
+            }
+        }
+
+        // The relay is only reused until it has failed twice in a row
+        let mut sticky_relay = StickyRelay::new(2);
+        sticky_relay.report_success(&sticky);
+        sticky_relay.report_failure(&other.hostname);
+        sticky_relay.report_failure(&sticky.hostname);
+        assert!(sticky_relay.relay.is_some());
+        sticky_relay.report_success(&sticky);
+        sticky_relay.report_failure(&sticky.hostname);
+        assert!(sticky_relay.relay.is_some());
+        sticky_relay.report_failure(&sticky.hostname);
+        assert!(sticky_relay.relay.is_none());
+    }
+
+    #[test]
+    fn test_protocol_ports() {
+        let mut openvpn_relay = wireguard_relay("se-got-ovpn-001", "185.213.154.131");
+        openvpn_relay.endpoint_data = RelayEndpointData::Openvpn;
+        let mut relay_list = relay_list(vec![
+            wireguard_relay("se9-wireguard", "185.213.154.68"),
+            openvpn_relay,
+        ]);
+        relay_list.wireguard.port_ranges = vec![(53, 53), (4000, 33433)];
+        relay_list.openvpn.ports = vec![
+            OpenVpnEndpoint {
+                port: 1194,
+                protocol: TransportProtocol::Udp,
+            },
+            OpenVpnEndpoint {
+                port: 443,
+                protocol: TransportProtocol::Tcp,
+            },
+            OpenVpnEndpoint {
+                port: 80,
+                protocol: TransportProtocol::Tcp,
+            },
+        ];
+        let mut relay_selector = new_relay_selector(relay_list);
+        let ports = ProtocolPorts {
+            wireguard: Constraint::Only(53),
+            openvpn: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Tcp,
+                port: Constraint::Only(443),
+            }),
+        };
+        let mut select = |tunnel_protocol, ports: &ProtocolPorts| {
+            let mut config = relay_selector.config();
+            config.relay_settings = RelaySettings::Normal(RelayConstraints {
+                tunnel_protocol: Constraint::Only(tunnel_protocol),
+                ..RelayConstraints::default()
+            });
+            relay_selector.set_config(config);
+            let constraints_override =
+                apply_protocol_ports(relay_selector.effective_constraints(None), None, ports);
+            select_relay(&relay_selector, 0, 0, constraints_override, None)
+        };
+
+        for _ in 0..10 {
+            match select(TunnelType::Wireguard, &ports) {
+                Ok((SelectedRelay::Normal(relay), ..)) => match relay.endpoint {
+                    MullvadEndpoint::Wireguard(endpoint) => {
+                        assert_eq!(endpoint.peer.endpoint.port(), 53)
+                    }
+                    endpoint => panic!("expected a WireGuard endpoint, got {:?}", endpoint),
+                },
+                result => panic!("expected a normal relay, got {:?}", result),
+            }
+            match select(TunnelType::OpenVpn, &ports) {
+                Ok((SelectedRelay::Normal(relay), ..)) => match relay.endpoint {
+                    MullvadEndpoint::OpenVpn(endpoint) => {
+                        assert_eq!(endpoint.address.port(), 443);
+                        assert_eq!(endpoint.protocol, TransportProtocol::Tcp);
+                    }
+                    endpoint => panic!("expected an OpenVPN endpoint, got {:?}", endpoint),
+                },
+                result => panic!("expected a normal relay, got {:?}", result),
+            }
+        }
+
+        // No relay accepts OpenVPN over UDP on port 443
+        let ports = ProtocolPorts {
+            openvpn: Constraint::Only(TransportPort {
+                protocol: TransportProtocol::Udp,
+                port: Constraint::Only(443),
+            }),
+            ..ports
+        };
+        assert!(matches!(
+            select(TunnelType::OpenVpn, &ports),
+            Err(Error::NoRelayAvailable)
+        ));
+
+        assert!(!relay_selector.has_matching_relay(apply_protocol_ports(
+            relay_selector.effective_constraints(None),
+            None,
+            &ports
+        )));
+
+        // Custom relays are not affected
+        assert!(apply_protocol_ports(None, None, &ports).is_none());
+    }
+
+    #[test]
+    fn test_preview_relays() {
+        let mut relay_list = relay_list(vec![wireguard_relay("se9-wireguard", "185.213.154.68")]);
+        relay_list.wireguard.port_ranges = vec![(53, 53), (4000, 33433)];
+        let relay_selector = new_relay_selector(relay_list);
+
+        let previews = preview_relays(3, |attempt| {
+            select_relay(&relay_selector, attempt, attempt, None, None)
+        });
+        assert_eq!(previews.len(), 3);
+        for preview in previews {
+            assert_eq!(preview.tunnel_type, TunnelType::Wireguard);
+            assert_eq!(preview.exit, "se9-wireguard");
+            assert_eq!(preview.entry, None);
+        }
+
+        let unmatched = RelayConstraintsUpdate {
+            location: Some(Constraint::Only(LocationConstraint::Country(
+                "no".to_string(),
+            ))),
+            ..RelayConstraintsUpdate::default()
+        };
+        let previews = preview_relays(3, |attempt| {
+            select_relay(
+                &relay_selector,
+                attempt,
+                attempt,
+                Some(unmatched.clone()),
+                None,
+            )
+        });
+        assert!(previews.is_empty());
+    }
+
+    #[test]
+    fn test_self_test() {
+        let mut openvpn_relay = wireguard_relay("no-osl-ovpn-001", "91.90.44.10");
+        openvpn_relay.endpoint_data = RelayEndpointData::Openvpn;
+        let mut relay_list = relay_list(vec![wireguard_relay("se9-wireguard", "185.213.154.68")]);
+        relay_list.wireguard.port_ranges = vec![(53, 53), (4000, 33433)];
+        relay_list.countries.push(RelayListCountry {
+            name: "Norway".to_string(),
+            code: "no".to_string(),
+            cities: vec![RelayListCity {
+                name: "Oslo".to_string(),
+                code: "osl".to_string(),
+                latitude: 59.91273,
+                longitude: 10.74609,
+                relays: vec![openvpn_relay],
+            }],
+        });
+        let mut relay_selector = new_relay_selector(relay_list);
+        let mut config = relay_selector.config();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        });
+        relay_selector.set_config(config);
+
+        let report = self_test(
+            &relay_selector,
+            &relay_selector.clone().get_locations(),
+            None,
+            &TunnelOptions::default(),
+        );
+        assert_eq!(report.passed, 2);
+        let failed_locations: Vec<_> = report
+            .failures
+            .iter()
+            .map(|failure| failure.location.clone())
+            .collect();
+        assert_eq!(
+            failed_locations,
+            vec![
+                LocationConstraint::Country("no".to_string()),
+                LocationConstraint::City("no".to_string(), "osl".to_string()),
+            ]
+        );
+        assert!(report
+            .failures
+            .iter()
+            .all(|failure| !failure.reason.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_relays() {
+        let relays = vec![wireguard_relay("se9-wireguard", "185.213.154.68")];
+
+        // Already loaded
+        let relay_selector = new_relay_selector(relay_list(relays.clone()));
+        assert!(wait_for_relays(&relay_selector, Duration::ZERO)
+            .await
+            .is_ok());
+
+        // Loaded after a delay
+        let relay_selector = new_relay_selector(RelayList::empty());
+        assert!(!relay_selector.has_relays());
+        let updater = relay_selector.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            updater.set_relays(relay_list(relays));
+        });
+        assert!(wait_for_relays(&relay_selector, Duration::from_secs(5))
+            .await
+            .is_ok());
+
+        // Never loaded
+        let relay_selector = new_relay_selector(RelayList::empty());
+        assert!(matches!(
+            wait_for_relays(&relay_selector, Duration::from_millis(200)).await,
+            Err(Error::RelayListUnavailable)
+        ));
+    }
+
+    #[test]
+    fn test_relay_restrictions_are_restored() {
+        let relay_selector = new_relay_selector(relay_list(vec![
+            wireguard_relay("se9-wireguard", "185.213.154.68"),
+            wireguard_relay("se10-wireguard", "185.213.154.69"),
+        ]));
+        let selected_exits = || {
+            (0..20)
+                .map(|_| {
+                    let (relay, _, _) = select_relay(&relay_selector, 0, 0, None, None).unwrap();
+                    match relay {
+                        SelectedRelay::Normal(relay) => relay.exit_relay.hostname,
+                        SelectedRelay::Custom(_) => unreachable!(),
+                    }
+                })
+                .collect::<HashSet<_>>()
+        };
+
+        {
+            let _restrictions = RelayRestrictions::apply(
+                &relay_selector,
+                Some(HashSet::from(["se9-wireguard".to_string()])),
+                HashSet::new(),
+            );
+            assert_eq!(
+                selected_exits(),
+                HashSet::from(["se9-wireguard".to_string()])
+            );
+        }
+        assert_eq!(relay_selector.allowed_relays(), None);
+
+        {
+            let _restrictions = RelayRestrictions::apply(
+                &relay_selector,
+                None,
+                HashSet::from(["se9-wireguard".to_string()]),
+            );
+            assert_eq!(
+                selected_exits(),
+                HashSet::from(["se10-wireguard".to_string()])
+            );
+        }
+        assert!(relay_selector.excluded_relays().is_empty());
+        assert_eq!(selected_exits().len(), 2);
+    }
+
     #[cfg(not(target_os = "android"))]
-    OpenVpn { relay: Relay, bridge: Option<Relay> },
+    #[test]
+    fn test_openvpn_cipher() {
+        let mut options = openvpn::TunnelOptions::default();
+        assert!(validate_openvpn_cipher(&options).is_ok());
+
+        options.cipher = Some("AES-256-GCM".to_string());
+        assert!(validate_openvpn_cipher(&options).is_ok());
+
+        options.cipher = Some("BF-CBC".to_string());
+        assert!(matches!(
+            validate_openvpn_cipher(&options),
+            Err(Error::UnsupportedCipher(cipher)) if cipher == "BF-CBC"
+        ));
+    }
+
+    #[test]
+    fn test_split_dns() {
+        assert!(validate_split_dns(&[]).is_ok());
+
+        let rules = [
+            SplitDnsRule {
+                domains: vec!["corp.example.com".to_owned(), "internal.".to_owned()],
+                resolvers: vec!["10.0.0.53".parse().unwrap()],
+            },
+            SplitDnsRule {
+                domains: vec!["lan".to_owned()],
+                resolvers: vec!["192.168.1.1".parse().unwrap(), "fd00::53".parse().unwrap()],
+            },
+        ];
+        assert!(validate_split_dns(&rules).is_ok());
+
+        let rules = [SplitDnsRule {
+            domains: vec!["bad_domain..example".to_owned()],
+            resolvers: vec!["10.0.0.53".parse().unwrap()],
+        }];
+        assert!(matches!(
+            validate_split_dns(&rules),
+            Err(Error::InvalidSplitDnsRule(_))
+        ));
+    }
+
+    #[test]
+    fn test_ip_version_preference() {
+        let mut relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        relay.ipv6_addr_in = Some("2a03:1b20:5:f011::a09f".parse().unwrap());
+        let ipv4_addr: IpAddr = "185.213.154.68".parse().unwrap();
+        let ipv6_addr: IpAddr = "2a03:1b20:5:f011::a09f".parse().unwrap();
+
+        let mut peer = wireguard_endpoint(&relay).peer;
+        apply_ip_version_preference(&mut peer, &relay, Constraint::Only(IpVersion::V6));
+        assert_eq!(peer.endpoint.ip(), ipv6_addr);
+
+        apply_ip_version_preference(&mut peer, &relay, Constraint::Only(IpVersion::V4));
+        assert_eq!(peer.endpoint.ip(), ipv4_addr);
+
+        apply_ip_version_preference(&mut peer, &relay, Constraint::Any);
+        assert_eq!(peer.endpoint.ip(), ipv4_addr);
+
+        let mut peer = wireguard_endpoint(&relay).peer;
+        peer.endpoint.set_ip(ipv6_addr);
+        apply_ip_version_preference(&mut peer, &relay, Constraint::Any);
+        assert_eq!(peer.endpoint.ip(), ipv6_addr);
+
+        relay.ipv6_addr_in = None;
+        apply_ip_version_preference(&mut peer, &relay, Constraint::Only(IpVersion::V6));
+        assert_eq!(peer.endpoint.ip(), ipv4_addr);
+    }
+
+    #[test]
+    fn test_wireguard_implementation() {
+        let relay = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let connection = |implementation| {
+            let mut parameters = match wireguard_parameters(&relay, None) {
+                TunnelParameters::Wireguard(parameters) => parameters,
+                TunnelParameters::OpenVpn(_) => unreachable!(),
+            };
+            apply_wireguard_implementation(&mut parameters.connection, implementation);
+            parameters.connection
+        };
+
+        let kernel = connection(WireguardImplementation::Kernel);
+        assert_eq!(
+            kernel.peer.allowed_ips,
+            talpid_types::net::all_of_the_internet()
+        );
+
+        let userspace = connection(WireguardImplementation::Userspace);
+        let expected: Vec<IpNetwork> = ["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"]
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect();
+        assert_eq!(userspace.peer.allowed_ips, expected);
+        assert_eq!(userspace.peer.endpoint, kernel.peer.endpoint);
+        assert_eq!(userspace.ipv4_gateway, kernel.ipv4_gateway);
+    }
+
+    #[test]
+    fn test_entry_obfuscation_match() {
+        let entry = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let endpoint = wireguard_endpoint(&entry);
+
+        assert!(validate_entry_obfuscation(&entry, &endpoint, &udp2tcp_obfuscator(&entry)).is_ok());
+    }
+
+    #[test]
+    fn test_entry_obfuscation_mismatch() {
+        let wireguard_entry = wireguard_relay("se9-wireguard", "185.213.154.68");
+        let endpoint = wireguard_endpoint(&wireguard_entry);
+
+        // An OpenVPN relay cannot act as the entry of an obfuscated WireGuard tunnel
+        let openvpn_entry = Relay {
+            hostname: "se-got-001".to_string(),
+            endpoint_data: RelayEndpointData::Openvpn,
+            ..wireguard_entry.clone()
+        };
+        assert!(matches!(
+            validate_entry_obfuscation(&openvpn_entry, &endpoint, &udp2tcp_obfuscator(&openvpn_entry)),
+            Err(Error::ObfuscationMismatch(hostname)) if hostname == "se-got-001"
+        ));
+
+        // The obfuscator must point at the entry relay
+        let other = wireguard_relay("se10-wireguard", "185.213.154.69");
+        assert!(matches!(
+            validate_entry_obfuscation(&wireguard_entry, &endpoint, &udp2tcp_obfuscator(&other)),
+            Err(Error::ObfuscationMismatch(_))
+        ));
+    }
 }
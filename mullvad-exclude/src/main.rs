@@ -1,37 +1,58 @@
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use nix::unistd::{execvp, getuid, setgid, setuid};
 #[cfg(target_os = "linux")]
-use nix::unistd::{execvp, getgid, getpid, getuid, setgid, setuid};
-#[cfg(target_os = "linux")]
+use nix::unistd::{getgid, getpid};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 use std::fmt::Write as _;
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 use std::{
     convert::Infallible,
     env,
     error::Error as StdError,
     ffi::{CString, NulError},
+    os::unix::ffi::OsStrExt,
+};
+#[cfg(target_os = "linux")]
+use std::{
     fs,
     io::{self, BufWriter, Write},
-    os::unix::ffi::OsStrExt,
 };
 
 #[cfg(target_os = "linux")]
 use talpid_types::cgroup::{find_net_cls_mount, SPLIT_TUNNEL_CGROUP_NAME};
+#[cfg(target_os = "macos")]
+use talpid_types::split_tunnel::SPLIT_TUNNEL_GROUP_NAME;
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 const PROGRAM_NAME: &str = "mullvad-exclude";
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 #[derive(err_derive::Error, Debug)]
 #[error(no_from)]
 enum Error {
     #[error(display = "Invalid arguments")]
     InvalidArguments,
 
+    #[cfg(target_os = "linux")]
     #[error(display = "Cannot set the cgroup")]
     AddProcToCGroup(#[error(source)] io::Error),
 
+    #[cfg(target_os = "macos")]
+    #[error(display = "Failed to look up the exclusion group")]
+    FindExclusionGroup(#[error(source)] nix::Error),
+
+    #[cfg(target_os = "macos")]
+    #[error(display = "The exclusion group does not exist")]
+    NoExclusionGroup,
+
+    #[cfg(target_os = "macos")]
+    #[error(display = "Failed to join the exclusion group")]
+    SetExclusionGroup(#[error(source)] nix::Error),
+
     #[error(display = "Failed to drop root user privileges for the process")]
     DropRootUid(#[error(source)] nix::Error),
 
+    #[cfg(target_os = "linux")]
     #[error(display = "Failed to drop root group privileges for the process")]
     DropRootGid(#[error(source)] nix::Error),
 
@@ -41,15 +62,17 @@ enum Error {
     #[error(display = "An argument contains interior nul bytes")]
     ArgumentNulError(#[error(source)] NulError),
 
+    #[cfg(target_os = "linux")]
     #[error(display = "Failed to find net_cls controller")]
     FindNetClsController(#[error(source)] io::Error),
 
+    #[cfg(target_os = "linux")]
     #[error(display = "No net_cls controller")]
     NoNetClsController,
 }
 
 fn main() {
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
     match run() {
         Err(Error::InvalidArguments) => {
             let mut args = env::args();
@@ -72,7 +95,7 @@ fn main() {
     }
 }
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 fn run() -> Result<Infallible, Error> {
     let mut args_iter = env::args_os().skip(1);
     let program = args_iter.next().ok_or(Error::InvalidArguments)?;
@@ -84,6 +107,16 @@ fn run() -> Result<Infallible, Error> {
         .collect::<Result<Vec<CString>, NulError>>()
         .map_err(Error::ArgumentNulError)?;
 
+    exclude_process()?;
+
+    // Launch the process
+    execvp(&program, &args).map_err(Error::Exec)
+}
+
+/// Adds the current process to the cgroup whose traffic is excluded from the tunnel, and drops
+/// root privileges.
+#[cfg(target_os = "linux")]
+fn exclude_process() -> Result<(), Error> {
     let cgroup_dir = find_net_cls_mount()
         .map_err(Error::FindNetClsController)?
         .ok_or(Error::NoNetClsController)?;
@@ -108,6 +141,22 @@ fn run() -> Result<Infallible, Error> {
     let real_gid = getgid();
     setgid(real_gid).map_err(Error::DropRootGid)?;
 
-    // Launch the process
-    execvp(&program, &args).map_err(Error::Exec)
+    Ok(())
+}
+
+/// Switches to the group whose traffic is excluded from the tunnel, and drops root privileges.
+/// Unlike the cgroup on Linux, the group is what identifies excluded traffic, so the real group
+/// is not restored.
+#[cfg(target_os = "macos")]
+fn exclude_process() -> Result<(), Error> {
+    let group = nix::unistd::Group::from_name(SPLIT_TUNNEL_GROUP_NAME)
+        .map_err(Error::FindExclusionGroup)?
+        .ok_or(Error::NoExclusionGroup)?;
+    setgid(group.gid).map_err(Error::SetExclusionGroup)?;
+
+    // Drop root privileges
+    let real_uid = getuid();
+    setuid(real_uid).map_err(Error::DropRootUid)?;
+
+    Ok(())
 }
@@ -24,6 +24,7 @@ pub const CLASSES: &[&str] = &[
     "net/mullvad/mullvadvpn/model/GetAccountDataResult$OtherError",
     "net/mullvad/mullvadvpn/model/LocationConstraint$City",
     "net/mullvad/mullvadvpn/model/LocationConstraint$Country",
+    "net/mullvad/mullvadvpn/model/LocationConstraint$CustomList",
     "net/mullvad/mullvadvpn/model/LocationConstraint$Hostname",
     "net/mullvad/mullvadvpn/model/PublicKey",
     "net/mullvad/mullvadvpn/model/Relay",
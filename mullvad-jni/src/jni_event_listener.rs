@@ -9,6 +9,8 @@ use mullvad_daemon::EventListener;
 use mullvad_types::{
     device::{DeviceEvent, RemoveDeviceEvent},
     relay_list::RelayList,
+    relay_rotation::RelayRotationEvent,
+    relay_selection::RelaySelectionEvent,
     settings::Settings,
     states::TunnelState,
     version::AppVersionInfo,
@@ -71,6 +73,14 @@ impl EventListener for JniEventListener {
     fn notify_remove_device_event(&self, event: RemoveDeviceEvent) {
         let _ = self.0.send(Event::RemoveDeviceEvent(event));
     }
+
+    fn notify_relay_rotation(&self, _event: RelayRotationEvent) {
+        // The app learns about the rotation from the tunnel state events that follow
+    }
+
+    fn notify_relay_selection(&self, _event: RelaySelectionEvent) {
+        // The app does not display relay selections
+    }
 }
 
 struct JniEventHandler<'env> {
@@ -0,0 +1,43 @@
+use crate::types::proto;
+use chrono::{DateTime, Utc};
+use mullvad_types::connection_history::ConnectionAttempt;
+use prost_types::Timestamp;
+use talpid_types::net::TunnelType;
+
+fn to_timestamp(time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+impl From<ConnectionAttempt> for proto::ConnectionAttempt {
+    fn from(attempt: ConnectionAttempt) -> Self {
+        proto::ConnectionAttempt {
+            started: Some(to_timestamp(attempt.started)),
+            tunnel_type: match attempt.tunnel_type {
+                TunnelType::Wireguard => i32::from(proto::TunnelType::Wireguard),
+                TunnelType::OpenVpn => i32::from(proto::TunnelType::Openvpn),
+            },
+            endpoint: Some(proto::Endpoint::from(attempt.endpoint)),
+            exit: attempt.exit.unwrap_or_default(),
+            entry: attempt.entry.unwrap_or_default(),
+            bridge: attempt.bridge.unwrap_or_default(),
+            obfuscator: attempt.obfuscator.unwrap_or_default(),
+            connected: attempt.connected.map(to_timestamp),
+            ended: attempt.ended.map(to_timestamp),
+            end_reason: attempt.end_reason.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<Vec<ConnectionAttempt>> for proto::ConnectionHistory {
+    fn from(attempts: Vec<ConnectionAttempt>) -> Self {
+        proto::ConnectionHistory {
+            attempts: attempts
+                .into_iter()
+                .map(proto::ConnectionAttempt::from)
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::types::{proto, FromProtobufTypeError};
+use mullvad_types::{
+    custom_list::{CustomList, CustomListsSettings},
+    relay_constraints::{Constraint, LocationConstraint},
+};
+
+impl From<&CustomListsSettings> for proto::CustomListSettings {
+    fn from(settings: &CustomListsSettings) -> Self {
+        Self {
+            custom_lists: settings
+                .custom_lists
+                .iter()
+                .cloned()
+                .map(proto::CustomList::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<CustomList> for proto::CustomList {
+    fn from(custom_list: CustomList) -> Self {
+        Self {
+            name: custom_list.name,
+            locations: custom_list
+                .locations
+                .into_iter()
+                .map(proto::RelayLocation::from)
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::CustomList> for CustomList {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(custom_list: proto::CustomList) -> Result<Self, Self::Error> {
+        if custom_list.name.is_empty() {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "missing custom list name",
+            ));
+        }
+        let locations = custom_list
+            .locations
+            .into_iter()
+            .map(
+                |location| match Constraint::<LocationConstraint>::from(location) {
+                    Constraint::Only(LocationConstraint::Nearest) => {
+                        Err(FromProtobufTypeError::InvalidArgument(
+                            "custom list cannot contain the nearest location",
+                        ))
+                    }
+                    Constraint::Only(location) => Ok(location),
+                    Constraint::Any => Err(FromProtobufTypeError::InvalidArgument(
+                        "missing custom list location",
+                    )),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CustomList {
+            name: custom_list.name,
+            locations,
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use crate::types::{proto, FromProtobufTypeError};
+use mullvad_types::dns_blocklist::{BlocklistSource, BlocklistStatus, DnsBlocklist};
+use prost_types::Timestamp;
+use std::path::PathBuf;
+
+impl From<&DnsBlocklist> for proto::DnsBlocklist {
+    fn from(blocklist: &DnsBlocklist) -> Self {
+        use proto::dns_blocklist::Source;
+
+        proto::DnsBlocklist {
+            source: Some(match &blocklist.source {
+                BlocklistSource::Url(url) => Source::Url(url.clone()),
+                BlocklistSource::File(path) => Source::Path(path.to_string_lossy().into_owned()),
+            }),
+            enabled: blocklist.enabled,
+            update_interval: blocklist.update_interval,
+        }
+    }
+}
+
+impl TryFrom<proto::DnsBlocklist> for DnsBlocklist {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(blocklist: proto::DnsBlocklist) -> Result<Self, Self::Error> {
+        use proto::dns_blocklist::Source;
+
+        let source = match blocklist.source {
+            Some(Source::Url(url)) => BlocklistSource::Url(url),
+            Some(Source::Path(path)) => BlocklistSource::File(PathBuf::from(path)),
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "missing blocklist source",
+                ))
+            }
+        };
+        // Make sure that the source is a URL or an absolute path
+        let source = source
+            .to_string()
+            .parse()
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid blocklist source"))?;
+        if blocklist.update_interval == 0 {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "update interval must be at least one hour",
+            ));
+        }
+        Ok(DnsBlocklist {
+            source,
+            enabled: blocklist.enabled,
+            update_interval: blocklist.update_interval,
+        })
+    }
+}
+
+impl From<Option<BlocklistStatus>> for proto::DnsBlocklistStatus {
+    fn from(status: Option<BlocklistStatus>) -> Self {
+        match status {
+            Some(status) => proto::DnsBlocklistStatus {
+                loaded: true,
+                domains: u32::try_from(status.domains).unwrap_or(u32::MAX),
+                updated: status.updated.map(|updated| Timestamp {
+                    seconds: updated.timestamp(),
+                    nanos: updated.timestamp_subsec_nanos() as i32,
+                }),
+                error: status.error.unwrap_or_default(),
+            },
+            None => proto::DnsBlocklistStatus::default(),
+        }
+    }
+}
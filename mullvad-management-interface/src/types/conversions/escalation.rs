@@ -0,0 +1,71 @@
+use crate::types::{proto, FromProtobufTypeError};
+use mullvad_types::{
+    escalation::{EscalationLadder, EscalationStep},
+    relay_constraints::Constraint,
+};
+use talpid_types::net::TunnelType;
+
+impl From<&EscalationLadder> for proto::EscalationLadder {
+    fn from(ladder: &EscalationLadder) -> Self {
+        Self {
+            steps: ladder
+                .steps
+                .iter()
+                .map(proto::EscalationStep::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<&EscalationStep> for proto::EscalationStep {
+    fn from(step: &EscalationStep) -> Self {
+        Self {
+            from_attempt: step.from_attempt,
+            tunnel_type: match step.tunnel_type {
+                TunnelType::OpenVpn => i32::from(proto::TunnelType::Openvpn),
+                TunnelType::Wireguard => i32::from(proto::TunnelType::Wireguard),
+            },
+            port: u32::from(step.port.unwrap_or(0)),
+            transport_protocol: i32::from(proto::TransportProtocol::from(step.transport_protocol)),
+            udp2tcp: step.udp2tcp,
+            bridge: step.bridge,
+        }
+    }
+}
+
+impl TryFrom<proto::EscalationLadder> for EscalationLadder {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(ladder: proto::EscalationLadder) -> Result<Self, Self::Error> {
+        Ok(EscalationLadder {
+            steps: ladder
+                .steps
+                .into_iter()
+                .map(EscalationStep::try_from)
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+}
+
+impl TryFrom<proto::EscalationStep> for EscalationStep {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(step: proto::EscalationStep) -> Result<Self, Self::Error> {
+        let port = match step.port {
+            0 => Constraint::Any,
+            port => Constraint::Only(u16::try_from(port).map_err(|_| {
+                FromProtobufTypeError::InvalidArgument("invalid escalation step port")
+            })?),
+        };
+        Ok(EscalationStep {
+            from_attempt: step.from_attempt,
+            tunnel_type: super::net::try_tunnel_type_from_i32(step.tunnel_type)?,
+            port,
+            transport_protocol: super::net::try_transport_protocol_from_i32(
+                step.transport_protocol,
+            )?,
+            udp2tcp: step.udp2tcp,
+            bridge: step.bridge,
+        })
+    }
+}
@@ -0,0 +1,60 @@
+use crate::types::{proto, FromProtobufTypeError};
+use talpid_types::{
+    firewall_exception::{Direction, FirewallException},
+    net::TransportProtocol,
+};
+
+impl From<&FirewallException> for proto::FirewallException {
+    fn from(exception: &FirewallException) -> Self {
+        use proto::firewall_exception::Direction as ProtoDirection;
+
+        let direction = match exception.direction {
+            Direction::Outgoing => ProtoDirection::Outgoing,
+            Direction::Incoming => ProtoDirection::Incoming,
+        };
+        proto::FirewallException {
+            direction: i32::from(direction),
+            protocol: i32::from(proto::TransportProtocol::from(exception.protocol)),
+            network: exception.network.to_string(),
+            port: u32::from(exception.port.unwrap_or(0)),
+        }
+    }
+}
+
+impl TryFrom<proto::FirewallException> for FirewallException {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(exception: proto::FirewallException) -> Result<Self, Self::Error> {
+        use proto::firewall_exception::Direction as ProtoDirection;
+
+        let direction = match ProtoDirection::from_i32(exception.direction) {
+            Some(ProtoDirection::Outgoing) => Direction::Outgoing,
+            Some(ProtoDirection::Incoming) => Direction::Incoming,
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid firewall exception direction",
+                ))
+            }
+        };
+        let protocol = proto::TransportProtocol::from_i32(exception.protocol)
+            .map(TransportProtocol::from)
+            .ok_or(FromProtobufTypeError::InvalidArgument(
+                "invalid transport protocol",
+            ))?;
+        let network = exception
+            .network
+            .parse()
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid network"))?;
+        let port = match u16::try_from(exception.port) {
+            Ok(0) => None,
+            Ok(port) => Some(port),
+            Err(_) => return Err(FromProtobufTypeError::InvalidArgument("invalid port")),
+        };
+        Ok(FirewallException {
+            direction,
+            protocol,
+            network,
+            port,
+        })
+    }
+}
@@ -0,0 +1,65 @@
+use crate::types::{proto, FromProtobufTypeError};
+use talpid_types::lan::{LanRules, LanService};
+
+impl From<&LanRules> for proto::LanRules {
+    fn from(rules: &LanRules) -> Self {
+        proto::LanRules {
+            subnets: rules
+                .subnets
+                .iter()
+                .map(|subnet| subnet.to_string())
+                .collect(),
+            interfaces: rules.interfaces.clone(),
+            services: rules
+                .services
+                .iter()
+                .map(|service| i32::from(proto::lan_rules::Service::from(*service)))
+                .collect(),
+        }
+    }
+}
+
+impl From<LanService> for proto::lan_rules::Service {
+    fn from(service: LanService) -> Self {
+        match service {
+            LanService::Mdns => proto::lan_rules::Service::Mdns,
+            LanService::Dhcp => proto::lan_rules::Service::Dhcp,
+            LanService::Printing => proto::lan_rules::Service::Printing,
+        }
+    }
+}
+
+impl TryFrom<proto::LanRules> for LanRules {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(rules: proto::LanRules) -> Result<Self, Self::Error> {
+        use proto::lan_rules::Service;
+
+        let subnets = rules
+            .subnets
+            .iter()
+            .map(|subnet| {
+                subnet
+                    .parse()
+                    .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid subnet"))
+            })
+            .collect::<Result<_, _>>()?;
+        let services = rules
+            .services
+            .into_iter()
+            .map(|service| match Service::from_i32(service) {
+                Some(Service::Mdns) => Ok(LanService::Mdns),
+                Some(Service::Dhcp) => Ok(LanService::Dhcp),
+                Some(Service::Printing) => Ok(LanService::Printing),
+                None => Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid LAN service",
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(LanRules {
+            subnets,
+            interfaces: rules.interfaces,
+            services,
+        })
+    }
+}
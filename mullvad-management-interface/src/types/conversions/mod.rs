@@ -1,13 +1,24 @@
 use std::str::FromStr;
 
+mod connection_history;
+mod custom_list;
 mod custom_tunnel;
 mod device;
+mod dns_blocklist;
+mod escalation;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod firewall_exception;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod lan;
 mod location;
 mod net;
+mod network_rules;
 pub mod relay_constraints;
 mod relay_list;
+mod schedule;
 mod settings;
 mod states;
+mod traffic_stats;
 mod version;
 mod wireguard;
 
@@ -22,22 +22,35 @@ impl From<talpid_types::net::TunnelEndpoint> for proto::TunnelEndpoint {
                     net::proxy::ProxyType::Custom => i32::from(proto::ProxyType::Custom),
                 },
             }),
-            obfuscation: endpoint.obfuscation.map(|obfuscation_endpoint| {
-                proto::ObfuscationEndpoint {
-                    address: obfuscation_endpoint.endpoint.address.ip().to_string(),
-                    port: u32::from(obfuscation_endpoint.endpoint.address.port()),
-                    protocol: i32::from(proto::TransportProtocol::from(
-                        obfuscation_endpoint.endpoint.protocol,
-                    )),
-                    obfuscation_type: match obfuscation_endpoint.obfuscation_type {
-                        net::ObfuscationType::Udp2Tcp => i32::from(proto::ObfuscationType::Udp2tcp),
-                    },
+            obfuscation: endpoint.obfuscation.map(proto::ObfuscationEndpoint::from),
+            entry_endpoint: endpoint.entry_endpoint.map(proto::Endpoint::from),
+        }
+    }
+}
+
+impl From<talpid_types::net::Endpoint> for proto::Endpoint {
+    fn from(endpoint: talpid_types::net::Endpoint) -> Self {
+        proto::Endpoint {
+            address: endpoint.address.to_string(),
+            protocol: i32::from(proto::TransportProtocol::from(endpoint.protocol)),
+        }
+    }
+}
+
+impl From<talpid_types::net::ObfuscationEndpoint> for proto::ObfuscationEndpoint {
+    fn from(endpoint: talpid_types::net::ObfuscationEndpoint) -> Self {
+        use talpid_types::net::ObfuscationType;
+
+        proto::ObfuscationEndpoint {
+            address: endpoint.endpoint.address.ip().to_string(),
+            port: u32::from(endpoint.endpoint.address.port()),
+            protocol: i32::from(proto::TransportProtocol::from(endpoint.endpoint.protocol)),
+            obfuscation_type: match endpoint.obfuscation_type {
+                ObfuscationType::Udp2Tcp => i32::from(proto::ObfuscationType::Udp2tcp),
+                ObfuscationType::PluggableTransport => {
+                    i32::from(proto::ObfuscationType::PluggableTransport)
                 }
-            }),
-            entry_endpoint: endpoint.entry_endpoint.map(|entry| proto::Endpoint {
-                address: entry.address.to_string(),
-                protocol: i32::from(proto::TransportProtocol::from(entry.protocol)),
-            }),
+            },
         }
     }
 }
@@ -100,6 +113,9 @@ impl TryFrom<proto::TunnelEndpoint> for talpid_types::net::TunnelEndpoint {
                             Some(proto::ObfuscationType::Udp2tcp) => {
                                 talpid_net::ObfuscationType::Udp2Tcp
                             }
+                            Some(proto::ObfuscationType::PluggableTransport) => {
+                                talpid_net::ObfuscationType::PluggableTransport
+                            }
                             None => {
                                 return Err(FromProtobufTypeError::InvalidArgument(
                                     "unknown obfuscation type",
@@ -0,0 +1,87 @@
+use crate::types::{proto, FromProtobufTypeError};
+use mullvad_types::network_rules::{NetworkRule, NetworkRules, NetworkTrust};
+use talpid_types::net::NetworkIdentity;
+
+impl From<NetworkIdentity> for proto::NetworkIdentity {
+    fn from(network: NetworkIdentity) -> Self {
+        use proto::network_identity::Network;
+
+        let network = match network {
+            NetworkIdentity::Wifi(ssid) => Network::Wifi(ssid),
+            NetworkIdentity::Wired(fingerprint) => Network::Wired(fingerprint),
+        };
+        proto::NetworkIdentity {
+            network: Some(network),
+        }
+    }
+}
+
+impl From<Option<NetworkIdentity>> for proto::NetworkIdentity {
+    fn from(network: Option<NetworkIdentity>) -> Self {
+        network
+            .map(proto::NetworkIdentity::from)
+            .unwrap_or_default()
+    }
+}
+
+impl TryFrom<proto::NetworkIdentity> for NetworkIdentity {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(network: proto::NetworkIdentity) -> Result<Self, Self::Error> {
+        use proto::network_identity::Network;
+
+        match network.network {
+            Some(Network::Wifi(ssid)) => Ok(NetworkIdentity::Wifi(ssid)),
+            Some(Network::Wired(fingerprint)) => Ok(NetworkIdentity::Wired(fingerprint)),
+            None => Err(FromProtobufTypeError::InvalidArgument("missing network")),
+        }
+    }
+}
+
+impl From<NetworkRule> for proto::NetworkRule {
+    fn from(rule: NetworkRule) -> Self {
+        let trust = match rule.trust {
+            NetworkTrust::Trusted => proto::network_rule::Trust::Trusted,
+            NetworkTrust::Untrusted => proto::network_rule::Trust::Untrusted,
+        };
+        proto::NetworkRule {
+            network: Some(proto::NetworkIdentity::from(rule.network)),
+            trust: i32::from(trust),
+        }
+    }
+}
+
+impl TryFrom<proto::NetworkRule> for NetworkRule {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(rule: proto::NetworkRule) -> Result<Self, Self::Error> {
+        let network = rule
+            .network
+            .ok_or(FromProtobufTypeError::InvalidArgument("missing network"))
+            .and_then(NetworkIdentity::try_from)?;
+        let trust = match proto::network_rule::Trust::from_i32(rule.trust) {
+            Some(proto::network_rule::Trust::Trusted) => NetworkTrust::Trusted,
+            Some(proto::network_rule::Trust::Untrusted) => NetworkTrust::Untrusted,
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid network trust",
+                ))
+            }
+        };
+        Ok(NetworkRule { network, trust })
+    }
+}
+
+impl From<&NetworkRules> for proto::NetworkRules {
+    fn from(rules: &NetworkRules) -> Self {
+        proto::NetworkRules {
+            rules: rules
+                .rules
+                .iter()
+                .cloned()
+                .map(proto::NetworkRule::from)
+                .collect(),
+            lockdown_untrusted: rules.lockdown_untrusted,
+        }
+    }
+}
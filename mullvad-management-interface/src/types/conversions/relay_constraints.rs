@@ -1,4 +1,7 @@
-use crate::types::{conversions::option_from_proto_string, proto, FromProtobufTypeError};
+use crate::types::{
+    conversions::{arg_from_str, bytes_to_pubkey, option_from_proto_string},
+    proto, FromProtobufTypeError,
+};
 use mullvad_types::relay_constraints::Constraint;
 
 impl TryFrom<&proto::WireguardConstraints>
@@ -10,38 +13,104 @@ impl TryFrom<&proto::WireguardConstraints>
         constraints: &proto::WireguardConstraints,
     ) -> Result<mullvad_types::relay_constraints::WireguardConstraints, Self::Error> {
         use mullvad_types::relay_constraints as mullvad_constraints;
-        use talpid_types::net;
-
-        let ip_version = match &constraints.ip_version {
-            Some(constraint) => match proto::IpVersion::from_i32(constraint.protocol) {
-                Some(proto::IpVersion::V4) => Some(net::IpVersion::V4),
-                Some(proto::IpVersion::V6) => Some(net::IpVersion::V6),
-                None => {
-                    return Err(FromProtobufTypeError::InvalidArgument(
-                        "invalid ip protocol version",
-                    ))
-                }
-            },
-            None => None,
-        };
 
         Ok(mullvad_constraints::WireguardConstraints {
-            port: if constraints.port == 0 {
-                Constraint::Any
-            } else {
-                Constraint::Only(constraints.port as u16)
-            },
-            ip_version: Constraint::from(ip_version),
+            port: try_port_ranges_constraint_from_proto(
+                constraints.port,
+                &constraints.port_ranges,
+            )?,
+            ip_version: try_ip_version_constraint_from_proto(&constraints.ip_version)?,
             use_multihop: constraints.use_multihop,
             entry_location: constraints
                 .entry_location
                 .clone()
                 .map(Constraint::<mullvad_types::relay_constraints::LocationConstraint>::from)
                 .unwrap_or(Constraint::Any),
+            entry_policy: match proto::EntryRelayPolicy::from_i32(constraints.entry_policy) {
+                Some(proto::EntryRelayPolicy::Random) => {
+                    mullvad_constraints::EntryRelayPolicy::Random
+                }
+                Some(proto::EntryRelayPolicy::ShortestPath) => {
+                    mullvad_constraints::EntryRelayPolicy::ShortestPath
+                }
+                None => {
+                    return Err(FromProtobufTypeError::InvalidArgument(
+                        "invalid entry relay policy",
+                    ))
+                }
+            },
+            custom_hop: constraints
+                .custom_hop
+                .as_ref()
+                .map(mullvad_constraints::CustomMultihopRelay::try_from)
+                .transpose()?,
         })
     }
 }
 
+impl TryFrom<&proto::CustomMultihopRelay>
+    for mullvad_types::relay_constraints::CustomMultihopRelay
+{
+    type Error = FromProtobufTypeError;
+
+    fn try_from(relay: &proto::CustomMultihopRelay) -> Result<Self, Self::Error> {
+        use mullvad_types::relay_constraints::CustomHop;
+
+        let hop = match proto::custom_multihop_relay::Hop::from_i32(relay.hop) {
+            Some(proto::custom_multihop_relay::Hop::Entry) => CustomHop::Entry,
+            Some(proto::custom_multihop_relay::Hop::Exit) => CustomHop::Exit {
+                ipv4_gateway: relay.ipv4_gateway.parse().map_err(|_err| {
+                    FromProtobufTypeError::InvalidArgument("invalid IPv4 gateway")
+                })?,
+            },
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid custom relay hop",
+                ))
+            }
+        };
+        Ok(Self {
+            hop,
+            host: relay.host.clone(),
+            port: u16::try_from(relay.port)
+                .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid port"))?,
+            public_key: bytes_to_pubkey(&relay.public_key)?,
+        })
+    }
+}
+
+impl From<&mullvad_types::relay_constraints::CustomMultihopRelay> for proto::CustomMultihopRelay {
+    fn from(relay: &mullvad_types::relay_constraints::CustomMultihopRelay) -> Self {
+        use mullvad_types::relay_constraints::CustomHop;
+
+        let (hop, ipv4_gateway) = match relay.hop {
+            CustomHop::Entry => (proto::custom_multihop_relay::Hop::Entry, String::new()),
+            CustomHop::Exit { ipv4_gateway } => (
+                proto::custom_multihop_relay::Hop::Exit,
+                ipv4_gateway.to_string(),
+            ),
+        };
+        Self {
+            hop: hop as i32,
+            host: relay.host.clone(),
+            port: u32::from(relay.port),
+            public_key: relay.public_key.as_bytes().to_vec(),
+            ipv4_gateway,
+        }
+    }
+}
+
+impl From<mullvad_types::relay_constraints::EntryRelayPolicy> for proto::EntryRelayPolicy {
+    fn from(policy: mullvad_types::relay_constraints::EntryRelayPolicy) -> Self {
+        use mullvad_types::relay_constraints::EntryRelayPolicy;
+
+        match policy {
+            EntryRelayPolicy::Random => proto::EntryRelayPolicy::Random,
+            EntryRelayPolicy::ShortestPath => proto::EntryRelayPolicy::ShortestPath,
+        }
+    }
+}
+
 impl TryFrom<&proto::OpenvpnConstraints> for mullvad_types::relay_constraints::OpenVpnConstraints {
     type Error = FromProtobufTypeError;
 
@@ -55,6 +124,13 @@ impl TryFrom<&proto::OpenvpnConstraints> for mullvad_types::relay_constraints::O
                 Some(port) => Some(mullvad_constraints::TransportPort::try_from(port.clone())?),
                 None => None,
             }),
+            ip_version: try_ip_version_constraint_from_proto(&constraints.ip_version)?,
+            use_multihop: constraints.use_multihop,
+            entry_location: constraints
+                .entry_location
+                .clone()
+                .map(Constraint::<mullvad_constraints::LocationConstraint>::from)
+                .unwrap_or(Constraint::Any),
         })
     }
 }
@@ -95,8 +171,13 @@ impl TryFrom<proto::RelaySettings> for mullvad_types::relay_constraints::RelaySe
                     .location
                     .map(Constraint::<mullvad_types::relay_constraints::LocationConstraint>::from)
                     .unwrap_or(Constraint::Any);
-                let providers = try_providers_constraint_from_proto(&settings.providers)?;
+                let providers = try_providers_constraint_from_proto(
+                    &settings.providers,
+                    settings.exclude_providers,
+                )?;
                 let ownership = try_ownership_constraint_from_i32(settings.ownership)?;
+                let endpoint_subnet =
+                    try_endpoint_subnet_constraint_from_proto(settings.endpoint_subnet)?;
                 let tunnel_protocol = settings
                     .tunnel_type
                     .map(Constraint::<net::TunnelType>::try_from)
@@ -122,6 +203,9 @@ impl TryFrom<proto::RelaySettings> for mullvad_types::relay_constraints::RelaySe
                         tunnel_protocol,
                         wireguard_constraints,
                         openvpn_constraints,
+                        endpoint_subnet,
+                        excluded_relays: settings.excluded_relays,
+                        avoided_locations: avoided_locations_from_proto(settings.avoided_locations),
                     },
                 ))
             }
@@ -172,6 +256,7 @@ impl TryFrom<proto::RelaySettingsUpdate> for mullvad_types::relay_constraints::R
                 let providers = if let Some(ref provider_update) = settings.providers {
                     Some(try_providers_constraint_from_proto(
                         &provider_update.providers,
+                        provider_update.exclude,
                     )?)
                 } else {
                     None
@@ -183,6 +268,17 @@ impl TryFrom<proto::RelaySettingsUpdate> for mullvad_types::relay_constraints::R
                 } else {
                     None
                 };
+                let endpoint_subnet = if let Some(update) = settings.endpoint_subnet {
+                    Some(try_endpoint_subnet_constraint_from_proto(
+                        update.endpoint_subnet,
+                    )?)
+                } else {
+                    None
+                };
+                let excluded_relays = settings.excluded_relays.map(|update| update.hostnames);
+                let avoided_locations = settings
+                    .avoided_locations
+                    .map(|update| avoided_locations_from_proto(update.locations));
                 let tunnel_protocol = if let Some(update) = settings.tunnel_type {
                     Some(
                         update
@@ -218,6 +314,9 @@ impl TryFrom<proto::RelaySettingsUpdate> for mullvad_types::relay_constraints::R
                         tunnel_protocol,
                         wireguard_constraints,
                         openvpn_constraints,
+                        endpoint_subnet,
+                        excluded_relays,
+                        avoided_locations,
                     },
                 ))
             }
@@ -240,7 +339,7 @@ impl From<mullvad_types::relay_constraints::BridgeState> for proto::BridgeState
 
 impl From<&mullvad_types::relay_constraints::ObfuscationSettings> for proto::ObfuscationSettings {
     fn from(settings: &mullvad_types::relay_constraints::ObfuscationSettings) -> Self {
-        use mullvad_types::relay_constraints::SelectedObfuscation;
+        use mullvad_types::relay_constraints::{ObfuscationMethod, SelectedObfuscation};
         let selected_obfuscation = i32::from(match settings.selected_obfuscation {
             SelectedObfuscation::Auto => proto::obfuscation_settings::SelectedObfuscation::Auto,
             SelectedObfuscation::Off => proto::obfuscation_settings::SelectedObfuscation::Off,
@@ -251,6 +350,25 @@ impl From<&mullvad_types::relay_constraints::ObfuscationSettings> for proto::Obf
         Self {
             selected_obfuscation,
             udp2tcp: Some(proto::Udp2TcpObfuscationSettings::from(&settings.udp2tcp)),
+            location: settings
+                .location
+                .clone()
+                .option()
+                .map(proto::RelayLocation::from),
+            priority: settings
+                .priority
+                .iter()
+                .map(|method| {
+                    i32::from(match method {
+                        ObfuscationMethod::Off => {
+                            proto::obfuscation_settings::ObfuscationMethod::MethodOff
+                        }
+                        ObfuscationMethod::Udp2Tcp => {
+                            proto::obfuscation_settings::ObfuscationMethod::MethodUdp2tcp
+                        }
+                    })
+                })
+                .collect(),
         }
     }
 }
@@ -280,7 +398,9 @@ impl From<mullvad_types::relay_constraints::BridgeSettings> for proto::BridgeSet
                         .option()
                         .map(proto::RelayLocation::from),
                     providers: convert_providers_constraint(&constraints.providers),
+                    exclude_providers: providers_are_excluded(&constraints.providers),
                     ownership: convert_ownership_constraint(&constraints.ownership) as i32,
+                    ip_version: convert_ip_version_constraint(constraints.ip_version),
                 })
             }
             MullvadBridgeSettings::Custom(proxy_settings) => match proxy_settings {
@@ -337,7 +457,19 @@ impl From<mullvad_types::relay_constraints::RelaySettings> for proto::RelaySetti
                         .option()
                         .map(proto::RelayLocation::from),
                     providers: convert_providers_constraint(&constraints.providers),
+                    exclude_providers: providers_are_excluded(&constraints.providers),
                     ownership: convert_ownership_constraint(&constraints.ownership) as i32,
+                    endpoint_subnet: constraints
+                        .endpoint_subnet
+                        .option()
+                        .map(|subnet| subnet.to_string())
+                        .unwrap_or_default(),
+                    excluded_relays: constraints.excluded_relays,
+                    avoided_locations: constraints
+                        .avoided_locations
+                        .into_iter()
+                        .map(proto::RelayLocation::from)
+                        .collect(),
                     tunnel_type: match constraints.tunnel_protocol {
                         Constraint::Any => None,
                         Constraint::Only(talpid_net::TunnelType::Wireguard) => {
@@ -352,19 +484,34 @@ impl From<mullvad_types::relay_constraints::RelaySettings> for proto::RelaySetti
                     }),
 
                     wireguard_constraints: Some(proto::WireguardConstraints {
-                        port: u32::from(constraints.wireguard_constraints.port.unwrap_or(0)),
-                        ip_version: constraints
+                        port: constraints
                             .wireguard_constraints
-                            .ip_version
+                            .port
+                            .as_ref()
                             .option()
-                            .map(proto::IpVersion::from)
-                            .map(proto::IpVersionConstraint::from),
+                            .and_then(|ports| ports.single())
+                            .map(u32::from)
+                            .unwrap_or(0),
+                        port_ranges: convert_port_ranges_constraint(
+                            &constraints.wireguard_constraints.port,
+                        ),
+                        ip_version: convert_ip_version_constraint(
+                            constraints.wireguard_constraints.ip_version,
+                        ),
                         use_multihop: constraints.wireguard_constraints.use_multihop,
                         entry_location: constraints
                             .wireguard_constraints
                             .entry_location
                             .option()
                             .map(proto::RelayLocation::from),
+                        entry_policy: proto::EntryRelayPolicy::from(
+                            constraints.wireguard_constraints.entry_policy,
+                        ) as i32,
+                        custom_hop: constraints
+                            .wireguard_constraints
+                            .custom_hop
+                            .as_ref()
+                            .map(proto::CustomMultihopRelay::from),
                     }),
 
                     openvpn_constraints: Some(proto::OpenvpnConstraints {
@@ -373,6 +520,15 @@ impl From<mullvad_types::relay_constraints::RelaySettings> for proto::RelaySetti
                             .port
                             .option()
                             .map(proto::TransportPort::from),
+                        ip_version: convert_ip_version_constraint(
+                            constraints.openvpn_constraints.ip_version,
+                        ),
+                        use_multihop: constraints.openvpn_constraints.use_multihop,
+                        entry_location: constraints
+                            .openvpn_constraints
+                            .entry_location
+                            .option()
+                            .map(proto::RelayLocation::from),
                     }),
                 })
             }
@@ -430,6 +586,15 @@ impl From<mullvad_types::relay_constraints::LocationConstraint> for proto::Relay
                 country,
                 city,
                 hostname,
+                ..Default::default()
+            },
+            LocationConstraint::CustomList(custom_list) => Self {
+                custom_list,
+                ..Default::default()
+            },
+            LocationConstraint::Nearest => Self {
+                nearest: true,
+                ..Default::default()
             },
         }
     }
@@ -441,7 +606,11 @@ impl From<proto::RelayLocation>
     fn from(location: proto::RelayLocation) -> Self {
         use mullvad_types::relay_constraints::LocationConstraint;
 
-        if let Some(hostname) = option_from_proto_string(location.hostname) {
+        if location.nearest {
+            Constraint::Only(LocationConstraint::Nearest)
+        } else if let Some(custom_list) = option_from_proto_string(location.custom_list) {
+            Constraint::Only(LocationConstraint::CustomList(custom_list))
+        } else if let Some(hostname) = option_from_proto_string(location.hostname) {
             Constraint::Only(LocationConstraint::Hostname(
                 location.country,
                 location.city,
@@ -476,14 +645,24 @@ impl TryFrom<proto::BridgeSettings> for mullvad_types::relay_constraints::Bridge
                         Constraint::<mullvad_constraints::LocationConstraint>::from(location)
                     }
                 };
-                let providers = try_providers_constraint_from_proto(&constraints.providers)?;
+                if location == Constraint::Only(mullvad_constraints::LocationConstraint::Nearest) {
+                    return Err(FromProtobufTypeError::InvalidArgument(
+                        "the nearest location cannot be used for bridges",
+                    ));
+                }
+                let providers = try_providers_constraint_from_proto(
+                    &constraints.providers,
+                    constraints.exclude_providers,
+                )?;
                 let ownership = try_ownership_constraint_from_i32(constraints.ownership)?;
+                let ip_version = try_ip_version_constraint_from_proto(&constraints.ip_version)?;
 
                 Ok(mullvad_constraints::BridgeSettings::Normal(
                     mullvad_constraints::BridgeConstraints {
                         location,
                         providers,
                         ownership,
+                        ip_version,
                     },
                 ))
             }
@@ -537,8 +716,11 @@ impl TryFrom<proto::ObfuscationSettings> for mullvad_types::relay_constraints::O
     type Error = FromProtobufTypeError;
 
     fn try_from(settings: proto::ObfuscationSettings) -> Result<Self, Self::Error> {
-        use mullvad_types::relay_constraints::SelectedObfuscation;
-        use proto::obfuscation_settings::SelectedObfuscation as IpcSelectedObfuscation;
+        use mullvad_types::relay_constraints::{ObfuscationMethod, SelectedObfuscation};
+        use proto::obfuscation_settings::{
+            ObfuscationMethod as IpcObfuscationMethod,
+            SelectedObfuscation as IpcSelectedObfuscation,
+        };
         let selected_obfuscation =
             match IpcSelectedObfuscation::from_i32(settings.selected_obfuscation) {
                 Some(IpcSelectedObfuscation::Auto) => SelectedObfuscation::Auto,
@@ -562,9 +744,33 @@ impl TryFrom<proto::ObfuscationSettings> for mullvad_types::relay_constraints::O
             }
         };
 
+        let location = settings
+            .location
+            .map(Constraint::<mullvad_types::relay_constraints::LocationConstraint>::from)
+            .unwrap_or(Constraint::Any);
+
+        let priority = settings
+            .priority
+            .into_iter()
+            .map(|method| match IpcObfuscationMethod::from_i32(method) {
+                Some(IpcObfuscationMethod::MethodOff) => Ok(ObfuscationMethod::Off),
+                Some(IpcObfuscationMethod::MethodUdp2tcp) => Ok(ObfuscationMethod::Udp2Tcp),
+                None => Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid obfuscation method",
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let priority = if priority.is_empty() {
+            mullvad_types::relay_constraints::ObfuscationSettings::default().priority
+        } else {
+            priority
+        };
+
         Ok(Self {
             selected_obfuscation,
             udp2tcp,
+            location,
+            priority,
         })
     }
 }
@@ -621,15 +827,23 @@ impl TryFrom<proto::TransportPort> for mullvad_types::relay_constraints::Transpo
     }
 }
 
+/// Converts a list of providers to a provider constraint. If `exclude` is set, any provider
+/// except the given ones is allowed.
 pub fn try_providers_constraint_from_proto(
     providers: &[String],
+    exclude: bool,
 ) -> Result<Constraint<mullvad_types::relay_constraints::Providers>, FromProtobufTypeError> {
+    use mullvad_types::relay_constraints::Providers;
+
     if !providers.is_empty() {
-        Ok(Constraint::Only(
-            mullvad_types::relay_constraints::Providers::new(providers.iter().cloned()).map_err(
-                |_| FromProtobufTypeError::InvalidArgument("must specify at least one provider"),
-            )?,
-        ))
+        let providers = if exclude {
+            Providers::new_excluded(providers.iter().cloned())
+        } else {
+            Providers::new(providers.iter().cloned())
+        };
+        Ok(Constraint::Only(providers.map_err(|_| {
+            FromProtobufTypeError::InvalidArgument("must specify at least one provider")
+        })?))
     } else {
         Ok(Constraint::Any)
     }
@@ -645,6 +859,77 @@ pub fn try_ownership_constraint_from_i32(
         ))
 }
 
+pub fn try_ip_version_constraint_from_proto(
+    constraint: &Option<proto::IpVersionConstraint>,
+) -> Result<Constraint<talpid_types::net::IpVersion>, FromProtobufTypeError> {
+    use talpid_types::net::IpVersion;
+
+    match constraint {
+        Some(constraint) => match proto::IpVersion::from_i32(constraint.protocol) {
+            Some(proto::IpVersion::V4) => Ok(Constraint::Only(IpVersion::V4)),
+            Some(proto::IpVersion::V6) => Ok(Constraint::Only(IpVersion::V6)),
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "invalid ip protocol version",
+            )),
+        },
+        None => Ok(Constraint::Any),
+    }
+}
+
+/// Returns the port constraint given by `port_ranges`, or by `port` if there are no ranges. A
+/// port of 0 means any port.
+pub fn try_port_ranges_constraint_from_proto(
+    port: u32,
+    port_ranges: &[proto::PortRange],
+) -> Result<Constraint<mullvad_types::relay_constraints::PortRanges>, FromProtobufTypeError> {
+    use mullvad_types::relay_constraints::PortRanges;
+
+    let invalid_port = || FromProtobufTypeError::InvalidArgument("invalid port");
+    if port_ranges.is_empty() {
+        return match port {
+            0 => Ok(Constraint::Any),
+            port => Ok(Constraint::Only(PortRanges::from(
+                u16::try_from(port).map_err(|_| invalid_port())?,
+            ))),
+        };
+    }
+    let ranges = port_ranges
+        .iter()
+        .map(|range| {
+            Ok((
+                u16::try_from(range.first).map_err(|_| invalid_port())?,
+                u16::try_from(range.last).map_err(|_| invalid_port())?,
+            ))
+        })
+        .collect::<Result<Vec<_>, FromProtobufTypeError>>()?;
+    PortRanges::new(ranges)
+        .map(Constraint::Only)
+        .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid port ranges"))
+}
+
+pub fn try_endpoint_subnet_constraint_from_proto(
+    subnet: String,
+) -> Result<Constraint<ipnetwork::IpNetwork>, FromProtobufTypeError> {
+    let subnet = option_from_proto_string(subnet)
+        .map(|subnet| arg_from_str(&subnet, "invalid endpoint subnet"))
+        .transpose()?;
+    Ok(Constraint::from(subnet))
+}
+
+/// Converts a list of avoided locations. Empty locations, which would otherwise mean any
+/// location, are left out.
+fn avoided_locations_from_proto(
+    locations: Vec<proto::RelayLocation>,
+) -> Vec<mullvad_types::relay_constraints::LocationConstraint> {
+    locations
+        .into_iter()
+        .filter_map(|location| {
+            Constraint::<mullvad_types::relay_constraints::LocationConstraint>::from(location)
+                .option()
+        })
+        .collect()
+}
+
 pub fn ownership_constraint_from_proto(
     ownership: proto::Ownership,
 ) -> Constraint<mullvad_types::relay_constraints::Ownership> {
@@ -666,6 +951,89 @@ fn convert_providers_constraint(
     }
 }
 
+fn providers_are_excluded(
+    providers: &Constraint<mullvad_types::relay_constraints::Providers>,
+) -> bool {
+    match providers {
+        Constraint::Any => false,
+        Constraint::Only(providers) => providers.is_excluded(),
+    }
+}
+
+impl From<Option<mullvad_types::relay_constraints::ProtocolPreference>>
+    for proto::ProtocolPreference
+{
+    fn from(preference: Option<mullvad_types::relay_constraints::ProtocolPreference>) -> Self {
+        use mullvad_types::relay_constraints::ProtocolPreference;
+        use proto::protocol_preference::{Preference, Weighted};
+
+        let preference = match preference {
+            None => Preference::Auto(()),
+            Some(ProtocolPreference::Weighted { wireguard, openvpn }) => {
+                Preference::Weighted(Weighted { wireguard, openvpn })
+            }
+            Some(ProtocolPreference::WireguardUnlessFailed) => {
+                Preference::WireguardUnlessFailed(())
+            }
+        };
+        proto::ProtocolPreference {
+            preference: Some(preference),
+        }
+    }
+}
+
+impl TryFrom<proto::ProtocolPreference>
+    for Option<mullvad_types::relay_constraints::ProtocolPreference>
+{
+    type Error = FromProtobufTypeError;
+
+    fn try_from(preference: proto::ProtocolPreference) -> Result<Self, Self::Error> {
+        use mullvad_types::relay_constraints::ProtocolPreference;
+        use proto::protocol_preference::Preference;
+
+        match preference.preference {
+            Some(Preference::Auto(())) => Ok(None),
+            Some(Preference::Weighted(weighted)) => Ok(Some(ProtocolPreference::Weighted {
+                wireguard: weighted.wireguard,
+                openvpn: weighted.openvpn,
+            })),
+            Some(Preference::WireguardUnlessFailed(())) => {
+                Ok(Some(ProtocolPreference::WireguardUnlessFailed))
+            }
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "missing protocol preference",
+            )),
+        }
+    }
+}
+
+/// Returns the port ranges of a constraint with more than one port. A single port is sent in the
+/// `port` field, for compatibility with clients that do not support port ranges.
+fn convert_port_ranges_constraint(
+    ports: &Constraint<mullvad_types::relay_constraints::PortRanges>,
+) -> Vec<proto::PortRange> {
+    match ports {
+        Constraint::Only(ports) if ports.single().is_none() => ports
+            .ranges()
+            .iter()
+            .map(|&(first, last)| proto::PortRange {
+                first: u32::from(first),
+                last: u32::from(last),
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn convert_ip_version_constraint(
+    ip_version: Constraint<talpid_types::net::IpVersion>,
+) -> Option<proto::IpVersionConstraint> {
+    ip_version
+        .option()
+        .map(proto::IpVersion::from)
+        .map(proto::IpVersionConstraint::from)
+}
+
 fn convert_ownership_constraint(
     ownership: &Constraint<mullvad_types::relay_constraints::Ownership>,
 ) -> proto::Ownership {
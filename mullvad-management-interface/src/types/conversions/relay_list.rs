@@ -10,6 +10,7 @@ impl From<mullvad_types::relay_list::RelayList> for proto::RelayList {
             openvpn: Some(proto::OpenVpnEndpointData::from(relay_list.openvpn)),
             bridge: Some(proto::BridgeEndpointData::from(relay_list.bridge)),
             wireguard: Some(proto::WireguardEndpointData::from(relay_list.wireguard)),
+            source: Some(proto::RelayListSource::from(&relay_list.source)),
         };
         proto_list.countries = relay_list
             .countries
@@ -20,6 +21,40 @@ impl From<mullvad_types::relay_list::RelayList> for proto::RelayList {
     }
 }
 
+impl From<&mullvad_types::relay_list::RelayListSource> for proto::RelayListSource {
+    fn from(source: &mullvad_types::relay_list::RelayListSource) -> Self {
+        use mullvad_types::relay_list::RelayListSource;
+        use proto::relay_list_source::Source;
+
+        let source = match source {
+            RelayListSource::Api => Source::Api(()),
+            RelayListSource::Url(url) => Source::Url(url.clone()),
+            RelayListSource::File(path) => Source::File(path.to_string_lossy().into_owned()),
+        };
+        proto::RelayListSource {
+            source: Some(source),
+        }
+    }
+}
+
+impl TryFrom<proto::RelayListSource> for mullvad_types::relay_list::RelayListSource {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(source: proto::RelayListSource) -> Result<Self, Self::Error> {
+        use mullvad_types::relay_list::RelayListSource;
+        use proto::relay_list_source::Source;
+
+        match source.source {
+            Some(Source::Api(())) => Ok(RelayListSource::Api),
+            Some(Source::Url(url)) => Ok(RelayListSource::Url(url)),
+            Some(Source::File(path)) => Ok(RelayListSource::File(path.into())),
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "missing relay list source",
+            )),
+        }
+    }
+}
+
 impl From<mullvad_types::relay_list::OpenVpnEndpointData> for proto::OpenVpnEndpointData {
     fn from(openvpn: mullvad_types::relay_list::OpenVpnEndpointData) -> Self {
         proto::OpenVpnEndpointData {
@@ -108,6 +143,7 @@ impl From<mullvad_types::relay_list::Relay> for proto::Relay {
             owned: relay.owned,
             provider: relay.provider,
             weight: relay.weight,
+            load: relay.load.map(u32::from),
             endpoint_type: match &relay.endpoint_data {
                 MullvadEndpointData::Openvpn => proto::relay::RelayType::Openvpn as i32,
                 MullvadEndpointData::Bridge => proto::relay::RelayType::Bridge as i32,
@@ -118,6 +154,10 @@ impl From<mullvad_types::relay_list::Relay> for proto::Relay {
                     "mullvad_daemon.management_interface/WireguardRelayEndpointData",
                     proto::WireguardRelayEndpointData {
                         public_key: data.public_key.as_bytes().to_vec(),
+                        multihop_entry: data.multihop_entry,
+                        pluggable_transports: data.pluggable_transports,
+                        quantum_resistant: data.quantum_resistant,
+                        daita: data.daita,
                     },
                 )),
                 _ => None,
@@ -162,6 +202,10 @@ impl TryFrom<proto::Relay> for mullvad_types::relay_list::Relay {
                 MullvadEndpointData::Wireguard(
                     mullvad_types::relay_list::WireguardRelayEndpointData {
                         public_key: bytes_to_pubkey(&data.public_key)?,
+                        multihop_entry: data.multihop_entry,
+                        pluggable_transports: data.pluggable_transports,
+                        quantum_resistant: data.quantum_resistant,
+                        daita: data.daita,
                     },
                 )
             }
@@ -191,6 +235,14 @@ impl TryFrom<proto::Relay> for mullvad_types::relay_list::Relay {
             owned: relay.owned,
             provider: relay.provider,
             weight: relay.weight,
+            load: relay
+                .load
+                .map(|load| {
+                    u8::try_from(load).map_err(|_err| {
+                        FromProtobufTypeError::InvalidArgument("invalid relay load")
+                    })
+                })
+                .transpose()?,
             endpoint_data,
             location: relay.location.map(|location| MullvadLocation {
                 country: location.country,
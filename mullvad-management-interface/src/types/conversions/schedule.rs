@@ -0,0 +1,87 @@
+use crate::types::{proto, FromProtobufTypeError};
+use chrono::{NaiveTime, Timelike, Weekday};
+use mullvad_types::schedule::{ConnectionSchedule, ScheduleState};
+use prost_types::Timestamp;
+
+impl From<ConnectionSchedule> for proto::ConnectionSchedule {
+    fn from(schedule: ConnectionSchedule) -> Self {
+        use proto::connection_schedule::Weekday as ProtoWeekday;
+
+        let days = schedule
+            .days
+            .iter()
+            .map(|day| {
+                let day = match day {
+                    Weekday::Mon => ProtoWeekday::Monday,
+                    Weekday::Tue => ProtoWeekday::Tuesday,
+                    Weekday::Wed => ProtoWeekday::Wednesday,
+                    Weekday::Thu => ProtoWeekday::Thursday,
+                    Weekday::Fri => ProtoWeekday::Friday,
+                    Weekday::Sat => ProtoWeekday::Saturday,
+                    Weekday::Sun => ProtoWeekday::Sunday,
+                };
+                i32::from(day)
+            })
+            .collect();
+        proto::ConnectionSchedule {
+            days,
+            start: minutes_from_time(schedule.start),
+            end: minutes_from_time(schedule.end),
+        }
+    }
+}
+
+impl TryFrom<proto::ConnectionSchedule> for ConnectionSchedule {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(schedule: proto::ConnectionSchedule) -> Result<Self, Self::Error> {
+        use proto::connection_schedule::Weekday as ProtoWeekday;
+
+        let days = schedule
+            .days
+            .into_iter()
+            .map(|day| match ProtoWeekday::from_i32(day) {
+                Some(ProtoWeekday::Monday) => Ok(Weekday::Mon),
+                Some(ProtoWeekday::Tuesday) => Ok(Weekday::Tue),
+                Some(ProtoWeekday::Wednesday) => Ok(Weekday::Wed),
+                Some(ProtoWeekday::Thursday) => Ok(Weekday::Thu),
+                Some(ProtoWeekday::Friday) => Ok(Weekday::Fri),
+                Some(ProtoWeekday::Saturday) => Ok(Weekday::Sat),
+                Some(ProtoWeekday::Sunday) => Ok(Weekday::Sun),
+                None => Err(FromProtobufTypeError::InvalidArgument("invalid weekday")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if days.is_empty() {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "schedule has no days",
+            ));
+        }
+        Ok(ConnectionSchedule {
+            days,
+            start: time_from_minutes(schedule.start)?,
+            end: time_from_minutes(schedule.end)?,
+        })
+    }
+}
+
+fn minutes_from_time(time: NaiveTime) -> u32 {
+    time.hour() * 60 + time.minute()
+}
+
+fn time_from_minutes(minutes: u32) -> Result<NaiveTime, FromProtobufTypeError> {
+    NaiveTime::from_hms_opt(minutes / 60, minutes % 60, 0).ok_or(
+        FromProtobufTypeError::InvalidArgument("time of day is out of range"),
+    )
+}
+
+impl From<ScheduleState> for proto::ScheduleState {
+    fn from(state: ScheduleState) -> Self {
+        proto::ScheduleState {
+            active: state.active,
+            next_change: state.next_change.map(|next_change| Timestamp {
+                seconds: next_change.timestamp(),
+                nanos: next_change.timestamp_subsec_nanos() as i32,
+            }),
+        }
+    }
+}
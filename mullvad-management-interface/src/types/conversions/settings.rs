@@ -1,4 +1,11 @@
-use crate::types::{proto, FromProtobufTypeError};
+use crate::types::{
+    conversions::option_from_proto_string,
+    proto::{
+        self, tunnel_options::wireguard_options::Implementation as WireguardImplementationProto,
+    },
+    FromProtobufTypeError,
+};
+use mullvad_types::{relay_constraints::Constraint, wireguard::WireguardImplementation};
 use talpid_types::ErrorExt;
 
 impl From<&mullvad_types::settings::Settings> for proto::Settings {
@@ -23,6 +30,56 @@ impl From<&mullvad_types::settings::Settings> for proto::Settings {
         #[cfg(not(windows))]
         let split_tunnel = None;
 
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let excluded_destinations = Some(proto::ExcludedDestinations::from(
+            &settings.excluded_destinations,
+        ));
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let excluded_destinations = None;
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let split_tunnel_mode = Some(proto::SplitTunnelMode::from(settings.split_tunnel_mode));
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let split_tunnel_mode = None;
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let lan_rules = settings.lan_rules.as_ref().map(proto::LanRules::from);
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let lan_rules = None;
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let firewall_exceptions = settings
+            .firewall_exceptions
+            .iter()
+            .map(proto::FirewallException::from)
+            .collect();
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let firewall_exceptions = vec![];
+
+        #[cfg(not(target_os = "android"))]
+        let network_rules = Some(proto::NetworkRules::from(&settings.network_rules));
+        #[cfg(target_os = "android")]
+        let network_rules = None;
+
+        #[cfg(not(target_os = "android"))]
+        let schedules = settings
+            .schedules
+            .iter()
+            .cloned()
+            .map(proto::ConnectionSchedule::from)
+            .collect();
+        #[cfg(target_os = "android")]
+        let schedules = vec![];
+
+        #[cfg(not(target_os = "android"))]
+        let dns_blocklists = settings
+            .dns_blocklists
+            .iter()
+            .map(proto::DnsBlocklist::from)
+            .collect();
+        #[cfg(target_os = "android")]
+        let dns_blocklists = vec![];
+
         Self {
             relay_settings: Some(proto::RelaySettings::from(settings.get_relay_settings())),
             bridge_settings: Some(proto::BridgeSettings::from(
@@ -38,6 +95,72 @@ impl From<&mullvad_types::settings::Settings> for proto::Settings {
                 &settings.obfuscation_settings,
             )),
             split_tunnel,
+            custom_lists: Some(proto::CustomListSettings::from(&settings.custom_lists)),
+            escalation_ladder: Some(proto::EscalationLadder::from(&settings.escalation_ladder)),
+            load_aware_selection: settings.load_aware_selection,
+            avoid_recent_relays: settings.avoid_recent_relays,
+            prefer_ipv6: settings.prefer_ipv6,
+            reuse_obfuscator: settings.reuse_obfuscator,
+            latency_aware_selection: settings.latency_aware_selection,
+            sticky_relay_max_failures: settings.sticky_relay_max_failures,
+            protocol_preference: Some(proto::ProtocolPreference::from(
+                settings.protocol_preference,
+            )),
+            relay_list_source: Some(proto::RelayListSource::from(&settings.relay_list_source)),
+            excluded_destinations,
+            split_tunnel_mode,
+            network_rules,
+            schedules,
+            dns_blocklists,
+            lan_rules,
+            firewall_exceptions,
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl From<talpid_types::split_tunnel::SplitTunnelMode> for proto::SplitTunnelMode {
+    fn from(mode: talpid_types::split_tunnel::SplitTunnelMode) -> Self {
+        use talpid_types::split_tunnel::SplitTunnelMode;
+        Self {
+            mode: i32::from(match mode {
+                SplitTunnelMode::Exclude => proto::split_tunnel_mode::Mode::Exclude,
+                SplitTunnelMode::Include => proto::split_tunnel_mode::Mode::Include,
+            }),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl TryFrom<proto::SplitTunnelMode> for talpid_types::split_tunnel::SplitTunnelMode {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(mode: proto::SplitTunnelMode) -> Result<Self, Self::Error> {
+        match proto::split_tunnel_mode::Mode::from_i32(mode.mode) {
+            Some(proto::split_tunnel_mode::Mode::Exclude) => {
+                Ok(talpid_types::split_tunnel::SplitTunnelMode::Exclude)
+            }
+            Some(proto::split_tunnel_mode::Mode::Include) => {
+                Ok(talpid_types::split_tunnel::SplitTunnelMode::Include)
+            }
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "invalid split tunnel mode",
+            )),
+        }
+    }
+}
+
+impl From<&mullvad_types::excluded_destinations::ExcludedDestinations>
+    for proto::ExcludedDestinations
+{
+    fn from(destinations: &mullvad_types::excluded_destinations::ExcludedDestinations) -> Self {
+        Self {
+            networks: destinations
+                .networks
+                .iter()
+                .map(|network| network.to_string())
+                .collect(),
+            domains: destinations.domains.clone(),
         }
     }
 }
@@ -65,16 +188,71 @@ impl From<&mullvad_types::settings::DnsOptions> for proto::DnsOptions {
                     .iter()
                     .map(|addr| addr.to_string())
                     .collect(),
+                encrypted_resolvers: options
+                    .custom_options
+                    .encrypted_resolvers
+                    .iter()
+                    .map(proto::EncryptedDnsResolver::from)
+                    .collect(),
             }),
         }
     }
 }
 
+impl From<&mullvad_types::settings::EncryptedDnsResolver> for proto::EncryptedDnsResolver {
+    fn from(resolver: &mullvad_types::settings::EncryptedDnsResolver) -> Self {
+        use mullvad_types::settings::EncryptedDnsProtocol;
+        use proto::encrypted_dns_resolver::Protocol;
+
+        let protocol = match resolver.protocol {
+            EncryptedDnsProtocol::Https => Protocol::Https,
+            EncryptedDnsProtocol::Tls => Protocol::Tls,
+        };
+        proto::EncryptedDnsResolver {
+            protocol: i32::from(protocol),
+            server_name: resolver.server_name.clone(),
+            address: resolver.address.to_string(),
+        }
+    }
+}
+
+impl TryFrom<proto::EncryptedDnsResolver> for mullvad_types::settings::EncryptedDnsResolver {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(resolver: proto::EncryptedDnsResolver) -> Result<Self, Self::Error> {
+        use mullvad_types::settings::EncryptedDnsProtocol;
+        use proto::encrypted_dns_resolver::Protocol;
+
+        let protocol = match Protocol::from_i32(resolver.protocol) {
+            Some(Protocol::Https) => EncryptedDnsProtocol::Https,
+            Some(Protocol::Tls) => EncryptedDnsProtocol::Tls,
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid encrypted DNS protocol",
+                ))
+            }
+        };
+        if resolver.server_name.is_empty() {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "missing encrypted DNS server name",
+            ));
+        }
+        Ok(mullvad_types::settings::EncryptedDnsResolver {
+            protocol,
+            server_name: resolver.server_name,
+            address: resolver.address.parse().map_err(|_| {
+                FromProtobufTypeError::InvalidArgument("invalid encrypted DNS server address")
+            })?,
+        })
+    }
+}
+
 impl From<&mullvad_types::settings::TunnelOptions> for proto::TunnelOptions {
     fn from(options: &mullvad_types::settings::TunnelOptions) -> Self {
         Self {
             openvpn: Some(proto::tunnel_options::OpenvpnOptions {
                 mssfix: u32::from(options.openvpn.mssfix.unwrap_or_default()),
+                cipher: options.openvpn.cipher.clone().unwrap_or_default(),
             }),
             wireguard: Some(proto::tunnel_options::WireguardOptions {
                 mtu: u32::from(options.wireguard.options.mtu.unwrap_or_default()),
@@ -86,15 +264,143 @@ impl From<&mullvad_types::settings::TunnelOptions> for proto::TunnelOptions {
                 use_wireguard_nt: options.wireguard.options.use_wireguard_nt,
                 #[cfg(not(windows))]
                 use_wireguard_nt: false,
-                use_pq_safe_psk: options.wireguard.options.use_pq_safe_psk,
+                preferred_ip_version: options
+                    .wireguard
+                    .preferred_ip_version
+                    .option()
+                    .map(proto::IpVersion::from)
+                    .map(proto::IpVersionConstraint::from),
+                require_obfuscation: options.wireguard.require_obfuscation,
+                implementation: match options.wireguard.implementation {
+                    WireguardImplementation::Kernel => WireguardImplementationProto::Kernel,
+                    WireguardImplementation::Userspace => WireguardImplementationProto::Userspace,
+                } as i32,
+                quantum_resistant: Some(proto::QuantumResistantState::from(
+                    options.wireguard.quantum_resistant,
+                )),
+                ephemeral_keys: options.wireguard.ephemeral_keys,
+                port_fallback: Some(proto::WireguardPortFallback::from(
+                    options.wireguard.port_fallback,
+                )),
+                mtu_detection: options.wireguard.options.mtu_detection,
+                daita: options.wireguard.options.daita,
             }),
             generic: Some(proto::tunnel_options::GenericOptions {
                 enable_ipv6: options.generic.enable_ipv6,
+                split_dns: options
+                    .generic
+                    .split_dns
+                    .iter()
+                    .map(|rule| proto::tunnel_options::SplitDnsRule {
+                        domains: rule.domains.clone(),
+                        resolvers: rule.resolvers.iter().map(|addr| addr.to_string()).collect(),
+                    })
+                    .collect(),
             }),
             #[cfg(not(target_os = "android"))]
             dns_options: Some(proto::DnsOptions::from(&options.dns_options)),
             #[cfg(target_os = "android")]
             dns_options: None,
+            protocol_ports: Some(proto::tunnel_options::ProtocolPorts {
+                wireguard: options
+                    .protocol_ports
+                    .wireguard
+                    .map(u32::from)
+                    .unwrap_or(0),
+                openvpn: options
+                    .protocol_ports
+                    .openvpn
+                    .option()
+                    .map(proto::TransportPort::from),
+            }),
+            relay_rotation: Some(proto::RelayRotationPolicy::from(options.relay_rotation)),
+            max_repicks: options.max_repicks,
+        }
+    }
+}
+
+impl From<mullvad_types::relay_rotation::RelayRotationPolicy> for proto::RelayRotationPolicy {
+    fn from(policy: mullvad_types::relay_rotation::RelayRotationPolicy) -> Self {
+        use mullvad_types::relay_rotation::RelayRotationPolicy;
+        use proto::relay_rotation_policy::{Policy, TimeOfDay};
+
+        let policy = match policy {
+            RelayRotationPolicy::Off => Policy::Off(()),
+            RelayRotationPolicy::Interval { hours } => Policy::IntervalHours(hours),
+            RelayRotationPolicy::Daily { hour, minute } => {
+                Policy::Daily(TimeOfDay { hour, minute })
+            }
+        };
+        proto::RelayRotationPolicy {
+            policy: Some(policy),
+        }
+    }
+}
+
+impl TryFrom<proto::RelayRotationPolicy> for mullvad_types::relay_rotation::RelayRotationPolicy {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(policy: proto::RelayRotationPolicy) -> Result<Self, Self::Error> {
+        use mullvad_types::relay_rotation::RelayRotationPolicy;
+        use proto::relay_rotation_policy::Policy;
+
+        match policy.policy {
+            Some(Policy::Off(())) => Ok(RelayRotationPolicy::Off),
+            Some(Policy::IntervalHours(hours)) => Ok(RelayRotationPolicy::Interval { hours }),
+            Some(Policy::Daily(time)) => Ok(RelayRotationPolicy::Daily {
+                hour: time.hour,
+                minute: time.minute,
+            }),
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "missing relay rotation policy",
+            )),
+        }
+    }
+}
+
+impl From<mullvad_types::wireguard::QuantumResistantState> for proto::QuantumResistantState {
+    fn from(state: mullvad_types::wireguard::QuantumResistantState) -> Self {
+        use mullvad_types::wireguard::QuantumResistantState;
+        Self {
+            state: i32::from(match state {
+                QuantumResistantState::Auto => proto::quantum_resistant_state::State::Auto,
+                QuantumResistantState::On => proto::quantum_resistant_state::State::On,
+                QuantumResistantState::Off => proto::quantum_resistant_state::State::Off,
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::QuantumResistantState> for mullvad_types::wireguard::QuantumResistantState {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(state: proto::QuantumResistantState) -> Result<Self, Self::Error> {
+        use mullvad_types::wireguard::QuantumResistantState;
+        match proto::quantum_resistant_state::State::from_i32(state.state) {
+            Some(proto::quantum_resistant_state::State::Auto) => Ok(QuantumResistantState::Auto),
+            Some(proto::quantum_resistant_state::State::On) => Ok(QuantumResistantState::On),
+            Some(proto::quantum_resistant_state::State::Off) => Ok(QuantumResistantState::Off),
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "invalid quantum resistance state",
+            )),
+        }
+    }
+}
+
+impl From<mullvad_types::wireguard::PortFallback> for proto::WireguardPortFallback {
+    fn from(fallback: mullvad_types::wireguard::PortFallback) -> Self {
+        Self {
+            port_53: fallback.port_53,
+            port_443: fallback.port_443,
+        }
+    }
+}
+
+impl From<proto::WireguardPortFallback> for mullvad_types::wireguard::PortFallback {
+    fn from(fallback: proto::WireguardPortFallback) -> Self {
+        Self {
+            port_53: fallback.port_53,
+            port_443: fallback.port_443,
         }
     }
 }
@@ -126,6 +432,55 @@ impl TryFrom<proto::TunnelOptions> for mullvad_types::settings::TunnelOptions {
                 "missing tunnel DNS options",
             ))?;
 
+        // Older clients do not send any protocol ports
+        let protocol_ports = match options.protocol_ports {
+            Some(ports) => mullvad_types::settings::ProtocolPorts {
+                wireguard: if ports.wireguard == 0 {
+                    Constraint::Any
+                } else {
+                    Constraint::Only(ports.wireguard as u16)
+                },
+                openvpn: Constraint::from(
+                    ports
+                        .openvpn
+                        .map(mullvad_types::relay_constraints::TransportPort::try_from)
+                        .transpose()?,
+                ),
+            },
+            None => mullvad_types::settings::ProtocolPorts::default(),
+        };
+
+        // Older clients do not send any rotation policy
+        let relay_rotation = options
+            .relay_rotation
+            .map(mullvad_types::relay_rotation::RelayRotationPolicy::try_from)
+            .transpose()?
+            .unwrap_or_default();
+
+        // Older clients do not send any maximum number of re-picks
+        let max_repicks = match options.max_repicks {
+            0 => mullvad_types::settings::TunnelOptions::default().max_repicks,
+            max_repicks => max_repicks,
+        };
+
+        // Older clients do not send any quantum resistance state
+        let quantum_resistant = wireguard_options
+            .quantum_resistant
+            .map(mullvad_types::wireguard::QuantumResistantState::try_from)
+            .transpose()?
+            .unwrap_or_default();
+
+        let implementation =
+            match WireguardImplementationProto::from_i32(wireguard_options.implementation) {
+                Some(WireguardImplementationProto::Kernel) => WireguardImplementation::Kernel,
+                Some(WireguardImplementationProto::Userspace) => WireguardImplementation::Userspace,
+                None => {
+                    return Err(FromProtobufTypeError::InvalidArgument(
+                        "invalid wireguard implementation",
+                    ))
+                }
+            };
+
         Ok(Self {
             openvpn: net::openvpn::TunnelOptions {
                 mssfix: if openvpn_options.mssfix != 0 {
@@ -133,6 +488,7 @@ impl TryFrom<proto::TunnelOptions> for mullvad_types::settings::TunnelOptions {
                 } else {
                     None
                 },
+                cipher: option_from_proto_string(openvpn_options.cipher),
             },
             wireguard: mullvad_types::wireguard::TunnelOptions {
                 options: net::wireguard::TunnelOptions {
@@ -141,7 +497,10 @@ impl TryFrom<proto::TunnelOptions> for mullvad_types::settings::TunnelOptions {
                     } else {
                         None
                     },
-                    use_pq_safe_psk: wireguard_options.use_pq_safe_psk,
+                    // Resolved from `quantum_resistant` when tunnel parameters are generated
+                    use_pq_safe_psk: false,
+                    mtu_detection: wireguard_options.mtu_detection,
+                    daita: wireguard_options.daita,
                     #[cfg(windows)]
                     use_wireguard_nt: wireguard_options.use_wireguard_nt,
                 },
@@ -159,12 +518,53 @@ impl TryFrom<proto::TunnelOptions> for mullvad_types::settings::TunnelOptions {
                         );
                         FromProtobufTypeError::InvalidArgument("invalid rotation interval")
                     })?,
+                preferred_ip_version: match wireguard_options.preferred_ip_version {
+                    Some(constraint) => match proto::IpVersion::from_i32(constraint.protocol) {
+                        Some(proto::IpVersion::V4) => Constraint::Only(net::IpVersion::V4),
+                        Some(proto::IpVersion::V6) => Constraint::Only(net::IpVersion::V6),
+                        None => {
+                            return Err(FromProtobufTypeError::InvalidArgument(
+                                "invalid ip protocol version",
+                            ))
+                        }
+                    },
+                    None => Constraint::Any,
+                },
+                require_obfuscation: wireguard_options.require_obfuscation,
+                implementation,
+                quantum_resistant,
+                ephemeral_keys: wireguard_options.ephemeral_keys,
+                port_fallback: wireguard_options
+                    .port_fallback
+                    .map(mullvad_types::wireguard::PortFallback::from)
+                    .unwrap_or_default(),
             },
             generic: net::GenericTunnelOptions {
                 enable_ipv6: generic_options.enable_ipv6,
+                split_dns: generic_options
+                    .split_dns
+                    .into_iter()
+                    .map(|rule| {
+                        Ok(net::SplitDnsRule {
+                            domains: rule.domains,
+                            resolvers: rule
+                                .resolvers
+                                .into_iter()
+                                .map(|addr| {
+                                    addr.parse().map_err(|_| {
+                                        FromProtobufTypeError::InvalidArgument("invalid IP address")
+                                    })
+                                })
+                                .collect::<Result<Vec<_>, _>>()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, FromProtobufTypeError>>()?,
             },
             #[cfg(not(target_os = "android"))]
             dns_options: mullvad_types::settings::DnsOptions::try_from(dns_options)?,
+            protocol_ports,
+            relay_rotation,
+            max_repicks,
         })
     }
 }
@@ -221,6 +621,11 @@ impl TryFrom<proto::DnsOptions> for mullvad_types::settings::DnsOptions {
                         })
                     })
                     .collect::<Result<Vec<_>, _>>()?,
+                encrypted_resolvers: custom_options
+                    .encrypted_resolvers
+                    .into_iter()
+                    .map(mullvad_types::settings::EncryptedDnsResolver::try_from)
+                    .collect::<Result<Vec<_>, _>>()?,
             },
         })
     }
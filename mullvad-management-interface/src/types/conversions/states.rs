@@ -128,6 +128,9 @@ impl From<mullvad_types::states::TunnelState> for proto::TunnelState {
                             talpid_tunnel::ParameterGenerationError::CustomTunnelHostResultionError => {
                                 i32::from(GenerationError::CustomTunnelHostResolutionError)
                             }
+                            talpid_tunnel::ParameterGenerationError::Cancelled => {
+                                i32::from(GenerationError::Cancelled)
+                            }
                         }
                             } else {
                                 0
@@ -284,6 +287,7 @@ impl TryFrom<proto::TunnelState> for mullvad_types::states::TunnelState {
                             Some(proto::error_state::GenerationError::NoMatchingBridgeRelay) => talpid_tunnel::ParameterGenerationError::NoMatchingBridgeRelay,
                             Some(proto::error_state::GenerationError::NoMatchingRelay) => talpid_tunnel::ParameterGenerationError::NoMatchingRelay,
                             Some(proto::error_state::GenerationError::NoWireguardKey) => talpid_tunnel::ParameterGenerationError::NoWireguardKey,
+                            Some(proto::error_state::GenerationError::Cancelled) => talpid_tunnel::ParameterGenerationError::Cancelled,
                             _ => return Err(FromProtobufTypeError::InvalidArgument(
                                 "invalid parameter error",
                             )),
@@ -0,0 +1,30 @@
+use crate::types::proto;
+use prost_types::Timestamp;
+
+impl From<mullvad_types::traffic_stats::TrafficSample> for proto::TrafficSample {
+    fn from(sample: mullvad_types::traffic_stats::TrafficSample) -> Self {
+        proto::TrafficSample {
+            timestamp: Some(Timestamp {
+                seconds: sample.timestamp.timestamp(),
+                nanos: sample.timestamp.timestamp_subsec_nanos() as i32,
+            }),
+            rx_bytes: sample.rx_bytes,
+            tx_bytes: sample.tx_bytes,
+            rx_packets: sample.rx_packets,
+            tx_packets: sample.tx_packets,
+            rx_rate: sample.rx_rate,
+            tx_rate: sample.tx_rate,
+        }
+    }
+}
+
+impl From<Vec<mullvad_types::traffic_stats::TrafficSample>> for proto::TrafficStats {
+    fn from(samples: Vec<mullvad_types::traffic_stats::TrafficSample>) -> Self {
+        proto::TrafficStats {
+            samples: samples
+                .into_iter()
+                .map(proto::TrafficSample::from)
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Filters and scorers that are applied to relays on top of the relay constraints.
+//!
+//! A [RelayFilter] decides whether a relay may be used by tunnels at all, and a [RelayScorer]
+//! scales the weight of a relay when one of the matching relays is picked. Additional filters and
+//! scorers can be registered with [crate::RelaySelector::add_filter] and
+//! [crate::RelaySelector::add_scorer]. They apply to the entry and exit relays, but not to
+//! bridges or obfuscators.
+
+use mullvad_types::relay_list::{Relay, RelayEndpointData};
+use std::{collections::HashSet, sync::Arc};
+
+/// Decides whether a relay may be used by tunnels.
+pub trait RelayFilter: Send + Sync {
+    /// Returns whether `relay` may be used.
+    fn matches(&self, relay: &Relay) -> bool;
+}
+
+impl<F: Fn(&Relay) -> bool + Send + Sync> RelayFilter for F {
+    fn matches(&self, relay: &Relay) -> bool {
+        self(relay)
+    }
+}
+
+/// Matches WireGuard relays that support DAITA, and all relays that are not WireGuard relays.
+pub(crate) struct DaitaRelays;
+
+impl RelayFilter for DaitaRelays {
+    fn matches(&self, relay: &Relay) -> bool {
+        match &relay.endpoint_data {
+            RelayEndpointData::Wireguard(data) => data.daita,
+            _ => true,
+        }
+    }
+}
+
+/// Scales the weight of a relay when picking between relays that match the constraints.
+pub trait RelayScorer: Send + Sync {
+    /// Returns the factor that the weight of `relay` is multiplied by. A factor of 1 keeps the
+    /// weight, and a relay with a factor of 0 is only picked if every relay has a weight of 0.
+    /// Negative factors are treated as 0.
+    fn scale(&self, relay: &Relay) -> f64;
+}
+
+impl<F: Fn(&Relay) -> f64 + Send + Sync> RelayScorer for F {
+    fn scale(&self, relay: &Relay) -> f64 {
+        self(relay)
+    }
+}
+
+/// Only matches relays whose hostname is in the set.
+pub(crate) struct AllowedHostnames<'a>(pub &'a HashSet<String>);
+
+impl RelayFilter for AllowedHostnames<'_> {
+    fn matches(&self, relay: &Relay) -> bool {
+        self.0.contains(&relay.hostname)
+    }
+}
+
+/// Matches all relays except those whose hostname is in the set.
+pub(crate) struct ExcludedHostnames<'a>(pub &'a HashSet<String>);
+
+impl RelayFilter for ExcludedHostnames<'_> {
+    fn matches(&self, relay: &Relay) -> bool {
+        !self.0.contains(&relay.hostname)
+    }
+}
+
+/// Matches WireGuard relays that support negotiating a post-quantum safe PSK, and all relays that
+/// are not WireGuard relays.
+pub(crate) struct QuantumResistantRelays;
+
+impl RelayFilter for QuantumResistantRelays {
+    fn matches(&self, relay: &Relay) -> bool {
+        match &relay.endpoint_data {
+            RelayEndpointData::Wireguard(data) => data.quantum_resistant,
+            _ => true,
+        }
+    }
+}
+
+/// Scales the weight of a relay down linearly with its load. A relay with a load of 0% keeps its
+/// weight and a fully loaded relay gets a weight of 0. Relays without a reported load are not
+/// scaled.
+pub(crate) struct LoadScorer;
+
+impl RelayScorer for LoadScorer {
+    fn scale(&self, relay: &Relay) -> f64 {
+        match relay.load {
+            Some(load) => 1.0 - f64::from(load.min(100)) / 100.0,
+            None => 1.0,
+        }
+    }
+}
+
+/// Returns whether `relay` matches every filter in `filters`.
+pub(crate) fn matches_all(filters: &[&dyn RelayFilter], relay: &Relay) -> bool {
+    filters.iter().all(|filter| filter.matches(relay))
+}
+
+/// Returns the product of the factors given by every scorer in `scorers`.
+pub(crate) fn combined_scale(scorers: &[Arc<dyn RelayScorer>], relay: &Relay) -> f64 {
+    scorers
+        .iter()
+        .map(|scorer| scorer.scale(relay).max(0.0))
+        .product()
+}
@@ -4,25 +4,30 @@
 use chrono::{DateTime, Local};
 use ipnetwork::IpNetwork;
 use mullvad_types::{
+    custom_list::CustomListsSettings,
+    custom_tunnel::ConnectionConfig,
     endpoint::{MullvadEndpoint, MullvadWireguardEndpoint},
-    location::{Coordinates, Location},
+    escalation::{EscalationLadder, EscalationStep},
+    location::{Coordinates, Hostname, Location},
     relay_constraints::{
-        BridgeSettings, BridgeState, Constraint, InternalBridgeConstraints, LocationConstraint,
-        Match, ObfuscationSettings, OpenVpnConstraints, Ownership, Providers, RelayConstraints,
-        RelaySettings, SelectedObfuscation, Set, TransportPort, Udp2TcpObfuscationSettings,
-        WireguardConstraints,
+        BridgeSettings, BridgeState, Constraint, EntryRelayPolicy, InternalBridgeConstraints,
+        LocationConstraint, Match, ObfuscationMethod, ObfuscationSettings, OpenVpnConstraints,
+        Ownership, PortRanges, Providers, RelayConstraints, RelayConstraintsUpdate, RelaySettings,
+        SelectedObfuscation, Set, TransportPort, Udp2TcpObfuscationSettings, WireguardConstraints,
     },
     relay_list::{BridgeEndpointData, Relay, RelayEndpointData, RelayList},
+    wireguard::PortFallback,
     CustomTunnelEndpoint,
 };
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::Mutex;
 use rand::{seq::SliceRandom, Rng};
 use std::{
+    collections::{HashMap, HashSet},
     io,
     net::{IpAddr, SocketAddr},
     path::Path,
     sync::Arc,
-    time::{self, SystemTime},
+    time::{self, Duration, SystemTime},
 };
 use talpid_types::{
     net::{
@@ -32,9 +37,18 @@ use talpid_types::{
     ErrorExt,
 };
 
+use filter::{
+    AllowedHostnames, DaitaRelays, ExcludedHostnames, LoadScorer, QuantumResistantRelays,
+    RelayFilter, RelayScorer,
+};
 use matcher::{BridgeMatcher, EndpointMatcher, OpenVpnMatcher, RelayMatcher, WireguardMatcher};
 
+pub mod filter;
 mod matcher;
+mod rng;
+mod signature;
+#[cfg(feature = "simulation")]
+pub mod simulation;
 pub mod updater;
 
 const DATE_TIME_FORMAT_STR: &str = "%Y-%m-%d %H:%M:%S%.3f";
@@ -45,6 +59,17 @@ const WIREGUARD_EXIT_IP_VERSION: Constraint<IpVersion> = Constraint::Only(IpVers
 
 const UDP2TCP_PORTS: [u16; 3] = [80, 443, 5001];
 
+/// Distance (km) from the client at which the weight of a relay is halved, when relays are
+/// weighted by their distance to the client.
+const CLIENT_DISTANCE_HALF_WEIGHT: f64 = 100.0;
+/// Factor that relay weights are multiplied by before being scaled by the distance to the client,
+/// so that the scaled weights can be rounded to integers without losing precision.
+const CLIENT_DISTANCE_WEIGHT_RESOLUTION: f64 = 1000.0;
+
+/// Latency (ms) above that of the fastest candidate at which the weight of a relay is halved, when
+/// relays are weighted by their measured latency.
+const RELAY_LATENCY_HALF_WEIGHT: f64 = 10.0;
+
 /// Minimum number of bridges to keep for selection when filtering by distance.
 const MIN_BRIDGE_COUNT: usize = 5;
 
@@ -63,17 +88,55 @@ pub enum Error {
     #[error(display = "No relays matching current constraints")]
     NoRelay,
 
+    /// `forced` is set if the bridge state requires a bridge to be used. In auto mode, the relay
+    /// selector falls back on connecting without a bridge.
     #[error(display = "No bridges matching current constraints")]
-    NoBridge,
+    NoBridge { forced: bool },
 
     #[error(display = "No obfuscators matching current constraints")]
     NoObfuscator,
 
+    #[error(display = "All relays matching current constraints are undergoing maintenance")]
+    RelaysInMaintenance,
+
+    #[error(display = "No relay matching the entry constraints can be used as a multihop entry")]
+    NoMultihopEntry,
+
+    #[error(display = "No relay matching current constraints is allowed for this account")]
+    NoAllowedRelay,
+
+    #[error(display = "Every relay matching current constraints is excluded")]
+    AllRelaysExcluded,
+
+    #[error(display = "No relay matching current constraints supports quantum resistant tunnels")]
+    NoQuantumResistantRelay,
+
+    #[error(display = "No relay matching current constraints supports DAITA")]
+    NoDaitaRelay,
+
+    #[error(display = "Every relay matching current constraints is rejected by a relay filter")]
+    RejectedByFilter,
+
     #[error(display = "Failure in serialization of the relay list")]
     Serialize(#[error(source)] serde_json::Error),
 
     #[error(display = "Downloader already shut down")]
     DownloaderShutDown,
+
+    #[error(display = "Failed to download the relay list")]
+    Download(#[error(source)] mullvad_api::Error),
+
+    #[error(display = "Failed to read the relay list file")]
+    ReadRelayList(#[error(source)] io::Error),
+
+    #[error(display = "Failed to parse the relay list")]
+    ParseRelayList(#[error(source)] serde_json::Error),
+
+    #[error(display = "The relay list does not contain any relays")]
+    EmptyRelayList,
+
+    #[error(display = "Failed to verify the relay list")]
+    VerifyRelayList(#[error(source)] signature::Error),
 }
 
 struct ParsedRelays {
@@ -164,12 +227,14 @@ impl ParsedRelays {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct SelectorConfig {
     pub relay_settings: RelaySettings,
     pub bridge_state: BridgeState,
     pub bridge_settings: BridgeSettings,
     pub obfuscation_settings: ObfuscationSettings,
+    pub custom_lists: CustomListsSettings,
+    pub escalation_ladder: EscalationLadder,
     pub default_tunnel_type: TunnelType,
 }
 
@@ -177,6 +242,34 @@ pub struct SelectorConfig {
 pub struct RelaySelector {
     config: Arc<Mutex<SelectorConfig>>,
     parsed_relays: Arc<Mutex<ParsedRelays>>,
+    client_location: Arc<Mutex<Option<Coordinates>>>,
+    relay_latencies: Arc<Mutex<HashMap<String, Duration>>>,
+    load_aware: Arc<Mutex<bool>>,
+    maintenance_windows: Arc<Mutex<HashMap<String, MaintenanceWindow>>>,
+    allowed_relays: Arc<Mutex<Option<HashSet<String>>>>,
+    excluded_relays: Arc<Mutex<HashSet<String>>>,
+    quantum_resistant: Arc<Mutex<bool>>,
+    daita: Arc<Mutex<bool>>,
+    port_fallback: Arc<Mutex<PortFallback>>,
+    prefer_ipv6: Arc<Mutex<bool>>,
+    ipv6_available: Arc<Mutex<bool>>,
+    filters: Arc<Mutex<Vec<Arc<dyn RelayFilter>>>>,
+    scorers: Arc<Mutex<Vec<Arc<dyn RelayScorer>>>>,
+    /// Returns the current time. Replaced in tests.
+    clock: fn() -> SystemTime,
+}
+
+/// Period of time during which a relay is unavailable due to scheduled maintenance.
+#[derive(Debug, Clone, Copy)]
+pub struct MaintenanceWindow {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl MaintenanceWindow {
+    fn contains(&self, time: SystemTime) -> bool {
+        self.start <= time && time < self.end
+    }
 }
 
 impl RelaySelector {
@@ -202,6 +295,46 @@ impl RelaySelector {
         RelaySelector {
             config: Arc::new(Mutex::new(config)),
             parsed_relays: Arc::new(Mutex::new(unsynchronized_parsed_relays)),
+            client_location: Arc::new(Mutex::new(None)),
+            relay_latencies: Arc::new(Mutex::new(HashMap::new())),
+            load_aware: Arc::new(Mutex::new(true)),
+            maintenance_windows: Arc::new(Mutex::new(HashMap::new())),
+            allowed_relays: Arc::new(Mutex::new(None)),
+            excluded_relays: Arc::new(Mutex::new(HashSet::new())),
+            quantum_resistant: Arc::new(Mutex::new(false)),
+            daita: Arc::new(Mutex::new(false)),
+            port_fallback: Arc::new(Mutex::new(PortFallback::default())),
+            prefer_ipv6: Arc::new(Mutex::new(false)),
+            ipv6_available: Arc::new(Mutex::new(true)),
+            filters: Arc::new(Mutex::new(vec![])),
+            scorers: Arc::new(Mutex::new(vec![])),
+            clock: SystemTime::now,
+        }
+    }
+
+    /// Returns a new `RelaySelector` backed by a fixed relay list. Unlike [Self::new], this does
+    /// not read any relays from disk.
+    pub fn from_relay_list(config: SelectorConfig, relay_list: RelayList) -> Self {
+        RelaySelector {
+            config: Arc::new(Mutex::new(config)),
+            parsed_relays: Arc::new(Mutex::new(ParsedRelays::from_relay_list(
+                relay_list,
+                SystemTime::now(),
+            ))),
+            client_location: Arc::new(Mutex::new(None)),
+            relay_latencies: Arc::new(Mutex::new(HashMap::new())),
+            load_aware: Arc::new(Mutex::new(true)),
+            maintenance_windows: Arc::new(Mutex::new(HashMap::new())),
+            allowed_relays: Arc::new(Mutex::new(None)),
+            excluded_relays: Arc::new(Mutex::new(HashSet::new())),
+            quantum_resistant: Arc::new(Mutex::new(false)),
+            daita: Arc::new(Mutex::new(false)),
+            port_fallback: Arc::new(Mutex::new(PortFallback::default())),
+            prefer_ipv6: Arc::new(Mutex::new(false)),
+            ipv6_available: Arc::new(Mutex::new(true)),
+            filters: Arc::new(Mutex::new(vec![])),
+            scorers: Arc::new(Mutex::new(vec![])),
+            clock: SystemTime::now,
         }
     }
 
@@ -209,6 +342,382 @@ impl RelaySelector {
         *self.config.lock() = config;
     }
 
+    /// Returns the configuration that relays are currently selected with.
+    pub fn config(&self) -> SelectorConfig {
+        self.config.lock().clone()
+    }
+
+    /// Sets the approximate location of the client. When set, relays closer to the client are
+    /// preferred if the location constraint is `any`, and the nearest location resolves to the
+    /// closest city.
+    pub fn set_client_location(&self, location: Option<Coordinates>) {
+        *self.client_location.lock() = location;
+    }
+
+    /// Returns whether the approximate location of the client is known.
+    pub fn has_client_location(&self) -> bool {
+        self.client_location.lock().is_some()
+    }
+
+    /// Sets the measured round-trip times to relays, by hostname. When any of the relays that
+    /// match the constraints has a measured latency, relays with a lower latency are preferred.
+    pub fn set_relay_latencies(&self, latencies: HashMap<String, Duration>) {
+        *self.relay_latencies.lock() = latencies;
+    }
+
+    /// Sets whether relays with a high load are less likely to be selected. Enabled by default.
+    /// When disabled, relays are selected using only their weights, distance, and latency.
+    pub fn set_load_aware(&self, enabled: bool) {
+        *self.load_aware.lock() = enabled;
+    }
+
+    /// Sets scheduled maintenance windows by relay hostname. Relays are treated as inactive while
+    /// they are in a maintenance window.
+    pub fn set_maintenance_windows(&self, windows: HashMap<String, MaintenanceWindow>) {
+        *self.maintenance_windows.lock() = windows;
+    }
+
+    /// Restricts the relays that tunnels may use to the relays with the given hostnames. Bridges
+    /// are not affected. `None` allows every relay.
+    pub fn set_allowed_relays(&self, hostnames: Option<HashSet<String>>) {
+        *self.allowed_relays.lock() = hostnames;
+    }
+
+    /// Returns the hostnames set with [Self::set_allowed_relays].
+    pub fn allowed_relays(&self) -> Option<HashSet<String>> {
+        self.allowed_relays.lock().clone()
+    }
+
+    /// Prevents the relays with the given hostnames from being used by tunnels. Bridges are not
+    /// affected.
+    pub fn set_excluded_relays(&self, hostnames: HashSet<String>) {
+        *self.excluded_relays.lock() = hostnames;
+    }
+
+    /// Returns the hostnames set with [Self::set_excluded_relays].
+    pub fn excluded_relays(&self) -> HashSet<String> {
+        self.excluded_relays.lock().clone()
+    }
+
+    /// Sets whether tunnels negotiate a post-quantum safe PSK. When enabled, only WireGuard relays
+    /// that support the exchange are used. Disabled by default.
+    pub fn set_quantum_resistant(&self, enabled: bool) {
+        *self.quantum_resistant.lock() = enabled;
+    }
+
+    /// Sets whether tunnels use DAITA. When enabled, only WireGuard relays that support DAITA are
+    /// used. Disabled by default.
+    pub fn set_daita(&self, enabled: bool) {
+        *self.daita.lock() = enabled;
+    }
+
+    /// Sets the well-known ports that WireGuard tunnels fall back to on later retry attempts if no
+    /// WireGuard port is constrained. Only port 53 is used by default.
+    pub fn set_port_fallback(&self, port_fallback: PortFallback) {
+        *self.port_fallback.lock() = port_fallback;
+    }
+
+    /// Sets whether relay endpoints are reached over IPv6 when the IP version is not constrained
+    /// and the host has IPv6 connectivity. IPv4 is used if no relay has a matching IPv6 endpoint.
+    /// Disabled by default.
+    pub fn set_prefer_ipv6(&self, enabled: bool) {
+        *self.prefer_ipv6.lock() = enabled;
+    }
+
+    /// Sets whether the host can reach the internet over IPv6. IPv6 is not preferred while this
+    /// is false. Assumed to be available by default.
+    pub fn set_ipv6_available(&self, available: bool) {
+        *self.ipv6_available.lock() = available;
+    }
+
+    /// Registers a filter that every entry and exit relay must match, in addition to the relay
+    /// constraints. Bridges and obfuscators are not affected.
+    pub fn add_filter(&self, filter: impl RelayFilter + 'static) {
+        self.filters.lock().push(Arc::new(filter));
+    }
+
+    /// Registers a scorer that the weight of each entry and exit relay is scaled by when picking
+    /// between the relays that match the constraints. Bridges and obfuscators are not affected.
+    pub fn add_scorer(&self, scorer: impl RelayScorer + 'static) {
+        self.scorers.lock().push(Arc::new(scorer));
+    }
+
+    /// Removes all filters and scorers added with [Self::add_filter] and [Self::add_scorer].
+    pub fn clear_filters(&self) {
+        self.filters.lock().clear();
+        self.scorers.lock().clear();
+    }
+
+    /// Replaces the relay list used for selection.
+    pub fn set_relays(&self, relay_list: RelayList) {
+        *self.parsed_relays.lock() = ParsedRelays::from_relay_list(relay_list, SystemTime::now());
+    }
+
+    /// Returns whether the selector has any relays to select from.
+    pub fn has_relays(&self) -> bool {
+        !self.parsed_relays.lock().relays().is_empty()
+    }
+
+    /// Returns the time at which the relay list was last replaced.
+    pub fn relays_last_updated(&self) -> SystemTime {
+        self.parsed_relays.lock().last_updated()
+    }
+
+    /// Returns the relays that tunnels may currently use with `constraints_override` applied to
+    /// the relay constraints. Returns an empty list if a custom relay is used.
+    pub fn candidate_relays(
+        &self,
+        constraints_override: Option<RelayConstraintsUpdate>,
+    ) -> Vec<Relay> {
+        let relay_constraints = match self.effective_constraints(constraints_override) {
+            Some(relay_constraints) => relay_constraints,
+            None => return vec![],
+        };
+        let custom_lists = self.config.lock().custom_lists.clone();
+        let relays = self.tunnel_relays();
+        let parsed_relays = self.parsed_relays.lock();
+        let nearest_cities = self.nearest_cities(&parsed_relays);
+        Self::matching_relays(
+            &relay_constraints,
+            &custom_lists,
+            nearest_cities.as_deref(),
+            &parsed_relays,
+            &relays,
+        )
+    }
+
+    /// Returns the relays in `relays` that match `relay_constraints`, with any custom list or
+    /// nearest location in the constraints resolved using `custom_lists` and `nearest_cities`.
+    /// Only the closest city with matching relays is included for the nearest location.
+    fn matching_relays(
+        relay_constraints: &RelayConstraints,
+        custom_lists: &CustomListsSettings,
+        nearest_cities: Option<&[LocationConstraint]>,
+        parsed_relays: &ParsedRelays,
+        relays: &[Relay],
+    ) -> Vec<Relay> {
+        let is_nearest = |location: &Constraint<LocationConstraint>| {
+            *location == Constraint::Only(LocationConstraint::Nearest)
+        };
+        let uses_nearest = is_nearest(&relay_constraints.location)
+            || is_nearest(&relay_constraints.wireguard_constraints.entry_location);
+
+        let mut matching_relays: Vec<Relay> = vec![];
+        for constraints in Self::resolve_locations(relay_constraints, custom_lists, nearest_cities)
+        {
+            if uses_nearest && !matching_relays.is_empty() {
+                break;
+            }
+            let matcher = RelayMatcher::new(
+                constraints,
+                parsed_relays.locations.openvpn.clone(),
+                parsed_relays.locations.wireguard.clone(),
+            );
+            for relay in matcher.filter_matching_relay_list(relays) {
+                if !matching_relays
+                    .iter()
+                    .any(|matching| matching.hostname == relay.hostname)
+                {
+                    matching_relays.push(relay);
+                }
+            }
+        }
+        matching_relays
+    }
+
+    /// Returns the relay constraints to select relays with, in the order that they should be
+    /// tried. A custom list in the location or entry location is replaced by each of the
+    /// locations in it, in random order, so that relays are selected from a random location in
+    /// the list. No constraints are returned if a custom list is empty or does not exist.
+    ///
+    /// The nearest location is replaced by each of `nearest_cities`, closest first, or by any
+    /// location if the location of the client is unknown. Custom lists in the avoided locations
+    /// are also replaced by the locations in them.
+    fn resolve_locations(
+        constraints: &RelayConstraints,
+        custom_lists: &CustomListsSettings,
+        nearest_cities: Option<&[LocationConstraint]>,
+    ) -> Vec<RelayConstraints> {
+        let resolve = |location: &Constraint<LocationConstraint>| match location {
+            Constraint::Only(LocationConstraint::CustomList(name)) => {
+                let mut locations: Vec<_> = custom_lists
+                    .locations(name)
+                    .iter()
+                    .cloned()
+                    .map(Constraint::Only)
+                    .collect();
+                rng::with_rng(|rng| locations.shuffle(rng));
+                locations
+            }
+            Constraint::Only(LocationConstraint::Nearest) => match nearest_cities {
+                Some(cities) => cities.iter().cloned().map(Constraint::Only).collect(),
+                None => vec![Constraint::Any],
+            },
+            location => vec![location.clone()],
+        };
+
+        let entry_locations = resolve(&constraints.wireguard_constraints.entry_location);
+        let avoided_locations =
+            Self::resolve_avoided_locations(&constraints.avoided_locations, custom_lists);
+        let mut resolved = vec![];
+        for location in resolve(&constraints.location) {
+            for entry_location in &entry_locations {
+                let mut constraints = constraints.clone();
+                constraints.location = location.clone();
+                constraints.wireguard_constraints.entry_location = entry_location.clone();
+                constraints.avoided_locations = avoided_locations.clone();
+                resolved.push(constraints);
+            }
+        }
+        resolved
+    }
+
+    /// Returns `avoided_locations` with every custom list replaced by the locations in it. The
+    /// nearest location cannot be avoided and is left out.
+    fn resolve_avoided_locations(
+        avoided_locations: &[LocationConstraint],
+        custom_lists: &CustomListsSettings,
+    ) -> Vec<LocationConstraint> {
+        let mut resolved = vec![];
+        for location in avoided_locations {
+            match location {
+                LocationConstraint::CustomList(name) => {
+                    resolved.extend(custom_lists.locations(name).iter().cloned())
+                }
+                LocationConstraint::Nearest => (),
+                location => resolved.push(location.clone()),
+            }
+        }
+        resolved
+    }
+
+    /// Returns the resolved locations that bridges should be avoided in, which are the avoided
+    /// locations of the relay constraints.
+    fn avoided_bridge_locations(config: &SelectorConfig) -> Vec<LocationConstraint> {
+        match &config.relay_settings {
+            RelaySettings::Normal(constraints) => Self::resolve_avoided_locations(
+                &constraints.avoided_locations,
+                &config.custom_lists,
+            ),
+            RelaySettings::CustomTunnelEndpoint(_) => Vec::new(),
+        }
+    }
+
+    /// Returns every city that has a relay, ordered by distance from the client, closest first.
+    /// Returns `None` if the location of the client is unknown.
+    fn nearest_cities(&self, parsed_relays: &ParsedRelays) -> Option<Vec<LocationConstraint>> {
+        let client_location = self.client_location.lock().clone()?;
+        let mut cities: Vec<(&Location, f64)> = vec![];
+        for location in parsed_relays
+            .relays()
+            .iter()
+            .filter_map(|relay| relay.location.as_ref())
+        {
+            if !cities.iter().any(|(city, _)| city.has_same_city(location)) {
+                cities.push((location, location.distance_from(&client_location)));
+            }
+        }
+        cities.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Some(
+            cities
+                .into_iter()
+                .map(|(city, _)| {
+                    LocationConstraint::City(city.country_code.clone(), city.city_code.clone())
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns all relays, with relays that are currently in a maintenance window marked as
+    /// inactive.
+    fn available_relays(&self) -> Vec<Relay> {
+        let mut relays = self.parsed_relays.lock().relays().clone();
+        let maintenance_windows = self.maintenance_windows.lock();
+        if !maintenance_windows.is_empty() {
+            let now = (self.clock)();
+            for relay in &mut relays {
+                if let Some(window) = maintenance_windows.get(&relay.hostname) {
+                    if window.contains(now) {
+                        relay.active = false;
+                    }
+                }
+            }
+        }
+        relays
+    }
+
+    /// Returns the relays that tunnels may use, with relays that are currently in a maintenance
+    /// window marked as inactive. Only relays that match the allowed and excluded relays, the
+    /// quantum resistance and DAITA settings, and every registered filter are included.
+    fn tunnel_relays(&self) -> Vec<Relay> {
+        let mut relays = self.available_relays();
+        let allowed_relays = self.allowed_relays.lock();
+        let excluded_relays = self.excluded_relays.lock();
+        let custom_filters = self.filters.lock();
+
+        let allowed = allowed_relays.as_ref().map(AllowedHostnames);
+        let excluded = ExcludedHostnames(&excluded_relays);
+        let mut filters: Vec<&dyn RelayFilter> = vec![&excluded];
+        if let Some(allowed) = &allowed {
+            filters.push(allowed);
+        }
+        if *self.quantum_resistant.lock() {
+            filters.push(&QuantumResistantRelays);
+        }
+        if *self.daita.lock() {
+            filters.push(&DaitaRelays);
+        }
+        filters.extend(custom_filters.iter().map(|filter| &**filter));
+
+        relays.retain(|relay| filter::matches_all(&filters, relay));
+        relays
+    }
+
+    /// Returns [Error::NoRelay] if no relay would match `matcher`, [Error::NoAllowedRelay] if
+    /// none of the matching relays may be used, [Error::AllRelaysExcluded] if all of the matching
+    /// relays that may be used are excluded, [Error::NoQuantumResistantRelay] if none of the
+    /// remaining relays support quantum resistant tunnels when required, [Error::NoDaitaRelay] if
+    /// none of the remaining relays support DAITA when required, [Error::RejectedByFilter] if the remaining relays are rejected by a registered filter, and
+    /// [Error::RelaysInMaintenance] otherwise.
+    fn no_relay_error<T: EndpointMatcher>(&self, matcher: &RelayMatcher<T>) -> Error {
+        let relays = self.parsed_relays.lock();
+        let mut matching_relays = matcher.filter_matching_relay_list(relays.relays());
+        if matching_relays.is_empty() {
+            return Error::NoRelay;
+        }
+        if let Some(allowed_relays) = &*self.allowed_relays.lock() {
+            matching_relays.retain(|relay| AllowedHostnames(allowed_relays).matches(relay));
+            if matching_relays.is_empty() {
+                return Error::NoAllowedRelay;
+            }
+        }
+        let excluded_relays = self.excluded_relays.lock();
+        matching_relays.retain(|relay| ExcludedHostnames(&excluded_relays).matches(relay));
+        if matching_relays.is_empty() {
+            return Error::AllRelaysExcluded;
+        }
+        if *self.quantum_resistant.lock() {
+            matching_relays.retain(|relay| QuantumResistantRelays.matches(relay));
+            if matching_relays.is_empty() {
+                return Error::NoQuantumResistantRelay;
+            }
+        }
+        if *self.daita.lock() {
+            matching_relays.retain(|relay| DaitaRelays.matches(relay));
+            if matching_relays.is_empty() {
+                return Error::NoDaitaRelay;
+            }
+        }
+        let custom_filters = self.filters.lock();
+        if !matching_relays
+            .iter()
+            .any(|relay| custom_filters.iter().all(|filter| filter.matches(relay)))
+        {
+            return Error::RejectedByFilter;
+        }
+        Error::RelaysInMaintenance
+    }
+
     /// Returns all countries and cities. The cities in the object returned does not have any
     /// relays in them.
     pub fn get_locations(&mut self) -> RelayList {
@@ -228,122 +737,586 @@ impl RelaySelector {
         Error,
     > {
         let config = self.config.lock();
-        match &config.relay_settings {
-            RelaySettings::CustomTunnelEndpoint(custom_relay) => {
-                Ok((SelectedRelay::Custom(custom_relay.clone()), None, None))
-            }
-            RelaySettings::Normal(constraints) => {
-                let relay = self.get_tunnel_endpoint(
-                    constraints,
-                    config.bridge_state,
-                    retry_attempt,
-                    config.default_tunnel_type,
-                )?;
-                let bridge = match relay.endpoint {
-                    MullvadEndpoint::OpenVpn(endpoint)
-                        if endpoint.protocol == TransportProtocol::Tcp =>
-                    {
-                        let location = relay
-                            .exit_relay
-                            .location
-                            .as_ref()
-                            .expect("Relay has no location set");
-                        self.get_bridge_for(&config, location, retry_attempt)?
-                    }
-                    _ => None,
-                };
-                let obfuscator = match relay.endpoint {
-                    MullvadEndpoint::Wireguard(ref endpoint) => {
-                        let obfuscator_relay =
-                            relay.entry_relay.as_ref().unwrap_or(&relay.exit_relay);
-                        self.get_obfuscator_inner(
-                            &config,
-                            obfuscator_relay,
-                            endpoint,
-                            retry_attempt,
-                        )?
-                    }
-                    _ => None,
-                };
-                Ok((SelectedRelay::Normal(relay), bridge, obfuscator))
-            }
-        }
+        self.get_relay_inner(
+            &config,
+            &config.relay_settings,
+            retry_attempt,
+            retry_attempt,
+        )
     }
 
-    /// Returns a random relay and relay endpoint matching the given constraints and with
-    /// preferences applied.
-    fn get_tunnel_endpoint(
+    /// Like [Self::get_relay], but with `constraints_override` layered on top of the configured
+    /// relay constraints. The stored configuration is not modified. Custom relays are returned
+    /// as-is.
+    pub fn get_relay_with_override(
         &self,
-        relay_constraints: &RelayConstraints,
-        bridge_state: BridgeState,
         retry_attempt: u32,
-        default_tunnel_type: TunnelType,
-    ) -> Result<NormalSelectedRelay, Error> {
-        match relay_constraints.tunnel_protocol {
-            Constraint::Only(TunnelType::OpenVpn) => self.get_openvpn_endpoint(
-                &relay_constraints.location,
-                &relay_constraints.providers,
-                &relay_constraints.ownership,
-                relay_constraints.openvpn_constraints,
-                bridge_state,
-                retry_attempt,
-            ),
+        constraints_override: RelayConstraintsUpdate,
+    ) -> Result<
+        (
+            SelectedRelay,
+            Option<SelectedBridge>,
+            Option<SelectedObfuscator>,
+        ),
+        Error,
+    > {
+        self.get_relay_with_escalation(retry_attempt, retry_attempt, Some(constraints_override))
+    }
 
-            Constraint::Only(TunnelType::Wireguard) => self.get_wireguard_endpoint(
-                &relay_constraints.location,
-                &relay_constraints.providers,
-                &relay_constraints.ownership,
-                &relay_constraints.wireguard_constraints,
-                retry_attempt,
-            ),
-            Constraint::Any => self.get_any_tunnel_endpoint(
-                relay_constraints,
-                bridge_state,
-                retry_attempt,
-                default_tunnel_type,
-            ),
-        }
+    /// Like [Self::get_relay_with_override], but decides whether to escalate to bridges and
+    /// obfuscation based on `escalation_attempt` rather than `retry_attempt`. `retry_attempt` is
+    /// still used to rotate between ports and protocols.
+    pub fn get_relay_with_escalation(
+        &self,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+        constraints_override: Option<RelayConstraintsUpdate>,
+    ) -> Result<
+        (
+            SelectedRelay,
+            Option<SelectedBridge>,
+            Option<SelectedObfuscator>,
+        ),
+        Error,
+    > {
+        self.get_relay_with_obfuscation_override(
+            retry_attempt,
+            escalation_attempt,
+            constraints_override,
+            None,
+        )
     }
 
-    /// Returns the average location of relays that match the given constraints.
-    /// This returns none if the location is `any` or if no relays match the constraints.
-    pub fn get_relay_midpoint(&self, relay_constraints: &RelayConstraints) -> Option<Coordinates> {
-        if relay_constraints.location.is_any() {
-            return None;
+    /// Like [Self::get_relay_with_escalation], but uses `udp2tcp_override` instead of the
+    /// configured udp2tcp obfuscation settings, if given. The stored configuration is not
+    /// modified.
+    pub fn get_relay_with_obfuscation_override(
+        &self,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+        constraints_override: Option<RelayConstraintsUpdate>,
+        udp2tcp_override: Option<Udp2TcpObfuscationSettings>,
+    ) -> Result<
+        (
+            SelectedRelay,
+            Option<SelectedBridge>,
+            Option<SelectedObfuscator>,
+        ),
+        Error,
+    > {
+        let mut config = self.config.lock().clone();
+        if let Some(udp2tcp) = udp2tcp_override {
+            config.obfuscation_settings.udp2tcp = udp2tcp;
         }
-
-        let (openvpn_data, wireguard_data) = {
-            let relays = self.parsed_relays.lock();
-            (
-                relays.locations.openvpn.clone(),
-                relays.locations.wireguard.clone(),
-            )
+        let relay_settings = match (&config.relay_settings, constraints_override) {
+            (RelaySettings::Normal(constraints), Some(constraints_override)) => {
+                RelaySettings::Normal(constraints.merge(constraints_override))
+            }
+            (relay_settings, _) => relay_settings.clone(),
         };
+        self.get_relay_inner(&config, &relay_settings, retry_attempt, escalation_attempt)
+    }
 
-        let matcher = RelayMatcher::new(relay_constraints.clone(), openvpn_data, wireguard_data);
-
-        let parsed_relays = self.parsed_relays.lock();
-        let mut matching_locations: Vec<Location> = matcher
-            .filter_matching_relay_list(parsed_relays.relays())
-            .into_iter()
-            .filter_map(|relay| relay.location)
-            .collect();
-        matching_locations.dedup_by(|a, b| a.has_same_city(b));
+    /// Returns the relay constraints that relays are selected with when `constraints_override` is
+    /// layered on top of the configured constraints, or `None` if a custom relay is used.
+    pub fn effective_constraints(
+        &self,
+        constraints_override: Option<RelayConstraintsUpdate>,
+    ) -> Option<RelayConstraints> {
+        match (&self.config.lock().relay_settings, constraints_override) {
+            (RelaySettings::Normal(constraints), Some(constraints_override)) => {
+                Some(constraints.merge(constraints_override))
+            }
+            (RelaySettings::Normal(constraints), None) => Some(constraints.clone()),
+            (RelaySettings::CustomTunnelEndpoint(_), _) => None,
+        }
+    }
 
-        if matching_locations.is_empty() {
+    /// Returns the first escalation attempt on which WireGuard traffic is obfuscated, or `None` if
+    /// obfuscation is not set to `auto` or automatic obfuscation never uses an obfuscator.
+    pub fn first_auto_obfuscation_attempt(&self) -> Option<u32> {
+        let config = self.config.lock();
+        let obfuscation_settings = &config.obfuscation_settings;
+        if obfuscation_settings.selected_obfuscation != SelectedObfuscation::Auto {
             return None;
         }
-        Some(Coordinates::midpoint(&matching_locations))
+        if !config.escalation_ladder.steps.is_empty() {
+            return config
+                .escalation_ladder
+                .steps
+                .iter()
+                .find(|step| step.tunnel_type == TunnelType::Wireguard && step.udp2tcp)
+                .map(|step| step.from_attempt);
+        }
+        // See `get_auto_obfuscator_retry_attempt`
+        obfuscation_settings
+            .priority
+            .iter()
+            .position(|method| matches!(method, ObfuscationMethod::Udp2Tcp))
+            .map(|index| index as u32 * 2)
     }
 
-    /// Returns an OpenVpn endpoint, should only ever be used when the user has specified the tunnel
-    /// protocol as only OpenVPN.
-    fn get_openvpn_endpoint(
+    fn get_relay_inner(
         &self,
-        location: &Constraint<LocationConstraint>,
-        providers: &Constraint<Providers>,
-        ownership: &Constraint<Ownership>,
-        openvpn_constraints: OpenVpnConstraints,
+        config: &SelectorConfig,
+        relay_settings: &RelaySettings,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+    ) -> Result<
+        (
+            SelectedRelay,
+            Option<SelectedBridge>,
+            Option<SelectedObfuscator>,
+        ),
+        Error,
+    > {
+        match relay_settings {
+            RelaySettings::CustomTunnelEndpoint(custom_relay) => {
+                let bridge = match &custom_relay.config {
+                    ConnectionConfig::OpenVpn(connection)
+                        if connection.endpoint.protocol == TransportProtocol::Tcp =>
+                    {
+                        // The location of a custom relay is unknown, so prefer bridges near the
+                        // client instead
+                        let client_location = self.client_location.lock().clone();
+                        self.get_bridge_for(config, client_location, escalation_attempt)?
+                    }
+                    _ => None,
+                };
+                Ok((SelectedRelay::Custom(custom_relay.clone()), bridge, None))
+            }
+            RelaySettings::Normal(constraints) => {
+                let nearest_cities = self.nearest_cities(&self.parsed_relays.lock());
+                let mut result = Err(Error::NoRelay);
+                for constraints in Self::resolve_locations(
+                    constraints,
+                    &config.custom_lists,
+                    nearest_cities.as_deref(),
+                ) {
+                    result = self.get_normal_relay(
+                        config,
+                        constraints,
+                        retry_attempt,
+                        escalation_attempt,
+                    );
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result
+            }
+        }
+    }
+
+    /// Selects a relay for `constraints`, which must have any custom lists resolved, and a bridge
+    /// or obfuscator if one should be used. The escalation ladder is followed instead of the
+    /// built-in retry strategy if one is configured.
+    fn get_normal_relay(
+        &self,
+        config: &SelectorConfig,
+        mut constraints: RelayConstraints,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+    ) -> Result<
+        (
+            SelectedRelay,
+            Option<SelectedBridge>,
+            Option<SelectedObfuscator>,
+        ),
+        Error,
+    > {
+        let step = config
+            .escalation_ladder
+            .step(escalation_attempt)
+            .filter(|step| {
+                constraints.tunnel_protocol.is_any()
+                    || constraints.tunnel_protocol == Constraint::Only(step.tunnel_type)
+            });
+        if let Some(step) = step {
+            log::debug!(
+                "Using escalation step for retry attempt {}: {}",
+                escalation_attempt,
+                step
+            );
+            let config = Self::apply_escalation_step(config, &mut constraints, step);
+            // The built-in preferences do not narrow anything on the first attempt, so this leaves
+            // the step in control of ports, protocols, bridges, and obfuscation.
+            return self.get_escalated_relay(&config, constraints, 0, 0);
+        }
+        self.get_escalated_relay(config, constraints, retry_attempt, escalation_attempt)
+    }
+
+    /// Applies `step` to the constraints and settings that are not explicitly set, and returns
+    /// the resulting selector config.
+    fn apply_escalation_step(
+        config: &SelectorConfig,
+        constraints: &mut RelayConstraints,
+        step: &EscalationStep,
+    ) -> SelectorConfig {
+        let mut config = config.clone();
+        constraints.tunnel_protocol = Constraint::Only(step.tunnel_type);
+        match step.tunnel_type {
+            TunnelType::Wireguard => {
+                let obfuscation = &mut config.obfuscation_settings;
+                if step.udp2tcp {
+                    if obfuscation.selected_obfuscation == SelectedObfuscation::Auto {
+                        obfuscation.selected_obfuscation = SelectedObfuscation::Udp2Tcp;
+                        if obfuscation.udp2tcp.port.is_any() {
+                            obfuscation.udp2tcp.port = step.port;
+                        }
+                    }
+                } else {
+                    if obfuscation.selected_obfuscation == SelectedObfuscation::Auto {
+                        obfuscation.selected_obfuscation = SelectedObfuscation::Off;
+                    }
+                    if constraints.wireguard_constraints.port.is_any() {
+                        constraints.wireguard_constraints.port = step.port.map(PortRanges::from);
+                    }
+                }
+            }
+            TunnelType::OpenVpn => {
+                if constraints.openvpn_constraints.port.is_any() {
+                    let protocol = if config.bridge_state == BridgeState::On
+                        || constraints.openvpn_constraints.use_multihop
+                    {
+                        TransportProtocol::Tcp
+                    } else {
+                        step.transport_protocol
+                    };
+                    constraints.openvpn_constraints.port = Constraint::Only(TransportPort {
+                        protocol,
+                        port: step.port,
+                    });
+                }
+                if config.bridge_state == BridgeState::Auto {
+                    config.bridge_state = if step.bridge {
+                        BridgeState::On
+                    } else {
+                        BridgeState::Off
+                    };
+                }
+            }
+        }
+        config
+    }
+
+    /// Selects a relay, bridge, and obfuscator for `constraints`, using the built-in retry strategy
+    /// to decide which ports, protocols, bridges, and obfuscation to prefer.
+    fn get_escalated_relay(
+        &self,
+        config: &SelectorConfig,
+        mut constraints: RelayConstraints,
+        retry_attempt: u32,
+        escalation_attempt: u32,
+    ) -> Result<
+        (
+            SelectedRelay,
+            Option<SelectedBridge>,
+            Option<SelectedObfuscator>,
+        ),
+        Error,
+    > {
+        self.apply_obfuscator_location(config, &mut constraints, escalation_attempt);
+        let mut relay = self.get_tunnel_endpoint(
+            &constraints,
+            config.bridge_state,
+            retry_attempt,
+            config.default_tunnel_type,
+        )?;
+        let bridge = match relay.endpoint {
+            MullvadEndpoint::OpenVpn(endpoint) if constraints.openvpn_constraints.use_multihop => {
+                if endpoint.protocol != TransportProtocol::Tcp {
+                    log::warn!("OpenVPN multihop can only be used over TCP");
+                    return Err(Error::NoRelay);
+                }
+                let (entry_relay, settings) =
+                    self.get_openvpn_entry(&constraints, &relay.exit_relay)?;
+                relay.entry_relay = Some(entry_relay.clone());
+                Some(SelectedBridge::Normal(NormalSelectedBridge {
+                    settings,
+                    relay: entry_relay,
+                }))
+            }
+            MullvadEndpoint::OpenVpn(endpoint) if endpoint.protocol == TransportProtocol::Tcp => {
+                let location = relay
+                    .exit_relay
+                    .location
+                    .as_ref()
+                    .expect("Relay has no location set");
+                self.get_bridge_for(
+                    config,
+                    Some(Coordinates::from(location)),
+                    escalation_attempt,
+                )?
+            }
+            _ => None,
+        };
+        let obfuscator = match relay.endpoint {
+            MullvadEndpoint::Wireguard(ref endpoint) => {
+                let obfuscator_relay = relay.entry_relay.as_ref().unwrap_or(&relay.exit_relay);
+                self.get_obfuscator_inner(config, obfuscator_relay, endpoint, escalation_attempt)?
+            }
+            _ => None,
+        };
+        if let Some(ref obfuscator) = obfuscator {
+            if !config
+                .obfuscation_settings
+                .location
+                .matches_with_opts(&obfuscator.relay, true)
+            {
+                log::warn!(
+                    "Obfuscator relay {} does not match the obfuscator location",
+                    obfuscator.relay.hostname
+                );
+                return Err(Error::NoObfuscator);
+            }
+        }
+        Ok((SelectedRelay::Normal(relay), bridge, obfuscator))
+    }
+
+    /// Narrows the location of the relay that obfuscated traffic is sent to, i.e. the entry relay
+    /// when using multihop, to the obfuscator location. The location is left as-is if it cannot
+    /// contain the obfuscator location, in which case no obfuscator will be found.
+    fn apply_obfuscator_location(
+        &self,
+        config: &SelectorConfig,
+        constraints: &mut RelayConstraints,
+        retry_attempt: u32,
+    ) {
+        let obfuscator_location = match &config.obfuscation_settings.location {
+            Constraint::Only(location)
+                if self.will_use_obfuscation(&config.obfuscation_settings, retry_attempt) =>
+            {
+                location
+            }
+            _ => return,
+        };
+        let obfuscated_relay_location = if constraints.wireguard_constraints.is_relay_multihop() {
+            &mut constraints.wireguard_constraints.entry_location
+        } else if constraints.tunnel_protocol == Constraint::Only(TunnelType::Wireguard) {
+            &mut constraints.location
+        } else {
+            return;
+        };
+        let narrow = match obfuscated_relay_location {
+            Constraint::Any => true,
+            Constraint::Only(location) => obfuscator_location.is_subset(location),
+        };
+        if narrow {
+            *obfuscated_relay_location = Constraint::Only(obfuscator_location.clone());
+        }
+    }
+
+    /// Returns a random relay and relay endpoint matching the given constraints and with
+    /// preferences applied. If IPv6 is preferred, an IPv6 endpoint is selected if possible.
+    fn get_tunnel_endpoint(
+        &self,
+        relay_constraints: &RelayConstraints,
+        bridge_state: BridgeState,
+        retry_attempt: u32,
+        default_tunnel_type: TunnelType,
+    ) -> Result<NormalSelectedRelay, Error> {
+        if *self.prefer_ipv6.lock() && *self.ipv6_available.lock() {
+            if let Some(ipv6_constraints) = Self::ipv6_constraints(relay_constraints, bridge_state)
+            {
+                match self.select_tunnel_endpoint(
+                    &ipv6_constraints,
+                    bridge_state,
+                    retry_attempt,
+                    default_tunnel_type,
+                ) {
+                    Ok(relay) => return Ok(relay),
+                    Err(_) => log::debug!("No IPv6 relay endpoint matches, falling back to IPv4"),
+                }
+            }
+        }
+        self.select_tunnel_endpoint(
+            relay_constraints,
+            bridge_state,
+            retry_attempt,
+            default_tunnel_type,
+        )
+    }
+
+    /// Returns `relay_constraints` with IPv6 required wherever the IP version is not constrained,
+    /// or `None` if that does not change anything. OpenVPN endpoints reached through a bridge or
+    /// an entry relay are left alone.
+    fn ipv6_constraints(
+        relay_constraints: &RelayConstraints,
+        bridge_state: BridgeState,
+    ) -> Option<RelayConstraints> {
+        let mut ipv6_constraints = relay_constraints.clone();
+        let mut changed = false;
+        if ipv6_constraints.wireguard_constraints.ip_version.is_any() {
+            ipv6_constraints.wireguard_constraints.ip_version = Constraint::Only(IpVersion::V6);
+            changed = true;
+        }
+        let openvpn_constraints = &mut ipv6_constraints.openvpn_constraints;
+        if openvpn_constraints.ip_version.is_any()
+            && bridge_state != BridgeState::On
+            && !openvpn_constraints.use_multihop
+        {
+            openvpn_constraints.ip_version = Constraint::Only(IpVersion::V6);
+            changed = true;
+        }
+        if changed {
+            Some(ipv6_constraints)
+        } else {
+            None
+        }
+    }
+
+    fn select_tunnel_endpoint(
+        &self,
+        relay_constraints: &RelayConstraints,
+        bridge_state: BridgeState,
+        retry_attempt: u32,
+        default_tunnel_type: TunnelType,
+    ) -> Result<NormalSelectedRelay, Error> {
+        match relay_constraints.tunnel_protocol {
+            Constraint::Only(TunnelType::OpenVpn) => self.get_openvpn_endpoint(
+                &relay_constraints.location,
+                &relay_constraints.providers,
+                &relay_constraints.ownership,
+                relay_constraints.endpoint_subnet,
+                &relay_constraints.excluded_relays,
+                &relay_constraints.avoided_locations,
+                relay_constraints.openvpn_constraints.clone(),
+                bridge_state,
+                retry_attempt,
+            ),
+
+            Constraint::Only(TunnelType::Wireguard) => self.get_wireguard_endpoint(
+                &relay_constraints.location,
+                &relay_constraints.providers,
+                &relay_constraints.ownership,
+                relay_constraints.endpoint_subnet,
+                &relay_constraints.excluded_relays,
+                &relay_constraints.avoided_locations,
+                &relay_constraints.wireguard_constraints,
+                retry_attempt,
+            ),
+            Constraint::Any => self.get_any_tunnel_endpoint(
+                relay_constraints,
+                bridge_state,
+                retry_attempt,
+                default_tunnel_type,
+            ),
+        }
+    }
+
+    /// Returns the average location of relays that match the given constraints.
+    /// This returns none if the location is `any` or if no relays match the constraints.
+    pub fn get_relay_midpoint(
+        &self,
+        relay_constraints: &RelayConstraints,
+        custom_lists: &CustomListsSettings,
+    ) -> Option<Coordinates> {
+        if relay_constraints.location.is_any() {
+            return None;
+        }
+
+        let parsed_relays = self.parsed_relays.lock();
+        let nearest_cities = self.nearest_cities(&parsed_relays);
+        let mut matching_locations: Vec<Location> = Self::matching_relays(
+            relay_constraints,
+            custom_lists,
+            nearest_cities.as_deref(),
+            &parsed_relays,
+            parsed_relays.relays(),
+        )
+        .into_iter()
+        .filter_map(|relay| relay.location)
+        .collect();
+        matching_locations.dedup_by(|a, b| a.has_same_city(b));
+
+        if matching_locations.is_empty() {
+            return None;
+        }
+        Some(Coordinates::midpoint(&matching_locations))
+    }
+
+    /// Checks that the current constraints can be satisfied, without selecting a relay. Returns
+    /// [Error::NoRelay] if no relay matches the relay constraints, or [Error::NoBridge] if a
+    /// bridge is required but no bridge matches the bridge constraints.
+    pub fn check_constraints(&self) -> Result<(), Error> {
+        let config = self.config.lock();
+        let relay_constraints = match &config.relay_settings {
+            RelaySettings::CustomTunnelEndpoint(_) => return Ok(()),
+            RelaySettings::Normal(constraints) => constraints,
+        };
+        let parsed_relays = self.parsed_relays.lock();
+        let nearest_cities = self.nearest_cities(&parsed_relays);
+
+        if Self::matching_relays(
+            relay_constraints,
+            &config.custom_lists,
+            nearest_cities.as_deref(),
+            &parsed_relays,
+            parsed_relays.relays(),
+        )
+        .is_empty()
+        {
+            return Err(Error::NoRelay);
+        }
+
+        let bridge_required = config.bridge_state == BridgeState::On
+            && relay_constraints.tunnel_protocol != Constraint::Only(TunnelType::Wireguard);
+        if let (true, BridgeSettings::Normal(settings)) = (bridge_required, &config.bridge_settings)
+        {
+            let bridge_matcher = RelayMatcher {
+                location: settings.location.clone(),
+                providers: settings.providers.clone(),
+                ownership: settings.ownership,
+                endpoint_subnet: Constraint::Any,
+                excluded_relays: Vec::new(),
+                avoided_locations: Self::avoided_bridge_locations(&config),
+                endpoint_matcher: BridgeMatcher(settings.ip_version),
+            };
+            if bridge_matcher
+                .filter_matching_relay_list(parsed_relays.relays())
+                .is_empty()
+            {
+                return Err(Error::NoBridge { forced: true });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether any relay that tunnels may use matches the relay constraints with
+    /// `constraints_override` applied, without selecting a relay. Always returns `true` if a
+    /// custom relay is used.
+    pub fn has_matching_relay(&self, constraints_override: Option<RelayConstraintsUpdate>) -> bool {
+        let relay_constraints = match self.effective_constraints(constraints_override) {
+            Some(relay_constraints) => relay_constraints,
+            None => return true,
+        };
+        let custom_lists = self.config.lock().custom_lists.clone();
+        let relays = self.tunnel_relays();
+        let parsed_relays = self.parsed_relays.lock();
+        let nearest_cities = self.nearest_cities(&parsed_relays);
+        !Self::matching_relays(
+            &relay_constraints,
+            &custom_lists,
+            nearest_cities.as_deref(),
+            &parsed_relays,
+            &relays,
+        )
+        .is_empty()
+    }
+
+    /// Returns an OpenVpn endpoint, should only ever be used when the user has specified the tunnel
+    /// protocol as only OpenVPN.
+    fn get_openvpn_endpoint(
+        &self,
+        location: &Constraint<LocationConstraint>,
+        providers: &Constraint<Providers>,
+        ownership: &Constraint<Ownership>,
+        endpoint_subnet: Constraint<IpNetwork>,
+        excluded_relays: &[Hostname],
+        avoided_locations: &[LocationConstraint],
+        openvpn_constraints: OpenVpnConstraints,
         bridge_state: BridgeState,
         retry_attempt: u32,
     ) -> Result<NormalSelectedRelay, Error> {
@@ -351,6 +1324,9 @@ impl RelaySelector {
             location: location.clone(),
             providers: providers.clone(),
             ownership: *ownership,
+            endpoint_subnet,
+            excluded_relays: excluded_relays.to_vec(),
+            avoided_locations: avoided_locations.to_vec(),
             endpoint_matcher: OpenVpnMatcher::new(
                 openvpn_constraints,
                 self.parsed_relays.lock().locations.openvpn.clone(),
@@ -358,7 +1334,8 @@ impl RelaySelector {
         };
 
         if relay_matcher.endpoint_matcher.constraints.port.is_any()
-            && bridge_state == BridgeState::On
+            && (bridge_state == BridgeState::On
+                || relay_matcher.endpoint_matcher.constraints.use_multihop)
         {
             relay_matcher.endpoint_matcher.constraints.port = Constraint::Only(TransportPort {
                 protocol: TransportProtocol::Tcp,
@@ -404,14 +1381,18 @@ impl RelaySelector {
         mut entry_matcher: RelayMatcher<WireguardMatcher>,
         exit_location: Constraint<LocationConstraint>,
     ) -> Result<NormalSelectedRelay, Error> {
+        // Only the entry relay is connected to directly, so the subnet does not apply to the exit
         let mut exit_matcher = RelayMatcher {
             location: exit_location,
+            endpoint_subnet: Constraint::Any,
             endpoint_matcher: self.wireguard_exit_matcher(),
             ..entry_matcher.clone()
         };
 
         let (exit_relay, entry_relay, exit_endpoint, mut entry_endpoint) =
-            if entry_matcher.location.is_subset(&exit_matcher.location) {
+            if !Self::uses_shortest_path_entry(&entry_matcher)
+                && entry_matcher.location.is_subset(&exit_matcher.location)
+            {
                 let (entry_relay, entry_endpoint) = self.get_entry_endpoint(&entry_matcher)?;
                 exit_matcher.set_peer(entry_relay.clone());
                 let exit_result = self.get_tunnel_endpoint_internal(&exit_matcher)?;
@@ -458,6 +1439,9 @@ impl RelaySelector {
         location: &Constraint<LocationConstraint>,
         providers: &Constraint<Providers>,
         ownership: &Constraint<Ownership>,
+        endpoint_subnet: Constraint<IpNetwork>,
+        excluded_relays: &[Hostname],
+        avoided_locations: &[LocationConstraint],
         wireguard_constraints: &WireguardConstraints,
         retry_attempt: u32,
     ) -> Result<NormalSelectedRelay, Error> {
@@ -465,29 +1449,33 @@ impl RelaySelector {
             location: location.clone(),
             providers: providers.clone(),
             ownership: *ownership,
+            endpoint_subnet,
+            excluded_relays: excluded_relays.to_vec(),
+            avoided_locations: avoided_locations.to_vec(),
             endpoint_matcher: WireguardMatcher::new(
                 wireguard_constraints.clone(),
                 self.parsed_relays.lock().locations.wireguard.clone(),
             ),
         };
 
+        let preferred_port = self
+            .preferred_wireguard_port(retry_attempt)
+            .map(PortRanges::from);
         let mut preferred_matcher: RelayMatcher<WireguardMatcher> = entry_relay_matcher.clone();
         preferred_matcher.endpoint_matcher.port = preferred_matcher
             .endpoint_matcher
             .port
-            .or(Self::preferred_wireguard_port(retry_attempt));
+            .or(preferred_port.clone());
 
-        if !wireguard_constraints.use_multihop {
+        if !wireguard_constraints.is_relay_multihop() {
             return self
                 .get_tunnel_endpoint_internal(&preferred_matcher)
                 .or_else(|_| self.get_tunnel_endpoint_internal(&entry_relay_matcher));
         }
 
         entry_relay_matcher.location = wireguard_constraints.entry_location.clone();
-        entry_relay_matcher.endpoint_matcher.port = entry_relay_matcher
-            .endpoint_matcher
-            .port
-            .or(Self::preferred_wireguard_port(retry_attempt));
+        entry_relay_matcher.endpoint_matcher.port =
+            entry_relay_matcher.endpoint_matcher.port.or(preferred_port);
         self.get_wireguard_multi_hop_endpoint(entry_relay_matcher, location.clone())
     }
 
@@ -517,14 +1505,21 @@ impl RelaySelector {
         }
         .into_wireguard_matcher();
 
-        // Pick the entry relay first if its location constraint is a subset of the exit location.
-        if relay_constraints.wireguard_constraints.use_multihop {
-            matcher.endpoint_matcher.wireguard = self.wireguard_exit_matcher();
-            if relay_constraints
+        // Pick the entry relay first if its location constraint is a subset of the exit location,
+        // unless the entry relay depends on the exit relay.
+        let entry_first = !Self::uses_shortest_path_entry(&entry_matcher)
+            && relay_constraints
                 .wireguard_constraints
                 .entry_location
-                .is_subset(&matcher.location)
-            {
+                .is_subset(&matcher.location);
+        if relay_constraints.wireguard_constraints.is_relay_multihop() {
+            matcher.endpoint_matcher.wireguard = self.wireguard_exit_matcher();
+            // Only the entry relay is connected to directly, so the subnet does not apply to the
+            // exit
+            if matcher.endpoint_matcher.tunnel_type == Constraint::Only(TunnelType::Wireguard) {
+                matcher.endpoint_subnet = Constraint::Any;
+            }
+            if entry_first {
                 if let Ok((entry_relay, entry_endpoint)) = self.get_entry_endpoint(&entry_matcher) {
                     matcher.endpoint_matcher.wireguard.peer = Some(entry_relay.clone());
                     selected_entry_relay = Some(entry_relay);
@@ -535,16 +1530,11 @@ impl RelaySelector {
 
         let mut selected_relay = self.get_tunnel_endpoint_internal(&matcher)?;
 
-        // Pick the entry relay last if its location constraint is NOT a subset of the exit
-        // location.
+        // Pick the entry relay last if it was not picked first.
         if matches!(selected_relay.endpoint, MullvadEndpoint::Wireguard(..))
-            && relay_constraints.wireguard_constraints.use_multihop
+            && relay_constraints.wireguard_constraints.is_relay_multihop()
         {
-            if !relay_constraints
-                .wireguard_constraints
-                .entry_location
-                .is_subset(&matcher.location)
-            {
+            if !entry_first {
                 entry_matcher.endpoint_matcher.peer = Some(selected_relay.exit_relay.clone());
                 if let Ok((entry_relay, entry_endpoint)) = self.get_entry_endpoint(&entry_matcher) {
                     selected_entry_relay = Some(entry_relay);
@@ -636,12 +1626,15 @@ impl RelaySelector {
         match original_constraints.tunnel_protocol {
             // If no tunnel protocol is selected, use preferred constraints
             Constraint::Any => {
-                if bridge_state == BridgeState::On {
+                if bridge_state == BridgeState::On
+                    || original_constraints.openvpn_constraints.use_multihop
+                {
                     relay_constraints.openvpn_constraints = OpenVpnConstraints {
                         port: Constraint::Only(TransportPort {
                             protocol: TransportProtocol::Tcp,
                             port: Constraint::Any,
                         }),
+                        ..original_constraints.openvpn_constraints.clone()
                     };
                 } else if original_constraints.openvpn_constraints.port.is_any() {
                     relay_constraints.openvpn_constraints = OpenVpnConstraints {
@@ -649,22 +1642,26 @@ impl RelaySelector {
                             protocol: preferred_protocol,
                             port: preferred_port,
                         }),
+                        ..original_constraints.openvpn_constraints.clone()
                     };
                 } else {
                     relay_constraints.openvpn_constraints =
-                        original_constraints.openvpn_constraints;
+                        original_constraints.openvpn_constraints.clone();
                 }
 
                 if relay_constraints.wireguard_constraints.port.is_any() {
-                    relay_constraints.wireguard_constraints.port = preferred_port;
+                    relay_constraints.wireguard_constraints.port =
+                        preferred_port.map(PortRanges::from);
                 }
 
                 relay_constraints.tunnel_protocol = Constraint::Only(preferred_tunnel);
             }
             Constraint::Only(TunnelType::OpenVpn) => {
                 let openvpn_constraints = &mut relay_constraints.openvpn_constraints;
-                *openvpn_constraints = original_constraints.openvpn_constraints;
-                if bridge_state == BridgeState::On && openvpn_constraints.port.is_any() {
+                *openvpn_constraints = original_constraints.openvpn_constraints.clone();
+                if (bridge_state == BridgeState::On || openvpn_constraints.use_multihop)
+                    && openvpn_constraints.port.is_any()
+                {
                     openvpn_constraints.port = Constraint::Only(TransportPort {
                         protocol: TransportProtocol::Tcp,
                         port: Constraint::Any,
@@ -682,8 +1679,9 @@ impl RelaySelector {
                 relay_constraints.wireguard_constraints =
                     original_constraints.wireguard_constraints.clone();
                 if relay_constraints.wireguard_constraints.port.is_any() {
-                    relay_constraints.wireguard_constraints.port =
-                        Self::preferred_wireguard_port(retry_attempt);
+                    relay_constraints.wireguard_constraints.port = self
+                        .preferred_wireguard_port(retry_attempt)
+                        .map(PortRanges::from);
                 }
             }
         };
@@ -695,15 +1693,31 @@ impl RelaySelector {
         &self,
         matcher: &RelayMatcher<WireguardMatcher>,
     ) -> Result<(Relay, MullvadWireguardEndpoint), Error> {
+        let relays = self.tunnel_relays();
         let matching_relays: Vec<Relay> = matcher
-            .filter_matching_relay_list(self.parsed_relays.lock().relays())
+            .filter_matching_relay_list(&relays)
+            .into_iter()
+            .collect();
+        if matching_relays.is_empty() {
+            Self::log_filter_report(matcher, &relays);
+            return Err(self.no_relay_error(matcher));
+        }
+        let matching_relays: Vec<Relay> = matching_relays
             .into_iter()
+            .filter(Self::is_multihop_entry)
             .collect();
+        if matching_relays.is_empty() {
+            log::warn!("No relay matching the entry constraints can be used as a multihop entry");
+            return Err(Error::NoMultihopEntry);
+        }
 
-        let relay = self
-            .pick_random_relay(&matching_relays)
-            .cloned()
-            .ok_or(Error::NoRelay)?;
+        let relay = match &matcher.endpoint_matcher.peer {
+            Some(exit_relay) if Self::uses_shortest_path_entry(matcher) => {
+                self.pick_shortest_path_entry(&matching_relays, exit_relay)
+            }
+            _ => self.pick_random_relay(&matching_relays).cloned(),
+        }
+        .ok_or(Error::NoRelay)?;
         let endpoint = matcher
             .mullvad_endpoint(&relay)
             .ok_or(Error::NoRelay)?
@@ -713,6 +1727,45 @@ impl RelaySelector {
         Ok((relay, endpoint))
     }
 
+    /// Returns whether the entry relay should be picked by [Self::pick_shortest_path_entry]. The
+    /// exit relay must then be selected before the entry relay.
+    fn uses_shortest_path_entry(entry_matcher: &RelayMatcher<WireguardMatcher>) -> bool {
+        entry_matcher.endpoint_matcher.entry_policy == EntryRelayPolicy::ShortestPath
+            && entry_matcher.location.is_any()
+    }
+
+    /// Picks the relay in `relays` that minimizes the combined distance from the client to the
+    /// relay and from the relay to `exit_relay`. Relays in the same city are equally far away, so
+    /// one of them is picked using [Self::pick_random_relay]. The distance from the client is
+    /// ignored if the location of the client is unknown.
+    fn pick_shortest_path_entry(&self, relays: &[Relay], exit_relay: &Relay) -> Option<Relay> {
+        let client_location = self.client_location.lock().clone();
+        let exit_location = exit_relay.location.as_ref().map(Coordinates::from);
+        let path_length = |relay: &Relay| match &relay.location {
+            Some(location) => [client_location.as_ref(), exit_location.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(|other| location.distance_from(other))
+                .sum::<f64>(),
+            None => f64::INFINITY,
+        };
+        let shortest = relays.iter().map(path_length).fold(f64::INFINITY, f64::min);
+        let closest: Vec<Relay> = relays
+            .iter()
+            .filter(|relay| path_length(relay) <= shortest)
+            .cloned()
+            .collect();
+        self.pick_random_relay(&closest).cloned()
+    }
+
+    /// Returns whether `relay` can be used as the entry relay of a multihop tunnel.
+    pub fn is_multihop_entry(relay: &Relay) -> bool {
+        matches!(
+            &relay.endpoint_data,
+            RelayEndpointData::Wireguard(data) if data.multihop_entry
+        )
+    }
+
     fn set_entry_peers(
         exit_peer: &wireguard::PeerConfig,
         entry_endpoint: &mut MullvadWireguardEndpoint,
@@ -721,36 +1774,93 @@ impl RelaySelector {
         entry_endpoint.exit_peer = Some(exit_peer.clone());
     }
 
-    fn get_bridge_for(
+    /// Selects an OpenVPN relay to use as the entry of an OpenVPN multihop tunnel to
+    /// `exit_relay`, along with the proxy settings that the connection to the exit relay is made
+    /// through.
+    fn get_openvpn_entry(
         &self,
-        config: &MutexGuard<'_, SelectorConfig>,
-        location: &mullvad_types::location::Location,
-        retry_attempt: u32,
-    ) -> Result<Option<SelectedBridge>, Error> {
-        match &config.bridge_settings {
-            BridgeSettings::Normal(settings) => {
-                let bridge_constraints = InternalBridgeConstraints {
-                    location: settings.location.clone(),
+        constraints: &RelayConstraints,
+        exit_relay: &Relay,
+    ) -> Result<(Relay, ProxySettings), Error> {
+        let mut excluded_relays = constraints.excluded_relays.clone();
+        excluded_relays.push(exit_relay.hostname.clone());
+        let matcher = RelayMatcher {
+            location: constraints.openvpn_constraints.entry_location.clone(),
+            providers: constraints.providers.clone(),
+            ownership: constraints.ownership,
+            endpoint_subnet: constraints.endpoint_subnet,
+            excluded_relays,
+            avoided_locations: constraints.avoided_locations.clone(),
+            endpoint_matcher: OpenVpnMatcher::new(
+                OpenVpnConstraints {
+                    ip_version: constraints.openvpn_constraints.ip_version,
+                    ..OpenVpnConstraints::default()
+                },
+                self.parsed_relays.lock().locations.openvpn.clone(),
+            ),
+        };
+        let relays = self.tunnel_relays();
+        let matching_relays = matcher.filter_matching_relay_list(&relays);
+        if matching_relays.is_empty() {
+            Self::log_filter_report(&matcher, &relays);
+            return Err(Error::NoMultihopEntry);
+        }
+        let relay = self
+            .pick_random_relay(&matching_relays)
+            .cloned()
+            .ok_or(Error::NoMultihopEntry)?;
+        let settings = Self::shadowsocks_proxy(
+            &self.parsed_relays.lock().locations.bridge,
+            &relay,
+            constraints.openvpn_constraints.ip_version,
+        )
+        .ok_or(Error::NoMultihopEntry)?;
+        log::info!(
+            "Selected entry relay {} going through {}",
+            relay.hostname,
+            exit_relay.hostname,
+        );
+        Ok((relay, settings))
+    }
+
+    fn get_bridge_for(
+        &self,
+        config: &SelectorConfig,
+        location: Option<Coordinates>,
+        retry_attempt: u32,
+    ) -> Result<Option<SelectedBridge>, Error> {
+        match &config.bridge_settings {
+            BridgeSettings::Normal(settings) => {
+                let bridge_constraints = InternalBridgeConstraints {
+                    location: settings.location.clone(),
                     providers: settings.providers.clone(),
                     ownership: settings.ownership,
                     // FIXME: This is temporary while talpid-core only supports TCP proxies
                     transport_protocol: Constraint::Only(TransportProtocol::Tcp),
+                    ip_version: settings.ip_version,
+                    avoided_locations: Self::avoided_bridge_locations(config),
                 };
                 match config.bridge_state {
                     BridgeState::On => {
                         let (settings, relay) = self
-                            .get_proxy_settings(&bridge_constraints, Some(location))
-                            .ok_or(Error::NoBridge)?;
+                            .get_proxy_settings(&bridge_constraints, location)
+                            .ok_or(Error::NoBridge { forced: true })?;
                         Ok(Some(SelectedBridge::Normal(NormalSelectedBridge {
                             settings,
                             relay,
                         })))
                     }
-                    BridgeState::Auto if Self::should_use_bridge(retry_attempt) => Ok(self
-                        .get_proxy_settings(&bridge_constraints, Some(location))
-                        .map(|(settings, relay)| {
-                            SelectedBridge::Normal(NormalSelectedBridge { settings, relay })
-                        })),
+                    BridgeState::Auto if Self::should_use_bridge(retry_attempt) => {
+                        let bridge = self.get_proxy_settings(&bridge_constraints, location).map(
+                            |(settings, relay)| {
+                                SelectedBridge::Normal(NormalSelectedBridge { settings, relay })
+                            },
+                        );
+                        if bridge.is_none() {
+                            log::warn!("No bridge matches the constraints, connecting without one");
+                        }
+                        Ok(bridge)
+                    }
                     BridgeState::Auto | BridgeState::Off => Ok(None),
                 }
             }
@@ -769,7 +1879,9 @@ impl RelaySelector {
         let config = self.config.lock();
 
         let near_location = match &config.relay_settings {
-            RelaySettings::Normal(settings) => self.get_relay_midpoint(settings),
+            RelaySettings::Normal(settings) => {
+                self.get_relay_midpoint(settings, &config.custom_lists)
+            }
             _ => None,
         };
 
@@ -779,12 +1891,16 @@ impl RelaySelector {
                 providers: settings.providers.clone(),
                 ownership: settings.ownership,
                 transport_protocol: Constraint::Only(TransportProtocol::Tcp),
+                ip_version: settings.ip_version,
+                avoided_locations: Self::avoided_bridge_locations(&config),
             },
             BridgeSettings::Custom(_bridge_settings) => InternalBridgeConstraints {
                 location: Constraint::Any,
                 providers: Constraint::Any,
                 ownership: Constraint::Any,
                 transport_protocol: Constraint::Only(TransportProtocol::Tcp),
+                ip_version: Constraint::Any,
+                avoided_locations: Self::avoided_bridge_locations(&config),
             },
         };
 
@@ -811,10 +1927,13 @@ impl RelaySelector {
             location: constraints.location.clone(),
             providers: constraints.providers.clone(),
             ownership: constraints.ownership,
-            endpoint_matcher: BridgeMatcher(()),
+            endpoint_subnet: Constraint::Any,
+            excluded_relays: Vec::new(),
+            avoided_locations: constraints.avoided_locations.clone(),
+            endpoint_matcher: BridgeMatcher(constraints.ip_version),
         };
         let matching_relays: Vec<Relay> =
-            matcher.filter_matching_relay_list(self.parsed_relays.lock().relays());
+            matcher.filter_matching_relay_list(&self.available_relays());
 
         if matching_relays.is_empty() {
             return None;
@@ -864,11 +1983,46 @@ impl RelaySelector {
             self.pick_random_relay(&matching_relays).cloned()
         };
         relay.and_then(|relay| {
-            self.pick_random_bridge(&self.parsed_relays.lock().locations.bridge, &relay)
-                .map(|bridge| (bridge, relay.clone()))
+            self.pick_random_bridge(
+                &self.parsed_relays.lock().locations.bridge,
+                &relay,
+                constraints.ip_version,
+            )
+            .map(|bridge| (bridge, relay.clone()))
         })
     }
 
+    /// Verifies that the relay of a selected obfuscator is still active in the current relay
+    /// list. Otherwise, the obfuscator is dropped if obfuscation is set to `auto`, and
+    /// [Error::NoObfuscator] is returned if obfuscation is required.
+    pub fn check_obfuscator_online(
+        &self,
+        obfuscator: SelectedObfuscator,
+    ) -> Result<Option<SelectedObfuscator>, Error> {
+        let online = self
+            .parsed_relays
+            .lock()
+            .relays()
+            .iter()
+            .any(|relay| relay.hostname == obfuscator.relay.hostname && relay.active);
+        if online {
+            return Ok(Some(obfuscator));
+        }
+        match self.config.lock().obfuscation_settings.selected_obfuscation {
+            SelectedObfuscation::Auto => {
+                log::warn!(
+                    "Obfuscator relay {} is offline, connecting without obfuscation",
+                    obfuscator.relay.hostname
+                );
+                Ok(None)
+            }
+            _ => {
+                log::error!("Obfuscator relay {} is offline", obfuscator.relay.hostname);
+                Err(Error::NoObfuscator)
+            }
+        }
+    }
+
     pub fn get_obfuscator(
         &self,
         relay: &Relay,
@@ -880,7 +2034,7 @@ impl RelaySelector {
 
     fn get_obfuscator_inner(
         &self,
-        config: &MutexGuard<'_, SelectorConfig>,
+        config: &SelectorConfig,
         relay: &Relay,
         endpoint: &MullvadWireguardEndpoint,
         retry_attempt: u32,
@@ -912,30 +2066,57 @@ impl RelaySelector {
         endpoint: &MullvadWireguardEndpoint,
         retry_attempt: u32,
     ) -> Option<SelectedObfuscator> {
-        if !self.should_use_auto_obfuscator(retry_attempt) {
-            return None;
-        }
         // TODO FIX: The third obfuscator entry will never be chosen
         // Because get_auto_obfuscator_retry_attempt() returns [0, 1]
         // And the udp2tcp endpoints are defined in a vector with entries [0, 1, 2]
+        let udp2tcp_attempt =
+            self.get_auto_obfuscator_retry_attempt(obfuscation_settings, retry_attempt)?;
         self.get_udp2tcp_obfuscator(
             &obfuscation_settings.udp2tcp,
             relay,
             endpoint,
-            self.get_auto_obfuscator_retry_attempt(retry_attempt)
-                .unwrap(),
+            udp2tcp_attempt,
         )
     }
 
-    fn should_use_auto_obfuscator(&self, retry_attempt: u32) -> bool {
-        self.get_auto_obfuscator_retry_attempt(retry_attempt)
+    fn will_use_obfuscation(
+        &self,
+        obfuscation_settings: &ObfuscationSettings,
+        retry_attempt: u32,
+    ) -> bool {
+        match obfuscation_settings.selected_obfuscation {
+            SelectedObfuscation::Auto => {
+                self.should_use_auto_obfuscator(obfuscation_settings, retry_attempt)
+            }
+            SelectedObfuscation::Off => false,
+            SelectedObfuscation::Udp2Tcp => true,
+        }
+    }
+
+    fn should_use_auto_obfuscator(
+        &self,
+        obfuscation_settings: &ObfuscationSettings,
+        retry_attempt: u32,
+    ) -> bool {
+        self.get_auto_obfuscator_retry_attempt(obfuscation_settings, retry_attempt)
             .is_some()
     }
 
-    fn get_auto_obfuscator_retry_attempt(&self, retry_attempt: u32) -> Option<u32> {
-        match retry_attempt % 4 {
-            0 | 1 => None,
-            filtered_retry => Some(filtered_retry - 2),
+    /// Returns the retry attempt to select a udp2tcp obfuscator with, if the automatic
+    /// obfuscation mode uses udp2tcp for `retry_attempt`. The methods in the priority order are
+    /// used for two attempts each, in turn.
+    fn get_auto_obfuscator_retry_attempt(
+        &self,
+        obfuscation_settings: &ObfuscationSettings,
+        retry_attempt: u32,
+    ) -> Option<u32> {
+        let priority = &obfuscation_settings.priority;
+        if priority.is_empty() {
+            return None;
+        }
+        match priority[(retry_attempt / 2) as usize % priority.len()] {
+            ObfuscationMethod::Off => None,
+            ObfuscationMethod::Udp2Tcp => Some(retry_attempt % 2),
         }
     }
 
@@ -1012,8 +2193,8 @@ impl RelaySelector {
         }
 
         // Try out WireGuard in the first two connection attempts, first with any port,
-        // afterwards on port 53. Afterwards, connect through OpenVPN alternating between UDP
-        // on any port twice and TCP on port 443 once.
+        // afterwards on the first fallback port. Afterwards, connect through OpenVPN alternating
+        // between UDP on any port twice and TCP on port 443 once.
         match retry_attempt {
             0 => (
                 Constraint::Any,
@@ -1021,7 +2202,13 @@ impl RelaySelector {
                 TunnelType::Wireguard,
             ),
             1 => (
-                Constraint::Only(53),
+                self.port_fallback
+                    .lock()
+                    .ports()
+                    .first()
+                    .copied()
+                    .map(Constraint::Only)
+                    .unwrap_or(Constraint::Any),
                 TransportProtocol::Udp,
                 TunnelType::Wireguard,
             ),
@@ -1033,13 +2220,17 @@ impl RelaySelector {
         }
     }
 
-    fn preferred_wireguard_port(retry_attempt: u32) -> Constraint<u16> {
+    fn preferred_wireguard_port(&self, retry_attempt: u32) -> Constraint<u16> {
         // This ensures that if after the first 2 failed attempts the daemon does not
         // connect, then afterwards 2 of each 4 successive attempts will try to connect
-        // on port 53.
+        // on a fallback port. The enabled fallback ports take turns.
+        let fallback_ports = self.port_fallback.lock().ports();
         match retry_attempt % 4 {
             0 | 1 => Constraint::Any,
-            _ => Constraint::Only(53),
+            _ if fallback_ports.is_empty() => Constraint::Any,
+            _ => Constraint::Only(
+                fallback_ports[(retry_attempt / 4) as usize % fallback_ports.len()],
+            ),
         }
     }
 
@@ -1066,12 +2257,33 @@ impl RelaySelector {
         &self,
         matcher: &RelayMatcher<T>,
     ) -> Result<NormalSelectedRelay, Error> {
+        let relays = self.tunnel_relays();
         let matching_relays: Vec<Relay> = matcher
-            .filter_matching_relay_list(self.parsed_relays.lock().relays())
+            .filter_matching_relay_list(&relays)
             .into_iter()
             .collect();
+        if matching_relays.is_empty() {
+            Self::log_filter_report(matcher, &relays);
+            return Err(self.no_relay_error(matcher));
+        }
+
+        let relay_latencies = self.relay_latencies.lock().clone();
+        let client_location = self.client_location.lock().clone();
+        let selected_relay = if matching_relays
+            .iter()
+            .any(|relay| relay_latencies.contains_key(&relay.hostname))
+        {
+            self.pick_random_low_latency_relay(&matching_relays, &relay_latencies)
+        } else {
+            match client_location {
+                Some(client_location) if matcher.location.is_any() => {
+                    self.pick_random_nearby_relay(&matching_relays, &client_location)
+                }
+                _ => self.pick_random_relay(&matching_relays),
+            }
+        };
 
-        self.pick_random_relay(&matching_relays)
+        selected_relay
             .and_then(|selected_relay| {
                 let endpoint = matcher.mullvad_endpoint(selected_relay);
                 let addr_in = endpoint
@@ -1084,10 +2296,80 @@ impl RelaySelector {
             .ok_or(Error::NoRelay)
     }
 
-    /// Picks a relay using [Self::pick_random_relay_fn], using the `weight` member of each relay
-    /// as the weight function.
+    /// Logs why no relays matched the given matcher. The report is only computed if debug
+    /// logging is enabled.
+    fn log_filter_report<T: EndpointMatcher>(matcher: &RelayMatcher<T>, relays: &[Relay]) {
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!("{}", matcher.filter_report(relays));
+        }
+    }
+
+    /// Picks a relay using [Self::pick_random_relay_fn], using the `weight` member of each relay,
+    /// scaled by [Self::weight_scale_fn], as the weight function.
     fn pick_random_relay<'a>(&self, relays: &'a [Relay]) -> Option<&'a Relay> {
-        self.pick_random_relay_fn(relays, |relay| relay.weight)
+        let weight_scale = self.weight_scale_fn();
+        self.pick_random_relay_fn(relays, |relay| {
+            (relay.weight as f64 * CLIENT_DISTANCE_WEIGHT_RESOLUTION * weight_scale(relay)).ceil()
+                as u64
+        })
+    }
+
+    /// Picks a relay using [Self::pick_random_relay_fn], scaling the `weight` member of each
+    /// relay down the further away it is from `client_location`.
+    fn pick_random_nearby_relay<'a>(
+        &self,
+        relays: &'a [Relay],
+        client_location: &Coordinates,
+    ) -> Option<&'a Relay> {
+        let weight_scale = self.weight_scale_fn();
+        let weight_fn = |relay: &Relay| {
+            let distance = relay
+                .location
+                .as_ref()
+                .map(|location| location.distance_from(client_location))
+                .unwrap_or(0.0);
+            let scale = weight_scale(relay) * CLIENT_DISTANCE_HALF_WEIGHT
+                / (CLIENT_DISTANCE_HALF_WEIGHT + distance.max(0.0));
+            (relay.weight as f64 * CLIENT_DISTANCE_WEIGHT_RESOLUTION * scale).ceil() as u64
+        };
+        self.pick_random_relay_fn(relays, weight_fn)
+    }
+
+    /// Picks a relay using [Self::pick_random_relay_fn], scaling the `weight` member of each
+    /// relay down the higher its latency is compared to the relay with the lowest latency. Relays
+    /// without a measured latency are treated like the relay with the highest latency.
+    fn pick_random_low_latency_relay<'a>(
+        &self,
+        relays: &'a [Relay],
+        latencies: &HashMap<String, Duration>,
+    ) -> Option<&'a Relay> {
+        let measured = relays
+            .iter()
+            .filter_map(|relay| latencies.get(&relay.hostname))
+            .map(|latency| latency.as_secs_f64() * 1000.0);
+        let lowest = measured.clone().fold(f64::INFINITY, f64::min);
+        let highest = measured.fold(0f64, f64::max);
+        let weight_scale = self.weight_scale_fn();
+        let weight_fn = |relay: &Relay| {
+            let latency = latencies
+                .get(&relay.hostname)
+                .map(|latency| latency.as_secs_f64() * 1000.0)
+                .unwrap_or(highest);
+            let scale = weight_scale(relay) * RELAY_LATENCY_HALF_WEIGHT
+                / (RELAY_LATENCY_HALF_WEIGHT + (latency - lowest).max(0.0));
+            (relay.weight as f64 * CLIENT_DISTANCE_WEIGHT_RESOLUTION * scale).ceil() as u64
+        };
+        self.pick_random_relay_fn(relays, weight_fn)
+    }
+
+    /// Returns a function that gives the factor by which the weight of a relay is scaled by the
+    /// registered scorers, and by [LoadScorer] if load-aware selection is enabled.
+    fn weight_scale_fn(&self) -> impl Fn(&Relay) -> f64 {
+        let mut scorers = self.scorers.lock().clone();
+        if *self.load_aware.lock() {
+            scorers.push(Arc::new(LoadScorer));
+        }
+        move |relay| filter::combined_scale(&scorers, relay)
     }
 
     /// Pick a random relay from the given slice. Will return `None` if the given slice is empty.
@@ -1100,13 +2382,12 @@ impl RelaySelector {
         weight_fn: impl Fn(&RelayType) -> u64,
     ) -> Option<&'a RelayType> {
         let total_weight: u64 = relays.iter().map(&weight_fn).sum();
-        let mut rng = rand::thread_rng();
         if total_weight == 0 {
-            relays.choose(&mut rng)
+            rng::with_rng(|rng| relays.choose(rng))
         } else {
             // Pick a random number in the range 1..=total_weight. This choses the relay with a
             // non-zero weight.
-            let mut i: u64 = rng.gen_range(1..=total_weight);
+            let mut i: u64 = rng::with_rng(|rng| rng.gen_range(1..=total_weight));
             Some(
                 relays
                     .iter()
@@ -1119,31 +2400,41 @@ impl RelaySelector {
         }
     }
 
-    /// Picks a random bridge from a relay.
+    /// Picks a random bridge from a relay, using the address of the relay with the given IP
+    /// version.
     fn pick_random_bridge(
         &self,
         data: &BridgeEndpointData,
         relay: &Relay,
+        ip_version: Constraint<IpVersion>,
     ) -> Option<ProxySettings> {
         if relay.endpoint_data != RelayEndpointData::Bridge {
             return None;
         }
-        data.shadowsocks
-            .choose(&mut rand::thread_rng())
-            .map(|shadowsocks_endpoint| {
-                log::info!(
-                    "Selected Shadowsocks bridge {} at {}:{}/{}",
-                    relay.hostname,
-                    relay.ipv4_addr_in,
-                    shadowsocks_endpoint.port,
-                    shadowsocks_endpoint.protocol
-                );
-                shadowsocks_endpoint.to_proxy_settings(
-                    relay.ipv4_addr_in.into(),
-                    #[cfg(target_os = "linux")]
-                    mullvad_types::TUNNEL_FWMARK,
-                )
-            })
+        Self::shadowsocks_proxy(data, relay, ip_version)
+    }
+
+    /// Returns proxy settings for a random Shadowsocks endpoint on `relay`.
+    fn shadowsocks_proxy(
+        data: &BridgeEndpointData,
+        relay: &Relay,
+        ip_version: Constraint<IpVersion>,
+    ) -> Option<ProxySettings> {
+        let address = matcher::entry_address(relay, ip_version)?;
+        rng::with_rng(|rng| data.shadowsocks.choose(rng)).map(|shadowsocks_endpoint| {
+            log::info!(
+                "Selected Shadowsocks proxy {} at {}:{}/{}",
+                relay.hostname,
+                address,
+                shadowsocks_endpoint.port,
+                shadowsocks_endpoint.protocol
+            );
+            shadowsocks_endpoint.to_proxy_settings(
+                address,
+                #[cfg(target_os = "linux")]
+                mullvad_types::TUNNEL_FWMARK,
+            )
+        })
     }
 
     /// Try to read the relays from disk, preferring the newer ones.
@@ -1177,7 +2468,7 @@ impl RelaySelector {
         let mut tunnel =
             WireguardMatcher::from_endpoint(self.parsed_relays.lock().locations.wireguard.clone());
         tunnel.ip_version = WIREGUARD_EXIT_IP_VERSION;
-        tunnel.port = WIREGUARD_EXIT_PORT;
+        tunnel.port = WIREGUARD_EXIT_PORT.map(PortRanges::from);
         tunnel
     }
 }
@@ -1238,13 +2529,17 @@ impl NormalSelectedRelay {
 #[cfg(test)]
 mod test {
     use super::*;
+    use matcher::FilterReport;
     use mullvad_types::{
-        relay_constraints::{BridgeConstraints, RelayConstraints},
+        custom_list::CustomList,
+        relay_constraints::{BridgeConstraints, CustomHop, CustomMultihopRelay, RelayConstraints},
         relay_list::{
             OpenVpnEndpoint, OpenVpnEndpointData, Relay, RelayListCity, RelayListCountry,
-            WireguardEndpointData, WireguardRelayEndpointData,
+            RelayListSource, ShadowsocksEndpointData, WireguardEndpointData,
+            WireguardRelayEndpointData,
         },
     };
+    use std::time::Duration;
     use talpid_types::net::wireguard::PublicKey;
 
     lazy_static::lazy_static! {
@@ -1270,8 +2565,13 @@ mod test {
                                     owned: true,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    load: None,
                                     endpoint_data: RelayEndpointData::Wireguard(WireguardRelayEndpointData {
                                         public_key: PublicKey::from_base64("BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=").unwrap(),
+                                        multihop_entry: true,
+                                        pluggable_transports: vec![],
+                                        quantum_resistant: true,
+                                        daita: false,
                                     }),
                                     location: None,
                                 },
@@ -1284,8 +2584,13 @@ mod test {
                                     owned: false,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    load: None,
                                     endpoint_data: RelayEndpointData::Wireguard(WireguardRelayEndpointData {
                                         public_key: PublicKey::from_base64("BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=").unwrap(),
+                                        multihop_entry: true,
+                                        pluggable_transports: vec![],
+                                        quantum_resistant: true,
+                                        daita: false,
                                     }),
                                     location: None,
                                 },
@@ -1298,6 +2603,7 @@ mod test {
                                     owned: true,
                                     provider: "31173".to_string(),
                                     weight: 1,
+                                    load: None,
                                     endpoint_data: RelayEndpointData::Openvpn,
                                     location: None,
                                 }
@@ -1331,16 +2637,13 @@ mod test {
                 ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
                 udp2tcp_ports: vec![],
             },
+            source: RelayListSource::Api,
         };
     }
 
     fn new_relay_selector_with_relays(relay_list: RelayList) -> RelaySelector {
-        RelaySelector {
-            parsed_relays: Arc::new(Mutex::new(ParsedRelays::from_relay_list(
-                relay_list,
-                SystemTime::now(),
-            ))),
-            config: Arc::new(Mutex::new(SelectorConfig {
+        RelaySelector::from_relay_list(
+            SelectorConfig {
                 relay_settings: RelaySettings::Normal(RelayConstraints {
                     location: Constraint::Only(LocationConstraint::Country("se".to_owned())),
                     ..Default::default()
@@ -1351,15 +2654,322 @@ mod test {
                     ..Default::default()
                 },
                 bridge_state: BridgeState::Auto,
+                custom_lists: CustomListsSettings::default(),
+                escalation_ladder: EscalationLadder::default(),
                 default_tunnel_type: TunnelType::Wireguard,
-            })),
-        }
+            },
+            relay_list,
+        )
     }
 
     fn new_relay_selector() -> RelaySelector {
         new_relay_selector_with_relays(RELAYS.clone())
     }
 
+    #[test]
+    fn test_check_constraints() {
+        let mut relay_selector = new_relay_selector();
+        let mut config = relay_selector.config.lock().clone();
+        assert!(relay_selector.check_constraints().is_ok());
+
+        let mut relay_constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Country("no".to_string())),
+            ..RelayConstraints::default()
+        };
+        config.relay_settings = RelaySettings::Normal(relay_constraints.clone());
+        relay_selector.set_config(config.clone());
+        assert!(matches!(
+            relay_selector.check_constraints(),
+            Err(Error::NoRelay)
+        ));
+
+        relay_constraints.location =
+            Constraint::Only(LocationConstraint::Country("se".to_string()));
+        relay_constraints.tunnel_protocol = Constraint::Only(TunnelType::OpenVpn);
+        config.relay_settings = RelaySettings::Normal(relay_constraints);
+        config.bridge_state = BridgeState::On;
+        config.bridge_settings = BridgeSettings::Normal(BridgeConstraints {
+            location: Constraint::Only(LocationConstraint::Country("no".to_string())),
+            ..BridgeConstraints::default()
+        });
+        relay_selector.set_config(config);
+        assert!(matches!(
+            relay_selector.check_constraints(),
+            Err(Error::NoBridge { forced: true })
+        ));
+    }
+
+    #[test]
+    fn test_latency_weighting() {
+        let relay_selector = new_relay_selector();
+        let constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::City(
+                "se".to_string(),
+                "got".to_string(),
+            )),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        let count_selections = |relay_selector: &RelaySelector| {
+            let mut counts = HashMap::new();
+            for _ in 0..1000 {
+                let relay = relay_selector
+                    .get_tunnel_endpoint(&constraints, BridgeState::Auto, 0, TunnelType::Wireguard)
+                    .unwrap();
+                *counts.entry(relay.exit_relay.hostname).or_insert(0) += 1;
+            }
+            counts
+        };
+
+        relay_selector.set_relay_latencies(HashMap::from([
+            ("se9-wireguard".to_string(), Duration::from_millis(10)),
+            ("se10-wireguard".to_string(), Duration::from_millis(100)),
+        ]));
+        let counts = count_selections(&relay_selector);
+        assert!(counts["se9-wireguard"] > 800, "{:?}", counts);
+        assert!(counts.contains_key("se10-wireguard"), "{:?}", counts);
+
+        // Relays without a measured latency are treated like the slowest relay
+        relay_selector.set_relay_latencies(HashMap::from([(
+            "se10-wireguard".to_string(),
+            Duration::from_millis(10),
+        )]));
+        let counts = count_selections(&relay_selector);
+        assert!(counts["se10-wireguard"] > 400, "{:?}", counts);
+        assert!(counts["se9-wireguard"] > 400, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_load_weighting() {
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        relays[0].load = Some(90);
+        relays[1].load = Some(0);
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        let constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        let count_selections = |relay_selector: &RelaySelector| {
+            let mut counts = HashMap::new();
+            for _ in 0..1000 {
+                let relay = relay_selector
+                    .get_tunnel_endpoint(&constraints, BridgeState::Auto, 0, TunnelType::Wireguard)
+                    .unwrap();
+                *counts.entry(relay.exit_relay.hostname).or_insert(0) += 1;
+            }
+            counts
+        };
+
+        let counts = count_selections(&relay_selector);
+        assert!(counts["se10-wireguard"] > 800, "{:?}", counts);
+        assert!(counts.contains_key("se9-wireguard"), "{:?}", counts);
+
+        // Without load-aware selection, the load is ignored
+        relay_selector.set_load_aware(false);
+        let counts = count_selections(&relay_selector);
+        assert!(counts["se9-wireguard"] > 400, "{:?}", counts);
+        assert!(counts["se10-wireguard"] > 400, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_has_matching_relay() {
+        let relay_selector = new_relay_selector();
+        assert!(relay_selector.has_matching_relay(None));
+
+        let city_override = |city: &str| RelayConstraintsUpdate {
+            location: Some(Constraint::Only(LocationConstraint::City(
+                "se".to_string(),
+                city.to_string(),
+            ))),
+            ..RelayConstraintsUpdate::default()
+        };
+        assert!(relay_selector.has_matching_relay(Some(city_override("got"))));
+        assert!(!relay_selector.has_matching_relay(Some(city_override("sto"))));
+    }
+
+    #[test]
+    fn test_client_location_weighting() {
+        let template = RELAYS.countries[0].cities[0].relays[0].clone();
+        let city = |name: &str, code: &str, latitude, longitude| RelayListCity {
+            name: name.to_string(),
+            code: code.to_string(),
+            latitude,
+            longitude,
+            relays: vec![Relay {
+                hostname: format!("se-{}-wg-001", code),
+                ..template.clone()
+            }],
+        };
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities = vec![
+            city("Gothenburg", "got", 57.70887, 11.97456),
+            city("Stockholm", "sto", 59.3289, 18.0649),
+            city("Luleå", "lla", 65.5848, 22.1547),
+        ];
+
+        let mut relay_selector = new_relay_selector_with_relays(relay_list);
+        let mut config = relay_selector.config.lock().clone();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        });
+        relay_selector.set_config(config);
+        relay_selector.set_client_location(Some(Coordinates {
+            latitude: 57.70887,
+            longitude: 11.97456,
+        }));
+
+        let mut selections = HashMap::new();
+        for _ in 0..1000 {
+            let (relay, ..) = relay_selector.get_relay(0).expect("expected a relay");
+            let hostname = match relay {
+                SelectedRelay::Normal(relay) => relay.exit_relay.hostname,
+                SelectedRelay::Custom(_) => panic!("expected a normal relay"),
+            };
+            *selections.entry(hostname).or_insert(0) += 1;
+        }
+
+        let closest = selections.get("se-got-wg-001").copied().unwrap_or(0);
+        assert!(closest > selections.get("se-sto-wg-001").copied().unwrap_or(0));
+        assert!(closest > selections.get("se-lla-wg-001").copied().unwrap_or(0));
+    }
+
+    #[test]
+    fn test_nearest_location() {
+        let template = RELAYS.countries[0].cities[0].relays[0].clone();
+        let city = |name: &str, code: &str, latitude, longitude| RelayListCity {
+            name: name.to_string(),
+            code: code.to_string(),
+            latitude,
+            longitude,
+            relays: vec![Relay {
+                hostname: format!("se-{}-wg-001", code),
+                ..template.clone()
+            }],
+        };
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities = vec![
+            city("Gothenburg", "got", 57.70887, 11.97456),
+            city("Stockholm", "sto", 59.3289, 18.0649),
+            city("Luleå", "lla", 65.5848, 22.1547),
+        ];
+
+        let mut relay_selector = new_relay_selector_with_relays(relay_list);
+        let mut config = relay_selector.config.lock().clone();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Nearest),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        });
+        relay_selector.set_config(config);
+        let select_hostname = |relay_selector: &RelaySelector| match relay_selector
+            .get_relay(0)
+            .expect("expected a relay")
+            .0
+        {
+            SelectedRelay::Normal(relay) => relay.exit_relay.hostname,
+            SelectedRelay::Custom(_) => panic!("expected a normal relay"),
+        };
+
+        // Luleå is closer to Stockholm than to Gothenburg
+        relay_selector.set_client_location(Some(Coordinates {
+            latitude: 65.5848,
+            longitude: 22.1547,
+        }));
+        for _ in 0..10 {
+            assert_eq!(select_hostname(&relay_selector), "se-lla-wg-001");
+        }
+
+        // The next closest city is used if no relay in the closest one can be used
+        relay_selector.set_excluded_relays(HashSet::from(["se-lla-wg-001".to_string()]));
+        for _ in 0..10 {
+            assert_eq!(select_hostname(&relay_selector), "se-sto-wg-001");
+        }
+        assert_eq!(
+            relay_selector
+                .candidate_relays(None)
+                .into_iter()
+                .map(|relay| relay.hostname)
+                .collect::<Vec<_>>(),
+            vec!["se-sto-wg-001".to_string()]
+        );
+
+        // Any location is used if the location of the client is unknown
+        relay_selector.set_client_location(None);
+        let hostnames: HashSet<_> = (0..100).map(|_| select_hostname(&relay_selector)).collect();
+        assert!(hostnames.contains("se-got-wg-001"), "{:?}", hostnames);
+        assert!(hostnames.contains("se-sto-wg-001"), "{:?}", hostnames);
+    }
+
+    #[test]
+    fn test_maintenance_window() {
+        fn before_window() -> SystemTime {
+            time::UNIX_EPOCH + Duration::from_secs(1000)
+        }
+        fn during_window() -> SystemTime {
+            time::UNIX_EPOCH + Duration::from_secs(2000)
+        }
+        fn after_window() -> SystemTime {
+            time::UNIX_EPOCH + Duration::from_secs(3000)
+        }
+
+        let mut relay_selector = new_relay_selector();
+        let mut config = relay_selector.config.lock().clone();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se9-wireguard".to_string(),
+            )),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        });
+        relay_selector.set_config(config);
+        relay_selector.set_maintenance_windows(HashMap::from([(
+            "se9-wireguard".to_string(),
+            MaintenanceWindow {
+                start: time::UNIX_EPOCH + Duration::from_secs(1500),
+                end: time::UNIX_EPOCH + Duration::from_secs(2500),
+            },
+        )]));
+
+        for clock in [before_window as fn() -> SystemTime, after_window] {
+            relay_selector.clock = clock;
+            assert!(relay_selector.get_relay(0).is_ok());
+        }
+
+        relay_selector.clock = during_window;
+        assert!(matches!(
+            relay_selector.get_relay(0),
+            Err(Error::RelaysInMaintenance)
+        ));
+    }
+
+    #[test]
+    fn test_relay_list_snapshot() {
+        let mut relay_list = RELAYS.clone();
+        for city in &mut relay_list.countries[0].cities {
+            city.relays
+                .retain(|relay| matches!(relay.endpoint_data, RelayEndpointData::Wireguard(_)));
+        }
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        assert_eq!(relay_selector.parsed_relays.lock().relays().len(), 2);
+
+        for attempt in 0..10 {
+            let (relay, ..) = relay_selector
+                .get_relay(attempt)
+                .expect("expected a relay from the snapshot");
+            match relay {
+                SelectedRelay::Normal(relay) => assert!(
+                    relay.exit_relay.hostname == "se9-wireguard"
+                        || relay.exit_relay.hostname == "se10-wireguard"
+                ),
+                SelectedRelay::Custom(_) => panic!("expected a relay from the snapshot"),
+            }
+        }
+    }
+
     #[test]
     fn test_preferred_tunnel_protocol() {
         let relay_selector = new_relay_selector();
@@ -1671,6 +3281,104 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_no_bridge_forced_and_auto() {
+        // The test relay list contains no bridges
+        let relay_selector = new_relay_selector();
+        let mut config = relay_selector.config.lock().clone();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Country("se".to_owned())),
+            tunnel_protocol: Constraint::Only(TunnelType::OpenVpn),
+            openvpn_constraints: OpenVpnConstraints {
+                port: Constraint::Only(TransportPort {
+                    protocol: TransportProtocol::Tcp,
+                    port: Constraint::Any,
+                }),
+                ..OpenVpnConstraints::default()
+            },
+            ..RelayConstraints::default()
+        });
+
+        let mut forced_selector = relay_selector.clone();
+        forced_selector.set_config(SelectorConfig {
+            bridge_state: BridgeState::On,
+            ..config.clone()
+        });
+        assert!(matches!(
+            forced_selector.get_relay(0),
+            Err(Error::NoBridge { forced: true })
+        ));
+
+        // Retry attempt 4 is the first attempt to use a bridge in auto mode
+        let mut auto_selector = new_relay_selector();
+        auto_selector.set_config(SelectorConfig {
+            bridge_state: BridgeState::Auto,
+            ..config
+        });
+        let (relay, bridge, _obfuscator) = auto_selector
+            .get_relay(4)
+            .expect("auto mode should fall back on not using a bridge");
+        assert!(matches!(relay, SelectedRelay::Normal(_)));
+        assert!(bridge.is_none());
+    }
+
+    #[test]
+    fn test_custom_relay_bridge() {
+        use talpid_types::net::{
+            openvpn::{self, LocalProxySettings},
+            Endpoint,
+        };
+
+        let custom_relay = |protocol| {
+            CustomTunnelEndpoint::new(
+                "vpn.example.com".to_string(),
+                ConnectionConfig::OpenVpn(openvpn::ConnectionConfig::new(
+                    Endpoint::new([10, 0, 0, 1], 443, protocol),
+                    "user".to_string(),
+                    "pass".to_string(),
+                )),
+            )
+        };
+        let proxy = ProxySettings::Local(LocalProxySettings {
+            port: 1080,
+            peer: "10.0.0.2:443".parse().unwrap(),
+        });
+
+        let mut relay_selector = new_relay_selector();
+        let config = SelectorConfig {
+            relay_settings: RelaySettings::CustomTunnelEndpoint(custom_relay(
+                TransportProtocol::Tcp,
+            )),
+            bridge_state: BridgeState::On,
+            bridge_settings: BridgeSettings::Custom(proxy.clone()),
+            ..relay_selector.config()
+        };
+        relay_selector.set_config(config.clone());
+        let (relay, bridge, _obfuscator) = relay_selector.get_relay(0).unwrap();
+        assert!(matches!(relay, SelectedRelay::Custom(_)));
+        assert!(matches!(bridge, Some(SelectedBridge::Custom(settings)) if settings == proxy));
+
+        // Bridges cannot carry UDP traffic
+        relay_selector.set_config(SelectorConfig {
+            relay_settings: RelaySettings::CustomTunnelEndpoint(custom_relay(
+                TransportProtocol::Udp,
+            )),
+            ..config.clone()
+        });
+        let (_relay, bridge, _obfuscator) = relay_selector.get_relay(0).unwrap();
+        assert!(bridge.is_none());
+
+        // There are no Mullvad bridges in the relay list
+        relay_selector.set_config(SelectorConfig {
+            bridge_settings: BridgeSettings::Normal(BridgeConstraints::default()),
+            ..config
+        });
+        assert!(matches!(
+            relay_selector.get_relay(0),
+            Err(Error::NoBridge { forced: true })
+        ));
+    }
+
     #[test]
     fn test_selecting_any_relay_will_consider_multihop() {
         let relay_constraints = RelayConstraints {
@@ -1708,36 +3416,202 @@ mod test {
         );
     }
 
-    const WIREGUARD_MULTIHOP_CONSTRAINTS: RelayConstraints = RelayConstraints {
-        location: Constraint::Any,
-        providers: Constraint::Any,
-        ownership: Constraint::Any,
-        wireguard_constraints: WireguardConstraints {
-            use_multihop: true,
-            port: Constraint::Any,
-            ip_version: Constraint::Any,
-            entry_location: Constraint::Any,
-        },
-        tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
-        openvpn_constraints: OpenVpnConstraints {
-            port: Constraint::Any,
-        },
-    };
-
-    const WIREGUARD_SINGLEHOP_CONSTRAINTS: RelayConstraints = RelayConstraints {
-        location: Constraint::Any,
-        providers: Constraint::Any,
-        ownership: Constraint::Any,
+    #[test]
+    fn test_custom_multihop_relay() {
+        let relay_selector = new_relay_selector();
+        let mut relay_constraints = RelayConstraints {
+            wireguard_constraints: WireguardConstraints {
+                use_multihop: true,
+                custom_hop: Some(CustomMultihopRelay {
+                    hop: CustomHop::Entry,
+                    host: "192.0.2.10".to_string(),
+                    port: 51820,
+                    public_key: PublicKey::from_base64(
+                        "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
+                    )
+                    .unwrap(),
+                }),
+                ..WireguardConstraints::default()
+            },
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+
+        // The custom server replaces one of the relays
+        let result = relay_selector
+            .get_tunnel_endpoint(
+                &relay_constraints,
+                BridgeState::Off,
+                0,
+                TunnelType::Wireguard,
+            )
+            .expect("expected a relay");
+        assert!(result.entry_relay.is_none());
+        assert!(result.endpoint.unwrap_wireguard().exit_peer.is_none());
+
+        // The custom server is ignored if multihop is disabled
+        relay_constraints.wireguard_constraints.use_multihop = false;
+        assert!(relay_constraints
+            .wireguard_constraints
+            .custom_multihop_relay()
+            .is_none());
+    }
+
+    #[test]
+    fn test_shortest_path_entry() {
+        let template = RELAYS.countries[0].cities[0].relays[0].clone();
+        let city = |name: &str, code: &str, latitude, longitude| RelayListCity {
+            name: name.to_string(),
+            code: code.to_string(),
+            latitude,
+            longitude,
+            relays: vec![Relay {
+                hostname: format!("se-{}-wg-001", code),
+                ..template.clone()
+            }],
+        };
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities = vec![
+            city("Gothenburg", "got", 57.70887, 11.97456),
+            city("Malmö", "mma", 55.60587, 13.00073),
+            city("Stockholm", "sto", 59.3289, 18.0649),
+            city("Luleå", "lla", 65.5848, 22.1547),
+        ];
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+
+        let constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::City(
+                "se".to_string(),
+                "lla".to_string(),
+            )),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            wireguard_constraints: WireguardConstraints {
+                use_multihop: true,
+                entry_policy: EntryRelayPolicy::ShortestPath,
+                ..WireguardConstraints::default()
+            },
+            ..RelayConstraints::default()
+        };
+        let select_entry = || {
+            relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Off, 0, TunnelType::Wireguard)
+                .expect("expected a relay")
+                .entry_relay
+                .expect("expected an entry relay")
+                .hostname
+        };
+
+        // Without a client location, the entry relay closest to the exit relay is used
+        for _ in 0..10 {
+            assert_eq!(select_entry(), "se-sto-wg-001");
+        }
+
+        // A relay in the same city as the client is on the shortest path to any exit relay
+        relay_selector.set_client_location(Some(Coordinates {
+            latitude: 57.70887,
+            longitude: 11.97456,
+        }));
+        for _ in 0..10 {
+            assert_eq!(select_entry(), "se-got-wg-001");
+        }
+    }
+
+    #[test]
+    fn test_multihop_entry_capability() {
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        let mut se11 = relays[1].clone();
+        se11.hostname = "se11-wireguard".to_string();
+        relays.push(se11);
+        for relay in relays.iter_mut() {
+            if let RelayEndpointData::Wireguard(data) = &mut relay.endpoint_data {
+                data.multihop_entry = relay.hostname == "se10-wireguard";
+            }
+        }
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+
+        let constraints_with_exit = |exit: &str| RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                exit.to_string(),
+            )),
+            ..WIREGUARD_MULTIHOP_CONSTRAINTS
+        };
+
+        let constraints = constraints_with_exit("se9-wireguard");
+        for attempt in 0..10 {
+            let result = relay_selector
+                .get_tunnel_endpoint(
+                    &constraints,
+                    BridgeState::Off,
+                    attempt,
+                    TunnelType::Wireguard,
+                )
+                .expect("Failed to select a multihop relay");
+            assert_eq!(result.exit_relay.hostname, "se9-wireguard");
+            assert_eq!(result.entry_relay.unwrap().hostname, "se10-wireguard");
+        }
+
+        // The only capable entry relay is the exit relay itself
+        let constraints = constraints_with_exit("se10-wireguard");
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &constraints,
+                BridgeState::Off,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoMultihopEntry)
+        ));
+    }
+
+    const WIREGUARD_MULTIHOP_CONSTRAINTS: RelayConstraints = RelayConstraints {
+        location: Constraint::Any,
+        providers: Constraint::Any,
+        ownership: Constraint::Any,
+        wireguard_constraints: WireguardConstraints {
+            use_multihop: true,
+            port: Constraint::Any,
+            ip_version: Constraint::Any,
+            entry_location: Constraint::Any,
+            entry_policy: EntryRelayPolicy::Random,
+            custom_hop: None,
+        },
+        tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+        openvpn_constraints: OpenVpnConstraints {
+            port: Constraint::Any,
+            ip_version: Constraint::Any,
+            use_multihop: false,
+            entry_location: Constraint::Any,
+        },
+        endpoint_subnet: Constraint::Any,
+        excluded_relays: Vec::new(),
+        avoided_locations: Vec::new(),
+    };
+
+    const WIREGUARD_SINGLEHOP_CONSTRAINTS: RelayConstraints = RelayConstraints {
+        location: Constraint::Any,
+        providers: Constraint::Any,
+        ownership: Constraint::Any,
         wireguard_constraints: WireguardConstraints {
             use_multihop: false,
             port: Constraint::Any,
             ip_version: Constraint::Any,
             entry_location: Constraint::Any,
+            entry_policy: EntryRelayPolicy::Random,
+            custom_hop: None,
         },
         tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
         openvpn_constraints: OpenVpnConstraints {
             port: Constraint::Any,
+            ip_version: Constraint::Any,
+            use_multihop: false,
+            entry_location: Constraint::Any,
         },
+        endpoint_subnet: Constraint::Any,
+        excluded_relays: Vec::new(),
+        avoided_locations: Vec::new(),
     };
 
     #[test]
@@ -1747,146 +3621,1202 @@ mod test {
         let result = relay_selector.get_tunnel_endpoint(&WIREGUARD_MULTIHOP_CONSTRAINTS, BridgeState::Off, 0, TunnelType::Wireguard)
             .expect("Failed to get relay when tunnel constraints are set to default WireGuard multihop constraints");
 
-        assert!(result.entry_relay.is_some());
-        // TODO: Verify that neither endpoint is using obfuscation for retry attempt 0
+        assert!(result.entry_relay.is_some());
+        // TODO: Verify that neither endpoint is using obfuscation for retry attempt 0
+    }
+
+    #[test]
+    fn test_selecting_wg_endpoint_with_udp2tcp_obfuscation() {
+        let relay_selector = new_relay_selector();
+
+        let result = relay_selector.get_tunnel_endpoint(&WIREGUARD_SINGLEHOP_CONSTRAINTS, BridgeState::Off, 0, TunnelType::Wireguard)
+            .expect("Failed to get relay when tunnel constraints are set to default WireGuard constraints");
+
+        assert!(result.entry_relay.is_none());
+        assert!(matches!(result.endpoint, MullvadEndpoint::Wireguard { .. }));
+
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Udp2Tcp,
+            ..ObfuscationSettings::default()
+        };
+
+        let obfs_config = relay_selector
+            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 0)
+            .unwrap()
+            .unwrap();
+
+        assert!(matches!(
+            obfs_config,
+            SelectedObfuscator {
+                config: ObfuscatorConfig::Udp2Tcp { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_udp2tcp_obfuscation_override() {
+        let relay_selector = new_relay_selector();
+        {
+            let mut config = relay_selector.config.lock();
+            config.relay_settings = RelaySettings::Normal(WIREGUARD_SINGLEHOP_CONSTRAINTS.clone());
+            config.obfuscation_settings = ObfuscationSettings {
+                selected_obfuscation: SelectedObfuscation::Udp2Tcp,
+                udp2tcp: Udp2TcpObfuscationSettings {
+                    port: Constraint::Only(80),
+                },
+                ..ObfuscationSettings::default()
+            };
+        }
+        let udp2tcp_port = |udp2tcp_override| {
+            let (_, _, obfuscator) = relay_selector
+                .get_relay_with_obfuscation_override(0, 0, None, udp2tcp_override)
+                .expect("failed to select relay");
+            match obfuscator.expect("expected obfuscator").config {
+                ObfuscatorConfig::Udp2Tcp { endpoint } => endpoint.port(),
+                config => panic!("unexpected obfuscator config {:?}", config),
+            }
+        };
+
+        assert_eq!(udp2tcp_port(None), 80);
+        assert_eq!(
+            udp2tcp_port(Some(Udp2TcpObfuscationSettings {
+                port: Constraint::Only(5001),
+            })),
+            5001
+        );
+        // The stored settings are left untouched
+        assert_eq!(udp2tcp_port(None), 80);
+    }
+
+    #[test]
+    fn test_selecting_wg_endpoint_with_auto_obfuscation() {
+        let relay_selector = new_relay_selector();
+
+        let result = relay_selector.get_tunnel_endpoint(&WIREGUARD_SINGLEHOP_CONSTRAINTS, BridgeState::Off, 0, TunnelType::Wireguard)
+            .expect("Failed to get relay when tunnel constraints are set to default WireGuard constraints");
+
+        assert!(result.entry_relay.is_none());
+        assert!(matches!(result.endpoint, MullvadEndpoint::Wireguard { .. }));
+
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Auto,
+            ..ObfuscationSettings::default()
+        };
+
+        assert!(relay_selector
+            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 0,)
+            .unwrap()
+            .is_none());
+
+        assert!(relay_selector
+            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 1,)
+            .unwrap()
+            .is_none());
+
+        assert!(relay_selector
+            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 2,)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_auto_obfuscation_priority() {
+        let relay_selector = new_relay_selector();
+
+        let result = relay_selector
+            .get_tunnel_endpoint(
+                &WIREGUARD_SINGLEHOP_CONSTRAINTS,
+                BridgeState::Off,
+                0,
+                TunnelType::Wireguard,
+            )
+            .expect("Failed to get WireGuard relay");
+
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Auto,
+            priority: vec![ObfuscationMethod::Udp2Tcp, ObfuscationMethod::Off],
+            ..ObfuscationSettings::default()
+        };
+
+        for (retry_attempt, should_obfuscate) in [(0, true), (1, true), (2, false), (3, false)] {
+            let obfuscator = relay_selector
+                .get_obfuscator(
+                    &result.exit_relay,
+                    result.endpoint.unwrap_wireguard(),
+                    retry_attempt,
+                )
+                .unwrap();
+            assert_eq!(obfuscator.is_some(), should_obfuscate);
+        }
+        assert_eq!(relay_selector.first_auto_obfuscation_attempt(), Some(0));
+
+        relay_selector.config.lock().obfuscation_settings.priority =
+            vec![ObfuscationMethod::Off, ObfuscationMethod::Udp2Tcp];
+        assert_eq!(relay_selector.first_auto_obfuscation_attempt(), Some(2));
+
+        relay_selector
+            .config
+            .lock()
+            .obfuscation_settings
+            .priority
+            .clear();
+        assert!(relay_selector
+            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 2)
+            .unwrap()
+            .is_none());
+        assert_eq!(relay_selector.first_auto_obfuscation_attempt(), None);
+    }
+
+    #[test]
+    fn test_offline_obfuscator() {
+        let obfuscator = |relay: &Relay| SelectedObfuscator {
+            config: ObfuscatorConfig::Udp2Tcp {
+                endpoint: SocketAddr::new(relay.ipv4_addr_in.into(), 443),
+            },
+            relay: relay.clone(),
+        };
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities[0].relays[0].active = false;
+        let offline_relay = relay_list.countries[0].cities[0].relays[0].clone();
+        let online_relay = relay_list.countries[0].cities[0].relays[1].clone();
+        let mut relay_selector = new_relay_selector_with_relays(relay_list);
+
+        assert!(matches!(
+            relay_selector.check_obfuscator_online(obfuscator(&online_relay)),
+            Ok(Some(_))
+        ));
+
+        let mut config = relay_selector.config.lock().clone();
+        config.obfuscation_settings.selected_obfuscation = SelectedObfuscation::Auto;
+        relay_selector.set_config(config.clone());
+        assert!(matches!(
+            relay_selector.check_obfuscator_online(obfuscator(&offline_relay)),
+            Ok(None)
+        ));
+
+        config.obfuscation_settings.selected_obfuscation = SelectedObfuscation::Udp2Tcp;
+        relay_selector.set_config(config);
+        assert!(matches!(
+            relay_selector.check_obfuscator_online(obfuscator(&offline_relay)),
+            Err(Error::NoObfuscator)
+        ));
+    }
+
+    #[test]
+    fn test_selecting_obfuscator_by_location() {
+        let mut relay_list = RELAYS.clone();
+        let mut malmo_relay = relay_list.countries[0].cities[0].relays[0].clone();
+        malmo_relay.hostname = "se-mma-wg-001".to_string();
+        relay_list.countries[0].cities.push(RelayListCity {
+            name: "Malmö".to_string(),
+            code: "mma".to_string(),
+            latitude: 55.607075,
+            longitude: 13.002716,
+            relays: vec![malmo_relay],
+        });
+
+        let mut relay_selector = new_relay_selector_with_relays(relay_list);
+        let obfuscation_location = LocationConstraint::City("se".to_string(), "mma".to_string());
+
+        for use_multihop in [false, true] {
+            let mut relay_constraints = WIREGUARD_SINGLEHOP_CONSTRAINTS.clone();
+            relay_constraints.wireguard_constraints.use_multihop = use_multihop;
+
+            let config = SelectorConfig {
+                relay_settings: RelaySettings::Normal(relay_constraints),
+                obfuscation_settings: ObfuscationSettings {
+                    selected_obfuscation: SelectedObfuscation::Udp2Tcp,
+                    location: Constraint::Only(obfuscation_location.clone()),
+                    ..Default::default()
+                },
+                ..relay_selector.config.lock().clone()
+            };
+            relay_selector.set_config(config);
+
+            for attempt in 0..10 {
+                let (relay, _bridge, obfuscator) = relay_selector
+                    .get_relay(attempt)
+                    .expect("expected a relay and an obfuscator");
+                let obfuscator = obfuscator.expect("expected an obfuscator");
+                assert_eq!(obfuscator.relay.hostname, "se-mma-wg-001");
+                if let SelectedRelay::Normal(relay) = relay {
+                    let obfuscated_relay = relay.entry_relay.unwrap_or(relay.exit_relay);
+                    assert_eq!(obfuscated_relay.hostname, obfuscator.relay.hostname);
+                }
+            }
+        }
+
+        // The obfuscator location cannot be satisfied if it conflicts with the relay location
+        let mut relay_constraints = WIREGUARD_SINGLEHOP_CONSTRAINTS.clone();
+        relay_constraints.location = Constraint::Only(LocationConstraint::City(
+            "se".to_string(),
+            "got".to_string(),
+        ));
+        let config = SelectorConfig {
+            relay_settings: RelaySettings::Normal(relay_constraints),
+            ..relay_selector.config.lock().clone()
+        };
+        relay_selector.set_config(config);
+        assert!(matches!(
+            relay_selector.get_relay(0),
+            Err(Error::NoObfuscator)
+        ));
+
+        // The obfuscator location is ignored after switching to OpenVPN
+        let mut relay_constraints = WIREGUARD_MULTIHOP_CONSTRAINTS.clone();
+        relay_constraints.tunnel_protocol = Constraint::Only(TunnelType::OpenVpn);
+        relay_constraints.location = Constraint::Only(LocationConstraint::City(
+            "se".to_string(),
+            "got".to_string(),
+        ));
+        let config = SelectorConfig {
+            relay_settings: RelaySettings::Normal(relay_constraints),
+            ..relay_selector.config.lock().clone()
+        };
+        relay_selector.set_config(config);
+        let (relay, _bridge, obfuscator) = relay_selector
+            .get_relay(0)
+            .expect("expected an OpenVPN relay");
+        assert!(obfuscator.is_none());
+        assert!(matches!(
+            relay,
+            SelectedRelay::Normal(NormalSelectedRelay {
+                endpoint: MullvadEndpoint::OpenVpn(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_selected_endpoints_use_correct_port_ranges() {
+        let relay_selector = new_relay_selector();
+
+        const TCP2UDP_PORTS: [u16; 3] = [80, 443, 5001];
+
+        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::Udp2Tcp,
+            ..ObfuscationSettings::default()
+        };
+
+        for attempt in 0..1000 {
+            let result = relay_selector
+                .get_tunnel_endpoint(
+                    &WIREGUARD_SINGLEHOP_CONSTRAINTS,
+                    BridgeState::Off,
+                    attempt,
+                    TunnelType::Wireguard,
+                )
+                .expect("Failed to select a WireGuard relay");
+            assert!(result.entry_relay.is_none());
+
+            let obfs_config = relay_selector
+                .get_obfuscator(
+                    &result.exit_relay,
+                    result.endpoint.unwrap_wireguard(),
+                    attempt,
+                )
+                .unwrap()
+                .expect("Failed to get Tcp2Udp endpoint");
+
+            assert!(matches!(
+                obfs_config,
+                SelectedObfuscator {
+                    config: ObfuscatorConfig::Udp2Tcp { .. },
+                    ..
+                }
+            ));
+
+            let SelectedObfuscator {
+                config: ObfuscatorConfig::Udp2Tcp { endpoint },
+                ..
+            } = obfs_config;
+            assert!(TCP2UDP_PORTS.contains(&endpoint.port()));
+        }
+    }
+
+    #[test]
+    fn test_wireguard_port_ranges() {
+        let relay_selector = new_relay_selector();
+        // The relays accept 53 and 4000-33433, but not 100-200
+        let mut constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            wireguard_constraints: WireguardConstraints {
+                port: Constraint::Only("53,100-200,4000-4001".parse().unwrap()),
+                ..WireguardConstraints::default()
+            },
+            ..RelayConstraints::default()
+        };
+
+        let mut selected_ports = HashSet::new();
+        for attempt in 0..100 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(
+                    &constraints,
+                    BridgeState::Auto,
+                    attempt,
+                    TunnelType::Wireguard,
+                )
+                .unwrap();
+            selected_ports.insert(relay.endpoint.to_endpoint().address.port());
+        }
+        assert_eq!(selected_ports, HashSet::from([53, 4000, 4001]));
+
+        constraints.wireguard_constraints.port =
+            Constraint::Only(PortRanges::new(vec![(100, 200)]).unwrap());
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoRelay)
+        ));
+
+        // Settings with a single port remain valid
+        let constraints: WireguardConstraints =
+            serde_json::from_str(r#"{"port":{"only":51820}}"#).unwrap();
+        assert_eq!(constraints.port, Constraint::Only(PortRanges::from(51820)));
+        assert!(PortRanges::new(vec![(200, 100)]).is_err());
+        assert!("0-10".parse::<PortRanges>().is_err());
+    }
+
+    #[test]
+    fn test_port_fallback() {
+        let mut relay_list = RELAYS.clone();
+        relay_list.wireguard.port_ranges.push((443, 443));
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        let selected_port = |attempt| {
+            relay_selector
+                .get_tunnel_endpoint(
+                    &WIREGUARD_SINGLEHOP_CONSTRAINTS,
+                    BridgeState::Auto,
+                    attempt,
+                    TunnelType::Wireguard,
+                )
+                .unwrap()
+                .endpoint
+                .to_endpoint()
+                .address
+                .port()
+        };
+
+        // Only port 53 is used by default
+        assert_eq!(selected_port(2), 53);
+        assert_eq!(selected_port(7), 53);
+
+        relay_selector.set_port_fallback(PortFallback {
+            port_53: true,
+            port_443: true,
+        });
+        assert_eq!(selected_port(2), 53);
+        assert_eq!(selected_port(3), 53);
+        assert_eq!(selected_port(6), 443);
+        assert_eq!(selected_port(7), 443);
+        assert_eq!(selected_port(10), 53);
+
+        relay_selector.set_port_fallback(PortFallback {
+            port_53: false,
+            port_443: true,
+        });
+        assert_eq!(selected_port(2), 443);
+        assert_eq!(selected_port(6), 443);
+
+        relay_selector.set_port_fallback(PortFallback {
+            port_53: false,
+            port_443: false,
+        });
+        assert_eq!(relay_selector.preferred_wireguard_port(2), Constraint::Any);
+    }
+
+    #[test]
+    fn test_escalation_attempt() {
+        let mut relay_selector = new_relay_selector();
+        let mut config = relay_selector.config.lock().clone();
+        config.relay_settings = RelaySettings::Normal(RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Country("se".to_string())),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        });
+        config.obfuscation_settings.selected_obfuscation = SelectedObfuscation::Auto;
+        relay_selector.set_config(config);
+
+        let (_, _, obfuscator) = relay_selector.get_relay(2).unwrap();
+        assert!(obfuscator.is_some());
+
+        // Escalation is decided by the escalation attempt alone
+        let (_, _, obfuscator) = relay_selector
+            .get_relay_with_escalation(2, 0, None)
+            .unwrap();
+        assert!(obfuscator.is_none());
+        let (_, _, obfuscator) = relay_selector
+            .get_relay_with_escalation(0, 2, None)
+            .unwrap();
+        assert!(obfuscator.is_some());
+    }
+
+    #[test]
+    fn test_constraints_override() {
+        let relay_selector = new_relay_selector();
+
+        let pinned_hostname = "se10-wireguard";
+        let constraints_override = RelayConstraintsUpdate {
+            location: Some(Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                pinned_hostname.to_string(),
+            ))),
+            ..Default::default()
+        };
+
+        for attempt in 0..10 {
+            let (relay, ..) = relay_selector
+                .get_relay_with_override(attempt, constraints_override.clone())
+                .expect("expected match");
+            match relay {
+                SelectedRelay::Normal(relay) => {
+                    assert_eq!(relay.exit_relay.hostname, pinned_hostname)
+                }
+                SelectedRelay::Custom(_) => panic!("expected a normal relay"),
+            }
+        }
+
+        // The override must not leak into the stored configuration
+        match &relay_selector.config.lock().relay_settings {
+            RelaySettings::Normal(constraints) => assert_eq!(
+                constraints.location,
+                Constraint::Only(LocationConstraint::Country("se".to_owned()))
+            ),
+            RelaySettings::CustomTunnelEndpoint(_) => panic!("expected normal relay settings"),
+        }
+    }
+
+    #[test]
+    fn test_filter_report() {
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+
+        let mut inactive = relays[0].clone();
+        inactive.hostname = "se11-wireguard".to_string();
+        inactive.active = false;
+        relays.push(inactive);
+
+        let mut other_provider = relays[0].clone();
+        other_provider.hostname = "se12-wireguard".to_string();
+        other_provider.provider = "1337".to_string();
+        relays.push(other_provider);
+
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+
+        let matcher = RelayMatcher {
+            location: Constraint::Only(LocationConstraint::Country("no".to_string())),
+            providers: Constraint::Only(
+                Providers::new(std::iter::once("31173".to_string()))
+                    .unwrap_or_else(|_| panic!("expected a provider")),
+            ),
+            ownership: Constraint::Only(Ownership::Rented),
+            endpoint_subnet: Constraint::Any,
+            excluded_relays: Vec::new(),
+            avoided_locations: Vec::new(),
+            endpoint_matcher: WireguardMatcher::from_endpoint(RELAYS.wireguard.clone()),
+        };
+        let report = matcher.filter_report(relay_selector.parsed_relays.lock().relays());
+
+        assert_eq!(
+            report,
+            FilterReport {
+                total: 5,
+                matching: 0,
+                inactive: 1,
+                excluded: 0,
+                providers: 1,
+                ownership: 2,
+                endpoint_subnet: 0,
+                location: 1,
+                endpoint: 0,
+            }
+        );
+
+        let matcher = RelayMatcher {
+            location: Constraint::Only(LocationConstraint::Country("se".to_string())),
+            ownership: Constraint::Any,
+            ..matcher
+        };
+        let report = matcher.filter_report(relay_selector.parsed_relays.lock().relays());
+
+        assert_eq!(
+            report,
+            FilterReport {
+                total: 5,
+                matching: 2,
+                inactive: 1,
+                excluded: 0,
+                providers: 1,
+                ownership: 0,
+                endpoint_subnet: 0,
+                location: 0,
+                endpoint: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_excluded_providers() {
+        let mut relay_list = RELAYS.clone();
+        let relays = &mut relay_list.countries[0].cities[0].relays;
+        let mut other_provider = relays[0].clone();
+        other_provider.hostname = "se11-wireguard".to_string();
+        other_provider.provider = "1337".to_string();
+        relays.push(other_provider);
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+
+        let providers = Providers::new_excluded(std::iter::once("31173".to_string()))
+            .unwrap_or_else(|_| panic!("expected a provider"));
+        assert_eq!(providers.to_string(), "any provider except 31173");
+        let constraints = RelayConstraints {
+            providers: Constraint::Only(providers),
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        for i in 0..10 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se11-wireguard");
+        }
+    }
+
+    #[test]
+    fn test_ownership() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints::default();
+        for i in 0..10 {
+            constraints.ownership = Constraint::Only(Ownership::MullvadOwned);
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert!(matches!(
+                relay,
+                NormalSelectedRelay {
+                    exit_relay: Relay { owned: true, .. },
+                    ..
+                }
+            ));
+
+            constraints.ownership = Constraint::Only(Ownership::Rented);
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert!(matches!(
+                relay,
+                NormalSelectedRelay {
+                    exit_relay: Relay { owned: false, .. },
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_endpoint_subnet() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+
+        for i in 0..10 {
+            constraints.endpoint_subnet = Constraint::Only("185.213.154.68/32".parse().unwrap());
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se9-wireguard");
+
+            constraints.endpoint_subnet =
+                Constraint::Only("2a03:1b20:5:f011::a10f/128".parse().unwrap());
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se10-wireguard");
+        }
+
+        constraints.endpoint_subnet = Constraint::Only("10.0.0.0/8".parse().unwrap());
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoRelay)
+        ));
+
+        assert!(serde_json::from_str::<RelayConstraints>(
+            r#"{"endpoint_subnet":{"only":"10.0.0.0/33"}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ip_version_constraint() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::OpenVpn),
+            ..RelayConstraints::default()
+        };
+
+        constraints.openvpn_constraints.ip_version = Constraint::Only(IpVersion::V4);
+        let relay = relay_selector
+            .get_tunnel_endpoint(&constraints, BridgeState::Auto, 0, TunnelType::Wireguard)
+            .unwrap();
+        assert!(relay.endpoint.to_endpoint().address.is_ipv4());
+
+        // se-got-001 has no IPv6 address
+        constraints.openvpn_constraints.ip_version = Constraint::Only(IpVersion::V6);
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoRelay)
+        ));
+
+        let mut relay_list = RELAYS.clone();
+        relay_list.countries[0].cities[0].relays[2].ipv6_addr_in =
+            Some("2a03:1b20:5:f011::a11f".parse().unwrap());
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        let relay = relay_selector
+            .get_tunnel_endpoint(&constraints, BridgeState::Auto, 0, TunnelType::Wireguard)
+            .unwrap();
+        assert!(relay.endpoint.to_endpoint().address.is_ipv6());
+
+        let constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            wireguard_constraints: WireguardConstraints {
+                ip_version: Constraint::Only(IpVersion::V6),
+                ..WireguardConstraints::default()
+            },
+            ..RelayConstraints::default()
+        };
+        for i in 0..10 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert!(relay.endpoint.to_endpoint().address.is_ipv6());
+        }
+    }
+
+    #[test]
+    fn test_prefer_ipv6() {
+        let relay_selector = new_relay_selector();
+        relay_selector.set_prefer_ipv6(true);
+        let wireguard_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+        for i in 0..10 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(
+                    &wireguard_constraints,
+                    BridgeState::Auto,
+                    i,
+                    TunnelType::Wireguard,
+                )
+                .unwrap();
+            assert!(relay.endpoint.to_endpoint().address.is_ipv6());
+        }
+
+        // An explicit IP version takes precedence
+        let mut constraints = wireguard_constraints.clone();
+        constraints.wireguard_constraints.ip_version = Constraint::Only(IpVersion::V4);
+        let relay = relay_selector
+            .get_tunnel_endpoint(&constraints, BridgeState::Auto, 0, TunnelType::Wireguard)
+            .unwrap();
+        assert!(relay.endpoint.to_endpoint().address.is_ipv4());
+
+        // se-got-001 has no IPv6 address, so IPv4 is used instead
+        let openvpn_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::OpenVpn),
+            ..RelayConstraints::default()
+        };
+        let relay = relay_selector
+            .get_tunnel_endpoint(
+                &openvpn_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard,
+            )
+            .unwrap();
+        assert!(relay.endpoint.to_endpoint().address.is_ipv4());
+
+        relay_selector.set_ipv6_available(false);
+        let relay = relay_selector
+            .get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard,
+            )
+            .unwrap();
+        assert!(relay.endpoint.to_endpoint().address.is_ipv4());
+    }
+
+    #[test]
+    fn test_custom_list_location() {
+        let mut relay_selector = new_relay_selector();
+        let custom_list = CustomList {
+            name: "favorites".to_string(),
+            locations: vec![
+                LocationConstraint::Hostname(
+                    "se".to_string(),
+                    "got".to_string(),
+                    "se9-wireguard".to_string(),
+                ),
+                LocationConstraint::Hostname(
+                    "se".to_string(),
+                    "got".to_string(),
+                    "se-got-001".to_string(),
+                ),
+            ],
+        };
+        relay_selector.set_config(SelectorConfig {
+            relay_settings: RelaySettings::Normal(RelayConstraints {
+                location: Constraint::Only(LocationConstraint::CustomList("favorites".to_string())),
+                tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+                ..RelayConstraints::default()
+            }),
+            custom_lists: CustomListsSettings {
+                custom_lists: vec![custom_list],
+            },
+            ..relay_selector.config()
+        });
+
+        // The OpenVPN relay in the list is skipped since it does not match the tunnel protocol
+        for i in 0..10 {
+            let (relay, ..) = relay_selector.get_relay(i).unwrap();
+            match relay {
+                SelectedRelay::Normal(relay) => {
+                    assert_eq!(relay.exit_relay.hostname, "se9-wireguard")
+                }
+                SelectedRelay::Custom(_) => unreachable!("Expected a normal relay"),
+            }
+        }
+        assert!(relay_selector.has_matching_relay(None));
+
+        let unknown_list = RelayConstraintsUpdate {
+            location: Some(Constraint::Only(LocationConstraint::CustomList(
+                "unknown".to_string(),
+            ))),
+            ..RelayConstraintsUpdate::default()
+        };
+        assert!(!relay_selector.has_matching_relay(Some(unknown_list.clone())));
+        assert!(matches!(
+            relay_selector.get_relay_with_override(0, unknown_list),
+            Err(Error::NoRelay)
+        ));
+    }
+
+    #[test]
+    fn test_excluded_relays_constraint() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            excluded_relays: vec!["se9-wireguard".to_string()],
+            ..RelayConstraints::default()
+        };
+
+        for i in 0..10 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se10-wireguard");
+        }
+
+        constraints
+            .excluded_relays
+            .push("se10-wireguard".to_string());
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoRelay)
+        ));
+    }
+
+    #[test]
+    fn test_avoided_locations_constraint() {
+        let relay_selector = new_relay_selector();
+        let mut constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            avoided_locations: vec![LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se9-wireguard".to_string(),
+            )],
+            ..RelayConstraints::default()
+        };
+
+        for i in 0..10 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se10-wireguard");
+        }
+
+        constraints.avoided_locations = vec![LocationConstraint::Country("se".to_string())];
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoRelay)
+        ));
     }
 
     #[test]
-    fn test_selecting_wg_endpoint_with_udp2tcp_obfuscation() {
+    fn test_allowed_relays() {
         let relay_selector = new_relay_selector();
+        let constraints = RelayConstraints::default();
 
-        let result = relay_selector.get_tunnel_endpoint(&WIREGUARD_SINGLEHOP_CONSTRAINTS, BridgeState::Off, 0, TunnelType::Wireguard)
-            .expect("Failed to get relay when tunnel constraints are set to default WireGuard constraints");
-
-        assert!(result.entry_relay.is_none());
-        assert!(matches!(result.endpoint, MullvadEndpoint::Wireguard { .. }));
+        relay_selector.set_allowed_relays(Some(HashSet::from(["se10-wireguard".to_string()])));
+        for i in 0..10 {
+            let relay = relay_selector
+                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se10-wireguard");
+        }
 
-        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
-            selected_obfuscation: SelectedObfuscation::Udp2Tcp,
-            ..ObfuscationSettings::default()
+        relay_selector.set_allowed_relays(Some(HashSet::from(["se-got-001".to_string()])));
+        let wireguard_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
         };
-
-        let obfs_config = relay_selector
-            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 0)
-            .unwrap()
-            .unwrap();
-
         assert!(matches!(
-            obfs_config,
-            SelectedObfuscator {
-                config: ObfuscatorConfig::Udp2Tcp { .. },
-                ..
-            }
+            relay_selector.get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoAllowedRelay)
         ));
+
+        relay_selector.set_allowed_relays(None);
+        assert!(relay_selector
+            .get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            )
+            .is_ok());
     }
 
     #[test]
-    fn test_selecting_wg_endpoint_with_auto_obfuscation() {
+    fn test_excluded_relays() {
         let relay_selector = new_relay_selector();
-
-        let result = relay_selector.get_tunnel_endpoint(&WIREGUARD_SINGLEHOP_CONSTRAINTS, BridgeState::Off, 0, TunnelType::Wireguard)
-            .expect("Failed to get relay when tunnel constraints are set to default WireGuard constraints");
-
-        assert!(result.entry_relay.is_none());
-        assert!(matches!(result.endpoint, MullvadEndpoint::Wireguard { .. }));
-
-        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
-            selected_obfuscation: SelectedObfuscation::Auto,
-            ..ObfuscationSettings::default()
+        let constraints = RelayConstraints::default();
+        let wireguard_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
         };
 
-        assert!(relay_selector
-            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 0,)
-            .unwrap()
-            .is_none());
+        relay_selector.set_excluded_relays(HashSet::from(["se9-wireguard".to_string()]));
+        let mut selected = HashSet::new();
+        for i in 0..10 {
+            for constraints in [&constraints, &wireguard_constraints] {
+                let relay = relay_selector
+                    .get_tunnel_endpoint(constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                    .unwrap();
+                selected.insert(relay.exit_relay.hostname);
+            }
+        }
+        assert!(!selected.contains("se9-wireguard"));
+        assert!(selected.contains("se10-wireguard"));
+        assert!(selected.contains("se-got-001"));
 
-        assert!(relay_selector
-            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 1,)
-            .unwrap()
-            .is_none());
+        relay_selector.set_excluded_relays(HashSet::from([
+            "se9-wireguard".to_string(),
+            "se10-wireguard".to_string(),
+        ]));
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::AllRelaysExcluded)
+        ));
 
+        relay_selector.set_excluded_relays(HashSet::new());
         assert!(relay_selector
-            .get_obfuscator(&result.exit_relay, result.endpoint.unwrap_wireguard(), 2,)
-            .unwrap()
-            .is_some());
+            .get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            )
+            .is_ok());
     }
 
     #[test]
-    fn test_selected_endpoints_use_correct_port_ranges() {
-        let relay_selector = new_relay_selector();
+    fn test_quantum_resistant_relays() {
+        let mut relay_list = RELAYS.clone();
+        for relay in relay_list.countries[0].cities[0].relays.iter_mut() {
+            if let RelayEndpointData::Wireguard(data) = &mut relay.endpoint_data {
+                data.quantum_resistant = relay.hostname == "se10-wireguard";
+            }
+        }
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        let constraints = RelayConstraints::default();
+        let wireguard_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
 
-        const TCP2UDP_PORTS: [u16; 3] = [80, 443, 5001];
+        relay_selector.set_quantum_resistant(true);
+        let mut selected = HashSet::new();
+        for i in 0..10 {
+            for constraints in [&constraints, &wireguard_constraints] {
+                let relay = relay_selector
+                    .get_tunnel_endpoint(constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                    .unwrap();
+                selected.insert(relay.exit_relay.hostname);
+            }
+        }
+        assert!(!selected.contains("se9-wireguard"));
+        assert!(selected.contains("se10-wireguard"));
+        // OpenVPN relays are not affected
+        assert!(selected.contains("se-got-001"));
+
+        let se9_constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se9-wireguard".to_string(),
+            )),
+            ..wireguard_constraints.clone()
+        };
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &se9_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoQuantumResistantRelay)
+        ));
 
-        relay_selector.config.lock().obfuscation_settings = ObfuscationSettings {
-            selected_obfuscation: SelectedObfuscation::Udp2Tcp,
-            ..ObfuscationSettings::default()
+        relay_selector.set_quantum_resistant(false);
+        assert!(relay_selector
+            .get_tunnel_endpoint(
+                &se9_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_daita_relays() {
+        let mut relay_list = RELAYS.clone();
+        for relay in relay_list.countries[0].cities[0].relays.iter_mut() {
+            if let RelayEndpointData::Wireguard(data) = &mut relay.endpoint_data {
+                data.daita = relay.hostname == "se10-wireguard";
+            }
+        }
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        let wireguard_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
         };
 
-        for attempt in 0..1000 {
-            let result = relay_selector
+        relay_selector.set_daita(true);
+        for i in 0..10 {
+            let relay = relay_selector
                 .get_tunnel_endpoint(
-                    &WIREGUARD_SINGLEHOP_CONSTRAINTS,
-                    BridgeState::Off,
-                    attempt,
+                    &wireguard_constraints,
+                    BridgeState::Auto,
+                    i,
                     TunnelType::Wireguard,
                 )
-                .expect("Failed to select a WireGuard relay");
-            assert!(result.entry_relay.is_none());
+                .unwrap();
+            assert_eq!(relay.exit_relay.hostname, "se10-wireguard");
+        }
 
-            let obfs_config = relay_selector
-                .get_obfuscator(
-                    &result.exit_relay,
-                    result.endpoint.unwrap_wireguard(),
-                    attempt,
-                )
-                .unwrap()
-                .expect("Failed to get Tcp2Udp endpoint");
+        let se9_constraints = RelayConstraints {
+            location: Constraint::Only(LocationConstraint::Hostname(
+                "se".to_string(),
+                "got".to_string(),
+                "se9-wireguard".to_string(),
+            )),
+            ..wireguard_constraints
+        };
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &se9_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::NoDaitaRelay)
+        ));
 
-            assert!(matches!(
-                obfs_config,
-                SelectedObfuscator {
-                    config: ObfuscatorConfig::Udp2Tcp { .. },
-                    ..
-                }
-            ));
+        relay_selector.set_daita(false);
+        assert!(relay_selector
+            .get_tunnel_endpoint(
+                &se9_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            )
+            .is_ok());
+    }
 
-            let SelectedObfuscator {
-                config: ObfuscatorConfig::Udp2Tcp { endpoint },
-                ..
-            } = obfs_config;
-            assert!(TCP2UDP_PORTS.contains(&endpoint.port()));
+    #[test]
+    fn test_openvpn_multihop() {
+        let mut relay_list = RELAYS.clone();
+        let mut entry = relay_list.countries[0].cities[0].relays[2].clone();
+        entry.hostname = "se-got-002".to_string();
+        entry.ipv4_addr_in = "185.213.154.132".parse().unwrap();
+        relay_list.countries[0].cities[0].relays.push(entry);
+        relay_list.bridge.shadowsocks = vec![ShadowsocksEndpointData {
+            port: 443,
+            cipher: "aes-256-gcm".to_string(),
+            password: "mullvad".to_string(),
+            protocol: TransportProtocol::Tcp,
+        }];
+        let relay_selector = new_relay_selector_with_relays(relay_list);
+        let config = relay_selector.config();
+        let exit_location = LocationConstraint::Hostname(
+            "se".to_string(),
+            "got".to_string(),
+            "se-got-001".to_string(),
+        );
+        let constraints = RelayConstraints {
+            location: Constraint::Only(exit_location.clone()),
+            tunnel_protocol: Constraint::Only(TunnelType::OpenVpn),
+            openvpn_constraints: OpenVpnConstraints {
+                use_multihop: true,
+                ..OpenVpnConstraints::default()
+            },
+            ..RelayConstraints::default()
+        };
+
+        for i in 0..10 {
+            let (relay, bridge, _) = relay_selector
+                .get_escalated_relay(&config, constraints.clone(), i, i)
+                .unwrap();
+            let relay = match relay {
+                SelectedRelay::Normal(relay) => relay,
+                SelectedRelay::Custom(_) => unreachable!(),
+            };
+            // The proxy is only reachable over TCP
+            assert_eq!(
+                relay.endpoint.to_endpoint().protocol,
+                TransportProtocol::Tcp
+            );
+            // The entry relay is never the exit relay, and replaces the bridge
+            assert_eq!(relay.exit_relay.hostname, "se-got-001");
+            assert_eq!(relay.entry_relay.unwrap().hostname, "se-got-002");
+            match bridge {
+                Some(SelectedBridge::Normal(bridge)) => {
+                    assert_eq!(bridge.relay.hostname, "se-got-002");
+                    assert_eq!(
+                        bridge.settings.get_endpoint().endpoint.address.ip(),
+                        "185.213.154.132".parse::<IpAddr>().unwrap()
+                    );
+                }
+                _ => panic!("Expected a proxy on the entry relay"),
+            }
         }
+
+        // There is no other relay to enter through
+        let constraints = RelayConstraints {
+            openvpn_constraints: OpenVpnConstraints {
+                use_multihop: true,
+                entry_location: Constraint::Only(exit_location),
+                ..OpenVpnConstraints::default()
+            },
+            ..constraints
+        };
+        assert!(matches!(
+            relay_selector.get_escalated_relay(&config, constraints, 0, 0),
+            Err(Error::NoMultihopEntry)
+        ));
     }
 
     #[test]
-    fn test_ownership() {
+    fn test_relay_filters() {
         let relay_selector = new_relay_selector();
-        let mut constraints = RelayConstraints::default();
+        let wireguard_constraints = RelayConstraints {
+            tunnel_protocol: Constraint::Only(TunnelType::Wireguard),
+            ..RelayConstraints::default()
+        };
+
+        // A scorer that gives a relay a weight of 0 prevents it from being picked
+        relay_selector.add_scorer(|relay: &Relay| {
+            if relay.hostname == "se9-wireguard" {
+                0.0
+            } else {
+                1.0
+            }
+        });
         for i in 0..10 {
-            constraints.ownership = Constraint::Only(Ownership::MullvadOwned);
             let relay = relay_selector
-                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .get_tunnel_endpoint(
+                    &wireguard_constraints,
+                    BridgeState::Auto,
+                    i,
+                    TunnelType::Wireguard,
+                )
                 .unwrap();
-            assert!(matches!(
-                relay,
-                NormalSelectedRelay {
-                    exit_relay: Relay { owned: true, .. },
-                    ..
-                }
-            ));
+            assert_eq!(relay.exit_relay.hostname, "se10-wireguard");
+        }
 
-            constraints.ownership = Constraint::Only(Ownership::Rented);
+        relay_selector.add_filter(|relay: &Relay| relay.hostname != "se10-wireguard");
+        for i in 0..10 {
             let relay = relay_selector
-                .get_tunnel_endpoint(&constraints, BridgeState::Auto, i, TunnelType::Wireguard)
+                .get_tunnel_endpoint(
+                    &wireguard_constraints,
+                    BridgeState::Auto,
+                    i,
+                    TunnelType::Wireguard,
+                )
                 .unwrap();
-            assert!(matches!(
-                relay,
-                NormalSelectedRelay {
-                    exit_relay: Relay { owned: false, .. },
-                    ..
-                }
-            ));
+            assert_eq!(relay.exit_relay.hostname, "se9-wireguard");
         }
+
+        relay_selector.add_filter(|relay: &Relay| relay.hostname != "se9-wireguard");
+        assert!(matches!(
+            relay_selector.get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            ),
+            Err(Error::RejectedByFilter)
+        ));
+
+        relay_selector.clear_filters();
+        assert!(relay_selector
+            .get_tunnel_endpoint(
+                &wireguard_constraints,
+                BridgeState::Auto,
+                0,
+                TunnelType::Wireguard
+            )
+            .is_ok());
     }
 
     /// Ensure that `include_in_country` is ignored if all relays have it set to false (i.e., some
@@ -1914,12 +4844,16 @@ mod test {
                             owned: true,
                             provider: "31173".to_string(),
                             weight: 1,
+                            load: None,
                             endpoint_data: RelayEndpointData::Wireguard(
                                 WireguardRelayEndpointData {
                                     public_key: PublicKey::from_base64(
                                         "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
                                     )
                                     .unwrap(),
+                                    multihop_entry: true,
+                                    pluggable_transports: vec![],
+                                    quantum_resistant: true,
                                 },
                             ),
                             location: None,
@@ -1933,12 +4867,16 @@ mod test {
                             owned: false,
                             provider: "31173".to_string(),
                             weight: 1,
+                            load: None,
                             endpoint_data: RelayEndpointData::Wireguard(
                                 WireguardRelayEndpointData {
                                     public_key: PublicKey::from_base64(
                                         "BLNHNoGO88LjV/wDBa7CUUwUzPq/fO2UwcGLy56hKy4=",
                                     )
                                     .unwrap(),
+                                    multihop_entry: true,
+                                    pluggable_transports: vec![],
+                                    quantum_resistant: true,
                                 },
                             ),
                             location: None,
@@ -1971,6 +4909,7 @@ mod test {
                 ipv6_gateway: "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
                 udp2tcp_ports: vec![],
             },
+            source: RelayListSource::Api,
         };
 
         // If include_in_country is false for all relays, a relay must be selected anyway.
@@ -1999,4 +4938,81 @@ mod test {
             }) if hostname == expected_relay.hostname
         ))
     }
+
+    #[test]
+    fn test_escalation_ladder() {
+        let mut relay_selector = new_relay_selector();
+        let wireguard_step = EscalationStep {
+            from_attempt: 0,
+            tunnel_type: TunnelType::Wireguard,
+            port: Constraint::Any,
+            transport_protocol: TransportProtocol::Udp,
+            udp2tcp: false,
+            bridge: false,
+        };
+        relay_selector.set_config(SelectorConfig {
+            obfuscation_settings: ObfuscationSettings {
+                selected_obfuscation: SelectedObfuscation::Auto,
+                ..Default::default()
+            },
+            escalation_ladder: EscalationLadder {
+                steps: vec![
+                    wireguard_step,
+                    EscalationStep {
+                        from_attempt: 3,
+                        port: Constraint::Only(443),
+                        udp2tcp: true,
+                        ..wireguard_step
+                    },
+                ],
+            },
+            ..relay_selector.config()
+        });
+        assert_eq!(relay_selector.first_auto_obfuscation_attempt(), Some(3));
+
+        // The built-in strategy would fall back on OpenVPN on the third attempt
+        for attempt in 0..3 {
+            let (relay, _, obfuscator) = relay_selector.get_relay(attempt).unwrap();
+            match relay {
+                SelectedRelay::Normal(relay) => {
+                    assert!(matches!(relay.endpoint, MullvadEndpoint::Wireguard(_)))
+                }
+                SelectedRelay::Custom(_) => unreachable!("Expected a normal relay"),
+            }
+            assert!(obfuscator.is_none());
+        }
+
+        for attempt in 3..10 {
+            let (_, _, obfuscator) = relay_selector.get_relay(attempt).unwrap();
+            match obfuscator.expect("expected udp2tcp obfuscation").config {
+                ObfuscatorConfig::Udp2Tcp { endpoint } => assert_eq!(endpoint.port(), 443),
+                _ => unreachable!("Expected udp2tcp obfuscation"),
+            }
+        }
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn test_simulation_is_reproducible() {
+        let config = new_relay_selector().config();
+        let attempts = simulation::simulate(config.clone(), RELAYS.clone(), 1234, 20);
+        assert_eq!(attempts.len(), 20);
+        assert_eq!(
+            attempts,
+            simulation::simulate(config.clone(), RELAYS.clone(), 1234, 20)
+        );
+        for (retry_attempt, attempt) in attempts.iter().enumerate() {
+            assert_eq!(attempt.retry_attempt, retry_attempt as u32);
+            let selection = attempt.result.as_ref().expect("expected a relay");
+            assert!(selection.endpoint.is_some());
+            assert!(selection.bridge.is_none());
+        }
+
+        // The WireGuard ports are picked from thousands of ports, so a different seed is
+        // practically guaranteed to select differently
+        assert_ne!(
+            attempts,
+            simulation::simulate(config, RELAYS.clone(), 4321, 20)
+        );
+    }
 }
@@ -1,8 +1,10 @@
+use ipnetwork::IpNetwork;
 use mullvad_types::{
     endpoint::{MullvadEndpoint, MullvadWireguardEndpoint},
+    location::Hostname,
     relay_constraints::{
-        Constraint, LocationConstraint, Match, OpenVpnConstraints, Ownership, Providers,
-        RelayConstraints, WireguardConstraints,
+        Constraint, EntryRelayPolicy, LocationConstraint, Match, OpenVpnConstraints, Ownership,
+        PortRanges, Providers, RelayConstraints, WireguardConstraints,
     },
     relay_list::{
         OpenVpnEndpoint, OpenVpnEndpointData, Relay, RelayEndpointData, WireguardEndpointData,
@@ -12,14 +14,24 @@ use rand::{
     seq::{IteratorRandom, SliceRandom},
     Rng,
 };
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
 use talpid_types::net::{all_of_the_internet, wireguard, Endpoint, IpVersion, TunnelType};
 
+use crate::rng;
+
 #[derive(Clone)]
 pub struct RelayMatcher<T: EndpointMatcher> {
     pub location: Constraint<LocationConstraint>,
     pub providers: Constraint<Providers>,
     pub ownership: Constraint<Ownership>,
+    pub endpoint_subnet: Constraint<IpNetwork>,
+    /// Hostnames of relays that never match.
+    pub excluded_relays: Vec<Hostname>,
+    /// Locations that no relay matches in.
+    pub avoided_locations: Vec<LocationConstraint>,
     pub endpoint_matcher: T,
 }
 
@@ -33,6 +45,9 @@ impl RelayMatcher<AnyTunnelMatcher> {
             location: constraints.location,
             providers: constraints.providers,
             ownership: constraints.ownership,
+            endpoint_subnet: constraints.endpoint_subnet,
+            excluded_relays: constraints.excluded_relays,
+            avoided_locations: constraints.avoided_locations,
             endpoint_matcher: AnyTunnelMatcher {
                 wireguard: WireguardMatcher::new(constraints.wireguard_constraints, wireguard_data),
                 openvpn: OpenVpnMatcher::new(constraints.openvpn_constraints, openvpn_data),
@@ -47,6 +62,9 @@ impl RelayMatcher<AnyTunnelMatcher> {
             location: self.location,
             providers: self.providers,
             ownership: self.ownership,
+            endpoint_subnet: self.endpoint_subnet,
+            excluded_relays: self.excluded_relays,
+            avoided_locations: self.avoided_locations,
         }
     }
 }
@@ -73,15 +91,56 @@ impl<T: EndpointMatcher> RelayMatcher<T> {
             .collect()
     }
 
+    /// Counts how many relays are eliminated by each constraint. This is more expensive than
+    /// [`Self::filter_matching_relay_list`] and is only meant for diagnostics.
+    pub fn filter_report(&self, relays: &[Relay]) -> FilterReport {
+        let mut report = FilterReport {
+            total: relays.len(),
+            ..Default::default()
+        };
+        for relay in relays {
+            if !relay.active {
+                report.inactive += 1;
+            } else if self.excluded_relays.contains(&relay.hostname) {
+                report.excluded += 1;
+            } else if !self.providers.matches(relay) {
+                report.providers += 1;
+            } else if !self.ownership.matches(relay) {
+                report.ownership += 1;
+            } else if !self.endpoint_subnet.matches(relay) {
+                report.endpoint_subnet += 1;
+            } else if !self.location.matches_with_opts(relay, true) || self.is_avoided(relay) {
+                report.location += 1;
+            } else if !self.endpoint_matcher.is_matching_relay(relay) {
+                report.endpoint += 1;
+            }
+        }
+        report.matching = self.filter_matching_relay_list(relays).len();
+        // Relays that are excluded by the `include_in_country` pass are counted as location
+        // eliminations.
+        report.location += report.total - report.eliminated() - report.matching;
+        report
+    }
+
     /// Filter a relay based on constraints and endpoint type, 1st pass.
     fn pre_filter_matching_relay(&self, relay: &Relay) -> bool {
         relay.active
+            && !self.excluded_relays.contains(&relay.hostname)
             && self.providers.matches(relay)
             && self.ownership.matches(relay)
+            && self.endpoint_subnet.matches(relay)
             && self.location.matches_with_opts(relay, true)
+            && !self.is_avoided(relay)
             && self.endpoint_matcher.is_matching_relay(relay)
     }
 
+    /// Returns whether `relay` is in any of the avoided locations.
+    fn is_avoided(&self, relay: &Relay) -> bool {
+        self.avoided_locations
+            .iter()
+            .any(|location| location.matches_with_opts(relay, true))
+    }
+
     /// Filter a relay based on constraints and endpoint type, 2nd pass.
     fn post_filter_matching_relay(&self, relay: &Relay, ignore_include_in_country: bool) -> bool {
         self.location
@@ -93,6 +152,51 @@ impl<T: EndpointMatcher> RelayMatcher<T> {
     }
 }
 
+/// Breakdown of how many relays were eliminated by each stage of relay filtering.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilterReport {
+    pub total: usize,
+    pub matching: usize,
+    pub inactive: usize,
+    pub excluded: usize,
+    pub providers: usize,
+    pub ownership: usize,
+    pub endpoint_subnet: usize,
+    pub location: usize,
+    pub endpoint: usize,
+}
+
+impl FilterReport {
+    fn eliminated(&self) -> usize {
+        self.inactive
+            + self.excluded
+            + self.providers
+            + self.ownership
+            + self.endpoint_subnet
+            + self.location
+            + self.endpoint
+    }
+}
+
+impl fmt::Display for FilterReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} relays matched ({} inactive, {} excluded, {} by provider, {} by ownership, \
+             {} by subnet, {} by location, {} by tunnel protocol or endpoint)",
+            self.matching,
+            self.total,
+            self.inactive,
+            self.excluded,
+            self.providers,
+            self.ownership,
+            self.endpoint_subnet,
+            self.location,
+            self.endpoint,
+        )
+    }
+}
+
 /// EndpointMatcher allows to abstract over different tunnel-specific or bridge constraints.
 /// This enables one to not have false dependencies on OpenVpn specific constraints when
 /// selecting only WireGuard tunnels.
@@ -104,9 +208,20 @@ pub trait EndpointMatcher: Clone {
     fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint>;
 }
 
+/// Returns the entry address of `relay` with the given IP version, or `None` if the relay has no
+/// such address. The IPv4 address is used if any IP version may be used.
+pub fn entry_address(relay: &Relay, ip_version: Constraint<IpVersion>) -> Option<IpAddr> {
+    match ip_version {
+        Constraint::Any | Constraint::Only(IpVersion::V4) => Some(relay.ipv4_addr_in.into()),
+        Constraint::Only(IpVersion::V6) => relay.ipv6_addr_in.map(|addr| addr.into()),
+    }
+}
+
 impl EndpointMatcher for OpenVpnMatcher {
     fn is_matching_relay(&self, relay: &Relay) -> bool {
-        self.matches(&self.data) && matches!(relay.endpoint_data, RelayEndpointData::Openvpn)
+        self.matches(&self.data)
+            && matches!(relay.endpoint_data, RelayEndpointData::Openvpn)
+            && entry_address(relay, self.constraints.ip_version).is_some()
     }
 
     fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
@@ -114,12 +229,9 @@ impl EndpointMatcher for OpenVpnMatcher {
             return None;
         }
 
+        let address = entry_address(relay, self.constraints.ip_version)?;
         self.get_transport_port().map(|endpoint| {
-            MullvadEndpoint::OpenVpn(Endpoint::new(
-                relay.ipv4_addr_in,
-                endpoint.port,
-                endpoint.protocol,
-            ))
+            MullvadEndpoint::OpenVpn(Endpoint::new(address, endpoint.port, endpoint.protocol))
         })
     }
 }
@@ -137,19 +249,17 @@ impl OpenVpnMatcher {
 
     fn get_transport_port(&self) -> Option<&OpenVpnEndpoint> {
         match self.constraints.port {
-            Constraint::Any => self.data.ports.choose(&mut rand::thread_rng()),
-            Constraint::Only(transport_port) => self
-                .data
-                .ports
-                .iter()
-                .filter(|endpoint| {
+            Constraint::Any => rng::with_rng(|rng| self.data.ports.choose(rng)),
+            Constraint::Only(transport_port) => {
+                let endpoints = self.data.ports.iter().filter(|endpoint| {
                     transport_port
                         .port
                         .map(|port| port == endpoint.port)
                         .unwrap_or(true)
                         && transport_port.protocol == endpoint.protocol
-                })
-                .choose(&mut rand::thread_rng()),
+                });
+                rng::with_rng(|rng| endpoints.choose(rng))
+            }
         }
     }
 }
@@ -210,8 +320,10 @@ pub struct WireguardMatcher {
     /// The peer is an already selected peer relay to be used with multihop.
     /// It's stored here so we can exclude it from further selections being made.
     pub peer: Option<Relay>,
-    pub port: Constraint<u16>,
+    pub port: Constraint<PortRanges>,
     pub ip_version: Constraint<IpVersion>,
+    /// How the entry relay is picked among the matching relays, when this matches entry relays.
+    pub entry_policy: EntryRelayPolicy,
 
     pub data: WireguardEndpointData,
 }
@@ -222,6 +334,7 @@ impl WireguardMatcher {
             peer: None,
             port: constraints.port,
             ip_version: constraints.ip_version,
+            entry_policy: constraints.entry_policy,
             data,
         }
     }
@@ -238,7 +351,7 @@ impl WireguardMatcher {
         relay: &Relay,
         data: &WireguardEndpointData,
     ) -> Option<MullvadEndpoint> {
-        let host = self.get_address_for_wireguard_relay(relay)?;
+        let host = entry_address(relay, self.ip_version)?;
         let port = self.get_port_for_wireguard_relay(data)?;
         let peer_config = wireguard::PeerConfig {
             public_key: relay
@@ -258,48 +371,31 @@ impl WireguardMatcher {
         }))
     }
 
-    fn get_address_for_wireguard_relay(&self, relay: &Relay) -> Option<IpAddr> {
-        match self.ip_version {
-            Constraint::Any | Constraint::Only(IpVersion::V4) => Some(relay.ipv4_addr_in.into()),
-            Constraint::Only(IpVersion::V6) => relay.ipv6_addr_in.map(|addr| addr.into()),
+    /// Returns a random port that is allowed by the port constraint and accepted by the relays.
+    fn get_port_for_wireguard_relay(&self, data: &WireguardEndpointData) -> Option<u16> {
+        let port_ranges = match &self.port {
+            Constraint::Any => data.port_ranges.clone(),
+            Constraint::Only(ports) => ports.intersect(&data.port_ranges),
+        };
+        let get_port_amount =
+            |range: &(u16, u16)| -> u64 { u64::from(range.1) - u64::from(range.0) + 1 };
+        let port_amount: u64 = port_ranges.iter().map(get_port_amount).sum();
+
+        if port_amount < 1 {
+            return None;
         }
-    }
 
-    fn get_port_for_wireguard_relay(&self, data: &WireguardEndpointData) -> Option<u16> {
-        match self.port {
-            Constraint::Any => {
-                let get_port_amount =
-                    |range: &(u16, u16)| -> u64 { (1 + range.1 - range.0) as u64 };
-                let port_amount: u64 = data.port_ranges.iter().map(get_port_amount).sum();
-
-                if port_amount < 1 {
-                    return None;
-                }
-
-                let mut port_index = rand::thread_rng().gen_range(0..port_amount);
-
-                for range in data.port_ranges.iter() {
-                    let ports_in_range = get_port_amount(range);
-                    if port_index < ports_in_range {
-                        return Some(port_index as u16 + range.0);
-                    }
-                    port_index -= ports_in_range;
-                }
-                log::error!("Port selection algorithm is broken!");
-                None
-            }
-            Constraint::Only(port) => {
-                if data
-                    .port_ranges
-                    .iter()
-                    .any(|range| (range.0 <= port && port <= range.1))
-                {
-                    Some(port)
-                } else {
-                    None
-                }
+        let mut port_index = rng::with_rng(|rng| rng.gen_range(0..port_amount));
+
+        for range in port_ranges.iter() {
+            let ports_in_range = get_port_amount(range);
+            if port_index < ports_in_range {
+                return Some(port_index as u16 + range.0);
             }
+            port_index -= ports_in_range;
         }
+        log::error!("Port selection algorithm is broken!");
+        None
     }
 }
 
@@ -311,6 +407,7 @@ impl EndpointMatcher for WireguardMatcher {
             .map(|peer_relay| peer_relay.hostname == relay.hostname)
             .unwrap_or(false)
             && matches!(relay.endpoint_data, RelayEndpointData::Wireguard(..))
+            && entry_address(relay, self.ip_version).is_some()
     }
 
     fn mullvad_endpoint(&self, relay: &Relay) -> Option<MullvadEndpoint> {
@@ -321,12 +418,14 @@ impl EndpointMatcher for WireguardMatcher {
     }
 }
 
+/// Matches bridges that have an entry address with the given IP version.
 #[derive(Clone)]
-pub struct BridgeMatcher(pub ());
+pub struct BridgeMatcher(pub Constraint<IpVersion>);
 
 impl EndpointMatcher for BridgeMatcher {
     fn is_matching_relay(&self, relay: &Relay) -> bool {
         matches!(relay.endpoint_data, RelayEndpointData::Bridge)
+            && entry_address(relay, self.0).is_some()
     }
 
     fn mullvad_endpoint(&self, _relay: &Relay) -> Option<MullvadEndpoint> {
@@ -0,0 +1,51 @@
+//! Source of randomness for relay selection. With the `simulation` feature, a seeded generator can
+//! be installed for the current thread so that selection becomes reproducible.
+
+use rand::RngCore;
+
+#[cfg(feature = "simulation")]
+use rand::{rngs::StdRng, SeedableRng};
+#[cfg(feature = "simulation")]
+use std::cell::RefCell;
+
+#[cfg(feature = "simulation")]
+thread_local! {
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+/// Calls `f` with the random number generator to use for relay selection. `f` must not call this
+/// function itself.
+#[cfg(not(feature = "simulation"))]
+pub(crate) fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    f(&mut rand::thread_rng())
+}
+
+/// Calls `f` with the random number generator to use for relay selection. This is the generator
+/// installed by [with_seed] if called from within it, and the thread-local generator otherwise.
+/// `f` must not call this function itself.
+#[cfg(feature = "simulation")]
+pub(crate) fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    SEEDED_RNG.with(|seeded_rng| match seeded_rng.borrow_mut().as_mut() {
+        Some(rng) => f(rng),
+        None => f(&mut rand::thread_rng()),
+    })
+}
+
+/// Runs `f` with a random number generator seeded with `seed` used for all relay selection on
+/// the current thread. The previous generator is restored afterwards, even if `f` panics.
+#[cfg(feature = "simulation")]
+pub(crate) fn with_seed<T>(seed: u64, f: impl FnOnce() -> T) -> T {
+    struct RestoreRng(Option<StdRng>);
+
+    impl Drop for RestoreRng {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            SEEDED_RNG.with(|seeded_rng| *seeded_rng.borrow_mut() = previous);
+        }
+    }
+
+    let previous =
+        SEEDED_RNG.with(|seeded_rng| seeded_rng.replace(Some(StdRng::seed_from_u64(seed))));
+    let _restore = RestoreRng(previous);
+    f()
+}
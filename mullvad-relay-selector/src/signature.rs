@@ -0,0 +1,106 @@
+//! Verification of the detached signatures of relay lists downloaded from the API.
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+/// Base64 encoded Ed25519 public keys that relay lists may be signed with. A relay list is
+/// accepted if it is signed by any of the keys, which allows the signing key to be rotated.
+/// No relay list is accepted if no keys are pinned, unless the `unsigned-relay-lists` feature is
+/// enabled.
+const PINNED_KEYS: &[&str] = &[];
+
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "The relay list is not signed")]
+    MissingSignature,
+
+    #[error(display = "The relay list signature is not valid base64")]
+    InvalidEncoding(#[error(source)] base64::DecodeError),
+
+    #[error(display = "The relay list is not signed by a trusted key")]
+    InvalidSignature,
+
+    #[error(display = "No key to verify the relay list with is pinned")]
+    NoPinnedKeys,
+}
+
+/// Verifies that `signature` is a valid signature of `body` by one of the pinned keys.
+pub fn verify(body: &[u8], signature: Option<&str>) -> Result<(), Error> {
+    let result = verify_with_keys(PINNED_KEYS, body, signature);
+    #[cfg(feature = "unsigned-relay-lists")]
+    if let Err(Error::NoPinnedKeys) = result {
+        log::warn!("Accepting a relay list without verifying it since no key is pinned");
+        return Ok(());
+    }
+    result
+}
+
+fn verify_with_keys(keys: &[&str], body: &[u8], signature: Option<&str>) -> Result<(), Error> {
+    if keys.is_empty() {
+        return Err(Error::NoPinnedKeys);
+    }
+    let signature = base64::decode(signature.ok_or(Error::MissingSignature)?)?;
+    let is_trusted = keys
+        .iter()
+        .filter_map(|key| base64::decode(key).ok())
+        .any(|key| {
+            UnparsedPublicKey::new(&ED25519, key)
+                .verify(body, &signature)
+                .is_ok()
+        });
+    if is_trusted {
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    fn new_key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_verify_with_keys() {
+        let body = br#"{"locations":{}}"#;
+        let trusted = new_key_pair();
+        let untrusted = new_key_pair();
+        let trusted_key = base64::encode(trusted.public_key().as_ref());
+        let keys = [trusted_key.as_str()];
+
+        let signature = base64::encode(trusted.sign(body).as_ref());
+        assert!(verify_with_keys(&keys, body, Some(&signature)).is_ok());
+        assert!(matches!(
+            verify_with_keys(&keys, b"{}", Some(&signature)),
+            Err(Error::InvalidSignature)
+        ));
+
+        let signature = base64::encode(untrusted.sign(body).as_ref());
+        assert!(matches!(
+            verify_with_keys(&keys, body, Some(&signature)),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify_with_keys(&keys, body, None),
+            Err(Error::MissingSignature)
+        ));
+        assert!(matches!(
+            verify_with_keys(&keys, body, Some("not base64!")),
+            Err(Error::InvalidEncoding(_))
+        ));
+
+        // Nothing is accepted if no keys are pinned
+        let signature = base64::encode(trusted.sign(body).as_ref());
+        assert!(matches!(
+            verify_with_keys(&[], body, Some(&signature)),
+            Err(Error::NoPinnedKeys)
+        ));
+    }
+}
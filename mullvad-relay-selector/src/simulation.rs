@@ -0,0 +1,110 @@
+//! Reproducible relay selection, for writing regression tests of selection behavior without a
+//! running daemon. Selection uses a fixed relay list and a random number generator seeded with a
+//! given seed, so the same inputs always result in the same selections.
+//!
+//! Only available with the `simulation` feature.
+
+use crate::{RelaySelector, SelectedBridge, SelectedRelay, SelectorConfig};
+use mullvad_types::relay_list::RelayList;
+use talpid_types::{net::Endpoint, ErrorExt};
+
+/// Relays that would be used for a single connection attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedSelection {
+    /// Hostname of the exit relay, or the host of a custom relay.
+    pub exit: String,
+    /// Hostname of the entry relay, if multihop is used.
+    pub entry: Option<String>,
+    /// Address and protocol that the tunnel would connect to. Not resolved for custom relays.
+    pub endpoint: Option<Endpoint>,
+    /// Hostname of the bridge, or the address of a custom bridge, if a bridge is used.
+    pub bridge: Option<String>,
+    /// Hostname of the obfuscator relay, if obfuscation is used.
+    pub obfuscator: Option<String>,
+}
+
+/// Outcome of relay selection for a single retry attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedAttempt {
+    pub retry_attempt: u32,
+    /// The selected relays, or the reason why no relays could be selected.
+    pub result: Result<SimulatedSelection, String>,
+}
+
+/// Runs relay selection for a sequence of retry attempts against a fixed relay list.
+pub struct Simulation {
+    relay_selector: RelaySelector,
+    seed: u64,
+}
+
+impl Simulation {
+    /// Creates a simulation that selects relays from `relay_list` using `config`, with randomness
+    /// derived from `seed`.
+    pub fn new(config: SelectorConfig, relay_list: RelayList, seed: u64) -> Self {
+        Simulation {
+            relay_selector: RelaySelector::from_relay_list(config, relay_list),
+            seed,
+        }
+    }
+
+    /// Returns the relay selector used by the simulation, e.g. to set the location of the client
+    /// or to register filters before running it. Maintenance windows depend on the current time
+    /// and make the results non-reproducible.
+    pub fn relay_selector(&self) -> &RelaySelector {
+        &self.relay_selector
+    }
+
+    /// Selects relays for the retry attempts `0..attempts`, as if every attempt failed. Every call
+    /// starts over from the seed and returns the same result for the same inputs.
+    pub fn run(&self, attempts: u32) -> Vec<SimulatedAttempt> {
+        crate::rng::with_seed(self.seed, || {
+            (0..attempts)
+                .map(|retry_attempt| SimulatedAttempt {
+                    retry_attempt,
+                    result: self.select(retry_attempt),
+                })
+                .collect()
+        })
+    }
+
+    fn select(&self, retry_attempt: u32) -> Result<SimulatedSelection, String> {
+        let (relay, bridge, obfuscator) = self
+            .relay_selector
+            .get_relay(retry_attempt)
+            .map_err(|error| error.display_chain())?;
+        let bridge = bridge.map(|bridge| match bridge {
+            SelectedBridge::Normal(bridge) => bridge.relay.hostname,
+            SelectedBridge::Custom(settings) => {
+                settings.get_endpoint().endpoint.address.to_string()
+            }
+        });
+        let obfuscator = obfuscator.map(|obfuscator| obfuscator.relay.hostname);
+        Ok(match relay {
+            SelectedRelay::Normal(relay) => SimulatedSelection {
+                exit: relay.exit_relay.hostname,
+                entry: relay.entry_relay.map(|entry| entry.hostname),
+                endpoint: Some(relay.endpoint.to_endpoint()),
+                bridge,
+                obfuscator,
+            },
+            SelectedRelay::Custom(custom_relay) => SimulatedSelection {
+                exit: custom_relay.host,
+                entry: None,
+                endpoint: None,
+                bridge,
+                obfuscator,
+            },
+        })
+    }
+}
+
+/// Selects relays from `relay_list` using `config` for the retry attempts `0..attempts`, with
+/// randomness derived from `seed`. See [Simulation::run].
+pub fn simulate(
+    config: SelectorConfig,
+    relay_list: RelayList,
+    seed: u64,
+    attempts: u32,
+) -> Vec<SimulatedAttempt> {
+    Simulation::new(config, relay_list, seed).run(attempts)
+}
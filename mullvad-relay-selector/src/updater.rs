@@ -1,13 +1,17 @@
-use super::{Error, ParsedRelays};
+use super::{signature, Error, ParsedRelays};
 use futures::{
     channel::mpsc,
-    future::{Fuse, FusedFuture},
-    Future, FutureExt, SinkExt, StreamExt,
+    future::{BoxFuture, Either, Fuse, FusedFuture},
+    Future, FutureExt, SinkExt, StreamExt, TryFutureExt,
 };
-use mullvad_api::{availability::ApiAvailabilityHandle, rest::MullvadRestHandle, RelayListProxy};
-use mullvad_types::relay_list::RelayList;
+use mullvad_api::{
+    availability::ApiAvailabilityHandle, rest::MullvadRestHandle, RelayListProxy, SignedResponse,
+};
+use mullvad_types::relay_list::{RelayList, RelayListDelta, RelayListSource};
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::{
+    io,
     path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -26,16 +30,35 @@ const UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 const EXPONENTIAL_BACKOFF_INITIAL: Duration = Duration::from_secs(16);
 const EXPONENTIAL_BACKOFF_FACTOR: u32 = 8;
 
+/// File that holds the last relay list downloaded in full that passed verification.
+const SIGNED_RELAYS_FILENAME: &str = "relays-signed.json";
+
 #[derive(Clone)]
 pub struct RelayListUpdaterHandle {
-    tx: mpsc::Sender<()>,
+    tx: mpsc::Sender<UpdaterCommand>,
+}
+
+enum UpdaterCommand {
+    /// Fetch a relay list from the current source.
+    Update,
+    /// Obtain relay lists from a different source, starting with fetching one.
+    SetSource(RelayListSource),
 }
 
 impl RelayListUpdaterHandle {
     pub async fn update(&mut self) {
+        self.send_command(UpdaterCommand::Update).await
+    }
+
+    /// Changes where relay lists are obtained from, and fetches a relay list from the new source.
+    pub async fn set_source(&mut self, source: RelayListSource) {
+        self.send_command(UpdaterCommand::SetSource(source)).await
+    }
+
+    async fn send_command(&mut self, command: UpdaterCommand) {
         if let Err(error) = self
             .tx
-            .send(())
+            .send(command)
             .await
             .map_err(|_| Error::DownloaderShutDown)
         {
@@ -47,13 +70,45 @@ impl RelayListUpdaterHandle {
     }
 }
 
+/// A downloaded relay list, or the changes to the current relay list, or a relay list read from
+/// a file.
+enum RelayListUpdate {
+    Full(SignedResponse<RelayList>),
+    Delta(SignedResponse<RelayListDelta>),
+    Imported(RelayList),
+}
+
+impl RelayListUpdate {
+    /// Verifies the signature of the downloaded relay list or changes.
+    fn verify(&self) -> Result<(), signature::Error> {
+        let (body, signature) = match self {
+            RelayListUpdate::Full(response) => (&response.body, &response.signature),
+            RelayListUpdate::Delta(response) => (&response.body, &response.signature),
+            // Relay list files are not signed. They are trusted since the user chose to use them.
+            RelayListUpdate::Imported(_) => return Ok(()),
+        };
+        signature::verify(body, signature.as_deref())
+    }
+}
+
+/// The body of a downloaded relay list exactly as it was served, along with its signature, so that
+/// it can be verified again when it is read back from disk.
+#[derive(Serialize, Deserialize)]
+struct SignedRelayList {
+    /// Base64 encoded response body.
+    body: String,
+    signature: Option<String>,
+}
+
 pub struct RelayListUpdater {
     api_client: RelayListProxy,
     cache_path: PathBuf,
+    signed_cache_path: PathBuf,
     parsed_relays: Arc<Mutex<ParsedRelays>>,
     on_update: Box<dyn Fn(&RelayList) + Send + 'static>,
     last_check: SystemTime,
     api_availability: ApiAvailabilityHandle,
+    source: RelayListSource,
 }
 
 impl RelayListUpdater {
@@ -61,6 +116,7 @@ impl RelayListUpdater {
         selector: super::RelaySelector,
         api_handle: MullvadRestHandle,
         cache_dir: &Path,
+        source: RelayListSource,
         on_update: impl Fn(&RelayList) + Send + 'static,
     ) -> RelayListUpdaterHandle {
         let (tx, cmd_rx) = mpsc::channel(1);
@@ -69,10 +125,12 @@ impl RelayListUpdater {
         let updater = RelayListUpdater {
             api_client,
             cache_path: cache_dir.join(super::RELAYS_FILENAME),
+            signed_cache_path: cache_dir.join(SIGNED_RELAYS_FILENAME),
             parsed_relays: selector.parsed_relays,
             on_update: Box::new(on_update),
             last_check: UNIX_EPOCH,
             api_availability,
+            source,
         };
 
         tokio::spawn(updater.run(cmd_rx));
@@ -80,7 +138,7 @@ impl RelayListUpdater {
         RelayListUpdaterHandle { tx }
     }
 
-    async fn run(mut self, mut cmd_rx: mpsc::Receiver<()>) {
+    async fn run(mut self, mut cmd_rx: mpsc::Receiver<UpdaterCommand>) {
         let mut download_future = Box::pin(Fuse::terminated());
         loop {
             let next_check = tokio::time::sleep(UPDATE_CHECK_INTERVAL).fuse();
@@ -89,8 +147,7 @@ impl RelayListUpdater {
             futures::select! {
                 _check_update = next_check => {
                     if download_future.is_terminated() && self.should_update() {
-                        let tag = self.parsed_relays.lock().tag().map(|tag| tag.to_string());
-                        download_future = Box::pin(Self::download_relay_list(self.api_availability.clone(), self.api_client.clone(), tag).fuse());
+                        download_future = Box::pin(self.fetch_relay_list().fuse());
                         self.last_check = SystemTime::now();
                     }
                 },
@@ -101,9 +158,14 @@ impl RelayListUpdater {
 
                 cmd = cmd_rx.next() => {
                     match cmd {
-                        Some(()) => {
-                            let tag = self.parsed_relays.lock().tag().map(|tag| tag.to_string());
-                            download_future = Box::pin(Self::download_relay_list(self.api_availability.clone(), self.api_client.clone(), tag).fuse());
+                        Some(UpdaterCommand::Update) => {
+                            download_future = Box::pin(self.fetch_relay_list().fuse());
+                            self.last_check = SystemTime::now();
+                        },
+                        Some(UpdaterCommand::SetSource(source)) => {
+                            log::info!("Obtaining relay lists from {}", source);
+                            self.source = source;
+                            download_future = Box::pin(self.fetch_relay_list().fuse());
                             self.last_check = SystemTime::now();
                         },
                         None => {
@@ -117,24 +179,140 @@ impl RelayListUpdater {
         }
     }
 
-    async fn consume_new_relay_list(
-        &mut self,
-        result: Result<Option<RelayList>, mullvad_api::Error>,
-    ) {
-        match result {
-            Ok(Some(relay_list)) => {
+    /// Fetches a relay list from the current source. If the current relay list was obtained
+    /// from the same source, nothing is returned unless the relay list has changed.
+    fn fetch_relay_list(&self) -> BoxFuture<'static, Result<Option<RelayListUpdate>, Error>> {
+        let tag = {
+            let parsed_relays = self.parsed_relays.lock();
+            // Tags are only meaningful to the source that issued them
+            if parsed_relays.locations().source == self.source {
+                parsed_relays.tag().map(|tag| tag.to_string())
+            } else {
+                None
+            }
+        };
+        let url = match &self.source {
+            RelayListSource::Api => None,
+            RelayListSource::Url(url) => Some(url.clone()),
+            RelayListSource::File(path) => {
+                let path = path.clone();
+                return async move {
+                    read_relay_list_file(&path)
+                        .await
+                        .map(|relay_list| Some(RelayListUpdate::Imported(relay_list)))
+                }
+                .boxed();
+            }
+        };
+        Self::download_relay_list(
+            self.api_availability.clone(),
+            self.api_client.clone(),
+            url,
+            tag,
+        )
+        .map_err(Error::Download)
+        .boxed()
+    }
+
+    async fn consume_new_relay_list(&mut self, result: Result<Option<RelayListUpdate>, Error>) {
+        if let Ok(Some(update)) = &result {
+            if let Err(error) = update.verify() {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to verify the downloaded relay list")
+                );
+                self.restore_signed_copy().await;
+                return;
+            }
+        }
+        let relay_list = match result {
+            Ok(Some(RelayListUpdate::Full(response))) => {
+                if let Err(error) =
+                    Self::cache_signed_copy(&self.signed_cache_path, &response).await
+                {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg("Failed to cache the verified relay list")
+                    );
+                }
+                Some(response.value)
+            }
+            Ok(Some(RelayListUpdate::Delta(response))) => self.apply_delta(response.value),
+            Ok(Some(RelayListUpdate::Imported(relay_list))) => Some(relay_list),
+            Ok(None) => {
+                log::debug!("Relay list is up-to-date");
+                None
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to fetch new relay list")
+                );
+                None
+            }
+        };
+        if let Some(mut relay_list) = relay_list {
+            if self.source.is_external() {
+                if let Err(error) = validate_relay_list(&relay_list) {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg(&format!(
+                            "Ignoring relay list from {}",
+                            self.source
+                        ))
+                    );
+                    return;
+                }
+            }
+            relay_list.source = self.source.clone();
+            if let Err(err) = self.update_cache(relay_list).await {
+                log::error!("Failed to update relay list cache: {}", err);
+            }
+        }
+    }
+
+    /// Replaces the current relay list, which may have been read from the unverified relay cache,
+    /// with the last relay list that passed verification. Its signature is verified again, and
+    /// the current relay list is kept if that fails.
+    async fn restore_signed_copy(&mut self) {
+        match read_signed_copy(&self.signed_cache_path).await {
+            Ok(mut relay_list) => {
+                log::info!("Falling back to the last verified relay list");
+                relay_list.source = self.source.clone();
                 if let Err(err) = self.update_cache(relay_list).await {
                     log::error!("Failed to update relay list cache: {}", err);
                 }
             }
-            Ok(None) => log::debug!("Relay list is up-to-date"),
             Err(error) => log::error!(
                 "{}",
-                error.display_chain_with_msg("Failed to fetch new relay list")
+                error.display_chain_with_msg("Failed to read the last verified relay list")
             ),
         }
     }
 
+    /// Returns the current relay list with the changes in `delta` applied, or `None` if the
+    /// relay list has been replaced since the changes were requested.
+    fn apply_delta(&self, delta: RelayListDelta) -> Option<RelayList> {
+        let mut relay_list = self.parsed_relays.lock().locations().clone();
+        if relay_list.etag.as_deref() != Some(delta.base_etag.as_str()) {
+            log::debug!("Ignoring relay list changes for an outdated relay list");
+            return None;
+        }
+        log::debug!(
+            "Applying relay list changes: {} added or changed, {} removed",
+            delta
+                .changed
+                .countries
+                .iter()
+                .flat_map(|country| &country.cities)
+                .map(|city| city.relays.len())
+                .sum::<usize>(),
+            delta.removed.len()
+        );
+        relay_list.apply_delta(delta);
+        Some(relay_list)
+    }
+
     /// Returns true if the current parsed_relays is older than UPDATE_INTERVAL
     fn should_update(&mut self) -> bool {
         let last_check = std::cmp::max(self.parsed_relays.lock().last_updated(), self.last_check);
@@ -147,17 +325,43 @@ impl RelayListUpdater {
         }
     }
 
+    /// Downloads the relay list from the API, or from `url` if it is set.
     fn download_relay_list(
         api_handle: ApiAvailabilityHandle,
         proxy: RelayListProxy,
+        url: Option<String>,
         tag: Option<String>,
-    ) -> impl Future<Output = Result<Option<RelayList>, mullvad_api::Error>> + 'static {
+    ) -> impl Future<Output = Result<Option<RelayListUpdate>, mullvad_api::Error>> + 'static {
         let download_futures = move || {
             let available = api_handle.wait_background();
-            let req = proxy.relay_list(tag.clone());
+            let (delta_req, req) = match &url {
+                // Mirrors only serve the full relay list
+                Some(url) => (
+                    None,
+                    Either::Left(proxy.mirrored_relay_list(url, tag.clone())),
+                ),
+                // Only the changes are downloaded if the current relay list is known to the API
+                None => (
+                    tag.clone().map(|tag| proxy.relay_list_delta(tag)),
+                    Either::Right(proxy.signed_relay_list(tag.clone())),
+                ),
+            };
             async move {
                 available.await?;
-                req.await.map_err(mullvad_api::Error::from)
+                if let Some(delta_req) = delta_req {
+                    match delta_req.await {
+                        Ok(delta) => return Ok(delta.map(RelayListUpdate::Delta)),
+                        Err(error) => log::debug!(
+                            "{}",
+                            error.display_chain_with_msg(
+                                "Failed to fetch relay list changes, fetching the full relay list"
+                            )
+                        ),
+                    }
+                }
+                req.await
+                    .map(|relay_list| relay_list.map(RelayListUpdate::Full))
+                    .map_err(mullvad_api::Error::from)
             }
         };
 
@@ -182,7 +386,8 @@ impl RelayListUpdater {
 
         let new_parsed_relays = ParsedRelays::from_relay_list(new_relay_list, SystemTime::now());
         log::info!(
-            "Downloaded relay inventory has {} relays",
+            "Relay inventory from {} has {} relays",
+            self.source,
             new_parsed_relays.relays().len()
         );
 
@@ -205,4 +410,54 @@ impl RelayListUpdater {
             .map_err(Error::WriteRelayCache)?;
         Ok(())
     }
+
+    /// Writes the body and signature of a verified relay list to `path`.
+    async fn cache_signed_copy(
+        path: &Path,
+        response: &SignedResponse<RelayList>,
+    ) -> Result<(), Error> {
+        let signed_relay_list = SignedRelayList {
+            body: base64::encode(&response.body),
+            signature: response.signature.clone(),
+        };
+        let bytes = serde_json::to_vec(&signed_relay_list).map_err(Error::Serialize)?;
+        tokio::fs::write(path, bytes)
+            .await
+            .map_err(Error::WriteRelayCache)
+    }
+}
+
+/// Reads a relay list written by `RelayListUpdater::cache_signed_copy`, and verifies its
+/// signature.
+async fn read_signed_copy(path: &Path) -> Result<RelayList, Error> {
+    let bytes = tokio::fs::read(path).await.map_err(Error::OpenRelayCache)?;
+    let signed_relay_list: SignedRelayList =
+        serde_json::from_slice(&bytes).map_err(Error::Serialize)?;
+    let body = base64::decode(&signed_relay_list.body)
+        .map_err(|error| Error::ReadRelayList(io::Error::new(io::ErrorKind::InvalidData, error)))?;
+    signature::verify(&body, signed_relay_list.signature.as_deref())
+        .map_err(Error::VerifyRelayList)?;
+    mullvad_api::parse_relay_list(&body).map_err(Error::ParseRelayList)
+}
+
+/// Reads a relay list file in the format served by the API, and checks that it can be used.
+pub async fn read_relay_list_file(path: &Path) -> Result<RelayList, Error> {
+    let body = tokio::fs::read(path).await.map_err(Error::ReadRelayList)?;
+    let relay_list = mullvad_api::parse_relay_list(&body).map_err(Error::ParseRelayList)?;
+    validate_relay_list(&relay_list)?;
+    Ok(relay_list)
+}
+
+/// Checks that a relay list that was not obtained from the API can be used.
+fn validate_relay_list(relay_list: &RelayList) -> Result<(), Error> {
+    let has_relays = relay_list
+        .countries
+        .iter()
+        .flat_map(|country| &country.cities)
+        .any(|city| !city.relays.is_empty());
+    if has_relays {
+        Ok(())
+    } else {
+        Err(Error::EmptyRelayList)
+    }
 }
@@ -0,0 +1,33 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use talpid_types::net::{Endpoint, TunnelType};
+
+/// A single attempt to establish a tunnel, as recorded in the connection history.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ConnectionAttempt {
+    /// When the daemon started connecting.
+    pub started: DateTime<Utc>,
+    pub tunnel_type: TunnelType,
+    /// Address and transport protocol that the tunnel connected to.
+    pub endpoint: Endpoint,
+    /// Hostname of the exit relay. This is `None` for custom relays.
+    pub exit: Option<String>,
+    pub entry: Option<String>,
+    pub bridge: Option<String>,
+    pub obfuscator: Option<String>,
+    /// When the tunnel came up, or `None` if it never did.
+    pub connected: Option<DateTime<Utc>>,
+    /// When the attempt ended, or `None` if it is still in progress.
+    pub ended: Option<DateTime<Utc>>,
+    /// Why the tunnel was disconnected, or why it failed to connect.
+    pub end_reason: Option<String>,
+}
+
+impl ConnectionAttempt {
+    /// Returns how long the tunnel was up for, or `None` if it never came up. Attempts that are
+    /// still in progress are counted up to now.
+    pub fn connected_duration(&self) -> Option<Duration> {
+        let connected = self.connected?;
+        Some(self.ended.unwrap_or_else(Utc::now) - connected)
+    }
+}
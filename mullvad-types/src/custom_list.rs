@@ -0,0 +1,41 @@
+use crate::relay_constraints::LocationConstraint;
+use serde::{Deserialize, Serialize};
+
+/// A named list of locations. A custom list can be used as a location constraint, in which case
+/// relays are selected from one of the locations in it.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct CustomList {
+    pub name: String,
+    pub locations: Vec<LocationConstraint>,
+}
+
+impl CustomList {
+    pub fn new(name: String) -> Self {
+        CustomList {
+            name,
+            locations: Vec::new(),
+        }
+    }
+}
+
+/// The custom lists defined by the user.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CustomListsSettings {
+    pub custom_lists: Vec<CustomList>,
+}
+
+impl CustomListsSettings {
+    /// Returns the custom list with the given name, if there is one.
+    pub fn get(&self, name: &str) -> Option<&CustomList> {
+        self.custom_lists.iter().find(|list| list.name == name)
+    }
+
+    /// Returns the locations in the custom list with the given name. Returns no locations if there
+    /// is no such list.
+    pub fn locations(&self, name: &str) -> &[LocationConstraint] {
+        self.get(name)
+            .map(|list| list.locations.as_slice())
+            .unwrap_or(&[])
+    }
+}
@@ -60,7 +60,7 @@ impl CustomTunnelEndpoint {
             .into(),
             ConnectionConfig::Wireguard(connection) => wireguard::TunnelParameters {
                 connection,
-                options: tunnel_options.wireguard.options.clone(),
+                options: tunnel_options.wireguard.to_talpid_tunnel_options(),
                 generic_options: tunnel_options.generic,
                 obfuscation: None,
             }
@@ -93,7 +93,7 @@ impl fmt::Display for CustomTunnelEndpoint {
 /// Returns the first IPv4 address if one exists, otherwise the first IPv6 address.
 /// Rust only provides means to resolve a socket addr, not just a host, for some reason. So
 /// because of this we do the resolving with port zero and then pick out the IPs.
-fn resolve_to_ip(host: &str) -> Result<IpAddr, Error> {
+pub fn resolve_to_ip(host: &str) -> Result<IpAddr, Error> {
     let (mut ipv4, mut ipv6): (Vec<IpAddr>, Vec<IpAddr>) = (host, 0)
         .to_socket_addrs()
         .map_err(|e| Error::InvalidHost(host.to_owned(), e))?
@@ -0,0 +1,202 @@
+use crate::excluded_destinations::is_valid_domain;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Default number of hours between updates of a blocklist.
+pub const DEFAULT_UPDATE_INTERVAL: u32 = 24;
+
+/// Domains that hosts files map to local addresses. These are never blocked.
+const LOCAL_DOMAINS: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    #[error(display = "\"{}\" is neither an HTTP(S) URL nor an absolute path", _0)]
+    InvalidSource(String),
+}
+
+/// Where the domains of a blocklist are read from.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistSource {
+    /// The blocklist is downloaded from an HTTP or HTTPS URL.
+    Url(String),
+    /// The blocklist is read from a local file.
+    File(PathBuf),
+}
+
+impl FromStr for BlocklistSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with("https://") || s.starts_with("http://") {
+            return Ok(BlocklistSource::Url(s.to_owned()));
+        }
+        if Path::new(s).is_absolute() {
+            Ok(BlocklistSource::File(PathBuf::from(s)))
+        } else {
+            Err(Error::InvalidSource(s.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for BlocklistSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlocklistSource::Url(url) => url.fmt(f),
+            BlocklistSource::File(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// A list of domains that the local DNS forwarder refuses to resolve, along with their
+/// subdomains.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct DnsBlocklist {
+    pub source: BlocklistSource,
+    pub enabled: bool,
+    /// Number of hours between updates of the blocklist.
+    pub update_interval: u32,
+}
+
+impl DnsBlocklist {
+    pub fn new(source: BlocklistSource) -> Self {
+        DnsBlocklist {
+            source,
+            enabled: true,
+            update_interval: DEFAULT_UPDATE_INTERVAL,
+        }
+    }
+}
+
+/// The state of a blocklist that has been loaded by the daemon.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlocklistStatus {
+    /// Number of domains in the blocklist.
+    pub domains: usize,
+    /// When the domains were last fetched or read, if ever.
+    pub updated: Option<DateTime<Utc>>,
+    /// Why the last update failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Parses the domains of a blocklist. Each line is either in hosts file format, e.g.
+/// `0.0.0.0 example.com`, a single domain, or an Adblock Plus rule for a domain, e.g.
+/// `||example.com^`. Comments and any other lines are ignored.
+pub fn parse_domains(contents: &str) -> HashSet<String> {
+    let mut domains = HashSet::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with('!') {
+            continue;
+        }
+
+        if let Some(rule) = line.strip_prefix("||") {
+            // Rules with options or paths apply to more specific requests than the domain
+            if let Some(domain) = rule.strip_suffix('^') {
+                insert_domain(&mut domains, domain);
+            }
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or_default();
+        if first.parse::<IpAddr>().is_ok() {
+            for domain in fields {
+                insert_domain(&mut domains, domain);
+            }
+        } else if fields.next().is_none() {
+            insert_domain(&mut domains, first);
+        }
+    }
+    domains
+}
+
+fn insert_domain(domains: &mut HashSet<String>, domain: &str) {
+    let domain = domain
+        .strip_suffix('.')
+        .unwrap_or(domain)
+        .to_ascii_lowercase();
+    if is_valid_domain(&domain) && !LOCAL_DOMAINS.contains(&domain.as_str()) {
+        domains.insert(domain);
+    }
+}
+
+/// Returns whether `domain`, or any domain that it is a subdomain of, is in `blocked`.
+pub fn is_blocked(blocked: &HashSet<String>, domain: &str) -> bool {
+    let domain = domain
+        .strip_suffix('.')
+        .unwrap_or(domain)
+        .to_ascii_lowercase();
+    let mut suffix = domain.as_str();
+    loop {
+        if blocked.contains(suffix) {
+            return true;
+        }
+        match suffix.split_once('.') {
+            Some((_, parent)) => suffix = parent,
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_blocklist() {
+        let contents = "\
+# A comment
+127.0.0.1 localhost
+0.0.0.0 ads.example.com tracker.example.net # trailing comment
+Malware.Example.org.
+||adblock.example.com^
+||adblock.example.com/path^
+! Adblock Plus comment
+not a domain
+";
+        let expected: HashSet<String> = [
+            "ads.example.com",
+            "tracker.example.net",
+            "malware.example.org",
+            "adblock.example.com",
+        ]
+        .iter()
+        .map(|domain| domain.to_string())
+        .collect();
+        assert_eq!(parse_domains(contents), expected);
+    }
+
+    #[test]
+    fn test_is_blocked() {
+        let blocked = parse_domains("example.com");
+        assert!(is_blocked(&blocked, "example.com."));
+        assert!(is_blocked(&blocked, "ads.EXAMPLE.com"));
+        assert!(!is_blocked(&blocked, "notexample.com"));
+        assert!(!is_blocked(&blocked, "com"));
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            "https://example.com/hosts".parse(),
+            Ok(BlocklistSource::Url("https://example.com/hosts".to_owned()))
+        );
+        assert!("example.com/hosts".parse::<BlocklistSource>().is_err());
+    }
+}
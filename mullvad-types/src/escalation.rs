@@ -0,0 +1,204 @@
+use crate::relay_constraints::Constraint;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use talpid_types::net::{TransportProtocol, TunnelType};
+
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    #[error(display = "The first escalation step must start at attempt 0")]
+    FirstStepNotAtZero,
+
+    #[error(display = "Escalation step starting at attempt {} is out of order", _0)]
+    StepsOutOfOrder(u32),
+
+    #[error(display = "Port 0 is not a valid port in escalation step {}", _0)]
+    InvalidPort(u32),
+
+    #[error(
+        display = "Escalation step {} uses udp2tcp obfuscation with OpenVPN",
+        _0
+    )]
+    ObfuscationWithOpenVpn(u32),
+
+    #[error(display = "Escalation step {} uses WireGuard over TCP", _0)]
+    WireguardOverTcp(u32),
+
+    #[error(
+        display = "Escalation step {} uses a bridge without OpenVPN over TCP",
+        _0
+    )]
+    BridgeWithoutOpenVpnTcp(u32),
+}
+
+/// A user-defined retry strategy. Replaces the built-in port, protocol, bridge, and obfuscation
+/// escalation when connection attempts fail. The built-in strategy is used if there are no steps.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EscalationLadder {
+    /// Steps ordered by the attempt they start at. The last step applies to all later attempts.
+    pub steps: Vec<EscalationStep>,
+}
+
+impl EscalationLadder {
+    /// Returns the step that applies to `retry_attempt`, or `None` if the built-in strategy
+    /// should be used.
+    pub fn step(&self, retry_attempt: u32) -> Option<&EscalationStep> {
+        self.steps
+            .iter()
+            .rev()
+            .find(|step| step.from_attempt <= retry_attempt)
+    }
+
+    /// Checks that the steps are ordered, cover every attempt, and only combine tunnel types,
+    /// protocols, bridges, and obfuscation that can be used together.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(first) = self.steps.first() {
+            if first.from_attempt != 0 {
+                return Err(Error::FirstStepNotAtZero);
+            }
+        }
+        for pair in self.steps.windows(2) {
+            if pair[1].from_attempt <= pair[0].from_attempt {
+                return Err(Error::StepsOutOfOrder(pair[1].from_attempt));
+            }
+        }
+        self.steps.iter().try_for_each(EscalationStep::validate)
+    }
+}
+
+/// Describes how to connect on every attempt from `from_attempt` until the next step begins.
+/// Constraints that are explicitly set in the relay settings take precedence.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub struct EscalationStep {
+    pub from_attempt: u32,
+    pub tunnel_type: TunnelType,
+    /// The port to connect to. If `udp2tcp` is set, this is the udp2tcp port.
+    pub port: Constraint<u16>,
+    /// The transport protocol used by OpenVPN. WireGuard always uses UDP.
+    pub transport_protocol: TransportProtocol,
+    /// Whether to use udp2tcp obfuscation. Only applies to WireGuard, and only if obfuscation is
+    /// set to `auto`.
+    pub udp2tcp: bool,
+    /// Whether to use a bridge. Only applies to OpenVPN over TCP, and only if the bridge state is
+    /// `auto`.
+    pub bridge: bool,
+}
+
+impl EscalationStep {
+    fn validate(&self) -> Result<(), Error> {
+        if self.port == Constraint::Only(0) {
+            return Err(Error::InvalidPort(self.from_attempt));
+        }
+        match self.tunnel_type {
+            TunnelType::Wireguard => {
+                if self.transport_protocol != TransportProtocol::Udp {
+                    return Err(Error::WireguardOverTcp(self.from_attempt));
+                }
+                if self.bridge {
+                    return Err(Error::BridgeWithoutOpenVpnTcp(self.from_attempt));
+                }
+            }
+            TunnelType::OpenVpn => {
+                if self.udp2tcp {
+                    return Err(Error::ObfuscationWithOpenVpn(self.from_attempt));
+                }
+                if self.bridge && self.transport_protocol != TransportProtocol::Tcp {
+                    return Err(Error::BridgeWithoutOpenVpnTcp(self.from_attempt));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for EscalationStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "from attempt {}: {}",
+            self.from_attempt, self.tunnel_type
+        )?;
+        if self.tunnel_type == TunnelType::OpenVpn {
+            write!(f, " over {}", self.transport_protocol)?;
+        }
+        match self.port {
+            Constraint::Any => write!(f, " on any port")?,
+            Constraint::Only(port) => write!(f, " on port {}", port)?,
+        }
+        if self.udp2tcp {
+            write!(f, " using udp2tcp")?;
+        }
+        if self.bridge {
+            write!(f, " using a bridge")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn step(from_attempt: u32, tunnel_type: TunnelType) -> EscalationStep {
+        EscalationStep {
+            from_attempt,
+            tunnel_type,
+            port: Constraint::Any,
+            transport_protocol: TransportProtocol::Udp,
+            udp2tcp: false,
+            bridge: false,
+        }
+    }
+
+    #[test]
+    fn test_step_for_attempt() {
+        let ladder = EscalationLadder {
+            steps: vec![
+                step(0, TunnelType::Wireguard),
+                EscalationStep {
+                    port: Constraint::Only(443),
+                    udp2tcp: true,
+                    ..step(3, TunnelType::Wireguard)
+                },
+            ],
+        };
+        assert_eq!(ladder.validate(), Ok(()));
+        assert_eq!(ladder.step(2), Some(&ladder.steps[0]));
+        assert_eq!(ladder.step(3), Some(&ladder.steps[1]));
+        assert_eq!(ladder.step(100), Some(&ladder.steps[1]));
+        assert_eq!(EscalationLadder::default().step(0), None);
+    }
+
+    #[test]
+    fn test_invalid_ladders() {
+        let ladder = EscalationLadder {
+            steps: vec![step(1, TunnelType::Wireguard)],
+        };
+        assert_eq!(ladder.validate(), Err(Error::FirstStepNotAtZero));
+
+        let ladder = EscalationLadder {
+            steps: vec![
+                step(0, TunnelType::Wireguard),
+                step(2, TunnelType::Wireguard),
+                step(2, TunnelType::OpenVpn),
+            ],
+        };
+        assert_eq!(ladder.validate(), Err(Error::StepsOutOfOrder(2)));
+
+        let ladder = EscalationLadder {
+            steps: vec![EscalationStep {
+                udp2tcp: true,
+                ..step(0, TunnelType::OpenVpn)
+            }],
+        };
+        assert_eq!(ladder.validate(), Err(Error::ObfuscationWithOpenVpn(0)));
+
+        let ladder = EscalationLadder {
+            steps: vec![EscalationStep {
+                bridge: true,
+                ..step(0, TunnelType::OpenVpn)
+            }],
+        };
+        assert_eq!(ladder.validate(), Err(Error::BridgeWithoutOpenVpnTcp(0)));
+    }
+}
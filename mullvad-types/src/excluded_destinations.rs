@@ -0,0 +1,177 @@
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    #[error(display = "\"{}\" is neither an IP network nor a domain name", _0)]
+    InvalidDestination(String),
+}
+
+/// Maximum length of a domain name, excluding the trailing dot.
+const MAX_DOMAIN_LENGTH: usize = 253;
+/// Maximum length of a single label of a domain name.
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// A destination whose traffic is sent outside the tunnel.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ExcludedDestination {
+    /// An IP address or a network in CIDR notation.
+    Network(IpNetwork),
+    /// A domain name. Traffic to the addresses that it resolves to is excluded.
+    Domain(String),
+}
+
+impl FromStr for ExcludedDestination {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(address) = s.parse::<IpAddr>() {
+            return Ok(ExcludedDestination::Network(IpNetwork::from(address)));
+        }
+        if let Ok(network) = s.parse::<IpNetwork>() {
+            return Ok(ExcludedDestination::Network(network));
+        }
+        let domain = s.strip_suffix('.').unwrap_or(s).to_ascii_lowercase();
+        if is_valid_domain(&domain) {
+            Ok(ExcludedDestination::Domain(domain))
+        } else {
+            Err(Error::InvalidDestination(s.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for ExcludedDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExcludedDestination::Network(network) => network.fmt(f),
+            ExcludedDestination::Domain(domain) => domain.fmt(f),
+        }
+    }
+}
+
+pub(crate) fn is_valid_domain(domain: &str) -> bool {
+    // Domains consisting only of digits and dots would be mistaken for malformed IPv4 addresses
+    if domain.is_empty()
+        || domain.len() > MAX_DOMAIN_LENGTH
+        || domain.chars().all(|c| c.is_ascii_digit() || c == '.')
+    {
+        return false;
+    }
+    domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Destinations whose traffic is sent outside the tunnel, regardless of which app sends it.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ExcludedDestinations {
+    /// Excluded IP networks.
+    pub networks: Vec<IpNetwork>,
+    /// Excluded domains. These are resolved periodically by the daemon.
+    pub domains: Vec<String>,
+}
+
+impl ExcludedDestinations {
+    /// Adds `destination` unless it is already excluded. Returns whether it was added.
+    pub fn add(&mut self, destination: ExcludedDestination) -> bool {
+        match destination {
+            ExcludedDestination::Network(network) => push_unique(&mut self.networks, network),
+            ExcludedDestination::Domain(domain) => push_unique(&mut self.domains, domain),
+        }
+    }
+
+    /// Removes `destination`. Returns whether it was excluded.
+    pub fn remove(&mut self, destination: &ExcludedDestination) -> bool {
+        match destination {
+            ExcludedDestination::Network(network) => remove_item(&mut self.networks, network),
+            ExcludedDestination::Domain(domain) => remove_item(&mut self.domains, domain),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty() && self.domains.is_empty()
+    }
+
+    /// Returns every excluded destination, networks first.
+    pub fn iter(&self) -> impl Iterator<Item = ExcludedDestination> + '_ {
+        self.networks
+            .iter()
+            .copied()
+            .map(ExcludedDestination::Network)
+            .chain(
+                self.domains
+                    .iter()
+                    .cloned()
+                    .map(ExcludedDestination::Domain),
+            )
+    }
+}
+
+fn push_unique<T: PartialEq>(items: &mut Vec<T>, item: T) -> bool {
+    if items.contains(&item) {
+        false
+    } else {
+        items.push(item);
+        true
+    }
+}
+
+fn remove_item<T: PartialEq>(items: &mut Vec<T>, item: &T) -> bool {
+    let len = items.len();
+    items.retain(|existing| existing != item);
+    items.len() != len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_destination() {
+        assert_eq!(
+            "10.0.0.1".parse(),
+            Ok(ExcludedDestination::Network("10.0.0.1/32".parse().unwrap()))
+        );
+        assert_eq!(
+            "fd00::/8".parse(),
+            Ok(ExcludedDestination::Network("fd00::/8".parse().unwrap()))
+        );
+        assert_eq!(
+            " Example.COM. ".parse(),
+            Ok(ExcludedDestination::Domain("example.com".to_owned()))
+        );
+        assert!("10.0.0.300".parse::<ExcludedDestination>().is_err());
+        assert!("-example.com".parse::<ExcludedDestination>().is_err());
+        assert!("example..com".parse::<ExcludedDestination>().is_err());
+        assert!("".parse::<ExcludedDestination>().is_err());
+    }
+
+    #[test]
+    fn test_add_remove_destinations() {
+        let mut destinations = ExcludedDestinations::default();
+        let network: ExcludedDestination = "192.168.1.0/24".parse().unwrap();
+        let domain: ExcludedDestination = "example.com".parse().unwrap();
+
+        assert!(destinations.add(network.clone()));
+        assert!(destinations.add(domain.clone()));
+        assert!(!destinations.add(domain.clone()));
+        assert_eq!(
+            destinations.iter().collect::<Vec<_>>(),
+            vec![network.clone(), domain.clone()]
+        );
+
+        assert!(destinations.remove(&network));
+        assert!(!destinations.remove(&network));
+        assert!(destinations.remove(&domain));
+        assert!(destinations.is_empty());
+    }
+}
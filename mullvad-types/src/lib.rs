@@ -2,13 +2,23 @@
 
 pub mod account;
 pub mod auth_failed;
+pub mod connection_history;
+pub mod custom_list;
 pub mod device;
+pub mod dns_blocklist;
 pub mod endpoint;
+pub mod escalation;
+pub mod excluded_destinations;
 pub mod location;
+pub mod network_rules;
 pub mod relay_constraints;
 pub mod relay_list;
+pub mod relay_rotation;
+pub mod relay_selection;
+pub mod schedule;
 pub mod settings;
 pub mod states;
+pub mod traffic_stats;
 pub mod version;
 pub mod wireguard;
 
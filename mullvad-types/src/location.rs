@@ -1,3 +1,4 @@
+use crate::relay_list::Relay;
 #[cfg(target_os = "android")]
 use jnix::IntoJava;
 use serde::{Deserialize, Serialize};
@@ -181,9 +182,29 @@ impl From<AmIMullvad> for GeoIpLocation {
     }
 }
 
+/// Returns the GeoIP location of traffic exiting through `relay`, or `None` if the relay has no
+/// location data.
+pub fn relay_to_geoip(relay: &Relay) -> Option<GeoIpLocation> {
+    let location = relay.location.as_ref()?;
+    Some(GeoIpLocation {
+        ipv4: None,
+        ipv6: None,
+        country: location.country.clone(),
+        city: Some(location.city.clone()),
+        latitude: location.latitude,
+        longitude: location.longitude,
+        mullvad_exit_ip: true,
+        hostname: Some(relay.hostname.clone()),
+        bridge_hostname: None,
+        entry_hostname: None,
+        obfuscator_hostname: None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Coordinates;
+    use super::{relay_to_geoip, Coordinates, Location};
+    use crate::relay_list::{Relay, RelayEndpointData};
 
     impl Coordinates {
         fn equal(&self, other: Coordinates) -> bool {
@@ -251,4 +272,42 @@ mod tests {
             longitude: 0.0,
         }));
     }
+
+    fn relay_with_location(location: Option<Location>) -> Relay {
+        Relay {
+            hostname: "se-got-001".to_string(),
+            ipv4_addr_in: "185.213.154.131".parse().unwrap(),
+            ipv6_addr_in: None,
+            include_in_country: true,
+            active: true,
+            owned: true,
+            provider: "31173".to_string(),
+            weight: 1,
+            load: None,
+            endpoint_data: RelayEndpointData::Openvpn,
+            location,
+        }
+    }
+
+    #[test]
+    fn test_relay_to_geoip() {
+        let relay = relay_with_location(Some(Location {
+            country: "Sweden".to_string(),
+            country_code: "se".to_string(),
+            city: "Gothenburg".to_string(),
+            city_code: "got".to_string(),
+            latitude: 57.70887,
+            longitude: 11.97456,
+        }));
+
+        let location = relay_to_geoip(&relay).expect("relay has a location");
+        assert_eq!(location.country, "Sweden");
+        assert_eq!(location.city.as_deref(), Some("Gothenburg"));
+        assert_eq!(location.latitude, 57.70887);
+        assert_eq!(location.longitude, 11.97456);
+        assert_eq!(location.hostname.as_deref(), Some("se-got-001"));
+        assert!(location.mullvad_exit_ip);
+
+        assert_eq!(relay_to_geoip(&relay_with_location(None)), None);
+    }
 }
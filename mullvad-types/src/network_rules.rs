@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use talpid_types::net::NetworkIdentity;
+
+/// How the daemon treats a network when the host joins it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkTrust {
+    /// Disconnect when joining the network.
+    Trusted,
+    /// Connect when joining the network.
+    Untrusted,
+}
+
+impl fmt::Display for NetworkTrust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkTrust::Trusted => "trusted".fmt(f),
+            NetworkTrust::Untrusted => "untrusted".fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct NetworkRule {
+    pub network: NetworkIdentity,
+    pub trust: NetworkTrust,
+}
+
+/// Rules for connecting and disconnecting automatically depending on the network that the host is
+/// connected to.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetworkRules {
+    /// At most one rule per network.
+    pub rules: Vec<NetworkRule>,
+    /// Block traffic outside the tunnel while on an untrusted network, even when disconnected.
+    pub lockdown_untrusted: bool,
+}
+
+impl NetworkRules {
+    /// Adds `rule`, replacing any existing rule for the same network. Returns whether the rules
+    /// changed.
+    pub fn set_rule(&mut self, rule: NetworkRule) -> bool {
+        match self
+            .rules
+            .iter_mut()
+            .find(|existing| existing.network == rule.network)
+        {
+            Some(existing) if *existing == rule => false,
+            Some(existing) => {
+                *existing = rule;
+                true
+            }
+            None => {
+                self.rules.push(rule);
+                true
+            }
+        }
+    }
+
+    /// Removes the rule for `network`. Returns whether there was one.
+    pub fn remove_rule(&mut self, network: &NetworkIdentity) -> bool {
+        let len = self.rules.len();
+        self.rules.retain(|rule| &rule.network != network);
+        self.rules.len() != len
+    }
+
+    /// Returns how `network` should be treated, or `None` if there is no rule for it.
+    pub fn trust(&self, network: &NetworkIdentity) -> Option<NetworkTrust> {
+        self.rules
+            .iter()
+            .find(|rule| &rule.network == network)
+            .map(|rule| rule.trust)
+    }
+
+    /// Returns whether traffic outside the tunnel should be blocked while on `network`.
+    pub fn is_locked_down(&self, network: &NetworkIdentity) -> bool {
+        self.lockdown_untrusted && self.trust(network) == Some(NetworkTrust::Untrusted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_network_rules() {
+        let home = NetworkIdentity::Wifi("Home".to_owned());
+        let cafe = NetworkIdentity::Wifi("Cafe".to_owned());
+        let office = NetworkIdentity::Wired("10.0.0.1%eth0".to_owned());
+        let mut rules = NetworkRules::default();
+
+        assert!(rules.set_rule(NetworkRule {
+            network: home.clone(),
+            trust: NetworkTrust::Trusted,
+        }));
+        assert!(rules.set_rule(NetworkRule {
+            network: cafe.clone(),
+            trust: NetworkTrust::Trusted,
+        }));
+        assert!(!rules.set_rule(NetworkRule {
+            network: cafe.clone(),
+            trust: NetworkTrust::Trusted,
+        }));
+        assert!(rules.set_rule(NetworkRule {
+            network: cafe.clone(),
+            trust: NetworkTrust::Untrusted,
+        }));
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(rules.trust(&home), Some(NetworkTrust::Trusted));
+        assert_eq!(rules.trust(&cafe), Some(NetworkTrust::Untrusted));
+        assert_eq!(rules.trust(&office), None);
+
+        assert!(!rules.is_locked_down(&cafe));
+        rules.lockdown_untrusted = true;
+        assert!(rules.is_locked_down(&cafe));
+        assert!(!rules.is_locked_down(&home));
+
+        assert!(rules.remove_rule(&cafe));
+        assert!(!rules.remove_rule(&cafe));
+        assert_eq!(rules.trust(&cafe), None);
+    }
+}
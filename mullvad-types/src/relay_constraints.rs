@@ -6,11 +6,14 @@ use crate::{
     relay_list::Relay,
     CustomTunnelEndpoint,
 };
+use ipnetwork::IpNetwork;
 #[cfg(target_os = "android")]
 use jnix::{FromJava, IntoJava};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, fmt};
-use talpid_types::net::{openvpn::ProxySettings, IpVersion, TransportProtocol, TunnelType};
+use std::{collections::HashSet, fmt, net::Ipv4Addr, str::FromStr};
+use talpid_types::net::{
+    openvpn::ProxySettings, wireguard::PublicKey, IpVersion, TransportProtocol, TunnelType,
+};
 
 pub trait Match<T> {
     fn matches(&self, other: &T) -> bool;
@@ -192,6 +195,15 @@ pub struct RelayConstraints {
     pub wireguard_constraints: WireguardConstraints,
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub openvpn_constraints: OpenVpnConstraints,
+    /// Only select relays with an entry IP address in this subnet.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub endpoint_subnet: Constraint<IpNetwork>,
+    /// Hostnames of relays that are never selected, regardless of the other constraints.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub excluded_relays: Vec<Hostname>,
+    /// Locations that relays are never selected from, regardless of the other constraints.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub avoided_locations: Vec<LocationConstraint>,
 }
 
 #[cfg(target_os = "android")]
@@ -204,6 +216,9 @@ impl Default for RelayConstraints {
             ownership: Constraint::default(),
             wireguard_constraints: WireguardConstraints::default(),
             openvpn_constraints: OpenVpnConstraints::default(),
+            endpoint_subnet: Constraint::default(),
+            excluded_relays: Vec::new(),
+            avoided_locations: Vec::new(),
         }
     }
 }
@@ -220,7 +235,14 @@ impl RelayConstraints {
                 .unwrap_or_else(|| self.wireguard_constraints.clone()),
             openvpn_constraints: update
                 .openvpn_constraints
-                .unwrap_or(self.openvpn_constraints),
+                .unwrap_or_else(|| self.openvpn_constraints.clone()),
+            endpoint_subnet: update.endpoint_subnet.unwrap_or(self.endpoint_subnet),
+            excluded_relays: update
+                .excluded_relays
+                .unwrap_or_else(|| self.excluded_relays.clone()),
+            avoided_locations: update
+                .avoided_locations
+                .unwrap_or_else(|| self.avoided_locations.clone()),
         }
     }
 }
@@ -256,11 +278,27 @@ impl fmt::Display for RelayConstraints {
             Constraint::Only(ref constraint) => constraint.fmt(f)?,
         }
         match self.ownership {
-            Constraint::Any => Ok(()),
+            Constraint::Any => (),
             Constraint::Only(ref constraint) => {
-                write!(f, " and {}", constraint)
+                write!(f, " and {}", constraint)?;
             }
         }
+        match self.endpoint_subnet {
+            Constraint::Any => (),
+            Constraint::Only(ref subnet) => write!(f, " with an entry address in {}", subnet)?,
+        }
+        if !self.excluded_relays.is_empty() {
+            write!(f, " excluding {}", self.excluded_relays.join(", "))?;
+        }
+        if !self.avoided_locations.is_empty() {
+            let locations: Vec<String> = self
+                .avoided_locations
+                .iter()
+                .map(|location| location.to_string())
+                .collect();
+            write!(f, " avoiding {}", locations.join(", "))?;
+        }
+        Ok(())
     }
 }
 
@@ -277,6 +315,13 @@ pub enum LocationConstraint {
     City(CountryCode, CityCode),
     /// An single hostname in a given city.
     Hostname(CountryCode, CityCode, Hostname),
+    /// Any of the locations in the custom list with the given name. The relay selector replaces
+    /// this with the locations in the list, so on its own it matches no relay.
+    CustomList(String),
+    /// The city closest to the approximate location of the client. The relay selector replaces
+    /// this with a city, or with any location if the location of the client is unknown, so on its
+    /// own it matches no relay.
+    Nearest,
 }
 
 impl LocationConstraint {
@@ -301,6 +346,7 @@ impl LocationConstraint {
                         && relay.hostname == *hostname
                 })
             }
+            LocationConstraint::CustomList(_) | LocationConstraint::Nearest => false,
         }
     }
 }
@@ -338,7 +384,9 @@ impl Set<LocationConstraint> for LocationConstraint {
                     country == other_country && city == other_city
                 }
                 LocationConstraint::Hostname(..) => self == other,
+                LocationConstraint::CustomList(_) | LocationConstraint::Nearest => false,
             },
+            LocationConstraint::CustomList(_) | LocationConstraint::Nearest => self == other,
         }
     }
 }
@@ -375,6 +423,9 @@ pub type Provider = String;
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Providers {
     providers: HashSet<Provider>,
+    /// Whether relays from any provider except `providers` are matched.
+    #[serde(default)]
+    exclude: bool,
 }
 
 /// Returned if the iterator contained no providers.
@@ -382,8 +433,23 @@ pub struct NoProviders(());
 
 impl Providers {
     pub fn new(providers: impl Iterator<Item = Provider>) -> Result<Providers, NoProviders> {
+        Self::with_exclusion(providers, false)
+    }
+
+    /// Like [`Providers::new`], but matches relays from any provider except the given ones.
+    pub fn new_excluded(
+        providers: impl Iterator<Item = Provider>,
+    ) -> Result<Providers, NoProviders> {
+        Self::with_exclusion(providers, true)
+    }
+
+    fn with_exclusion(
+        providers: impl Iterator<Item = Provider>,
+        exclude: bool,
+    ) -> Result<Providers, NoProviders> {
         let providers = Providers {
             providers: providers.collect(),
+            exclude,
         };
         if providers.providers.is_empty() {
             return Err(NoProviders(()));
@@ -391,14 +457,32 @@ impl Providers {
         Ok(providers)
     }
 
+    /// Returns whether the providers are excluded rather than allowed.
+    pub fn is_excluded(&self) -> bool {
+        self.exclude
+    }
+
     pub fn into_vec(self) -> Vec<Provider> {
         self.providers.into_iter().collect()
     }
 }
 
+/// Matches relays with an entry address in the subnet.
+impl Match<Relay> for IpNetwork {
+    fn matches(&self, relay: &Relay) -> bool {
+        match self {
+            IpNetwork::V4(subnet) => subnet.contains(relay.ipv4_addr_in),
+            IpNetwork::V6(subnet) => relay
+                .ipv6_addr_in
+                .map(|address| subnet.contains(address))
+                .unwrap_or(false),
+        }
+    }
+}
+
 impl Match<Relay> for Providers {
     fn matches(&self, relay: &Relay) -> bool {
-        self.providers.contains(&relay.provider)
+        self.providers.contains(&relay.provider) != self.exclude
     }
 }
 
@@ -410,7 +494,11 @@ impl From<Providers> for Vec<Provider> {
 
 impl fmt::Display for Providers {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "provider(s) ")?;
+        if self.exclude {
+            write!(f, "any provider except ")?;
+        } else {
+            write!(f, "provider(s) ")?;
+        }
         for (i, provider) in self.providers.iter().enumerate() {
             if i == 0 {
                 write!(f, "{}", provider)?;
@@ -430,6 +518,8 @@ impl fmt::Display for LocationConstraint {
             LocationConstraint::Hostname(country, city, hostname) => {
                 write!(f, "city {}, {}, hostname {}", city, country, hostname)
             }
+            LocationConstraint::CustomList(name) => write!(f, "custom list {}", name),
+            LocationConstraint::Nearest => write!(f, "nearest location"),
         }
     }
 }
@@ -441,23 +531,166 @@ pub struct TransportPort {
 }
 
 /// [`Constraint`]s applicable to OpenVPN relays.
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct OpenVpnConstraints {
     pub port: Constraint<TransportPort>,
+    #[serde(default)]
+    pub ip_version: Constraint<IpVersion>,
+    /// Connect to the exit relay through an entry relay. This replaces any bridge, and requires
+    /// TCP.
+    #[serde(default)]
+    pub use_multihop: bool,
+    #[serde(default)]
+    pub entry_location: Constraint<LocationConstraint>,
 }
 
 impl fmt::Display for OpenVpnConstraints {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self.port {
-            Constraint::Any => write!(f, "any port"),
+            Constraint::Any => write!(f, "any port")?,
             Constraint::Only(port) => {
                 match port.port {
                     Constraint::Any => write!(f, "any port")?,
                     Constraint::Only(port) => write!(f, "port {}", port)?,
                 }
-                write!(f, "/{}", port.protocol)
+                write!(f, "/{}", port.protocol)?;
+            }
+        }
+        if let Constraint::Only(ip_version) = self.ip_version {
+            write!(f, " over {}", ip_version)?;
+        }
+        if self.use_multihop {
+            match &self.entry_location {
+                Constraint::Any => write!(f, " (via any location)"),
+                Constraint::Only(location) => write!(f, " (via {})", location),
+            }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+#[error(display = "Port ranges must be non-empty and only contain ports between 1 and 65535")]
+pub struct InvalidPortRanges;
+
+/// A non-empty set of ports, given as inclusive ranges. Deserializes from a single port as well,
+/// so that settings from before port ranges were supported remain valid.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "PortRangesRepr", into = "PortRangesRepr")]
+pub struct PortRanges(Vec<(u16, u16)>);
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum PortRangesRepr {
+    Port(u16),
+    Ranges(Vec<(u16, u16)>),
+}
+
+impl PortRanges {
+    /// Returns the set of ports in the inclusive `ranges`. Fails if there are no ranges, if a
+    /// range ends before it starts, or if a range includes port 0.
+    pub fn new(ranges: Vec<(u16, u16)>) -> Result<Self, InvalidPortRanges> {
+        if ranges.is_empty()
+            || ranges
+                .iter()
+                .any(|&(first, last)| first == 0 || first > last)
+        {
+            return Err(InvalidPortRanges);
+        }
+        Ok(PortRanges(ranges))
+    }
+
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.0
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| first <= port && port <= last)
+    }
+
+    /// Returns the port if the set contains exactly one port.
+    pub fn single(&self) -> Option<u16> {
+        match self.0[..] {
+            [(first, last)] if first == last => Some(first),
+            _ => None,
+        }
+    }
+
+    /// Returns the ranges of ports that are both in this set and in `ranges`.
+    pub fn intersect(&self, ranges: &[(u16, u16)]) -> Vec<(u16, u16)> {
+        let mut intersection = vec![];
+        for &(first, last) in &self.0 {
+            for &(other_first, other_last) in ranges {
+                let first = first.max(other_first);
+                let last = last.min(other_last);
+                if first <= last {
+                    intersection.push((first, last));
+                }
+            }
+        }
+        intersection
+    }
+}
+
+impl From<u16> for PortRanges {
+    fn from(port: u16) -> Self {
+        PortRanges(vec![(port, port)])
+    }
+}
+
+impl TryFrom<PortRangesRepr> for PortRanges {
+    type Error = InvalidPortRanges;
+
+    fn try_from(repr: PortRangesRepr) -> Result<Self, Self::Error> {
+        match repr {
+            PortRangesRepr::Port(port) => PortRanges::new(vec![(port, port)]),
+            PortRangesRepr::Ranges(ranges) => PortRanges::new(ranges),
+        }
+    }
+}
+
+impl From<PortRanges> for PortRangesRepr {
+    fn from(ports: PortRanges) -> Self {
+        match ports.single() {
+            Some(port) => PortRangesRepr::Port(port),
+            None => PortRangesRepr::Ranges(ports.0),
+        }
+    }
+}
+
+/// Parses a comma-separated list of ports and port ranges, e.g. `53,443,51820-51830`.
+impl FromStr for PortRanges {
+    type Err = InvalidPortRanges;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |port: &str| port.trim().parse::<u16>().map_err(|_| InvalidPortRanges);
+        let ranges = s
+            .split(',')
+            .map(|range| match range.split_once('-') {
+                Some((first, last)) => Ok((parse_port(first)?, parse_port(last)?)),
+                None => parse_port(range).map(|port| (port, port)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        PortRanges::new(ranges)
+    }
+}
+
+impl fmt::Display for PortRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &(first, last)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if first == last {
+                write!(f, "{}", first)?;
+            } else {
+                write!(f, "{}-{}", first, last)?;
             }
         }
+        Ok(())
     }
 }
 
@@ -465,25 +698,128 @@ impl fmt::Display for OpenVpnConstraints {
 #[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(default)]
 pub struct WireguardConstraints {
-    pub port: Constraint<u16>,
+    /// The ports that may be connected to. A random port in the set is picked on each attempt.
+    pub port: Constraint<PortRanges>,
     pub ip_version: Constraint<IpVersion>,
     pub use_multihop: bool,
     pub entry_location: Constraint<LocationConstraint>,
+    /// How the entry relay is selected when any entry location may be used.
+    pub entry_policy: EntryRelayPolicy,
+    /// A custom server to use as one of the hops when multihop is enabled, instead of a second
+    /// relay.
+    pub custom_hop: Option<CustomMultihopRelay>,
+}
+
+impl WireguardConstraints {
+    /// Returns whether both hops of the tunnel are relays selected by the relay selector.
+    pub fn is_relay_multihop(&self) -> bool {
+        self.use_multihop && self.custom_hop.is_none()
+    }
+
+    /// Returns the custom server to combine with the selected relay, if multihop is enabled.
+    pub fn custom_multihop_relay(&self) -> Option<&CustomMultihopRelay> {
+        self.custom_hop.as_ref().filter(|_| self.use_multihop)
+    }
+}
+
+/// A WireGuard server that is not a Mullvad relay, used as one of the hops of a multihop tunnel.
+/// The other hop is the relay selected using the normal location constraints. Both hops use the
+/// key and tunnel addresses of the device, so the server must accept them.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct CustomMultihopRelay {
+    pub hop: CustomHop,
+    /// Hostname or IP address of the server.
+    pub host: String,
+    pub port: u16,
+    pub public_key: PublicKey,
+}
+
+/// Which hop of a multihop tunnel a [`CustomMultihopRelay`] is used as.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CustomHop {
+    /// Traffic enters through the custom server and leaves through the selected relay.
+    Entry,
+    /// Traffic enters through the selected relay and leaves through the custom server, which
+    /// must answer pings and DNS requests at `ipv4_gateway`.
+    Exit { ipv4_gateway: Ipv4Addr },
+}
+
+impl fmt::Display for CustomMultihopRelay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.hop {
+            CustomHop::Entry => write!(f, "custom entry {}:{}", self.host, self.port),
+            CustomHop::Exit { .. } => write!(f, "custom exit {}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Selects how the entry relay of a multihop tunnel is chosen.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryRelayPolicy {
+    /// Pick a random relay that matches the entry constraints.
+    #[default]
+    Random,
+    /// Pick a relay that minimizes the combined distance from the client to the entry relay and
+    /// from the entry relay to the exit relay. Only applies if the entry location is `any`.
+    ShortestPath,
+}
+
+impl fmt::Display for EntryRelayPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryRelayPolicy::Random => "random".fmt(f),
+            EntryRelayPolicy::ShortestPath => "shortest path".fmt(f),
+        }
+    }
+}
+
+/// How the tunnel protocol is chosen when the relay constraints allow any tunnel protocol. OpenVPN
+/// is never chosen on Android.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolPreference {
+    /// Chooses WireGuard or OpenVPN at random, in proportion to the given weights.
+    Weighted { wireguard: u32, openvpn: u32 },
+    /// Chooses WireGuard unless a WireGuard tunnel has failed since the daemon started.
+    WireguardUnlessFailed,
+}
+
+impl fmt::Display for ProtocolPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolPreference::Weighted { wireguard, openvpn } => {
+                write!(
+                    f,
+                    "WireGuard weight {}, OpenVPN weight {}",
+                    wireguard, openvpn
+                )
+            }
+            ProtocolPreference::WireguardUnlessFailed => "WireGuard unless it has failed".fmt(f),
+        }
+    }
 }
 
 impl fmt::Display for WireguardConstraints {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match self.port {
+        match &self.port {
             Constraint::Any => write!(f, "any port")?,
-            Constraint::Only(port) => write!(f, "port {}", port)?,
+            Constraint::Only(ports) if ports.single().is_some() => write!(f, "port {}", ports)?,
+            Constraint::Only(ports) => write!(f, "ports {}", ports)?,
         }
         write!(f, " over ")?;
         match self.ip_version {
             Constraint::Any => write!(f, "IPv4 or IPv6")?,
             Constraint::Only(protocol) => write!(f, "{}", protocol)?,
         }
-        if self.use_multihop {
+        if let Some(custom_hop) = self.custom_multihop_relay() {
+            write!(f, " (with {})", custom_hop)
+        } else if self.use_multihop {
             match &self.entry_location {
+                Constraint::Any if self.entry_policy == EntryRelayPolicy::ShortestPath => {
+                    write!(f, " (via the shortest path)")
+                }
                 Constraint::Any => write!(f, " (via any location)"),
                 Constraint::Only(location) => write!(f, " (via {})", location),
             }
@@ -522,6 +858,39 @@ impl fmt::Display for SelectedObfuscation {
     }
 }
 
+/// An obfuscation method that the automatic obfuscation mode may use.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObfuscationMethod {
+    Off,
+    Udp2Tcp,
+}
+
+impl fmt::Display for ObfuscationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObfuscationMethod::Off => "off".fmt(f),
+            ObfuscationMethod::Udp2Tcp => "udp2tcp".fmt(f),
+        }
+    }
+}
+
+impl FromStr for ObfuscationMethod {
+    type Err = InvalidObfuscationMethod;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ObfuscationMethod::Off),
+            "udp2tcp" => Ok(ObfuscationMethod::Udp2Tcp),
+            _ => Err(InvalidObfuscationMethod),
+        }
+    }
+}
+
+#[derive(err_derive::Error, Debug, Clone, PartialEq, Eq)]
+#[error(display = "Not a valid obfuscation method")]
+pub struct InvalidObfuscationMethod;
+
 #[derive(Default, Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Udp2TcpObfuscationSettings {
@@ -538,12 +907,29 @@ impl fmt::Display for Udp2TcpObfuscationSettings {
 }
 
 /// Contains obfuscation settings
-#[derive(Default, Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(default)]
 pub struct ObfuscationSettings {
     pub selected_obfuscation: SelectedObfuscation,
     pub udp2tcp: Udp2TcpObfuscationSettings,
+    /// Location of the relay that obfuscated traffic is sent to. This is the entry relay when
+    /// multihop is used.
+    pub location: Constraint<LocationConstraint>,
+    /// Order in which the automatic obfuscation mode tries obfuscation methods. Each method is
+    /// used for two attempts in a row, after which the next method is tried.
+    pub priority: Vec<ObfuscationMethod>,
+}
+
+impl Default for ObfuscationSettings {
+    fn default() -> Self {
+        ObfuscationSettings {
+            selected_obfuscation: SelectedObfuscation::default(),
+            udp2tcp: Udp2TcpObfuscationSettings::default(),
+            location: Constraint::Any,
+            priority: vec![ObfuscationMethod::Off, ObfuscationMethod::Udp2Tcp],
+        }
+    }
 }
 
 /// Limits the set of bridge servers to use in `mullvad-daemon`.
@@ -554,6 +940,7 @@ pub struct BridgeConstraints {
     pub location: Constraint<LocationConstraint>,
     pub providers: Constraint<Providers>,
     pub ownership: Constraint<Ownership>,
+    pub ip_version: Constraint<IpVersion>,
 }
 
 impl fmt::Display for BridgeConstraints {
@@ -568,11 +955,15 @@ impl fmt::Display for BridgeConstraints {
             Constraint::Only(ref constraint) => constraint.fmt(f)?,
         }
         match self.ownership {
-            Constraint::Any => Ok(()),
+            Constraint::Any => (),
             Constraint::Only(ref constraint) => {
-                write!(f, " and {}", constraint)
+                write!(f, " and {}", constraint)?;
             }
         }
+        match self.ip_version {
+            Constraint::Any => Ok(()),
+            Constraint::Only(ip_version) => write!(f, " over {}", ip_version),
+        }
     }
 }
 
@@ -605,6 +996,9 @@ pub struct InternalBridgeConstraints {
     pub providers: Constraint<Providers>,
     pub ownership: Constraint<Ownership>,
     pub transport_protocol: Constraint<TransportProtocol>,
+    pub ip_version: Constraint<IpVersion>,
+    /// Locations that bridges are never selected from.
+    pub avoided_locations: Vec<LocationConstraint>,
 }
 
 /// Used to update the [`RelaySettings`] used in `mullvad-daemon`.
@@ -632,7 +1026,8 @@ impl RelaySettingsUpdate {
                     port: Constraint::Only(TransportPort {
                         protocol: TransportProtocol::Udp,
                         ..
-                    })
+                    }),
+                    ..
                 })
             ),
         }
@@ -640,7 +1035,7 @@ impl RelaySettingsUpdate {
 }
 
 /// Used in [`RelaySettings`] to change relay constraints in the daemon.
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 #[cfg_attr(target_os = "android", derive(FromJava))]
 #[cfg_attr(target_os = "android", jnix(package = "net.mullvad.mullvadvpn.model"))]
 #[serde(default)]
@@ -656,4 +1051,10 @@ pub struct RelayConstraintsUpdate {
     pub wireguard_constraints: Option<WireguardConstraints>,
     #[cfg_attr(target_os = "android", jnix(default))]
     pub openvpn_constraints: Option<OpenVpnConstraints>,
+    #[cfg_attr(target_os = "android", jnix(default))]
+    pub endpoint_subnet: Option<Constraint<IpNetwork>>,
+    #[cfg_attr(target_os = "android", jnix(default))]
+    pub excluded_relays: Option<Vec<Hostname>>,
+    #[cfg_attr(target_os = "android", jnix(default))]
+    pub avoided_locations: Option<Vec<LocationConstraint>>,
 }
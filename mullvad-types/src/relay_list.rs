@@ -1,8 +1,13 @@
-use crate::location::{CityCode, CountryCode, Location};
+use crate::location::{CityCode, CountryCode, Hostname, Location};
 #[cfg(target_os = "android")]
 use jnix::IntoJava;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
 use talpid_types::net::{
     openvpn::{ProxySettings, ShadowsocksProxySettings},
     wireguard, TransportProtocol,
@@ -24,12 +29,128 @@ pub struct RelayList {
     pub bridge: BridgeEndpointData,
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub wireguard: WireguardEndpointData,
+    /// Where the relay list was obtained from.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    #[serde(default)]
+    pub source: RelayListSource,
+}
+
+/// Where relay lists are obtained from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayListSource {
+    /// The official relay list from the Mullvad API.
+    Api,
+    /// A mirror of the relay list served by the Mullvad API, for environments where the API
+    /// cannot be reached.
+    Url(String),
+    /// A relay list file on disk, in the format served by the Mullvad API.
+    File(PathBuf),
+}
+
+impl RelayListSource {
+    /// Returns whether relay lists from this source are obtained from somewhere other than the
+    /// Mullvad API.
+    pub fn is_external(&self) -> bool {
+        *self != RelayListSource::Api
+    }
+}
+
+impl Default for RelayListSource {
+    fn default() -> Self {
+        RelayListSource::Api
+    }
+}
+
+impl fmt::Display for RelayListSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayListSource::Api => write!(f, "Mullvad API"),
+            RelayListSource::Url(url) => write!(f, "URL {}", url),
+            RelayListSource::File(path) => write!(f, "file {}", path.display()),
+        }
+    }
 }
 
 impl RelayList {
     pub fn empty() -> Self {
         Self::default()
     }
+
+    /// Applies the changes in `delta` to the relay list. Changed relays replace the relays with
+    /// the same hostname, and cities and countries that no longer have any relays are removed.
+    /// The tag and endpoint data are replaced by those of the delta.
+    pub fn apply_delta(&mut self, delta: RelayListDelta) {
+        let RelayListDelta {
+            changed, removed, ..
+        } = delta;
+        let removed: HashSet<Hostname> = removed
+            .into_iter()
+            .chain(
+                changed
+                    .countries
+                    .iter()
+                    .flat_map(|country| &country.cities)
+                    .flat_map(|city| &city.relays)
+                    .map(|relay| relay.hostname.clone()),
+            )
+            .collect();
+
+        for country in &mut self.countries {
+            for city in &mut country.cities {
+                city.relays
+                    .retain(|relay| !removed.contains(&relay.hostname));
+            }
+        }
+
+        for changed_country in changed.countries {
+            let country = match self
+                .countries
+                .iter_mut()
+                .find(|country| country.code == changed_country.code)
+            {
+                Some(country) => country,
+                None => {
+                    self.countries.push(changed_country);
+                    continue;
+                }
+            };
+            for changed_city in changed_country.cities {
+                match country
+                    .cities
+                    .iter_mut()
+                    .find(|city| city.code == changed_city.code)
+                {
+                    Some(city) => city.relays.extend(changed_city.relays),
+                    None => country.cities.push(changed_city),
+                }
+            }
+        }
+
+        for country in &mut self.countries {
+            country.cities.retain(|city| !city.relays.is_empty());
+        }
+        self.countries.retain(|country| !country.cities.is_empty());
+        self.countries.sort_by(|a, b| a.code.cmp(&b.code));
+
+        self.etag = changed.etag;
+        self.openvpn = changed.openvpn;
+        self.bridge = changed.bridge;
+        self.wireguard = changed.wireguard;
+    }
+}
+
+/// Changes to the relay list since the relay list with the tag `base_etag`, obtained from the API
+/// using `mullvad_api::RelayListProxy`.
+#[derive(Debug, Clone)]
+pub struct RelayListDelta {
+    /// Tag of the relay list that the changes apply to.
+    pub base_etag: String,
+    /// Relays that were added or changed, along with the tag and endpoint data of the new relay
+    /// list.
+    pub changed: RelayList,
+    /// Hostnames of relays that were removed.
+    pub removed: Vec<Hostname>,
 }
 
 /// A list of [`RelayListCity`]s within a country. Used by [`RelayList`].
@@ -76,6 +197,10 @@ pub struct Relay {
     pub provider: String,
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub weight: u64,
+    /// Current load of the relay in percent, if reported by the API.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    #[serde(default)]
+    pub load: Option<u8>,
     pub endpoint_data: RelayEndpointData,
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub location: Option<Location>,
@@ -147,6 +272,26 @@ impl Default for WireguardEndpointData {
 pub struct WireguardRelayEndpointData {
     /// Public key used by the relay peer
     pub public_key: wireguard::PublicKey,
+    /// Whether the relay can be used as the entry relay of a multihop tunnel
+    #[serde(default = "default_multihop_entry")]
+    pub multihop_entry: bool,
+    /// Names of the pluggable transports that the relay accepts obfuscated traffic through
+    #[serde(default)]
+    pub pluggable_transports: Vec<String>,
+    /// Whether the relay supports negotiating a post-quantum safe PSK
+    #[serde(default = "default_quantum_resistant")]
+    pub quantum_resistant: bool,
+    /// Whether the relay supports DAITA
+    #[serde(default)]
+    pub daita: bool,
+}
+
+fn default_multihop_entry() -> bool {
+    true
+}
+
+fn default_quantum_resistant() -> bool {
+    true
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
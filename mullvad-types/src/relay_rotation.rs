@@ -0,0 +1,164 @@
+use chrono::{NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    #[error(display = "The rotation interval must be at least one hour")]
+    IntervalTooShort,
+
+    #[error(display = "{:02}:{:02} is not a valid time of day", _0, _1)]
+    InvalidTimeOfDay(u32, u32),
+}
+
+/// When the daemon should reconnect to a newly selected relay while the tunnel is up. The new
+/// relay is selected using the normal relay constraints.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayRotationPolicy {
+    /// Relays are not rotated on a schedule.
+    Off,
+    /// Rotate after being connected to the same relay for this many hours.
+    Interval { hours: u32 },
+    /// Rotate every day at this local time.
+    Daily { hour: u32, minute: u32 },
+}
+
+impl Default for RelayRotationPolicy {
+    fn default() -> Self {
+        RelayRotationPolicy::Off
+    }
+}
+
+impl RelayRotationPolicy {
+    pub fn validate(&self) -> Result<(), Error> {
+        match *self {
+            RelayRotationPolicy::Off => Ok(()),
+            RelayRotationPolicy::Interval { hours } if hours == 0 => Err(Error::IntervalTooShort),
+            RelayRotationPolicy::Interval { .. } => Ok(()),
+            RelayRotationPolicy::Daily { hour, minute } => NaiveTime::from_hms_opt(hour, minute, 0)
+                .map(|_| ())
+                .ok_or(Error::InvalidTimeOfDay(hour, minute)),
+        }
+    }
+
+    /// Returns how long to stay connected before rotating relays, given that the tunnel came up
+    /// at the local time `connected_at`. Returns `None` if relays should not be rotated, or if
+    /// the policy is invalid.
+    pub fn time_until_rotation(&self, connected_at: NaiveDateTime) -> Option<Duration> {
+        self.validate().ok()?;
+        match *self {
+            RelayRotationPolicy::Off => None,
+            RelayRotationPolicy::Interval { hours } => {
+                Some(Duration::from_secs(u64::from(hours) * 60 * 60))
+            }
+            RelayRotationPolicy::Daily { hour, minute } => {
+                let mut rotation_at = connected_at
+                    .date()
+                    .and_time(NaiveTime::from_hms_opt(hour, minute, 0)?);
+                if rotation_at <= connected_at {
+                    rotation_at += chrono::Duration::days(1);
+                }
+                (rotation_at - connected_at).to_std().ok()
+            }
+        }
+    }
+}
+
+impl fmt::Display for RelayRotationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayRotationPolicy::Off => write!(f, "off"),
+            RelayRotationPolicy::Interval { hours } => write!(f, "every {} hours", hours),
+            RelayRotationPolicy::Daily { hour, minute } => {
+                write!(f, "daily at {:02}:{:02}", hour, minute)
+            }
+        }
+    }
+}
+
+/// Sent when the daemon reconnects to rotate relays according to the rotation policy.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct RelayRotationEvent {
+    /// Hostname of the relay that is rotated away from, if it is known.
+    pub previous_relay: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_time_until_rotation() {
+        let connected_at = NaiveDate::from_ymd(2022, 6, 1).and_hms(10, 30, 0);
+
+        assert_eq!(
+            RelayRotationPolicy::Off.time_until_rotation(connected_at),
+            None
+        );
+        assert_eq!(
+            RelayRotationPolicy::Interval { hours: 2 }.time_until_rotation(connected_at),
+            Some(Duration::from_secs(2 * 60 * 60))
+        );
+
+        // Later the same day
+        assert_eq!(
+            RelayRotationPolicy::Daily {
+                hour: 11,
+                minute: 0
+            }
+            .time_until_rotation(connected_at),
+            Some(Duration::from_secs(30 * 60))
+        );
+        // The time has already passed today, or is right now
+        assert_eq!(
+            RelayRotationPolicy::Daily {
+                hour: 10,
+                minute: 0
+            }
+            .time_until_rotation(connected_at),
+            Some(Duration::from_secs(23 * 60 * 60 + 30 * 60))
+        );
+        assert_eq!(
+            RelayRotationPolicy::Daily {
+                hour: 10,
+                minute: 30
+            }
+            .time_until_rotation(connected_at),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(
+            RelayRotationPolicy::Interval { hours: 0 }.validate(),
+            Err(Error::IntervalTooShort)
+        );
+        assert_eq!(
+            RelayRotationPolicy::Daily {
+                hour: 24,
+                minute: 0
+            }
+            .validate(),
+            Err(Error::InvalidTimeOfDay(24, 0))
+        );
+        assert_eq!(
+            RelayRotationPolicy::Daily {
+                hour: 23,
+                minute: 59
+            }
+            .validate(),
+            Ok(())
+        );
+        assert_eq!(
+            RelayRotationPolicy::Daily {
+                hour: 25,
+                minute: 0
+            }
+            .time_until_rotation(NaiveDate::from_ymd(2022, 6, 1).and_hms(0, 0, 0)),
+            None
+        );
+    }
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+use talpid_types::net::TunnelType;
+
+/// Sent whenever the daemon has selected relays for a connection attempt, or failed to.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct RelaySelectionEvent {
+    pub retry_attempt: u32,
+    /// The selected relays, or the reason why no relays could be selected.
+    pub result: Result<SelectedRelays, String>,
+}
+
+/// Relays selected for a single connection attempt.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SelectedRelays {
+    pub tunnel_type: TunnelType,
+    /// Hostname of the exit relay. This is `None` for custom relays.
+    pub exit: Option<String>,
+    pub entry: Option<String>,
+    pub bridge: Option<String>,
+    pub obfuscator: Option<String>,
+    /// Explanation of why these relays were selected, for displaying to the user.
+    pub explanation: String,
+}
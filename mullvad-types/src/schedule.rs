@@ -0,0 +1,137 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A recurring period during which the tunnel should be connected. Times are in local time.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ConnectionSchedule {
+    /// Days on which the period starts.
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    /// End of the period. If this is not after `start`, the period ends on the following day.
+    pub end: NaiveTime,
+}
+
+impl ConnectionSchedule {
+    /// Returns the periods that start on the days from `first` up to, but not including, `last`.
+    fn periods(
+        &self,
+        first: NaiveDate,
+        last: NaiveDate,
+    ) -> impl Iterator<Item = (NaiveDateTime, NaiveDateTime)> + '_ {
+        let days = (last - first).num_days();
+        (0..days)
+            .map(move |offset| first + Duration::days(offset))
+            .filter(|date| self.days.contains(&date.weekday()))
+            .map(|date| {
+                let end_date = if self.end > self.start {
+                    date
+                } else {
+                    date + Duration::days(1)
+                };
+                (date.and_time(self.start), end_date.and_time(self.end))
+            })
+    }
+}
+
+impl fmt::Display for ConnectionSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days: Vec<String> = self.days.iter().map(|day| day.to_string()).collect();
+        write!(
+            f,
+            "{} {}-{}",
+            days.join(","),
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Returns whether `time` is within a period of any of the `schedules`.
+pub fn is_active(schedules: &[ConnectionSchedule], time: NaiveDateTime) -> bool {
+    // Periods that cross midnight may have started on the previous day
+    let first = time.date() - Duration::days(1);
+    let last = time.date() + Duration::days(1);
+    schedules
+        .iter()
+        .flat_map(|schedule| schedule.periods(first, last))
+        .any(|(start, end)| start <= time && time < end)
+}
+
+/// Returns the first time after `time` when a period of any of the `schedules` starts or ends.
+/// Periods that overlap are not merged, so whether the schedules are active may not change then.
+pub fn next_change(schedules: &[ConnectionSchedule], time: NaiveDateTime) -> Option<NaiveDateTime> {
+    let first = time.date() - Duration::days(1);
+    let last = time.date() + Duration::days(8);
+    schedules
+        .iter()
+        .flat_map(|schedule| schedule.periods(first, last))
+        .flat_map(|(start, end)| [start, end])
+        .filter(|boundary| *boundary > time)
+        .min()
+}
+
+/// Whether the connection schedules currently call for the tunnel to be connected.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ScheduleState {
+    pub active: bool,
+    /// When a period starts or ends next, or `None` if there are no schedules.
+    pub next_change: Option<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(date: &str, time: &str) -> NaiveDateTime {
+        NaiveDateTime::new(date.parse().unwrap(), time.parse().unwrap())
+    }
+
+    #[test]
+    fn test_schedules() {
+        let schedules = vec![
+            ConnectionSchedule {
+                days: vec![
+                    Weekday::Mon,
+                    Weekday::Tue,
+                    Weekday::Wed,
+                    Weekday::Thu,
+                    Weekday::Fri,
+                ],
+                start: "09:00:00".parse().unwrap(),
+                end: "17:00:00".parse().unwrap(),
+            },
+            ConnectionSchedule {
+                days: vec![Weekday::Sat],
+                start: "22:00:00".parse().unwrap(),
+                end: "02:00:00".parse().unwrap(),
+            },
+        ];
+
+        // 2022-11-18 is a Friday
+        assert!(!is_active(&schedules, time("2022-11-18", "08:59:59")));
+        assert!(is_active(&schedules, time("2022-11-18", "09:00:00")));
+        assert!(!is_active(&schedules, time("2022-11-18", "17:00:00")));
+        assert!(is_active(&schedules, time("2022-11-19", "23:00:00")));
+        assert!(is_active(&schedules, time("2022-11-20", "01:00:00")));
+        assert!(!is_active(&schedules, time("2022-11-20", "02:00:00")));
+
+        assert_eq!(
+            next_change(&schedules, time("2022-11-18", "12:00:00")),
+            Some(time("2022-11-18", "17:00:00"))
+        );
+        assert_eq!(
+            next_change(&schedules, time("2022-11-18", "17:00:00")),
+            Some(time("2022-11-19", "22:00:00"))
+        );
+        assert_eq!(
+            next_change(&schedules, time("2022-11-19", "23:00:00")),
+            Some(time("2022-11-20", "02:00:00"))
+        );
+        assert_eq!(
+            next_change(&schedules, time("2022-11-20", "02:00:00")),
+            Some(time("2022-11-21", "09:00:00"))
+        );
+        assert_eq!(next_change(&[], time("2022-11-20", "02:00:00")), None);
+    }
+}
@@ -1,7 +1,26 @@
 #[cfg(target_os = "android")]
 use jnix::{jni::objects::JObject, FromJava, IntoJava, JnixEnv};
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    #[error(display = "\"{}\" is not a https:// or tls:// URL", _0)]
+    InvalidScheme(String),
+
+    #[error(display = "\"{}\" has no host", _0)]
+    MissingHost(String),
+
+    #[error(display = "\"{}\" has an invalid port", _0)]
+    InvalidPort(String),
+
+    #[error(display = "Only the /dns-query path is supported for DNS over HTTPS")]
+    UnsupportedPath,
+}
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 #[serde(rename_all = "snake_case")]
@@ -49,6 +68,7 @@ impl From<AndroidDnsOptions> for DnsOptions {
             default_options: DefaultDnsOptions::default(),
             custom_options: CustomDnsOptions {
                 addresses: options.addresses,
+                encrypted_resolvers: vec![],
             },
         }
     }
@@ -81,4 +101,177 @@ pub struct DefaultDnsOptions {
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub struct CustomDnsOptions {
     pub addresses: Vec<IpAddr>,
+    /// Resolvers that are reached over DNS over HTTPS or DNS over TLS. If there are any, they are
+    /// used instead of `addresses`.
+    #[serde(default)]
+    pub encrypted_resolvers: Vec<EncryptedDnsResolver>,
+}
+
+/// Protocol used to encrypt DNS queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptedDnsProtocol {
+    /// DNS over HTTPS
+    Https,
+    /// DNS over TLS
+    Tls,
+}
+
+impl EncryptedDnsProtocol {
+    fn scheme(&self) -> &'static str {
+        match self {
+            EncryptedDnsProtocol::Https => "https",
+            EncryptedDnsProtocol::Tls => "tls",
+        }
+    }
+
+    fn default_port(&self) -> u16 {
+        match self {
+            EncryptedDnsProtocol::Https => 443,
+            EncryptedDnsProtocol::Tls => 853,
+        }
+    }
+}
+
+/// A DNS resolver that is reached over DNS over HTTPS or DNS over TLS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct EncryptedDnsResolver {
+    pub protocol: EncryptedDnsProtocol,
+    /// Name that the certificate of the resolver is verified against.
+    pub server_name: String,
+    /// Address of the resolver. It cannot be looked up using DNS once queries are sent to the
+    /// resolver, so resolvers given by name are looked up when they are added.
+    pub address: SocketAddr,
+}
+
+impl fmt::Display for EncryptedDnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = EncryptedDnsUrl {
+            protocol: self.protocol,
+            host: self.server_name.clone(),
+            port: self.address.port(),
+        };
+        write!(f, "{} ({})", url, self.address.ip())
+    }
+}
+
+/// The URL of an encrypted DNS resolver, such as `https://dns.example.com/dns-query` or
+/// `tls://192.0.2.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedDnsUrl {
+    pub protocol: EncryptedDnsProtocol,
+    /// Host name or IP address of the resolver.
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for EncryptedDnsUrl {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (EncryptedDnsProtocol::Https, rest)
+        } else if let Some(rest) = url.strip_prefix("tls://") {
+            (EncryptedDnsProtocol::Tls, rest)
+        } else {
+            return Err(Error::InvalidScheme(url.to_owned()));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let path_supported = match protocol {
+            EncryptedDnsProtocol::Https => ["", "/", "/dns-query"].contains(&path),
+            EncryptedDnsProtocol::Tls => ["", "/"].contains(&path),
+        };
+        if !path_supported {
+            return Err(Error::UnsupportedPath);
+        }
+
+        // IPv6 addresses are enclosed in brackets, since they contain colons
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => return Err(Error::MissingHost(url.to_owned())),
+            },
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(Error::MissingHost(url.to_owned()));
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| Error::InvalidPort(url.to_owned()))?,
+            None => protocol.default_port(),
+        };
+
+        Ok(EncryptedDnsUrl {
+            protocol,
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for EncryptedDnsUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", self.protocol.scheme())?;
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(address)) => write!(f, "[{}]", address)?,
+            _ => write!(f, "{}", self.host)?,
+        }
+        if self.port != self.protocol.default_port() {
+            write!(f, ":{}", self.port)?;
+        }
+        if self.protocol == EncryptedDnsProtocol::Https {
+            write!(f, "/dns-query")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_encrypted_dns_url() {
+        let url: EncryptedDnsUrl = "https://dns.example.com/dns-query".parse().unwrap();
+        assert_eq!(
+            url,
+            EncryptedDnsUrl {
+                protocol: EncryptedDnsProtocol::Https,
+                host: "dns.example.com".to_owned(),
+                port: 443,
+            }
+        );
+        assert_eq!(url.to_string(), "https://dns.example.com/dns-query");
+
+        let url: EncryptedDnsUrl = "tls://[2001:db8::1]:8853".parse().unwrap();
+        assert_eq!(
+            url,
+            EncryptedDnsUrl {
+                protocol: EncryptedDnsProtocol::Tls,
+                host: "2001:db8::1".to_owned(),
+                port: 8853,
+            }
+        );
+        assert_eq!(url.to_string(), "tls://[2001:db8::1]:8853");
+
+        assert_eq!(
+            "tls://192.0.2.1".parse::<EncryptedDnsUrl>().unwrap().port,
+            853
+        );
+        assert!("http://192.0.2.1".parse::<EncryptedDnsUrl>().is_err());
+        assert!("https://192.0.2.1/resolve"
+            .parse::<EncryptedDnsUrl>()
+            .is_err());
+        assert!("tls://192.0.2.1:port".parse::<EncryptedDnsUrl>().is_err());
+        assert!("tls://".parse::<EncryptedDnsUrl>().is_err());
+    }
 }
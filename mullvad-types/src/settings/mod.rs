@@ -1,11 +1,21 @@
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::excluded_destinations::ExcludedDestinations;
 use crate::{
+    custom_list::CustomListsSettings,
+    escalation::EscalationLadder,
     relay_constraints::{
         BridgeConstraints, BridgeSettings, BridgeState, Constraint, LocationConstraint,
-        ObfuscationSettings, RelayConstraints, RelaySettings, RelaySettingsUpdate,
-        SelectedObfuscation, WireguardConstraints,
+        ObfuscationSettings, ProtocolPreference, RelayConstraints, RelaySettings,
+        RelaySettingsUpdate, SelectedObfuscation, TransportPort, WireguardConstraints,
     },
+    relay_list::RelayListSource,
+    relay_rotation::RelayRotationPolicy,
     wireguard,
 };
+#[cfg(not(target_os = "android"))]
+use crate::{
+    dns_blocklist::DnsBlocklist, network_rules::NetworkRules, schedule::ConnectionSchedule,
+};
 #[cfg(target_os = "android")]
 use jnix::IntoJava;
 use rand::Rng;
@@ -13,6 +23,10 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 #[cfg(target_os = "windows")]
 use std::{collections::HashSet, path::PathBuf};
 use talpid_types::net::{self, openvpn, GenericTunnelOptions};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use talpid_types::{
+    firewall_exception::FirewallException, lan::LanRules, split_tunnel::SplitTunnelMode,
+};
 
 mod dns;
 
@@ -20,7 +34,7 @@ mod dns;
 /// latest version that exists in `SettingsVersion`.
 /// This should be bumped when a new version is introduced along with a migration
 /// being added to `mullvad-daemon`.
-pub const CURRENT_SETTINGS_VERSION: SettingsVersion = SettingsVersion::V6;
+pub const CURRENT_SETTINGS_VERSION: SettingsVersion = SettingsVersion::V7;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
 #[repr(u32)]
@@ -30,6 +44,7 @@ pub enum SettingsVersion {
     V4 = 4,
     V5 = 5,
     V6 = 6,
+    V7 = 7,
 }
 
 impl<'de> Deserialize<'de> for SettingsVersion {
@@ -43,6 +58,7 @@ impl<'de> Deserialize<'de> for SettingsVersion {
             v if v == SettingsVersion::V4 as u32 => Ok(SettingsVersion::V4),
             v if v == SettingsVersion::V5 as u32 => Ok(SettingsVersion::V5),
             v if v == SettingsVersion::V6 as u32 => Ok(SettingsVersion::V6),
+            v if v == SettingsVersion::V7 as u32 => Ok(SettingsVersion::V7),
             v => Err(serde::de::Error::custom(format!(
                 "{} is not a valid SettingsVersion",
                 v
@@ -73,14 +89,65 @@ pub struct Settings {
     pub obfuscation_settings: ObfuscationSettings,
     #[cfg_attr(target_os = "android", jnix(skip))]
     bridge_state: BridgeState,
+    /// Named lists of locations that can be used as location constraints.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub custom_lists: CustomListsSettings,
+    /// Retry strategy to use instead of the built-in one when connection attempts fail.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub escalation_ladder: EscalationLadder,
+    /// Whether relays with a high load should be less likely to be selected.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub load_aware_selection: bool,
+    /// Number of exit relays of the most recent successful connections that should be avoided
+    /// when selecting relays. Zero disables this.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub avoid_recent_relays: u32,
+    /// Whether relay endpoints should be reached over IPv6 when the host has IPv6 connectivity
+    /// and the IP version is not constrained.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub prefer_ipv6: bool,
+    /// Whether the obfuscator of the last connection should be reused when reconnecting, as long
+    /// as it is still online and satisfies the constraints.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub reuse_obfuscator: bool,
+    /// Whether the latency to relays should be measured while disconnected, so that relays with a
+    /// lower latency are preferred.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub latency_aware_selection: bool,
+    /// Number of failed connection attempts after which the exit relay of the last successful
+    /// connection is no longer reused when reconnecting. Zero disables reusing it.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub sticky_relay_max_failures: u32,
+    /// How the tunnel protocol is chosen when the relay constraints allow any tunnel protocol. The
+    /// relay selector chooses it based on the retry attempt if this is `None`.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub protocol_preference: Option<ProtocolPreference>,
+    /// Where relay lists are obtained from.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub relay_list_source: RelayListSource,
     /// If the daemon should allow communication with private (LAN) networks.
     pub allow_lan: bool,
+    /// Restricts the local network traffic that is allowed when `allow_lan` is set. All of it is
+    /// allowed if this is `None`.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub lan_rules: Option<LanRules>,
     /// Extra level of kill switch. When this setting is on, the disconnected state will block
     /// the firewall to not allow any traffic in or out.
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub block_when_disconnected: bool,
     /// If the daemon should connect the VPN tunnel directly on start or not.
     pub auto_connect: bool,
+    /// Rules for connecting and disconnecting depending on the network that the host is on.
+    #[cfg(not(target_os = "android"))]
+    pub network_rules: NetworkRules,
+    /// Recurring periods during which the tunnel is connected. The daemon connects and
+    /// disconnects when a period starts or ends.
+    #[cfg(not(target_os = "android"))]
+    pub schedules: Vec<ConnectionSchedule>,
+    /// Lists of domains that are blocked by the local DNS forwarder, in addition to the content
+    /// blockers in the DNS options.
+    #[cfg(not(target_os = "android"))]
+    pub dns_blocklists: Vec<DnsBlocklist>,
     /// Options that should be applied to tunnels of a specific type regardless of where the relays
     /// might be located.
     pub tunnel_options: TunnelOptions,
@@ -89,6 +156,17 @@ pub struct Settings {
     /// Split tunneling settings
     #[cfg(windows)]
     pub split_tunnel: SplitTunnelSettings,
+    /// Networks and domains whose traffic is sent outside the tunnel
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub excluded_destinations: ExcludedDestinations,
+    /// Whether the processes that are selected for split tunneling are excluded from the tunnel,
+    /// or are the only ones that use it
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub split_tunnel_mode: SplitTunnelMode,
+    /// Traffic that the firewall allows whenever it is active, in addition to what is allowed by
+    /// the other settings.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub firewall_exceptions: Vec<FirewallException>,
     /// Temporary variable for a random number between 0 and 1 that determines if the user should
     /// use wireguard or openvpn when the automatic feature is set. This variable will be removed
     /// in future versions.
@@ -132,14 +210,38 @@ impl Default for Settings {
                 ..Default::default()
             },
             bridge_state: BridgeState::Auto,
+            custom_lists: CustomListsSettings::default(),
+            escalation_ladder: EscalationLadder::default(),
+            load_aware_selection: true,
+            avoid_recent_relays: 0,
+            prefer_ipv6: false,
+            reuse_obfuscator: false,
+            latency_aware_selection: false,
+            sticky_relay_max_failures: 0,
+            protocol_preference: None,
+            relay_list_source: RelayListSource::Api,
             allow_lan: false,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            lan_rules: None,
             block_when_disconnected: false,
             auto_connect: false,
+            #[cfg(not(target_os = "android"))]
+            network_rules: NetworkRules::default(),
+            #[cfg(not(target_os = "android"))]
+            schedules: vec![],
+            #[cfg(not(target_os = "android"))]
+            dns_blocklists: vec![],
             tunnel_options: TunnelOptions::default(),
             show_beta_releases: false,
             wg_migration_rand_num: rand::thread_rng().gen_range(0.0..=1.0),
             #[cfg(windows)]
             split_tunnel: SplitTunnelSettings::default(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            excluded_destinations: ExcludedDestinations::default(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            split_tunnel_mode: SplitTunnelMode::default(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            firewall_exceptions: vec![],
             settings_version: CURRENT_SETTINGS_VERSION,
         }
     }
@@ -204,9 +306,40 @@ pub struct TunnelOptions {
     pub generic: GenericTunnelOptions,
     /// DNS options.
     pub dns_options: DnsOptions,
+    /// Ports that tunnels are restricted to, depending on the tunnel protocol.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub protocol_ports: ProtocolPorts,
+    /// When to reconnect to a newly selected relay while connected.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub relay_rotation: RelayRotationPolicy,
+    /// Maximum number of relays that are selected when generating tunnel parameters, including
+    /// relays that are selected in place of rejected ones.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub max_repicks: u32,
 }
 
-pub use dns::{CustomDnsOptions, DefaultDnsOptions, DnsOptions, DnsState};
+/// Ports that tunnels of each tunnel protocol are restricted to, in addition to the relay
+/// constraints. This allows networks that only let each protocol through on a different port.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtocolPorts {
+    /// Port used when a WireGuard tunnel is selected.
+    pub wireguard: Constraint<u16>,
+    /// Port used when an OpenVPN tunnel is selected.
+    pub openvpn: Constraint<TransportPort>,
+}
+
+impl ProtocolPorts {
+    /// Returns whether no protocol is restricted to a port.
+    pub fn is_any(&self) -> bool {
+        self.wireguard.is_any() && self.openvpn.is_any()
+    }
+}
+
+pub use dns::{
+    CustomDnsOptions, DefaultDnsOptions, DnsOptions, DnsState, EncryptedDnsProtocol,
+    EncryptedDnsResolver, EncryptedDnsUrl, Error as DnsError,
+};
 
 #[cfg(target_os = "android")]
 pub use dns::AndroidDnsOptions;
@@ -218,12 +351,22 @@ impl Default for TunnelOptions {
             wireguard: wireguard::TunnelOptions {
                 options: net::wireguard::TunnelOptions::default(),
                 rotation_interval: None,
+                preferred_ip_version: Constraint::Any,
+                require_obfuscation: false,
+                implementation: wireguard::WireguardImplementation::default(),
+                quantum_resistant: wireguard::QuantumResistantState::default(),
+                ephemeral_keys: false,
+                port_fallback: wireguard::PortFallback::default(),
             },
             generic: GenericTunnelOptions {
                 // Enable IPv6 be default on Android
                 enable_ipv6: cfg!(target_os = "android"),
+                split_dns: vec![],
             },
             dns_options: DnsOptions::default(),
+            protocol_ports: ProtocolPorts::default(),
+            relay_rotation: RelayRotationPolicy::default(),
+            max_repicks: 10,
         }
     }
 }
@@ -0,0 +1,20 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Traffic through the tunnel interface, sampled at a point in time while connected.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TrafficSample {
+    pub timestamp: DateTime<Utc>,
+    /// Bytes received through the tunnel since it came up.
+    pub rx_bytes: u64,
+    /// Bytes sent through the tunnel since it came up.
+    pub tx_bytes: u64,
+    /// Packets received through the tunnel since it came up.
+    pub rx_packets: u64,
+    /// Packets sent through the tunnel since it came up.
+    pub tx_packets: u64,
+    /// Bytes per second received since the previous sample.
+    pub rx_rate: u64,
+    /// Bytes per second sent since the previous sample.
+    pub tx_rate: u64,
+}
@@ -1,10 +1,11 @@
 #![allow(clippy::identity_op)]
+use crate::relay_constraints::Constraint;
 use chrono::{offset::Utc, DateTime};
 #[cfg(target_os = "android")]
 use jnix::IntoJava;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{convert::TryFrom, fmt, time::Duration};
-use talpid_types::net::wireguard;
+use std::{collections::HashSet, convert::TryFrom, fmt, time::Duration};
+use talpid_types::net::{wireguard, IpVersion};
 
 pub const MIN_ROTATION_INTERVAL: Duration = Duration::from_secs(1 * 24 * 60 * 60);
 pub const MAX_ROTATION_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
@@ -21,9 +22,20 @@ pub struct WireguardData {
     pub addresses: AssociatedAddresses,
     #[serde(default = "Utc::now")]
     pub created: DateTime<Utc>,
+    /// Hostnames of the relays that the account may connect to, if it is restricted by policy.
+    #[serde(default)]
+    pub allowed_relays: Option<HashSet<String>>,
+    /// Time after which the relays no longer accept the key, if known.
+    #[serde(default)]
+    pub expiry: Option<DateTime<Utc>>,
 }
 
 impl WireguardData {
+    /// Returns whether the key is past its expiry at `now`. Keys without an expiry never expire.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expiry.map(|expiry| expiry <= now).unwrap_or(false)
+    }
+
     /// Create a public key
     pub fn get_public_key(&self) -> PublicKey {
         PublicKey {
@@ -127,6 +139,130 @@ pub struct TunnelOptions {
     /// Interval used for automatic key rotation
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub rotation_interval: Option<RotationInterval>,
+    /// IP version to use for the peer address of a relay that has both an IPv4 and an IPv6
+    /// address. `Any` uses the address chosen by the relay selector.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub preferred_ip_version: Constraint<IpVersion>,
+    /// Refuse to connect unless the tunnel is obfuscated, even if obfuscation is set to `auto`.
+    /// Ignored for OpenVPN tunnels.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub require_obfuscation: bool,
+    /// WireGuard implementation that tunnel parameters are generated for.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub implementation: WireguardImplementation,
+    /// Whether to negotiate a post-quantum safe PSK with the relay.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub quantum_resistant: QuantumResistantState,
+    /// Replace the device key with a new key for every connection, so that the tunnels of
+    /// different connections cannot be linked by their key. The new key is registered with the
+    /// relay through a tunnel that uses the device key.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub ephemeral_keys: bool,
+    /// Well-known ports to fall back to on later connection attempts.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub port_fallback: PortFallback,
+}
+
+impl TunnelOptions {
+    /// Returns the options to pass on to the tunnel, with the quantum resistance setting resolved.
+    pub fn to_talpid_tunnel_options(&self) -> wireguard::TunnelOptions {
+        wireguard::TunnelOptions {
+            use_pq_safe_psk: self.negotiates_ephemeral_key(),
+            ..self.options.clone()
+        }
+    }
+
+    /// Returns whether an ephemeral key is negotiated with the relay when connecting. The same
+    /// exchange registers the ephemeral key and establishes a post-quantum safe PSK, so this is
+    /// done if either quantum resistance or ephemeral keys are enabled.
+    pub fn negotiates_ephemeral_key(&self) -> bool {
+        self.quantum_resistant.is_enabled() || self.ephemeral_keys
+    }
+}
+
+/// Whether WireGuard tunnels negotiate a post-quantum safe PSK with the relay before the tunnel is
+/// used. Only relays that support the exchange are used while this is enabled.
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantumResistantState {
+    /// Let the app decide. This is currently the same as `Off`.
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+impl QuantumResistantState {
+    /// Returns whether a PSK should be negotiated.
+    pub fn is_enabled(&self) -> bool {
+        *self == QuantumResistantState::On
+    }
+}
+
+impl fmt::Display for QuantumResistantState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuantumResistantState::Auto => write!(f, "auto"),
+            QuantumResistantState::On => write!(f, "on"),
+            QuantumResistantState::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// Well-known ports that WireGuard tunnels fall back to on later connection attempts, for networks
+/// that block high UDP ports. This only applies when no WireGuard port is constrained, and the
+/// enabled ports take turns if more than one is enabled.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortFallback {
+    pub port_53: bool,
+    pub port_443: bool,
+}
+
+impl PortFallback {
+    /// Returns the enabled fallback ports, in the order they are tried.
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports = vec![];
+        if self.port_53 {
+            ports.push(53);
+        }
+        if self.port_443 {
+            ports.push(443);
+        }
+        ports
+    }
+}
+
+impl Default for PortFallback {
+    fn default() -> Self {
+        PortFallback {
+            port_53: true,
+            port_443: false,
+        }
+    }
+}
+
+impl fmt::Display for PortFallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ports = self.ports();
+        if ports.is_empty() {
+            return write!(f, "none");
+        }
+        let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+        write!(f, "{}", ports.join(", "))
+    }
+}
+
+/// WireGuard implementation used for the tunnel. This only affects the generated tunnel
+/// parameters where the implementations expect them to differ.
+#[derive(Default, Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireguardImplementation {
+    /// The kernel module.
+    #[default]
+    Kernel,
+    /// A userspace implementation, such as wireguard-go.
+    Userspace,
 }
 
 /// Represents a published public key
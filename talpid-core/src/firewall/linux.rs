@@ -14,7 +14,13 @@ use std::{
     fs, io,
     net::{IpAddr, Ipv4Addr},
 };
-use talpid_types::net::{AllowedTunnelTraffic, Endpoint, TransportProtocol};
+use talpid_types::{
+    firewall_exception::{self, FirewallException},
+    lan::{LanRules, LanService},
+    net::{AllowedTunnelTraffic, Endpoint, TransportProtocol},
+    split_tunnel::SplitTunnelMode,
+    ErrorExt,
+};
 
 /// Priority for rules that tag split tunneling packets. Equals NF_IP_PRI_MANGLE.
 const MANGLE_CHAIN_PRIORITY: i32 = libc::NF_IP_PRI_MANGLE;
@@ -361,11 +367,32 @@ impl<'a> PolicyBatch<'a> {
             }
         }
 
+        let split_tunnel_mode = match policy {
+            FirewallPolicy::Connecting {
+                split_tunnel_mode, ..
+            }
+            | FirewallPolicy::Connected {
+                split_tunnel_mode, ..
+            }
+            | FirewallPolicy::Blocked {
+                split_tunnel_mode, ..
+            } => *split_tunnel_mode,
+        };
         let mangle_chains = [&self.mangle_chain_v4, &self.mangle_chain_v6];
         for chain in &mangle_chains {
             let mut rule = Rule::new(chain);
             rule.add_expr(&nft_expr!(meta cgroup));
-            rule.add_expr(&nft_expr!(cmp == split_tunnel::NET_CLS_CLASSID));
+            match split_tunnel_mode {
+                SplitTunnelMode::Exclude => {
+                    rule.add_expr(&nft_expr!(cmp == split_tunnel::NET_CLS_CLASSID));
+                }
+                // Processes outside the cgroup are only excluded while connected, since their DNS
+                // requests would leak outside the tunnel otherwise
+                SplitTunnelMode::Include if matches!(policy, FirewallPolicy::Connected { .. }) => {
+                    rule.add_expr(&nft_expr!(cmp != split_tunnel::NET_CLS_CLASSID));
+                }
+                SplitTunnelMode::Include => continue,
+            }
             rule.add_expr(&nft_expr!(immediate data split_tunnel::MARK));
             rule.add_expr(&nft_expr!(ct mark set));
             rule.add_expr(&nft_expr!(immediate data fwmark));
@@ -373,6 +400,30 @@ impl<'a> PolicyBatch<'a> {
             self.batch.add(&rule, nftnl::MsgType::Add);
         }
 
+        // Excluded destinations are marked the same way as traffic from excluded processes
+        if let FirewallPolicy::Connecting {
+            excluded_networks, ..
+        }
+        | FirewallPolicy::Connected {
+            excluded_networks, ..
+        } = policy
+        {
+            for network in excluded_networks {
+                let chain = if network.is_ipv4() {
+                    &self.mangle_chain_v4
+                } else {
+                    &self.mangle_chain_v6
+                };
+                let mut rule = Rule::new(chain);
+                check_net(&mut rule, End::Dst, *network);
+                rule.add_expr(&nft_expr!(immediate data split_tunnel::MARK));
+                rule.add_expr(&nft_expr!(ct mark set));
+                rule.add_expr(&nft_expr!(immediate data fwmark));
+                rule.add_expr(&nft_expr!(meta mark set));
+                self.batch.add(&rule, nftnl::MsgType::Add);
+            }
+        }
+
         for chain in &[&self.in_chain, &self.out_chain] {
             let mut rule = Rule::new(chain);
             rule.add_expr(&nft_expr!(ct mark));
@@ -555,13 +606,16 @@ impl<'a> PolicyBatch<'a> {
     }
 
     fn add_policy_specific_rules(&mut self, policy: &FirewallPolicy, fwmark: u32) -> Result<()> {
-        let allow_lan = match policy {
+        let (allow_lan, lan_rules, firewall_exceptions) = match policy {
             FirewallPolicy::Connecting {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
+                lan_rules,
+                firewall_exceptions,
                 allowed_endpoint,
                 allowed_tunnel_traffic,
+                ..
             } => {
                 self.add_allow_tunnel_endpoint_rules(peer_endpoint, fwmark);
                 self.add_allow_endpoint_rules(&allowed_endpoint.endpoint);
@@ -584,13 +638,16 @@ impl<'a> PolicyBatch<'a> {
                         self.add_block_cve_2019_14899(tunnel);
                     }
                 }
-                *allow_lan
+                (*allow_lan, lan_rules, firewall_exceptions)
             }
             FirewallPolicy::Connected {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
+                lan_rules,
+                firewall_exceptions,
                 dns_servers,
+                ..
             } => {
                 self.add_allow_tunnel_endpoint_rules(peer_endpoint, fwmark);
                 self.add_allow_dns_rules(tunnel, dns_servers, TransportProtocol::Udp)?;
@@ -602,11 +659,14 @@ impl<'a> PolicyBatch<'a> {
                 if *allow_lan {
                     self.add_block_cve_2019_14899(tunnel);
                 }
-                *allow_lan
+                (*allow_lan, lan_rules, firewall_exceptions)
             }
             FirewallPolicy::Blocked {
                 allow_lan,
+                lan_rules,
+                firewall_exceptions,
                 allowed_endpoint,
+                ..
             } => {
                 if let Some(endpoint) = allowed_endpoint {
                     self.add_allow_endpoint_rules(&endpoint.endpoint);
@@ -614,12 +674,18 @@ impl<'a> PolicyBatch<'a> {
 
                 // Important to drop DNS before allowing LAN (to stop DNS leaking to the LAN)
                 self.add_drop_dns_rule();
-                *allow_lan
+                (*allow_lan, lan_rules, firewall_exceptions)
             }
         };
 
         if allow_lan {
-            self.add_allow_lan_rules();
+            match lan_rules {
+                Some(rules) => self.add_lan_rules(rules),
+                None => self.add_allow_lan_rules(),
+            }
+        }
+        for exception in firewall_exceptions {
+            self.add_firewall_exception_rules(exception);
         }
 
         // Reject any remaining outgoing traffic
@@ -875,6 +941,153 @@ impl<'a> PolicyBatch<'a> {
         self.add_dhcp_server_rules();
     }
 
+    /// Allows only the local network traffic that matches `rules`.
+    fn add_lan_rules(&mut self, rules: &LanRules) {
+        for net in rules
+            .subnets
+            .iter()
+            .filter(|net| super::is_lan_network(net))
+        {
+            for chain in &[&self.out_chain, &self.forward_chain] {
+                let mut out_rule = Rule::new(chain);
+                check_net(&mut out_rule, End::Dst, *net);
+                add_verdict(&mut out_rule, &Verdict::Accept);
+                self.batch.add(&out_rule, nftnl::MsgType::Add);
+            }
+            let mut in_rule = Rule::new(&self.in_chain);
+            check_net(&mut in_rule, End::Src, *net);
+            add_verdict(&mut in_rule, &Verdict::Accept);
+            self.batch.add(&in_rule, nftnl::MsgType::Add);
+        }
+
+        for iface in &rules.interfaces {
+            if let Err(error) = self.add_allow_lan_iface_rules(iface) {
+                // Interfaces that do not exist yet are allowed the next time a policy is applied
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg(&format!(
+                        "Not allowing local network traffic over {}",
+                        iface
+                    ))
+                );
+            }
+        }
+
+        for service in &rules.services {
+            match service {
+                LanService::Mdns => self.add_mdns_rules(),
+                LanService::Dhcp => self.add_dhcp_server_rules(),
+                LanService::Printing => self.add_printing_rules(),
+            }
+        }
+    }
+
+    fn add_allow_lan_iface_rules(&mut self, iface: &str) -> Result<()> {
+        let mut rules = vec![];
+        for chain in &[&self.out_chain, &self.forward_chain] {
+            for net in super::ALLOWED_LAN_NETS
+                .iter()
+                .chain(&*super::ALLOWED_LAN_MULTICAST_NETS)
+            {
+                let mut out_rule = Rule::new(chain);
+                check_iface(&mut out_rule, Direction::Out, iface)?;
+                check_net(&mut out_rule, End::Dst, *net);
+                add_verdict(&mut out_rule, &Verdict::Accept);
+                rules.push(out_rule);
+            }
+        }
+        for net in &*super::ALLOWED_LAN_NETS {
+            let mut in_rule = Rule::new(&self.in_chain);
+            check_iface(&mut in_rule, Direction::In, iface)?;
+            check_net(&mut in_rule, End::Src, *net);
+            add_verdict(&mut in_rule, &Verdict::Accept);
+            rules.push(in_rule);
+        }
+        for rule in &rules {
+            self.batch.add(rule, nftnl::MsgType::Add);
+        }
+        Ok(())
+    }
+
+    fn add_mdns_rules(&mut self) {
+        use TransportProtocol::Udp;
+        for address in &*super::MDNS_ADDRS {
+            let mut out_rule = Rule::new(&self.out_chain);
+            check_endpoint(
+                &mut out_rule,
+                End::Dst,
+                &Endpoint::new(*address, super::MDNS_PORT, Udp),
+            );
+            add_verdict(&mut out_rule, &Verdict::Accept);
+            self.batch.add(&out_rule, nftnl::MsgType::Add);
+        }
+        // Responses are either multicast or sent directly to the querying port
+        for net in &*super::ALLOWED_LAN_NETS {
+            let mut in_rule = Rule::new(&self.in_chain);
+            check_net(&mut in_rule, End::Src, *net);
+            check_port(&mut in_rule, Udp, End::Src, super::MDNS_PORT);
+            add_verdict(&mut in_rule, &Verdict::Accept);
+            self.batch.add(&in_rule, nftnl::MsgType::Add);
+        }
+    }
+
+    fn add_printing_rules(&mut self) {
+        use TransportProtocol::Tcp;
+        let allowed_states = nftnl::expr::ct::States::ESTABLISHED.bits();
+        for net in &*super::ALLOWED_LAN_NETS {
+            for port in super::PRINTING_PORTS {
+                let mut out_rule = Rule::new(&self.out_chain);
+                check_net(&mut out_rule, End::Dst, *net);
+                check_port(&mut out_rule, Tcp, End::Dst, port);
+                add_verdict(&mut out_rule, &Verdict::Accept);
+                self.batch.add(&out_rule, nftnl::MsgType::Add);
+
+                let mut in_rule = Rule::new(&self.in_chain);
+                check_net(&mut in_rule, End::Src, *net);
+                check_port(&mut in_rule, Tcp, End::Src, port);
+                in_rule.add_expr(&nft_expr!(ct state));
+                in_rule.add_expr(&nft_expr!(bitwise mask allowed_states, xor 0u32));
+                in_rule.add_expr(&nft_expr!(cmp != 0u32));
+                add_verdict(&mut in_rule, &Verdict::Accept);
+                self.batch.add(&in_rule, nftnl::MsgType::Add);
+            }
+        }
+    }
+
+    /// Allows the traffic of `exception`, along with responses to it.
+    fn add_firewall_exception_rules(&mut self, exception: &FirewallException) {
+        // The port of an exception is always the destination port of the initiating traffic
+        let check_traffic = |rule: &mut Rule<'_>, end: End| match exception.port {
+            Some(port) => check_port(rule, exception.protocol, end, port),
+            None => check_l4proto(rule, exception.protocol),
+        };
+        let (initiating_chain, response_chain, remote_end, response_remote_end) =
+            match exception.direction {
+                firewall_exception::Direction::Outgoing => {
+                    (&self.out_chain, &self.in_chain, End::Dst, End::Src)
+                }
+                firewall_exception::Direction::Incoming => {
+                    (&self.in_chain, &self.out_chain, End::Src, End::Dst)
+                }
+            };
+
+        let mut initiating_rule = Rule::new(initiating_chain);
+        check_net(&mut initiating_rule, remote_end, exception.network);
+        check_traffic(&mut initiating_rule, End::Dst);
+        add_verdict(&mut initiating_rule, &Verdict::Accept);
+        self.batch.add(&initiating_rule, nftnl::MsgType::Add);
+
+        let mut response_rule = Rule::new(response_chain);
+        check_net(&mut response_rule, response_remote_end, exception.network);
+        check_traffic(&mut response_rule, End::Src);
+        response_rule.add_expr(&nft_expr!(ct state));
+        let allowed_states = nftnl::expr::ct::States::ESTABLISHED.bits();
+        response_rule.add_expr(&nft_expr!(bitwise mask allowed_states, xor 0u32));
+        response_rule.add_expr(&nft_expr!(cmp != 0u32));
+        add_verdict(&mut response_rule, &Verdict::Accept);
+        self.batch.add(&response_rule, nftnl::MsgType::Add);
+    }
+
     fn add_dhcp_server_rules(&mut self) {
         use TransportProtocol::Udp;
         // Outgoing DHCPv4 response
@@ -1,4 +1,4 @@
-use super::{FirewallArguments, FirewallPolicy};
+use super::{ExclusionRoute, FirewallArguments, FirewallPolicy};
 use ipnetwork::IpNetwork;
 use pfctl::{DropAction, FilterRuleAction, Uid};
 use std::{
@@ -6,7 +6,12 @@ use std::{
     net::{IpAddr, Ipv4Addr},
 };
 use subslice::SubsliceExt;
-use talpid_types::net::{self, AllowedTunnelTraffic};
+use talpid_types::{
+    firewall_exception::{self, FirewallException},
+    lan::{LanRules, LanService},
+    net::{self, AllowedTunnelTraffic},
+    split_tunnel::SplitTunnelMode,
+};
 
 pub use pfctl::Error;
 
@@ -20,11 +25,15 @@ pub struct Firewall {
     pf: pfctl::PfCtl,
     pf_was_enabled: Option<bool>,
     rule_logging: RuleLogging,
+    /// Group ID of processes whose traffic is routed outside the tunnel.
+    exclusion_gid: Option<u32>,
 }
 
 impl Firewall {
-    pub fn from_args(_args: FirewallArguments) -> Result<Self> {
-        Self::new()
+    pub fn from_args(args: FirewallArguments) -> Result<Self> {
+        let mut firewall = Self::new()?;
+        firewall.exclusion_gid = Some(args.exclusion_gid);
+        Ok(firewall)
     }
 
     pub fn new() -> Result<Self> {
@@ -43,6 +52,7 @@ impl Firewall {
             pf: pfctl::PfCtl::new()?,
             pf_was_enabled: None,
             rule_logging,
+            exclusion_gid: None,
         })
     }
 
@@ -85,6 +95,7 @@ impl Firewall {
         let mut anchor_change = pfctl::AnchorChange::new();
         anchor_change.set_filter_rules(new_filter_rules);
         anchor_change.set_redirect_rules(self.get_dns_redirect_rules(&policy)?);
+        anchor_change.set_nat_rules(self.get_split_tunnel_nat_rules(&policy)?);
         Ok(self.pf.set_rules(ANCHOR_NAME, anchor_change)?)
     }
 
@@ -118,10 +129,24 @@ impl Firewall {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
+                lan_rules,
+                firewall_exceptions,
                 allowed_endpoint,
                 allowed_tunnel_traffic,
+                excluded_networks,
+                exclusion_routes,
+                split_tunnel_mode,
             } => {
-                let mut rules = vec![self.get_allow_relay_rule(*peer_endpoint)?];
+                let mut rules = vec![];
+                if let Some(tunnel) = tunnel {
+                    rules.append(&mut self.get_split_tunnel_rules(
+                        &tunnel.interface,
+                        excluded_networks,
+                        exclusion_routes,
+                        self.get_excluded_group(*split_tunnel_mode, false),
+                    )?);
+                }
+                rules.push(self.get_allow_relay_rule(*peer_endpoint)?);
                 rules.push(self.get_allowed_endpoint_rule(allowed_endpoint.endpoint)?);
 
                 // Important to block DNS after allow relay rule (so the relay can operate
@@ -136,15 +161,21 @@ impl Firewall {
                 }
 
                 if *allow_lan {
-                    rules.append(&mut self.get_allow_lan_rules()?);
+                    rules.append(&mut self.get_allow_lan_rules(lan_rules.as_ref())?);
                 }
+                rules.append(&mut self.get_firewall_exception_rules(firewall_exceptions)?);
                 Ok(rules)
             }
             FirewallPolicy::Connected {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
+                lan_rules,
+                firewall_exceptions,
                 dns_servers,
+                excluded_networks,
+                exclusion_routes,
+                split_tunnel_mode,
             } => {
                 let mut rules = vec![];
 
@@ -152,6 +183,14 @@ impl Firewall {
                     rules.append(&mut self.get_allow_dns_rules_when_connected(&tunnel, *server)?);
                 }
 
+                // Excluded traffic must be rerouted before it is allowed through the tunnel
+                rules.append(&mut self.get_split_tunnel_rules(
+                    &tunnel.interface,
+                    excluded_networks,
+                    exclusion_routes,
+                    self.get_excluded_group(*split_tunnel_mode, true),
+                )?);
+
                 rules.push(self.get_allow_relay_rule(*peer_endpoint)?);
 
                 // Important to block DNS *before* we allow the tunnel and allow LAN. So DNS
@@ -167,13 +206,16 @@ impl Firewall {
                 );
 
                 if *allow_lan {
-                    rules.append(&mut self.get_allow_lan_rules()?);
+                    rules.append(&mut self.get_allow_lan_rules(lan_rules.as_ref())?);
                 }
+                rules.append(&mut self.get_firewall_exception_rules(firewall_exceptions)?);
 
                 Ok(rules)
             }
             FirewallPolicy::Blocked {
                 allow_lan,
+                lan_rules,
+                firewall_exceptions,
                 allowed_endpoint,
                 ..
             } => {
@@ -185,8 +227,9 @@ impl Firewall {
                 if *allow_lan {
                     // Important to block DNS before allow LAN (so DNS does not leak to the LAN)
                     rules.append(&mut self.get_block_dns_rules()?);
-                    rules.append(&mut self.get_allow_lan_rules()?);
+                    rules.append(&mut self.get_allow_lan_rules(lan_rules.as_ref())?);
                 }
+                rules.append(&mut self.get_firewall_exception_rules(firewall_exceptions)?);
 
                 Ok(rules)
             }
@@ -277,6 +320,148 @@ impl Firewall {
         Ok(rules)
     }
 
+    /// Returns the group whose processes are excluded from the tunnel, if any. In the include
+    /// mode, every process outside the exclusion group is excluded, but only while connected,
+    /// since their DNS requests would leak outside the tunnel otherwise.
+    fn get_excluded_group(&self, mode: SplitTunnelMode, connected: bool) -> Option<pfctl::Gid> {
+        let exclusion_gid = self.exclusion_gid?;
+        match mode {
+            SplitTunnelMode::Exclude => Some(pfctl::Gid::from(exclusion_gid)),
+            SplitTunnelMode::Include if connected => Some(pfctl::Gid::from(pfctl::Id::One(
+                exclusion_gid,
+                pfctl::Modifier::NotEqual,
+            ))),
+            SplitTunnelMode::Include => None,
+        }
+    }
+
+    /// Produces rules that send traffic from processes in `excluded_group` and to excluded
+    /// networks, that would otherwise enter the tunnel, to the gateway of the physical network
+    /// instead. The daemon runs as the exclusion group as well, so sockets owned by root are not
+    /// excluded by group.
+    fn get_split_tunnel_rules(
+        &self,
+        tunnel_interface: &str,
+        excluded_networks: &[IpNetwork],
+        exclusion_routes: &[ExclusionRoute],
+        excluded_group: Option<pfctl::Gid>,
+    ) -> Result<Vec<pfctl::FilterRule>> {
+        let mut rules = vec![];
+        for route in exclusion_routes {
+            let route_to = || {
+                pfctl::Route::route_to(pfctl::PoolAddr::new(
+                    pfctl::Interface::from(route.interface.as_str()),
+                    pfctl::Ip::from(route.gateway),
+                ))
+            };
+            for network in excluded_networks
+                .iter()
+                .filter(|network| network.is_ipv4() == route.gateway.is_ipv4())
+            {
+                rules.push(
+                    self.create_rule_builder(FilterRuleAction::Pass)
+                        .direction(pfctl::Direction::Out)
+                        .quick(true)
+                        .interface(tunnel_interface)
+                        .to(pfctl::Ip::from(*network))
+                        .route(route_to())
+                        .keep_state(pfctl::StatePolicy::Keep)
+                        .build()?,
+                );
+                rules.push(
+                    self.create_rule_builder(FilterRuleAction::Pass)
+                        .direction(pfctl::Direction::Out)
+                        .quick(true)
+                        .interface(&route.interface)
+                        .to(pfctl::Ip::from(*network))
+                        .keep_state(pfctl::StatePolicy::Keep)
+                        .build()?,
+                );
+            }
+
+            let excluded_group = match excluded_group {
+                Some(group) => group,
+                None => continue,
+            };
+            let af = match route.gateway {
+                IpAddr::V4(_) => pfctl::AddrFamily::Ipv4,
+                IpAddr::V6(_) => pfctl::AddrFamily::Ipv6,
+            };
+            let excluded_user =
+                pfctl::Uid::from(pfctl::Id::One(super::ROOT_UID, pfctl::Modifier::NotEqual));
+
+            let route_to_rule = self
+                .create_rule_builder(FilterRuleAction::Pass)
+                .direction(pfctl::Direction::Out)
+                .quick(true)
+                .interface(tunnel_interface)
+                .af(af)
+                .user(excluded_user)
+                .group(excluded_group)
+                .route(route_to())
+                .keep_state(pfctl::StatePolicy::Keep)
+                .build()?;
+            rules.push(route_to_rule);
+
+            // The rerouted packets are filtered again on the physical interface
+            let allow_rule = self
+                .create_rule_builder(FilterRuleAction::Pass)
+                .direction(pfctl::Direction::Out)
+                .quick(true)
+                .interface(&route.interface)
+                .af(af)
+                .user(excluded_user)
+                .group(excluded_group)
+                .keep_state(pfctl::StatePolicy::Keep)
+                .build()?;
+            rules.push(allow_rule);
+        }
+        Ok(rules)
+    }
+
+    /// Produces rules that translate the source address of rerouted excluded traffic from the
+    /// tunnel address to the address of the physical interface.
+    fn get_split_tunnel_nat_rules(&self, policy: &FirewallPolicy) -> Result<Vec<pfctl::NatRule>> {
+        let (tunnel, excluded_networks, exclusion_routes) = match policy {
+            FirewallPolicy::Connecting {
+                tunnel: Some(tunnel),
+                excluded_networks,
+                exclusion_routes,
+                ..
+            }
+            | FirewallPolicy::Connected {
+                tunnel,
+                excluded_networks,
+                exclusion_routes,
+                ..
+            } => (tunnel, excluded_networks, exclusion_routes),
+            _ => return Ok(vec![]),
+        };
+        if self.exclusion_gid.is_none() && excluded_networks.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut rules = vec![];
+        for route in exclusion_routes {
+            for tunnel_ip in tunnel
+                .ips
+                .iter()
+                .filter(|ip| ip.is_ipv4() == route.gateway.is_ipv4())
+            {
+                rules.push(
+                    pfctl::NatRuleBuilder::default()
+                        .action(pfctl::NatRuleAction::Nat)
+                        .interface(&route.interface)
+                        .from(pfctl::Ip::from(*tunnel_ip))
+                        .to(pfctl::Ip::Any)
+                        .nat_to(pfctl::Interface::from(route.interface.as_str()))
+                        .build()?,
+                );
+            }
+        }
+        Ok(rules)
+    }
+
     fn get_allow_relay_rule(&self, relay_endpoint: net::Endpoint) -> Result<pfctl::FilterRule> {
         let pfctl_proto = as_pfctl_proto(relay_endpoint.protocol);
 
@@ -364,7 +549,16 @@ impl Firewall {
         Ok(vec![lo0_rule])
     }
 
-    fn get_allow_lan_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
+    /// Returns rules that allow the local network traffic that matches `lan_rules`, or all of it
+    /// if there are no rules.
+    fn get_allow_lan_rules(&self, lan_rules: Option<&LanRules>) -> Result<Vec<pfctl::FilterRule>> {
+        match lan_rules {
+            Some(lan_rules) => self.get_lan_rules(lan_rules),
+            None => self.get_all_lan_rules(),
+        }
+    }
+
+    fn get_all_lan_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
         let mut rules = vec![];
         for net in &*super::ALLOWED_LAN_NETS {
             let mut rule_builder = self.create_rule_builder(FilterRuleAction::Pass);
@@ -391,7 +585,159 @@ impl Firewall {
                 .build()?;
             rules.push(allow_multicast_out);
         }
+        rules.append(&mut self.get_dhcp_server_rules()?);
 
+        Ok(rules)
+    }
+
+    fn get_lan_rules(&self, lan_rules: &LanRules) -> Result<Vec<pfctl::FilterRule>> {
+        let mut rules = vec![];
+        for net in lan_rules
+            .subnets
+            .iter()
+            .filter(|net| super::is_lan_network(net))
+        {
+            let mut rule_builder = self.create_rule_builder(FilterRuleAction::Pass);
+            rule_builder.quick(true);
+            rules.push(
+                rule_builder
+                    .direction(pfctl::Direction::Out)
+                    .from(pfctl::Ip::Any)
+                    .to(pfctl::Ip::from(*net))
+                    .build()?,
+            );
+            rules.push(
+                rule_builder
+                    .direction(pfctl::Direction::In)
+                    .from(pfctl::Ip::from(*net))
+                    .to(pfctl::Ip::Any)
+                    .build()?,
+            );
+        }
+
+        for iface in &lan_rules.interfaces {
+            let mut rule_builder = self.create_rule_builder(FilterRuleAction::Pass);
+            rule_builder.quick(true).interface(iface.as_str());
+            for net in &*super::ALLOWED_LAN_NETS {
+                rules.push(
+                    rule_builder
+                        .direction(pfctl::Direction::Out)
+                        .from(pfctl::Ip::Any)
+                        .to(pfctl::Ip::from(*net))
+                        .build()?,
+                );
+                rules.push(
+                    rule_builder
+                        .direction(pfctl::Direction::In)
+                        .from(pfctl::Ip::from(*net))
+                        .to(pfctl::Ip::Any)
+                        .build()?,
+                );
+            }
+            for multicast_net in &*super::ALLOWED_LAN_MULTICAST_NETS {
+                rules.push(
+                    rule_builder
+                        .direction(pfctl::Direction::Out)
+                        .from(pfctl::Ip::Any)
+                        .to(pfctl::Ip::from(*multicast_net))
+                        .build()?,
+                );
+            }
+        }
+
+        for service in &lan_rules.services {
+            match service {
+                LanService::Mdns => rules.append(&mut self.get_mdns_rules()?),
+                LanService::Dhcp => rules.append(&mut self.get_dhcp_server_rules()?),
+                LanService::Printing => rules.append(&mut self.get_printing_rules()?),
+            }
+        }
+
+        Ok(rules)
+    }
+
+    fn get_mdns_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
+        let mut rules = vec![];
+        for address in &*super::MDNS_ADDRS {
+            rules.push(
+                self.create_rule_builder(FilterRuleAction::Pass)
+                    .quick(true)
+                    .direction(pfctl::Direction::Out)
+                    .proto(pfctl::Proto::Udp)
+                    .to(pfctl::Endpoint::new(
+                        *address,
+                        pfctl::Port::from(super::MDNS_PORT),
+                    ))
+                    .build()?,
+            );
+        }
+        // Responses are either multicast or sent directly to the querying port
+        for net in &*super::ALLOWED_LAN_NETS {
+            rules.push(
+                self.create_rule_builder(FilterRuleAction::Pass)
+                    .quick(true)
+                    .direction(pfctl::Direction::In)
+                    .proto(pfctl::Proto::Udp)
+                    .from(pfctl::Endpoint::new(
+                        *net,
+                        pfctl::Port::from(super::MDNS_PORT),
+                    ))
+                    .build()?,
+            );
+        }
+        Ok(rules)
+    }
+
+    fn get_printing_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
+        let mut rules = vec![];
+        for net in &*super::ALLOWED_LAN_NETS {
+            for port in super::PRINTING_PORTS {
+                rules.push(
+                    self.create_rule_builder(FilterRuleAction::Pass)
+                        .quick(true)
+                        .direction(pfctl::Direction::Out)
+                        .proto(pfctl::Proto::Tcp)
+                        .to(pfctl::Endpoint::new(*net, pfctl::Port::from(port)))
+                        .keep_state(pfctl::StatePolicy::Keep)
+                        .build()?,
+                );
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Returns rules that allow the traffic of `exceptions`. Responses are allowed by keeping
+    /// state.
+    fn get_firewall_exception_rules(
+        &self,
+        exceptions: &[FirewallException],
+    ) -> Result<Vec<pfctl::FilterRule>> {
+        let mut rules = vec![];
+        for exception in exceptions {
+            let port = exception
+                .port
+                .map(pfctl::Port::from)
+                .unwrap_or(pfctl::Port::Any);
+            let mut rule_builder = self.create_rule_builder(FilterRuleAction::Pass);
+            rule_builder
+                .quick(true)
+                .proto(as_pfctl_proto(exception.protocol))
+                .keep_state(pfctl::StatePolicy::Keep);
+            match exception.direction {
+                firewall_exception::Direction::Outgoing => rule_builder
+                    .direction(pfctl::Direction::Out)
+                    .to(pfctl::Endpoint::new(exception.network, port)),
+                firewall_exception::Direction::Incoming => rule_builder
+                    .direction(pfctl::Direction::In)
+                    .from(pfctl::Ip::from(exception.network))
+                    .to(port),
+            };
+            rules.push(rule_builder.build()?);
+        }
+        Ok(rules)
+    }
+
+    fn get_dhcp_server_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
         let dhcpv4_out = self
             .create_rule_builder(FilterRuleAction::Pass)
             .quick(true)
@@ -412,10 +758,7 @@ impl Firewall {
                 pfctl::Port::from(super::DHCPV4_SERVER_PORT),
             ))
             .build()?;
-        rules.push(dhcpv4_out);
-        rules.push(dhcpv4_in);
-
-        Ok(rules)
+        Ok(vec![dhcpv4_out, dhcpv4_in])
     }
 
     fn get_allow_dhcp_client_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
@@ -596,6 +939,7 @@ impl Firewall {
         // remove_anchor() does not deactivate active rules
         self.pf
             .flush_rules(ANCHOR_NAME, pfctl::RulesetKind::Filter)?;
+        self.pf.flush_rules(ANCHOR_NAME, pfctl::RulesetKind::Nat)?;
         Ok(())
     }
 
@@ -636,6 +980,8 @@ impl Firewall {
             .try_add_anchor(ANCHOR_NAME, pfctl::AnchorKind::Filter)?;
         self.pf
             .try_add_anchor(ANCHOR_NAME, pfctl::AnchorKind::Redirect)?;
+        self.pf
+            .try_add_anchor(ANCHOR_NAME, pfctl::AnchorKind::Nat)?;
         Ok(())
     }
 
@@ -644,6 +990,8 @@ impl Firewall {
             .try_remove_anchor(ANCHOR_NAME, pfctl::AnchorKind::Filter)?;
         self.pf
             .try_remove_anchor(ANCHOR_NAME, pfctl::AnchorKind::Redirect)?;
+        self.pf
+            .try_remove_anchor(ANCHOR_NAME, pfctl::AnchorKind::Nat)?;
         Ok(())
     }
 }
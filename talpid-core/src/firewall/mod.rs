@@ -9,6 +9,10 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr},
 };
 use talpid_types::net::{AllowedEndpoint, AllowedTunnelTraffic, Endpoint};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use talpid_types::{
+    firewall_exception::FirewallException, lan::LanRules, split_tunnel::SplitTunnelMode,
+};
 
 #[cfg(target_os = "macos")]
 #[path = "macos.rs"]
@@ -65,6 +69,12 @@ lazy_static! {
     ];
     static ref ROUTER_SOLICITATION_OUT_DST_ADDR: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
     static ref SOLICITED_NODE_MULTICAST: Ipv6Network = Ipv6Network::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 1, 0xFF00, 0), 104).unwrap();
+    /// Multicast addresses that mDNS queries are sent to.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    static ref MDNS_ADDRS: [IpAddr; 2] = [
+        IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)),
+        IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb)),
+    ];
     static ref LOOPBACK_NETS: [IpNetwork; 2] = [
         IpNetwork::V4(ipnetwork::Ipv4Network::new(Ipv4Addr::new(127, 0, 0, 0), 8).unwrap()),
         IpNetwork::V6(ipnetwork::Ipv6Network::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 128).unwrap()),
@@ -80,6 +90,11 @@ const DHCPV6_SERVER_PORT: u16 = 547;
 const DHCPV6_CLIENT_PORT: u16 = 546;
 #[cfg(all(unix, not(target_os = "android")))]
 const ROOT_UID: u32 = 0;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const MDNS_PORT: u16 = 5353;
+/// Ports of LPD, IPP and raw TCP printing.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const PRINTING_PORTS: [u16; 3] = [515, 631, 9100];
 
 #[cfg(any(all(unix, not(target_os = "android")), target_os = "windows"))]
 /// Returns whether an address belongs to a private subnet.
@@ -91,6 +106,16 @@ pub fn is_local_address(address: &IpAddr) -> bool {
         .any(|net| net.contains(address))
 }
 
+/// Returns whether all addresses of a network are local addresses or local multicast addresses,
+/// i.e. whether it can be allowed when LAN access is restricted to specific subnets.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn is_lan_network(network: &IpNetwork) -> bool {
+    (*ALLOWED_LAN_NETS)
+        .iter()
+        .chain(&*ALLOWED_LAN_MULTICAST_NETS)
+        .any(|lan| lan.prefix() <= network.prefix() && lan.contains(network.network()))
+}
+
 /// A enum that describes network security strategy
 ///
 /// # Firewall block/allow specification.
@@ -107,6 +132,13 @@ pub enum FirewallPolicy {
         tunnel: Option<crate::tunnel::TunnelMetadata>,
         /// Flag setting if communication with LAN networks should be possible.
         allow_lan: bool,
+        /// Local network traffic to allow if `allow_lan` is set. All of it is allowed if this is
+        /// `None`.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        lan_rules: Option<LanRules>,
+        /// Traffic that is allowed in addition to what the policy allows.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        firewall_exceptions: Vec<FirewallException>,
         /// Host that should be reachable while connecting.
         allowed_endpoint: AllowedEndpoint,
         /// Networks for which to permit in-tunnel traffic.
@@ -114,6 +146,17 @@ pub enum FirewallPolicy {
         /// A process that is allowed to send packets to the relay.
         #[cfg(windows)]
         relay_client: PathBuf,
+        /// Networks whose traffic is sent outside the tunnel.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        excluded_networks: Vec<IpNetwork>,
+        /// Whether traffic from the processes that are selected for split tunneling is sent
+        /// outside the tunnel, or is the only traffic that is sent through it.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        split_tunnel_mode: SplitTunnelMode,
+        /// Default routes of the physical network, that traffic from excluded processes is routed
+        /// over once the tunnel interface is up.
+        #[cfg(target_os = "macos")]
+        exclusion_routes: Vec<ExclusionRoute>,
     },
 
     /// Allow traffic only to server and over tunnel interface
@@ -124,20 +167,49 @@ pub enum FirewallPolicy {
         tunnel: crate::tunnel::TunnelMetadata,
         /// Flag setting if communication with LAN networks should be possible.
         allow_lan: bool,
+        /// Local network traffic to allow if `allow_lan` is set. All of it is allowed if this is
+        /// `None`.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        lan_rules: Option<LanRules>,
+        /// Traffic that is allowed in addition to what the policy allows.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        firewall_exceptions: Vec<FirewallException>,
         /// Servers that are allowed to respond to DNS requests.
         #[cfg(not(target_os = "android"))]
         dns_servers: Vec<IpAddr>,
         /// A process that is allowed to send packets to the relay.
         #[cfg(windows)]
         relay_client: PathBuf,
+        /// Networks whose traffic is sent outside the tunnel.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        excluded_networks: Vec<IpNetwork>,
+        /// Whether traffic from the processes that are selected for split tunneling is sent
+        /// outside the tunnel, or is the only traffic that is sent through it.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        split_tunnel_mode: SplitTunnelMode,
+        /// Default routes of the physical network, that traffic from excluded processes is routed
+        /// over.
+        #[cfg(target_os = "macos")]
+        exclusion_routes: Vec<ExclusionRoute>,
     },
 
     /// Block all network traffic in and out from the computer.
     Blocked {
         /// Flag setting if communication with LAN networks should be possible.
         allow_lan: bool,
+        /// Local network traffic to allow if `allow_lan` is set. All of it is allowed if this is
+        /// `None`.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        lan_rules: Option<LanRules>,
+        /// Traffic that is allowed in addition to what the policy allows.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        firewall_exceptions: Vec<FirewallException>,
         /// Host that should be reachable while in the blocked state.
         allowed_endpoint: Option<AllowedEndpoint>,
+        /// Processes that are selected for split tunneling are only allowed to communicate in the
+        /// blocked state if they are excluded from the tunnel.
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        split_tunnel_mode: SplitTunnelMode,
         /// Desination port for DNS traffic redirection. Traffic destined to `127.0.0.1:53` will be
         /// redirected to `127.0.0.1:$dns_redirect_port`.
         #[cfg(target_os = "macos")]
@@ -237,6 +309,20 @@ pub struct FirewallArguments {
     /// the tunnel and _leaked_ during blocked states.
     #[cfg(target_os = "linux")]
     pub fwmark: u32,
+    /// Group ID of processes whose traffic is excluded from the tunnel.
+    #[cfg(target_os = "macos")]
+    pub exclusion_gid: u32,
+}
+
+/// A default route of the physical network. Traffic from processes that are excluded from the
+/// tunnel is sent to `gateway` on `interface` instead of through the tunnel.
+#[cfg(target_os = "macos")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ExclusionRoute {
+    /// Name of the physical interface.
+    pub interface: String,
+    /// Address of the default gateway.
+    pub gateway: IpAddr,
 }
 
 /// State to enter during firewall init.
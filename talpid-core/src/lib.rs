@@ -15,6 +15,10 @@ pub mod window;
 
 mod offline;
 
+/// Identifies the network that the host is connected to.
+#[cfg(not(target_os = "android"))]
+pub mod network_identity;
+
 /// Split tunneling
 pub mod split_tunnel;
 
@@ -0,0 +1,183 @@
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+use talpid_dbus::network_manager::{self, NetworkManager};
+use talpid_types::net::NetworkIdentity;
+
+/// Errors that can happen when identifying the current network.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// Unable to read the routing table.
+    #[error(display = "Failed to read the routing table")]
+    ReadRoutes(#[error(source)] io::Error),
+
+    /// Unable to obtain the SSID of a Wi-Fi interface.
+    #[error(display = "Failed to obtain the SSID of {} from NetworkManager", _0)]
+    Ssid(String, #[error(source)] network_manager::Error),
+
+    /// The task that identifies the network panicked.
+    #[error(display = "The network identification task panicked")]
+    TaskPanicked,
+}
+
+const IPV4_ROUTES: &str = "/proc/net/route";
+const IPV6_ROUTES: &str = "/proc/net/ipv6_route";
+
+const RTF_UP: u32 = 0x1;
+const RTF_GATEWAY: u32 = 0x2;
+
+/// A default route in the main routing table. The tunnel is routed using a separate table, so
+/// these always go through the physical network.
+struct DefaultRoute {
+    interface: String,
+    gateway: IpAddr,
+    metric: u32,
+}
+
+pub async fn current_network() -> Result<Option<NetworkIdentity>, Error> {
+    // NetworkManager is queried over D-Bus, which blocks
+    tokio::task::spawn_blocking(|| {
+        let route = match default_route()? {
+            Some(route) => route,
+            None => return Ok(None),
+        };
+        identify_network(route).map(Some)
+    })
+    .await
+    .map_err(|_| Error::TaskPanicked)?
+}
+
+fn identify_network(route: DefaultRoute) -> Result<NetworkIdentity, Error> {
+    let ssid = NetworkManager::new().and_then(|manager| manager.get_wifi_ssid(&route.interface));
+    match ssid {
+        Ok(Some(ssid)) => Ok(NetworkIdentity::Wifi(ssid)),
+        Ok(None) => Ok(wired_network(&route)),
+        // Without NetworkManager, only networks that are not Wi-Fi can be identified
+        Err(error) if is_wireless(&route.interface) => Err(Error::Ssid(route.interface, error)),
+        Err(_) => Ok(wired_network(&route)),
+    }
+}
+
+fn wired_network(route: &DefaultRoute) -> NetworkIdentity {
+    NetworkIdentity::Wired(format!("{}%{}", route.gateway, route.interface))
+}
+
+fn is_wireless(interface: &str) -> bool {
+    Path::new("/sys/class/net")
+        .join(interface)
+        .join("wireless")
+        .exists()
+}
+
+/// Returns the IPv4 default route with the lowest metric, or the IPv6 one if there is no IPv4
+/// default route.
+fn default_route() -> Result<Option<DefaultRoute>, Error> {
+    let ipv4_routes = fs::read_to_string(IPV4_ROUTES).map_err(Error::ReadRoutes)?;
+    if let Some(route) = parse_ipv4_default_route(&ipv4_routes) {
+        return Ok(Some(route));
+    }
+    match fs::read_to_string(IPV6_ROUTES) {
+        Ok(ipv6_routes) => Ok(parse_ipv6_default_route(&ipv6_routes)),
+        // IPv6 may be disabled
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(Error::ReadRoutes(error)),
+    }
+}
+
+/// Parses the default route in the format of `/proc/net/route`. Addresses are printed as
+/// hexadecimal numbers in host byte order.
+fn parse_ipv4_default_route(routes: &str) -> Option<DefaultRoute> {
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (interface, destination, gateway, flags, metric, mask) = match fields[..] {
+                [interface, destination, gateway, flags, _, _, metric, mask, ..] => {
+                    (interface, destination, gateway, flags, metric, mask)
+                }
+                _ => return None,
+            };
+            let flags = u32::from_str_radix(flags, 16).ok()?;
+            if destination != "00000000" || mask != "00000000" || !is_gateway_route(flags) {
+                return None;
+            }
+            let gateway = u32::from_str_radix(gateway, 16).ok()?;
+            Some(DefaultRoute {
+                interface: interface.to_owned(),
+                gateway: IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())),
+                metric: metric.parse().ok()?,
+            })
+        })
+        .min_by_key(|route| route.metric)
+}
+
+/// Parses the default route in the format of `/proc/net/ipv6_route`.
+fn parse_ipv6_default_route(routes: &str) -> Option<DefaultRoute> {
+    routes
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (destination, prefix, gateway, metric, flags, interface) = match fields[..] {
+                [destination, prefix, _, _, gateway, metric, _, _, flags, interface] => {
+                    (destination, prefix, gateway, metric, flags, interface)
+                }
+                _ => return None,
+            };
+            let flags = u32::from_str_radix(flags, 16).ok()?;
+            if u128::from_str_radix(destination, 16).ok()? != 0
+                || prefix != "00"
+                || !is_gateway_route(flags)
+            {
+                return None;
+            }
+            Some(DefaultRoute {
+                interface: interface.to_owned(),
+                gateway: IpAddr::V6(Ipv6Addr::from(u128::from_str_radix(gateway, 16).ok()?)),
+                metric: u32::from_str_radix(metric, 16).ok()?,
+            })
+        })
+        .min_by_key(|route| route.metric)
+}
+
+fn is_gateway_route(flags: u32) -> bool {
+    flags & (RTF_UP | RTF_GATEWAY) == RTF_UP | RTF_GATEWAY
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_default_routes() {
+        let ipv4_routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+        let route = parse_ipv4_default_route(ipv4_routes).unwrap();
+        assert_eq!(route.interface, "eth0");
+        if cfg!(target_endian = "little") {
+            assert_eq!(route.gateway, "10.0.0.1".parse::<IpAddr>().unwrap());
+        }
+
+        let ipv6_routes = "\
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003    wlan0
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001    wlan0
+";
+        let route = parse_ipv6_default_route(ipv6_routes).unwrap();
+        assert_eq!(route.interface, "wlan0");
+        assert_eq!(route.gateway, "fe80::1".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            wired_network(&route),
+            NetworkIdentity::Wired("fe80::1%wlan0".to_owned())
+        );
+
+        assert!(parse_ipv4_default_route("Iface\tDestination\n").is_none());
+    }
+}
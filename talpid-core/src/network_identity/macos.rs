@@ -0,0 +1,80 @@
+use std::io;
+use talpid_types::net::NetworkIdentity;
+use tokio::process::Command;
+
+/// Errors that can happen when identifying the current network.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// Unable to obtain the default routes.
+    #[error(display = "Failed to obtain the default routes")]
+    DefaultRoutes(#[error(source)] talpid_routing::Error),
+
+    /// The default route goes through a tunnel, so the physical network is unknown.
+    #[error(display = "The default route goes through a tunnel interface")]
+    TunnelRoute,
+
+    /// Unable to run `ipconfig` to obtain the SSID of an interface.
+    #[error(display = "Failed to obtain the SSID of {}", _0)]
+    Ssid(String, #[error(source)] io::Error),
+}
+
+pub async fn current_network() -> Result<Option<NetworkIdentity>, Error> {
+    let node = match talpid_routing::get_default_routes()
+        .await
+        .map_err(Error::DefaultRoutes)?
+    {
+        (Some(node), _) | (None, Some(node)) => node,
+        (None, None) => return Ok(None),
+    };
+    let fingerprint = match crate::offline::node_fingerprint(&node) {
+        Some(fingerprint) => fingerprint,
+        None => return Ok(None),
+    };
+    let interface = match node.get_device() {
+        Some(interface) if interface.contains("tun") => return Err(Error::TunnelRoute),
+        Some(interface) => interface,
+        None => return Ok(Some(NetworkIdentity::Wired(fingerprint))),
+    };
+    match wifi_ssid(interface).await? {
+        Some(ssid) => Ok(Some(NetworkIdentity::Wifi(ssid))),
+        None => Ok(Some(NetworkIdentity::Wired(fingerprint))),
+    }
+}
+
+/// Returns the SSID of the Wi-Fi network that `interface` is associated with, or `None` if it is
+/// not a Wi-Fi interface.
+async fn wifi_ssid(interface: &str) -> Result<Option<String>, Error> {
+    let output = Command::new("/usr/sbin/ipconfig")
+        .args(["getsummary", interface])
+        .output()
+        .await
+        .map_err(|error| Error::Ssid(interface.to_owned(), error))?;
+    Ok(parse_ssid(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_ssid(summary: &str) -> Option<String> {
+    summary
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("SSID : "))
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ssid() {
+        let summary = "<dictionary> {
+  BSSID : 01:23:45:67:89:ab
+  InterfaceType : WiFi
+  SSID : Coffee shop
+}";
+        assert_eq!(parse_ssid(summary), Some("Coffee shop".to_owned()));
+        assert_eq!(
+            parse_ssid("<dictionary> {\n  InterfaceType : Ethernet\n}"),
+            None
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Identifies the network that the host is connected to, so that it can be recognized when the
+//! host joins it again. Wi-Fi networks are identified by their SSID. Other networks are identified
+//! by the gateway and interface of the default route.
+
+use crate::mpsc::Sender;
+use std::time::Duration;
+use talpid_types::{net::NetworkIdentity, ErrorExt};
+
+#[cfg(target_os = "linux")]
+#[path = "linux.rs"]
+mod imp;
+
+#[cfg(target_os = "macos")]
+#[path = "macos.rs"]
+mod imp;
+
+#[cfg(windows)]
+#[path = "windows.rs"]
+mod imp;
+
+pub use self::imp::Error;
+
+/// How often the current network is identified.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Starts watching the network that the host is connected to. The identity of the network, or
+/// `None` if the host is not connected to any network, is sent to `listener` once it is known and
+/// then whenever it changes. The watcher stops when `listener` is closed.
+pub fn spawn_watcher(listener: impl Sender<Option<NetworkIdentity>> + Send + 'static) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last_network = None;
+        // Set if identifying the network failed, so that the failure is only logged once
+        let mut failed = false;
+        loop {
+            interval.tick().await;
+            let network = match imp::current_network().await {
+                Ok(network) => network,
+                Err(error) => {
+                    // The last known network is kept, since the host may still be connected to it
+                    if !failed {
+                        log::warn!(
+                            "{}",
+                            error.display_chain_with_msg("Failed to identify the current network")
+                        );
+                        failed = true;
+                    }
+                    continue;
+                }
+            };
+            failed = false;
+            if last_network.as_ref() == Some(&network) {
+                continue;
+            }
+            if listener.send(network.clone()).is_err() {
+                break;
+            }
+            last_network = Some(network);
+        }
+    });
+}
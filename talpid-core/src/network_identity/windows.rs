@@ -0,0 +1,109 @@
+use std::{ffi::c_void, io, ptr};
+use talpid_routing::get_best_default_route;
+use talpid_types::net::NetworkIdentity;
+use talpid_windows_net::{guid_from_luid, AddressFamily};
+use windows_sys::Win32::{
+    Foundation::ERROR_SUCCESS,
+    NetworkManagement::{
+        Ndis::NET_LUID_LH,
+        WiFi::{
+            wlan_intf_opcode_current_connection, WlanCloseHandle, WlanFreeMemory, WlanOpenHandle,
+            WlanQueryInterface, WLAN_CONNECTION_ATTRIBUTES,
+        },
+    },
+};
+
+/// Version 2 of the WLAN API, which is available since Windows Vista.
+const WLAN_API_VERSION_2_0: u32 = 2;
+
+/// Errors that can happen when identifying the current network.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// Unable to obtain the default route.
+    #[error(display = "Failed to obtain the default route")]
+    DefaultRoute(#[error(source)] talpid_routing::Error),
+
+    /// Unable to obtain the GUID of the interface of the default route.
+    #[error(display = "Failed to obtain the GUID of the default interface")]
+    InterfaceGuid(#[error(source)] io::Error),
+}
+
+pub async fn current_network() -> Result<Option<NetworkIdentity>, Error> {
+    let mut route = None;
+    for family in [AddressFamily::Ipv4, AddressFamily::Ipv6] {
+        route = get_best_default_route(family).map_err(Error::DefaultRoute)?;
+        if route.is_some() {
+            break;
+        }
+    }
+    let route = match route {
+        Some(route) => route,
+        None => return Ok(None),
+    };
+    match wifi_ssid(&route.iface)? {
+        Some(ssid) => Ok(Some(NetworkIdentity::Wifi(ssid))),
+        None => {
+            // SAFETY: Every bit pattern is a valid LUID
+            let luid = unsafe { route.iface.Value };
+            Ok(Some(NetworkIdentity::Wired(format!(
+                "{}%{}",
+                route.gateway.ip(),
+                luid
+            ))))
+        }
+    }
+}
+
+/// Returns the SSID of the Wi-Fi network that the interface is connected to, or `None` if it is
+/// not a connected Wi-Fi interface.
+fn wifi_ssid(luid: &NET_LUID_LH) -> Result<Option<String>, Error> {
+    let guid = guid_from_luid(luid).map_err(Error::InterfaceGuid)?;
+
+    let mut negotiated_version = 0;
+    let mut handle = 0;
+    // SAFETY: The out pointers are valid
+    let status = unsafe {
+        WlanOpenHandle(
+            WLAN_API_VERSION_2_0,
+            ptr::null(),
+            &mut negotiated_version,
+            &mut handle,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        // The WLAN service is not running on hosts without Wi-Fi adapters
+        return Ok(None);
+    }
+
+    let mut data_size = 0;
+    let mut data: *mut c_void = ptr::null_mut();
+    // SAFETY: `handle` is open, and the out pointers are valid. This fails for interfaces that
+    // are not connected Wi-Fi interfaces.
+    let status = unsafe {
+        WlanQueryInterface(
+            handle,
+            &guid,
+            wlan_intf_opcode_current_connection,
+            ptr::null(),
+            &mut data_size,
+            &mut data,
+            ptr::null_mut(),
+        )
+    };
+    let ssid = if status == ERROR_SUCCESS && !data.is_null() {
+        // SAFETY: The current connection is returned as `WLAN_CONNECTION_ATTRIBUTES`
+        let attributes = unsafe { &*(data as *const WLAN_CONNECTION_ATTRIBUTES) };
+        let ssid = &attributes.wlanAssociationAttributes.dot11Ssid;
+        let length = (ssid.uSSIDLength as usize).min(ssid.ucSSID.len());
+        let ssid = String::from_utf8_lossy(&ssid.ucSSID[..length]).into_owned();
+        // SAFETY: `data` was allocated by `WlanQueryInterface` and is not used after this
+        unsafe { WlanFreeMemory(data) };
+        Some(ssid)
+    } else {
+        None
+    };
+    // SAFETY: `handle` is open and is not used after this
+    unsafe { WlanCloseHandle(handle, ptr::null()) };
+    Ok(ssid)
+}
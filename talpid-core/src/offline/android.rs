@@ -113,6 +113,15 @@ impl MonitorHandle {
         }
     }
 
+    pub async fn network_fingerprint(&self) -> Option<String> {
+        // The routing table is not available to the daemon on Android
+        None
+    }
+
+    pub async fn has_ipv6_route(&self) -> bool {
+        false
+    }
+
     fn get_is_connected(&self) -> Result<bool, Error> {
         let result = self.call_method(
             "isConnected",
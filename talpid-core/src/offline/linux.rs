@@ -38,6 +38,28 @@ impl MonitorHandle {
             }
         }
     }
+
+    pub async fn network_fingerprint(&self) -> Option<String> {
+        for address in [PUBLIC_INTERNET_ADDRESS_V4, PUBLIC_INTERNET_ADDRESS_V6] {
+            if let Ok(Some(route)) = self
+                .route_manager
+                .get_destination_route(address, self.fwmark)
+                .await
+            {
+                return super::node_fingerprint(route.get_node());
+            }
+        }
+        None
+    }
+
+    pub async fn has_ipv6_route(&self) -> bool {
+        matches!(
+            self.route_manager
+                .get_destination_route(PUBLIC_INTERNET_ADDRESS_V6, self.fwmark)
+                .await,
+            Ok(Some(_))
+        )
+    }
 }
 
 pub async fn spawn_monitor(
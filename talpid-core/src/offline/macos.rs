@@ -40,6 +40,23 @@ impl MonitorHandle {
     pub async fn host_is_offline(&self) -> bool {
         !exists_non_tunnel_default_route().await
     }
+
+    pub async fn network_fingerprint(&self) -> Option<String> {
+        match talpid_routing::get_default_routes().await {
+            Ok((Some(node), _)) | Ok((None, Some(node))) => super::node_fingerprint(&node),
+            _ => None,
+        }
+    }
+
+    pub async fn has_ipv6_route(&self) -> bool {
+        match talpid_routing::get_default_routes().await {
+            Ok((_, Some(node))) => node
+                .get_device()
+                .map(|iface_name| !iface_name.contains("tun"))
+                .unwrap_or(true),
+            _ => false,
+        }
+    }
 }
 
 async fn exists_non_tunnel_default_route() -> bool {
@@ -40,6 +40,31 @@ impl MonitorHandle {
             None => false,
         }
     }
+
+    /// Returns a fingerprint of the network that the default route goes through, or `None` if it
+    /// cannot be determined. It is made up of the gateway and interface of the default route, so
+    /// it changes when the host joins a different network.
+    pub async fn network_fingerprint(&self) -> Option<String> {
+        self.0.as_ref()?.network_fingerprint().await
+    }
+
+    /// Returns whether the host has a route to the IPv6 internet outside of the tunnel.
+    pub async fn has_ipv6_route(&self) -> bool {
+        match self.0.as_ref() {
+            Some(monitor) => monitor.has_ipv6_route().await,
+            None => false,
+        }
+    }
+}
+
+/// Returns a fingerprint of the network that `gateway` belongs to.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn node_fingerprint(gateway: &talpid_routing::Node) -> Option<String> {
+    let address = gateway.get_address()?;
+    Some(match gateway.get_device() {
+        Some(interface) => format!("{}%{}", address, interface),
+        None => address.to_string(),
+    })
 }
 
 pub async fn spawn_monitor(
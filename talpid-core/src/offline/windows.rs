@@ -142,6 +142,19 @@ impl BroadcastListener {
         let state = self.system_state.lock();
         state.is_offline_currently()
     }
+
+    pub async fn network_fingerprint(&self) -> Option<String> {
+        let route = [AddressFamily::Ipv4, AddressFamily::Ipv6]
+            .into_iter()
+            .find_map(|family| get_best_default_route(family).ok().flatten())?;
+        // SAFETY: Every bit pattern is a valid LUID
+        let luid = unsafe { route.iface.Value };
+        Some(format!("{}%{}", route.gateway.ip(), luid))
+    }
+
+    pub async fn has_ipv6_route(&self) -> bool {
+        self.system_state.lock().v6_connectivity
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,74 @@
+use std::{io, mem};
+
+/// Errors related to split tunneling.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// Unable to list processes.
+    #[error(display = "Unable to list processes")]
+    ListProcesses(#[error(source)] io::Error),
+}
+
+/// Lists the processes that are excluded from the VPN tunnel. Processes are excluded by launching
+/// them with `mullvad-exclude`, which runs them as the exclusion group. Unlike on Linux, processes
+/// cannot be added or removed after they have been started.
+pub struct PidManager {
+    exclusion_gid: u32,
+}
+
+impl PidManager {
+    /// Creates a new PID manager for processes that run as `exclusion_gid`.
+    pub fn new(exclusion_gid: u32) -> PidManager {
+        PidManager { exclusion_gid }
+    }
+
+    /// Return a list of all PIDs that currently run as the exclusion group. The current process
+    /// is not included, since it runs as the exclusion group as well.
+    pub fn list(&self) -> Result<Vec<i32>, Error> {
+        let own_pid = std::process::id() as i32;
+        Ok(list_pids()
+            .map_err(Error::ListProcesses)?
+            .into_iter()
+            .filter(|pid| *pid != own_pid && process_gid(*pid) == Some(self.exclusion_gid))
+            .collect())
+    }
+}
+
+fn list_pids() -> io::Result<Vec<i32>> {
+    // SAFETY: A null buffer only queries the number of processes
+    let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Leave room for processes started since the count was obtained
+    let mut pids = vec![0i32; count as usize + 32];
+    let buffer_size = (pids.len() * mem::size_of::<i32>()) as libc::c_int;
+    // SAFETY: `pids` is valid for writes of `buffer_size` bytes
+    let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr().cast(), buffer_size) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    pids.truncate(count as usize);
+    Ok(pids)
+}
+
+/// Returns the effective group ID of a process, or `None` if the process no longer exists.
+fn process_gid(pid: i32) -> Option<u32> {
+    // SAFETY: Every bit pattern is a valid `proc_bsdinfo`
+    let mut info: libc::proc_bsdinfo = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    // SAFETY: `info` is valid for writes of `size` bytes
+    let written = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            (&mut info as *mut libc::proc_bsdinfo).cast(),
+            size,
+        )
+    };
+    if written != size {
+        return None;
+    }
+    Some(info.pbi_gid)
+}
@@ -5,6 +5,13 @@ mod imp;
 #[cfg(target_os = "linux")]
 pub use imp::*;
 
+#[cfg(target_os = "macos")]
+#[path = "macos.rs"]
+mod imp;
+
+#[cfg(target_os = "macos")]
+pub use imp::*;
+
 #[cfg(windows)]
 #[path = "windows/mod.rs"]
 mod imp;
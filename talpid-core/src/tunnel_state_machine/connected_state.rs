@@ -1,3 +1,5 @@
+#[cfg(not(target_os = "android"))]
+use super::DnsUpstream;
 use super::{
     AfterDisconnect, ConnectingState, DisconnectingState, ErrorState, EventConsequence,
     EventResult, SharedTunnelStateValues, TunnelCommand, TunnelCommandReceiver, TunnelState,
@@ -87,35 +89,57 @@ impl ConnectedState {
         if let Some(ref servers) = shared_values.dns_servers {
             servers.clone()
         } else {
-            let mut dns_ips = vec![self.metadata.ipv4_gateway.into()];
-            if let Some(ipv6_gateway) = self.metadata.ipv6_gateway {
-                dns_ips.push(ipv6_gateway.into());
-            };
-            dns_ips
+            self.tunnel_gateways()
         }
         #[cfg(target_os = "android")]
         {
-            let mut dns_ips = vec![];
-            dns_ips.push(self.metadata.ipv4_gateway.into());
-            if let Some(ipv6_gateway) = self.metadata.ipv6_gateway {
-                dns_ips.push(ipv6_gateway.into());
-            };
-            dns_ips
+            self.tunnel_gateways()
         }
     }
 
+    fn tunnel_gateways(&self) -> Vec<IpAddr> {
+        let mut gateways = vec![self.metadata.ipv4_gateway.into()];
+        if let Some(ipv6_gateway) = self.metadata.ipv6_gateway {
+            gateways.push(ipv6_gateway.into());
+        };
+        gateways
+    }
+
+    /// Returns the DNS servers that DNS traffic is allowed to. Besides the DNS servers that are
+    /// used, this includes the resolvers that a local forwarder sends queries to in plaintext.
+    #[cfg(not(target_os = "android"))]
+    fn get_allowed_dns_servers(&self, shared_values: &SharedTunnelStateValues) -> Vec<IpAddr> {
+        let mut servers = self.get_dns_servers(shared_values);
+        match &shared_values.dns_upstream {
+            Some(DnsUpstream::TunnelGateway) => servers.extend(self.tunnel_gateways()),
+            Some(DnsUpstream::Resolvers(resolvers)) => servers.extend(resolvers),
+            None => (),
+        }
+        servers
+    }
+
     fn get_firewall_policy(&self, shared_values: &SharedTunnelStateValues) -> FirewallPolicy {
         FirewallPolicy::Connected {
             peer_endpoint: self.tunnel_parameters.get_next_hop_endpoint(),
             tunnel: self.metadata.clone(),
             allow_lan: shared_values.allow_lan,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            lan_rules: shared_values.lan_rules.clone(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            firewall_exceptions: shared_values.firewall_exceptions.clone(),
             #[cfg(not(target_os = "android"))]
-            dns_servers: self.get_dns_servers(shared_values),
+            dns_servers: self.get_allowed_dns_servers(shared_values),
             #[cfg(windows)]
             relay_client: TunnelMonitor::get_relay_client(
                 &shared_values.resource_dir,
                 &self.tunnel_parameters,
             ),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            excluded_networks: shared_values.excluded_networks.clone(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            split_tunnel_mode: shared_values.split_tunnel_mode,
+            #[cfg(target_os = "macos")]
+            exclusion_routes: shared_values.exclusion_routes(),
         }
     }
 
@@ -129,6 +153,8 @@ impl ConnectedState {
                 !crate::firewall::is_local_address(ip)
                     || IpAddr::V4(self.metadata.ipv4_gateway) == *ip
                     || self.metadata.ipv6_gateway.map(IpAddr::V6) == Some(*ip)
+                    // A local forwarder must be used as the system resolver
+                    || (shared_values.dns_upstream.is_some() && ip.is_loopback())
             })
             .collect::<Vec<_>>();
 
@@ -242,6 +268,29 @@ impl ConnectedState {
                     self.disconnect(shared_values, AfterDisconnect::Block(error_cause))
                 }
             },
+            #[cfg(not(target_os = "android"))]
+            Some(TunnelCommand::DnsUpstream(upstream)) => {
+                if shared_values.dns_upstream == upstream {
+                    return SameState(self.into());
+                }
+                shared_values.dns_upstream = upstream;
+                if let Err(error) = self.set_firewall_policy(shared_values) {
+                    return self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    );
+                }
+                match self.set_dns(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => {
+                        log::error!("{}", error.display_chain_with_msg("Failed to set DNS"));
+                        self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetDnsError),
+                        )
+                    }
+                }
+            }
             Some(TunnelCommand::BlockWhenDisconnected(block_when_disconnected)) => {
                 shared_values.block_when_disconnected = block_when_disconnected;
                 SameState(self.into())
@@ -276,6 +325,62 @@ impl ConnectedState {
                 shared_values.split_tunnel.set_paths(&paths, result_tx);
                 SameState(self.into())
             }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetExcludedNetworks(networks)) => {
+                if shared_values.excluded_networks == networks {
+                    return SameState(self.into());
+                }
+                shared_values.excluded_networks = networks;
+                match self.set_firewall_policy(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    ),
+                }
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetSplitTunnelMode(mode)) => {
+                if shared_values.split_tunnel_mode == mode {
+                    return SameState(self.into());
+                }
+                shared_values.split_tunnel_mode = mode;
+                match self.set_firewall_policy(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    ),
+                }
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetLanRules(lan_rules)) => {
+                if shared_values.lan_rules == lan_rules {
+                    return SameState(self.into());
+                }
+                shared_values.lan_rules = lan_rules;
+                match self.set_firewall_policy(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    ),
+                }
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetFirewallExceptions(exceptions)) => {
+                if shared_values.firewall_exceptions == exceptions {
+                    return SameState(self.into());
+                }
+                shared_values.firewall_exceptions = exceptions;
+                match self.set_firewall_policy(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    ),
+                }
+            }
         }
     }
 
@@ -290,6 +395,13 @@ impl ConnectedState {
             Some((TunnelEvent::Down, _)) | None => {
                 self.disconnect(shared_values, AfterDisconnect::Reconnect(0))
             }
+            Some((TunnelEvent::PathMtu(mtu), _)) => {
+                log::info!("Tunnel MTU lowered to {}", mtu);
+                shared_values
+                    .tunnel_parameters_generator
+                    .report_path_mtu(mtu);
+                SameState(self.into())
+            }
             Some(_) => SameState(self.into()),
         }
     }
@@ -324,6 +436,15 @@ impl TunnelState for ConnectedState {
     ) -> (TunnelStateWrapper, TunnelStateTransition) {
         let connected_state = ConnectedState::from(bootstrap);
         let tunnel_endpoint = connected_state.tunnel_parameters.get_tunnel_endpoint();
+        shared_values
+            .tunnel_parameters_generator
+            .set_tunnel_addresses(&connected_state.metadata.ips);
+        shared_values
+            .tunnel_parameters_generator
+            .set_tunnel_interface(&connected_state.metadata.interface);
+        shared_values
+            .tunnel_parameters_generator
+            .set_tunnel_gateways(&connected_state.tunnel_gateways());
 
         if let Err(error) = connected_state.set_firewall_policy(shared_values) {
             DisconnectingState::enter(
@@ -1,7 +1,7 @@
 use super::{
-    AfterDisconnect, ConnectedState, ConnectedStateBootstrap, DisconnectingState, ErrorState,
-    EventConsequence, EventResult, SharedTunnelStateValues, TunnelCommand, TunnelCommandReceiver,
-    TunnelState, TunnelStateTransition, TunnelStateWrapper,
+    AfterDisconnect, ConnectedState, ConnectedStateBootstrap, DisconnectedState,
+    DisconnectingState, ErrorState, EventConsequence, EventResult, SharedTunnelStateValues,
+    TunnelCommand, TunnelCommandReceiver, TunnelState, TunnelStateTransition, TunnelStateWrapper,
 };
 use crate::{
     firewall::FirewallPolicy,
@@ -15,7 +15,10 @@ use futures::{
 };
 use std::{
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
     time::{Duration, Instant},
 };
@@ -23,7 +26,7 @@ use talpid_routing::RouteManager;
 use talpid_tunnel::{tun_provider::TunProvider, TunnelArgs, TunnelEvent, TunnelMetadata};
 use talpid_types::{
     net::{AllowedTunnelTraffic, TunnelParameters},
-    tunnel::{ErrorStateCause, FirewallPolicyError},
+    tunnel::{ErrorStateCause, FirewallPolicyError, ParameterGenerationError},
     ErrorExt,
 };
 
@@ -48,6 +51,8 @@ pub struct ConnectingState {
     allowed_tunnel_traffic: AllowedTunnelTraffic,
     tunnel_close_event: TunnelCloseEvent,
     tunnel_close_tx: oneshot::Sender<()>,
+    /// Set if the tunnel closed because it timed out.
+    timed_out: Arc<AtomicBool>,
     retry_attempt: u32,
 }
 
@@ -67,10 +72,24 @@ impl ConnectingState {
             peer_endpoint,
             tunnel: tunnel_metadata.clone(),
             allow_lan: shared_values.allow_lan,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            lan_rules: shared_values.lan_rules.clone(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            firewall_exceptions: shared_values.firewall_exceptions.clone(),
             allowed_endpoint: shared_values.allowed_endpoint.clone(),
             allowed_tunnel_traffic,
             #[cfg(windows)]
             relay_client: TunnelMonitor::get_relay_client(&shared_values.resource_dir, &params),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            excluded_networks: shared_values.excluded_networks.clone(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            split_tunnel_mode: shared_values.split_tunnel_mode,
+            #[cfg(target_os = "macos")]
+            exclusion_routes: if tunnel_metadata.is_some() {
+                shared_values.exclusion_routes()
+            } else {
+                vec![]
+            },
         };
         shared_values
             .firewall
@@ -117,6 +136,8 @@ impl ConnectingState {
         let (tunnel_close_event_tx, tunnel_close_event_rx) = oneshot::channel();
 
         let mut tunnel_parameters = parameters.clone();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let monitor_timed_out = timed_out.clone();
 
         tokio::task::spawn_blocking(move || {
             let start = Instant::now();
@@ -152,7 +173,8 @@ impl ConnectingState {
 
             let block_reason = match TunnelMonitor::start(&mut tunnel_parameters, &log_dir, args) {
                 Ok(monitor) => {
-                    let reason = Self::wait_for_tunnel_monitor(monitor, retry_attempt);
+                    let reason =
+                        Self::wait_for_tunnel_monitor(monitor, retry_attempt, &monitor_timed_out);
                     log::debug!("Tunnel monitor exited with block reason: {:?}", reason);
                     reason
                 }
@@ -211,6 +233,7 @@ impl ConnectingState {
             allowed_tunnel_traffic: AllowedTunnelTraffic::None,
             tunnel_close_event: tunnel_close_event_rx.fuse(),
             tunnel_close_tx,
+            timed_out,
             retry_attempt,
         }
     }
@@ -218,6 +241,7 @@ impl ConnectingState {
     fn wait_for_tunnel_monitor(
         tunnel_monitor: TunnelMonitor,
         retry_attempt: u32,
+        timed_out: &AtomicBool,
     ) -> Option<ErrorStateCause> {
         match tunnel_monitor.wait() {
             Ok(_) => None,
@@ -226,6 +250,7 @@ impl ConnectingState {
                     talpid_wireguard::Error::TimeoutError,
                 ) => {
                     log::debug!("WireGuard tunnel timed out");
+                    timed_out.store(true, Ordering::SeqCst);
                     None
                 }
                 error @ tunnel::Error::WireguardTunnelMonitoringError(..)
@@ -354,6 +379,11 @@ impl ConnectingState {
                 Ok(_) => SameState(self.into()),
                 Err(cause) => self.disconnect(shared_values, AfterDisconnect::Block(cause)),
             },
+            #[cfg(not(target_os = "android"))]
+            Some(TunnelCommand::DnsUpstream(upstream)) => {
+                shared_values.dns_upstream = upstream;
+                SameState(self.into())
+            }
             Some(TunnelCommand::BlockWhenDisconnected(block_when_disconnected)) => {
                 shared_values.block_when_disconnected = block_when_disconnected;
                 SameState(self.into())
@@ -388,6 +418,78 @@ impl ConnectingState {
                 shared_values.split_tunnel.set_paths(&paths, result_tx);
                 SameState(self.into())
             }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetExcludedNetworks(networks)) => {
+                if shared_values.excluded_networks != networks {
+                    shared_values.excluded_networks = networks;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                        self.allowed_tunnel_traffic.clone(),
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetSplitTunnelMode(mode)) => {
+                if shared_values.split_tunnel_mode != mode {
+                    shared_values.split_tunnel_mode = mode;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                        self.allowed_tunnel_traffic.clone(),
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetLanRules(lan_rules)) => {
+                if shared_values.lan_rules != lan_rules {
+                    shared_values.lan_rules = lan_rules;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                        self.allowed_tunnel_traffic.clone(),
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetFirewallExceptions(exceptions)) => {
+                if shared_values.firewall_exceptions != exceptions {
+                    shared_values.firewall_exceptions = exceptions;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                        self.allowed_tunnel_traffic.clone(),
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
         }
     }
 
@@ -441,7 +543,9 @@ impl ConnectingState {
                 shared_values,
                 self.into_connected_state_bootstrap(metadata),
             )),
-            Some((TunnelEvent::Down, _)) => SameState(self.into()),
+            Some((TunnelEvent::Down, _)) | Some((TunnelEvent::PathMtu(_), _)) => {
+                SameState(self.into())
+            }
             None => {
                 // The channel was closed
                 log::debug!("The tunnel disconnected unexpectedly");
@@ -463,6 +567,12 @@ impl ConnectingState {
             return NewState(ErrorState::enter(shared_values, block_reason));
         }
 
+        if self.timed_out.load(Ordering::SeqCst) {
+            shared_values
+                .tunnel_parameters_generator
+                .report_handshake_timeout();
+        }
+
         log::info!(
             "Tunnel closed. Reconnecting, attempt {}.",
             self.retry_attempt + 1
@@ -535,11 +645,28 @@ impl TunnelState for ConnectingState {
         if shared_values.is_offline {
             return ErrorState::enter(shared_values, ErrorStateCause::IsOffline);
         }
+        let network = shared_values
+            .runtime
+            .block_on(shared_values.offline_monitor.network_fingerprint());
+        shared_values
+            .tunnel_parameters_generator
+            .set_network(network);
+        let ipv6_available = shared_values
+            .runtime
+            .block_on(shared_values.offline_monitor.has_ipv6_route());
+        shared_values
+            .tunnel_parameters_generator
+            .set_ipv6_available(ipv6_available);
         match shared_values.runtime.block_on(
             shared_values
                 .tunnel_parameters_generator
                 .generate(retry_attempt),
         ) {
+            Err(ParameterGenerationError::Cancelled) => {
+                // Generation is only cancelled when disconnecting, so this is not an error
+                log::debug!("Stopped connecting since tunnel parameter generation was cancelled");
+                DisconnectedState::enter(shared_values, true)
+            }
             Err(err) => {
                 ErrorState::enter(shared_values, ErrorStateCause::TunnelParameterError(err))
             }
@@ -23,9 +23,15 @@ impl DisconnectedState {
         let result = if shared_values.block_when_disconnected {
             let policy = FirewallPolicy::Blocked {
                 allow_lan: shared_values.allow_lan,
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                lan_rules: shared_values.lan_rules.clone(),
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                firewall_exceptions: shared_values.firewall_exceptions.clone(),
                 allowed_endpoint: Some(shared_values.allowed_endpoint.clone()),
                 #[cfg(target_os = "macos")]
                 dns_redirect_port: shared_values.filtering_resolver.listening_port(),
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                split_tunnel_mode: shared_values.split_tunnel_mode,
             };
 
             shared_values.firewall.apply_policy(policy).map_err(|e| {
@@ -163,6 +169,11 @@ impl TunnelState for DisconnectedState {
 
                 SameState(self.into())
             }
+            #[cfg(not(target_os = "android"))]
+            Some(TunnelCommand::DnsUpstream(upstream)) => {
+                shared_values.dns_upstream = upstream;
+                SameState(self.into())
+            }
             Some(TunnelCommand::BlockWhenDisconnected(block_when_disconnected)) => {
                 if shared_values.block_when_disconnected != block_when_disconnected {
                     shared_values.block_when_disconnected = block_when_disconnected;
@@ -206,6 +217,41 @@ impl TunnelState for DisconnectedState {
                 shared_values.split_tunnel.set_paths(&paths, result_tx);
                 SameState(self.into())
             }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetExcludedNetworks(networks)) => {
+                shared_values.excluded_networks = networks;
+                SameState(self.into())
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetSplitTunnelMode(mode)) => {
+                if shared_values.split_tunnel_mode != mode {
+                    shared_values.split_tunnel_mode = mode;
+                    if shared_values.block_when_disconnected {
+                        Self::set_firewall_policy(shared_values, false);
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetLanRules(lan_rules)) => {
+                if shared_values.lan_rules != lan_rules {
+                    shared_values.lan_rules = lan_rules;
+                    if shared_values.block_when_disconnected {
+                        Self::set_firewall_policy(shared_values, false);
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Some(TunnelCommand::SetFirewallExceptions(exceptions)) => {
+                if shared_values.firewall_exceptions != exceptions {
+                    shared_values.firewall_exceptions = exceptions;
+                    if shared_values.block_when_disconnected {
+                        Self::set_firewall_policy(shared_values, false);
+                    }
+                }
+                SameState(self.into())
+            }
             None => {
                 Self::reset_dns(shared_values);
                 Finished
@@ -36,6 +36,11 @@ impl DisconnectingState {
                     let _ = shared_values.set_dns_servers(servers);
                     AfterDisconnect::Nothing
                 }
+                #[cfg(not(target_os = "android"))]
+                Some(TunnelCommand::DnsUpstream(upstream)) => {
+                    shared_values.dns_upstream = upstream;
+                    AfterDisconnect::Nothing
+                }
                 Some(TunnelCommand::BlockWhenDisconnected(block_when_disconnected)) => {
                     shared_values.block_when_disconnected = block_when_disconnected;
                     AfterDisconnect::Nothing
@@ -57,6 +62,26 @@ impl DisconnectingState {
                     shared_values.split_tunnel.set_paths(&paths, result_tx);
                     AfterDisconnect::Nothing
                 }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetExcludedNetworks(networks)) => {
+                    shared_values.excluded_networks = networks;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetSplitTunnelMode(mode)) => {
+                    shared_values.split_tunnel_mode = mode;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetLanRules(lan_rules)) => {
+                    shared_values.lan_rules = lan_rules;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetFirewallExceptions(exceptions)) => {
+                    shared_values.firewall_exceptions = exceptions;
+                    AfterDisconnect::Nothing
+                }
             },
             AfterDisconnect::Block(reason) => match command {
                 Some(TunnelCommand::AllowLan(allow_lan)) => {
@@ -72,6 +97,11 @@ impl DisconnectingState {
                     let _ = shared_values.set_dns_servers(servers);
                     AfterDisconnect::Block(reason)
                 }
+                #[cfg(not(target_os = "android"))]
+                Some(TunnelCommand::DnsUpstream(upstream)) => {
+                    shared_values.dns_upstream = upstream;
+                    AfterDisconnect::Block(reason)
+                }
                 Some(TunnelCommand::BlockWhenDisconnected(block_when_disconnected)) => {
                     shared_values.block_when_disconnected = block_when_disconnected;
                     AfterDisconnect::Block(reason)
@@ -97,6 +127,26 @@ impl DisconnectingState {
                     shared_values.split_tunnel.set_paths(&paths, result_tx);
                     AfterDisconnect::Block(reason)
                 }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetExcludedNetworks(networks)) => {
+                    shared_values.excluded_networks = networks;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetSplitTunnelMode(mode)) => {
+                    shared_values.split_tunnel_mode = mode;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetLanRules(lan_rules)) => {
+                    shared_values.lan_rules = lan_rules;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetFirewallExceptions(exceptions)) => {
+                    shared_values.firewall_exceptions = exceptions;
+                    AfterDisconnect::Block(reason)
+                }
                 None => AfterDisconnect::Block(reason),
             },
             AfterDisconnect::Reconnect(retry_attempt) => match command {
@@ -113,6 +163,11 @@ impl DisconnectingState {
                     let _ = shared_values.set_dns_servers(servers);
                     AfterDisconnect::Reconnect(retry_attempt)
                 }
+                #[cfg(not(target_os = "android"))]
+                Some(TunnelCommand::DnsUpstream(upstream)) => {
+                    shared_values.dns_upstream = upstream;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
                 Some(TunnelCommand::BlockWhenDisconnected(block_when_disconnected)) => {
                     shared_values.block_when_disconnected = block_when_disconnected;
                     AfterDisconnect::Reconnect(retry_attempt)
@@ -138,6 +193,26 @@ impl DisconnectingState {
                     shared_values.split_tunnel.set_paths(&paths, result_tx);
                     AfterDisconnect::Reconnect(retry_attempt)
                 }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetExcludedNetworks(networks)) => {
+                    shared_values.excluded_networks = networks;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetSplitTunnelMode(mode)) => {
+                    shared_values.split_tunnel_mode = mode;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetLanRules(lan_rules)) => {
+                    shared_values.lan_rules = lan_rules;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                Some(TunnelCommand::SetFirewallExceptions(exceptions)) => {
+                    shared_values.firewall_exceptions = exceptions;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
             },
         };
 
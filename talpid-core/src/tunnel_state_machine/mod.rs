@@ -19,6 +19,8 @@ use crate::{
     mpsc::Sender,
     offline,
 };
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use ipnetwork::IpNetwork;
 #[cfg(windows)]
 use std::ffi::OsString;
 use talpid_routing::RouteManager;
@@ -42,6 +44,10 @@ use std::{
 };
 #[cfg(target_os = "android")]
 use talpid_types::{android::AndroidContext, ErrorExt};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use talpid_types::{
+    firewall_exception::FirewallException, lan::LanRules, split_tunnel::SplitTunnelMode,
+};
 use talpid_types::{
     net::{AllowedEndpoint, TunnelParameters},
     tunnel::{ErrorStateCause, ParameterGenerationError, TunnelStateTransition},
@@ -91,10 +97,19 @@ pub enum Error {
 pub struct InitialTunnelState {
     /// Whether to allow LAN traffic when not in the (non-blocking) disconnected state.
     pub allow_lan: bool,
+    /// LAN traffic to allow if `allow_lan` is set. All of it is allowed if this is `None`.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub lan_rules: Option<LanRules>,
+    /// Traffic that the firewall allows in every state.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub firewall_exceptions: Vec<FirewallException>,
     /// Block traffic unless connected to the VPN.
     pub block_when_disconnected: bool,
     /// DNS servers to use. If `None`, the tunnel gateway is used.
     pub dns_servers: Option<Vec<IpAddr>>,
+    /// Where a local DNS forwarder among `dns_servers` sends queries, if there is one.
+    #[cfg(not(target_os = "android"))]
+    pub dns_upstream: Option<DnsUpstream>,
     /// A single endpoint that is allowed to communicate outside the tunnel, i.e.
     /// in any of the blocking states.
     pub allowed_endpoint: AllowedEndpoint,
@@ -103,6 +118,24 @@ pub struct InitialTunnelState {
     /// Programs to exclude from the tunnel using the split tunnel driver.
     #[cfg(windows)]
     pub exclude_paths: Vec<OsString>,
+    /// Networks whose traffic is sent outside the tunnel.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub excluded_networks: Vec<IpNetwork>,
+    /// Whether split tunneled processes are excluded from the tunnel or are the only ones using it.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub split_tunnel_mode: SplitTunnelMode,
+}
+
+/// Where a local DNS forwarder sends the queries that it receives. The forwarder is listening on a
+/// loopback address, and it is used as the system resolver while connected.
+#[cfg(not(target_os = "android"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsUpstream {
+    /// Queries are sent in plaintext to the gateway of the tunnel.
+    TunnelGateway,
+    /// Queries are sent in plaintext to these resolvers through the tunnel, so DNS traffic to
+    /// them is allowed. This is empty if the forwarder encrypts the queries that it sends.
+    Resolvers(Vec<IpAddr>),
 }
 
 /// Identifiers for various network resources that should be unique to a given instance of a tunnel
@@ -200,6 +233,9 @@ pub enum TunnelCommand {
     AllowEndpoint(AllowedEndpoint, oneshot::Sender<()>),
     /// Set DNS servers to use.
     Dns(Option<Vec<IpAddr>>),
+    /// Set where a local DNS forwarder among the DNS servers sends queries.
+    #[cfg(not(target_os = "android"))]
+    DnsUpstream(Option<DnsUpstream>),
     /// Enable or disable the block_when_disconnected feature.
     BlockWhenDisconnected(bool),
     /// Notify the state machine of the connectivity of the device.
@@ -219,6 +255,19 @@ pub enum TunnelCommand {
         oneshot::Sender<Result<(), split_tunnel::Error>>,
         Vec<OsString>,
     ),
+    /// Set networks whose traffic is sent outside the tunnel.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    SetExcludedNetworks(Vec<IpNetwork>),
+    /// Set whether split tunneled processes are excluded from the tunnel or are the only ones
+    /// using it.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    SetSplitTunnelMode(SplitTunnelMode),
+    /// Set the LAN traffic to allow if LAN access is allowed.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    SetLanRules(Option<LanRules>),
+    /// Set the traffic that the firewall allows in every state.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    SetFirewallExceptions(Vec<FirewallException>),
 }
 
 type TunnelCommandReceiver = stream::Fuse<mpsc::UnboundedReceiver<TunnelCommand>>;
@@ -313,6 +362,8 @@ impl TunnelStateMachine {
             allow_lan: args.settings.allow_lan,
             #[cfg(target_os = "linux")]
             fwmark: args.linux_ids.fwmark,
+            #[cfg(target_os = "macos")]
+            exclusion_gid,
         };
 
         let firewall = Firewall::from_args(fw_args).map_err(Error::InitFirewallError)?;
@@ -373,12 +424,22 @@ impl TunnelStateMachine {
             firewall,
             dns_monitor,
             route_manager,
-            _offline_monitor: offline_monitor,
+            offline_monitor,
             allow_lan: args.settings.allow_lan,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            lan_rules: args.settings.lan_rules,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            firewall_exceptions: args.settings.firewall_exceptions,
             block_when_disconnected: args.settings.block_when_disconnected,
             is_offline,
             dns_servers: args.settings.dns_servers,
+            #[cfg(not(target_os = "android"))]
+            dns_upstream: args.settings.dns_upstream,
             allowed_endpoint: args.settings.allowed_endpoint,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            excluded_networks: args.settings.excluded_networks,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            split_tunnel_mode: args.settings.split_tunnel_mode,
             tunnel_parameters_generator: Box::new(args.tunnel_parameters_generator),
             tun_provider: Arc::new(Mutex::new(args.tun_provider)),
             log_dir: args.log_dir,
@@ -387,8 +448,6 @@ impl TunnelStateMachine {
             connectivity_check_was_enabled: None,
             #[cfg(target_os = "macos")]
             filtering_resolver,
-            #[cfg(target_os = "macos")]
-            _exclusion_gid: exclusion_gid,
         };
 
         tokio::task::spawn_blocking(move || {
@@ -444,6 +503,34 @@ pub trait TunnelParametersGenerator: Send + 'static {
         &mut self,
         retry_attempt: u32,
     ) -> Pin<Box<dyn Future<Output = Result<TunnelParameters, ParameterGenerationError>>>>;
+
+    /// Called with the IP addresses assigned to the tunnel interface when the tunnel generated
+    /// by the last call to `generate` comes up, before the connected state is entered.
+    fn set_tunnel_addresses(&mut self, _addresses: &[IpAddr]) {}
+
+    /// Called with the name of the tunnel interface when the tunnel generated by the last call to
+    /// `generate` comes up, before the connected state is entered.
+    fn set_tunnel_interface(&mut self, _interface: &str) {}
+
+    /// Called with the gateways of the tunnel when the tunnel generated by the last call to
+    /// `generate` comes up, before the connected state is entered.
+    fn set_tunnel_gateways(&mut self, _gateways: &[IpAddr]) {}
+
+    /// Called with the MTU that the tunnel generated by the last call to `generate` lowered
+    /// itself to while connected.
+    fn report_path_mtu(&mut self, _mtu: u16) {}
+
+    /// Called before `generate` with a fingerprint of the network that the tunnel will be
+    /// established over, or `None` if it is unknown.
+    fn set_network(&mut self, _fingerprint: Option<String>) {}
+
+    /// Called before `generate` with whether the host has a route to the IPv6 internet outside
+    /// of the tunnel.
+    fn set_ipv6_available(&mut self, _available: bool) {}
+
+    /// Called when the WireGuard tunnel generated by the last call to `generate` timed out while
+    /// connecting, which usually means that the handshake did not complete.
+    fn report_handshake_timeout(&mut self) {}
 }
 
 /// Values that are common to all tunnel states.
@@ -457,17 +544,32 @@ struct SharedTunnelStateValues {
     firewall: Firewall,
     dns_monitor: DnsMonitor,
     route_manager: RouteManager,
-    _offline_monitor: offline::MonitorHandle,
+    offline_monitor: offline::MonitorHandle,
     /// Should LAN access be allowed outside the tunnel.
     allow_lan: bool,
+    /// LAN traffic to allow if `allow_lan` is set. All of it is allowed if this is `None`.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    lan_rules: Option<LanRules>,
+    /// Traffic that the firewall allows in every state.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    firewall_exceptions: Vec<FirewallException>,
     /// Should network access be allowed when in the disconnected state.
     block_when_disconnected: bool,
     /// True when the computer is known to be offline.
     is_offline: bool,
     /// DNS servers to use (overriding default).
     dns_servers: Option<Vec<IpAddr>>,
+    /// Where a local DNS forwarder among the DNS servers sends queries, if there is one.
+    #[cfg(not(target_os = "android"))]
+    dns_upstream: Option<DnsUpstream>,
     /// Endpoint that should not be blocked by the firewall.
     allowed_endpoint: AllowedEndpoint,
+    /// Networks whose traffic is sent outside the tunnel while connecting or connected.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    excluded_networks: Vec<IpNetwork>,
+    /// Whether split tunneled processes are excluded from the tunnel or are the only ones using it.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    split_tunnel_mode: SplitTunnelMode,
     /// The generator of new `TunnelParameter`s
     tunnel_parameters_generator: Box<dyn TunnelParametersGenerator>,
     /// The provider of tunnel devices.
@@ -484,10 +586,6 @@ struct SharedTunnelStateValues {
     /// Filtering resolver handle
     #[cfg(target_os = "macos")]
     filtering_resolver: crate::resolver::ResolverHandle,
-
-    /// Exclusion GID
-    #[cfg(target_os = "macos")]
-    _exclusion_gid: u32,
 }
 
 impl SharedTunnelStateValues {
@@ -570,6 +668,39 @@ impl SharedTunnelStateValues {
         }
     }
 
+    /// Returns the default routes of the physical network, that traffic from excluded processes
+    /// is routed over.
+    #[cfg(target_os = "macos")]
+    pub fn exclusion_routes(&self) -> Vec<crate::firewall::ExclusionRoute> {
+        use talpid_types::ErrorExt;
+
+        let (v4_route, v6_route) = match self.runtime.block_on(talpid_routing::get_default_routes())
+        {
+            Ok(routes) => routes,
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to obtain default routes")
+                );
+                return vec![];
+            }
+        };
+        v4_route
+            .into_iter()
+            .chain(v6_route)
+            .filter_map(|node| {
+                let interface = node.get_device()?;
+                if interface.contains("tun") {
+                    return None;
+                }
+                Some(crate::firewall::ExclusionRoute {
+                    interface: interface.to_owned(),
+                    gateway: node.get_address()?,
+                })
+            })
+            .collect()
+    }
+
     #[cfg(target_os = "android")]
     pub fn bypass_socket(&mut self, fd: RawFd, tx: oneshot::Sender<()>) {
         if let Err(err) = self.tun_provider.lock().unwrap().bypass(fd) {
@@ -245,6 +245,11 @@ impl OpenVpnCommand {
             args.push(OsString::from(mssfix.to_string()));
         }
 
+        if let Some(ref cipher) = self.tunnel_options.cipher {
+            args.push(OsString::from("--data-ciphers"));
+            args.push(OsString::from(cipher));
+        }
+
         if !self.enable_ipv6 {
             args.push(OsString::from("--pull-filter"));
             args.push(OsString::from("ignore"));
@@ -57,6 +57,8 @@ pub enum TunnelEvent {
     InterfaceUp(TunnelMetadata, AllowedTunnelTraffic),
     /// Sent when the tunnel comes up and is ready for traffic.
     Up(TunnelMetadata),
+    /// Sent when the tunnel has lowered its MTU because larger packets did not make it through.
+    PathMtu(u16),
     /// Sent when the tunnel goes down.
     Down,
 }
@@ -0,0 +1,43 @@
+use crate::net::TransportProtocol;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Traffic that the firewall allows in every tunnel state, in addition to what the current policy
+/// allows. Responses to the traffic are allowed as well.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct FirewallException {
+    /// Whether connections are initiated by this device or by the remote hosts.
+    pub direction: Direction,
+    pub protocol: TransportProtocol,
+    /// Network of the remote hosts.
+    pub network: IpNetwork,
+    /// Destination port of the traffic, i.e. the port on the remote hosts for outgoing traffic
+    /// and the local port for incoming traffic. Any port is allowed if this is `None`.
+    pub port: Option<u16>,
+}
+
+/// Which end initiates the connections that a [`FirewallException`] allows.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Traffic sent from remote hosts to this device.
+    Incoming,
+    /// Traffic sent from this device to remote hosts.
+    Outgoing,
+}
+
+impl fmt::Display for FirewallException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.direction {
+            Direction::Incoming => {
+                write!(f, "incoming {} from {} to ", self.protocol, self.network)?
+            }
+            Direction::Outgoing => write!(f, "outgoing {} to {} ", self.protocol, self.network)?,
+        }
+        match self.port {
+            Some(port) => write!(f, "port {}", port),
+            None => write!(f, "any port"),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Restricts the local network traffic that is allowed when LAN access is allowed. Traffic is
+/// allowed if it matches any of the subnets, interfaces or services.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LanRules {
+    /// Local networks that traffic is allowed to and from.
+    pub subnets: Vec<IpNetwork>,
+    /// Interfaces over which traffic to and from any local network is allowed.
+    pub interfaces: Vec<String>,
+    /// Services that are allowed on any local network.
+    pub services: Vec<LanService>,
+}
+
+/// A service on the local network that can be allowed on its own.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LanService {
+    /// Multicast DNS, which is used to discover devices on the local network.
+    Mdns,
+    /// Serving DHCP to other devices, e.g. when sharing the connection. Acting as a DHCP client
+    /// is always allowed.
+    Dhcp,
+    /// Printing to network printers over LPD, IPP or raw TCP.
+    Printing,
+}
+
+impl fmt::Display for LanService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LanService::Mdns => "mdns".fmt(f),
+            LanService::Dhcp => "dhcp".fmt(f),
+            LanService::Printing => "printing".fmt(f),
+        }
+    }
+}
+
+/// Error returned when parsing an unknown [`LanService`].
+#[derive(err_derive::Error, Debug, Clone, Eq, PartialEq)]
+#[error(display = "Unknown LAN service: {}", _0)]
+pub struct UnknownLanService(pub String);
+
+impl FromStr for LanService {
+    type Err = UnknownLanService;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mdns" => Ok(LanService::Mdns),
+            "dhcp" => Ok(LanService::Dhcp),
+            "printing" => Ok(LanService::Printing),
+            _ => Err(UnknownLanService(s.to_owned())),
+        }
+    }
+}
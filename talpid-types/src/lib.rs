@@ -4,12 +4,19 @@ use std::{error::Error, fmt, fmt::Write};
 
 #[cfg(target_os = "android")]
 pub mod android;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod firewall_exception;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod lan;
 pub mod net;
 pub mod tunnel;
 
 #[cfg(target_os = "linux")]
 pub mod cgroup;
 
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod split_tunnel;
+
 /// Used to generate string representations of error chains.
 pub trait ErrorExt {
     /// Creates a string representation of the entire error chain.
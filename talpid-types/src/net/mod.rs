@@ -74,6 +74,7 @@ impl TunnelParameters {
                 address: *endpoint,
                 protocol: TransportProtocol::Tcp,
             },
+            ObfuscatorConfig::PluggableTransport { endpoint, .. } => *endpoint,
         }
     }
 
@@ -175,12 +176,15 @@ impl fmt::Display for TunnelEndpoint {
 pub enum ObfuscationType {
     #[serde(rename = "udp2tcp")]
     Udp2Tcp,
+    #[serde(rename = "pluggable_transport")]
+    PluggableTransport,
 }
 
 impl fmt::Display for ObfuscationType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
             ObfuscationType::Udp2Tcp => "Udp2Tcp".fmt(f),
+            ObfuscationType::PluggableTransport => "PluggableTransport".fmt(f),
         }
     }
 }
@@ -202,6 +206,9 @@ impl From<&ObfuscatorConfig> for ObfuscationEndpoint {
                 },
                 ObfuscationType::Udp2Tcp,
             ),
+            ObfuscatorConfig::PluggableTransport { endpoint, .. } => {
+                (*endpoint, ObfuscationType::PluggableTransport)
+            }
         };
 
         ObfuscationEndpoint {
@@ -365,6 +372,38 @@ pub struct GenericTunnelOptions {
     /// Enable configuration of IPv6 on the tunnel interface, allowing IPv6 communication to be
     /// forwarded through the tunnel.
     pub enable_ipv6: bool,
+    /// Resolvers to use for specific domains. Queries for other domains are sent to the regular
+    /// DNS servers.
+    #[serde(default)]
+    pub split_dns: Vec<SplitDnsRule>,
+}
+
+/// Sends DNS queries for a set of domains, and their subdomains, to specific resolvers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct SplitDnsRule {
+    pub domains: Vec<String>,
+    pub resolvers: Vec<IpAddr>,
+}
+
+/// Identifies the network that the host is connected to, i.e. the one that the default route
+/// goes through.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkIdentity {
+    /// A Wi-Fi network, identified by its SSID.
+    Wifi(String),
+    /// A wired network, or any other network that is not Wi-Fi. It is identified by the gateway
+    /// and interface of the default route, e.g. `192.168.1.1%eth0`.
+    Wired(String),
+}
+
+impl fmt::Display for NetworkIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkIdentity::Wifi(ssid) => write!(f, "Wi-Fi network \"{}\"", ssid),
+            NetworkIdentity::Wired(fingerprint) => write!(f, "wired network {}", fingerprint),
+        }
+    }
 }
 
 /// Returns a vector of IP networks representing all of the internet, 0.0.0.0/0.
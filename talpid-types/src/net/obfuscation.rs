@@ -1,7 +1,17 @@
+use super::Endpoint;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::{collections::BTreeMap, net::SocketAddr};
 
 #[derive(Clone, Eq, PartialEq, Deserialize, Serialize, Debug)]
 pub enum ObfuscatorConfig {
-    Udp2Tcp { endpoint: SocketAddr },
+    Udp2Tcp {
+        endpoint: SocketAddr,
+    },
+    /// Obfuscation through the pluggable transport `name`. The `parameters` are opaque to
+    /// everything but the transport itself, so they are passed on as is.
+    PluggableTransport {
+        name: String,
+        endpoint: Endpoint,
+        parameters: BTreeMap<String, String>,
+    },
 }
@@ -44,6 +44,9 @@ pub struct TunnelOptions {
     /// Optional argument for openvpn to try and limit TCP packet size,
     /// as discussed [here](https://openvpn.net/archive/openvpn-users/2003-11/msg00154.html)
     pub mssfix: Option<u16>,
+    /// Cipher to use for the data channel. OpenVPN negotiates the cipher if this is not set.
+    #[serde(default)]
+    pub cipher: Option<String>,
 }
 
 /// Proxy server options to be used by `OpenVpnMonitor` when starting a tunnel.
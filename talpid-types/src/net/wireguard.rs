@@ -87,8 +87,18 @@ pub struct TunnelOptions {
         jnix(map = "|maybe_mtu| maybe_mtu.map(|mtu| mtu as i32)")
     )]
     pub mtu: Option<u16>,
-    /// Obtain a PSK using the relay config client.
+    /// Obtain a PSK using the relay config client. This is resolved from the quantum resistance
+    /// setting whenever tunnel parameters are generated, so it is not persisted.
+    #[serde(skip)]
     pub use_pq_safe_psk: bool,
+    /// Lower the MTU if it turns out to be too large for the path to the relay, by probing it
+    /// once the tunnel is up. Only used when `mtu` is not set.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub mtu_detection: bool,
+    /// Enable DAITA (defense against AI-guided traffic analysis), which pads packets and injects
+    /// dummy traffic to hide patterns in the traffic. Requires a relay that supports it.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub daita: bool,
     /// Temporary switch for wireguard-nt
     #[cfg(windows)]
     #[serde(default = "default_wgnt_setting")]
@@ -107,6 +117,8 @@ impl Default for TunnelOptions {
         Self {
             mtu: None,
             use_pq_safe_psk: false,
+            mtu_detection: false,
+            daita: false,
             #[cfg(windows)]
             use_wireguard_nt: default_wgnt_setting(),
         }
@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Name of the group that processes excluded from the tunnel run as. Traffic from sockets owned
+/// by this group, and by a user other than root, is routed outside the tunnel.
+#[cfg(target_os = "macos")]
+pub const SPLIT_TUNNEL_GROUP_NAME: &str = "mullvad-exclusion";
+
+/// Determines how traffic from processes that are selected for split tunneling is treated.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitTunnelMode {
+    /// Traffic from the selected processes is sent outside the tunnel.
+    #[default]
+    Exclude,
+    /// Only traffic from the selected processes is sent through the tunnel. Traffic from every
+    /// other process is sent outside the tunnel.
+    Include,
+}
+
+impl fmt::Display for SplitTunnelMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitTunnelMode::Exclude => "exclude".fmt(f),
+            SplitTunnelMode::Include => "include".fmt(f),
+        }
+    }
+}
@@ -134,6 +134,9 @@ pub enum ParameterGenerationError {
     /// Failure to resolve the hostname of a custom tunnel configuration
     #[error(display = "Can't resolve hostname for custom tunnel host")]
     CustomTunnelHostResultionError,
+    /// Tunnel parameter generation was aborted since the tunnel is being disconnected
+    #[error(display = "Tunnel parameter generation was cancelled")]
+    Cancelled,
 }
 
 /// Application that prevents setting the firewall policy.
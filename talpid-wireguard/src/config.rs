@@ -18,6 +18,9 @@ pub struct Config {
     pub ipv6_gateway: Option<Ipv6Addr>,
     /// Maximum transmission unit for the tunnel
     pub mtu: u16,
+    /// Lower `mtu` if pings of that size are not answered once the tunnel is up
+    #[cfg(not(target_os = "android"))]
+    pub mtu_detection: bool,
     /// Firewall mark
     #[cfg(target_os = "linux")]
     pub fwmark: Option<u32>,
@@ -117,6 +120,8 @@ impl Config {
             ipv4_gateway: connection_config.ipv4_gateway,
             ipv6_gateway,
             mtu,
+            #[cfg(not(target_os = "android"))]
+            mtu_detection: wg_options.mtu_detection,
             #[cfg(target_os = "linux")]
             fwmark: connection_config.fwmark,
             #[cfg(target_os = "linux")]
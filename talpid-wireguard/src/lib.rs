@@ -46,6 +46,8 @@ use tunnel_obfuscation::{
 pub mod config;
 mod connectivity_check;
 mod logging;
+#[cfg(not(target_os = "android"))]
+mod mtu_detection;
 mod ping_monitor;
 mod stats;
 mod wireguard_go;
@@ -83,6 +85,10 @@ pub enum Error {
     #[error(display = "Tunnel obfuscator failed")]
     ObfuscatorError(#[error(source)] ObfuscationError),
 
+    /// No implementation of the pluggable transport exists
+    #[error(display = "Unsupported pluggable transport: {}", _0)]
+    UnsupportedPluggableTransport(String),
+
     /// Failed to set up connectivity monitor
     #[error(display = "Connectivity monitor failed")]
     ConnectivityMonitorError(#[error(source)] connectivity_check::Error),
@@ -215,6 +221,9 @@ async fn maybe_create_obfuscator(
                     remote_socket_fd,
                 )));
             }
+            ObfuscatorConfig::PluggableTransport { name, .. } => {
+                return Err(Error::UnsupportedPluggableTransport(name.clone()));
+            }
         }
     }
     Ok(None)
@@ -374,6 +383,13 @@ impl WireguardMonitor {
 
             (on_event)(TunnelEvent::Up(metadata)).await;
 
+            #[cfg(not(target_os = "android"))]
+            if config.mtu_detection {
+                if let Some(mtu) = Self::detect_mtu(&iface_name, &config).await {
+                    (on_event)(TunnelEvent::PathMtu(mtu)).await;
+                }
+            }
+
             tokio::task::spawn_blocking(move || {
                 if let Err(error) = connectivity_monitor.run() {
                     log::error!(
@@ -405,6 +421,47 @@ impl WireguardMonitor {
         Ok(monitor)
     }
 
+    /// Lowers the MTU of the tunnel interface if pings of the configured MTU are not answered by
+    /// the gateway. Returns the new MTU if it was lowered.
+    #[cfg(not(target_os = "android"))]
+    async fn detect_mtu(iface_name: &str, config: &Config) -> Option<u16> {
+        let gateway = config.ipv4_gateway;
+        let current_mtu = config.mtu;
+        let iface_name = iface_name.to_owned();
+        let result = tokio::task::spawn_blocking(move || {
+            let mtu = mtu_detection::detect_mtu(
+                gateway,
+                #[cfg(not(target_os = "windows"))]
+                &iface_name,
+                current_mtu,
+            )?;
+            if mtu < current_mtu {
+                mtu_detection::set_mtu(&iface_name, mtu)?;
+            }
+            Ok::<u16, mtu_detection::Error>(mtu)
+        })
+        .await
+        .unwrap();
+
+        match result {
+            Ok(mtu) if mtu < current_mtu => {
+                log::info!("Lowered tunnel MTU from {} to {}", current_mtu, mtu);
+                Some(mtu)
+            }
+            Ok(_) => {
+                log::debug!("Tunnel MTU {} works", current_mtu);
+                None
+            }
+            Err(error) => {
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to detect tunnel MTU")
+                );
+                None
+            }
+        }
+    }
+
     /// Replace `0.0.0.0/0`/`::/0` with the gateway IPs when `gateway_only` is true.
     /// Used to block traffic to other destinations while connecting on Android.
     fn patch_allowed_ips(config: &Config, gateway_only: bool) -> Cow<'_, Config> {
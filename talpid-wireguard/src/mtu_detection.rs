@@ -0,0 +1,223 @@
+//! Detects the largest packets that can be sent through the tunnel, by sending pings of different
+//! sizes to the gateway and checking which of them are answered. Packets that are too large for
+//! some link between the client and the relay are often silently dropped rather than fragmented.
+
+use byteorder::{ByteOrder, NetworkEndian};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io::{self, Read},
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// The smallest MTU that is probed. IPv6 requires at least this MTU.
+const MIN_PROBED_MTU: u16 = 1280;
+/// Difference in size between two consecutive probes.
+const PROBE_STEP: u16 = 10;
+/// How long to wait for replies after sending the probes.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+const IPV4_HEADER_SIZE: u16 = 20;
+const ICMP_HEADER_SIZE: u16 = 8;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+
+/// MTU detection errors
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// Failed to open raw socket
+    #[error(display = "Failed to open ICMP socket")]
+    Open(#[error(source)] io::Error),
+
+    /// Failed to send a probe
+    #[error(display = "Failed to send ICMP probe")]
+    Send(#[error(source)] io::Error),
+
+    /// Failed to read from raw socket
+    #[error(display = "Failed to read ICMP socket")]
+    Receive(#[error(source)] io::Error),
+
+    /// None of the probes were answered
+    #[error(display = "None of the MTU probes were answered")]
+    NoReply,
+
+    /// Failed to update the MTU of the tunnel interface
+    #[error(display = "Failed to set the MTU of the tunnel interface")]
+    SetMtu(#[error(source)] io::Error),
+}
+
+/// Returns the largest MTU, up to `current_mtu`, for which a ping to `gateway` is answered.
+/// This blocks for up to a few seconds.
+pub fn detect_mtu(
+    gateway: Ipv4Addr,
+    #[cfg(not(target_os = "windows"))] interface_name: &str,
+    current_mtu: u16,
+) -> Result<u16, Error> {
+    let sock = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).map_err(Error::Open)?;
+
+    #[cfg(target_os = "linux")]
+    sock.bind_device(Some(interface_name.as_bytes()))
+        .map_err(Error::Open)?;
+
+    #[cfg(target_os = "macos")]
+    {
+        let index = nix::net::if_::if_nametoindex(interface_name)
+            .map_err(|error| Error::Open(io::Error::from(error)))?;
+        sock.bind_device_by_index(std::num::NonZeroU32::new(index))
+            .map_err(Error::Open)?;
+    }
+
+    let destination = SocketAddr::new(gateway.into(), 0).into();
+    let id: u16 = rand::random();
+    let sizes = probe_sizes(current_mtu);
+    for (seq, size) in sizes.iter().enumerate() {
+        let packet = echo_request(id, seq as u16, *size);
+        sock.send_to(&packet, &destination).map_err(Error::Send)?;
+    }
+
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut buffer = vec![0u8; usize::from(current_mtu)];
+    let mut largest_answered = None;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        sock.set_read_timeout(Some(remaining))
+            .map_err(Error::Receive)?;
+        let len = match (&sock).read(&mut buffer) {
+            Ok(len) => len,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(error) => return Err(Error::Receive(error)),
+        };
+        let answered = parse_echo_reply(&buffer[..len], id)
+            .and_then(|seq| sizes.get(usize::from(seq)).copied());
+        if answered > largest_answered {
+            largest_answered = answered;
+            if largest_answered == sizes.last().copied() {
+                break;
+            }
+        }
+    }
+
+    largest_answered.ok_or(Error::NoReply)
+}
+
+/// Sets the MTU of the tunnel interface.
+#[cfg(unix)]
+pub fn set_mtu(interface_name: &str, mtu: u16) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(target_os = "linux")]
+    const SIOCSIFMTU: libc::c_ulong = libc::SIOCSIFMTU;
+    #[cfg(target_os = "macos")]
+    const SIOCSIFMTU: libc::c_ulong = 0x80206934;
+
+    /// `struct ifreq` with the `ifr_mtu` member of the union.
+    #[repr(C)]
+    struct IfreqMtu {
+        name: [libc::c_char; libc::IFNAMSIZ],
+        mtu: libc::c_int,
+        _padding: [u8; 20],
+    }
+
+    let mut request = IfreqMtu {
+        name: [0; libc::IFNAMSIZ],
+        mtu: libc::c_int::from(mtu),
+        _padding: [0; 20],
+    };
+    // The name must be null-terminated
+    if interface_name.len() >= request.name.len() {
+        return Err(Error::SetMtu(io::Error::from(io::ErrorKind::InvalidInput)));
+    }
+    for (dst, src) in request.name.iter_mut().zip(interface_name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    let sock = Socket::new(Domain::IPV4, Type::DGRAM, None).map_err(Error::SetMtu)?;
+    // SAFETY: `request` is a valid `ifreq` that outlives the call
+    if unsafe { libc::ioctl(sock.as_raw_fd(), SIOCSIFMTU, &request) } < 0 {
+        return Err(Error::SetMtu(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Sets the MTU of the tunnel interface.
+#[cfg(windows)]
+pub fn set_mtu(interface_name: &str, mtu: u16) -> Result<(), Error> {
+    let luid = talpid_windows_net::luid_from_alias(interface_name).map_err(Error::SetMtu)?;
+    talpid_tunnel::network_interface::initialize_interfaces(luid, Some(u32::from(mtu)))
+        .map_err(Error::SetMtu)
+}
+
+/// Returns the MTUs to probe, in increasing order. `current_mtu` is always included.
+fn probe_sizes(current_mtu: u16) -> Vec<u16> {
+    let mut sizes: Vec<u16> = (MIN_PROBED_MTU..current_mtu)
+        .step_by(usize::from(PROBE_STEP))
+        .collect();
+    sizes.push(current_mtu);
+    sizes
+}
+
+/// Returns an ICMP echo request that results in an IPv4 packet of `packet_size` bytes.
+fn echo_request(id: u16, seq: u16, packet_size: u16) -> Vec<u8> {
+    let len = packet_size
+        .saturating_sub(IPV4_HEADER_SIZE)
+        .max(ICMP_HEADER_SIZE);
+    let mut packet = vec![0u8; usize::from(len)];
+    packet[0] = ICMP_ECHO_REQUEST;
+    NetworkEndian::write_u16(&mut packet[4..6], id);
+    NetworkEndian::write_u16(&mut packet[6..8], seq);
+    let checksum = internet_checksum::checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum);
+    packet
+}
+
+/// Returns the sequence number of `packet` if it is an IPv4 packet that contains an ICMP echo
+/// reply with the given ID.
+fn parse_echo_reply(packet: &[u8], id: u16) -> Option<u16> {
+    let header_len = usize::from(packet.first()? & 0x0f) * 4;
+    let icmp = packet.get(header_len..header_len + usize::from(ICMP_HEADER_SIZE))?;
+    if icmp[0] != ICMP_ECHO_REPLY || NetworkEndian::read_u16(&icmp[4..6]) != id {
+        return None;
+    }
+    Some(NetworkEndian::read_u16(&icmp[6..8]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_probe_sizes() {
+        assert_eq!(probe_sizes(1320), vec![1280, 1290, 1300, 1310, 1320]);
+        assert_eq!(probe_sizes(1285), vec![1280, 1285]);
+        assert_eq!(probe_sizes(1200), vec![1200]);
+    }
+
+    #[test]
+    fn test_parse_echo_reply() {
+        let mut reply = vec![0u8; 20];
+        reply[0] = 0x45;
+        let mut icmp = echo_request(0x1234, 7, 1300);
+        assert_eq!(icmp.len(), 1280);
+        // Not a reply
+        reply.extend_from_slice(&icmp);
+        assert_eq!(parse_echo_reply(&reply, 0x1234), None);
+
+        icmp[0] = ICMP_ECHO_REPLY;
+        reply.truncate(20);
+        reply.extend_from_slice(&icmp);
+        assert_eq!(parse_echo_reply(&reply, 0x1234), Some(7));
+        assert_eq!(parse_echo_reply(&reply, 0x4321), None);
+        assert_eq!(parse_echo_reply(&reply[..24], 0x1234), None);
+    }
+}
@@ -1023,6 +1023,8 @@ mod tests {
                 ipv4_gateway: "0.0.0.0".parse().unwrap(),
                 ipv6_gateway: None,
                 mtu: 0,
+                mtu_detection: false,
+                daita: false,
                 use_wireguard_nt: true,
                 obfuscator_config: None,
             }